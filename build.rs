@@ -2,7 +2,46 @@ use static_files::resource_dir;
 
 fn main() -> std::io::Result<()> {
     println!("cargo:rerun-if-changed=migrations");
+    println!("cargo:rerun-if-changed=migrations_postgres");
     println!("cargo:rerun-if-changed=static");
     println!("cargo:rerun-if-changed=templates");
-    resource_dir("./static").build()
+    resource_dir("./static").build()?;
+
+    if std::env::var_os("CARGO_FEATURE_FRONTEND").is_some() {
+        embed_frontend()?;
+    }
+    #[cfg(feature = "grpc")]
+    compile_proto()?;
+    Ok(())
+}
+
+/// Generates the gRPC server code, protox parses the proto file so protoc isn't needed
+#[cfg(feature = "grpc")]
+fn compile_proto() -> std::io::Result<()> {
+    println!("cargo:rerun-if-changed=proto");
+    let descriptors = protox::compile(["ssm.proto"], ["proto"]).map_err(std::io::Error::other)?;
+    tonic_prost_build::configure()
+        .build_client(false)
+        .compile_fds(descriptors)
+}
+
+/// Embeds the built frontend, so a single binary serves both the API and the UI
+fn embed_frontend() -> std::io::Result<()> {
+    println!("cargo:rerun-if-env-changed=SSM_FRONTEND_DIST");
+    let dist = std::env::var("SSM_FRONTEND_DIST").unwrap_or_else(|_| "frontend/dist".to_owned());
+    println!("cargo:rerun-if-changed={dist}");
+
+    if !std::path::Path::new(&dist).join("index.html").exists() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("The frontend feature needs a built frontend with an index.html in '{dist}', set SSM_FRONTEND_DIST to its location"),
+        ));
+    }
+
+    let out_dir = std::env::var("OUT_DIR").expect("OUT_DIR is set by cargo");
+    let mut frontend = resource_dir(dist);
+    frontend
+        .with_generated_filename(std::path::Path::new(&out_dir).join("frontend.rs"))
+        .with_generated_fn("generate_frontend");
+    frontend.build()
 }
@@ -0,0 +1 @@
+ALTER TABLE host ADD COLUMN transport TEXT NOT NULL DEFAULT 'script';
@@ -0,0 +1 @@
+ALTER TABLE host ADD COLUMN tags TEXT NOT NULL DEFAULT '';
@@ -0,0 +1,7 @@
+CREATE TABLE schedule (
+	id INTEGER NOT NULL PRIMARY KEY,
+	name TEXT UNIQUE NOT NULL,
+	cron TEXT NOT NULL,
+	job TEXT NOT NULL,
+	tag TEXT
+);
@@ -0,0 +1,2 @@
+ALTER TABLE host DROP COLUMN post_deploy_hook;
+ALTER TABLE host DROP COLUMN pre_deploy_hook;
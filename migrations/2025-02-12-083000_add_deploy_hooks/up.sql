@@ -0,0 +1,2 @@
+ALTER TABLE host ADD COLUMN pre_deploy_hook TEXT;
+ALTER TABLE host ADD COLUMN post_deploy_hook TEXT;
@@ -0,0 +1,24 @@
+CREATE TABLE authorization_history (
+	id INTEGER NOT NULL PRIMARY KEY,
+	authorization_id INTEGER NOT NULL,
+	action TEXT NOT NULL,
+	host_id INTEGER NOT NULL,
+	host_name TEXT NOT NULL,
+	user_id INTEGER NOT NULL,
+	username TEXT NOT NULL,
+	login TEXT NOT NULL,
+	options TEXT,
+	actor TEXT NOT NULL,
+	changed_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX authorization_history_host ON authorization_history(host_id, changed_at);
+
+-- Authorizations from before the history was kept
+INSERT INTO authorization_history
+	(authorization_id, action, host_id, host_name, user_id, username, login, options, actor, changed_at)
+SELECT authorization.id, 'created', host.id, host.name, user.id, user.username,
+	authorization.login, authorization.options, 'migration', CURRENT_TIMESTAMP
+FROM authorization
+	INNER JOIN host ON host.id = authorization.host_id
+	INNER JOIN user ON user.id = authorization.user_id;
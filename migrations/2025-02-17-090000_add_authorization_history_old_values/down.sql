@@ -0,0 +1,2 @@
+ALTER TABLE authorization_history DROP COLUMN old_options;
+ALTER TABLE authorization_history DROP COLUMN old_login;
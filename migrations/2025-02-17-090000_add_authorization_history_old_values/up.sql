@@ -0,0 +1,2 @@
+ALTER TABLE authorization_history ADD COLUMN old_login TEXT;
+ALTER TABLE authorization_history ADD COLUMN old_options TEXT;
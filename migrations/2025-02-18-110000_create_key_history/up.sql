@@ -0,0 +1,19 @@
+CREATE TABLE key_history (
+	id INTEGER NOT NULL PRIMARY KEY,
+	key_id INTEGER,
+	key_base64 TEXT NOT NULL,
+	action TEXT NOT NULL,
+	username TEXT,
+	host_name TEXT,
+	login TEXT,
+	actor TEXT NOT NULL,
+	changed_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX key_history_key ON key_history(key_base64, changed_at);
+
+-- Keys from before the history was kept
+INSERT INTO key_history (key_id, key_base64, action, username, actor, changed_at)
+SELECT user_key.id, user_key.key_base64, 'created', user.username, 'migration', CURRENT_TIMESTAMP
+FROM user_key
+	INNER JOIN user ON user.id = user_key.user_id;
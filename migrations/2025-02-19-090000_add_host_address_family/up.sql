@@ -0,0 +1,2 @@
+-- NULL uses the address_family of the ssh configuration
+ALTER TABLE host ADD COLUMN address_family TEXT;
@@ -0,0 +1,2 @@
+-- socks5://host:port or http://host:port, used instead of a direct connection
+ALTER TABLE host ADD COLUMN proxy TEXT;
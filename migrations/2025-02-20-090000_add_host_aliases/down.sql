@@ -0,0 +1,2 @@
+ALTER TABLE host DROP COLUMN aliases;
+ALTER TABLE host DROP COLUMN fallback_addresses;
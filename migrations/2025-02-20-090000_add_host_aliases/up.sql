@@ -0,0 +1,4 @@
+-- comma separated, in the order they are tried after address
+ALTER TABLE host ADD COLUMN fallback_addresses TEXT NOT NULL DEFAULT '';
+-- comma separated alternative names
+ALTER TABLE host ADD COLUMN aliases TEXT NOT NULL DEFAULT '';
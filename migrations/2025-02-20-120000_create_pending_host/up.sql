@@ -0,0 +1,15 @@
+-- Hosts whose offered host key still has to be confirmed, referenced by a random token
+CREATE TABLE pending_host (
+	token TEXT NOT NULL PRIMARY KEY,
+	name TEXT NOT NULL,
+	username TEXT NOT NULL,
+	address TEXT NOT NULL,
+	port INTEGER NOT NULL,
+	key_fingerprint TEXT NOT NULL,
+	jump_via INTEGER,
+	transport TEXT NOT NULL,
+	tags TEXT NOT NULL,
+	proxy TEXT,
+	created_at TIMESTAMP NOT NULL,
+	FOREIGN KEY (jump_via) REFERENCES host(id) ON DELETE CASCADE
+);
@@ -0,0 +1 @@
+ALTER TABLE host DROP COLUMN additional_key_fingerprints;
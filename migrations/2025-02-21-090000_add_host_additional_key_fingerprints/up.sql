@@ -0,0 +1,2 @@
+-- comma separated host key fingerprints accepted besides key_fingerprint
+ALTER TABLE host ADD COLUMN additional_key_fingerprints TEXT NOT NULL DEFAULT '';
@@ -0,0 +1,2 @@
+ALTER TABLE host DROP COLUMN key_mismatch;
+DROP TABLE security_event;
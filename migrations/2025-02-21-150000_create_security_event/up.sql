@@ -0,0 +1,11 @@
+CREATE TABLE security_event (
+	id INTEGER NOT NULL PRIMARY KEY,
+	kind TEXT NOT NULL,
+	severity TEXT NOT NULL,
+	host_name TEXT,
+	message TEXT NOT NULL,
+	created_at TIMESTAMP NOT NULL
+);
+
+-- host key fingerprint offered instead of an accepted one, kept until the host keys are changed
+ALTER TABLE host ADD COLUMN key_mismatch TEXT;
@@ -0,0 +1,7 @@
+-- events waiting to be published to the message bus, deleted once published
+CREATE TABLE event_outbox (
+	id INTEGER NOT NULL PRIMARY KEY,
+	event TEXT NOT NULL,
+	payload TEXT NOT NULL,
+	created_at TIMESTAMP NOT NULL
+);
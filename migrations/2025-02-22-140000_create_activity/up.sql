@@ -0,0 +1,13 @@
+-- mutating requests with who made them, for reviewing one operator's actions
+CREATE TABLE activity (
+	id INTEGER NOT NULL PRIMARY KEY,
+	actor TEXT NOT NULL,
+	action TEXT NOT NULL,
+	method TEXT NOT NULL,
+	path TEXT NOT NULL,
+	status INTEGER NOT NULL,
+	created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX activity_actor ON activity (actor, created_at);
+CREATE INDEX activity_action ON activity (action, created_at);
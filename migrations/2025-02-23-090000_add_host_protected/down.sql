@@ -0,0 +1,2 @@
+DROP TABLE host_confirmation;
+ALTER TABLE host DROP COLUMN protected;
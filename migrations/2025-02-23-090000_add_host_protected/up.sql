@@ -0,0 +1,13 @@
+-- deleting the host and deploying to it need a confirmation token from a preview
+ALTER TABLE host ADD COLUMN protected BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Confirmation tokens handed out by previews of destructive operations on protected hosts
+CREATE TABLE host_confirmation (
+	token TEXT NOT NULL PRIMARY KEY,
+	host_id INTEGER NOT NULL,
+	action TEXT NOT NULL,
+	login TEXT,
+	authorized_keys TEXT,
+	created_at TIMESTAMP NOT NULL,
+	FOREIGN KEY (host_id) REFERENCES host(id) ON DELETE CASCADE
+);
@@ -0,0 +1,11 @@
+-- Ad-hoc change freezes, deployments are rejected between starts_at and ends_at
+CREATE TABLE freeze_window (
+	id INTEGER NOT NULL PRIMARY KEY,
+	starts_at TIMESTAMP NOT NULL,
+	ends_at TIMESTAMP NOT NULL,
+	reason TEXT NOT NULL,
+	created_by TEXT NOT NULL,
+	created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX freeze_window_ends_at ON freeze_window (ends_at);
@@ -0,0 +1,2 @@
+-- comma separated SHA256 fingerprints of keys that may stay in the authorized_keys files without being managed
+ALTER TABLE host ADD COLUMN tolerated_keys TEXT NOT NULL DEFAULT '';
@@ -0,0 +1 @@
+ALTER TABLE key_history DROP COLUMN previous_username;
@@ -0,0 +1,2 @@
+-- owner of the key before it was transferred to another user
+ALTER TABLE key_history ADD COLUMN previous_username TEXT;
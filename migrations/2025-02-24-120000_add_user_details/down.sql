@@ -0,0 +1,5 @@
+DROP INDEX user_external_id;
+ALTER TABLE user DROP COLUMN external_id;
+ALTER TABLE user DROP COLUMN department;
+ALTER TABLE user DROP COLUMN full_name;
+ALTER TABLE user DROP COLUMN email;
@@ -0,0 +1,8 @@
+-- who a user is, for reports and notifications
+ALTER TABLE user ADD COLUMN email TEXT;
+ALTER TABLE user ADD COLUMN full_name TEXT;
+ALTER TABLE user ADD COLUMN department TEXT;
+-- id of the user at the identity provider
+ALTER TABLE user ADD COLUMN external_id TEXT;
+
+CREATE UNIQUE INDEX user_external_id ON user(external_id);
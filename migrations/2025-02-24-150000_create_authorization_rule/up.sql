@@ -0,0 +1,12 @@
+-- Authorizations granted by attributes: users of a department get a login on hosts with a tag
+CREATE TABLE authorization_rule (
+	id INTEGER NOT NULL PRIMARY KEY,
+	department TEXT NOT NULL,
+	tag TEXT NOT NULL,
+	login TEXT NOT NULL,
+	options TEXT,
+	created_by TEXT NOT NULL,
+	created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX authorization_rule_tag ON authorization_rule (tag);
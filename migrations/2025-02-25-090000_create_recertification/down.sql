@@ -0,0 +1,2 @@
+DROP TABLE recertification_item;
+DROP TABLE recertification_campaign;
@@ -0,0 +1,32 @@
+-- Periodic reviews of who may log in where
+CREATE TABLE recertification_campaign (
+	id INTEGER NOT NULL PRIMARY KEY,
+	name TEXT NOT NULL,
+	-- Authorizations on hosts with this tag are reviewed, on all hosts if NULL
+	tag TEXT,
+	deadline TIMESTAMP NOT NULL,
+	-- Revoke authorizations that weren't reviewed by the deadline instead of flagging them
+	auto_revoke BOOLEAN NOT NULL DEFAULT FALSE,
+	created_by TEXT NOT NULL,
+	created_at TIMESTAMP NOT NULL,
+	closed_at TIMESTAMP
+);
+
+-- One authorization to review. Host, user and login are copied, so the report
+-- still shows them after the authorization was revoked
+CREATE TABLE recertification_item (
+	id INTEGER NOT NULL PRIMARY KEY,
+	campaign_id INTEGER NOT NULL REFERENCES recertification_campaign(id) ON DELETE CASCADE,
+	authorization_id INTEGER NOT NULL,
+	host TEXT NOT NULL,
+	username TEXT NOT NULL,
+	login TEXT NOT NULL,
+	options TEXT,
+	-- approved, revoked, auto_revoked or overdue, NULL while pending
+	decision TEXT,
+	reviewer TEXT,
+	reviewed_at TIMESTAMP,
+	comment TEXT
+);
+
+CREATE INDEX recertification_item_campaign ON recertification_item (campaign_id);
@@ -0,0 +1,2 @@
+ALTER TABLE authorization DROP COLUMN principals;
+ALTER TABLE host DROP COLUMN principals_file;
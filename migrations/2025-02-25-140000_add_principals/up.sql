@@ -0,0 +1,4 @@
+-- authorized_principals file managed on hosts trusting a user CA, with %h and %u like AuthorizedPrincipalsFile
+ALTER TABLE host ADD COLUMN principals_file TEXT;
+-- comma separated certificate principals the login accepts for the user
+ALTER TABLE authorization ADD COLUMN principals TEXT;
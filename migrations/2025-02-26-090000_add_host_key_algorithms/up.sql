@@ -0,0 +1,2 @@
+-- comma separated key algorithms the sshd of the host accepts, keys of other algorithms aren't deployed to it
+ALTER TABLE host ADD COLUMN key_algorithms TEXT NOT NULL DEFAULT '';
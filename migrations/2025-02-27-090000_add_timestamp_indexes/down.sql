@@ -0,0 +1,8 @@
+DROP INDEX recertification_campaign_deadline;
+DROP INDEX freeze_window_starts_at;
+DROP INDEX host_confirmation_created_at;
+DROP INDEX pending_host_created_at;
+DROP INDEX security_event_created_at;
+DROP INDEX authorization_history_changed_at;
+DROP INDEX key_history_changed_at;
+DROP INDEX activity_created_at;
@@ -0,0 +1,9 @@
+-- time range filters and newest first listings that don't filter by anything else
+CREATE INDEX activity_created_at ON activity (created_at, id);
+CREATE INDEX key_history_changed_at ON key_history (changed_at, id);
+CREATE INDEX authorization_history_changed_at ON authorization_history (changed_at, id);
+CREATE INDEX security_event_created_at ON security_event (created_at, id);
+CREATE INDEX pending_host_created_at ON pending_host (created_at);
+CREATE INDEX host_confirmation_created_at ON host_confirmation (created_at);
+CREATE INDEX freeze_window_starts_at ON freeze_window (starts_at);
+CREATE INDEX recertification_campaign_deadline ON recertification_campaign (deadline);
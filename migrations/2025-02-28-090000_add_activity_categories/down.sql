@@ -0,0 +1,4 @@
+DROP TABLE activity_category;
+DROP INDEX activity_category_created_at;
+ALTER TABLE activity DROP COLUMN severity;
+ALTER TABLE activity DROP COLUMN category;
@@ -0,0 +1,2 @@
+-- configured connection plugin whose stdin and stdout carry the SSH connection instead of TCP
+ALTER TABLE host ADD COLUMN connection_plugin TEXT;
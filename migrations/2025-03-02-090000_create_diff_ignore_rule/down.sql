@@ -0,0 +1 @@
+DROP TABLE diff_ignore_rule;
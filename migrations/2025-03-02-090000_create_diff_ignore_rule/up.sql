@@ -0,0 +1,13 @@
+-- Lines of authorized_keys files the diff doesn't report, e.g. keys a cloud provider injects
+CREATE TABLE diff_ignore_rule (
+	id INTEGER NOT NULL PRIMARY KEY,
+	-- NULL applies to every host
+	host_id INTEGER,
+	-- NULL applies to every login
+	login TEXT,
+	-- regular expression, searched for in the line
+	pattern TEXT NOT NULL,
+	created_by TEXT NOT NULL,
+	created_at TIMESTAMP NOT NULL,
+	FOREIGN KEY (host_id) REFERENCES host(id) ON DELETE CASCADE
+);
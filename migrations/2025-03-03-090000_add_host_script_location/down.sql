@@ -0,0 +1,2 @@
+ALTER TABLE host DROP COLUMN script_interpreter;
+ALTER TABLE host DROP COLUMN script_path;
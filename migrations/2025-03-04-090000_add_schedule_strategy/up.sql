@@ -0,0 +1,2 @@
+-- how a deploy job rolls out the queued logins: all, canary:<hosts> or canary:tag
+ALTER TABLE schedule ADD COLUMN strategy TEXT;
@@ -0,0 +1,16 @@
+DROP TABLE recertification_item;
+DROP TABLE recertification_campaign;
+DROP TABLE authorization_rule;
+DROP TABLE freeze_window;
+DROP TABLE host_confirmation;
+DROP TABLE activity;
+DROP TABLE event_outbox;
+DROP TABLE security_event;
+DROP TABLE pending_host;
+DROP TABLE key_history;
+DROP TABLE authorization_history;
+DROP TABLE schedule;
+DROP TABLE user_key;
+DROP TABLE "authorization";
+DROP TABLE "user";
+DROP TABLE host;
@@ -0,0 +1,242 @@
+-- The schema of all SQLite migrations up to 2025-02-27-090000_add_timestamp_indexes in one.
+-- Every change after it needs a migration here as well as in migrations/.
+-- user and authorization are reserved words in PostgreSQL, so they are quoted.
+-- Timestamps are UTC without an offset like in SQLite.
+
+CREATE TABLE host (
+	id SERIAL PRIMARY KEY,
+	name TEXT UNIQUE NOT NULL,
+	username TEXT NOT NULL,
+	address TEXT NOT NULL,
+	port INTEGER NOT NULL,
+	key_fingerprint TEXT,
+	jump_via INTEGER REFERENCES host(id) ON DELETE CASCADE,
+	-- how authorized_keys files are read and written (script, exec or sftp)
+	transport TEXT NOT NULL DEFAULT 'script',
+	-- comma separated tags for grouping hosts
+	tags TEXT NOT NULL DEFAULT '',
+	pre_deploy_hook TEXT,
+	post_deploy_hook TEXT,
+	-- NULL uses the address_family of the ssh configuration
+	address_family TEXT,
+	-- socks5://host:port or http://host:port, used instead of a direct connection
+	proxy TEXT,
+	-- comma separated, in the order they are tried after address
+	fallback_addresses TEXT NOT NULL DEFAULT '',
+	-- comma separated alternative names
+	aliases TEXT NOT NULL DEFAULT '',
+	-- comma separated host key fingerprints accepted besides key_fingerprint
+	additional_key_fingerprints TEXT NOT NULL DEFAULT '',
+	-- host key fingerprint offered instead of an accepted one, kept until the host keys are changed
+	key_mismatch TEXT,
+	-- deleting the host and deploying to it need a confirmation token from a preview
+	protected BOOLEAN NOT NULL DEFAULT FALSE,
+	-- comma separated SHA256 fingerprints of keys that may stay in the authorized_keys files without being managed
+	tolerated_keys TEXT NOT NULL DEFAULT '',
+	-- authorized_principals file managed on hosts trusting a user CA, with %h and %u like AuthorizedPrincipalsFile
+	principals_file TEXT,
+	-- comma separated key algorithms the sshd of the host accepts, keys of other algorithms aren't deployed to it
+	key_algorithms TEXT NOT NULL DEFAULT '',
+	CONSTRAINT unique_address_port UNIQUE (address, port)
+);
+
+CREATE TABLE "user" (
+	id SERIAL PRIMARY KEY,
+	username TEXT UNIQUE NOT NULL,
+	enabled BOOLEAN NOT NULL DEFAULT TRUE,
+	-- who a user is, for reports and notifications
+	email TEXT,
+	full_name TEXT,
+	department TEXT,
+	-- id of the user at the identity provider
+	external_id TEXT
+);
+
+CREATE UNIQUE INDEX user_external_id ON "user" (external_id);
+
+CREATE TABLE "authorization" (
+	id SERIAL PRIMARY KEY,
+	host_id INTEGER NOT NULL REFERENCES host(id) ON DELETE CASCADE,
+	user_id INTEGER NOT NULL REFERENCES "user"(id) ON DELETE CASCADE,
+	login TEXT NOT NULL,
+	options TEXT,
+	-- comma separated certificate principals the login accepts for the user
+	principals TEXT,
+	UNIQUE (user_id, host_id, login)
+);
+
+CREATE TABLE user_key (
+	id SERIAL PRIMARY KEY,
+	key_type TEXT NOT NULL,
+	key_base64 TEXT UNIQUE NOT NULL,
+	comment TEXT,
+	user_id INTEGER NOT NULL REFERENCES "user"(id) ON DELETE CASCADE
+);
+
+CREATE TABLE schedule (
+	id SERIAL PRIMARY KEY,
+	name TEXT UNIQUE NOT NULL,
+	cron TEXT NOT NULL,
+	job TEXT NOT NULL,
+	tag TEXT
+);
+
+CREATE TABLE authorization_history (
+	id SERIAL PRIMARY KEY,
+	authorization_id INTEGER NOT NULL,
+	action TEXT NOT NULL,
+	host_id INTEGER NOT NULL,
+	host_name TEXT NOT NULL,
+	user_id INTEGER NOT NULL,
+	username TEXT NOT NULL,
+	login TEXT NOT NULL,
+	options TEXT,
+	actor TEXT NOT NULL,
+	changed_at TIMESTAMP NOT NULL,
+	old_login TEXT,
+	old_options TEXT
+);
+
+CREATE INDEX authorization_history_host ON authorization_history (host_id, changed_at);
+CREATE INDEX authorization_history_changed_at ON authorization_history (changed_at, id);
+
+CREATE TABLE key_history (
+	id SERIAL PRIMARY KEY,
+	key_id INTEGER,
+	key_base64 TEXT NOT NULL,
+	action TEXT NOT NULL,
+	username TEXT,
+	host_name TEXT,
+	login TEXT,
+	actor TEXT NOT NULL,
+	changed_at TIMESTAMP NOT NULL,
+	-- owner of the key before it was transferred to another user
+	previous_username TEXT
+);
+
+CREATE INDEX key_history_key ON key_history (key_base64, changed_at);
+CREATE INDEX key_history_changed_at ON key_history (changed_at, id);
+
+-- Hosts whose offered host key still has to be confirmed, referenced by a random token
+CREATE TABLE pending_host (
+	token TEXT PRIMARY KEY,
+	name TEXT NOT NULL,
+	username TEXT NOT NULL,
+	address TEXT NOT NULL,
+	port INTEGER NOT NULL,
+	key_fingerprint TEXT NOT NULL,
+	jump_via INTEGER REFERENCES host(id) ON DELETE CASCADE,
+	transport TEXT NOT NULL,
+	tags TEXT NOT NULL,
+	proxy TEXT,
+	created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX pending_host_created_at ON pending_host (created_at);
+
+CREATE TABLE security_event (
+	id SERIAL PRIMARY KEY,
+	kind TEXT NOT NULL,
+	severity TEXT NOT NULL,
+	host_name TEXT,
+	message TEXT NOT NULL,
+	created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX security_event_created_at ON security_event (created_at, id);
+
+-- events waiting to be published to the message bus, deleted once published
+CREATE TABLE event_outbox (
+	id SERIAL PRIMARY KEY,
+	event TEXT NOT NULL,
+	payload TEXT NOT NULL,
+	created_at TIMESTAMP NOT NULL
+);
+
+-- mutating requests with who made them, for reviewing one operator's actions
+CREATE TABLE activity (
+	id SERIAL PRIMARY KEY,
+	actor TEXT NOT NULL,
+	action TEXT NOT NULL,
+	method TEXT NOT NULL,
+	path TEXT NOT NULL,
+	status INTEGER NOT NULL,
+	created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX activity_actor ON activity (actor, created_at);
+CREATE INDEX activity_action ON activity (action, created_at);
+CREATE INDEX activity_created_at ON activity (created_at, id);
+
+-- Confirmation tokens handed out by previews of destructive operations on protected hosts
+CREATE TABLE host_confirmation (
+	token TEXT PRIMARY KEY,
+	host_id INTEGER NOT NULL REFERENCES host(id) ON DELETE CASCADE,
+	action TEXT NOT NULL,
+	login TEXT,
+	authorized_keys TEXT,
+	created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX host_confirmation_created_at ON host_confirmation (created_at);
+
+-- Ad-hoc change freezes, deployments are rejected between starts_at and ends_at
+CREATE TABLE freeze_window (
+	id SERIAL PRIMARY KEY,
+	starts_at TIMESTAMP NOT NULL,
+	ends_at TIMESTAMP NOT NULL,
+	reason TEXT NOT NULL,
+	created_by TEXT NOT NULL,
+	created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX freeze_window_ends_at ON freeze_window (ends_at);
+CREATE INDEX freeze_window_starts_at ON freeze_window (starts_at);
+
+-- Authorizations granted by attributes: users of a department get a login on hosts with a tag
+CREATE TABLE authorization_rule (
+	id SERIAL PRIMARY KEY,
+	department TEXT NOT NULL,
+	tag TEXT NOT NULL,
+	login TEXT NOT NULL,
+	options TEXT,
+	created_by TEXT NOT NULL,
+	created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX authorization_rule_tag ON authorization_rule (tag);
+
+-- Periodic reviews of who may log in where
+CREATE TABLE recertification_campaign (
+	id SERIAL PRIMARY KEY,
+	name TEXT NOT NULL,
+	-- Authorizations on hosts with this tag are reviewed, on all hosts if NULL
+	tag TEXT,
+	deadline TIMESTAMP NOT NULL,
+	-- Revoke authorizations that weren't reviewed by the deadline instead of flagging them
+	auto_revoke BOOLEAN NOT NULL DEFAULT FALSE,
+	created_by TEXT NOT NULL,
+	created_at TIMESTAMP NOT NULL,
+	closed_at TIMESTAMP
+);
+
+CREATE INDEX recertification_campaign_deadline ON recertification_campaign (deadline);
+
+-- One authorization to review. Host, user and login are copied, so the report
+-- still shows them after the authorization was revoked
+CREATE TABLE recertification_item (
+	id SERIAL PRIMARY KEY,
+	campaign_id INTEGER NOT NULL REFERENCES recertification_campaign(id) ON DELETE CASCADE,
+	authorization_id INTEGER NOT NULL,
+	host TEXT NOT NULL,
+	username TEXT NOT NULL,
+	login TEXT NOT NULL,
+	options TEXT,
+	-- approved, revoked, auto_revoked or overdue, NULL while pending
+	decision TEXT,
+	reviewer TEXT,
+	reviewed_at TIMESTAMP,
+	comment TEXT
+);
+
+CREATE INDEX recertification_item_campaign ON recertification_item (campaign_id);
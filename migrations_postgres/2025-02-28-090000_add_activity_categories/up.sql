@@ -0,0 +1,30 @@
+-- category of the action and its severity when the request was recorded
+ALTER TABLE activity ADD COLUMN category TEXT NOT NULL DEFAULT '';
+ALTER TABLE activity ADD COLUMN severity TEXT NOT NULL DEFAULT 'info';
+
+UPDATE activity SET category = CASE
+	WHEN action = 'deploy' THEN 'deploy'
+	WHEN action LIKE 'host.%' THEN 'hosts'
+	WHEN action LIKE 'authorization.%' THEN 'authorizations'
+	WHEN action LIKE 'user.%' THEN 'users'
+	WHEN action LIKE 'key.%' THEN 'keys'
+	WHEN action LIKE 'rule.%' THEN 'rules'
+	WHEN action LIKE 'recertification.%' THEN 'recertification'
+	WHEN action LIKE 'freeze.%' THEN 'freezes'
+	WHEN action LIKE 'schedule.%' THEN 'schedules'
+	WHEN action LIKE 'cache.%' THEN 'cache'
+	WHEN action LIKE 'orphans.%' THEN 'reports'
+	ELSE 'settings'
+END;
+UPDATE activity SET severity = 'notice'
+	WHERE category IN ('deploy', 'hosts', 'authorizations', 'users', 'keys', 'rules', 'recertification');
+UPDATE activity SET severity = 'warning' WHERE category IN ('reports', 'settings');
+
+CREATE INDEX activity_category_created_at ON activity (category, created_at);
+
+-- Categories changed through the settings API, the others use their defaults
+CREATE TABLE activity_category (
+	category TEXT NOT NULL PRIMARY KEY,
+	enabled BOOLEAN NOT NULL,
+	severity TEXT NOT NULL
+);
@@ -0,0 +1,12 @@
+-- Lines of authorized_keys files the diff doesn't report, e.g. keys a cloud provider injects
+CREATE TABLE diff_ignore_rule (
+	id SERIAL PRIMARY KEY,
+	-- NULL applies to every host
+	host_id INTEGER REFERENCES host(id) ON DELETE CASCADE,
+	-- NULL applies to every login
+	login TEXT,
+	-- regular expression, searched for in the line
+	pattern TEXT NOT NULL,
+	created_by TEXT NOT NULL,
+	created_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,4 @@
+-- where the script of the script transport is installed, relative to the home directory or absolute
+ALTER TABLE host ADD COLUMN script_path TEXT;
+-- command the script is run with, like bash or busybox sh, instead of its shebang line
+ALTER TABLE host ADD COLUMN script_interpreter TEXT;
@@ -0,0 +1,121 @@
+//! Role assignments restricting what logins of the Web UI and API may change
+use std::collections::{HashMap, HashSet};
+
+use serde::Deserialize;
+
+use crate::{i18n::Message, models::Host};
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// May change everything
+    Admin,
+    /// May change hosts with the tag of the assignment, everything else is read-only
+    Operator,
+    /// May deploy during a change freeze, in addition to other roles
+    BreakFreeze,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RoleAssignment {
+    /// Login from the htpasswd file
+    user: String,
+    role: Role,
+    /// Hosts the role applies to, required for operators and ignored otherwise
+    tag: Option<String>,
+}
+
+/// Actions of the activity log operators may perform, changes to a host
+/// are further limited to hosts with their tags by the handlers
+const OPERATOR_ACTIONS: [&str; 12] = [
+    "deploy",
+    "host.create",
+    "host.update",
+    "host.delete",
+    "host.hostkey.add",
+    "host.hostkey.remove",
+    "authorization.create",
+    "authorization.update",
+    "authorization.delete",
+    "cache.invalidate",
+    "cache.warm",
+    "auth.change_password",
+];
+
+/// Logins without a role assignment are admins
+#[derive(Debug, Default)]
+pub struct AccessControl {
+    admins: HashSet<String>,
+    /// Tags of the hosts each operator may change
+    operators: HashMap<String, HashSet<String>>,
+    /// Logins that may deploy during a change freeze
+    freeze_breakers: HashSet<String>,
+}
+
+impl AccessControl {
+    /// Checks the role assignments, exiting if one is invalid
+    pub fn new(assignments: &[RoleAssignment]) -> Self {
+        let mut access = Self::default();
+        for assignment in assignments {
+            match (assignment.role, &assignment.tag) {
+                (Role::Admin, _) => {
+                    access.admins.insert(assignment.user.clone());
+                }
+                (Role::Operator, Some(tag)) if !tag.trim().is_empty() => {
+                    access
+                        .operators
+                        .entry(assignment.user.clone())
+                        .or_default()
+                        .insert(tag.trim().to_owned());
+                }
+                (Role::BreakFreeze, _) => {
+                    access.freeze_breakers.insert(assignment.user.clone());
+                }
+                (Role::Operator, _) => {
+                    eprintln!(
+                        "The operator role of '{}' needs a tag, e.g. tag = \"staging\"",
+                        assignment.user
+                    );
+                    std::process::exit(3);
+                }
+            }
+        }
+        access
+    }
+
+    /// Tags of the hosts this login may change, `None` if it may change everything
+    fn operator_tags(&self, user: &str) -> Option<&HashSet<String>> {
+        if self.admins.contains(user) {
+            return None;
+        }
+        self.operators.get(user)
+    }
+
+    /// Whether the login may perform an action of the activity log at all.
+    /// Operators are limited to actions on hosts, which also need [`Self::may_change_host`].
+    pub fn may_perform(&self, user: &str, action: &str) -> bool {
+        self.operator_tags(user).is_none() || OPERATOR_ACTIONS.contains(&action)
+    }
+
+    /// Whether the login may change this host, its authorizations and deploy to it
+    pub fn may_change_host(&self, user: &str, host: &Host) -> bool {
+        self.operator_tags(user)
+            .is_none_or(|tags| host.tag_list().any(|tag| tags.contains(tag)))
+    }
+
+    /// Whether the login may give a host these comma separated tags
+    pub fn may_use_tags(&self, user: &str, tags: &str) -> bool {
+        self.operator_tags(user)
+            .is_none_or(|allowed| tags.split(',').any(|tag| allowed.contains(tag.trim())))
+    }
+
+    /// Whether the login may deploy during a change freeze, which needs an explicit assignment
+    pub fn may_break_freeze(&self, user: &str) -> bool {
+        self.freeze_breakers.contains(user)
+    }
+
+    /// Message for requests that were refused
+    pub fn denied(user: &str, host: &str) -> Message {
+        Message::new("access.host_denied").arg(user).arg(host)
+    }
+}
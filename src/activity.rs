@@ -0,0 +1,295 @@
+//! What the activity log records. Every action belongs to a category, which can be turned off
+//! and gives the recorded requests their severity. Read-only requests are only recorded if
+//! their category is turned on.
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::history::now,
+    models::{Activity, ActivityCategorySetting, NewActivity},
+    DbConnection,
+};
+
+/// Action of read-only requests
+pub const READ_ACTION: &str = "read";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Debug,
+    Info,
+    Notice,
+    Warning,
+}
+
+impl Severity {
+    const ALL: [Self; 4] = [Self::Debug, Self::Info, Self::Notice, Self::Warning];
+
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Debug => "debug",
+            Self::Info => "info",
+            Self::Notice => "notice",
+            Self::Warning => "warning",
+        }
+    }
+
+    /// This severity and all higher ones, for filtering
+    pub fn and_above(self) -> Vec<&'static str> {
+        Self::ALL
+            .into_iter()
+            .filter(|severity| *severity >= self)
+            .map(Self::as_str)
+            .collect()
+    }
+}
+
+impl FromStr for Severity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|severity| severity.as_str().eq(s))
+            .ok_or_else(|| {
+                format!("Unknown severity '{s}', expected debug, info, notice or warning")
+            })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActivityCategory {
+    Deploy,
+    Hosts,
+    Authorizations,
+    Users,
+    Keys,
+    Rules,
+    Recertification,
+    Freezes,
+    Schedules,
+    Cache,
+    Reports,
+    Settings,
+    Reads,
+}
+
+impl ActivityCategory {
+    const ALL: [Self; 13] = [
+        Self::Deploy,
+        Self::Hosts,
+        Self::Authorizations,
+        Self::Users,
+        Self::Keys,
+        Self::Rules,
+        Self::Recertification,
+        Self::Freezes,
+        Self::Schedules,
+        Self::Cache,
+        Self::Reports,
+        Self::Settings,
+        Self::Reads,
+    ];
+
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Deploy => "deploy",
+            Self::Hosts => "hosts",
+            Self::Authorizations => "authorizations",
+            Self::Users => "users",
+            Self::Keys => "keys",
+            Self::Rules => "rules",
+            Self::Recertification => "recertification",
+            Self::Freezes => "freezes",
+            Self::Schedules => "schedules",
+            Self::Cache => "cache",
+            Self::Reports => "reports",
+            Self::Settings => "settings",
+            Self::Reads => "reads",
+        }
+    }
+
+    /// The category of an action, by the part before the first dot
+    pub fn of(action: &str) -> Self {
+        match action.split('.').next().unwrap_or_default() {
+            "deploy" => Self::Deploy,
+            "host" => Self::Hosts,
+            "authorization" => Self::Authorizations,
+            "user" => Self::Users,
+            "key" => Self::Keys,
+            "rule" => Self::Rules,
+            "recertification" => Self::Recertification,
+            "freeze" => Self::Freezes,
+            "schedule" => Self::Schedules,
+            "cache" => Self::Cache,
+            "orphans" => Self::Reports,
+            READ_ACTION => Self::Reads,
+            _ => Self::Settings,
+        }
+    }
+
+    /// Changes are recorded by default, reads aren't
+    const fn default_setting(self) -> CategorySetting {
+        let severity = match self {
+            Self::Deploy
+            | Self::Hosts
+            | Self::Authorizations
+            | Self::Users
+            | Self::Keys
+            | Self::Rules
+            | Self::Recertification => Severity::Notice,
+            Self::Freezes | Self::Schedules | Self::Cache => Severity::Info,
+            Self::Reports | Self::Settings => Severity::Warning,
+            Self::Reads => Severity::Debug,
+        };
+        CategorySetting {
+            enabled: !matches!(self, Self::Reads),
+            severity,
+        }
+    }
+}
+
+impl FromStr for ActivityCategory {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|category| category.as_str().eq(s))
+            .ok_or_else(|| format!("Unknown activity category '{s}'"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct CategorySetting {
+    pub enabled: bool,
+    pub severity: Severity,
+}
+
+/// A category with its current setting, as listed by the settings API
+#[derive(Debug, Serialize)]
+pub struct CategoryState {
+    pub category: ActivityCategory,
+    #[serde(flatten)]
+    pub setting: CategorySetting,
+    /// Whether the setting was changed from the default
+    pub changed: bool,
+}
+
+/// The settings of all categories, kept in memory so requests can be checked without a query
+#[derive(Debug, Default)]
+pub struct ActivityLog {
+    changed: RwLock<HashMap<ActivityCategory, CategorySetting>>,
+}
+
+impl ActivityLog {
+    /// Loads the changed settings, stored ones of unknown categories are ignored
+    pub fn load(conn: &mut DbConnection) -> Result<Self, String> {
+        let changed = ActivityCategorySetting::all(conn)?
+            .into_iter()
+            .filter_map(|stored| {
+                Some((
+                    stored.category.parse().ok()?,
+                    CategorySetting {
+                        enabled: stored.enabled,
+                        severity: stored.severity.parse().ok()?,
+                    },
+                ))
+            })
+            .collect();
+        Ok(Self {
+            changed: RwLock::new(changed),
+        })
+    }
+
+    pub fn setting(&self, category: ActivityCategory) -> CategorySetting {
+        self.changed
+            .read()
+            .ok()
+            .and_then(|changed| changed.get(&category).copied())
+            .unwrap_or_else(|| category.default_setting())
+    }
+
+    pub fn states(&self) -> Vec<CategoryState> {
+        let changed = self
+            .changed
+            .read()
+            .map(|changed| changed.clone())
+            .unwrap_or_default();
+        ActivityCategory::ALL
+            .into_iter()
+            .map(|category| {
+                let stored = changed.get(&category).copied();
+                CategoryState {
+                    category,
+                    setting: stored.unwrap_or_else(|| category.default_setting()),
+                    changed: stored.is_some(),
+                }
+            })
+            .collect()
+    }
+
+    /// Stores the setting of a category, `None` goes back to the default
+    pub fn update(
+        &self,
+        conn: &mut DbConnection,
+        category: ActivityCategory,
+        setting: Option<CategorySetting>,
+    ) -> Result<(), String> {
+        let stored = setting.map(|setting| ActivityCategorySetting {
+            category: category.as_str().to_owned(),
+            enabled: setting.enabled,
+            severity: setting.severity.as_str().to_owned(),
+        });
+        ActivityCategorySetting::store(conn, category.as_str(), stored)?;
+
+        let mut changed = self
+            .changed
+            .write()
+            .map_err(|_| "The activity settings are poisoned".to_owned())?;
+        match setting {
+            Some(setting) => changed.insert(category, setting),
+            None => changed.remove(&category),
+        };
+        Ok(())
+    }
+
+    /// Whether read-only requests are recorded, to skip them without looking at the request
+    pub fn records_reads(&self) -> bool {
+        self.setting(ActivityCategory::Reads).enabled
+    }
+
+    /// Records a request unless its category is turned off
+    pub fn record(
+        &self,
+        conn: &mut DbConnection,
+        actor: &str,
+        action: &str,
+        method: &str,
+        path: &str,
+        status: u16,
+    ) -> Result<(), String> {
+        let category = ActivityCategory::of(action);
+        let setting = self.setting(category);
+        if !setting.enabled {
+            return Ok(());
+        }
+        Activity::record(
+            conn,
+            NewActivity {
+                actor: actor.to_owned(),
+                action: action.to_owned(),
+                method: method.to_owned(),
+                path: path.to_owned(),
+                status: status.into(),
+                created_at: now(),
+                category: category.as_str().to_owned(),
+                severity: setting.severity.as_str().to_owned(),
+            },
+        )
+    }
+}
@@ -0,0 +1,339 @@
+//! Publishes events from the outbox to NATS or Kafka, so other systems learn about changes without polling
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+use crate::{models::OutboxEvent, ConnectionPool};
+
+/// How often the outbox is checked for new events
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait before trying again after publishing failed
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+/// How long connecting or publishing a batch may take
+const BUS_TIMEOUT: Duration = Duration::from_secs(30);
+/// Most events published at once
+const BATCH_SIZE: i64 = 100;
+
+fn default_topic() -> String {
+    "ssm".to_owned()
+}
+
+fn all_events() -> Vec<String> {
+    vec!["*".to_owned()]
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct BusConfig {
+    /// `nats://[user:password@]host:port` or `kafka://host:port[,host:port]`
+    url: String,
+    /// Subject prefix for NATS (`<topic>.<event>`) or the Kafka topic (default ssm)
+    #[serde(default = "default_topic")]
+    topic: String,
+    /// Events that are published, `*` matches all (default all)
+    #[serde(default = "all_events")]
+    pub events: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+enum Target {
+    Nats {
+        address: String,
+        credentials: Option<(String, String)>,
+    },
+    #[cfg(feature = "kafka")]
+    Kafka { brokers: Vec<String> },
+}
+
+enum Connection {
+    Nats(NatsConnection),
+    #[cfg(feature = "kafka")]
+    Kafka(rskafka::client::partition::PartitionClient),
+}
+
+pub struct Publisher {
+    target: Target,
+    topic: String,
+    pool: ConnectionPool,
+}
+
+impl Publisher {
+    /// Checks the bus URL, exiting if it is invalid
+    pub fn new(config: &BusConfig, pool: ConnectionPool) -> Self {
+        let target = if let Some(rest) = config.url.strip_prefix("nats://") {
+            let (credentials, address) = match rest.rsplit_once('@') {
+                Some((credentials, address)) => {
+                    let (user, password) = credentials.split_once(':').unwrap_or((credentials, ""));
+                    (Some((user.to_owned(), password.to_owned())), address)
+                }
+                None => (None, rest),
+            };
+            let address = address.trim_end_matches('/');
+            let address = if address.contains(':') {
+                address.to_owned()
+            } else {
+                format!("{address}:4222")
+            };
+            Target::Nats {
+                address,
+                credentials,
+            }
+        } else if let Some(brokers) = config.url.strip_prefix("kafka://") {
+            #[cfg(feature = "kafka")]
+            {
+                Target::Kafka {
+                    brokers: brokers
+                        .trim_end_matches('/')
+                        .split(',')
+                        .map(str::to_owned)
+                        .collect(),
+                }
+            }
+            #[cfg(not(feature = "kafka"))]
+            {
+                eprintln!(
+                    "Publishing to Kafka ({brokers}) needs SSM to be built with the kafka feature."
+                );
+                std::process::exit(3);
+            }
+        } else {
+            eprintln!(
+                "Unsupported bus URL '{}', use nats://host:port or kafka://host:port",
+                config.url
+            );
+            std::process::exit(3);
+        };
+
+        Self {
+            target,
+            topic: config.topic.clone(),
+            pool,
+        }
+    }
+
+    /// Publishes events from the outbox as they come in, retrying until they are delivered
+    pub async fn run(self) {
+        info!("Publishing events to {}", self.describe());
+        let mut connection = None;
+        loop {
+            match tokio::time::timeout(BUS_TIMEOUT, self.publish_pending(&mut connection)).await {
+                // More events may be waiting
+                Ok(Ok(published)) if published as i64 == BATCH_SIZE => continue,
+                Ok(Ok(_)) => tokio::time::sleep(POLL_INTERVAL).await,
+                Ok(Err(e)) => {
+                    warn!("Failed to publish events to {}: {e}", self.describe());
+                    connection = None;
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                }
+                Err(_) => {
+                    warn!("Publishing events to {} timed out", self.describe());
+                    connection = None;
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        match &self.target {
+            Target::Nats { address, .. } => format!("NATS at {address}"),
+            #[cfg(feature = "kafka")]
+            Target::Kafka { brokers } => format!("Kafka at {}", brokers.join(",")),
+        }
+    }
+
+    /// Publishes a batch of events and removes them from the outbox. Returns how many were published.
+    async fn publish_pending(&self, connection: &mut Option<Connection>) -> Result<usize, String> {
+        let pool = self.pool.clone();
+        let events = actix_web::web::block(move || {
+            let mut conn = pool.get().map_err(|e| e.to_string())?;
+            OutboxEvent::pending(&mut conn, BATCH_SIZE)
+        })
+        .await
+        .map_err(|_| "Blocking error.".to_owned())??;
+        if events.is_empty() {
+            return Ok(0);
+        }
+
+        let connection = match connection {
+            Some(connection) => connection,
+            none => none.insert(self.connect().await?),
+        };
+        match connection {
+            Connection::Nats(nats) => nats.publish(&self.topic, &events).await?,
+            #[cfg(feature = "kafka")]
+            Connection::Kafka(client) => publish_kafka(client, &events).await?,
+        }
+
+        let ids: Vec<i32> = events.iter().map(|event| event.id).collect();
+        let pool = self.pool.clone();
+        actix_web::web::block(move || {
+            let mut conn = pool.get().map_err(|e| e.to_string())?;
+            OutboxEvent::remove(&mut conn, &ids)
+        })
+        .await
+        .map_err(|_| "Blocking error.".to_owned())??;
+        debug!(
+            "Published {} events, the oldest from {}",
+            events.len(),
+            events[0].created_at
+        );
+        Ok(events.len())
+    }
+
+    async fn connect(&self) -> Result<Connection, String> {
+        match &self.target {
+            Target::Nats {
+                address,
+                credentials,
+            } => NatsConnection::connect(address, credentials.as_ref())
+                .await
+                .map(Connection::Nats),
+            #[cfg(feature = "kafka")]
+            Target::Kafka { brokers } => {
+                use rskafka::client::{partition::UnknownTopicHandling, ClientBuilder};
+
+                let client = ClientBuilder::new(brokers.clone())
+                    .build()
+                    .await
+                    .map_err(|e| e.to_string())?;
+                client
+                    .partition_client(self.topic.clone(), 0, UnknownTopicHandling::Error)
+                    .await
+                    .map(Connection::Kafka)
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
+/// The event name is the record key, the payload is the same JSON hooks receive
+#[cfg(feature = "kafka")]
+async fn publish_kafka(
+    client: &rskafka::client::partition::PartitionClient,
+    events: &[OutboxEvent],
+) -> Result<(), String> {
+    use rskafka::{chrono::DateTime, client::partition::Compression, record::Record};
+
+    let records = events
+        .iter()
+        .map(|event| {
+            let created_at = event.created_at.assume_utc();
+            Record {
+                key: Some(event.event.clone().into_bytes()),
+                value: Some(event.payload.clone().into_bytes()),
+                headers: Default::default(),
+                timestamp: DateTime::from_timestamp(
+                    created_at.unix_timestamp(),
+                    created_at.nanosecond(),
+                )
+                .unwrap_or_default(),
+            }
+        })
+        .collect();
+    client
+        .produce(records, Compression::NoCompression)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Minimal NATS client, publishing is all that's needed
+struct NatsConnection {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+
+#[derive(serde::Serialize)]
+struct NatsConnect<'a> {
+    verbose: bool,
+    pedantic: bool,
+    name: &'static str,
+    lang: &'static str,
+    version: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pass: Option<&'a str>,
+}
+
+impl NatsConnection {
+    async fn connect(
+        address: &str,
+        credentials: Option<&(String, String)>,
+    ) -> Result<Self, String> {
+        let stream = TcpStream::connect(address)
+            .await
+            .map_err(|e| format!("Failed to connect: {e}"))?;
+        let (reader, writer) = stream.into_split();
+        let mut connection = Self {
+            reader: BufReader::new(reader),
+            writer,
+        };
+
+        let info = connection.read_line().await?;
+        if !info.starts_with("INFO ") {
+            return Err(format!("Not a NATS server, it sent '{info}'"));
+        }
+        if info.contains("\"tls_required\":true") {
+            return Err("The server requires TLS, which isn't supported".to_owned());
+        }
+
+        let connect = serde_json::to_string(&NatsConnect {
+            verbose: false,
+            pedantic: false,
+            name: "ssm",
+            lang: "rust",
+            version: env!("CARGO_PKG_VERSION"),
+            user: credentials.map(|(user, _)| user.as_str()),
+            pass: credentials.map(|(_, password)| password.as_str()),
+        })
+        .map_err(|e| e.to_string())?;
+        connection
+            .write(format!("CONNECT {connect}\r\n").as_bytes())
+            .await?;
+        connection.flush().await?;
+        Ok(connection)
+    }
+
+    /// Sends the events, then waits for the server to answer a PING so errors aren't missed
+    async fn publish(&mut self, topic: &str, events: &[OutboxEvent]) -> Result<(), String> {
+        for event in events {
+            let head = format!("PUB {topic}.{} {}\r\n", event.event, event.payload.len());
+            self.write(head.as_bytes()).await?;
+            self.write(event.payload.as_bytes()).await?;
+            self.write(b"\r\n").await?;
+        }
+        self.flush().await
+    }
+
+    async fn flush(&mut self) -> Result<(), String> {
+        self.write(b"PING\r\n").await?;
+        loop {
+            let line = self.read_line().await?;
+            match line.as_str() {
+                "PONG" => return Ok(()),
+                "PING" => self.write(b"PONG\r\n").await?,
+                line if line.starts_with("-ERR") => return Err(format!("NATS error: {line}")),
+                // +OK and INFO updates
+                _ => {}
+            }
+        }
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<(), String> {
+        self.writer.write_all(data).await.map_err(|e| e.to_string())
+    }
+
+    async fn read_line(&mut self) -> Result<String, String> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line).await {
+            Ok(0) => Err("Connection closed".to_owned()),
+            Ok(_) => Ok(line.trim_end().to_owned()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
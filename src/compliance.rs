@@ -0,0 +1,298 @@
+//! Policies the cached state of the hosts is checked against, grouped into packs
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use time::{Duration, OffsetDateTime, PrimitiveDateTime};
+
+use crate::ssh::{AuthorizedKey, AuthorizedKeyEntry, CacheInfo};
+
+/// Name of the pack with all policies, unless one is configured with this name
+pub const DEFAULT_PACK: &str = "default";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    /// No key but the one of ssm can log in as root
+    NoRootKeys,
+    /// Keys don't allow agent or port forwarding
+    NoForwarding,
+    /// Keys known to ssm are younger than `max_key_age_days`
+    MaxKeyAge,
+    /// Every authorized_keys file is managed by ssm
+    ManagedPragma,
+    /// Keys on hosts with one of the `security_key_tags` are backed by a FIDO2 security key
+    SecurityKeys,
+}
+
+impl Policy {
+    pub const ALL: [Self; 5] = [
+        Self::NoRootKeys,
+        Self::NoForwarding,
+        Self::MaxKeyAge,
+        Self::ManagedPragma,
+        Self::SecurityKeys,
+    ];
+
+    pub const fn description(self) -> &'static str {
+        match self {
+            Self::NoRootKeys => "No key but the one of ssm can log in as root",
+            Self::NoForwarding => "Keys don't allow agent or port forwarding",
+            Self::MaxKeyAge => "Keys are younger than the maximum key age",
+            Self::ManagedPragma => "Every authorized_keys file is managed by ssm",
+            Self::SecurityKeys => "Keys on hosts with a security key tag are security keys",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PolicyPack {
+    pub name: String,
+    pub policies: Vec<Policy>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ComplianceConfig {
+    /// Keys added to ssm longer ago violate `max_key_age` (default 365)
+    #[serde(default = "default_max_key_age_days")]
+    pub max_key_age_days: i64,
+    /// Hosts with one of these tags only accept keys backed by a security key, e.g. `sk-ssh-ed25519@openssh.com`
+    #[serde(default)]
+    pub security_key_tags: Vec<String>,
+    #[serde(default)]
+    pub packs: Vec<PolicyPack>,
+}
+
+const fn default_max_key_age_days() -> i64 {
+    365
+}
+
+impl Default for ComplianceConfig {
+    fn default() -> Self {
+        Self {
+            max_key_age_days: default_max_key_age_days(),
+            security_key_tags: Vec::new(),
+            packs: Vec::new(),
+        }
+    }
+}
+
+impl ComplianceConfig {
+    /// The configured packs and the default pack with all policies
+    pub fn packs(&self) -> Vec<PolicyPack> {
+        let mut packs = self.packs.clone();
+        if !packs.iter().any(|pack| pack.name.eq(DEFAULT_PACK)) {
+            packs.insert(
+                0,
+                PolicyPack {
+                    name: DEFAULT_PACK.to_owned(),
+                    policies: Policy::ALL.to_vec(),
+                },
+            );
+        }
+        packs
+    }
+
+    /// Checks the packs, called at startup
+    pub fn check(&self) -> Result<(), String> {
+        if self.max_key_age_days < 1 {
+            return Err("max_key_age_days has to be at least 1".to_owned());
+        }
+        for (i, pack) in self.packs.iter().enumerate() {
+            if self.packs[..i]
+                .iter()
+                .any(|other| other.name.eq(&pack.name))
+            {
+                return Err(format!("There is more than one pack named {}", pack.name));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn pack(&self, name: &str) -> Option<PolicyPack> {
+        self.packs().into_iter().find(|pack| pack.name.eq(name))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Violation {
+    pub policy: Policy,
+    pub login: String,
+    pub detail: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HostCompliance {
+    pub host: String,
+    /// Percentage of the policies of the pack the host complies with
+    pub score: u8,
+    pub passed: usize,
+    pub failed: usize,
+    /// When the evaluated state was fetched from the host
+    #[serde(with = "time::serde::rfc3339")]
+    pub checked_at: OffsetDateTime,
+    pub stale: bool,
+    pub violations: Vec<Violation>,
+}
+
+/// What a host is checked by: its logins with whether they have the pragma and their keys
+pub struct HostState {
+    pub host: String,
+    pub tags: Vec<String>,
+    pub cache: CacheInfo,
+    pub logins: Vec<(String, bool, Vec<AuthorizedKey>)>,
+}
+
+impl HostState {
+    /// Lines of the authorized_keys files that couldn't be parsed are left out
+    pub fn new(
+        host: String,
+        tags: Vec<String>,
+        cache: CacheInfo,
+        logins: impl IntoIterator<Item = (String, bool, Vec<AuthorizedKeyEntry>)>,
+    ) -> Self {
+        Self {
+            host,
+            tags,
+            cache,
+            logins: logins
+                .into_iter()
+                .map(|(login, has_pragma, entries)| {
+                    (login, has_pragma, entries.into_iter().flatten().collect())
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Facts the policies need besides the state of the host
+pub struct Context<'a> {
+    pub own_key_base64: &'a str,
+    /// When the keys known to ssm were added, by base64
+    pub key_created: &'a HashMap<String, PrimitiveDateTime>,
+    pub now: PrimitiveDateTime,
+    pub max_key_age: Duration,
+    pub security_key_tags: &'a [String],
+}
+
+/// The comment of a key, or its fingerprint if it has none
+pub fn key_name(key: &AuthorizedKey) -> String {
+    key.comment
+        .clone()
+        .or_else(|| key.fingerprint())
+        .unwrap_or_else(|| key.base64.clone())
+}
+
+/// Whether the options of a key allow agent or port forwarding
+fn allows_forwarding(key: &AuthorizedKey) -> bool {
+    let options: Vec<String> = key
+        .options
+        .iter()
+        .map(|option| {
+            option
+                .split('=')
+                .next()
+                .unwrap_or_default()
+                .to_ascii_lowercase()
+        })
+        .collect();
+    let has = |name: &str| options.iter().any(|option| option.eq(name));
+    if has("restrict") {
+        has("agent-forwarding") || has("port-forwarding")
+    } else {
+        !has("no-agent-forwarding") || !has("no-port-forwarding")
+    }
+}
+
+impl Policy {
+    fn check(self, state: &HostState, context: &Context, violations: &mut Vec<Violation>) {
+        if self == Self::SecurityKeys
+            && !state
+                .tags
+                .iter()
+                .any(|tag| context.security_key_tags.contains(tag))
+        {
+            return;
+        }
+        for (login, has_pragma, keys) in &state.logins {
+            let mut violation = |detail: String| {
+                violations.push(Violation {
+                    policy: self,
+                    login: login.clone(),
+                    detail,
+                });
+            };
+            match self {
+                Self::ManagedPragma => {
+                    if !has_pragma {
+                        violation("The authorized_keys file isn't managed by ssm".to_owned());
+                    }
+                }
+                Self::NoRootKeys | Self::NoForwarding | Self::MaxKeyAge | Self::SecurityKeys => {
+                    for key in keys
+                        .iter()
+                        .filter(|key| !key.base64.eq(context.own_key_base64))
+                    {
+                        match self {
+                            Self::NoRootKeys if login.eq("root") => {
+                                violation(format!("{} can log in as root", key_name(key)));
+                            }
+                            Self::NoForwarding if allows_forwarding(key) => {
+                                violation(format!("{} allows forwarding", key_name(key)));
+                            }
+                            Self::SecurityKeys if !key.is_security_key() => {
+                                violation(format!(
+                                    "{} is not a security key but {}",
+                                    key_name(key),
+                                    key.algorithm
+                                ));
+                            }
+                            Self::MaxKeyAge => {
+                                if let Some(created) = context.key_created.get(&key.base64) {
+                                    if context.now - *created > context.max_key_age {
+                                        violation(format!(
+                                            "{} was added {} days ago",
+                                            key_name(key),
+                                            (context.now - *created).whole_days()
+                                        ));
+                                    }
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl PolicyPack {
+    /// Checks a host against the policies of this pack. The score is the share of policies
+    /// without violations, 100 for a pack without policies.
+    pub fn evaluate(&self, state: HostState, context: &Context) -> HostCompliance {
+        let mut violations = Vec::new();
+        let mut failed = 0;
+        for policy in &self.policies {
+            let before = violations.len();
+            policy.check(&state, context, &mut violations);
+            if violations.len() > before {
+                failed += 1;
+            }
+        }
+        let passed = self.policies.len() - failed;
+        let score = if self.policies.is_empty() {
+            100
+        } else {
+            (passed * 100 / self.policies.len()) as u8
+        };
+        HostCompliance {
+            host: state.host,
+            score,
+            passed,
+            failed,
+            checked_at: state.cache.cached_at,
+            stale: state.cache.stale,
+            violations,
+        }
+    }
+}
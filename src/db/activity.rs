@@ -0,0 +1,114 @@
+use diesel::prelude::*;
+use time::PrimitiveDateTime;
+
+use crate::{
+    activity::Severity,
+    models::{Activity, ActivityCategorySetting, NewActivity},
+    schema::{activity, activity_category},
+    DbConnection,
+};
+
+use super::{query, query_drop};
+
+/// Filters for [`Activity::search`], all optional
+#[derive(Debug, Default)]
+pub struct ActivityFilter {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub category: Option<String>,
+    /// Requests recorded with this severity or a higher one
+    pub severity: Option<Severity>,
+    pub since: Option<PrimitiveDateTime>,
+    pub until: Option<PrimitiveDateTime>,
+}
+
+impl Activity {
+    /// Use [`crate::activity::ActivityLog::record`], which knows whether to record the request
+    pub fn record(conn: &mut DbConnection, activity: NewActivity) -> Result<(), String> {
+        query_drop(
+            diesel::insert_into(activity::table)
+                .values(activity)
+                .execute(conn),
+        )
+    }
+
+    /// Recorded requests matching the filter, newest first
+    pub fn search(
+        conn: &mut DbConnection,
+        filter: &ActivityFilter,
+        limit: i64,
+    ) -> Result<Vec<Self>, String> {
+        Self::search_before(conn, filter, None, limit)
+    }
+
+    /// Like [`Activity::search`], but only requests recorded before `before`, the `created_at`
+    /// and `id` of the last request of the previous page
+    pub fn search_before(
+        conn: &mut DbConnection,
+        filter: &ActivityFilter,
+        before: Option<(PrimitiveDateTime, i32)>,
+        limit: i64,
+    ) -> Result<Vec<Self>, String> {
+        let mut statement = activity::table.into_boxed();
+        if let Some(actor) = &filter.actor {
+            statement = statement.filter(activity::actor.eq(actor));
+        }
+        if let Some(action) = &filter.action {
+            statement = statement.filter(activity::action.eq(action));
+        }
+        if let Some(category) = &filter.category {
+            statement = statement.filter(activity::category.eq(category));
+        }
+        if let Some(severity) = filter.severity {
+            statement = statement.filter(activity::severity.eq_any(severity.and_above()));
+        }
+        if let Some(since) = filter.since {
+            statement = statement.filter(activity::created_at.ge(since));
+        }
+        if let Some(until) = filter.until {
+            statement = statement.filter(activity::created_at.lt(until));
+        }
+        if let Some((created_at, id)) = before {
+            statement = statement.filter(
+                activity::created_at
+                    .lt(created_at)
+                    .or(activity::created_at.eq(created_at).and(activity::id.lt(id))),
+            );
+        }
+        query(
+            statement
+                .order((activity::created_at.desc(), activity::id.desc()))
+                .limit(limit)
+                .load::<Self>(conn),
+        )
+    }
+}
+
+impl ActivityCategorySetting {
+    /// The categories whose setting was changed
+    pub fn all(conn: &mut DbConnection) -> Result<Vec<Self>, String> {
+        query(activity_category::table.load::<Self>(conn))
+    }
+
+    /// Replaces the stored setting of a category, `None` removes it
+    pub fn store(
+        conn: &mut DbConnection,
+        category: &str,
+        setting: Option<Self>,
+    ) -> Result<(), String> {
+        // A category without a stored setting changes nothing, which isn't an error
+        query(conn.transaction(|conn| {
+            diesel::delete(
+                activity_category::table.filter(activity_category::category.eq(category)),
+            )
+            .execute(conn)?;
+            match setting {
+                Some(setting) => diesel::insert_into(activity_category::table)
+                    .values(setting)
+                    .execute(conn),
+                None => Ok(0),
+            }
+        }))
+        .map(|_| ())
+    }
+}
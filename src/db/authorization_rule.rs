@@ -0,0 +1,122 @@
+use diesel::prelude::*;
+use ssh_key::authorized_keys::ConfigOpts;
+
+use crate::{
+    models::{AuthorizationRule, Host, NewAuthorizationRule, PublicUserKey},
+    schema::{authorization_rule, user, user_key},
+    DbConnection,
+};
+
+use super::history::now;
+use super::{query, query_drop, AllowedUserOnHost, RuleGrant};
+
+impl AuthorizationRule {
+    pub fn add(
+        conn: &mut DbConnection,
+        department: String,
+        tag: String,
+        login: String,
+        options: Option<String>,
+        created_by: &str,
+    ) -> Result<Self, String> {
+        let options = options.filter(|options| !options.trim().is_empty());
+        if let Some(Err(error)) = options.as_ref().map(ConfigOpts::new) {
+            return Err(format!("Invalid options: {error}"));
+        }
+        query(conn.transaction(|conn| {
+            diesel::insert_into(authorization_rule::table)
+                .values(NewAuthorizationRule {
+                    department,
+                    tag,
+                    login,
+                    options,
+                    created_by: created_by.to_owned(),
+                    created_at: now(),
+                })
+                .execute(conn)?;
+            authorization_rule::table
+                .order(authorization_rule::id.desc())
+                .first::<Self>(conn)
+        }))
+    }
+
+    pub fn all(conn: &mut DbConnection) -> Result<Vec<Self>, String> {
+        query(
+            authorization_rule::table
+                .order(authorization_rule::id)
+                .load::<Self>(conn),
+        )
+    }
+
+    pub fn delete(conn: &mut DbConnection, id: i32) -> Result<(), String> {
+        query_drop(
+            diesel::delete(authorization_rule::table.filter(authorization_rule::id.eq(id)))
+                .execute(conn),
+        )
+    }
+
+    /// Rules matching one of the tags of the host
+    pub fn for_host(conn: &mut DbConnection, host: &Host) -> Result<Vec<Self>, String> {
+        let tags: Vec<&str> = host.tag_list().collect();
+        if tags.is_empty() {
+            return Ok(Vec::new());
+        }
+        query(
+            authorization_rule::table
+                .filter(authorization_rule::tag.eq_any(tags))
+                .order(authorization_rule::id)
+                .load::<Self>(conn),
+        )
+    }
+}
+
+impl Host {
+    /// Users the authorization rules grant a login on this host, sorted by rule
+    pub fn get_rule_grants(&self, conn: &mut DbConnection) -> Result<Vec<RuleGrant>, String> {
+        let mut grants = Vec::new();
+        for rule in AuthorizationRule::for_host(conn, self)? {
+            let usernames = query(
+                user::table
+                    .filter(user::department.eq(&rule.department))
+                    .select(user::username)
+                    .order(user::username)
+                    .load::<String>(conn),
+            )?;
+            grants.extend(
+                usernames
+                    .into_iter()
+                    .map(|username| (rule.id, username, rule.login.clone(), rule.options.clone())),
+            );
+        }
+        Ok(grants)
+    }
+
+    /// Keys the authorization rules grant on this host
+    pub(super) fn get_rule_keys(
+        &self,
+        conn: &mut DbConnection,
+    ) -> Result<Vec<AllowedUserOnHost>, String> {
+        let mut keys = Vec::new();
+        for rule in AuthorizationRule::for_host(conn, self)? {
+            let granted = query(
+                user_key::table
+                    .inner_join(user::table)
+                    .filter(user::department.eq(&rule.department))
+                    .filter(user::enabled.eq(true))
+                    .select((PublicUserKey::as_select(), user::username))
+                    .load::<(PublicUserKey, String)>(conn),
+            )?;
+            keys.extend(
+                granted
+                    .into_iter()
+                    .map(|(key, username)| AllowedUserOnHost {
+                        key,
+                        login: rule.login.clone(),
+                        username,
+                        options: rule.options.clone(),
+                    }),
+            );
+        }
+        Ok(keys)
+    }
+}
@@ -0,0 +1,92 @@
+//! Copies of the whole database for the disaster recovery bundle
+use std::{fs, io::Write, os::unix::fs::OpenOptionsExt, path::PathBuf};
+
+use diesel::{sql_query, sql_types::Text, RunQueryDsl};
+use uuid::Uuid;
+
+use crate::DbConnection;
+
+use super::query;
+
+/// A file of the copy and its content
+pub type DatabaseFile = (String, Vec<u8>);
+
+#[cfg(feature = "postgres")]
+#[derive(diesel::QueryableByName)]
+struct TableName {
+    #[diesel(sql_type = Text)]
+    tablename: String,
+}
+
+#[cfg(feature = "postgres")]
+#[derive(diesel::QueryableByName)]
+struct TableRows {
+    #[diesel(sql_type = Text)]
+    rows: String,
+}
+
+/// A consistent copy of the database: the SQLite file as `ssm.db`, ready to be put in place of a
+/// lost one, or every PostgreSQL table as a JSON array of its rows in `database/<table>.json`
+pub fn database_files(conn: &mut DbConnection) -> Result<Vec<DatabaseFile>, String> {
+    match conn {
+        DbConnection::Sqlite(conn) => Ok(vec![(String::from("ssm.db"), sqlite_copy(conn)?)]),
+        #[cfg(feature = "postgres")]
+        DbConnection::Postgresql(conn) => postgres_tables(conn),
+        #[cfg(feature = "mysql")]
+        DbConnection::Mysql(_) => Err(String::from(
+            "Copies of MySQL databases are not supported, use mysqldump",
+        )),
+    }
+}
+
+/// Writes the database into an empty file only the owner can read, `VACUUM INTO` copies it
+/// without stopping writers
+fn sqlite_copy(conn: &mut diesel::SqliteConnection) -> Result<Vec<u8>, String> {
+    let path: PathBuf = std::env::temp_dir().join(format!("ssm-{}.db", Uuid::new_v4()));
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut file| file.flush())
+        .map_err(|e| format!("Failed to create {}: {e}", path.display()))?;
+
+    let copied = query(
+        sql_query("VACUUM INTO ?")
+            .bind::<Text, _>(path.to_string_lossy())
+            .execute(conn),
+    )
+    .and_then(|_| fs::read(&path).map_err(|e| format!("Failed to read {}: {e}", path.display())));
+    let _ = fs::remove_file(&path);
+    copied
+}
+
+/// The tables are read in one snapshot, so rows that reference each other match
+#[cfg(feature = "postgres")]
+fn postgres_tables(conn: &mut diesel::PgConnection) -> Result<Vec<DatabaseFile>, String> {
+    query(
+        conn.build_transaction()
+            .read_only()
+            .repeatable_read()
+            .run(|conn| {
+                let tables: Vec<TableName> = sql_query(
+                    "SELECT tablename FROM pg_tables WHERE schemaname = current_schema() ORDER BY tablename",
+                )
+                .load(conn)?;
+                tables
+                    .into_iter()
+                    .map(|table| {
+                        let rows: TableRows = sql_query(format!(
+                            "SELECT coalesce(json_agg(t), '[]')::text AS rows FROM \"{}\" t",
+                            table.tablename.replace('"', "\"\"")
+                        ))
+                        .get_result(conn)?;
+                        Ok((
+                            format!("database/{}.json", table.tablename),
+                            rows.rows.into_bytes(),
+                        ))
+                    })
+                    .collect()
+            }),
+    )
+}
@@ -0,0 +1,98 @@
+use diesel::prelude::*;
+use log::warn;
+use regex::Regex;
+
+use crate::{
+    models::{DiffIgnoreRule, Host, NewDiffIgnoreRule},
+    schema::{diff_ignore_rule, host},
+    DbConnection,
+};
+
+use super::history::now;
+use super::{query, query_drop};
+
+impl DiffIgnoreRule {
+    /// Adds a rule for every host if `host_id` is `None` and for every login if `login` is
+    pub fn add(
+        conn: &mut DbConnection,
+        host_id: Option<i32>,
+        login: Option<String>,
+        pattern: String,
+        created_by: &str,
+    ) -> Result<Self, String> {
+        if let Err(error) = Regex::new(&pattern) {
+            return Err(format!("Invalid pattern: {error}"));
+        }
+        query(conn.transaction(|conn| {
+            diesel::insert_into(diff_ignore_rule::table)
+                .values(NewDiffIgnoreRule {
+                    host_id,
+                    login,
+                    pattern,
+                    created_by: created_by.to_owned(),
+                    created_at: now(),
+                })
+                .execute(conn)?;
+            diff_ignore_rule::table
+                .order(diff_ignore_rule::id.desc())
+                .first::<Self>(conn)
+        }))
+    }
+
+    /// All rules with the name of their host
+    pub fn all(conn: &mut DbConnection) -> Result<Vec<(Self, Option<String>)>, String> {
+        query(
+            diff_ignore_rule::table
+                .left_join(host::table)
+                .select((Self::as_select(), host::name.nullable()))
+                .order(diff_ignore_rule::id)
+                .load::<(Self, Option<String>)>(conn),
+        )
+    }
+
+    pub fn delete(conn: &mut DbConnection, id: i32) -> Result<(), String> {
+        query_drop(
+            diesel::delete(diff_ignore_rule::table.filter(diff_ignore_rule::id.eq(id)))
+                .execute(conn),
+        )
+    }
+}
+
+/// The compiled ignore rules that apply to a host
+pub struct DiffIgnoreRules(Vec<(Option<String>, Regex)>);
+
+impl DiffIgnoreRules {
+    pub fn for_host(conn: &mut DbConnection, host: &Host) -> Result<Self, String> {
+        let rules = query(
+            diff_ignore_rule::table
+                .filter(
+                    diff_ignore_rule::host_id
+                        .is_null()
+                        .or(diff_ignore_rule::host_id.eq(host.id)),
+                )
+                .load::<DiffIgnoreRule>(conn),
+        )?;
+        Ok(Self(
+            rules
+                .into_iter()
+                .filter_map(|rule| match Regex::new(&rule.pattern) {
+                    Ok(pattern) => Some((rule.login, pattern)),
+                    Err(error) => {
+                        warn!("Skipping diff ignore rule {}: {error}", rule.id);
+                        None
+                    }
+                })
+                .collect(),
+        ))
+    }
+
+    /// Whether a rule for the login matches the line of its authorized_keys file
+    pub fn ignores(&self, login: &str, line: &str) -> bool {
+        self.0.iter().any(|(rule_login, pattern)| {
+            rule_login
+                .as_deref()
+                .is_none_or(|rule_login| rule_login == login)
+                && pattern.is_match(line)
+        })
+    }
+}
@@ -0,0 +1,67 @@
+use diesel::prelude::*;
+use time::PrimitiveDateTime;
+
+use crate::{
+    models::{FreezeWindow, NewFreezeWindow},
+    schema::freeze_window,
+    DbConnection,
+};
+
+use super::history::now;
+use super::{query, query_drop};
+
+impl FreezeWindow {
+    pub fn add(
+        conn: &mut DbConnection,
+        starts_at: PrimitiveDateTime,
+        ends_at: PrimitiveDateTime,
+        reason: String,
+        created_by: &str,
+    ) -> Result<Self, String> {
+        query(conn.transaction(|conn| {
+            diesel::insert_into(freeze_window::table)
+                .values(NewFreezeWindow {
+                    starts_at,
+                    ends_at,
+                    reason,
+                    created_by: created_by.to_owned(),
+                    created_at: now(),
+                })
+                .execute(conn)?;
+            freeze_window::table
+                .order(freeze_window::id.desc())
+                .first::<Self>(conn)
+        }))
+    }
+
+    /// Freezes that didn't end yet, the next one first
+    pub fn upcoming(conn: &mut DbConnection) -> Result<Vec<Self>, String> {
+        query(
+            freeze_window::table
+                .filter(freeze_window::ends_at.gt(now()))
+                .order(freeze_window::starts_at)
+                .load::<Self>(conn),
+        )
+    }
+
+    /// The freeze active at this time that lasts longest
+    pub fn active_at(
+        conn: &mut DbConnection,
+        at: PrimitiveDateTime,
+    ) -> Result<Option<Self>, String> {
+        query(
+            freeze_window::table
+                .filter(freeze_window::starts_at.le(at))
+                .filter(freeze_window::ends_at.gt(at))
+                .order(freeze_window::ends_at.desc())
+                .first::<Self>(conn)
+                .optional(),
+        )
+    }
+
+    pub fn delete(conn: &mut DbConnection, id: i32) -> Result<(), String> {
+        query_drop(
+            diesel::delete(freeze_window::table.filter(freeze_window::id.eq(id))).execute(conn),
+        )
+    }
+}
@@ -0,0 +1,412 @@
+use std::collections::HashMap;
+
+use diesel::dsl::insert_into;
+use diesel::prelude::*;
+use time::{OffsetDateTime, PrimitiveDateTime};
+
+use super::query;
+use crate::models::{AuthorizationHistory, KeyHistory, NewAuthorizationHistory, NewKeyHistory};
+use crate::schema::{authorization, authorization_history, host, key_history, user, user_key};
+use crate::ssh::parse_authorized_keyfile;
+use crate::DbConnection;
+
+pub const CREATED: &str = "created";
+pub const UPDATED: &str = "updated";
+pub const DELETED: &str = "deleted";
+pub const TRANSFERRED: &str = "transferred";
+pub const DEPLOYED: &str = "deployed";
+pub const REMOVED: &str = "removed";
+
+/// Current time in the form it is stored in the database
+pub fn now() -> PrimitiveDateTime {
+    let now = OffsetDateTime::now_utc();
+    PrimitiveDateTime::new(now.date(), now.time())
+}
+
+impl AuthorizationHistory {
+    /// Records the current state of these authorizations. Call this after creating and before deleting them.
+    pub fn record(
+        conn: &mut DbConnection,
+        action: &str,
+        authorization_ids: &[i32],
+        actor: &str,
+    ) -> QueryResult<usize> {
+        Self::record_with_old(conn, action, authorization_ids, actor, None)
+    }
+
+    /// Records the state of an authorization after an update, together with its previous login and options
+    pub fn record_update(
+        conn: &mut DbConnection,
+        authorization_id: i32,
+        actor: &str,
+        old_login: String,
+        old_options: Option<String>,
+    ) -> QueryResult<usize> {
+        Self::record_with_old(
+            conn,
+            UPDATED,
+            &[authorization_id],
+            actor,
+            Some((old_login, old_options)),
+        )
+    }
+
+    fn record_with_old(
+        conn: &mut DbConnection,
+        action: &str,
+        authorization_ids: &[i32],
+        actor: &str,
+        old: Option<(String, Option<String>)>,
+    ) -> QueryResult<usize> {
+        let (old_login, old_options) =
+            old.map_or((None, None), |(login, options)| (Some(login), options));
+        let changed_at = now();
+        let entries = authorization::table
+            .inner_join(host::table)
+            .inner_join(user::table)
+            .filter(authorization::id.eq_any(authorization_ids))
+            .select((
+                authorization::id,
+                host::id,
+                host::name,
+                user::id,
+                user::username,
+                authorization::login,
+                authorization::options,
+            ))
+            .load::<(i32, i32, String, i32, String, String, Option<String>)>(conn)?
+            .into_iter()
+            .map(
+                |(authorization_id, host_id, host_name, user_id, username, login, options)| {
+                    NewAuthorizationHistory {
+                        authorization_id,
+                        action: action.to_owned(),
+                        host_id,
+                        host_name,
+                        user_id,
+                        username,
+                        login,
+                        options,
+                        actor: actor.to_owned(),
+                        changed_at,
+                        old_login: old_login.clone(),
+                        old_options: old_options.clone(),
+                    }
+                },
+            )
+            .collect::<Vec<NewAuthorizationHistory>>();
+
+        // Batch inserts are not available on every backend
+        let mut recorded = 0;
+        for entry in entries {
+            recorded += insert_into(authorization_history::table)
+                .values(entry)
+                .execute(conn)?;
+        }
+        Ok(recorded)
+    }
+
+    /// All recorded changes of one authorization, oldest first
+    pub fn for_authorization(
+        conn: &mut DbConnection,
+        authorization_id: i32,
+    ) -> Result<Vec<Self>, String> {
+        query(
+            authorization_history::table
+                .filter(authorization_history::authorization_id.eq(authorization_id))
+                .order((authorization_history::changed_at, authorization_history::id))
+                .load::<Self>(conn),
+        )
+    }
+
+    /// The most recent changes of all authorizations, newest first
+    pub fn recent(conn: &mut DbConnection, limit: i64) -> Result<Vec<Self>, String> {
+        query(
+            authorization_history::table
+                .order((
+                    authorization_history::changed_at.desc(),
+                    authorization_history::id.desc(),
+                ))
+                .limit(limit)
+                .load::<Self>(conn),
+        )
+    }
+
+    /// When each authorization was created, for those created since the history is recorded
+    pub fn created_dates(
+        conn: &mut DbConnection,
+    ) -> Result<HashMap<i32, PrimitiveDateTime>, String> {
+        let created = query(
+            authorization_history::table
+                .filter(authorization_history::action.eq(CREATED))
+                .select((
+                    authorization_history::authorization_id,
+                    authorization_history::changed_at,
+                ))
+                .order(authorization_history::changed_at.desc())
+                .load::<(i32, PrimitiveDateTime)>(conn),
+        )?;
+        // Ordered newest first, so the earliest record of an authorization wins
+        Ok(created.into_iter().collect())
+    }
+
+    /// When the history starts, older changes are unknown
+    pub fn first_record(conn: &mut DbConnection) -> Result<Option<PrimitiveDateTime>, String> {
+        query(
+            authorization_history::table
+                .select(diesel::dsl::min(authorization_history::changed_at))
+                .first(conn),
+        )
+    }
+
+    /// Reconstructs which authorizations existed on a host at a point in time.
+    /// The host is matched by its current id or by the name it had at the time.
+    pub fn access_at(
+        conn: &mut DbConnection,
+        host_name: &str,
+        at: PrimitiveDateTime,
+    ) -> Result<Vec<Self>, String> {
+        let host_id: Option<i32> = query(
+            host::table
+                .filter(host::name.eq(host_name))
+                .select(host::id)
+                .first(conn)
+                .optional(),
+        )?;
+
+        let changes = query(
+            authorization_history::table
+                .filter(
+                    authorization_history::host_name
+                        .eq(host_name)
+                        .or(authorization_history::host_id.eq(host_id.unwrap_or(-1))),
+                )
+                .filter(authorization_history::changed_at.le(at))
+                .order((authorization_history::changed_at, authorization_history::id))
+                .load::<Self>(conn),
+        )?;
+
+        let mut latest: HashMap<i32, Self> = HashMap::new();
+        for change in changes {
+            latest.insert(change.authorization_id, change);
+        }
+
+        let mut access: Vec<Self> = latest
+            .into_values()
+            .filter(|change| change.action.ne(DELETED))
+            .collect();
+        access.sort_by(|a, b| a.username.cmp(&b.username).then(a.login.cmp(&b.login)));
+        Ok(access)
+    }
+}
+
+impl KeyHistory {
+    /// Records keys known to ssm being created or deleted. Call this after creating and before deleting them.
+    pub fn record(
+        conn: &mut DbConnection,
+        action: &str,
+        key_ids: &[i32],
+        actor: &str,
+    ) -> QueryResult<usize> {
+        Self::record_with_previous(conn, action, key_ids, actor, None)
+    }
+
+    /// Records a key after it was transferred to another user, together with its previous owner
+    pub fn record_transfer(
+        conn: &mut DbConnection,
+        key_id: i32,
+        actor: &str,
+        previous_username: String,
+    ) -> QueryResult<usize> {
+        Self::record_with_previous(conn, TRANSFERRED, &[key_id], actor, Some(previous_username))
+    }
+
+    fn record_with_previous(
+        conn: &mut DbConnection,
+        action: &str,
+        key_ids: &[i32],
+        actor: &str,
+        previous_username: Option<String>,
+    ) -> QueryResult<usize> {
+        let changed_at = now();
+        let entries = user_key::table
+            .inner_join(user::table)
+            .filter(user_key::id.eq_any(key_ids))
+            .select((user_key::id, user_key::key_base64, user::username))
+            .load::<(i32, String, String)>(conn)?
+            .into_iter()
+            .map(|(key_id, key_base64, username)| NewKeyHistory {
+                key_id: Some(key_id),
+                key_base64,
+                action: action.to_owned(),
+                username: Some(username),
+                host_name: None,
+                login: None,
+                actor: actor.to_owned(),
+                changed_at,
+                previous_username: previous_username.clone(),
+            })
+            .collect::<Vec<NewKeyHistory>>();
+
+        Self::insert(conn, entries)
+    }
+
+    /// The most recent changes of all keys, newest first
+    pub fn recent(conn: &mut DbConnection, limit: i64) -> Result<Vec<Self>, String> {
+        query(
+            key_history::table
+                .order((key_history::changed_at.desc(), key_history::id.desc()))
+                .limit(limit)
+                .load::<Self>(conn),
+        )
+    }
+
+    /// When the keys known to ssm were added, by base64. Keys added before the history was recorded are missing.
+    pub fn created_dates(
+        conn: &mut DbConnection,
+    ) -> Result<HashMap<String, PrimitiveDateTime>, String> {
+        let created = query(
+            key_history::table
+                .inner_join(user_key::table.on(key_history::key_id.eq(user_key::id.nullable())))
+                .filter(key_history::action.eq(CREATED))
+                .select((user_key::key_base64, key_history::changed_at))
+                .order(key_history::changed_at.desc())
+                .load::<(String, PrimitiveDateTime)>(conn),
+        )?;
+        // Ordered newest first, so the earliest record of a key wins
+        Ok(created.into_iter().collect())
+    }
+
+    /// When a key was added to ssm, if that was recorded
+    #[cfg(feature = "graphql")]
+    pub fn created_at(
+        conn: &mut DbConnection,
+        key_id: i32,
+    ) -> Result<Option<PrimitiveDateTime>, String> {
+        query(
+            key_history::table
+                .filter(key_history::key_id.eq(key_id))
+                .filter(key_history::action.eq(CREATED))
+                .select(key_history::changed_at)
+                .order(key_history::changed_at.asc())
+                .first(conn)
+                .optional(),
+        )
+    }
+
+    /// Records which keys were added to and removed from an authorized_keys file by a deployment.
+    /// Keys unknown to ssm are recorded too, without an owner.
+    pub fn record_deploy(
+        conn: &mut DbConnection,
+        host_name: &str,
+        login: &str,
+        old_keyfile: &str,
+        new_keyfile: &str,
+        actor: &str,
+    ) -> Result<usize, String> {
+        let old_keys = keys_in(old_keyfile);
+        let new_keys = keys_in(new_keyfile);
+
+        let changes: Vec<(&str, &String)> = new_keys
+            .iter()
+            .filter(|key| !old_keys.contains(key))
+            .map(|key| (DEPLOYED, key))
+            .chain(
+                old_keys
+                    .iter()
+                    .filter(|key| !new_keys.contains(key))
+                    .map(|key| (REMOVED, key)),
+            )
+            .collect();
+        if changes.is_empty() {
+            return Ok(0);
+        }
+
+        let changed_at = now();
+        query(conn.transaction(|conn| {
+            let owners: HashMap<String, (i32, String)> = user_key::table
+                .inner_join(user::table)
+                .filter(user_key::key_base64.eq_any(changes.iter().map(|(_, key)| *key)))
+                .select((user_key::key_base64, user_key::id, user::username))
+                .load::<(String, i32, String)>(conn)?
+                .into_iter()
+                .map(|(key_base64, id, username)| (key_base64, (id, username)))
+                .collect();
+
+            let entries = changes
+                .iter()
+                .map(|(action, key_base64)| {
+                    let owner = owners.get(*key_base64);
+                    NewKeyHistory {
+                        key_id: owner.map(|(id, _)| *id),
+                        key_base64: (*key_base64).clone(),
+                        action: (*action).to_owned(),
+                        username: owner.map(|(_, username)| username.clone()),
+                        host_name: Some(host_name.to_owned()),
+                        login: Some(login.to_owned()),
+                        actor: actor.to_owned(),
+                        changed_at,
+                        previous_username: None,
+                    }
+                })
+                .collect();
+
+            Self::insert(conn, entries)
+        }))
+    }
+
+    fn insert(conn: &mut DbConnection, entries: Vec<NewKeyHistory>) -> QueryResult<usize> {
+        // Batch inserts are not available on every backend
+        let mut recorded = 0;
+        for entry in entries {
+            recorded += insert_into(key_history::table)
+                .values(entry)
+                .execute(conn)?;
+        }
+        Ok(recorded)
+    }
+
+    /// All recorded changes of one key, oldest first. Also works for keys that were deleted since.
+    pub fn for_key(
+        conn: &mut DbConnection,
+        key_id: i32,
+    ) -> Result<Option<(String, Vec<Self>)>, String> {
+        let current: Option<String> = query(
+            user_key::table
+                .filter(user_key::id.eq(key_id))
+                .select(user_key::key_base64)
+                .first(conn)
+                .optional(),
+        )?;
+        let key_base64 = match current {
+            Some(key_base64) => key_base64,
+            None => match query(
+                key_history::table
+                    .filter(key_history::key_id.eq(key_id))
+                    .select(key_history::key_base64)
+                    .first::<String>(conn)
+                    .optional(),
+            )? {
+                Some(key_base64) => key_base64,
+                None => return Ok(None),
+            },
+        };
+
+        let history = query(
+            key_history::table
+                .filter(key_history::key_base64.eq(&key_base64))
+                .order((key_history::changed_at, key_history::id))
+                .load::<Self>(conn),
+        )?;
+        Ok(Some((key_base64, history)))
+    }
+}
+
+/// Base64 encoded keys in an authorized_keys file, skipping comments and unparsable lines
+fn keys_in(keyfile: &str) -> Vec<String> {
+    parse_authorized_keyfile(keyfile)
+        .1
+        .into_iter()
+        .filter_map(|entry| entry.ok().map(|key| key.base64))
+        .collect()
+}
@@ -2,18 +2,25 @@ use crate::schema::authorization;
 use crate::schema::host;
 use crate::schema::user;
 use crate::schema::user_key;
+use crate::ssh::parse_authorized_keyfile;
+use crate::ssh::AddressFamily;
+use crate::ssh::AuthorizedKey;
 use crate::ssh::ConnectionDetails;
+use crate::ssh::Proxy;
 use crate::ssh::SshClient;
 use crate::ssh::SshClientError;
 use crate::{
-    models::{Host, NewHost, PublicUserKey},
+    limits::{KeyLimits, KeyfileUsage},
+    models::{AuthorizationHistory, Host, HostChanges, NewHost, PublicUserKey},
     DbConnection,
 };
 use diesel::dsl::insert_into;
 use diesel::prelude::*;
 use diesel::r2d2::ConnectionManager;
 use diesel::r2d2::PooledConnection;
+use std::str::FromStr;
 
+use super::history;
 use super::query;
 use super::query_drop;
 use super::AllowedUserOnHost;
@@ -22,39 +29,252 @@ use super::UserAndOptions;
 
 impl Host {
     pub fn to_connection(&self) -> Result<ConnectionDetails, SshClientError> {
-        Ok(ConnectionDetails::new(
-            self.address.clone(),
-            self.port
-                .try_into()
-                .map_err(|_| SshClientError::PortCastFailed)?,
-        ))
+        let mut connection = ConnectionDetails::new(self.address.clone(), self.port);
+        connection.address_family = self
+            .address_family
+            .as_deref()
+            .map(AddressFamily::from_str)
+            .transpose()
+            .map_err(SshClientError::ExecutionError)?;
+        connection.fallback_addresses = self.fallback_address_list().map(str::to_owned).collect();
+        connection.proxy = self
+            .proxy
+            .as_deref()
+            .map(Proxy::from_str)
+            .transpose()
+            .map_err(SshClientError::ExecutionError)?;
+        Ok(connection)
+    }
+
+    /// Tags of this host
+    pub fn tag_list(&self) -> impl Iterator<Item = &str> {
+        self.tags.split(',').filter(|tag| !tag.is_empty())
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tag_list().any(|t| t.eq(tag))
+    }
+
+    /// Alternative names the host can be looked up by
+    pub fn alias_list(&self) -> impl Iterator<Item = &str> {
+        self.aliases.split(',').filter(|alias| !alias.is_empty())
+    }
+
+    /// Addresses tried in order when `address` can't be reached
+    pub fn fallback_address_list(&self) -> impl Iterator<Item = &str> {
+        self.fallback_addresses
+            .split(',')
+            .filter(|address| !address.is_empty())
+    }
+
+    /// Every accepted host key fingerprint, the primary one first
+    pub fn key_fingerprint_list(&self) -> impl Iterator<Item = &str> {
+        self.key_fingerprint.as_deref().into_iter().chain(
+            self.additional_key_fingerprints
+                .split(',')
+                .filter(|fingerprint| !fingerprint.is_empty()),
+        )
+    }
+
+    pub fn accepts_key_fingerprint(&self, fingerprint: &str) -> bool {
+        self.key_fingerprint_list().any(|f| f.eq(fingerprint))
+    }
+
+    /// Fingerprints of unmanaged keys that may stay in the authorized_keys files of this host
+    pub fn tolerated_key_list(&self) -> impl Iterator<Item = &str> {
+        self.tolerated_keys
+            .split(',')
+            .filter(|fingerprint| !fingerprint.is_empty())
+    }
+
+    /// Whether the diff ignores this key and deployments keep it
+    pub fn tolerates(&self, key: &AuthorizedKey) -> bool {
+        !self.tolerated_keys.is_empty()
+            && key
+                .fingerprint()
+                .is_some_and(|fingerprint| self.tolerated_key_list().any(|f| f.eq(&fingerprint)))
+    }
+
+    /// Appends the tolerated entries of the current file to a generated one, unchanged
+    pub fn keep_tolerated_keys(&self, generated: String, current_keyfile: &str) -> String {
+        if self.tolerated_keys.is_empty() {
+            return generated;
+        }
+        let (_, generated_entries) = parse_authorized_keyfile(&generated);
+        let mut kept: Vec<String> = generated_entries
+            .into_iter()
+            .filter_map(|entry| entry.ok().and_then(|key| key.fingerprint()))
+            .collect();
+
+        let mut keyfile = generated;
+        for line in current_keyfile
+            .lines()
+            .filter(|line| !line.trim_start().starts_with('#'))
+        {
+            let Some(Ok(key)) = parse_authorized_keyfile(line).1.pop() else {
+                continue;
+            };
+            if let Some(fingerprint) = key.fingerprint() {
+                if self.tolerates(&key) && !kept.contains(&fingerprint) {
+                    keyfile.push_str(line.trim());
+                    keyfile.push('\n');
+                    kept.push(fingerprint);
+                }
+            }
+        }
+        keyfile
+    }
+
+    /// Key algorithms the sshd of this host accepts, all if empty
+    pub fn key_algorithm_list(&self) -> impl Iterator<Item = &str> {
+        self.key_algorithms
+            .split(',')
+            .filter(|algorithm| !algorithm.is_empty())
+    }
+
+    /// Whether keys of this type are deployed to the host
+    pub fn accepts_key_type(&self, key_type: &str) -> bool {
+        self.key_algorithms.is_empty() || self.key_algorithm_list().any(|a| a.eq(key_type))
+    }
+
+    /// Turns user input into the stored form of accepted key algorithms, refusing unknown ones
+    pub fn normalize_key_algorithms(key_algorithms: &str) -> Result<String, String> {
+        let key_algorithms = Self::normalize_list(key_algorithms);
+        for algorithm in key_algorithms.split(',').filter(|a| !a.is_empty()) {
+            if let Ok(ssh_key::Algorithm::Other(_)) | Err(_) = ssh_key::Algorithm::new(algorithm) {
+                return Err(format!(
+                    "'{algorithm}' is not a key algorithm like ssh-ed25519 or ssh-rsa"
+                ));
+            }
+        }
+        Ok(key_algorithms)
+    }
+
+    /// Turns user input like "a, b ,a" into the stored form "a,b", keeping the order
+    pub fn normalize_list(list: &str) -> String {
+        let mut items: Vec<&str> = Vec::new();
+        for item in list.split(',').map(str::trim) {
+            if !item.is_empty() && !items.contains(&item) {
+                items.push(item);
+            }
+        }
+        items.join(",")
+    }
+
+    /// Finds a host other than `host_id` already using one of the names, as name or alias
+    pub fn find_name_conflict<'a>(
+        conn: &mut DbConnection,
+        host_id: i32,
+        names: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Option<(String, String)>, String> {
+        let others: Vec<Self> = query(host::table.filter(host::id.ne(host_id)).load::<Self>(conn))?;
+        Ok(names.into_iter().find_map(|name| {
+            others
+                .iter()
+                .find(|other| other.name.eq(name) || other.alias_list().any(|alias| alias.eq(name)))
+                .map(|other| (name.to_owned(), other.name.clone()))
+        }))
+    }
+
+    /// Turns user input like "prod, web ,prod" into the stored form "prod,web"
+    pub fn normalize_tags(tags: &str) -> String {
+        let mut tags: Vec<&str> = tags
+            .split(',')
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .collect();
+        tags.sort_unstable();
+        tags.dedup();
+        tags.join(",")
+    }
+
+    /// Saves the edited settings of the host named `host_name` in a single update, so a failure
+    /// leaves all of them as they were
+    pub fn update(
+        conn: &mut DbConnection,
+        host_name: &str,
+        changes: HostChanges,
+    ) -> Result<(), String> {
+        if let Some(location) = &changes.principals_file {
+            Self::check_principals_file(location)?;
+        }
+        Self::check_script_location(
+            changes.script_path.as_deref(),
+            changes.script_interpreter.as_deref(),
+        )?;
+        query_drop(
+            diesel::update(host::table.filter(host::name.eq(host_name)))
+                .set(changes)
+                .execute(conn),
+        )
+    }
+
+    /// Checks where the script transport installs its script, relative to the home directory or
+    /// absolute, and the command it is run with, like `bash` or `busybox sh`. Both end up in shell
+    /// commands, so only portable path characters and spaces between arguments are allowed.
+    pub fn check_script_location(
+        path: Option<&str>,
+        interpreter: Option<&str>,
+    ) -> Result<(), String> {
+        let portable = |value: &str, spaces: bool| {
+            !value.is_empty()
+                && !value.starts_with('-')
+                && value.chars().all(|c| {
+                    c.is_ascii_alphanumeric()
+                        || matches!(c, '/' | '.' | '_' | '-')
+                        || (spaces && c == ' ')
+                })
+        };
+        if let Some(path) = path.filter(|path| !portable(path, false) || path.ends_with('/')) {
+            return Err(format!(
+                "'{path}' isn't a script location like .ssh/ssm.sh or /usr/local/lib/ssm.sh"
+            ));
+        }
+        if let Some(interpreter) = interpreter.filter(|interpreter| !portable(interpreter, true)) {
+            return Err(format!(
+                "'{interpreter}' isn't an interpreter like bash or /bin/busybox sh"
+            ));
+        }
+        Ok(())
     }
 
     /// Adds a new host to the database
     pub fn add_host(conn: &mut DbConnection, host: &NewHost) -> Result<i32, String> {
-        query(insert_into(host::table).values(host.clone()).execute(conn)).map(|id| (id as i32))
+        query(insert_into(host::table).values(host.clone()).execute(conn)).map(|id| id as i32)
     }
 
+    /// Authorizes a user on this host and records it in the authorization history
     pub fn authorize_user(
         conn: &mut DbConnection,
         host_id: i32,
         user_id: i32,
         login: String,
         mut options: Option<String>,
+        actor: &str,
     ) -> Result<(), String> {
         if options.as_ref().is_some_and(String::is_empty) {
             options = None;
         }
-        query_drop(
+        query(conn.transaction(|conn| {
             insert_into(authorization::table)
                 .values((
                     authorization::host_id.eq(host_id),
                     authorization::user_id.eq(user_id),
-                    authorization::login.eq(login),
+                    authorization::login.eq(&login),
                     authorization::options.eq(options),
                 ))
-                .execute(conn),
-        )
+                .execute(conn)?;
+
+            let id = authorization::table
+                .filter(authorization::host_id.eq(host_id))
+                .filter(authorization::user_id.eq(user_id))
+                .filter(authorization::login.eq(&login))
+                .select(authorization::id)
+                .first::<i32>(conn)?;
+
+            AuthorizationHistory::record(conn, history::CREATED, &[id], actor)
+        }))
+        .map(|_| ())
     }
 
     /// Get authorized Users and associated options
@@ -83,16 +303,9 @@ impl Host {
         mut conn: PooledConnection<ConnectionManager<DbConnection>>,
         host: String,
     ) -> Result<Option<Self>, String> {
-        actix_web::web::block(move || {
-            query(
-                host::table
-                    .filter(host::name.eq(host))
-                    .first::<Self>(&mut conn)
-                    .optional(),
-            )
-        })
-        .await
-        .map_err(|_| "Blocking error.".to_owned())?
+        actix_web::web::block(move || Self::get_from_name_sync(&mut conn, host))
+            .await
+            .map_err(|_| "Blocking error.".to_owned())?
     }
 
     /// Get a host from an id
@@ -116,12 +329,20 @@ impl Host {
         conn: &mut DbConnection,
         host: String,
     ) -> Result<Option<Self>, String> {
-        query(
+        if let Some(found) = query(
             host::table
-                .filter(host::name.eq(host))
+                .filter(host::name.eq(&host))
                 .first::<Self>(conn)
                 .optional(),
-        )
+        )? {
+            return Ok(Some(found));
+        }
+
+        let with_aliases: Vec<Self> =
+            query(host::table.filter(host::aliases.ne("")).load::<Self>(conn))?;
+        Ok(with_aliases
+            .into_iter()
+            .find(|candidate| candidate.alias_list().any(|alias| alias.eq(&host))))
     }
 
     /// Get a host from an id
@@ -133,11 +354,26 @@ impl Host {
                 .optional(),
         )
     }
+    /// Get the host an authorization belongs to
+    pub fn get_from_authorization(
+        conn: &mut DbConnection,
+        authorization_id: i32,
+    ) -> Result<Option<Self>, String> {
+        query(
+            authorization::table
+                .inner_join(host::table)
+                .filter(authorization::id.eq(authorization_id))
+                .select(Self::as_select())
+                .first::<Self>(conn)
+                .optional(),
+        )
+    }
+
     pub fn get_all_hosts(conn: &mut DbConnection) -> Result<Vec<Self>, String> {
         query(host::table.load::<Self>(conn))
     }
 
-    /// Gets all allowed users allowed on this host, sorted by login
+    /// Gets all allowed users allowed on this host, sorted by login. Disabled users are left out.
     pub fn get_authorized_keys(
         &self,
         conn: &mut DbConnection,
@@ -153,6 +389,7 @@ impl Host {
                     authorization::options,
                 ))
                 .filter(authorization::host_id.eq(self.id))
+                .filter(user::enabled.eq(true))
                 .order(authorization::login.desc())
                 .load::<(PublicUserKey, String, String, Option<String>)>(conn),
         )
@@ -160,26 +397,92 @@ impl Host {
             allowed_list
                 .into_iter()
                 .map(AllowedUserOnHost::from)
-                .collect()
+                .collect::<AuthorizedKeysList>()
+        })
+        .and_then(|mut allowed_list| {
+            for granted in self.get_rule_keys(conn)? {
+                if !allowed_list.iter().any(|allowed| {
+                    allowed.login.eq(&granted.login) && allowed.key.id == granted.key.id
+                }) {
+                    allowed_list.push(granted);
+                }
+            }
+            Ok(allowed_list)
         })
     }
 
-    /// Generate authorized key file for a login on a host. Includes ssm key, if applicable
+    /// Generate authorized key file for a login on a host. Includes ssm key, if applicable.
+    /// Keys of disabled users and of algorithms the host doesn't accept are left out.
+    /// Fails if the file is over the limits.
     pub fn get_authorized_keys_file_for(
         &self,
-        ssh_client: &SshClient,
+        ssh_client: &dyn SshClient,
         conn: &mut DbConnection,
         login: &str,
+        limits: &KeyLimits,
     ) -> Result<String, String> {
-        let res: Vec<(PublicUserKey, Option<String>)> = query(
+        let keyfile = self.generate_authorized_keys_file(ssh_client, conn, login)?;
+        limits.check(login, KeyfileUsage::of(&keyfile))?;
+        Ok(keyfile)
+    }
+
+    /// Fails if authorizing a user for a login would take its authorized_keys file over the limits
+    pub fn check_key_limits(
+        &self,
+        ssh_client: &dyn SshClient,
+        conn: &mut DbConnection,
+        user_id: i32,
+        login: &str,
+        options: Option<&str>,
+        limits: &KeyLimits,
+    ) -> Result<(), String> {
+        if !limits.enabled() {
+            return Ok(());
+        }
+        let keyfile = self.generate_authorized_keys_file(ssh_client, conn, login)?;
+        let user_keys: Vec<PublicUserKey> = query(
+            user_key::table
+                .filter(user_key::user_id.eq(user_id))
+                .load::<PublicUserKey>(conn),
+        )?;
+        let usage = user_keys
+            .iter()
+            .filter(|key| self.accepts_key_type(&key.key_type))
+            .filter(|key| !keyfile.contains(&key.key_base64))
+            .fold(KeyfileUsage::of(&keyfile), |usage, key| {
+                let line = options
+                    .filter(|options| !options.is_empty())
+                    .map_or_else(String::new, |o| format!("{o} "))
+                    + key.to_openssh().as_str();
+                usage.with_line(&line)
+            });
+        limits.check(login, usage)
+    }
+
+    /// The authorized_keys file of a login as it would be deployed, regardless of the limits
+    pub fn generate_authorized_keys_file(
+        &self,
+        ssh_client: &dyn SshClient,
+        conn: &mut DbConnection,
+        login: &str,
+    ) -> Result<String, String> {
+        let mut res: Vec<(PublicUserKey, Option<String>)> = query(
             user::table
                 .inner_join(user_key::table)
                 .inner_join(authorization::table)
                 .select((PublicUserKey::as_select(), authorization::options))
                 .filter(authorization::host_id.eq(self.id))
                 .filter(authorization::login.eq(login))
+                .filter(user::enabled.eq(true))
                 .load::<(PublicUserKey, Option<String>)>(conn),
         )?;
+        for granted in self.get_rule_keys(conn)? {
+            if granted.login.eq(login) && !res.iter().any(|(key, _)| key.id == granted.key.id) {
+                res.push((granted.key, granted.options));
+            }
+        }
+        // The sshd of the host would refuse them, the diff warns about them instead
+        res.retain(|(key, _)| self.accepts_key_type(&key.key_type));
 
         let estimated_size = (res.len() + 2) * 150;
 
@@ -207,13 +510,158 @@ impl Host {
         )
     }
 
-    pub fn delete(self, conn: &mut DbConnection) -> Result<usize, String> {
-        query(diesel::delete(host::table.filter(host::id.eq(self.id))).execute(conn))
+    /// Deletes this host and every host that jumps via it, recording the authorizations that go with them
+    pub fn delete(self, conn: &mut DbConnection, actor: &str) -> Result<usize, String> {
+        query(conn.transaction(|conn| {
+            let mut host_ids = vec![self.id];
+            let mut next = vec![self.id];
+            while !next.is_empty() {
+                next = host::table
+                    .filter(host::jump_via.eq_any(&next))
+                    .select(host::id)
+                    .load::<i32>(conn)?;
+                next.retain(|id| !host_ids.contains(id));
+                host_ids.extend(&next);
+            }
+
+            let authorization_ids = authorization::table
+                .filter(authorization::host_id.eq_any(&host_ids))
+                .select(authorization::id)
+                .load::<i32>(conn)?;
+            AuthorizationHistory::record(conn, history::DELETED, &authorization_ids, actor)?;
+
+            diesel::delete(host::table.filter(host::id.eq(self.id))).execute(conn)
+        }))
     }
 
-    pub fn delete_authorization(conn: &mut DbConnection, authorization: i32) -> Result<(), String> {
-        query_drop(
+    /// Changes the login and options of an authorization, recording the previous values
+    pub fn update_authorization(
+        conn: &mut DbConnection,
+        authorization_id: i32,
+        new_login: String,
+        mut new_options: Option<String>,
+        actor: &str,
+    ) -> Result<(), String> {
+        if new_options.as_ref().is_some_and(String::is_empty) {
+            new_options = None;
+        }
+        query_drop(conn.transaction(|conn| {
+            let Some((old_login, old_options)) = authorization::table
+                .filter(authorization::id.eq(authorization_id))
+                .select((authorization::login, authorization::options))
+                .first::<(String, Option<String>)>(conn)
+                .optional()?
+            else {
+                return Ok(0);
+            };
+
+            let updated =
+                diesel::update(authorization::table.filter(authorization::id.eq(authorization_id)))
+                    .set((
+                        authorization::login.eq(new_login),
+                        authorization::options.eq(new_options),
+                    ))
+                    .execute(conn)?;
+
+            AuthorizationHistory::record_update(
+                conn,
+                authorization_id,
+                actor,
+                old_login,
+                old_options,
+            )?;
+            Ok(updated)
+        }))
+    }
+
+    pub fn delete_authorization(
+        conn: &mut DbConnection,
+        authorization: i32,
+        actor: &str,
+    ) -> Result<(), String> {
+        query_drop(conn.transaction(|conn| {
+            AuthorizationHistory::record(conn, history::DELETED, &[authorization], actor)?;
             diesel::delete(authorization::table.filter(authorization::id.eq(authorization)))
+                .execute(conn)
+        }))
+    }
+
+    /// Remembers the host key a host offered instead of an accepted one
+    pub fn mark_key_mismatch(
+        &self,
+        conn: &mut DbConnection,
+        fingerprint: &str,
+    ) -> Result<(), String> {
+        query_drop(
+            diesel::update(host::table)
+                .filter(host::id.eq(self.id))
+                .set(host::key_mismatch.eq(fingerprint))
+                .execute(conn),
+        )
+    }
+
+    /// Accepts another host key for this host. Becomes the primary key if there is none yet.
+    pub fn add_key_fingerprint(
+        &self,
+        conn: &mut DbConnection,
+        fingerprint: &str,
+    ) -> Result<(), String> {
+        if self.accepts_key_fingerprint(fingerprint) {
+            return Err(format!("Host key {fingerprint} is already accepted"));
+        }
+        let mut fingerprints: Vec<&str> = self.key_fingerprint_list().collect();
+        fingerprints.push(fingerprint);
+        self.set_key_fingerprints(conn, &fingerprints)
+    }
+
+    /// Accepts all of these host keys that aren't accepted yet, keeping the primary key if there is one
+    pub fn extend_key_fingerprints(
+        &self,
+        conn: &mut DbConnection,
+        fingerprints: &[String],
+    ) -> Result<(), String> {
+        let mut accepted: Vec<&str> = self.key_fingerprint_list().collect();
+        for fingerprint in fingerprints {
+            if !accepted.contains(&fingerprint.as_str()) {
+                accepted.push(fingerprint);
+            }
+        }
+        self.set_key_fingerprints(conn, &accepted)
+    }
+
+    /// Stops accepting a host key. When the primary key is removed, the next one takes its place.
+    pub fn remove_key_fingerprint(
+        &self,
+        conn: &mut DbConnection,
+        fingerprint: &str,
+    ) -> Result<(), String> {
+        if !self.accepts_key_fingerprint(fingerprint) {
+            return Err(format!("Host key {fingerprint} is not accepted"));
+        }
+        let fingerprints: Vec<&str> = self
+            .key_fingerprint_list()
+            .filter(|f| f.ne(&fingerprint))
+            .collect();
+        if fingerprints.is_empty() {
+            return Err("Can't remove the only host key of a host".to_owned());
+        }
+        self.set_key_fingerprints(conn, &fingerprints)
+    }
+
+    fn set_key_fingerprints(
+        &self,
+        conn: &mut DbConnection,
+        fingerprints: &[&str],
+    ) -> Result<(), String> {
+        query_drop(
+            diesel::update(host::table)
+                .filter(host::id.eq(self.id))
+                .set((
+                    host::key_fingerprint.eq(fingerprints.first().copied()),
+                    host::key_mismatch.eq(None::<String>),
+                    host::additional_key_fingerprints
+                        .eq(fingerprints.get(1..).unwrap_or_default().join(",")),
+                ))
                 .execute(conn),
         )
     }
@@ -226,7 +674,10 @@ impl Host {
         query_drop(
             diesel::update(host::table)
                 .filter(host::id.eq(self.id))
-                .set(host::key_fingerprint.eq(fingerprint))
+                .set((
+                    host::key_fingerprint.eq(fingerprint),
+                    host::key_mismatch.eq(None::<String>),
+                ))
                 .execute(conn),
         )
     }
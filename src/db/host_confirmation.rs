@@ -0,0 +1,85 @@
+use diesel::prelude::*;
+use time::Duration;
+use uuid::Uuid;
+
+use crate::{models::HostConfirmation, schema::host_confirmation, DbConnection};
+
+use super::history::now;
+use super::{query, query_drop};
+
+/// How long a preview of a destructive operation can be confirmed
+const CONFIRMATION_TTL: Duration = Duration::minutes(15);
+
+/// Deleting the host
+pub const DELETE: &str = "delete";
+/// Writing an authorized_keys file
+pub const DEPLOY: &str = "deploy";
+/// Replacing the sshd_config, confirmed for every host
+pub const SSHD_CONFIG: &str = "sshd_config";
+
+impl HostConfirmation {
+    /// Hands out a token confirming an action on a protected host, returns the token.
+    /// Expired tokens are removed on the way.
+    pub fn create(
+        conn: &mut DbConnection,
+        host_id: i32,
+        action: &str,
+        login: Option<String>,
+        authorized_keys: Option<String>,
+    ) -> Result<String, String> {
+        let created_at = now();
+        query(
+            diesel::delete(
+                host_confirmation::table
+                    .filter(host_confirmation::created_at.lt(created_at - CONFIRMATION_TTL)),
+            )
+            .execute(conn),
+        )?;
+
+        let token = Uuid::new_v4().simple().to_string();
+        query_drop(
+            diesel::insert_into(host_confirmation::table)
+                .values(Self {
+                    token: token.clone(),
+                    host_id,
+                    action: action.to_owned(),
+                    login,
+                    authorized_keys,
+                    created_at,
+                })
+                .execute(conn),
+        )?;
+        Ok(token)
+    }
+
+    /// Uses up a token, returns whether it confirms exactly this action.
+    /// Deployments are only confirmed for the login and authorized_keys file that were previewed,
+    /// sshd_config changes for the file that was previewed (kept in `authorized_keys`).
+    pub fn redeem(
+        conn: &mut DbConnection,
+        token: &str,
+        host_id: i32,
+        action: &str,
+        login: Option<&str>,
+        authorized_keys: Option<&str>,
+    ) -> Result<bool, String> {
+        let confirmation = query(
+            host_confirmation::table
+                .filter(host_confirmation::token.eq(token))
+                .filter(host_confirmation::created_at.ge(now() - CONFIRMATION_TTL))
+                .first::<Self>(conn)
+                .optional(),
+        )?;
+        query(
+            diesel::delete(host_confirmation::table.filter(host_confirmation::token.eq(token)))
+                .execute(conn),
+        )?;
+
+        Ok(confirmation.is_some_and(|confirmation| {
+            confirmation.host_id == host_id
+                && confirmation.action.eq(action)
+                && confirmation.login.as_deref() == login
+                && confirmation.authorized_keys.as_deref() == authorized_keys
+        }))
+    }
+}
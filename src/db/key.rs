@@ -1,7 +1,9 @@
-use super::{query, query_drop, UsernameAndKey};
-use crate::models::NewPublicUserKey;
-use crate::schema::user;
+use std::collections::BTreeSet;
+
+use super::{found, history, query, query_drop, UsernameAndKey};
+use crate::models::{Host, KeyHistory, NewPublicUserKey};
 use crate::schema::user_key;
+use crate::schema::{authorization, host, user};
 use crate::{models::PublicUserKey, DbConnection};
 use diesel::dsl::insert_into;
 use diesel::prelude::*;
@@ -30,14 +32,118 @@ impl PublicUserKey {
             .map(|keys| keys.iter().map(|key| T::from(key.to_owned())).collect())
     }
 
-    /// Add a new user key to the db
-    pub fn add_key(conn: &mut DbConnection, key: NewPublicUserKey) -> Result<(), String> {
-        query_drop(insert_into(user_key::table).values(key).execute(conn))
+    /// A key together with its owner
+    pub fn get_with_username(
+        conn: &mut DbConnection,
+        key: i32,
+    ) -> Result<Option<UsernameAndKey>, String> {
+        query(
+            user_key::table
+                .inner_join(user::table)
+                .filter(user_key::id.eq(key))
+                .select((user::username, Self::as_select()))
+                .first::<UsernameAndKey>(conn)
+                .optional(),
+        )
     }
 
-    /// Remove a key from the db
-    pub fn delete_key(conn: &mut DbConnection, key: i32) -> Result<(), String> {
-        query_drop(diesel::delete(user_key::table.filter(user_key::id.eq(key))).execute(conn))
+    /// The key with this base64 encoding together with its owner
+    pub fn find_by_base64(
+        conn: &mut DbConnection,
+        key_base64: &str,
+    ) -> Result<Option<UsernameAndKey>, String> {
+        query(
+            user_key::table
+                .inner_join(user::table)
+                .filter(user_key::key_base64.eq(key_base64))
+                .select((user::username, Self::as_select()))
+                .first::<UsernameAndKey>(conn)
+                .optional(),
+        )
+    }
+
+    /// Add a new user key to the db and record it in the key history
+    pub fn add_key(
+        conn: &mut DbConnection,
+        key: NewPublicUserKey,
+        actor: &str,
+    ) -> Result<(), String> {
+        query_drop(conn.transaction(|conn| {
+            insert_into(user_key::table).values(&key).execute(conn)?;
+
+            let id = user_key::table
+                .filter(user_key::key_base64.eq(key.key_base64()))
+                .select(user_key::id)
+                .first::<i32>(conn)?;
+
+            KeyHistory::record(conn, history::CREATED, &[id], actor)
+        }))
+    }
+
+    /// Remove a key from the db and record it in the key history. Returns the deleted key with its owner.
+    pub fn delete_key(
+        conn: &mut DbConnection,
+        key: i32,
+        actor: &str,
+    ) -> Result<UsernameAndKey, String> {
+        found(conn.transaction(|conn| {
+            let deleted = user_key::table
+                .inner_join(user::table)
+                .filter(user_key::id.eq(key))
+                .select((user::username, Self::as_select()))
+                .first::<UsernameAndKey>(conn)?;
+            KeyHistory::record(conn, history::DELETED, &[key], actor)?;
+            diesel::delete(user_key::table.filter(user_key::id.eq(key))).execute(conn)?;
+            Ok(deleted)
+        }))
+    }
+
+    /// Hosts and logins whose authorized_keys files get the keys of these users, through
+    /// authorizations and authorization rules
+    pub fn deployed_to(
+        conn: &mut DbConnection,
+        usernames: &[&str],
+    ) -> Result<Vec<(String, String)>, String> {
+        let mut logins: BTreeSet<(String, String)> = query(
+            authorization::table
+                .inner_join(user::table)
+                .inner_join(host::table)
+                .filter(user::username.eq_any(usernames))
+                .select((host::name, authorization::login))
+                .load::<(String, String)>(conn),
+        )?
+        .into_iter()
+        .collect();
+        for host in Host::get_all_hosts(conn)? {
+            for (_, username, login, _) in host.get_rule_grants(conn)? {
+                if usernames.contains(&username.as_str()) {
+                    logins.insert((host.name.clone(), login));
+                }
+            }
+        }
+        Ok(logins.into_iter().collect())
+    }
+
+    /// Moves a key to another user and records the transfer in the key history.
+    /// The key is then deployed wherever the new owner is authorized. Returns the previous owner.
+    pub fn transfer(
+        conn: &mut DbConnection,
+        key: i32,
+        to_user: i32,
+        actor: &str,
+    ) -> Result<String, String> {
+        found(conn.transaction(|conn| {
+            let (previous_username, _) = user_key::table
+                .inner_join(user::table)
+                .filter(user_key::id.eq(key))
+                .select((user::username, Self::as_select()))
+                .first::<UsernameAndKey>(conn)?;
+            diesel::update(user_key::table.filter(user_key::id.eq(key)))
+                .set(user_key::user_id.eq(to_user))
+                .execute(conn)?;
+            KeyHistory::record_transfer(conn, key, actor, previous_username.clone())?;
+            Ok(previous_username)
+        }))
     }
 
     pub fn update_comment(
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+
+use diesel::prelude::*;
+use serde::Serialize;
+use time::PrimitiveDateTime;
+
+use crate::{
+    models::{KeyHistory, User},
+    schema::{key_history, user, user_key},
+    DbConnection,
+};
+
+use super::{history, query};
+
+/// Selects the keys of a batch operation, a key has to match every criterion that is set
+#[derive(Debug, Default)]
+pub struct KeyFilter {
+    /// Owner of the keys
+    pub username: Option<String>,
+    /// Pattern for the comment, `*` matches any text and `?` a single character.
+    /// Keys without a comment never match.
+    pub comment: Option<String>,
+    /// Keys added to ssm before this time. Keys added before the key history was
+    /// recorded have no known age and never match.
+    pub created_before: Option<PrimitiveDateTime>,
+}
+
+impl KeyFilter {
+    /// Whether no criterion is set, so every key would match
+    pub fn is_empty(&self) -> bool {
+        self.username.is_none() && self.comment.is_none() && self.created_before.is_none()
+    }
+
+    fn matches(&self, comment: Option<&str>, created: Option<&PrimitiveDateTime>) -> bool {
+        let comment_matches = self
+            .comment
+            .as_deref()
+            .is_none_or(|pattern| comment.is_some_and(|comment| glob_matches(pattern, comment)));
+        let age_matches = self
+            .created_before
+            .is_none_or(|before| created.is_some_and(|created| *created < before));
+        comment_matches && age_matches
+    }
+}
+
+/// `*` matches any text and `?` a single character, everything else itself
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    // Position after the last `*` in the pattern and the text it started to cover
+    let mut star = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((after_star, covered)) => {
+                    p = after_star;
+                    t = covered + 1;
+                    star = Some((after_star, covered + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// A key selected by a batch operation
+#[derive(Debug, Serialize)]
+pub struct BatchKey {
+    pub id: i32,
+    /// Owner before the operation
+    pub username: String,
+    pub key_type: String,
+    pub key_base64: String,
+    pub comment: Option<String>,
+    /// When the key was added, unknown for keys older than the key history
+    #[serde(with = "crate::db::utc_rfc3339::option")]
+    pub created_at: Option<PrimitiveDateTime>,
+}
+
+/// What a batch operation on keys changes
+#[derive(Debug, Serialize)]
+pub struct KeyBatch {
+    pub keys: Vec<BatchKey>,
+    /// Whether the operation was carried out or only planned
+    pub applied: bool,
+}
+
+impl KeyBatch {
+    /// Deletes every key matching the filter and records it in the key history.
+    /// With `dry_run` only reports which keys would be deleted.
+    pub fn delete(
+        conn: &mut DbConnection,
+        filter: &KeyFilter,
+        actor: &str,
+        dry_run: bool,
+    ) -> Result<Self, String> {
+        query(conn.transaction(|conn| {
+            let mut batch = Self::select(conn, filter)?;
+            if dry_run || batch.keys.is_empty() {
+                return Ok(batch);
+            }
+
+            let ids = batch.ids();
+            KeyHistory::record(conn, history::DELETED, &ids, actor)?;
+            diesel::delete(user_key::table.filter(user_key::id.eq_any(&ids))).execute(conn)?;
+            batch.applied = true;
+            Ok(batch)
+        }))
+    }
+
+    /// Moves every key matching the filter to another user and records the transfers in the
+    /// key history. Keys that already belong to `to` are left out.
+    /// With `dry_run` only reports which keys would be moved.
+    pub fn reassign(
+        conn: &mut DbConnection,
+        filter: &KeyFilter,
+        to: &User,
+        actor: &str,
+        dry_run: bool,
+    ) -> Result<Self, String> {
+        query(conn.transaction(|conn| {
+            let mut batch = Self::select(conn, filter)?;
+            batch.keys.retain(|key| key.username.ne(&to.username));
+            if dry_run || batch.keys.is_empty() {
+                return Ok(batch);
+            }
+
+            diesel::update(user_key::table.filter(user_key::id.eq_any(batch.ids())))
+                .set(user_key::user_id.eq(to.id))
+                .execute(conn)?;
+            for key in &batch.keys {
+                KeyHistory::record_transfer(conn, key.id, actor, key.username.clone())?;
+            }
+            batch.applied = true;
+            Ok(batch)
+        }))
+    }
+
+    fn select(conn: &mut DbConnection, filter: &KeyFilter) -> QueryResult<Self> {
+        let mut keys = user_key::table
+            .inner_join(user::table)
+            .select((
+                user_key::id,
+                user::username,
+                user_key::key_type,
+                user_key::key_base64,
+                user_key::comment,
+            ))
+            .order(user_key::id)
+            .into_boxed();
+        if let Some(username) = &filter.username {
+            keys = keys.filter(user::username.eq(username));
+        }
+        let keys = keys.load::<(i32, String, String, String, Option<String>)>(conn)?;
+
+        // Ordered newest first, so the earliest record of a key wins
+        let created: HashMap<i32, PrimitiveDateTime> = key_history::table
+            .filter(key_history::action.eq(history::CREATED))
+            .filter(key_history::key_id.is_not_null())
+            .select((
+                key_history::key_id.assume_not_null(),
+                key_history::changed_at,
+            ))
+            .order(key_history::changed_at.desc())
+            .load::<(i32, PrimitiveDateTime)>(conn)?
+            .into_iter()
+            .collect();
+
+        let keys = keys
+            .into_iter()
+            .filter(|(id, _, _, _, comment)| filter.matches(comment.as_deref(), created.get(id)))
+            .map(|(id, username, key_type, key_base64, comment)| BatchKey {
+                created_at: created.get(&id).copied(),
+                id,
+                username,
+                key_type,
+                key_base64,
+                comment,
+            })
+            .collect();
+
+        Ok(Self {
+            keys,
+            applied: false,
+        })
+    }
+
+    fn ids(&self) -> Vec<i32> {
+        self.keys.iter().map(|key| key.id).collect()
+    }
+
+    /// Owners of the keys before the operation
+    pub fn usernames(&self) -> Vec<&str> {
+        let mut usernames: Vec<&str> = self.keys.iter().map(|key| key.username.as_str()).collect();
+        usernames.sort_unstable();
+        usernames.dedup();
+        usernames
+    }
+}
+
+impl BatchKey {
+    /// SHA256 fingerprint, as sshd logs it
+    pub fn fingerprint(&self) -> Option<String> {
+        ssh_key::PublicKey::from_openssh(&format!("{} {}", self.key_type, self.key_base64))
+            .ok()
+            .map(|key| key.fingerprint(ssh_key::HashAlg::Sha256).to_string())
+    }
+}
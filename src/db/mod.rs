@@ -6,14 +6,40 @@ use ssh_key::{authorized_keys::ConfigOpts, Algorithm};
 
 use crate::{models::PublicUserKey, ssh::AuthorizedKey};
 
+pub mod activity;
+mod authorization_rule;
+pub mod backup;
+pub mod diff_ignore_rule;
+mod freeze_window;
+pub mod history;
 mod host;
+pub mod host_confirmation;
 mod key;
-mod user;
+pub mod key_batch;
+pub mod orphans;
+mod outbox;
+mod pending_host;
+mod principals;
+pub mod recertification;
+mod schedule;
+pub mod search;
+pub mod simulation;
+pub mod security_event;
+pub mod stats;
+pub mod unused_access;
+pub mod user;
+pub mod user_merge;
 
 // TODO: this should probably be a struct
 /// Authorization ID, Username, Login and SSH options
 pub type UserAndOptions = (i32, String, String, Option<String>);
 
+/// Rule ID, Username, Login and SSH options of an authorization granted by a rule
+pub type RuleGrant = (i32, String, String, Option<String>);
+
+/// Login, certificate principal and Username of a principal authorized on a host
+pub type PrincipalGrant = (String, String, String);
+
 /// A fictional authorized_keys entry for an allowed user
 #[derive(Clone, Debug)]
 pub struct AllowedUserOnHost {
@@ -78,3 +104,39 @@ pub fn query_drop(query_result: Result<usize, Error>) -> Result<(), String> {
         Err(_) => query(query_result).map(|_| ()),
     }
 }
+
+/// Like `query`, but reports a missing record as not found instead of a database error
+pub fn found<T>(query_result: Result<T, Error>) -> Result<T, String> {
+    match query_result {
+        Err(Error::NotFound) => Err(String::from("Record not found.")),
+        query_result => query(query_result),
+    }
+}
+
+/// Timestamps are stored as UTC without an offset, this serializes them as RFC 3339
+pub mod utc_rfc3339 {
+    use serde::Serializer;
+    use time::PrimitiveDateTime;
+
+    pub fn serialize<S: Serializer>(
+        value: &PrimitiveDateTime,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        time::serde::rfc3339::serialize(&value.assume_utc(), serializer)
+    }
+
+    pub mod option {
+        use serde::Serializer;
+        use time::PrimitiveDateTime;
+
+        pub fn serialize<S: Serializer>(
+            value: &Option<PrimitiveDateTime>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            time::serde::rfc3339::option::serialize(
+                &value.map(PrimitiveDateTime::assume_utc),
+                serializer,
+            )
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use diesel::prelude::*;
+use serde::Serialize;
+
+use crate::{
+    models::{AuthorizationHistory, Host, KeyHistory, User},
+    schema::{authorization, authorization_rule, host, user, user_key},
+    DbConnection,
+};
+
+use super::{history, query};
+
+/// A key whose owner isn't authorized anywhere
+#[derive(Debug, Serialize)]
+pub struct OrphanKey {
+    pub id: i32,
+    pub username: String,
+    pub key_type: String,
+    pub comment: Option<String>,
+}
+
+/// A host nobody is authorized on, neither directly nor by a rule
+#[derive(Debug, Serialize)]
+pub struct OrphanHost {
+    pub name: String,
+    /// Protected hosts are reported but never cleaned up
+    pub protected: bool,
+}
+
+/// An authorization that can't grant access because its user is disabled
+#[derive(Debug, Serialize)]
+pub struct OrphanAuthorization {
+    pub id: i32,
+    pub host: String,
+    pub username: String,
+    pub login: String,
+}
+
+/// Entries that don't grant access to anything and accumulate over time
+#[derive(Debug, Default, Serialize)]
+pub struct Orphans {
+    pub keys: Vec<OrphanKey>,
+    /// Users without keys, except those with certificate principals
+    pub users: Vec<String>,
+    /// Hosts without authorizations that no other host jumps through
+    pub hosts: Vec<OrphanHost>,
+    pub authorizations: Vec<OrphanAuthorization>,
+}
+
+/// Which orphans to clean up
+#[derive(Debug, Clone, Copy)]
+pub enum OrphanKind {
+    Keys,
+    Users,
+    Hosts,
+    Authorizations,
+}
+
+impl FromStr for OrphanKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keys" => Ok(Self::Keys),
+            "users" => Ok(Self::Users),
+            "hosts" => Ok(Self::Hosts),
+            "authorizations" => Ok(Self::Authorizations),
+            _ => Err(format!(
+                "Unknown orphans '{s}', expected keys, users, hosts or authorizations"
+            )),
+        }
+    }
+}
+
+impl Orphans {
+    pub fn find(conn: &mut DbConnection) -> Result<Self, String> {
+        query(Self::load(conn))
+    }
+
+    /// Deletes the orphans of one kind in a transaction and records them in the history.
+    /// Returns what was deleted, the other kinds are left empty.
+    pub fn clean_up(
+        conn: &mut DbConnection,
+        kind: OrphanKind,
+        actor: &str,
+    ) -> Result<Self, String> {
+        query(conn.transaction(|conn| {
+            let orphans = Self::load(conn)?;
+            let mut removed = Self::default();
+            match kind {
+                OrphanKind::Keys => {
+                    let ids: Vec<i32> = orphans.keys.iter().map(|key| key.id).collect();
+                    KeyHistory::record(conn, history::DELETED, &ids, actor)?;
+                    diesel::delete(user_key::table.filter(user_key::id.eq_any(&ids)))
+                        .execute(conn)?;
+                    removed.keys = orphans.keys;
+                }
+                OrphanKind::Users => {
+                    let authorization_ids = authorization::table
+                        .inner_join(user::table)
+                        .filter(user::username.eq_any(&orphans.users))
+                        .select(authorization::id)
+                        .load::<i32>(conn)?;
+                    AuthorizationHistory::record(
+                        conn,
+                        history::DELETED,
+                        &authorization_ids,
+                        actor,
+                    )?;
+                    diesel::delete(user::table.filter(user::username.eq_any(&orphans.users)))
+                        .execute(conn)?;
+                    removed.users = orphans.users;
+                }
+                OrphanKind::Hosts => {
+                    removed.hosts = orphans
+                        .hosts
+                        .into_iter()
+                        .filter(|host| !host.protected)
+                        .collect();
+                    let names: Vec<&str> = removed
+                        .hosts
+                        .iter()
+                        .map(|host| host.name.as_str())
+                        .collect();
+                    diesel::delete(host::table.filter(host::name.eq_any(names))).execute(conn)?;
+                }
+                OrphanKind::Authorizations => {
+                    let ids: Vec<i32> = orphans.authorizations.iter().map(|a| a.id).collect();
+                    AuthorizationHistory::record(conn, history::DELETED, &ids, actor)?;
+                    diesel::delete(authorization::table.filter(authorization::id.eq_any(&ids)))
+                        .execute(conn)?;
+                    removed.authorizations = orphans.authorizations;
+                }
+            }
+            Ok(removed)
+        }))
+    }
+
+    fn load(conn: &mut DbConnection) -> QueryResult<Self> {
+        let users = user::table.order(user::username).load::<User>(conn)?;
+        let hosts = host::table.order(host::name).load::<Host>(conn)?;
+        let rules = authorization_rule::table
+            .select((authorization_rule::department, authorization_rule::tag))
+            .load::<(String, String)>(conn)?;
+        let authorizations = authorization::table
+            .inner_join(host::table)
+            .inner_join(user::table)
+            .select((
+                authorization::id,
+                host::name,
+                user::username,
+                authorization::login,
+                authorization::principals,
+            ))
+            .order(authorization::id)
+            .load::<(i32, String, String, String, Option<String>)>(conn)?;
+        let key_owners: HashSet<i32> = user_key::table
+            .select(user_key::user_id)
+            .load::<i32>(conn)?
+            .into_iter()
+            .collect();
+
+        // Rules only grant something if a host has their tag and a user their department
+        let rules: Vec<(String, String)> = rules
+            .into_iter()
+            .filter(|(department, tag)| {
+                hosts.iter().any(|host| host.has_tag(tag))
+                    && users
+                        .iter()
+                        .any(|user| user.department.as_ref().is_some_and(|d| d.eq(department)))
+            })
+            .collect();
+        let authorized_users: HashSet<&str> = authorizations
+            .iter()
+            .map(|(_, _, username, _, _)| username.as_str())
+            .collect();
+        let principal_users: HashSet<&str> = authorizations
+            .iter()
+            .filter(|(_, _, _, _, principals)| principals.as_ref().is_some_and(|p| !p.is_empty()))
+            .map(|(_, _, username, _, _)| username.as_str())
+            .collect();
+        let authorized_hosts: HashSet<&str> = authorizations
+            .iter()
+            .map(|(_, host, _, _, _)| host.as_str())
+            .collect();
+        let jump_hosts: HashSet<i32> = hosts.iter().filter_map(|host| host.jump_via).collect();
+
+        let keys = user_key::table
+            .inner_join(user::table)
+            .select((
+                user_key::id,
+                user::username,
+                user::department,
+                user_key::key_type,
+                user_key::comment,
+            ))
+            .order(user_key::id)
+            .load::<(i32, String, Option<String>, String, Option<String>)>(conn)?
+            .into_iter()
+            .filter(|(_, username, department, _, _)| {
+                !authorized_users.contains(username.as_str())
+                    && !rules
+                        .iter()
+                        .any(|(rule_department, _)| department.as_ref() == Some(rule_department))
+            })
+            .map(|(id, username, _, key_type, comment)| OrphanKey {
+                id,
+                username,
+                key_type,
+                comment,
+            })
+            .collect();
+
+        let orphan_users = users
+            .iter()
+            .filter(|user| {
+                !key_owners.contains(&user.id) && !principal_users.contains(user.username.as_str())
+            })
+            .map(|user| user.username.clone())
+            .collect();
+
+        let orphan_hosts = hosts
+            .iter()
+            .filter(|host| {
+                !authorized_hosts.contains(host.name.as_str())
+                    && !jump_hosts.contains(&host.id)
+                    && !rules.iter().any(|(_, tag)| host.has_tag(tag))
+            })
+            .map(|host| OrphanHost {
+                name: host.name.clone(),
+                protected: host.protected,
+            })
+            .collect();
+
+        let disabled: HashSet<&str> = users
+            .iter()
+            .filter(|user| !user.enabled)
+            .map(|user| user.username.as_str())
+            .collect();
+        let orphan_authorizations = authorizations
+            .iter()
+            .filter(|(_, _, username, _, _)| disabled.contains(username.as_str()))
+            .map(|(id, host, username, login, _)| OrphanAuthorization {
+                id: *id,
+                host: host.clone(),
+                username: username.clone(),
+                login: login.clone(),
+            })
+            .collect();
+
+        Ok(Self {
+            keys,
+            users: orphan_users,
+            hosts: orphan_hosts,
+            authorizations: orphan_authorizations,
+        })
+    }
+}
@@ -0,0 +1,41 @@
+use diesel::prelude::*;
+
+use crate::{
+    models::{NewOutboxEvent, OutboxEvent},
+    schema::event_outbox,
+    DbConnection,
+};
+
+use super::history::now;
+use super::{query, query_drop};
+
+impl OutboxEvent {
+    pub fn record(conn: &mut DbConnection, event: &str, payload: String) -> Result<(), String> {
+        query_drop(
+            diesel::insert_into(event_outbox::table)
+                .values(NewOutboxEvent {
+                    event: event.to_owned(),
+                    payload,
+                    created_at: now(),
+                })
+                .execute(conn),
+        )
+    }
+
+    /// The oldest events that weren't published yet, in the order they happened
+    pub fn pending(conn: &mut DbConnection, limit: i64) -> Result<Vec<Self>, String> {
+        query(
+            event_outbox::table
+                .order(event_outbox::id.asc())
+                .limit(limit)
+                .load::<Self>(conn),
+        )
+    }
+
+    /// Removes events after they were published
+    pub fn remove(conn: &mut DbConnection, ids: &[i32]) -> Result<(), String> {
+        query_drop(
+            diesel::delete(event_outbox::table.filter(event_outbox::id.eq_any(ids))).execute(conn),
+        )
+    }
+}
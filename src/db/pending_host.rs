@@ -0,0 +1,116 @@
+use std::str::FromStr;
+
+use diesel::prelude::*;
+use time::Duration;
+use uuid::Uuid;
+
+use crate::{
+    models::{Host, NewHost, PendingHost},
+    schema::pending_host,
+    ssh::{ConnectionDetails, Proxy},
+    ConnectionPool, DbConnection,
+};
+
+use super::history::now;
+use super::query;
+
+/// How long a discovered host key can be confirmed
+const PENDING_HOST_TTL: Duration = Duration::minutes(15);
+
+impl PendingHost {
+    /// Stores a host with the key it offered until the key is confirmed, returns the confirmation token.
+    /// Expired pending hosts are removed on the way.
+    pub fn create(conn: &mut DbConnection, host: NewHost) -> Result<String, String> {
+        let created_at = now();
+        query(
+            diesel::delete(
+                pending_host::table
+                    .filter(pending_host::created_at.lt(created_at - PENDING_HOST_TTL)),
+            )
+            .execute(conn),
+        )?;
+
+        let token = Uuid::new_v4().simple().to_string();
+        query(
+            diesel::insert_into(pending_host::table)
+                .values(Self {
+                    token: token.clone(),
+                    name: host.name,
+                    username: host.username,
+                    address: host.address,
+                    port: host.port,
+                    key_fingerprint: host.key_fingerprint,
+                    jump_via: host.jump_via,
+                    transport: host.transport,
+                    tags: host.tags,
+                    proxy: host.proxy,
+                    created_at,
+                })
+                .execute(conn),
+        )?;
+        Ok(token)
+    }
+
+    /// Gets a pending host, unless it expired
+    pub fn get(conn: &mut DbConnection, token: &str) -> Result<Option<Self>, String> {
+        query(
+            pending_host::table
+                .filter(pending_host::token.eq(token))
+                .filter(pending_host::created_at.ge(now() - PENDING_HOST_TTL))
+                .first::<Self>(conn)
+                .optional(),
+        )
+    }
+
+    /// All pending hosts that didn't expire yet, newest first
+    pub fn list(conn: &mut DbConnection) -> Result<Vec<Self>, String> {
+        query(
+            pending_host::table
+                .filter(pending_host::created_at.ge(now() - PENDING_HOST_TTL))
+                .order(pending_host::created_at.desc())
+                .load::<Self>(conn),
+        )
+    }
+
+    /// The jumphost and connection details to reach this host
+    pub async fn connection(
+        &self,
+        conn: &ConnectionPool,
+    ) -> Result<(Option<Host>, ConnectionDetails), String> {
+        let jumphost = match self.jump_via {
+            Some(via) => Some(
+                Host::get_from_id(conn.get().unwrap(), via)
+                    .await?
+                    .ok_or_else(|| String::from("Couldn't find jump host"))?,
+            ),
+            None => None,
+        };
+        let mut address = ConnectionDetails::new(self.address.clone(), self.port);
+        address.proxy = self.proxy.as_deref().map(Proxy::from_str).transpose()?;
+        Ok((jumphost, address))
+    }
+
+    /// Removes a pending host after it was added
+    pub fn delete(conn: &mut DbConnection, token: &str) -> Result<(), String> {
+        query(
+            diesel::delete(pending_host::table.filter(pending_host::token.eq(token))).execute(conn),
+        )
+        .map(|_| ())
+    }
+}
+
+impl From<PendingHost> for NewHost {
+    fn from(value: PendingHost) -> Self {
+        Self {
+            name: value.name,
+            address: value.address,
+            port: value.port,
+            username: value.username,
+            key_fingerprint: value.key_fingerprint,
+            jump_via: value.jump_via,
+            transport: value.transport,
+            tags: value.tags,
+            proxy: value.proxy,
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use diesel::prelude::*;
+
+use crate::{
+    models::{AuthorizationHistory, Host},
+    schema::{authorization, user},
+    DbConnection,
+};
+
+use super::{history, query, query_drop, PrincipalGrant};
+
+impl Host {
+    /// Checks a location like the AuthorizedPrincipalsFile of sshd, relative to the home directory
+    /// or absolute with `%h` and `%u`. It ends up in shell commands, so only portable path characters are allowed.
+    pub fn check_principals_file(location: &str) -> Result<(), String> {
+        let mut tokens = location.split('%').skip(1);
+        if location.is_empty()
+            || location.starts_with('-')
+            || !location
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '.' | '_' | '-' | '%'))
+            || !tokens.all(|rest| rest.starts_with(['h', 'u']))
+        {
+            return Err(format!(
+                "'{location}' isn't a principals file like .ssh/authorized_principals or /etc/ssh/principals/%u"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Where the principals of a login are, `home` is put in as is
+    pub fn principals_location(location: &str, login: &str, home: &str) -> String {
+        let location = location.replace("%h", home).replace("%u", login);
+        if location.starts_with('/') {
+            location
+        } else {
+            format!("{home}/{location}")
+        }
+    }
+
+    /// Principals of the enabled users authorized on this host, sorted by login and principal
+    pub fn get_authorized_principals(
+        &self,
+        conn: &mut DbConnection,
+    ) -> Result<Vec<PrincipalGrant>, String> {
+        let authorizations = query(
+            authorization::table
+                .inner_join(user::table)
+                .filter(authorization::host_id.eq(self.id))
+                .filter(authorization::principals.is_not_null())
+                .filter(user::enabled.eq(true))
+                .select((
+                    authorization::login,
+                    authorization::principals.assume_not_null(),
+                    user::username,
+                ))
+                .load::<(String, String, String)>(conn),
+        )?;
+
+        let mut grants: Vec<PrincipalGrant> = authorizations
+            .into_iter()
+            .flat_map(|(login, principals, username)| {
+                principals
+                    .split(',')
+                    .filter(|principal| !principal.is_empty())
+                    .map(|principal| (login.clone(), principal.to_owned(), username.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        grants.sort();
+        Ok(grants)
+    }
+
+    /// Generate the authorized_principals file for a login on this host
+    pub fn get_authorized_principals_file_for(
+        &self,
+        conn: &mut DbConnection,
+        login: &str,
+    ) -> Result<String, String> {
+        let mut principals: Vec<String> = self
+            .get_authorized_principals(conn)?
+            .into_iter()
+            .filter(|(principal_login, _, _)| principal_login.eq(login))
+            .map(|(_, principal, _)| principal)
+            .collect();
+        principals.dedup();
+        Ok(principals
+            .into_iter()
+            .map(|principal| format!("{principal}\n"))
+            .collect())
+    }
+
+    /// Replaces the certificate principals of an authorization, an empty list removes them.
+    /// Returns the principals as stored, sorted and without duplicates.
+    pub fn set_authorization_principals(
+        conn: &mut DbConnection,
+        authorization_id: i32,
+        principals: &[String],
+        actor: &str,
+    ) -> Result<Vec<String>, String> {
+        let mut principals: Vec<&str> = principals.iter().map(|p| p.trim()).collect();
+        if let Some(invalid) = principals.iter().find(|principal| {
+            principal.is_empty()
+                || principal.starts_with('#')
+                || principal.contains(|c: char| c.is_whitespace() || c == ',')
+        }) {
+            return Err(format!("'{invalid}' isn't a valid principal"));
+        }
+        principals.sort_unstable();
+        principals.dedup();
+        let joined = (!principals.is_empty()).then(|| principals.join(","));
+
+        query_drop(conn.transaction(|conn| {
+            let updated =
+                diesel::update(authorization::table.filter(authorization::id.eq(authorization_id)))
+                    .set(authorization::principals.eq(joined))
+                    .execute(conn)?;
+            if updated > 0 {
+                AuthorizationHistory::record(conn, history::UPDATED, &[authorization_id], actor)?;
+            }
+            Ok(updated)
+        }))?;
+        Ok(principals.into_iter().map(str::to_owned).collect())
+    }
+}
@@ -0,0 +1,273 @@
+use diesel::prelude::*;
+use serde::Serialize;
+use time::PrimitiveDateTime;
+
+use crate::{
+    models::{
+        AuthorizationHistory, Host, NewRecertificationCampaign, NewRecertificationItem,
+        RecertificationCampaign, RecertificationItem,
+    },
+    schema::{authorization, host, recertification_campaign, recertification_item, user},
+    DbConnection,
+};
+
+use super::history::{self, now};
+use super::{found, query};
+
+pub const APPROVED: &str = "approved";
+pub const REVOKED: &str = "revoked";
+/// Revoked at the deadline of a campaign with `auto_revoke`
+pub const AUTO_REVOKED: &str = "auto_revoked";
+/// Not reviewed by the deadline of a campaign without `auto_revoke`
+pub const OVERDUE: &str = "overdue";
+
+/// How far a campaign got
+#[derive(Debug, Serialize)]
+pub struct RecertificationReport {
+    #[serde(flatten)]
+    pub campaign: RecertificationCampaign,
+    pub total: usize,
+    pub approved: usize,
+    pub revoked: usize,
+    pub auto_revoked: usize,
+    pub overdue: usize,
+    pub pending: usize,
+}
+
+impl RecertificationCampaign {
+    /// Starts a campaign with an item for every authorization on the hosts with the tag,
+    /// or on all hosts. `None` if there are no authorizations to review.
+    pub fn create(
+        conn: &mut DbConnection,
+        name: String,
+        tag: Option<String>,
+        deadline: PrimitiveDateTime,
+        auto_revoke: bool,
+        created_by: &str,
+    ) -> Result<Option<Self>, String> {
+        query(conn.transaction(|conn| {
+            let authorizations: Vec<_> = authorization::table
+                .inner_join(host::table)
+                .inner_join(user::table)
+                .select((
+                    authorization::id,
+                    Host::as_select(),
+                    user::username,
+                    authorization::login,
+                    authorization::options,
+                ))
+                .order((host::name, user::username, authorization::login))
+                .load::<(i32, Host, String, String, Option<String>)>(conn)?
+                .into_iter()
+                .filter(|(_, host, _, _, _)| tag.as_deref().is_none_or(|tag| host.has_tag(tag)))
+                .collect();
+            if authorizations.is_empty() {
+                return Ok(None);
+            }
+
+            diesel::insert_into(recertification_campaign::table)
+                .values(NewRecertificationCampaign {
+                    name,
+                    tag,
+                    deadline,
+                    auto_revoke,
+                    created_by: created_by.to_owned(),
+                    created_at: now(),
+                })
+                .execute(conn)?;
+            let campaign = recertification_campaign::table
+                .order(recertification_campaign::id.desc())
+                .first::<Self>(conn)?;
+
+            for (authorization_id, host, username, login, options) in authorizations {
+                diesel::insert_into(recertification_item::table)
+                    .values(NewRecertificationItem {
+                        campaign_id: campaign.id,
+                        authorization_id,
+                        host: host.name,
+                        username,
+                        login,
+                        options,
+                    })
+                    .execute(conn)?;
+            }
+            Ok(Some(campaign))
+        }))
+    }
+
+    pub fn get(conn: &mut DbConnection, id: i32) -> Result<Self, String> {
+        found(
+            recertification_campaign::table
+                .filter(recertification_campaign::id.eq(id))
+                .first::<Self>(conn),
+        )
+    }
+
+    /// All campaigns, newest first
+    pub fn all(conn: &mut DbConnection) -> Result<Vec<Self>, String> {
+        query(
+            recertification_campaign::table
+                .order(recertification_campaign::id.desc())
+                .load::<Self>(conn),
+        )
+    }
+
+    pub fn items(&self, conn: &mut DbConnection) -> Result<Vec<RecertificationItem>, String> {
+        query(
+            recertification_item::table
+                .filter(recertification_item::campaign_id.eq(self.id))
+                .order(recertification_item::id)
+                .load::<RecertificationItem>(conn),
+        )
+    }
+
+    pub fn report(self, conn: &mut DbConnection) -> Result<RecertificationReport, String> {
+        let decisions = query(
+            recertification_item::table
+                .filter(recertification_item::campaign_id.eq(self.id))
+                .select(recertification_item::decision)
+                .load::<Option<String>>(conn),
+        )?;
+        let count = |decision: &str| {
+            decisions
+                .iter()
+                .filter(|d| d.as_deref() == Some(decision))
+                .count()
+        };
+        Ok(RecertificationReport {
+            total: decisions.len(),
+            approved: count(APPROVED),
+            revoked: count(REVOKED),
+            auto_revoked: count(AUTO_REVOKED),
+            overdue: count(OVERDUE),
+            pending: decisions.iter().filter(|d| d.is_none()).count(),
+            campaign: self,
+        })
+    }
+
+    /// Closes the campaign once every item was reviewed, returns its report if it was closed
+    pub fn close_if_reviewed(
+        conn: &mut DbConnection,
+        id: i32,
+    ) -> Result<Option<RecertificationReport>, String> {
+        let closed = query(
+            diesel::update(
+                recertification_campaign::table
+                    .filter(recertification_campaign::id.eq(id))
+                    .filter(recertification_campaign::closed_at.is_null()),
+            )
+            .filter(diesel::dsl::not(diesel::dsl::exists(
+                recertification_item::table
+                    .filter(recertification_item::campaign_id.eq(id))
+                    .filter(recertification_item::decision.is_null()),
+            )))
+            .set(recertification_campaign::closed_at.eq(now()))
+            .execute(conn),
+        )?;
+        if closed == 0 {
+            return Ok(None);
+        }
+        Self::get(conn, id)?.report(conn).map(Some)
+    }
+
+    /// Closes the open campaigns whose deadline passed. Their pending items are revoked
+    /// with `auto_revoke` and flagged as overdue otherwise. Returns the reports of the closed campaigns.
+    pub fn close_overdue(
+        conn: &mut DbConnection,
+        actor: &str,
+    ) -> Result<Vec<RecertificationReport>, String> {
+        let at = now();
+        let campaigns = query(
+            recertification_campaign::table
+                .filter(recertification_campaign::closed_at.is_null())
+                .filter(recertification_campaign::deadline.le(at))
+                .order(recertification_campaign::id)
+                .load::<Self>(conn),
+        )?;
+
+        let mut reports = Vec::with_capacity(campaigns.len());
+        for campaign in campaigns {
+            query(conn.transaction(|conn| {
+                let pending = recertification_item::table
+                    .filter(recertification_item::campaign_id.eq(campaign.id))
+                    .filter(recertification_item::decision.is_null());
+                let decision = if campaign.auto_revoke {
+                    let authorizations = pending
+                        .select(recertification_item::authorization_id)
+                        .load::<i32>(conn)?;
+                    revoke(conn, &authorizations, actor)?;
+                    AUTO_REVOKED
+                } else {
+                    OVERDUE
+                };
+                diesel::update(pending)
+                    .set((
+                        recertification_item::decision.eq(decision),
+                        recertification_item::reviewer.eq(actor),
+                        recertification_item::reviewed_at.eq(at),
+                    ))
+                    .execute(conn)?;
+                diesel::update(
+                    recertification_campaign::table
+                        .filter(recertification_campaign::id.eq(campaign.id)),
+                )
+                .set(recertification_campaign::closed_at.eq(at))
+                .execute(conn)
+            }))?;
+            reports.push(Self::get(conn, campaign.id)?.report(conn)?);
+        }
+        Ok(reports)
+    }
+}
+
+impl RecertificationItem {
+    pub fn get(conn: &mut DbConnection, campaign_id: i32, id: i32) -> Result<Option<Self>, String> {
+        query(
+            recertification_item::table
+                .filter(recertification_item::campaign_id.eq(campaign_id))
+                .filter(recertification_item::id.eq(id))
+                .first::<Self>(conn)
+                .optional(),
+        )
+    }
+
+    /// Records the decision of a reviewer, deleting the authorization if it is revoked
+    pub fn review(
+        self,
+        conn: &mut DbConnection,
+        approve: bool,
+        comment: Option<String>,
+        reviewer: &str,
+    ) -> Result<Self, String> {
+        query(conn.transaction(|conn| {
+            if !approve {
+                revoke(conn, &[self.authorization_id], reviewer)?;
+            }
+            diesel::update(
+                recertification_item::table.filter(recertification_item::id.eq(self.id)),
+            )
+            .set((
+                recertification_item::decision.eq(if approve { APPROVED } else { REVOKED }),
+                recertification_item::reviewer.eq(reviewer),
+                recertification_item::reviewed_at.eq(now()),
+                recertification_item::comment.eq(comment),
+            ))
+            .execute(conn)?;
+            recertification_item::table
+                .filter(recertification_item::id.eq(self.id))
+                .first::<Self>(conn)
+        }))
+    }
+}
+
+/// Deletes the authorizations that still exist, recording the deletion in their history
+fn revoke(conn: &mut DbConnection, ids: &[i32], actor: &str) -> QueryResult<()> {
+    let existing = authorization::table
+        .filter(authorization::id.eq_any(ids))
+        .select(authorization::id)
+        .load::<i32>(conn)?;
+    AuthorizationHistory::record(conn, history::DELETED, &existing, actor)?;
+    diesel::delete(authorization::table.filter(authorization::id.eq_any(&existing)))
+        .execute(conn)?;
+    Ok(())
+}
@@ -0,0 +1,33 @@
+use super::{query, query_drop};
+use crate::models::{NewSchedule, Schedule};
+use crate::schema::schedule;
+use crate::DbConnection;
+use diesel::dsl::insert_into;
+use diesel::prelude::*;
+
+impl Schedule {
+    pub fn get_all_schedules(conn: &mut DbConnection) -> Result<Vec<Self>, String> {
+        query(schedule::table.order(schedule::name).load::<Self>(conn))
+    }
+
+    pub fn add_schedule(conn: &mut DbConnection, new: &NewSchedule) -> Result<(), String> {
+        query_drop(insert_into(schedule::table).values(new).execute(conn))
+    }
+
+    /// Replace the schedule with this name
+    pub fn update_schedule(
+        conn: &mut DbConnection,
+        name: &str,
+        new: &NewSchedule,
+    ) -> Result<(), String> {
+        query_drop(
+            diesel::update(schedule::table.filter(schedule::name.eq(name)))
+                .set(new)
+                .execute(conn),
+        )
+    }
+
+    pub fn delete_schedule(conn: &mut DbConnection, name: &str) -> Result<(), String> {
+        query_drop(diesel::delete(schedule::table.filter(schedule::name.eq(name))).execute(conn))
+    }
+}
@@ -0,0 +1,96 @@
+//! Text search for the search box: a part of a name, address, comment or recorded request
+//! finds the hosts, users, keys and activity containing it
+use diesel::prelude::*;
+
+use crate::{
+    models::{Activity, Host, PublicUserKey, User},
+    schema::{activity, host, user, user_key},
+    DbConnection,
+};
+
+use super::{query, UsernameAndKey};
+
+/// What matched, at most `limit` of each kind
+pub struct SearchResults {
+    /// Sorted by name
+    pub hosts: Vec<Host>,
+    /// Sorted by username
+    pub users: Vec<User>,
+    /// Keys with their owner, sorted by owner
+    pub keys: Vec<UsernameAndKey>,
+    /// Newest first
+    pub activity: Vec<Activity>,
+}
+
+impl SearchResults {
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+            && self.users.is_empty()
+            && self.keys.is_empty()
+            && self.activity.is_empty()
+    }
+}
+
+/// Hosts by name, address, fallback address, alias or tag, users by username, real name, email or
+/// department, keys by comment or base64 and activity by actor, action or path
+pub fn search(conn: &mut DbConnection, text: &str, limit: i64) -> Result<SearchResults, String> {
+    let pattern = format!("%{text}%");
+    let hosts = query(
+        host::table
+            .filter(
+                host::name
+                    .like(&pattern)
+                    .or(host::address.like(&pattern))
+                    .or(host::fallback_addresses.like(&pattern))
+                    .or(host::aliases.like(&pattern))
+                    .or(host::tags.like(&pattern)),
+            )
+            .order(host::name)
+            .limit(limit)
+            .load::<Host>(conn),
+    )?;
+    let users = query(
+        user::table
+            .filter(
+                user::username
+                    .like(&pattern)
+                    .or(user::full_name.like(&pattern))
+                    .or(user::email.like(&pattern))
+                    .or(user::department.like(&pattern)),
+            )
+            .order(user::username)
+            .limit(limit)
+            .load::<User>(conn),
+    )?;
+    let keys = query(
+        user_key::table
+            .inner_join(user::table)
+            .filter(
+                user_key::comment
+                    .like(&pattern)
+                    .or(user_key::key_base64.like(&pattern)),
+            )
+            .select((user::username, PublicUserKey::as_select()))
+            .order((user::username, user_key::id))
+            .limit(limit)
+            .load::<UsernameAndKey>(conn),
+    )?;
+    let activity = query(
+        activity::table
+            .filter(
+                activity::actor
+                    .like(&pattern)
+                    .or(activity::action.like(&pattern))
+                    .or(activity::path.like(&pattern)),
+            )
+            .order((activity::created_at.desc(), activity::id.desc()))
+            .limit(limit)
+            .load::<Activity>(conn),
+    )?;
+    Ok(SearchResults {
+        hosts,
+        users,
+        keys,
+        activity,
+    })
+}
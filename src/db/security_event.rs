@@ -0,0 +1,50 @@
+use diesel::prelude::*;
+
+use crate::{
+    models::{NewSecurityEvent, SecurityEvent},
+    schema::security_event,
+    DbConnection,
+};
+
+use super::history::now;
+use super::query;
+
+pub const HOSTKEY_MISMATCH: &str = "hostkey.mismatch";
+/// A deployment during a change freeze
+pub const FREEZE_OVERRIDE: &str = "freeze.override";
+
+pub const CRITICAL: &str = "critical";
+pub const WARNING: &str = "warning";
+
+impl SecurityEvent {
+    pub fn record(
+        conn: &mut DbConnection,
+        kind: &str,
+        severity: &str,
+        host_name: Option<&str>,
+        message: String,
+    ) -> Result<(), String> {
+        query(
+            diesel::insert_into(security_event::table)
+                .values(NewSecurityEvent {
+                    kind: kind.to_owned(),
+                    severity: severity.to_owned(),
+                    host_name: host_name.map(str::to_owned),
+                    message,
+                    created_at: now(),
+                })
+                .execute(conn),
+        )
+        .map(|_| ())
+    }
+
+    /// The most recent events, newest first
+    pub fn recent(conn: &mut DbConnection, limit: i64) -> Result<Vec<Self>, String> {
+        query(
+            security_event::table
+                .order((security_event::created_at.desc(), security_event::id.desc()))
+                .limit(limit)
+                .load::<Self>(conn),
+        )
+    }
+}
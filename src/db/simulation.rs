@@ -0,0 +1,146 @@
+use std::collections::{BTreeMap, HashSet};
+
+use diesel::prelude::*;
+use diesel::result::Error;
+use serde::Serialize;
+
+use crate::{
+    models::Host,
+    schema::{authorization, user},
+    DbConnection,
+};
+
+use super::{query, AllowedUserOnHost};
+
+/// A hypothetical change, applied in a transaction that is rolled back
+#[derive(Debug)]
+pub enum SimulatedChange {
+    Authorize {
+        host_id: i32,
+        user_id: i32,
+        login: String,
+        options: Option<String>,
+    },
+    /// Removes an authorization by its id
+    RemoveAuthorization(i32),
+    DisableUser(i32),
+}
+
+/// A key that would be added to or removed from an authorized_keys file
+#[derive(Debug, Serialize, PartialEq, Eq, Hash, Clone)]
+pub struct SimulatedKey {
+    pub username: String,
+    pub key_id: i32,
+    pub key_type: String,
+    pub comment: Option<String>,
+    pub options: Option<String>,
+}
+
+/// How the authorized_keys file of one login would change
+#[derive(Debug, Serialize)]
+pub struct SimulatedFileChange {
+    pub host: String,
+    pub login: String,
+    pub added: Vec<SimulatedKey>,
+    pub removed: Vec<SimulatedKey>,
+}
+
+/// Generated entries of every login on every host
+type Fleet = BTreeMap<(String, String), HashSet<SimulatedKey>>;
+
+impl SimulatedChange {
+    fn apply(&self, conn: &mut DbConnection) -> QueryResult<usize> {
+        match self {
+            Self::Authorize {
+                host_id,
+                user_id,
+                login,
+                options,
+            } => diesel::insert_into(authorization::table)
+                .values((
+                    authorization::host_id.eq(host_id),
+                    authorization::user_id.eq(user_id),
+                    authorization::login.eq(login),
+                    authorization::options.eq(options),
+                ))
+                .execute(conn),
+            Self::RemoveAuthorization(id) => {
+                diesel::delete(authorization::table.filter(authorization::id.eq(id))).execute(conn)
+            }
+            Self::DisableUser(id) => diesel::update(user::table.filter(user::id.eq(id)))
+                .set(user::enabled.eq(false))
+                .execute(conn),
+        }
+    }
+
+    /// Which authorized_keys files would change, without persisting anything
+    pub fn simulate(
+        conn: &mut DbConnection,
+        changes: &[Self],
+    ) -> Result<Vec<SimulatedFileChange>, String> {
+        let before = fleet(conn)?;
+        let mut after = Err(String::new());
+        let res = conn.transaction::<(), Error, _>(|conn| {
+            for change in changes {
+                change.apply(conn)?;
+            }
+            after = fleet(conn);
+            // Nothing of the simulation is kept
+            Err(Error::RollbackTransaction)
+        });
+        if !matches!(res, Err(Error::RollbackTransaction)) {
+            query(res)?;
+        }
+        let after = after?;
+
+        let logins: HashSet<&(String, String)> = before.keys().chain(after.keys()).collect();
+        let empty = HashSet::new();
+        let mut file_changes: Vec<SimulatedFileChange> = logins
+            .into_iter()
+            .filter_map(|login| {
+                let old = before.get(login).unwrap_or(&empty);
+                let new = after.get(login).unwrap_or(&empty);
+                let mut added: Vec<SimulatedKey> = new.difference(old).cloned().collect();
+                let mut removed: Vec<SimulatedKey> = old.difference(new).cloned().collect();
+                if added.is_empty() && removed.is_empty() {
+                    return None;
+                }
+                added.sort_by_key(|key| key.key_id);
+                removed.sort_by_key(|key| key.key_id);
+                Some(SimulatedFileChange {
+                    host: login.0.clone(),
+                    login: login.1.clone(),
+                    added,
+                    removed,
+                })
+            })
+            .collect();
+        file_changes.sort_by(|a, b| (&a.host, &a.login).cmp(&(&b.host, &b.login)));
+        Ok(file_changes)
+    }
+}
+
+fn fleet(conn: &mut DbConnection) -> Result<Fleet, String> {
+    let mut fleet = Fleet::new();
+    for host in Host::get_all_hosts(conn)? {
+        for allowed in host.get_authorized_keys(conn)? {
+            let AllowedUserOnHost {
+                key,
+                login,
+                username,
+                options,
+            } = allowed;
+            fleet
+                .entry((host.name.clone(), login))
+                .or_default()
+                .insert(SimulatedKey {
+                    username,
+                    key_id: key.id,
+                    key_type: key.key_type,
+                    comment: key.comment,
+                    options,
+                });
+        }
+    }
+    Ok(fleet)
+}
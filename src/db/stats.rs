@@ -0,0 +1,29 @@
+use diesel::prelude::*;
+use serde::Serialize;
+
+use crate::{
+    schema::{authorization, host, user, user_key},
+    DbConnection,
+};
+
+use super::query;
+
+/// How many of each entity exist
+#[derive(Debug, Serialize)]
+pub struct Counts {
+    pub hosts: i64,
+    pub users: i64,
+    pub keys: i64,
+    pub authorizations: i64,
+}
+
+impl Counts {
+    pub fn get(conn: &mut DbConnection) -> Result<Self, String> {
+        Ok(Self {
+            hosts: query(host::table.count().get_result(conn))?,
+            users: query(user::table.count().get_result(conn))?,
+            keys: query(user_key::table.count().get_result(conn))?,
+            authorizations: query(authorization::table.count().get_result(conn))?,
+        })
+    }
+}
@@ -0,0 +1,64 @@
+//! Authorizations with when they were granted and the keys they grant, to tell from the logs of the
+//! hosts which of them nobody uses anymore
+use diesel::prelude::*;
+use time::PrimitiveDateTime;
+
+use crate::{
+    models::{AuthorizationHistory, Host, PublicUserKey},
+    schema::{authorization, host, user},
+    DbConnection,
+};
+
+use super::query;
+
+/// An authorization of an enabled user
+#[derive(Debug)]
+pub struct GrantedAuthorization {
+    pub id: i32,
+    pub host: Host,
+    pub username: String,
+    pub login: String,
+    /// Unknown for authorizations created before the history was recorded
+    pub granted_at: Option<PrimitiveDateTime>,
+    /// The keys of the user
+    pub keys: Vec<PublicUserKey>,
+}
+
+impl GrantedAuthorization {
+    /// Authorizations of disabled users grant nothing and are left out, so are authorization rules
+    pub fn all(conn: &mut DbConnection) -> Result<Vec<Self>, String> {
+        let granted = AuthorizationHistory::created_dates(conn)?;
+        let keys = PublicUserKey::get_all_keys(conn)?;
+        let authorizations = query(
+            authorization::table
+                .inner_join(host::table)
+                .inner_join(user::table)
+                .filter(user::enabled.eq(true))
+                .select((
+                    authorization::id,
+                    Host::as_select(),
+                    user::id,
+                    user::username,
+                    authorization::login,
+                ))
+                .order((host::name, user::username, authorization::login))
+                .load::<(i32, Host, i32, String, String)>(conn),
+        )?;
+
+        Ok(authorizations
+            .into_iter()
+            .map(|(id, host, user_id, username, login)| Self {
+                id,
+                host,
+                username,
+                login,
+                granted_at: granted.get(&id).copied(),
+                keys: keys
+                    .iter()
+                    .filter(|key| key.user_id == user_id)
+                    .cloned()
+                    .collect(),
+            })
+            .collect())
+    }
+}
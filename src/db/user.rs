@@ -4,17 +4,64 @@ use diesel::{delete, prelude::*};
 use crate::schema::user_key;
 use crate::schema::{authorization, host, user};
 use crate::{
-    models::{NewUser, PublicUserKey, User},
+    models::{AuthorizationHistory, KeyHistory, NewUser, PublicUserKey, User, UserDetails},
     DbConnection,
 };
 
-use super::{query, query_drop, UserAndOptions};
+use super::{found, history, query, query_drop, UserAndOptions};
+
+/// Filters for [`User::search`], all optional
+#[derive(Debug, Default)]
+pub struct UserFilter {
+    pub department: Option<String>,
+    pub email: Option<String>,
+    pub external_id: Option<String>,
+    /// Part of the username or full name
+    pub name: Option<String>,
+}
+
+impl UserDetails {
+    /// Trims the fields and clears empty ones
+    pub fn normalized(self) -> Result<Self, String> {
+        let clean = |field: Option<String>| {
+            field
+                .map(|value| value.trim().to_owned())
+                .filter(|value| !value.is_empty())
+        };
+        let details = Self {
+            email: clean(self.email),
+            full_name: clean(self.full_name),
+            department: clean(self.department),
+            external_id: clean(self.external_id),
+        };
+        if let Some(email) = details.email.as_deref() {
+            if !email.contains('@') {
+                return Err(format!("'{email}' is not an email address"));
+            }
+        }
+        Ok(details)
+    }
+}
 
 impl User {
     pub fn get_all_users(conn: &mut DbConnection) -> Result<Vec<Self>, String> {
         query(user::table.load::<Self>(conn))
     }
 
+    /// Usernames of the enabled users with the logins they are authorized for on any host,
+    /// each pair once
+    pub fn logins(conn: &mut DbConnection) -> Result<Vec<(String, String)>, String> {
+        query(
+            user::table
+                .inner_join(authorization::table)
+                .filter(user::enabled.eq(true))
+                .select((user::username, authorization::login))
+                .distinct()
+                .order((user::username, authorization::login))
+                .load::<(String, String)>(conn),
+        )
+    }
+
     pub fn get_user(conn: &mut DbConnection, username: String) -> Result<Self, String> {
         query(
             user::table
@@ -23,6 +70,47 @@ impl User {
         )
     }
 
+    pub fn find_user(conn: &mut DbConnection, username: &str) -> Result<Option<Self>, String> {
+        query(
+            user::table
+                .filter(user::username.eq(username))
+                .first::<Self>(conn)
+                .optional(),
+        )
+    }
+
+    /// Users matching the filter, sorted by username
+    pub fn search(conn: &mut DbConnection, filter: &UserFilter) -> Result<Vec<Self>, String> {
+        let mut statement = user::table.into_boxed();
+        if let Some(department) = &filter.department {
+            statement = statement.filter(user::department.eq(department));
+        }
+        if let Some(email) = &filter.email {
+            statement = statement.filter(user::email.eq(email));
+        }
+        if let Some(external_id) = &filter.external_id {
+            statement = statement.filter(user::external_id.eq(external_id));
+        }
+        if let Some(name) = &filter.name {
+            let pattern = format!("%{name}%");
+            statement = statement.filter(
+                user::username
+                    .like(pattern.clone())
+                    .or(user::full_name.like(pattern)),
+            );
+        }
+        query(statement.order(user::username).load::<Self>(conn))
+    }
+
+    pub fn get_from_id(conn: &mut DbConnection, id: i32) -> Result<Option<Self>, String> {
+        query(
+            user::table
+                .filter(user::id.eq(id))
+                .first::<Self>(conn)
+                .optional(),
+        )
+    }
+
     pub fn get_keys(&self, conn: &mut DbConnection) -> Result<Vec<PublicUserKey>, String> {
         query(
             user_key::table
@@ -41,9 +129,34 @@ impl User {
         .map(|_| new_user.username)
     }
 
-    /// Delete a user from the Database
-    pub fn delete_user(conn: &mut DbConnection, username: &str) -> Result<(), String> {
-        query_drop(delete(user::table.filter(user::username.eq(username))).execute(conn))
+    /// Delete a user from the Database, recording the authorizations that go with it.
+    /// Returns the user's keys, which are deleted with it.
+    pub fn delete_user(
+        conn: &mut DbConnection,
+        username: &str,
+        actor: &str,
+    ) -> Result<Vec<PublicUserKey>, String> {
+        found(conn.transaction(|conn| {
+            let authorization_ids = authorization::table
+                .inner_join(user::table)
+                .filter(user::username.eq(username))
+                .select(authorization::id)
+                .load::<i32>(conn)?;
+            AuthorizationHistory::record(conn, history::DELETED, &authorization_ids, actor)?;
+
+            let keys = user_key::table
+                .inner_join(user::table)
+                .filter(user::username.eq(username))
+                .select(PublicUserKey::as_select())
+                .load::<PublicUserKey>(conn)?;
+            let key_ids: Vec<i32> = keys.iter().map(|key| key.id).collect();
+            KeyHistory::record(conn, history::DELETED, &key_ids, actor)?;
+
+            match delete(user::table.filter(user::username.eq(username))).execute(conn)? {
+                0 => Err(diesel::result::Error::NotFound),
+                _ => Ok(keys),
+            }
+        }))
     }
 
     /// Update a user's enabled status and username in the Database
@@ -51,7 +164,7 @@ impl User {
         conn: &mut DbConnection,
         old_username: &str,
         new_username: &str,
-        _enabled: bool,
+        new_enabled: bool,
     ) -> Result<(), String> {
         use crate::schema::user::dsl::*;
         use diesel::prelude::*;
@@ -59,13 +172,41 @@ impl User {
         // Update username and enabled status
         diesel::update(user)
             .filter(username.eq(old_username))
-            .set((username.eq(new_username), enabled.eq(enabled)))
+            .set((username.eq(new_username), enabled.eq(new_enabled)))
             .execute(conn)
             .map_err(|e| e.to_string())?;
 
         Ok(())
     }
 
+    /// Sets who the user is, the details have to be [normalized](UserDetails::normalized)
+    pub fn set_details(
+        conn: &mut DbConnection,
+        username: &str,
+        details: &UserDetails,
+    ) -> Result<(), String> {
+        if let Some(external_id) = &details.external_id {
+            let other: Option<String> = query(
+                user::table
+                    .filter(user::external_id.eq(external_id))
+                    .filter(user::username.ne(username))
+                    .select(user::username)
+                    .first(conn)
+                    .optional(),
+            )?;
+            if let Some(other) = other {
+                return Err(format!(
+                    "'{other}' already has the external id '{external_id}'"
+                ));
+            }
+        }
+        query_drop(
+            diesel::update(user::table.filter(user::username.eq(username)))
+                .set(details)
+                .execute(conn),
+        )
+    }
+
     /// Find all hosts this user is authorized on
     pub fn get_authorizations(
         &self,
@@ -0,0 +1,133 @@
+use diesel::prelude::*;
+use serde::Serialize;
+
+use crate::{
+    models::{AuthorizationHistory, KeyHistory, User},
+    schema::{authorization, host, user, user_key},
+    DbConnection,
+};
+
+use super::{history, query};
+
+/// The same login on a host is authorized for both users
+#[derive(Debug, Serialize)]
+pub struct MergeConflict {
+    pub host: String,
+    pub login: String,
+    /// Authorization of the user merged into, which stays
+    pub kept: i32,
+    pub kept_options: Option<String>,
+    /// Authorization of the merged user, which is deleted
+    pub dropped: i32,
+    pub dropped_options: Option<String>,
+}
+
+/// What merging one user into another changes
+#[derive(Debug, Serialize)]
+pub struct UserMerge {
+    /// Deleted after the merge
+    pub from: String,
+    pub into: String,
+    /// Keys moved to `into`
+    pub keys: Vec<i32>,
+    /// Authorizations moved to `into`
+    pub authorizations: Vec<i32>,
+    pub conflicts: Vec<MergeConflict>,
+    /// Whether the merge was carried out or only planned
+    pub merged: bool,
+}
+
+impl UserMerge {
+    /// Moves the keys and authorizations of `from` to `into` and deletes `from`.
+    /// Conflicting authorizations of `from` are deleted, the ones of `into` win.
+    /// With `dry_run` only reports what would change. The history of keys and authorizations
+    /// is kept, their ids don't change.
+    pub fn merge(
+        conn: &mut DbConnection,
+        from: &User,
+        into: &User,
+        actor: &str,
+        dry_run: bool,
+    ) -> Result<Self, String> {
+        query(conn.transaction(|conn| {
+            let keys = user_key::table
+                .filter(user_key::user_id.eq(from.id))
+                .select(user_key::id)
+                .load::<i32>(conn)?;
+
+            let existing = authorization::table
+                .filter(authorization::user_id.eq(into.id))
+                .select((
+                    authorization::id,
+                    authorization::host_id,
+                    authorization::login,
+                    authorization::options,
+                ))
+                .load::<(i32, i32, String, Option<String>)>(conn)?;
+
+            let mut authorizations = Vec::new();
+            let mut conflicts = Vec::new();
+            for (id, host_id, host_name, login, options) in authorization::table
+                .inner_join(host::table)
+                .filter(authorization::user_id.eq(from.id))
+                .select((
+                    authorization::id,
+                    host::id,
+                    host::name,
+                    authorization::login,
+                    authorization::options,
+                ))
+                .load::<(i32, i32, String, String, Option<String>)>(conn)?
+            {
+                match existing.iter().find(|(_, other_host, other_login, _)| {
+                    *other_host == host_id && other_login.eq(&login)
+                }) {
+                    Some((kept, _, _, kept_options)) => conflicts.push(MergeConflict {
+                        host: host_name,
+                        login,
+                        kept: *kept,
+                        kept_options: kept_options.clone(),
+                        dropped: id,
+                        dropped_options: options,
+                    }),
+                    None => authorizations.push(id),
+                }
+            }
+
+            let mut merge = Self {
+                from: from.username.clone(),
+                into: into.username.clone(),
+                keys,
+                authorizations,
+                conflicts,
+                merged: false,
+            };
+            if dry_run {
+                return Ok(merge);
+            }
+
+            let dropped: Vec<i32> = merge.conflicts.iter().map(|c| c.dropped).collect();
+            AuthorizationHistory::record(conn, history::DELETED, &dropped, actor)?;
+            diesel::delete(authorization::table.filter(authorization::id.eq_any(&dropped)))
+                .execute(conn)?;
+
+            diesel::update(
+                authorization::table.filter(authorization::id.eq_any(&merge.authorizations)),
+            )
+            .set(authorization::user_id.eq(into.id))
+            .execute(conn)?;
+            AuthorizationHistory::record(conn, history::UPDATED, &merge.authorizations, actor)?;
+
+            diesel::update(user_key::table.filter(user_key::id.eq_any(&merge.keys)))
+                .set(user_key::user_id.eq(into.id))
+                .execute(conn)?;
+            for key in &merge.keys {
+                KeyHistory::record_transfer(conn, *key, actor, from.username.clone())?;
+            }
+
+            diesel::delete(user::table.filter(user::id.eq(from.id))).execute(conn)?;
+            merge.merged = true;
+            Ok(merge)
+        }))
+    }
+}
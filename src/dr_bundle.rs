@@ -0,0 +1,179 @@
+//! Bundle for rebuilding SSM after its server is lost: a copy of the database, the public key SSM
+//! logs in with and the host key fingerprints of the hosts, as a tar archive encrypted with age.
+//! The private key is left out unless it is asked for, so a leaked bundle doesn't open the hosts.
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use age::{secrecy::SecretString, x25519, Encryptor};
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{db::backup::DatabaseFile, models::Host};
+
+/// Encryption and content of the bundle
+#[derive(Debug, Default, Deserialize, Clone)]
+pub struct DrBundleConfig {
+    /// age public keys (`age1...`) the bundle is encrypted to
+    #[serde(default)]
+    pub recipients: Vec<String>,
+    /// Passphrase the bundle is encrypted with instead of recipients
+    pub passphrase: Option<String>,
+    /// Add the private key SSM logs in with (default false)
+    #[serde(default)]
+    pub include_private_key: bool,
+}
+
+enum Encryption {
+    Recipients(Vec<x25519::Recipient>),
+    Passphrase(String),
+}
+
+pub struct DrBundle {
+    encryption: Option<Encryption>,
+    /// Only set if the private key goes into the bundle
+    private_key_file: Option<PathBuf>,
+}
+
+/// The accepted host key fingerprints of a host, the primary one first
+#[derive(Serialize)]
+struct HostFingerprints<'a> {
+    name: &'a str,
+    address: &'a str,
+    port: u16,
+    fingerprints: Vec<&'a str>,
+}
+
+#[derive(Serialize)]
+struct Manifest<'a> {
+    version: &'static str,
+    created: String,
+    files: Vec<&'a str>,
+}
+
+impl DrBundle {
+    /// Checks the recipients, exiting if one is invalid or a passphrase is set as well
+    pub fn new(config: &DrBundleConfig, private_key_file: &Path) -> Self {
+        let passphrase = config
+            .passphrase
+            .clone()
+            .filter(|passphrase| !passphrase.is_empty());
+        let encryption = match (config.recipients.is_empty(), passphrase) {
+            (true, None) => None,
+            (true, Some(passphrase)) => Some(Encryption::Passphrase(passphrase)),
+            (false, None) => Some(Encryption::Recipients(
+                config
+                    .recipients
+                    .iter()
+                    .map(|recipient| {
+                        x25519::Recipient::from_str(recipient.trim()).unwrap_or_else(|e| {
+                            eprintln!("Invalid dr_bundle recipient '{recipient}': {e}");
+                            std::process::exit(3);
+                        })
+                    })
+                    .collect(),
+            )),
+            (false, Some(_)) => {
+                eprintln!(
+                    "The dr_bundle is encrypted to recipients or with a passphrase, not both"
+                );
+                std::process::exit(3);
+            }
+        };
+        Self {
+            encryption,
+            private_key_file: config
+                .include_private_key
+                .then(|| private_key_file.to_owned()),
+        }
+    }
+
+    /// Whether there is a way to encrypt the bundle
+    pub const fn enabled(&self) -> bool {
+        self.encryption.is_some()
+    }
+
+    /// The files that don't come from the database: the public key as `ssm.pub`, the fingerprints
+    /// of the hosts as `host_fingerprints.json` and the private key as `id_ssm` if it is included
+    pub fn files(&self, public_key: &str, hosts: &[Host]) -> Result<Vec<DatabaseFile>, String> {
+        let fingerprints: Vec<HostFingerprints> = hosts
+            .iter()
+            .map(|host| HostFingerprints {
+                name: &host.name,
+                address: &host.address,
+                port: host.port,
+                fingerprints: host.key_fingerprint_list().collect(),
+            })
+            .collect();
+        let mut files = vec![
+            (
+                String::from("ssm.pub"),
+                format!("{public_key}\n").into_bytes(),
+            ),
+            (
+                String::from("host_fingerprints.json"),
+                serde_json::to_vec_pretty(&fingerprints).map_err(|e| e.to_string())?,
+            ),
+        ];
+        if let Some(path) = &self.private_key_file {
+            let private_key = std::fs::read(path)
+                .map_err(|e| format!("Failed to read the private key {}: {e}", path.display()))?;
+            files.push((String::from("id_ssm"), private_key));
+        }
+        Ok(files)
+    }
+
+    /// The files with a `manifest.json` listing them as tar archive, encrypted with age
+    pub fn seal(&self, files: &[DatabaseFile]) -> Result<Vec<u8>, String> {
+        let encryptor = match &self.encryption {
+            None => {
+                return Err(String::from(
+                    "The dr_bundle has no recipients or passphrase",
+                ))
+            }
+            Some(Encryption::Passphrase(passphrase)) => {
+                Encryptor::with_user_passphrase(SecretString::from(passphrase.clone()))
+            }
+            Some(Encryption::Recipients(recipients)) => Encryptor::with_recipients(
+                recipients
+                    .iter()
+                    .map(|recipient| recipient as &dyn age::Recipient),
+            )
+            .map_err(|e| e.to_string())?,
+        };
+
+        let now = OffsetDateTime::now_utc();
+        let manifest = Manifest {
+            version: env!("CARGO_PKG_VERSION"),
+            created: now.format(&Rfc3339).unwrap_or_default(),
+            files: files.iter().map(|(name, _)| name.as_str()).collect(),
+        };
+        let manifest = serde_json::to_vec_pretty(&manifest).map_err(|e| e.to_string())?;
+
+        let mut archive = tar::Builder::new(Vec::new());
+        for (name, content) in std::iter::once(("manifest.json", &manifest))
+            .chain(files.iter().map(|(name, content)| (name.as_str(), content)))
+        {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o600);
+            header.set_mtime(now.unix_timestamp().unsigned_abs());
+            archive
+                .append_data(&mut header, name, content.as_slice())
+                .map_err(|e| format!("Failed to add {name} to the bundle: {e}"))?;
+        }
+        let archive = archive.into_inner().map_err(|e| e.to_string())?;
+
+        let mut sealed = Vec::new();
+        let mut writer = encryptor
+            .wrap_output(&mut sealed)
+            .map_err(|e| e.to_string())?;
+        writer
+            .write_all(&archive)
+            .and_then(|()| writer.finish().map(drop))
+            .map_err(|e| format!("Failed to encrypt the bundle: {e}"))?;
+        Ok(sealed)
+    }
+}
@@ -0,0 +1,35 @@
+//! CSV encoding of exported rows
+use time::{format_description::well_known::Rfc3339, PrimitiveDateTime};
+
+/// A row that can be written as a CSV record
+pub trait CsvRecord {
+    /// Names of the columns
+    const HEADER: &'static [&'static str];
+
+    fn fields(&self) -> Vec<String>;
+}
+
+/// Quotes a field if it contains a separator, a quote or a line break
+pub fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// One CSV record, terminated by CRLF
+pub fn csv_line<S: AsRef<str>>(fields: &[S]) -> String {
+    let mut line = fields
+        .iter()
+        .map(|field| csv_field(field.as_ref()))
+        .collect::<Vec<_>>()
+        .join(",");
+    line.push_str("\r\n");
+    line
+}
+
+/// A timestamp stored in UTC, as RFC 3339 like in the JSON responses
+pub fn csv_timestamp(at: PrimitiveDateTime) -> String {
+    at.assume_utc().format(&Rfc3339).unwrap_or_default()
+}
@@ -0,0 +1,196 @@
+//! Failed logins read from the lines sshd logs for refused passwords and keys and for unknown
+//! logins. Counted per host, login and source address they give a view of brute-force attempts
+//! across the fleet without an agent on the hosts.
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+};
+
+use serde::{Deserialize, Serialize};
+use time::{Duration, OffsetDateTime, UtcOffset};
+
+use crate::{
+    key_usage::read_timestamp,
+    models::Host,
+    ssh::{SshClient, SshClientError},
+};
+
+const fn default_hours() -> u32 {
+    24
+}
+
+/// Reading failed logins from the logs of the hosts
+#[derive(Debug, Deserialize, Clone)]
+pub struct FailedLoginsConfig {
+    /// Read the sshd logs of hosts, this needs root or the adm or systemd-journal group (default false)
+    #[serde(default)]
+    pub enabled: bool,
+    /// Hours to look back when a request doesn't ask for others (default 24)
+    #[serde(default = "default_hours")]
+    pub hours: u32,
+}
+
+impl Default for FailedLoginsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            hours: default_hours(),
+        }
+    }
+}
+
+/// Failed logins from one address
+#[derive(Debug, Serialize)]
+pub struct SourceAttempts {
+    pub address: String,
+    pub attempts: usize,
+}
+
+/// Failed logins as one login
+#[derive(Debug, Serialize)]
+pub struct LoginAttempts {
+    pub login: String,
+    /// The login doesn't exist on the host, scanners guessing names
+    pub unknown: bool,
+    pub attempts: usize,
+    #[serde(with = "time::serde::rfc3339")]
+    pub last_attempt: OffsetDateTime,
+    /// Most attempts first
+    pub sources: Vec<SourceAttempts>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct FailedLogins {
+    pub attempts: usize,
+    /// Most attempts first
+    pub logins: Vec<LoginAttempts>,
+}
+
+/// A failed login as sshd logs it
+struct Attempt<'a> {
+    login: &'a str,
+    unknown: bool,
+    source: &'a str,
+    at: OffsetDateTime,
+}
+
+/// The attempts as one login so far
+struct Tally<'a> {
+    attempts: usize,
+    last: OffsetDateTime,
+    sources: HashMap<&'a str, usize>,
+}
+
+/// Most attempts first, ties by name
+fn by_attempts<'a>(counts: impl IntoIterator<Item = (&'a str, usize)>) -> Vec<SourceAttempts> {
+    let mut sources: Vec<SourceAttempts> = counts
+        .into_iter()
+        .map(|(address, attempts)| SourceAttempts {
+            address: address.to_owned(),
+            attempts,
+        })
+        .collect();
+    sources.sort_by(|a, b| b.attempts.cmp(&a.attempts).then(a.address.cmp(&b.address)));
+    sources
+}
+
+impl FailedLogins {
+    /// Reads the output of `get_failed_logins`, lines without a count are a single attempt. Lines
+    /// before `since` are left out; the auth logs are counted per day, a day whose last attempt
+    /// is after `since` counts whole.
+    pub fn read(content: &str, now: OffsetDateTime, since: OffsetDateTime) -> Self {
+        let mut logins: BTreeMap<(&str, bool), Tally> = BTreeMap::new();
+        for line in content.lines() {
+            let (count, line) = match line.split_once(' ') {
+                Some((count, rest)) => match count.parse::<usize>() {
+                    Ok(count) => (count, rest),
+                    Err(_) => (1, line),
+                },
+                None => continue,
+            };
+            let Some(attempt) = read_attempt(line, now) else {
+                continue;
+            };
+            if attempt.at < since {
+                continue;
+            }
+            let at = attempt.at.to_offset(UtcOffset::UTC);
+            let tally = logins
+                .entry((attempt.login, attempt.unknown))
+                .or_insert(Tally {
+                    attempts: 0,
+                    last: at,
+                    sources: HashMap::new(),
+                });
+            tally.attempts += count;
+            tally.last = tally.last.max(at);
+            *tally.sources.entry(attempt.source).or_default() += count;
+        }
+
+        let mut logins: Vec<LoginAttempts> = logins
+            .into_iter()
+            .map(|((login, unknown), tally)| LoginAttempts {
+                login: login.to_owned(),
+                unknown,
+                attempts: tally.attempts,
+                last_attempt: tally.last,
+                sources: by_attempts(tally.sources),
+            })
+            .collect();
+        logins.sort_by_key(|login| Reverse(login.attempts));
+        Self {
+            attempts: logins.iter().map(|login| login.attempts).sum(),
+            logins,
+        }
+    }
+
+    /// Attempts per source address over all logins
+    pub fn sources(&self) -> HashMap<&str, usize> {
+        let mut sources = HashMap::new();
+        for login in &self.logins {
+            for source in &login.sources {
+                *sources.entry(source.address.as_str()).or_default() += source.attempts;
+            }
+        }
+        sources
+    }
+}
+
+/// Reads `<date> <host> sshd[<pid>]: Failed <method> for <login> from <address> ...` and
+/// `... Invalid user <login> from <address> ...`. sshd logs the failed passwords of unknown logins
+/// as well, they are skipped so a connection counts once.
+fn read_attempt(line: &str, now: OffsetDateTime) -> Option<Attempt<'_>> {
+    let (head, login, unknown, rest) = if let Some((head, failed)) = line.split_once(": Failed ") {
+        let (_method, rest) = failed.split_once(" for ")?;
+        if rest.starts_with("invalid user ") {
+            return None;
+        }
+        let (login, rest) = rest.split_once(" from ")?;
+        (head, login, false, rest)
+    } else {
+        let (head, invalid) = line.split_once(": Invalid user ")?;
+        let (login, rest) = invalid.split_once(" from ")?;
+        (head, login, true, rest)
+    };
+    Some(Attempt {
+        login,
+        unknown,
+        source: rest.split_whitespace().next()?,
+        at: read_timestamp(head, now)?,
+    })
+}
+
+/// The failed logins of a host in the last `hours` hours
+pub async fn host_failed_logins(
+    ssh_client: &dyn SshClient,
+    host: Host,
+    hours: u32,
+) -> Result<FailedLogins, SshClientError> {
+    let content = ssh_client.get_failed_logins(host, hours).await?;
+    let now = OffsetDateTime::now_utc();
+    Ok(FailedLogins::read(
+        &content,
+        now,
+        now - Duration::hours(i64::from(hours)),
+    ))
+}
@@ -0,0 +1,322 @@
+//! Findings of the compliance report and the diff, exported for vulnerability management tools
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::compliance::{key_name, Policy, Violation};
+use crate::export::csv_line;
+use crate::ssh::DiffItem;
+
+/// Ordered from the most severe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    High,
+    Medium,
+    Low,
+}
+
+impl Severity {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::High => "high",
+            Self::Medium => "medium",
+            Self::Low => "low",
+        }
+    }
+
+    /// The SARIF level of this severity
+    const fn level(self) -> &'static str {
+        match self {
+            Self::High => "error",
+            Self::Medium => "warning",
+            Self::Low => "note",
+        }
+    }
+}
+
+/// A kind of finding
+pub struct Rule {
+    pub id: &'static str,
+    pub severity: Severity,
+    pub description: &'static str,
+    pub remediation: &'static str,
+}
+
+const fn policy_rule(policy: Policy) -> Rule {
+    match policy {
+        Policy::NoRootKeys => Rule {
+            id: "no_root_keys",
+            severity: Severity::High,
+            description: policy.description(),
+            remediation: "Authorize the user for a personal login instead of root",
+        },
+        Policy::NoForwarding => Rule {
+            id: "no_forwarding",
+            severity: Severity::Medium,
+            description: policy.description(),
+            remediation: "Set the restrict option on the authorization",
+        },
+        Policy::MaxKeyAge => Rule {
+            id: "max_key_age",
+            severity: Severity::Medium,
+            description: policy.description(),
+            remediation: "Have the user add a new key and delete the old one",
+        },
+        Policy::ManagedPragma => Rule {
+            id: "managed_pragma",
+            severity: Severity::Low,
+            description: policy.description(),
+            remediation: "Review the file and deploy it from ssm",
+        },
+        Policy::SecurityKeys => Rule {
+            id: "security_keys",
+            severity: Severity::Medium,
+            description: policy.description(),
+            remediation:
+                "Have the user add an sk-ssh-ed25519 or sk-ecdsa key and delete the other one",
+        },
+    }
+}
+
+const KEY_MISSING: Rule = Rule {
+    id: "key_missing",
+    severity: Severity::Low,
+    description: "An authorized key is missing",
+    remediation: "Deploy the authorized_keys file of the login",
+};
+const UNKNOWN_KEY: Rule = Rule {
+    id: "unknown_key",
+    severity: Severity::High,
+    description: "A key ssm doesn't know can log in",
+    remediation: "Assign the key to its user or deploy the file to remove it",
+};
+const UNAUTHORIZED_KEY: Rule = Rule {
+    id: "unauthorized_key",
+    severity: Severity::High,
+    description: "A key of a user without an authorization can log in",
+    remediation: "Authorize the user or deploy the file to remove the key",
+};
+const DUPLICATE_KEY: Rule = Rule {
+    id: "duplicate_key",
+    severity: Severity::Low,
+    description: "A key is in the authorized_keys file more than once",
+    remediation: "Deploy the authorized_keys file of the login",
+};
+const FAULTY_KEY: Rule = Rule {
+    id: "faulty_key",
+    severity: Severity::Medium,
+    description: "A line of the authorized_keys file can't be parsed",
+    remediation: "Deploy the authorized_keys file of the login",
+};
+const PRAGMA_MISSING: Rule = Rule {
+    id: "pragma_missing",
+    severity: Severity::Low,
+    description: "The authorized_keys file isn't managed by ssm",
+    remediation: "Review the file and deploy it from ssm",
+};
+const PRINCIPAL_MISSING: Rule = Rule {
+    id: "principal_missing",
+    severity: Severity::Low,
+    description: "An authorized certificate principal is missing",
+    remediation: "Deploy the authorized_principals file of the login",
+};
+const UNKNOWN_PRINCIPAL: Rule = Rule {
+    id: "unknown_principal",
+    severity: Severity::High,
+    description: "A certificate principal that isn't authorized can log in",
+    remediation: "Deploy the authorized_principals file of the login",
+};
+
+const INCOMPATIBLE_KEY: Rule = Rule {
+    id: "incompatible_key",
+    severity: Severity::Low,
+    description: "An authorized key has an algorithm the host doesn't accept",
+    remediation:
+        "Have the user add a key of an accepted algorithm or change the key algorithms of the host",
+};
+const KEY_LIMIT: Rule = Rule {
+    id: "key_limit",
+    severity: Severity::Low,
+    description: "An authorized_keys file is close to or over a configured limit",
+    remediation: "Revoke authorizations nobody needs anymore or raise the limit",
+};
+
+/// Every rule a finding can have, in the order they are listed in SARIF
+pub fn rules() -> Vec<Rule> {
+    Policy::ALL
+        .into_iter()
+        .map(policy_rule)
+        .chain([
+            KEY_MISSING,
+            UNKNOWN_KEY,
+            UNAUTHORIZED_KEY,
+            DUPLICATE_KEY,
+            FAULTY_KEY,
+            PRAGMA_MISSING,
+            PRINCIPAL_MISSING,
+            UNKNOWN_PRINCIPAL,
+            INCOMPATIBLE_KEY,
+            KEY_LIMIT,
+        ])
+        .collect()
+}
+
+#[derive(Debug)]
+pub struct Finding {
+    pub rule: &'static str,
+    pub severity: Severity,
+    pub host: String,
+    pub login: String,
+    pub message: String,
+    pub remediation: &'static str,
+}
+
+impl Finding {
+    fn new(rule: &Rule, host: &str, login: &str, message: String) -> Self {
+        Self {
+            rule: rule.id,
+            severity: rule.severity,
+            host: host.to_owned(),
+            login: login.to_owned(),
+            message,
+            remediation: rule.remediation,
+        }
+    }
+
+    pub fn from_violation(host: &str, violation: &Violation) -> Self {
+        Self::new(
+            &policy_rule(violation.policy),
+            host,
+            &violation.login,
+            violation.detail.clone(),
+        )
+    }
+
+    pub fn from_diff(host: &str, login: &str, item: &DiffItem) -> Self {
+        let (rule, message) = match item {
+            DiffItem::KeyMissing(key, username) => (
+                KEY_MISSING,
+                format!("{} of {username} is missing", key_name(key)),
+            ),
+            DiffItem::UnknownKey(key) => {
+                (UNKNOWN_KEY, format!("{} isn't known to ssm", key_name(key)))
+            }
+            DiffItem::UnauthorizedKey(key, username) => (
+                UNAUTHORIZED_KEY,
+                format!("{} of {username} isn't authorized", key_name(key)),
+            ),
+            DiffItem::DuplicateKey(key) => (
+                DUPLICATE_KEY,
+                format!("{} is in the file more than once", key_name(key)),
+            ),
+            DiffItem::FaultyKey(error, _) => {
+                (FAULTY_KEY, format!("A line can't be parsed: {error}"))
+            }
+            DiffItem::PragmaMissing => (PRAGMA_MISSING, PRAGMA_MISSING.description.to_owned()),
+            DiffItem::PrincipalMissing(principal, username) => (
+                PRINCIPAL_MISSING,
+                format!("Principal {principal} of {username} is missing"),
+            ),
+            DiffItem::UnknownPrincipal(principal) => (
+                UNKNOWN_PRINCIPAL,
+                format!("Principal {principal} isn't authorized"),
+            ),
+            DiffItem::IncompatibleKey(key, username) => (
+                INCOMPATIBLE_KEY,
+                format!(
+                    "{} of {username} isn't deployed, the host doesn't accept {} keys",
+                    key_name(key),
+                    key.algorithm
+                ),
+            ),
+            DiffItem::LimitReached(warning) if warning.exceeded() => (
+                KEY_LIMIT,
+                format!("The file has {warning} and can't be deployed"),
+            ),
+            DiffItem::LimitReached(warning) => (KEY_LIMIT, format!("The file has {warning}")),
+        };
+        Self::new(&rule, host, login, message)
+    }
+}
+
+/// One line per finding, with a header line
+pub fn to_csv(findings: &[Finding]) -> String {
+    let mut csv = csv_line(&[
+        "severity",
+        "rule",
+        "host",
+        "login",
+        "message",
+        "remediation",
+    ]);
+    for finding in findings {
+        csv.push_str(&csv_line(&[
+            finding.severity.as_str(),
+            finding.rule,
+            finding.host.as_str(),
+            finding.login.as_str(),
+            finding.message.as_str(),
+            finding.remediation,
+        ]));
+    }
+    csv
+}
+
+/// A SARIF 2.1.0 log with one run. Hosts and logins are logical locations, as there are no source files.
+pub fn to_sarif(findings: &[Finding]) -> Value {
+    let rules = rules();
+    let driver_rules: Vec<Value> = rules
+        .iter()
+        .map(|rule| {
+            json!({
+                "id": rule.id,
+                "shortDescription": { "text": rule.description },
+                "help": { "text": rule.remediation },
+                "defaultConfiguration": { "level": rule.severity.level() },
+                "properties": { "severity": rule.severity },
+            })
+        })
+        .collect();
+    let results: Vec<Value> = findings
+        .iter()
+        .map(|finding| {
+            json!({
+                "ruleId": finding.rule,
+                "ruleIndex": rules.iter().position(|rule| rule.id.eq(finding.rule)),
+                "level": finding.severity.level(),
+                "message": { "text": finding.message },
+                "locations": [{
+                    "logicalLocations": [{
+                        "name": finding.login,
+                        "fullyQualifiedName": format!("{}/{}", finding.host, finding.login),
+                        "kind": "resource",
+                    }],
+                }],
+                "partialFingerprints": {
+                    "ssm/v1": format!("{}/{}/{}/{}", finding.rule, finding.host, finding.login, finding.message),
+                },
+                "properties": {
+                    "severity": finding.severity,
+                    "host": finding.host,
+                    "login": finding.login,
+                    "remediation": finding.remediation,
+                },
+            })
+        })
+        .collect();
+    json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "ssm",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "informationUri": env!("CARGO_PKG_REPOSITORY"),
+                    "rules": driver_rules,
+                },
+            },
+            "results": results,
+        }],
+    })
+}
@@ -69,6 +69,32 @@ impl FormResponseBuilder {
         }
     }
 
+    pub fn forbidden(message: impl ToString) -> Self {
+        Self {
+            triggers: Vec::new(),
+            status: StatusCode::FORBIDDEN,
+            response: FormResponse::Error(message.to_string()),
+        }
+    }
+
+    /// Refused during a change freeze
+    pub const fn locked(message: String) -> Self {
+        Self {
+            triggers: Vec::new(),
+            status: StatusCode::LOCKED,
+            response: FormResponse::Error(message),
+        }
+    }
+
+    /// Refused in read-only mode
+    pub fn unavailable(message: impl ToString) -> Self {
+        Self {
+            triggers: Vec::new(),
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            response: FormResponse::Error(message.to_string()),
+        }
+    }
+
     pub const fn dialog(modal: Modal) -> Self {
         Self {
             triggers: Vec::new(),
@@ -0,0 +1,216 @@
+//! Change freezes, deployments are rejected while one is active unless the login may break them.
+//! Read-only mode goes further, nothing can be changed until it is switched off again.
+use std::sync::RwLock;
+
+use serde::{Deserialize, Serialize};
+use time::{macros::format_description, Duration, PrimitiveDateTime, Weekday};
+
+use crate::{models::FreezeWindow, DbConnection};
+
+const MINUTES_PER_WEEK: i64 = 7 * 24 * 60;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct FreezeConfig {
+    /// Weekday and time in UTC the freeze begins, e.g. `Fri 18:00`
+    from: String,
+    /// Weekday and time in UTC the freeze ends, e.g. `Mon 06:00`
+    until: String,
+    /// Shown when a deployment is rejected
+    reason: Option<String>,
+}
+
+/// A freeze repeating every week
+#[derive(Debug, Serialize, Clone)]
+pub struct RecurringFreeze {
+    from: String,
+    until: String,
+    reason: String,
+    /// Minutes since Monday 00:00
+    #[serde(skip)]
+    start: i64,
+    #[serde(skip)]
+    end: i64,
+}
+
+impl RecurringFreeze {
+    /// When the freeze active at this time ends
+    fn ends_after(&self, at: PrimitiveDateTime) -> Option<PrimitiveDateTime> {
+        let now = minute_of_week(at);
+        let active = if self.start <= self.end {
+            self.start <= now && now < self.end
+        } else {
+            // Spans the end of the week
+            now >= self.start || now < self.end
+        };
+        active.then(|| {
+            let remaining = (self.end - now).rem_euclid(MINUTES_PER_WEEK);
+            let at = at
+                .replace_second(0)
+                .unwrap_or(at)
+                .replace_nanosecond(0)
+                .unwrap_or(at);
+            at + Duration::minutes(remaining)
+        })
+    }
+}
+
+/// Why deployments are rejected right now
+#[derive(Debug, Serialize, Clone)]
+pub struct ActiveFreeze {
+    pub reason: String,
+    #[serde(with = "crate::db::utc_rfc3339")]
+    pub until: PrimitiveDateTime,
+    /// The ad-hoc freeze, recurring freezes have none
+    pub id: Option<i32>,
+}
+
+impl ActiveFreeze {
+    pub fn message(&self) -> String {
+        let until = self
+            .until
+            .format(format_description!("[year]-[month]-[day] [hour]:[minute]"))
+            .unwrap_or_default();
+        format!("Deployments are frozen until {until} UTC: {}", self.reason)
+    }
+}
+
+/// Why changes are rejected, e.g. during a database migration
+#[derive(Debug, Serialize, Clone)]
+pub struct ReadOnly {
+    pub reason: String,
+    /// Who switched it on
+    pub by: String,
+    #[serde(with = "crate::db::utc_rfc3339")]
+    pub since: PrimitiveDateTime,
+}
+
+impl ReadOnly {
+    pub fn since_str(&self) -> String {
+        self.since
+            .format(format_description!("[year]-[month]-[day] [hour]:[minute]"))
+            .unwrap_or_default()
+    }
+
+    pub fn message(&self) -> String {
+        format!(
+            "SSM is read-only since {} UTC: {}",
+            self.since_str(),
+            self.reason
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Freezes {
+    recurring: Vec<RecurringFreeze>,
+    /// Kept in memory only, a restart ends it
+    read_only: RwLock<Option<ReadOnly>>,
+}
+
+impl Freezes {
+    /// Checks the recurring freezes, exiting if one is invalid
+    pub fn new(config: &[FreezeConfig]) -> Self {
+        let recurring = config
+            .iter()
+            .map(|freeze| {
+                let parse = |input: &str| {
+                    parse_minute_of_week(input).unwrap_or_else(|| {
+                        eprintln!(
+                            "Invalid freeze time '{input}', use a weekday and time in UTC like \"Fri 18:00\""
+                        );
+                        std::process::exit(3);
+                    })
+                };
+                let (start, end) = (parse(&freeze.from), parse(&freeze.until));
+                if start == end {
+                    eprintln!(
+                        "The freeze from '{}' until '{}' is empty",
+                        freeze.from, freeze.until
+                    );
+                    std::process::exit(3);
+                }
+                RecurringFreeze {
+                    from: freeze.from.clone(),
+                    until: freeze.until.clone(),
+                    reason: freeze
+                        .reason
+                        .clone()
+                        .unwrap_or_else(|| "Weekly change freeze".to_owned()),
+                    start,
+                    end,
+                }
+            })
+            .collect();
+        Self {
+            recurring,
+            read_only: RwLock::default(),
+        }
+    }
+
+    /// Set while SSM is in read-only mode
+    pub fn read_only(&self) -> Option<ReadOnly> {
+        self.read_only
+            .read()
+            .expect("Read-only lock poisoned")
+            .clone()
+    }
+
+    /// Switches read-only mode on, or off with `None`
+    pub fn set_read_only(&self, read_only: Option<ReadOnly>) {
+        *self.read_only.write().expect("Read-only lock poisoned") = read_only;
+    }
+
+    pub fn recurring(&self) -> &[RecurringFreeze] {
+        &self.recurring
+    }
+
+    /// The active freeze lasting longest, recurring or ad-hoc
+    pub fn active(
+        &self,
+        conn: &mut DbConnection,
+        at: PrimitiveDateTime,
+    ) -> Result<Option<ActiveFreeze>, String> {
+        let recurring = self.recurring.iter().filter_map(|freeze| {
+            freeze.ends_after(at).map(|until| ActiveFreeze {
+                reason: freeze.reason.clone(),
+                until,
+                id: None,
+            })
+        });
+        let ad_hoc = FreezeWindow::active_at(conn, at)?.map(|window| ActiveFreeze {
+            reason: window.reason,
+            until: window.ends_at,
+            id: Some(window.id),
+        });
+        Ok(recurring.chain(ad_hoc).max_by_key(|freeze| freeze.until))
+    }
+}
+
+fn minute_of_week(at: PrimitiveDateTime) -> i64 {
+    i64::from(at.weekday().number_days_from_monday()) * 24 * 60
+        + i64::from(at.hour()) * 60
+        + i64::from(at.minute())
+}
+
+/// Parses `Fri 18:00` or `friday 18:00` into minutes since Monday 00:00
+fn parse_minute_of_week(input: &str) -> Option<i64> {
+    let (day, time) = input.trim().split_once(char::is_whitespace)?;
+    let day = day.to_lowercase();
+    let weekday = [
+        Weekday::Monday,
+        Weekday::Tuesday,
+        Weekday::Wednesday,
+        Weekday::Thursday,
+        Weekday::Friday,
+        Weekday::Saturday,
+        Weekday::Sunday,
+    ]
+    .into_iter()
+    .find(|weekday| day.len() >= 3 && weekday.to_string().to_lowercase().starts_with(&day))?;
+    let (hour, minute) = time.trim().split_once(':')?;
+    let (hour, minute) = (hour.parse::<i64>().ok()?, minute.parse::<i64>().ok()?);
+    if !(0..24).contains(&hour) || !(0..60).contains(&minute) {
+        return None;
+    }
+    Some(i64::from(weekday.number_days_from_monday()) * 24 * 60 + hour * 60 + minute)
+}
@@ -0,0 +1,747 @@
+//! gRPC interface for internal automation, defined in proto/ssm.proto
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+};
+
+use actix_web::web;
+use log::{error, info, warn};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+use tonic::{metadata::MetadataValue, transport::Server, Request, Response, Status};
+
+use crate::{
+    activity::ActivityLog,
+    db::{history::now, UserAndOptions},
+    freeze::Freezes,
+    hooks::{Event, EventHooks},
+    i18n::Message,
+    key_usage::fingerprint,
+    limits::KeyLimits,
+    models::{self, KeyHistory, NewPublicUserKey, NewUser, PublicUserKey},
+    scheduler::Scheduler,
+    ssh::{deploy_principals, locks_out, locks_out_ssm, parse_public_key, SshClient},
+    ConnectionPool, DbConnection,
+};
+
+mod proto {
+    tonic::include_proto!("ssm.v1");
+}
+
+use proto::{
+    deploy_progress::Stage,
+    ssm_server::{Ssm, SsmServer},
+    AddKeyRequest, Authorization, AuthorizeRequest, CreateUserRequest, DeleteKeyRequest,
+    DeleteKeyResponse, DeleteUserRequest, DeleteUserResponse, DeployProgress, DeployRequest,
+    GetHostRequest, Host, Key, ListAuthorizationsRequest, ListAuthorizationsResponse,
+    ListHostsRequest, ListHostsResponse, ListKeysRequest, ListKeysResponse, ListUsersRequest,
+    ListUsersResponse, RevokeRequest, RevokeResponse, User,
+};
+
+/// Recorded as the actor of changes made through gRPC
+const ACTOR: &str = "grpc";
+
+fn default_listen() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 50051))
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct GrpcConfig {
+    /// Address the gRPC server listens on (default 127.0.0.1:50051)
+    #[serde(default = "default_listen")]
+    listen: SocketAddr,
+    /// Clients authenticate with `authorization: Bearer <token>`
+    token: String,
+}
+
+/// Serves the gRPC interface until the server fails
+#[allow(clippy::too_many_arguments)]
+pub async fn serve(
+    config: GrpcConfig,
+    pool: ConnectionPool,
+    ssh_client: Arc<dyn SshClient>,
+    event_hooks: Arc<EventHooks>,
+    freezes: Arc<Freezes>,
+    activity_log: Arc<ActivityLog>,
+    scheduler: Arc<Scheduler>,
+    key_limits: KeyLimits,
+) {
+    let Ok(expected) = MetadataValue::try_from(format!("Bearer {}", config.token)) else {
+        error!("The gRPC token contains invalid characters, not starting the gRPC server");
+        return;
+    };
+    let service = SsmService {
+        pool,
+        ssh_client,
+        event_hooks,
+        freezes,
+        activity_log,
+        scheduler,
+        key_limits,
+    };
+    let check_token = move |request: Request<()>| match request.metadata().get("authorization") {
+        Some(token) if token == expected => Ok(request),
+        _ => Err(Status::unauthenticated("Missing or invalid token")),
+    };
+
+    info!("Starting gRPC server on {}", config.listen);
+    if let Err(e) = Server::builder()
+        .add_service(SsmServer::with_interceptor(service, check_token))
+        .serve(config.listen)
+        .await
+    {
+        error!("gRPC server failed: {e}");
+    }
+}
+
+struct SsmService {
+    pool: ConnectionPool,
+    ssh_client: Arc<dyn SshClient>,
+    event_hooks: Arc<EventHooks>,
+    freezes: Arc<Freezes>,
+    activity_log: Arc<ActivityLog>,
+    /// Queues the deployments that remove deleted keys
+    scheduler: Arc<Scheduler>,
+    key_limits: KeyLimits,
+}
+
+impl SsmService {
+    /// Runs a database query on the blocking thread pool
+    async fn with_conn<T, F>(&self, f: F) -> Result<T, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut DbConnection) -> Result<T, Status> + Send + 'static,
+    {
+        let pool = self.pool.clone();
+        web::block(move || {
+            let mut conn = pool.get().map_err(|e| Status::unavailable(e.to_string()))?;
+            f(&mut conn)
+        })
+        .await
+        .map_err(|_| Status::internal("Blocking error."))?
+    }
+
+    /// Rejects changes while SSM is read-only
+    fn check_writable(&self) -> Result<(), Status> {
+        match self.freezes.read_only() {
+            Some(read_only) => Err(Status::unavailable(read_only.message())),
+            None => Ok(()),
+        }
+    }
+
+    /// Records a change in the activity log, `target` names the call and what it changed
+    async fn record<T>(&self, action: &'static str, target: String, result: &Result<T, Status>) {
+        let status = match result {
+            Ok(_) => 200,
+            Err(status) => http_status(status),
+        };
+        let pool = self.pool.clone();
+        let activity_log = self.activity_log.clone();
+        let _ = web::block(move || {
+            let recorded = pool.get().map_err(|e| e.to_string()).and_then(|mut conn| {
+                activity_log.record(&mut conn, ACTOR, action, "GRPC", &target, status)
+            });
+            if let Err(e) = recorded {
+                error!("Failed to record {action} by {ACTOR}: {e}");
+            }
+        })
+        .await;
+    }
+}
+
+/// The HTTP status closest to a gRPC status, so activity of both can be filtered alike
+fn http_status(status: &Status) -> u16 {
+    match status.code() {
+        tonic::Code::InvalidArgument => 400,
+        tonic::Code::Unauthenticated => 401,
+        tonic::Code::PermissionDenied => 403,
+        tonic::Code::NotFound => 404,
+        tonic::Code::AlreadyExists => 409,
+        tonic::Code::FailedPrecondition => 412,
+        tonic::Code::Unavailable => 503,
+        _ => 500,
+    }
+}
+
+/// Database errors are reported as internal errors
+fn internal(error: String) -> Status {
+    Status::internal(error)
+}
+
+fn get_host(conn: &mut DbConnection, name: String) -> Result<models::Host, Status> {
+    models::Host::get_from_name_sync(conn, name.clone())
+        .map_err(internal)?
+        .ok_or_else(|| Status::not_found(format!("No host named '{name}'")))
+}
+
+fn get_user(conn: &mut DbConnection, username: &str) -> Result<models::User, Status> {
+    models::User::find_user(conn, username)
+        .map_err(internal)?
+        .ok_or_else(|| Status::not_found(format!("No user named '{username}'")))
+}
+
+fn host_message(host: models::Host, names: &HashMap<i32, String>) -> Host {
+    Host {
+        id: host.id,
+        tags: host.tag_list().map(str::to_owned).collect(),
+        aliases: host.alias_list().map(str::to_owned).collect(),
+        key_fingerprints: host.key_fingerprint_list().map(str::to_owned).collect(),
+        jump_via: host.jump_via.and_then(|id| names.get(&id).cloned()),
+        name: host.name,
+        address: host.address,
+        port: host.port.into(),
+        username: host.username,
+        transport: host.transport,
+        protected: host.protected,
+    }
+}
+
+fn user_message(user: models::User) -> User {
+    User {
+        id: user.id,
+        username: user.username,
+        enabled: user.enabled,
+        email: user.email,
+        full_name: user.full_name,
+        department: user.department,
+        external_id: user.external_id,
+    }
+}
+
+fn key_message(username: String, key: PublicUserKey) -> Key {
+    Key {
+        id: key.id,
+        username,
+        security_key: key.is_security_key(),
+        key_type: key.key_type,
+        key_base64: key.key_base64,
+        comment: key.comment,
+    }
+}
+
+fn progress(stage: Stage, login: &str, message: String) -> Result<DeployProgress, Status> {
+    Ok(DeployProgress {
+        stage: stage.into(),
+        login: login.to_owned(),
+        message,
+    })
+}
+
+#[tonic::async_trait]
+impl Ssm for SsmService {
+    async fn list_hosts(
+        &self,
+        request: Request<ListHostsRequest>,
+    ) -> Result<Response<ListHostsResponse>, Status> {
+        let tag = request.into_inner().tag;
+        let hosts = self
+            .with_conn(|conn| models::Host::get_all_hosts(conn).map_err(internal))
+            .await?;
+        let names: HashMap<i32, String> = hosts
+            .iter()
+            .map(|host| (host.id, host.name.clone()))
+            .collect();
+
+        Ok(Response::new(ListHostsResponse {
+            hosts: hosts
+                .into_iter()
+                .filter(|host| tag.as_deref().is_none_or(|tag| host.has_tag(tag)))
+                .map(|host| host_message(host, &names))
+                .collect(),
+        }))
+    }
+
+    async fn get_host(&self, request: Request<GetHostRequest>) -> Result<Response<Host>, Status> {
+        let name = request.into_inner().name;
+        let host = self
+            .with_conn(move |conn| {
+                let host = get_host(conn, name)?;
+                let mut names = HashMap::new();
+                if let Some(jump_via) = host.jump_via {
+                    if let Some(jumphost) =
+                        models::Host::get_from_id_sync(conn, jump_via).map_err(internal)?
+                    {
+                        names.insert(jumphost.id, jumphost.name);
+                    }
+                }
+                Ok(host_message(host, &names))
+            })
+            .await?;
+        Ok(Response::new(host))
+    }
+
+    async fn list_users(
+        &self,
+        _request: Request<ListUsersRequest>,
+    ) -> Result<Response<ListUsersResponse>, Status> {
+        let users = self
+            .with_conn(|conn| models::User::get_all_users(conn).map_err(internal))
+            .await?;
+        Ok(Response::new(ListUsersResponse {
+            users: users.into_iter().map(user_message).collect(),
+        }))
+    }
+
+    async fn create_user(
+        &self,
+        request: Request<CreateUserRequest>,
+    ) -> Result<Response<User>, Status> {
+        self.check_writable()?;
+        let username = request.into_inner().username;
+        if username.is_empty() {
+            return Err(Status::invalid_argument("The username can't be empty"));
+        }
+        let target = format!("CreateUser {username}");
+        let result = self
+            .with_conn(move |conn| {
+                if models::User::find_user(conn, &username)
+                    .map_err(internal)?
+                    .is_some()
+                {
+                    return Err(Status::already_exists(format!(
+                        "User '{username}' already exists"
+                    )));
+                }
+                models::User::add_user(conn, NewUser { username })
+                    .and_then(|username| models::User::get_user(conn, username))
+                    .map_err(internal)
+            })
+            .await;
+        self.record("user.create", target, &result).await;
+        Ok(Response::new(user_message(result?)))
+    }
+
+    async fn delete_user(
+        &self,
+        request: Request<DeleteUserRequest>,
+    ) -> Result<Response<DeleteUserResponse>, Status> {
+        self.check_writable()?;
+        let username = request.into_inner().username;
+        let deleted = username.clone();
+        let result = self
+            .with_conn(move |conn| {
+                get_user(conn, &deleted)?;
+                models::User::delete_user(conn, &deleted, ACTOR).map_err(internal)
+            })
+            .await;
+        self.record("user.delete", format!("DeleteUser {username}"), &result)
+            .await;
+        let keys = result?;
+        for key in keys {
+            self.event_hooks
+                .emit(Event::key_revoked(username.clone(), key));
+        }
+        Ok(Response::new(DeleteUserResponse {}))
+    }
+
+    async fn list_keys(
+        &self,
+        request: Request<ListKeysRequest>,
+    ) -> Result<Response<ListKeysResponse>, Status> {
+        let username = request.into_inner().username;
+        let keys = self
+            .with_conn(move |conn| match username {
+                Some(username) => {
+                    let user = get_user(conn, &username)?;
+                    Ok(user
+                        .get_keys(conn)
+                        .map_err(internal)?
+                        .into_iter()
+                        .map(|key| key_message(username.clone(), key))
+                        .collect())
+                }
+                None => Ok(PublicUserKey::get_all_keys_with_username(conn)
+                    .map_err(internal)?
+                    .into_iter()
+                    .map(|(username, key)| key_message(username, key))
+                    .collect()),
+            })
+            .await?;
+        Ok(Response::new(ListKeysResponse { keys }))
+    }
+
+    async fn add_key(&self, request: Request<AddKeyRequest>) -> Result<Response<Key>, Status> {
+        self.check_writable()?;
+        let AddKeyRequest {
+            username,
+            public_key,
+        } = request.into_inner();
+        let parsed = parse_public_key(&public_key).map_err(Status::invalid_argument)?;
+        let key_base64 = parsed.base64.clone();
+
+        let target = format!("AddKey {username}");
+        let key = self
+            .with_conn(move |conn| {
+                let user = get_user(conn, &username)?;
+                let new_key =
+                    NewPublicUserKey::new(parsed.algorithm, parsed.base64, parsed.comment, user.id);
+                PublicUserKey::add_key(conn, new_key, ACTOR).map_err(internal)?;
+                user.get_keys(conn)
+                    .map_err(internal)?
+                    .into_iter()
+                    .find(|key| key.key_base64.eq(&key_base64))
+                    .map(|key| key_message(username, key))
+                    .ok_or_else(|| Status::internal("Added key not found"))
+            })
+            .await;
+        self.record("key.create", target, &key).await;
+        Ok(Response::new(key?))
+    }
+
+    async fn delete_key(
+        &self,
+        request: Request<DeleteKeyRequest>,
+    ) -> Result<Response<DeleteKeyResponse>, Status> {
+        self.check_writable()?;
+        let id = request.into_inner().id;
+        let result = self
+            .with_conn(move |conn| {
+                let (username, key) =
+                    PublicUserKey::delete_key(conn, id, ACTOR).map_err(|error| {
+                        if error.eq("Record not found.") {
+                            Status::not_found(format!("No key with id {id}"))
+                        } else {
+                            internal(error)
+                        }
+                    })?;
+                let logins =
+                    PublicUserKey::deployed_to(conn, &[username.as_str()]).map_err(internal)?;
+                Ok((username, key, logins))
+            })
+            .await;
+        self.record("key.delete", format!("DeleteKey {id}"), &result)
+            .await;
+        let (username, key, logins) = result?;
+        let removal = Arc::clone(&self.scheduler).queue_key_removal(
+            fingerprint(&key).into_iter().collect(),
+            logins,
+            ACTOR,
+        );
+        self.event_hooks.emit(Event::key_revoked(username, key));
+        Ok(Response::new(DeleteKeyResponse {
+            removal: removal.id,
+            logins: u32::try_from(removal.targets.len()).unwrap_or(u32::MAX),
+        }))
+    }
+
+    async fn list_authorizations(
+        &self,
+        request: Request<ListAuthorizationsRequest>,
+    ) -> Result<Response<ListAuthorizationsResponse>, Status> {
+        let ListAuthorizationsRequest { host, username } = request.into_inner();
+        let authorizations = self
+            .with_conn(move |conn| {
+                let to_message =
+                    |host: String, username: String, (id, _, login, options)| Authorization {
+                        id,
+                        host,
+                        username,
+                        login,
+                        options,
+                    };
+                match (host, username) {
+                    (Some(host), username) => {
+                        let host = get_host(conn, host)?;
+                        Ok(host
+                            .get_authorized_users(conn)
+                            .map_err(internal)?
+                            .into_iter()
+                            .filter(|(_, user, _, _)| {
+                                username.as_ref().is_none_or(|username| user.eq(username))
+                            })
+                            .map(|authorization: UserAndOptions| {
+                                to_message(
+                                    host.name.clone(),
+                                    authorization.1.clone(),
+                                    authorization,
+                                )
+                            })
+                            .collect::<Vec<_>>())
+                    }
+                    (None, Some(username)) => {
+                        let user = get_user(conn, &username)?;
+                        Ok(user
+                            .get_authorizations(conn)
+                            .map_err(internal)?
+                            .into_iter()
+                            .map(|authorization: UserAndOptions| {
+                                to_message(authorization.1.clone(), username.clone(), authorization)
+                            })
+                            .collect())
+                    }
+                    (None, None) => {
+                        Err(Status::invalid_argument("Filter by host, username or both"))
+                    }
+                }
+            })
+            .await?;
+        Ok(Response::new(ListAuthorizationsResponse { authorizations }))
+    }
+
+    async fn authorize(
+        &self,
+        request: Request<AuthorizeRequest>,
+    ) -> Result<Response<Authorization>, Status> {
+        self.check_writable()?;
+        let AuthorizeRequest {
+            host,
+            username,
+            login,
+            options,
+        } = request.into_inner();
+        if login.is_empty() {
+            return Err(Status::invalid_argument("The login can't be empty"));
+        }
+        let target = format!("Authorize {host} {username} {login}");
+        let (ssh_client, key_limits) = (self.ssh_client.clone(), self.key_limits);
+        let authorization = self
+            .with_conn(move |conn| {
+                let host = get_host(conn, host)?;
+                let user = get_user(conn, &username)?;
+                if host
+                    .get_authorized_users(conn)
+                    .map_err(internal)?
+                    .iter()
+                    .any(|(_, authorized, authorized_login, _)| {
+                        authorized.eq(&username) && authorized_login.eq(&login)
+                    })
+                {
+                    return Err(Status::already_exists(format!(
+                        "'{username}' is already authorized as '{login}' on '{}'",
+                        host.name
+                    )));
+                }
+                host.check_key_limits(
+                    ssh_client.as_ref(),
+                    conn,
+                    user.id,
+                    &login,
+                    options.as_deref(),
+                    &key_limits,
+                )
+                .map_err(Status::failed_precondition)?;
+                models::Host::authorize_user(conn, host.id, user.id, login.clone(), options, ACTOR)
+                    .map_err(internal)?;
+                host.get_authorized_users(conn)
+                    .map_err(internal)?
+                    .into_iter()
+                    .find(|(_, authorized, authorized_login, _)| {
+                        authorized.eq(&username) && authorized_login.eq(&login)
+                    })
+                    .map(|(id, username, login, options)| Authorization {
+                        id,
+                        host: host.name.clone(),
+                        username,
+                        login,
+                        options,
+                    })
+                    .ok_or_else(|| Status::internal("Added authorization not found"))
+            })
+            .await;
+        self.record("authorization.create", target, &authorization)
+            .await;
+        Ok(Response::new(authorization?))
+    }
+
+    async fn revoke(
+        &self,
+        request: Request<RevokeRequest>,
+    ) -> Result<Response<RevokeResponse>, Status> {
+        self.check_writable()?;
+        let id = request.into_inner().id;
+        let result = self
+            .with_conn(move |conn| {
+                models::Host::delete_authorization(conn, id, ACTOR).map_err(internal)
+            })
+            .await;
+        self.record("authorization.delete", format!("Revoke {id}"), &result)
+            .await;
+        result?;
+        Ok(Response::new(RevokeResponse {}))
+    }
+
+    type DeployStream = Pin<Box<dyn Stream<Item = Result<DeployProgress, Status>> + Send>>;
+
+    async fn deploy(
+        &self,
+        request: Request<DeployRequest>,
+    ) -> Result<Response<Self::DeployStream>, Status> {
+        self.check_writable()?;
+        let DeployRequest { host, login, force } = request.into_inner();
+        let target = match &login {
+            Some(login) => format!("Deploy {host} {login}"),
+            None => format!("Deploy {host}"),
+        };
+        let freezes = self.freezes.clone();
+        let result = self
+            .with_conn(move |conn| {
+                // Breaking a freeze needs a login, which gRPC clients don't have
+                if let Some(freeze) = freezes.active(conn, now()).map_err(internal)? {
+                    return Err(Status::failed_precondition(freeze.message()));
+                }
+                let host = get_host(conn, host)?;
+                if host.protected {
+                    return Err(Status::failed_precondition(format!(
+                        "{} is protected, deploy to it from the Web UI",
+                        host.name
+                    )));
+                }
+                let logins: BTreeSet<String> = match login {
+                    Some(login) => BTreeSet::from([login]),
+                    None => host
+                        .get_authorized_users(conn)
+                        .map_err(internal)?
+                        .into_iter()
+                        .map(|(_, _, login, _)| login)
+                        .collect(),
+                };
+                Ok((host, logins))
+            })
+            .await;
+        self.record("deploy", target, &result).await;
+        let (host, logins) = result?;
+
+        let (sender, receiver) = mpsc::channel(logins.len() * 2 + 1);
+        let pool = self.pool.clone();
+        let ssh_client = self.ssh_client.clone();
+        let event_hooks = self.event_hooks.clone();
+        let key_limits = self.key_limits;
+        tokio::spawn(async move {
+            let mut failed = 0;
+            for login in &logins {
+                // Stop when the client went away
+                if sender
+                    .send(progress(Stage::Started, login, String::new()))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+                let (stage, message) = match deploy_login(
+                    &pool,
+                    ssh_client.clone(),
+                    &event_hooks,
+                    &host,
+                    login,
+                    &key_limits,
+                    force,
+                )
+                .await
+                {
+                    Ok(message) => (Stage::Deployed, message),
+                    Err(error) => {
+                        failed += 1;
+                        (Stage::Failed, error)
+                    }
+                };
+                if sender.send(progress(stage, login, message)).await.is_err() {
+                    return;
+                }
+            }
+            let summary = format!(
+                "Deployed {} of {} logins on {}",
+                logins.len() - failed,
+                logins.len(),
+                host.name
+            );
+            let _ = sender.send(progress(Stage::Finished, "", summary)).await;
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+}
+
+/// Writes the generated authorized_keys file of one login and records what changed
+async fn deploy_login(
+    pool: &ConnectionPool,
+    ssh_client: Arc<dyn SshClient>,
+    event_hooks: &EventHooks,
+    host: &models::Host,
+    login: &str,
+    key_limits: &KeyLimits,
+    force: bool,
+) -> Result<String, String> {
+    // The previous file tells which keys this deployment adds and removes
+    let previous_keyfile = ssh_client
+        .get_authorized_keyfile(host.clone(), login)
+        .await
+        .unwrap_or_default();
+    let authorized_keys = {
+        let (pool, host, login, ssh_client, key_limits) = (
+            pool.clone(),
+            host.clone(),
+            login.to_owned(),
+            ssh_client.clone(),
+            *key_limits,
+        );
+        web::block(move || {
+            let mut conn = pool.get().map_err(|e| e.to_string())?;
+            host.get_authorized_keys_file_for(ssh_client.as_ref(), &mut conn, &login, &key_limits)
+        })
+        .await
+        .map_err(|_| "Blocking error.".to_owned())??
+    };
+    let authorized_keys = host.keep_tolerated_keys(authorized_keys, &previous_keyfile);
+    let own_key = ssh_client.get_own_key_b64();
+    if !force && locks_out_ssm(host, login, &authorized_keys, &own_key) {
+        return Err(Message::new("deploy.own_key")
+            .arg(login)
+            .arg(&host.name)
+            .to_string());
+    }
+    if !force && locks_out(&previous_keyfile, &authorized_keys, &own_key) {
+        return Err(Message::new("deploy.last_key")
+            .arg(login)
+            .arg(&host.name)
+            .to_string());
+    }
+
+    let output = ssh_client
+        .set_authorized_keys(host.name.clone(), login.to_owned(), authorized_keys.clone())
+        .await
+        .map_err(|e| e.to_string())?;
+    let principals = deploy_principals(ssh_client.as_ref(), pool, host, login).await;
+
+    let (pool, host_name, login_owned) = (pool.clone(), host.name.clone(), login.to_owned());
+    let recorded = web::block(move || {
+        let mut conn = pool.get().map_err(|e| e.to_string())?;
+        KeyHistory::record_deploy(
+            &mut conn,
+            &host_name,
+            &login_owned,
+            &previous_keyfile,
+            &authorized_keys,
+            ACTOR,
+        )
+    })
+    .await
+    .map_err(|_| "Blocking error.".to_owned())?;
+    if let Err(e) = recorded {
+        warn!("Failed to record key history for {}: {e}", host.name);
+    }
+
+    event_hooks.emit(Event::KeysDeployed {
+        host: host.name.clone(),
+        login: login.to_owned(),
+    });
+    let mut message = String::from("Applied authorized_keys");
+    if let Some(pre_hook) = output.pre_hook {
+        message.push_str(&format!("\nPre deploy hook: {pre_hook}"));
+    }
+    if let Some(post_hook) = output.post_hook {
+        message.push_str(&format!("\nPost deploy hook: {post_hook}"));
+    }
+    if let Some(hash) = output.verified_hash {
+        message.push_str(&format!("\nVerified, SHA256 {hash}"));
+    }
+    for finding in output.permissions {
+        message.push_str(&format!("\nPermissions: {finding}"));
+    }
+    if principals.map_err(|e| format!("{message}\nFailed to apply authorized_principals: {e}"))? {
+        message.push_str("\nApplied authorized_principals");
+    }
+    Ok(message)
+}
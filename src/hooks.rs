@@ -0,0 +1,273 @@
+//! Server-side event hooks, running local programs or calling URLs when something happens
+use std::process::Stdio;
+use std::time::Duration;
+
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::{
+    db::recertification::RecertificationReport,
+    models::{OutboxEvent, PublicUserKey},
+    ConnectionPool,
+};
+
+/// How long a hook may take before it is abandoned
+const HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct HookConfig {
+    /// Events this hook is interested in, `*` matches all
+    events: Vec<String>,
+    /// Program that gets the event as JSON on stdin
+    command: Option<String>,
+    /// http or https URL the event is POSTed to
+    url: Option<String>,
+}
+
+/// Something that happened in SSM
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
+pub enum Event {
+    HostCreated {
+        host: String,
+        address: String,
+    },
+    KeysDeployed {
+        host: String,
+        login: String,
+    },
+    DriftDetected {
+        host: String,
+        /// Logins whose authorized_keys differ from the database
+        logins: Vec<String>,
+    },
+    HostKeyMismatch {
+        host: String,
+        address: String,
+        /// Fingerprint of the host key the host offered
+        offered: String,
+        /// Fingerprints accepted for the host
+        accepted: Vec<String>,
+    },
+    /// A key was deleted, by itself or with its user
+    KeyRevoked {
+        user: String,
+        key_id: i32,
+        key_type: String,
+        key_base64: String,
+        comment: Option<String>,
+    },
+    /// Every authorization of a campaign was reviewed or its deadline passed
+    RecertificationCompleted {
+        campaign: i32,
+        name: String,
+        tag: Option<String>,
+        total: usize,
+        approved: usize,
+        revoked: usize,
+        auto_revoked: usize,
+        overdue: usize,
+    },
+}
+
+impl Event {
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::HostCreated { .. } => "host.created",
+            Self::KeysDeployed { .. } => "keys.deployed",
+            Self::DriftDetected { .. } => "drift.detected",
+            Self::HostKeyMismatch { .. } => "hostkey.mismatch",
+            Self::KeyRevoked { .. } => "key.revoked",
+            Self::RecertificationCompleted { .. } => "recertification.completed",
+        }
+    }
+
+    pub fn key_revoked(user: String, key: PublicUserKey) -> Self {
+        Self::KeyRevoked {
+            user,
+            key_id: key.id,
+            key_type: key.key_type,
+            key_base64: key.key_base64,
+            comment: key.comment,
+        }
+    }
+
+    pub fn recertification_completed(report: RecertificationReport) -> Self {
+        Self::RecertificationCompleted {
+            campaign: report.campaign.id,
+            name: report.campaign.name,
+            tag: report.campaign.tag,
+            total: report.total,
+            approved: report.approved,
+            revoked: report.revoked,
+            auto_revoked: report.auto_revoked,
+            overdue: report.overdue,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    event: &'static str,
+    #[serde(with = "time::serde::rfc3339")]
+    timestamp: OffsetDateTime,
+    data: &'a Event,
+}
+
+/// Where events are stored until the bus publisher sends them
+#[derive(Debug, Clone)]
+struct Outbox {
+    pool: ConnectionPool,
+    /// Events that are published, `*` matches all
+    events: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct EventHooks {
+    hooks: Vec<HookConfig>,
+    outbox: Option<Outbox>,
+}
+
+impl EventHooks {
+    /// Checks the configured hooks
+    pub fn new(hooks: Vec<HookConfig>) -> Result<Self, String> {
+        for hook in &hooks {
+            match (&hook.command, &hook.url) {
+                (Some(_), None) => {}
+                (None, Some(url)) => check_url(url)?,
+                _ => return Err("Each hook needs exactly one of 'command' or 'url'".to_owned()),
+            }
+        }
+        Ok(Self {
+            hooks,
+            outbox: None,
+        })
+    }
+
+    /// Also writes the given events to the outbox, for publishing them to a message bus
+    pub fn with_outbox(mut self, pool: ConnectionPool, events: Vec<String>) -> Self {
+        self.outbox = Some(Outbox { pool, events });
+        self
+    }
+
+    /// Runs all hooks subscribed to this event in the background
+    pub fn emit(&self, event: Event) {
+        let name = event.name();
+        let payload = match serde_json::to_vec(&Payload {
+            event: name,
+            timestamp: OffsetDateTime::now_utc(),
+            data: &event,
+        }) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("Failed to serialize {name} event: {e}");
+                return;
+            }
+        };
+
+        if let Some(outbox) = self
+            .outbox
+            .as_ref()
+            .filter(|outbox| outbox.events.iter().any(|e| e.eq("*") || e.eq(name)))
+        {
+            let pool = outbox.pool.clone();
+            let payload = String::from_utf8_lossy(&payload).into_owned();
+            tokio::task::spawn_blocking(move || {
+                let recorded = pool
+                    .get()
+                    .map_err(|e| e.to_string())
+                    .and_then(|mut conn| OutboxEvent::record(&mut conn, name, payload));
+                if let Err(e) = recorded {
+                    error!("Failed to write {name} event to the outbox: {e}");
+                }
+            });
+        }
+
+        for hook in self
+            .hooks
+            .iter()
+            .filter(|hook| hook.events.iter().any(|e| e.eq("*") || e.eq(name)))
+        {
+            let hook = hook.clone();
+            let payload = payload.clone();
+
+            tokio::spawn(async move {
+                let result = match (hook.command, hook.url) {
+                    (Some(command), _) => {
+                        tokio::time::timeout(HOOK_TIMEOUT, run_command(&command, name, &payload))
+                            .await
+                    }
+                    (None, Some(url)) => {
+                        tokio::time::timeout(HOOK_TIMEOUT, post(&url, &payload)).await
+                    }
+                    (None, None) => return,
+                };
+                match result {
+                    Ok(Ok(())) => debug!("Ran hook for {name}"),
+                    Ok(Err(e)) => warn!("Hook for {name} failed: {e}"),
+                    Err(_) => warn!("Hook for {name} timed out"),
+                }
+            });
+        }
+    }
+}
+
+async fn run_command(command: &str, event: &str, payload: &[u8]) -> Result<(), String> {
+    let mut child = Command::new(command)
+        .env("SSM_EVENT", event)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("Failed to start '{command}': {e}"))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(payload)
+            .await
+            .map_err(|e| format!("Failed to write to '{command}': {e}"))?;
+    }
+
+    let status = child.wait().await.map_err(|e| e.to_string())?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("'{command}' exited with {status}"))
+    }
+}
+
+/// Hook URLs need to be http or https URLs with a host
+fn check_url(url: &str) -> Result<(), String> {
+    let uri: ureq::http::Uri = url
+        .parse()
+        .map_err(|e| format!("Invalid hook URL '{url}': {e}"))?;
+    match uri.scheme_str() {
+        Some("http" | "https") if uri.host().is_some() => Ok(()),
+        _ => Err(format!(
+            "Hook URL '{url}' is not supported, only http:// and https:// URLs can be called directly. Use a command hook for anything else."
+        )),
+    }
+}
+
+/// POSTs the event on the blocking pool, statuses other than 2xx count as failures
+async fn post(url: &str, payload: &[u8]) -> Result<(), String> {
+    let (url, payload) = (url.to_owned(), payload.to_vec());
+    tokio::task::spawn_blocking(move || {
+        let agent: ureq::Agent = ureq::Agent::config_builder()
+            .timeout_global(Some(HOOK_TIMEOUT))
+            .user_agent("ssm")
+            .build()
+            .into();
+        agent
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .send(payload.as_slice())
+            .map(|_| ())
+            .map_err(|e| format!("POST to {url} failed: {e}"))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
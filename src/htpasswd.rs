@@ -0,0 +1,404 @@
+//! Passwords of the htpasswd file. Besides bcrypt as `htpasswd -B` writes it, argon2id and SHA-512
+//! crypt hashes are accepted. Hashes of other schemes than argon2id can be upgraded after a login,
+//! and logins can change their password through the API.
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use log::error;
+
+/// Keeps two changes from writing the file at the same time
+static WRITE: Mutex<()> = Mutex::new(());
+
+/// The fingerprints of the file last read, so requests don't have to read it each time
+static FINGERPRINTS: Mutex<Option<Fingerprints>> = Mutex::new(None);
+
+struct Fingerprints {
+    path: PathBuf,
+    /// Modification time and size of the file when it was read
+    version: (SystemTime, u64),
+    /// The fingerprint of the hash of each user
+    by_user: HashMap<String, String>,
+}
+
+impl Fingerprints {
+    fn read(path: &Path) -> io::Result<Self> {
+        // Before reading, so a change in between is noticed on the next check
+        let version = version(path)?;
+        let content = fs::read_to_string(path)?;
+        Ok(Self::of(path, version, &content))
+    }
+
+    fn of(path: &Path, version: (SystemTime, u64), content: &str) -> Self {
+        let mut by_user = HashMap::new();
+        for (name, hash) in content.lines().filter_map(|line| line.split_once(':')) {
+            // Like the login, only the first line of a user counts
+            by_user
+                .entry(name.to_owned())
+                .or_insert_with(|| fingerprint(hash));
+        }
+        Self {
+            path: path.to_owned(),
+            version,
+            by_user,
+        }
+    }
+}
+
+fn version(path: &Path) -> io::Result<(SystemTime, u64)> {
+    let metadata = fs::metadata(path)?;
+    Ok((metadata.modified()?, metadata.len()))
+}
+
+/// How a password of the htpasswd file is hashed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashScheme {
+    /// `$2y$` of `htpasswd -B`, `$2b$` and `$2a$`
+    Bcrypt,
+    /// `$argon2id$`, the scheme of new and upgraded hashes
+    Argon2id,
+    /// `$6$` of `mkpasswd -m sha-512` and `openssl passwd -6`
+    Sha512Crypt,
+}
+
+impl HashScheme {
+    pub fn of(hash: &str) -> Option<Self> {
+        if ["$2y$", "$2b$", "$2a$"]
+            .iter()
+            .any(|prefix| hash.starts_with(prefix))
+        {
+            Some(Self::Bcrypt)
+        } else if hash.starts_with("$argon2id$") {
+            Some(Self::Argon2id)
+        } else if hash.starts_with("$6$") {
+            Some(Self::Sha512Crypt)
+        } else {
+            None
+        }
+    }
+
+    /// Whether hashes of this scheme are replaced with argon2id ones
+    pub fn is_legacy(self) -> bool {
+        self != Self::Argon2id
+    }
+}
+
+/// The hash of `username` in the content of the htpasswd file, like the login only the first line
+/// of the user counts
+pub fn hash_of<'a>(content: &'a str, username: &str) -> Option<&'a str> {
+    content
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find_map(|(name, hash)| (name == username).then_some(hash))
+}
+
+/// Identifies a hash in the session without keeping the hash itself in the cookie
+pub fn fingerprint(hash: &str) -> String {
+    ssh_key::HashAlg::Sha256
+        .digest(hash.as_bytes())
+        .iter()
+        .take(16)
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// The fingerprint of the hash of `username` in the file at `path`, `None` if the user isn't in it.
+/// The file is only read again once its modification time or size changed, if that fails the
+/// fingerprints read before are kept. Blocks on the file system.
+pub fn current_fingerprint(path: &Path, username: &str) -> Result<Option<String>, String> {
+    let mut cached = FINGERPRINTS.lock().map_err(|e| e.to_string())?;
+    let read_before = cached
+        .as_ref()
+        .filter(|fingerprints| fingerprints.path == path)
+        .map(|fingerprints| fingerprints.version);
+    if read_before.is_none() || version(path).ok() != read_before {
+        match Fingerprints::read(path) {
+            Ok(fingerprints) => *cached = Some(fingerprints),
+            Err(e) if read_before.is_some() => {
+                error!("Error reading authentication file, keeping the passwords read before: {e}");
+            }
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    Ok(cached
+        .as_ref()
+        .and_then(|fingerprints| fingerprints.by_user.get(username).cloned()))
+}
+
+/// Whether `password` matches `hash`, false for unsupported schemes and malformed hashes
+pub fn verify(password: &str, hash: &str) -> bool {
+    match HashScheme::of(hash) {
+        Some(HashScheme::Bcrypt) => {
+            // The hashes of Apache are the same, only the bcrypt crate doesn't know the prefix
+            let hash = hash.replacen("$2y$", "$2b$", 1);
+            bcrypt::verify(password, &hash).unwrap_or(false)
+        }
+        Some(HashScheme::Argon2id) => PasswordHash::new(hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        }),
+        Some(HashScheme::Sha512Crypt) => sha_crypt::sha512_check(password, hash).is_ok(),
+        None => {
+            let hash_type = hash.get(..4).unwrap_or(hash);
+            error!("Unsupported hash type '{hash_type}' encountered.");
+            false
+        }
+    }
+}
+
+/// An argon2id hash of `password` with a random salt and the default parameters
+pub fn hash(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// Replaces the hash of `username` in the htpasswd file with an argon2id hash of `password` and
+/// returns it. The file is written next to the old one with its permissions and renamed over it.
+pub fn set_password(path: &Path, username: &str, password: &str) -> Result<String, String> {
+    replace_hash(path, username, password, |_| true)?
+        .ok_or_else(|| format!("The password of {username} wasn't replaced"))
+}
+
+/// Like [`set_password`], but only if `current` is the password of `username` in the file, `None`
+/// if it isn't. Both happen while no other change writes the file.
+pub fn change_password(
+    path: &Path,
+    username: &str,
+    current: &str,
+    password: &str,
+) -> Result<Option<String>, String> {
+    replace_hash(path, username, password, |hash| verify(current, hash))
+}
+
+/// Replaces the hash of `username` if `accept` accepts the one in the file
+fn replace_hash(
+    path: &Path,
+    username: &str,
+    password: &str,
+    accept: impl FnOnce(&str) -> bool,
+) -> Result<Option<String>, String> {
+    let hash = hash(password)?;
+    let _write = WRITE.lock().map_err(|e| e.to_string())?;
+
+    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    match hash_of(&content, username) {
+        Some(current) if !accept(current) => return Ok(None),
+        Some(_) => {}
+        None => return Err(format!("{username} isn't in the file anymore")),
+    }
+    let mut replaced = false;
+    let mut changed = String::with_capacity(content.len() + hash.len());
+    for line in content.lines() {
+        match line.split_once(':') {
+            // Like the login, only the first line of the user counts
+            Some((name, _)) if name == username && !replaced => {
+                changed.push_str(&format!("{username}:{hash}"));
+                replaced = true;
+            }
+            _ => changed.push_str(line),
+        }
+        changed.push('\n');
+    }
+
+    let mut temporary = OsString::from(path.as_os_str());
+    temporary.push(".new");
+    let permissions = fs::metadata(path).map_err(|e| e.to_string())?.permissions();
+    let written = fs::File::create(&temporary).and_then(|mut file| {
+        file.set_permissions(permissions)?;
+        file.write_all(changed.as_bytes())?;
+        file.sync_all()
+    });
+    match written.and_then(|()| fs::rename(&temporary, path)) {
+        Ok(()) => {
+            if let Ok(mut cached) = FINGERPRINTS.lock() {
+                *cached = version(path)
+                    .ok()
+                    .map(|version| Fingerprints::of(path, version, &changed));
+            }
+            Ok(Some(hash))
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&temporary);
+            Err(e.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `admin`, as `htpasswd -B -C 4` writes it
+    const BCRYPT: &str = "$2b$04$cqpMKZLfwI9eAwbJY4duzO49gO4vUFH41GCze3DX32XfybqMrBbR6";
+    /// `secret`
+    const ARGON2ID: &str = "$argon2id$v=19$m=19456,t=2,p=1$9rOLruCgHpWDkZ5vIm9b5A$ywudJBzhu4IShTUkehvyAdt5dr31E6dUh7Dcxp7gQJA";
+    /// `secret`, as `openssl passwd -6 -salt saltsalt` writes it
+    const SHA512_CRYPT: &str = "$6$saltsalt$TVLlQcbpFVof5W3Yz4DTP6gRstiNuHwwTt6GLc1E5n0U0aDehy0S5knV8wiOQSpT0Y77vwPZN.Pq.H91p5hVO1";
+
+    /// Tests writing files share the cached fingerprints
+    static FILES: Mutex<()> = Mutex::new(());
+
+    /// A file in the temporary directory that is removed again at the end of the test
+    struct TemporaryFile(PathBuf);
+
+    impl TemporaryFile {
+        fn new(name: &str, content: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("ssm-{}-{name}", std::process::id()));
+            fs::write(&path, content).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TemporaryFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn scheme_of_hash() {
+        assert_eq!(HashScheme::of(BCRYPT), Some(HashScheme::Bcrypt));
+        let apache = BCRYPT.replacen("$2b$", "$2y$", 1);
+        assert_eq!(HashScheme::of(&apache), Some(HashScheme::Bcrypt));
+        assert_eq!(HashScheme::of(ARGON2ID), Some(HashScheme::Argon2id));
+        assert_eq!(HashScheme::of(SHA512_CRYPT), Some(HashScheme::Sha512Crypt));
+        for unsupported in [
+            "$apr1$salt$hash",
+            "{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=",
+            "plain",
+            "",
+        ] {
+            assert_eq!(HashScheme::of(unsupported), None);
+        }
+
+        assert!(HashScheme::Bcrypt.is_legacy());
+        assert!(HashScheme::Sha512Crypt.is_legacy());
+        assert!(!HashScheme::Argon2id.is_legacy());
+    }
+
+    #[test]
+    fn verify_each_scheme() {
+        assert!(verify("admin", BCRYPT));
+        assert!(verify("admin", &BCRYPT.replacen("$2b$", "$2y$", 1)));
+        assert!(verify("secret", ARGON2ID));
+        assert!(verify("secret", SHA512_CRYPT));
+
+        assert!(!verify("Admin", BCRYPT));
+        assert!(!verify("secret ", ARGON2ID));
+        assert!(!verify("", SHA512_CRYPT));
+    }
+
+    #[test]
+    fn verify_rejects_unsupported_and_malformed_hashes() {
+        assert!(!verify("admin", "$apr1$lZL6V/ci$eIMz/iKDkbtys/uU7LEK00"));
+        assert!(!verify("admin", "$2b$04$truncated"));
+        assert!(!verify("secret", "$argon2id$v=19$m=19456,t=2,p=1$invalid"));
+        assert!(!verify("secret", "$6$"));
+    }
+
+    #[test]
+    fn new_hashes_are_argon2id() {
+        let hash = hash("correct horse battery staple").unwrap();
+        assert_eq!(HashScheme::of(&hash), Some(HashScheme::Argon2id));
+        assert!(verify("correct horse battery staple", &hash));
+        assert_ne!(hash, super::hash("correct horse battery staple").unwrap());
+    }
+
+    #[test]
+    fn first_line_of_a_user_counts() {
+        let content = format!("alice:{BCRYPT}\nbob:{ARGON2ID}\nalice:{SHA512_CRYPT}\n");
+        assert_eq!(hash_of(&content, "alice"), Some(BCRYPT));
+        assert_eq!(hash_of(&content, "bob"), Some(ARGON2ID));
+        assert_eq!(hash_of(&content, "ali"), None);
+    }
+
+    #[test]
+    fn set_password_replaces_only_the_user() {
+        let _files = FILES.lock().unwrap_or_else(|e| e.into_inner());
+        let file = TemporaryFile::new(
+            "set-password",
+            &format!("alice:{BCRYPT}\n# comment\nbob:{SHA512_CRYPT}\nalice:{SHA512_CRYPT}\n"),
+        );
+
+        let hash = set_password(&file.0, "alice", "new password").unwrap();
+        let content = fs::read_to_string(&file.0).unwrap();
+        assert_eq!(
+            content,
+            format!("alice:{hash}\n# comment\nbob:{SHA512_CRYPT}\nalice:{SHA512_CRYPT}\n")
+        );
+        assert!(verify("new password", hash_of(&content, "alice").unwrap()));
+        assert!(set_password(&file.0, "carol", "new password").is_err());
+    }
+
+    #[test]
+    fn change_password_needs_the_current_password() {
+        let _files = FILES.lock().unwrap_or_else(|e| e.into_inner());
+        let file = TemporaryFile::new("change-password", &format!("alice:{ARGON2ID}\n"));
+
+        assert_eq!(
+            change_password(&file.0, "alice", "wrong", "new password").unwrap(),
+            None
+        );
+        assert_eq!(
+            fs::read_to_string(&file.0).unwrap(),
+            format!("alice:{ARGON2ID}\n")
+        );
+
+        let hash = change_password(&file.0, "alice", "secret", "new password")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(&file.0).unwrap(),
+            format!("alice:{hash}\n")
+        );
+        assert!(change_password(&file.0, "bob", "secret", "new password").is_err());
+    }
+
+    #[test]
+    fn fingerprints_follow_the_file() {
+        let _files = FILES.lock().unwrap_or_else(|e| e.into_inner());
+        let file = TemporaryFile::new("fingerprints", &format!("alice:{BCRYPT}\n"));
+
+        let read = current_fingerprint(&file.0, "alice").unwrap();
+        assert_eq!(read, Some(fingerprint(BCRYPT)));
+        assert_eq!(current_fingerprint(&file.0, "bob").unwrap(), None);
+
+        // Changed by hand, with another size so the change is seen within the same mtime
+        fs::write(&file.0, format!("alice:{SHA512_CRYPT}\nbob:{ARGON2ID}\n")).unwrap();
+        let changed = current_fingerprint(&file.0, "alice").unwrap();
+        assert_eq!(changed, Some(fingerprint(SHA512_CRYPT)));
+        assert_eq!(
+            current_fingerprint(&file.0, "bob").unwrap(),
+            Some(fingerprint(ARGON2ID))
+        );
+
+        let hash = set_password(&file.0, "alice", "new password").unwrap();
+        assert_eq!(
+            current_fingerprint(&file.0, "alice").unwrap(),
+            Some(fingerprint(&hash))
+        );
+
+        // A file that can't be read keeps what was read before
+        fs::remove_file(&file.0).unwrap();
+        assert_eq!(
+            current_fingerprint(&file.0, "alice").unwrap(),
+            Some(fingerprint(&hash))
+        );
+        let never_read = file.0.with_extension("missing");
+        assert!(current_fingerprint(&never_read, "alice").is_err());
+    }
+}
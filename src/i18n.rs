@@ -0,0 +1,391 @@
+//! Messages in the language of the request. API errors carry the code of their message next to
+//! the text, so clients can match on it instead of on the English text.
+use std::fmt;
+use std::future::Future;
+
+/// Languages of the message catalog
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Lang {
+    #[default]
+    En,
+    De,
+}
+
+impl Lang {
+    /// The most preferred language of an Accept-Language header that is in the catalog,
+    /// English if there is none
+    pub fn from_accept_language(header: &str) -> Self {
+        let mut best: Option<(Self, f32)> = None;
+        for range in header.split(',') {
+            let mut params = range.split(';');
+            let Some(lang) = params.next().and_then(Self::from_tag) else {
+                continue;
+            };
+            let quality = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok());
+            match quality {
+                Some(quality) if quality > 0.0 && best.is_none_or(|(_, q)| quality > q) => {
+                    best = Some((lang, quality));
+                }
+                _ => {}
+            }
+        }
+        best.map_or(Self::En, |(lang, _)| lang)
+    }
+
+    /// Only the primary language counts, `de-AT` is German
+    fn from_tag(tag: &str) -> Option<Self> {
+        let primary = tag.trim().split('-').next()?;
+        if primary.eq_ignore_ascii_case("en") {
+            Some(Self::En)
+        } else if primary.eq_ignore_ascii_case("de") {
+            Some(Self::De)
+        } else {
+            None
+        }
+    }
+
+    /// For the Content-Language header
+    pub const fn tag(self) -> &'static str {
+        match self {
+            Self::En => "en",
+            Self::De => "de",
+        }
+    }
+}
+
+tokio::task_local! {
+    static LANG: Lang;
+}
+
+/// The language of the request that is handled, English outside of requests
+pub fn current() -> Lang {
+    LANG.try_with(|lang| *lang).unwrap_or_default()
+}
+
+/// Shows the messages of everything `f` does in `lang`
+pub async fn scope<F: Future>(lang: Lang, f: F) -> F::Output {
+    LANG.scope(lang, f).await
+}
+
+/// Shows the messages of `f` in `lang`, for work on other threads like the blocking pool
+pub fn sync_scope<F: FnOnce() -> R, R>(lang: Lang, f: F) -> R {
+    LANG.sync_scope(lang, f)
+}
+
+/// Code, English and German text of every message. `{0}`, `{1}`, ... are replaced by the arguments.
+const CATALOG: &[(&str, &str, &str)] = &[
+    (
+        "activity.category_not_found",
+        "There is no activity category named {0}",
+        "Es gibt keine Aktivitätskategorie namens {0}",
+    ),
+    (
+        "activity.settings_required",
+        "Changes of the settings are always recorded",
+        "Änderungen der Einstellungen werden immer aufgezeichnet",
+    ),
+    (
+        "access.host_denied",
+        "'{0}' may not change '{1}'",
+        "'{0}' darf '{1}' nicht ändern",
+    ),
+    (
+        "access.network_denied",
+        "Requests from {0} aren't allowed",
+        "Anfragen von {0} sind nicht erlaubt",
+    ),
+    (
+        "access.operator_denied",
+        "'{0}' may only change hosts with their tags",
+        "'{0}' darf nur Hosts mit den eigenen Tags ändern",
+    ),
+    (
+        "auth.password_too_short",
+        "The new password needs at least {0} characters",
+        "Das neue Passwort braucht mindestens {0} Zeichen",
+    ),
+    (
+        "auth.wrong_password",
+        "The current password is wrong",
+        "Das aktuelle Passwort ist falsch",
+    ),
+    (
+        "authorization.added",
+        "Authorized user",
+        "Benutzer berechtigt",
+    ),
+    (
+        "authorization.deleted",
+        "Deleted authorization.",
+        "Berechtigung gelöscht.",
+    ),
+    (
+        "authorization.no_history",
+        "No history for this authorization",
+        "Keine Historie für diese Berechtigung",
+    ),
+    (
+        "authorization.not_found",
+        "No such authorization",
+        "Berechtigung nicht gefunden",
+    ),
+    (
+        "compliance.no_cached_state",
+        "There is no usable cached state of {0}",
+        "Es gibt keinen verwendbaren Stand von {0} im Cache",
+    ),
+    (
+        "compliance.pack_not_found",
+        "There is no compliance pack named {0}",
+        "Es gibt kein Compliance-Paket namens {0}",
+    ),
+    (
+        "confirmation.expired",
+        "This confirmation expired, please add the host again",
+        "Diese Bestätigung ist abgelaufen, bitte den Host erneut hinzufügen",
+    ),
+    (
+        "confirmation.invalid",
+        "The token doesn't confirm this change, preview it again",
+        "Das Token bestätigt diese Änderung nicht, bitte die Vorschau erneut abrufen",
+    ),
+    (
+        "csrf.invalid",
+        "Missing or invalid {0} header, get a token from /api/auth/csrf",
+        "Fehlender oder ungültiger {0}-Header, ein Token gibt es unter /api/auth/csrf",
+    ),
+    (
+        "deploy.last_key",
+        "Deploying would leave {0} on {1} without a key besides the one of SSM, force it to deploy anyway",
+        "Nach dem Deployment hätte {0} auf {1} außer dem von SSM keinen Schlüssel mehr, zum Deployen erzwingen",
+    ),
+    (
+        "deploy.own_key",
+        "Deploying would remove the key of SSM for {0} on {1}, SSM couldn't manage the host anymore, force it to deploy anyway",
+        "Nach dem Deployment fehlte der Schlüssel von SSM für {0} auf {1}, SSM könnte den Host nicht mehr verwalten, zum Deployen erzwingen",
+    ),
+    (
+        "dr_bundle.disabled",
+        "The disaster recovery bundle is disabled, it needs recipients or a passphrase",
+        "Das Notfallpaket ist deaktiviert, es braucht Empfänger oder eine Passphrase",
+    ),
+    (
+        "failed_logins.disabled",
+        "Reading failed logins is disabled",
+        "Das Auslesen fehlgeschlagener Anmeldungen ist deaktiviert",
+    ),
+    (
+        "freeze.deploy_denied",
+        "'{0}' may not deploy during a change freeze",
+        "'{0}' darf während eines Change Freeze nicht ausrollen",
+    ),
+    (
+        "freeze.invalid_window",
+        "The freeze has to end in the future and after it begins",
+        "Der Freeze muss in der Zukunft und nach seinem Beginn enden",
+    ),
+    (
+        "freeze.reason_required",
+        "A reason is required",
+        "Eine Begründung ist erforderlich",
+    ),
+    ("host.added", "Added host", "Host hinzugefügt"),
+    (
+        "host.deleted",
+        "Deleted {0} record(s)",
+        "{0} Eintrag/Einträge gelöscht",
+    ),
+    (
+        "host.invalid_fingerprint",
+        "Invalid fingerprint '{0}'",
+        "Ungültiger Fingerprint '{0}'",
+    ),
+    (
+        "host.jump_host_not_found",
+        "Jump host not found",
+        "Jump-Host nicht gefunden",
+    ),
+    ("host.not_found", "No such host", "Host nicht gefunden"),
+    ("hostkey.added", "Added hostkey", "Hostkey hinzugefügt"),
+    ("key.added", "Added key", "Schlüssel hinzugefügt"),
+    (
+        "key.already_owned",
+        "The key already belongs to '{0}'",
+        "Der Schlüssel gehört bereits '{0}'",
+    ),
+    (
+        "key.batch_already_owned",
+        "The keys already belong to '{0}'",
+        "Die Schlüssel gehören bereits '{0}'",
+    ),
+    (
+        "key.batch_filter_required",
+        "A filter is required to delete keys",
+        "Zum Löschen von Schlüsseln ist ein Filter nötig",
+    ),
+    (
+        "key.comment_updated",
+        "Comment updated successfully",
+        "Kommentar aktualisiert",
+    ),
+    ("key.deleted", "Deleted key", "Schlüssel gelöscht"),
+    (
+        "key.deleted_removing",
+        "Deleted key, removing it from {0} authorized_keys files",
+        "Schlüssel gelöscht, er wird aus {0} authorized_keys-Dateien entfernt",
+    ),
+    (
+        "key.invalid_algorithm",
+        "Invalid key algorithm",
+        "Ungültiger Schlüsselalgorithmus",
+    ),
+    (
+        "key.no_history",
+        "No history for this key",
+        "Keine Historie für diesen Schlüssel",
+    ),
+    ("key.not_found", "No such key", "Schlüssel nicht gefunden"),
+    (
+        "key.usage_disabled",
+        "Reading when keys were last used is disabled",
+        "Das Auslesen der letzten Schlüsselnutzung ist deaktiviert",
+    ),
+    (
+        "keyfile.login_not_found",
+        "'{0}' has no login '{1}'",
+        "'{0}' hat keinen Login '{1}'",
+    ),
+    (
+        "keyfile.not_cached",
+        "'{0}' wasn't fetched yet",
+        "'{0}' wurde noch nicht abgefragt",
+    ),
+    (
+        "maintenance.read_only",
+        "SSM is read-only since {0} UTC: {1}",
+        "SSM ist seit {0} UTC schreibgeschützt: {1}",
+    ),
+    (
+        "recertification.already_decided",
+        "The authorization was already {0}",
+        "Über die Berechtigung wurde bereits entschieden: {0}",
+    ),
+    (
+        "recertification.deadline_past",
+        "The deadline has to be in the future",
+        "Die Frist muss in der Zukunft liegen",
+    ),
+    (
+        "recertification.item_not_found",
+        "No such item",
+        "Eintrag nicht gefunden",
+    ),
+    (
+        "recertification.name_required",
+        "A name is required",
+        "Ein Name ist erforderlich",
+    ),
+    (
+        "recertification.nothing_to_review",
+        "There are no authorizations to review",
+        "Es gibt keine Berechtigungen zu prüfen",
+    ),
+    (
+        "search.no_criteria",
+        "Search by fingerprint, base64 or comment",
+        "Suche nach fingerprint, base64 oder comment",
+    ),
+    (
+        "search.query_required",
+        "Search with a text in q",
+        "Suche mit einem Text in q",
+    ),
+    (
+        "sshd_config.change_disabled",
+        "Changing sshd_config is disabled",
+        "Das Ändern der sshd_config ist deaktiviert",
+    ),
+    (
+        "sshd_config.no_directives",
+        "No directives to change",
+        "Keine Direktiven zu ändern",
+    ),
+    (
+        "sshd_config.read_disabled",
+        "Reading sshd_config is disabled",
+        "Das Lesen der sshd_config ist deaktiviert",
+    ),
+    ("user.added", "Added user", "Benutzer hinzugefügt"),
+    ("user.deleted", "Deleted user", "Benutzer gelöscht"),
+    (
+        "update.available",
+        "A newer SSM version is available: {0}",
+        "Eine neuere SSM-Version ist verfügbar: {0}",
+    ),
+    (
+        "user.merge_into_itself",
+        "A user can't be merged into itself",
+        "Ein Benutzer kann nicht mit sich selbst zusammengeführt werden",
+    ),
+    (
+        "user.not_found",
+        "No such user '{0}'",
+        "Benutzer '{0}' nicht gefunden",
+    ),
+    (
+        "user.pair_not_found",
+        "No such user '{0}' or '{1}'",
+        "Benutzer '{0}' oder '{1}' nicht gefunden",
+    ),
+];
+
+/// A message of the catalog with its arguments. It is shown in the language of the request
+/// when it is formatted, e.g. with `to_string()`.
+#[derive(Debug, Clone)]
+pub struct Message {
+    code: &'static str,
+    args: Vec<String>,
+}
+
+impl Message {
+    pub const fn new(code: &'static str) -> Self {
+        Self {
+            code,
+            args: Vec::new(),
+        }
+    }
+
+    /// Adds the next argument, it replaces `{0}` first, then `{1}` and so on
+    #[must_use]
+    pub fn arg(mut self, arg: impl fmt::Display) -> Self {
+        self.args.push(arg.to_string());
+        self
+    }
+
+    pub const fn code(&self) -> &'static str {
+        self.code
+    }
+
+    /// The text in `lang`, the code itself if it isn't in the catalog
+    pub fn text(&self, lang: Lang) -> String {
+        let Some((_, en, de)) = CATALOG.iter().find(|(code, _, _)| code.eq(&self.code)) else {
+            return self.code.to_owned();
+        };
+        let mut text = match lang {
+            Lang::En => (*en).to_owned(),
+            Lang::De => (*de).to_owned(),
+        };
+        for (i, arg) in self.args.iter().enumerate() {
+            text = text.replace(&format!("{{{i}}}"), arg);
+        }
+        text
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text(current()))
+    }
+}
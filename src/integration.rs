@@ -0,0 +1,215 @@
+//! Inventory exchange with Teleport and HashiCorp Boundary, for shops that move between managing
+//! authorized_keys files and an access platform. SSM doesn't talk to the platforms itself, it writes
+//! files for their CLIs (`tctl create -f`, the Boundary hosts API) and reads the output of
+//! `tctl get nodes --format=json` and `boundary hosts list -format json`.
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::models::Host;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    Teleport,
+    Boundary,
+}
+
+impl FromStr for Platform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "teleport" => Ok(Self::Teleport),
+            "boundary" => Ok(Self::Boundary),
+            _ => Err(format!(
+                "Unknown platform '{s}', expected teleport or boundary"
+            )),
+        }
+    }
+}
+
+/// Labels Teleport sets itself, they aren't turned into tags
+const TELEPORT_INTERNAL_LABELS: &[&str] = &["teleport.internal/", "teleport.dev/"];
+
+/// A host in the inventory of a platform
+#[derive(Debug, Serialize)]
+pub struct InventoryHost {
+    pub name: String,
+    pub address: String,
+    pub port: u16,
+    /// Labels as tags, `key=value` or just `key` for labels with the value `true`
+    pub tags: String,
+}
+
+/// Tags of a host as Teleport labels, `key=value` tags keep their value, others get `true`
+fn labels(host: &Host) -> BTreeMap<&str, &str> {
+    host.tag_list()
+        .map(|tag| tag.split_once('=').unwrap_or((tag, "true")))
+        .collect()
+}
+
+/// Stable name of the Teleport node of a host, so exporting again updates the same node
+fn node_name(host: &Host) -> String {
+    Uuid::new_v5(
+        &Uuid::NAMESPACE_URL,
+        format!("ssm:host:{}", host.name).as_bytes(),
+    )
+    .to_string()
+}
+
+/// Teleport resources for `tctl create -f`: an OpenSSH node per host and a user per username with
+/// the logins the user is authorized for and the given roles
+pub fn teleport_resources(hosts: &[Host], logins: &[(String, String)], roles: &[&str]) -> String {
+    let nodes = hosts.iter().map(|host| {
+        json!({
+            "kind": "node",
+            "sub_kind": "openssh",
+            "version": "v2",
+            "metadata": {
+                "name": node_name(host),
+                "labels": labels(host),
+            },
+            "spec": {
+                "addr": format!("{}:{}", host.address, host.port),
+                "hostname": host.name,
+            },
+        })
+    });
+
+    let mut users: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for (username, login) in logins {
+        users.entry(username).or_default().push(login);
+    }
+    let users = users.into_iter().map(|(username, logins)| {
+        json!({
+            "kind": "user",
+            "version": "v2",
+            "metadata": { "name": username },
+            "spec": {
+                "roles": roles,
+                "traits": { "logins": logins },
+            },
+        })
+    });
+
+    nodes
+        .chain(users)
+        .map(|resource| format!("---\n{resource}\n"))
+        .collect()
+}
+
+/// Static hosts of a Boundary host catalog, one request body of the hosts API each. Boundary keeps
+/// ports on targets, so only the address is exported.
+pub fn boundary_hosts(hosts: &[Host], host_catalog_id: &str) -> Vec<Value> {
+    hosts
+        .iter()
+        .map(|host| {
+            let description = format!(
+                "{}@{}:{} managed by SSM",
+                host.username, host.address, host.port
+            );
+            json!({
+                "host_catalog_id": host_catalog_id,
+                "type": "static",
+                "name": host.name,
+                "description": description,
+                "attributes": { "address": host.address },
+            })
+        })
+        .collect()
+}
+
+/// Reads the hosts of `tctl get nodes --format=json` or `boundary hosts list -format json`
+pub fn parse_inventory(
+    platform: Platform,
+    inventory: &Value,
+) -> Result<Vec<InventoryHost>, String> {
+    let items = match inventory {
+        Value::Array(items) => items,
+        Value::Object(object) => match object.get("items") {
+            Some(Value::Array(items)) => items,
+            Some(Value::Null) | None => return Ok(Vec::new()),
+            Some(_) => return Err(String::from("items has to be a list")),
+        },
+        _ => return Err(String::from("Expected a list of hosts")),
+    };
+    items
+        .iter()
+        .map(|item| match platform {
+            Platform::Teleport => teleport_node(item),
+            Platform::Boundary => boundary_host(item),
+        })
+        .collect()
+}
+
+fn str_at<'a>(value: &'a Value, pointer: &str) -> Option<&'a str> {
+    value
+        .pointer(pointer)
+        .and_then(Value::as_str)
+        .filter(|s| !s.is_empty())
+}
+
+fn teleport_node(node: &Value) -> Result<InventoryHost, String> {
+    let name = str_at(node, "/spec/hostname")
+        .or_else(|| str_at(node, "/metadata/name"))
+        .ok_or_else(|| String::from("Node without a name"))?;
+    // Nodes connected through a reverse tunnel have no address
+    let (address, port) = match str_at(node, "/spec/addr") {
+        Some(addr) => match addr.rsplit_once(':') {
+            Some((address, port)) => (
+                address.trim_start_matches('[').trim_end_matches(']'),
+                port.parse()
+                    .map_err(|_| format!("Invalid address of node {name}: {addr}"))?,
+            ),
+            None => (addr, 22),
+        },
+        None => (name, 22),
+    };
+
+    let mut tags: Vec<String> = node
+        .pointer("/metadata/labels")
+        .and_then(Value::as_object)
+        .into_iter()
+        .flatten()
+        .filter(|(key, _)| {
+            !TELEPORT_INTERNAL_LABELS
+                .iter()
+                .any(|prefix| key.starts_with(prefix))
+        })
+        .filter_map(|(key, value)| match value.as_str()? {
+            "true" => Some(key.clone()),
+            value => Some(format!("{key}={value}")),
+        })
+        .filter(|tag| !tag.contains(','))
+        .collect();
+    tags.sort_unstable();
+
+    Ok(InventoryHost {
+        name: name.to_owned(),
+        address: address.to_owned(),
+        port,
+        tags: tags.join(","),
+    })
+}
+
+fn boundary_host(host: &Value) -> Result<InventoryHost, String> {
+    let name = str_at(host, "/name")
+        .or_else(|| str_at(host, "/external_name"))
+        .or_else(|| str_at(host, "/id"))
+        .ok_or_else(|| String::from("Host without a name or id"))?;
+    // Static hosts have an address, dynamic ones the names and addresses found by the plugin
+    let address = str_at(host, "/attributes/address")
+        .or_else(|| str_at(host, "/dns_names/0"))
+        .or_else(|| str_at(host, "/ip_addresses/0"))
+        .ok_or_else(|| format!("Host {name} has no address"))?;
+
+    Ok(InventoryHost {
+        name: name.to_owned(),
+        address: address.to_owned(),
+        port: 22,
+        tags: String::new(),
+    })
+}
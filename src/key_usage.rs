@@ -0,0 +1,193 @@
+//! When logins and keys were last used, read from the lines sshd logs for every accepted login and
+//! from lastlog. Keys nobody logged in with for a long time are candidates for revocation.
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+use ssh_key::HashAlg;
+use time::{macros::format_description, Duration, OffsetDateTime, UtcOffset};
+
+use crate::{
+    db::UsernameAndKey,
+    models::{Host, PublicUserKey},
+    ssh::{SshClient, SshClientError},
+};
+
+/// Reading when logins and keys were last used from the logs of the hosts
+#[derive(Debug, Default, Deserialize, Clone)]
+pub struct KeyUsageConfig {
+    /// Read the sshd logs of hosts, this needs root or the adm or systemd-journal group (default false)
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+/// The last login of a login, with any method
+#[derive(Debug, Serialize)]
+pub struct LoginUse {
+    pub login: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub last_login: OffsetDateTime,
+}
+
+/// The last login with a key
+#[derive(Debug, Serialize)]
+pub struct KeyUse {
+    pub login: String,
+    /// Fingerprint as sshd logs it, SHA256 unless FingerprintHash is set
+    pub fingerprint: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub last_used: OffsetDateTime,
+    /// The user owning the key, missing for keys that aren't in the database
+    pub user: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct KeyUsage {
+    pub logins: Vec<LoginUse>,
+    pub keys: Vec<KeyUse>,
+}
+
+/// A date as lastlog prints it without the weekday, or a syslog date with the year and offset added
+const DATE: &[time::format_description::FormatItem<'_>] = format_description!(
+    "[month repr:short] [day padding:none] [hour]:[minute]:[second] [offset_hour sign:mandatory][offset_minute] [year]"
+);
+
+fn keep_latest<K: Ord>(map: &mut BTreeMap<K, OffsetDateTime>, key: K, at: OffsetDateTime) {
+    let at = at.to_offset(UtcOffset::UTC);
+    let latest = map.entry(key).or_insert(at);
+    *latest = (*latest).max(at);
+}
+
+impl KeyUsage {
+    /// Reads the output of `get_key_usage`. Syslog dates have neither a year nor an offset, they are
+    /// taken as UTC within the year before `now`.
+    pub fn read(content: &str, now: OffsetDateTime) -> Self {
+        let mut logins = BTreeMap::new();
+        let mut keys = BTreeMap::new();
+        for line in content.lines() {
+            if let Some(entry) = line.strip_prefix("lastlog ") {
+                if let Some((login, at)) = read_lastlog(entry) {
+                    keep_latest(&mut logins, login, at);
+                }
+                continue;
+            }
+            let Some((login, fingerprint, at)) = read_accepted(line, now) else {
+                continue;
+            };
+            keep_latest(&mut logins, login, at);
+            if let Some(fingerprint) = fingerprint {
+                keep_latest(&mut keys, (login, fingerprint), at);
+            }
+        }
+
+        Self {
+            logins: logins
+                .into_iter()
+                .map(|(login, last_login)| LoginUse {
+                    login: login.to_owned(),
+                    last_login,
+                })
+                .collect(),
+            keys: keys
+                .into_iter()
+                .map(|((login, fingerprint), last_used)| KeyUse {
+                    login: login.to_owned(),
+                    fingerprint: fingerprint.to_owned(),
+                    last_used,
+                    user: None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Names the users owning the keys
+    pub fn assign_owners(&mut self, keys: &[UsernameAndKey]) {
+        let owners: HashMap<String, &str> = keys
+            .iter()
+            .filter_map(|(username, key)| Some((fingerprint(key)?, username.as_str())))
+            .collect();
+        for key in &mut self.keys {
+            key.user = owners.get(&key.fingerprint).map(|&owner| owner.to_owned());
+        }
+    }
+
+    /// The last login with a key, for any login
+    pub fn last_used(&self, fingerprint: &str) -> Option<OffsetDateTime> {
+        self.keys
+            .iter()
+            .filter(|key| key.fingerprint == fingerprint)
+            .map(|key| key.last_used)
+            .max()
+    }
+
+    /// The last login as `login` with one of these keys
+    pub fn last_used_as(&self, login: &str, fingerprints: &[String]) -> Option<OffsetDateTime> {
+        self.keys
+            .iter()
+            .filter(|key| key.login == login && fingerprints.contains(&key.fingerprint))
+            .map(|key| key.last_used)
+            .max()
+    }
+}
+
+/// SHA256 fingerprint of a key, as sshd logs it
+pub fn fingerprint(key: &PublicUserKey) -> Option<String> {
+    ssh_key::PublicKey::try_from(key)
+        .ok()
+        .map(|key| key.fingerprint(HashAlg::Sha256).to_string())
+}
+
+/// Reads `<date> <host> sshd[<pid>]: Accepted <method> for <login> from ...`. Logins with a key or
+/// certificate end with the fingerprint of the key, a certificate also has the one of its CA.
+fn read_accepted(line: &str, now: OffsetDateTime) -> Option<(&str, Option<&str>, OffsetDateTime)> {
+    let (head, accepted) = line.split_once(": Accepted ")?;
+    let mut words = accepted.split_whitespace();
+    let method = words.next()?;
+    if words.next()? != "for" {
+        return None;
+    }
+    let login = words.next()?;
+    let fingerprint = match method {
+        "publickey" => words.find(|word| word.starts_with("SHA256:") || word.starts_with("MD5:")),
+        _ => None,
+    };
+    Some((login, fingerprint, read_timestamp(head, now)?))
+}
+
+/// Reads the date at the start of a log line: seconds since the epoch (`journalctl -o short-unix`),
+/// RFC 3339 (rsyslog's high precision format) or the traditional `Oct  4 04:55:42`
+pub fn read_timestamp(head: &str, now: OffsetDateTime) -> Option<OffsetDateTime> {
+    let mut words = head.split_whitespace();
+    let first = words.next()?;
+    if let Ok(seconds) = first.split('.').next()?.parse::<i64>() {
+        return OffsetDateTime::from_unix_timestamp(seconds).ok();
+    }
+    if let Ok(at) = OffsetDateTime::parse(first, &time::format_description::well_known::Rfc3339) {
+        return Some(at);
+    }
+
+    let (day, time) = (words.next()?, words.next()?);
+    let in_year =
+        |year: i32| OffsetDateTime::parse(&format!("{first} {day} {time} +0000 {year}"), DATE).ok();
+    in_year(now.year())
+        .filter(|at| *at <= now + Duration::days(1))
+        .or_else(|| in_year(now.year() - 1))
+}
+
+/// Reads a line of lastlog: the login, the terminal, the remote host if there is one and the date
+fn read_lastlog(entry: &str) -> Option<(&str, OffsetDateTime)> {
+    let words: Vec<&str> = entry.split_whitespace().collect();
+    let [login, .., _weekday, month, day, time, offset, year] = words.as_slice() else {
+        return None;
+    };
+    let date = format!("{month} {day} {time} {offset} {year}");
+    Some((login, OffsetDateTime::parse(&date, DATE).ok()?))
+}
+
+/// When the logins and keys of a host were last used
+pub async fn host_usage(
+    ssh_client: &dyn SshClient,
+    host: Host,
+) -> Result<KeyUsage, SshClientError> {
+    let content = ssh_client.get_key_usage(host).await?;
+    Ok(KeyUsage::read(&content, OffsetDateTime::now_utc()))
+}
@@ -0,0 +1,130 @@
+//! Limits on the authorized_keys file of a login. sshd reads the whole file on every login and
+//! clients give up after MaxAuthTries keys, so files with hundreds of keys slow down logins and
+//! hide keys nobody uses anymore.
+use std::fmt;
+
+use serde::Deserialize;
+
+const fn default_warn_percent() -> usize {
+    80
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct KeyLimits {
+    /// Most keys in the authorized_keys file of a login, the key of SSM included (default unlimited)
+    #[serde(default)]
+    pub max_keys_per_login: Option<usize>,
+    /// Largest authorized_keys file in bytes (default unlimited)
+    #[serde(default)]
+    pub max_file_size: Option<usize>,
+    /// The diff warns about files that reach this percentage of a limit (default 80)
+    #[serde(default = "default_warn_percent")]
+    pub warn_percent: usize,
+}
+
+impl Default for KeyLimits {
+    fn default() -> Self {
+        Self {
+            max_keys_per_login: None,
+            max_file_size: None,
+            warn_percent: default_warn_percent(),
+        }
+    }
+}
+
+/// Keys and bytes of an authorized_keys file
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KeyfileUsage {
+    pub keys: usize,
+    pub size: usize,
+}
+
+impl KeyfileUsage {
+    pub fn of(keyfile: &str) -> Self {
+        Self {
+            keys: keyfile
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .count(),
+            size: keyfile.len(),
+        }
+    }
+
+    /// The usage after adding a line
+    pub fn with_line(self, line: &str) -> Self {
+        Self {
+            keys: self.keys + 1,
+            size: self.size + line.len() + 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitKind {
+    Keys,
+    Size,
+}
+
+/// An authorized_keys file close to or over a limit
+#[derive(Debug, Clone, Copy)]
+pub struct LimitWarning {
+    pub kind: LimitKind,
+    pub value: usize,
+    pub max: usize,
+}
+
+impl LimitWarning {
+    pub const fn exceeded(&self) -> bool {
+        self.value > self.max
+    }
+}
+
+impl fmt::Display for LimitWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unit = match self.kind {
+            LimitKind::Keys => "keys",
+            LimitKind::Size => "bytes",
+        };
+        write!(f, "{} of at most {} {unit}", self.value, self.max)
+    }
+}
+
+impl KeyLimits {
+    /// Whether any limit is set
+    pub const fn enabled(&self) -> bool {
+        self.max_keys_per_login.is_some() || self.max_file_size.is_some()
+    }
+
+    fn usages(&self, usage: KeyfileUsage) -> impl Iterator<Item = LimitWarning> {
+        [
+            (LimitKind::Keys, usage.keys, self.max_keys_per_login),
+            (LimitKind::Size, usage.size, self.max_file_size),
+        ]
+        .into_iter()
+        .filter_map(|(kind, value, max)| {
+            Some(LimitWarning {
+                kind,
+                value,
+                max: max?,
+            })
+        })
+    }
+
+    /// Fails if the authorized_keys file of the login is over a limit
+    pub fn check(&self, login: &str, usage: KeyfileUsage) -> Result<(), String> {
+        match self.usages(usage).find(LimitWarning::exceeded) {
+            Some(warning) => Err(format!(
+                "The authorized_keys file of {login} would have {warning}"
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// The limits an authorized_keys file reaches `warn_percent` of
+    pub fn warnings(&self, usage: KeyfileUsage) -> Vec<LimitWarning> {
+        self.usages(usage)
+            .filter(|warning| warning.value * 100 >= warning.max * self.warn_percent)
+            .collect()
+    }
+}
@@ -5,7 +5,7 @@ use actix_session::{storage::CookieSessionStore, SessionMiddleware};
 use actix_web::{
     dev::ServiceResponse,
     http::{header, StatusCode},
-    middleware::{ErrorHandlerResponse, ErrorHandlers},
+    middleware::{Condition, ErrorHandlerResponse, ErrorHandlers},
     web::{self, Data},
     App, HttpResponse, HttpServer,
 };
@@ -15,28 +15,60 @@ use croner::Cron;
 use diesel::prelude::QueryResult;
 use log::{error, info};
 use serde::Deserialize;
-use ssh::{CachingSshClient, SshClient};
+use hooks::{EventHooks, HookConfig};
+use middleware::{AdminAllowlist, AdminAllowlistConfig, SecurityHeaders, SecurityHeadersConfig};
+use scheduler::Scheduler;
+use ssh::{AddressFamily, CachingSshClient, HostCache, HostKeyPolicy, RealSshClient, SshClient};
 
 use diesel::r2d2::ConnectionManager;
+use diesel::r2d2::CustomizeConnection;
 use diesel::r2d2::Pool;
 
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use russh::keys::key::PrivateKeyWithHashAlg;
 use ssh_key::PrivateKey;
-use tokio_cron_scheduler::{JobBuilder, JobScheduler};
 
+mod access;
+mod activity;
+mod bus;
+mod compliance;
 mod db;
+mod dr_bundle;
+mod export;
+mod failed_logins;
+mod findings;
 mod forms;
+mod freeze;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod hooks;
+mod htpasswd;
+mod i18n;
+mod integration;
+mod key_usage;
+mod limits;
 mod middleware;
 mod models;
+mod remediation;
 mod routes;
+mod scheduler;
 mod schema;
 mod ssh;
+mod sshd;
+#[cfg(feature = "otel")]
+mod telemetry;
 mod templates;
+mod timing;
+mod update;
 
 include!(concat!(env!("OUT_DIR"), "/generated.rs"));
+#[cfg(feature = "frontend")]
+include!(concat!(env!("OUT_DIR"), "/frontend.rs"));
 
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
+/// PostgreSQL starts from the schema of all SQLite migrations, changes go into both directories
+#[cfg(feature = "postgres")]
+pub const POSTGRES_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations_postgres");
 
 #[derive(diesel::MultiConnection)]
 pub enum DbConnection {
@@ -50,10 +82,57 @@ pub enum DbConnection {
 
 pub type ConnectionPool = Pool<ConnectionManager<DbConnection>>;
 
+/// Settings SQLite keeps per connection: foreign keys are enforced, so deletes cascade on every
+/// connection of the pool, and connections wait for each other instead of failing with
+/// "database is locked", e.g. when the activity of a request is recorded while a job reads the hosts
+#[derive(Debug)]
+struct SqlitePragmas;
+
+impl CustomizeConnection<DbConnection, diesel::r2d2::Error> for SqlitePragmas {
+    fn on_acquire(&self, conn: &mut DbConnection) -> Result<(), diesel::r2d2::Error> {
+        use diesel::{sql_query, RunQueryDsl};
+
+        #[allow(irrefutable_let_patterns)]
+        if let DbConnection::Sqlite(conn) = conn {
+            for pragma in ["PRAGMA foreign_keys = on", "PRAGMA busy_timeout = 5000"] {
+                sql_query(pragma)
+                    .execute(conn)
+                    .map_err(diesel::r2d2::Error::QueryError)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The migrations for the backend of `conn`, PostgreSQL has its own. There are none for MySQL,
+/// the SQLite ones would fail on it halfway through.
+#[cfg_attr(
+    not(any(feature = "postgres", feature = "mysql")),
+    allow(unused_variables)
+)]
+fn migrations(conn: &DbConnection) -> Result<EmbeddedMigrations, String> {
+    #[cfg(feature = "postgres")]
+    if let DbConnection::Postgresql(_) = conn {
+        return Ok(POSTGRES_MIGRATIONS);
+    }
+    #[cfg(feature = "mysql")]
+    if let DbConnection::Mysql(_) = conn {
+        return Err(
+            "MySQL databases aren't supported, there are no migrations for them. Use SQLite or PostgreSQL."
+                .to_owned(),
+        );
+    }
+    Ok(MIGRATIONS)
+}
+
 const fn default_timeout() -> Duration {
     Duration::from_secs(120)
 }
 
+const fn default_diff_deadline() -> Duration {
+    Duration::from_secs(10)
+}
+
 fn deserialize_timeout<'de, D>(deserializer: D) -> Result<Duration, D::Error>
 where
     D: serde::Deserializer<'de>,
@@ -62,6 +141,15 @@ where
     Ok(Duration::from_secs(seconds))
 }
 
+/// Reads a TTL in seconds, where 0 disables expiry
+fn deserialize_ttl<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let seconds = u64::deserialize(deserializer)?;
+    Ok((seconds > 0).then(|| Duration::from_secs(seconds)))
+}
+
 fn deserialize_cron<'de, D>(deserializer: D) -> Result<Option<Cron>, D::Error>
 where
     D: serde::Deserializer<'de>,
@@ -93,13 +181,80 @@ pub struct SshConfig {
     #[serde(default = "no_cron", deserialize_with = "deserialize_cron")]
     update_schedule: Option<Cron>,
 
+    /// Cron schedule when to close overdue recertification campaigns (default disabled)
+    #[serde(default = "no_cron", deserialize_with = "deserialize_cron")]
+    recertify_schedule: Option<Cron>,
+
+    /// Cron schedule when to retry deployments queued by revocations that failed (default disabled)
+    #[serde(default = "no_cron", deserialize_with = "deserialize_cron")]
+    deploy_schedule: Option<Cron>,
+
+    /// How the deploy job rolls out the queued logins: `all`, `canary:<hosts>` or `canary:tag`
+    /// (default all)
+    #[serde(default)]
+    deploy_strategy: scheduler::DeployStrategy,
+
     /// Path to an OpenSSH Private Key
     private_key_file: PathBuf,
     /// Passphrase for the key
     private_key_passphrase: Option<String>,
+    /// Generate an ed25519 key if there is none at `private_key_file` (default false)
+    #[serde(default)]
+    generate_if_missing: bool,
     /// Connection timeout in seconds (default 2m)
     #[serde(default = "default_timeout", deserialize_with = "deserialize_timeout")]
     timeout: Duration,
+    /// How long in seconds the diff waits for hosts before it answers with the cached state (default 10)
+    #[serde(
+        default = "default_diff_deadline",
+        deserialize_with = "deserialize_timeout"
+    )]
+    diff_deadline: Duration,
+    /// Age in seconds after which cached host data is served as stale and refreshed
+    /// in the background (default 0, never expires)
+    #[serde(default, deserialize_with = "deserialize_ttl")]
+    cache_ttl: Option<Duration>,
+    /// Maximum random delay in seconds before a scheduled job starts (default 0)
+    #[serde(default, deserialize_with = "deserialize_timeout")]
+    schedule_jitter: Duration,
+    /// How many hosts a scheduled job connects to at the same time (default 1)
+    #[serde(default = "default_max_concurrent_connections")]
+    max_concurrent_connections: usize,
+    /// How many recent SSH operations are kept per host (default 50, 0 disables the log)
+    #[serde(default = "default_operation_log_size")]
+    operation_log_size: usize,
+    /// Most bytes a command on a host may output and a file read over SFTP may have (default 16 MiB)
+    #[serde(default = "default_max_output_size")]
+    max_output_size: usize,
+    /// Which addresses are tried first if a host resolves to IPv4 and IPv6 (default any),
+    /// can be overridden per host
+    #[serde(default)]
+    address_family: AddressFamily,
+    /// What happens when a host offers an unknown host key (default log)
+    #[serde(default)]
+    hostkey_policy: HostKeyPolicy,
+    /// Restore the previous authorized_keys file if a deployment can't be verified (default false)
+    #[serde(default)]
+    rollback_failed_deploys: bool,
+    /// Fix owners, modes and SELinux contexts of `.ssh` and authorized_keys after deploying (default false),
+    /// wrong ones are reported either way
+    #[serde(default)]
+    repair_permissions: bool,
+    /// Commands hosts can connect through instead of TCP, by name
+    #[serde(default)]
+    connection_plugins: ssh::ConnectionPlugins,
+}
+
+const fn default_max_concurrent_connections() -> usize {
+    1
+}
+
+const fn default_operation_log_size() -> usize {
+    50
+}
+
+const fn default_max_output_size() -> usize {
+    16 * 1024 * 1024
 }
 
 fn default_database_url() -> String {
@@ -127,6 +282,10 @@ fn default_htpasswd_path() -> PathBuf {
     PathBuf::from(".htpasswd")
 }
 
+const fn default_compress_api() -> bool {
+    true
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Configuration {
     ssh: SshConfig,
@@ -142,6 +301,66 @@ pub struct Configuration {
     session_key: String,
     #[serde(default = "default_htpasswd_path")]
     htpasswd_path: PathBuf,
+    /// Replace bcrypt and SHA-512 crypt hashes of the htpasswd file with argon2id ones after a login (default false)
+    #[serde(default)]
+    upgrade_password_hashes: bool,
+    /// Use a generated key and an in-memory fleet instead of real hosts
+    #[cfg(feature = "demo")]
+    #[serde(default)]
+    demo: bool,
+    /// Programs or URLs notified about events
+    #[serde(default)]
+    hooks: Vec<HookConfig>,
+    /// Limits what logins may change, logins without an assignment are admins
+    #[serde(default)]
+    roles: Vec<access::RoleAssignment>,
+    /// Weekly change freezes, more can be added through the API
+    #[serde(default)]
+    freeze: Vec<freeze::FreezeConfig>,
+    /// What the check job does about drift, per host or tag (default only reporting it)
+    #[serde(default)]
+    auto_remediate: Vec<remediation::RemediationRule>,
+    /// Headers added to every response
+    #[serde(default)]
+    security_headers: SecurityHeadersConfig,
+    /// Networks the Web UI and the API can be used from (default everywhere)
+    #[serde(default)]
+    admin_allowlist: AdminAllowlistConfig,
+    /// Compress API responses with gzip, brotli or zstd for clients that accept it (default true)
+    #[serde(default = "default_compress_api")]
+    compress_api: bool,
+    /// Add an X-SSM-Timing header with database, SSH and other time to every response (default false)
+    #[serde(default)]
+    debug_timing: bool,
+    /// Most keys and bytes in the authorized_keys file of a login (default unlimited)
+    #[serde(default)]
+    key_limits: limits::KeyLimits,
+    /// Reading when logins and keys were last used from the sshd logs of the hosts (default disabled)
+    #[serde(default)]
+    key_usage: key_usage::KeyUsageConfig,
+    /// Reading failed logins from the sshd logs of the hosts (default disabled)
+    #[serde(default)]
+    failed_logins: failed_logins::FailedLoginsConfig,
+    /// Reading and changing sshd_config directives of the hosts (default disabled)
+    #[serde(default)]
+    sshd_config: sshd::SshdConfigPolicy,
+    /// Policy packs the compliance report scores hosts against
+    #[serde(default)]
+    compliance: compliance::ComplianceConfig,
+    /// Message bus events are published to (default disabled)
+    bus: Option<bus::BusConfig>,
+    /// Where to export traces to (default disabled)
+    #[cfg(feature = "otel")]
+    otel: Option<telemetry::OtelConfig>,
+    /// gRPC interface for automation (default disabled)
+    #[cfg(feature = "grpc")]
+    grpc: Option<grpc::GrpcConfig>,
+    /// Encryption and content of the disaster recovery bundle (default disabled)
+    #[serde(default)]
+    dr_bundle: dr_bundle::DrBundleConfig,
+    /// Looking for newer releases on GitHub (default disabled)
+    #[serde(default)]
+    update_check: update::UpdateCheckConfig,
 }
 
 fn get_configuration() -> (Configuration, String) {
@@ -178,6 +397,91 @@ fn get_configuration() -> (Configuration, String) {
     )
 }
 
+/// Writes a new ed25519 key, encrypted with the passphrase if there is one and only readable by
+/// the owner, and its public key next to it. Returns the public key.
+fn generate_private_key(config: &SshConfig) -> ssh_key::Result<String> {
+    let key_path = &config.private_key_file;
+    let mut rng = ssh_key::rand_core::OsRng;
+
+    let mut key = PrivateKey::random(&mut rng, ssh_key::Algorithm::Ed25519)?;
+    key.set_comment("ssm");
+    let public_key = key.public_key().to_openssh()?;
+    let key = match config.private_key_passphrase.as_ref() {
+        Some(key_passphrase) => key.encrypt(&mut rng, key_passphrase)?,
+        None => key,
+    };
+
+    if let Some(dir) = key_path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    key.write_openssh_file(key_path, ssh_key::LineEnding::LF)?;
+    let mut public_key_path = key_path.clone().into_os_string();
+    public_key_path.push(".pub");
+    std::fs::write(public_key_path, format!("{public_key}\n"))?;
+    Ok(public_key)
+}
+
+fn load_private_key(config: &SshConfig) -> PrivateKey {
+    let key_path = &config.private_key_file;
+
+    if !key_path.exists() {
+        if !config.generate_if_missing {
+            error!(
+                "There is no private key at {}, create one with ssh-keygen or set ssh.generate_if_missing = true",
+                key_path.display()
+            );
+            std::process::exit(4);
+        }
+        match generate_private_key(config) {
+            Ok(public_key) => info!(
+                "Generated a new key at {}, add its public key to the hosts: {public_key}",
+                key_path.display()
+            ),
+            Err(e) => {
+                error!("Failed to generate a key at {}: {e}", key_path.display());
+                std::process::exit(4);
+            }
+        }
+    }
+
+    let key =
+        PrivateKey::read_openssh_file(key_path).expect("Failed to read key from '{key_path}'.");
+
+    match config.private_key_passphrase.as_ref() {
+        Some(key_passphrase) => match key.decrypt(key_passphrase) {
+            Ok(k) => k,
+            Err(ssh_key::Error::Decrypted) => {
+                error!("Tried to decrypt ssh key, but it is already decrypted.");
+                std::process::exit(4);
+            }
+            Err(e) => {
+                error!("Failed to decrypt ssh key: {e}");
+                std::process::exit(4);
+            }
+        },
+        None => key,
+    }
+}
+
+fn real_ssh_client(
+    pool: ConnectionPool,
+    config: &SshConfig,
+    event_hooks: Arc<EventHooks>,
+) -> RealSshClient {
+    let key = load_private_key(config);
+
+    let hash = match key.algorithm() {
+        ssh_key::Algorithm::Rsa { hash } => hash,
+        _ => None,
+    };
+
+    // TODO: maybe a better error message
+    let key = PrivateKeyWithHashAlg::new(Arc::new(key), hash)
+        .expect("Failed to convert key to Private key");
+
+    RealSshClient::new(pool, key, config.clone(), event_hooks)
+}
+
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
     color_eyre::install().expect("Couldn't intall color_eyre");
@@ -195,6 +499,9 @@ async fn main() -> Result<(), std::io::Error> {
     pretty_env_logger::init();
     info!("{}", config_source);
 
+    #[cfg(feature = "otel")]
+    let tracer_provider = configuration.otel.as_ref().map(telemetry::init);
+
     if !configuration.htpasswd_path.exists() {
         error!(
             "htpasswd file does not exist: {:?}",
@@ -202,139 +509,134 @@ async fn main() -> Result<(), std::io::Error> {
         );
         std::process::exit(3);
     }
+    if let Err(e) = configuration.sshd_config.check() {
+        error!("Invalid sshd_config settings: {e}");
+        std::process::exit(3);
+    }
+    if let Err(e) = configuration.compliance.check() {
+        error!("Invalid compliance settings: {e}");
+        std::process::exit(3);
+    }
 
     let database_url = configuration.database_url.clone();
     let manager = ConnectionManager::<DbConnection>::new(database_url);
     let pool: ConnectionPool = Pool::builder()
+        .connection_customizer(Box::new(SqlitePragmas))
+        .event_handler(Box::new(timing::PoolTimings))
         .build(manager)
         .expect("Database URL should be a valid URI");
 
     {
-        use diesel::{sql_query, RunQueryDsl};
-
         info!(
             "Trying to connect to database '{}'",
             configuration.database_url
         );
         let mut conn = pool.get().expect("Couldn't connect to database");
 
-        sql_query("PRAGMA foreign_keys = on")
-            .execute(&mut conn)
-            .expect("Couldn't activate foreign key support");
-
-        conn.run_pending_migrations(MIGRATIONS)
+        let migrations = migrations(&conn).unwrap_or_else(|e| {
+            error!("{e}");
+            std::process::exit(3);
+        });
+        conn.run_pending_migrations(migrations)
             .expect("Error while running migrations:");
     }
 
-    let key_path = &configuration.ssh.private_key_file;
-
-    let mut key =
-        PrivateKey::read_openssh_file(key_path).expect("Failed to read key from '{key_path}'.");
-
-    if let Some(key_passphrase) = configuration.ssh.private_key_passphrase.as_ref() {
-        key = match key.decrypt(key_passphrase) {
-            Ok(k) => k,
-            Err(ssh_key::Error::Decrypted) => {
-                error!("Tried to decrypt ssh key, but it is already decrypted.");
-                std::process::exit(4);
-            }
-            Err(e) => {
-                error!("Failed to decrypt ssh key: {e}");
-                std::process::exit(4);
-            }
-        };
-    };
+    let mut event_hooks = EventHooks::new(configuration.hooks.clone()).unwrap_or_else(|e| {
+        error!("Invalid hook settings: {e}");
+        std::process::exit(3);
+    });
+    if let Some(bus_config) = &configuration.bus {
+        event_hooks = event_hooks.with_outbox(pool.clone(), bus_config.events.clone());
+        tokio::spawn(bus::Publisher::new(bus_config, pool.clone()).run());
+    }
+    let event_hooks = Data::new(event_hooks);
+    let access_control = Data::new(access::AccessControl::new(&configuration.roles));
+    let freezes = Data::new(freeze::Freezes::new(&configuration.freeze));
+    let activity_log = Data::new(
+        activity::ActivityLog::load(&mut pool.get().expect("Couldn't connect to database"))
+            .expect("Failed to load the activity settings"),
+    );
+    let update_check = Data::new(update::UpdateCheck::new(&configuration.update_check));
+    if update_check.enabled() {
+        tokio::spawn(update_check.clone().into_inner().run());
+    }
 
-    let hash = match key.algorithm() {
-        ssh_key::Algorithm::Rsa { hash } => hash,
-        _ => None,
+    #[cfg(feature = "demo")]
+    let ssh_client: Arc<dyn SshClient> = if configuration.demo {
+        info!("Running in demo mode, no real hosts will be contacted");
+        let key = PrivateKey::random(&mut ssh_key::rand_core::OsRng, ssh_key::Algorithm::Ed25519)
+            .expect("Failed to generate demo key");
+        let demo_client = ssh::demo::DemoSshClient::new(&key);
+        demo_client
+            .seed(&mut pool.get().expect("Couldn't connect to database"))
+            .expect("Failed to seed demo data");
+        Arc::new(demo_client)
+    } else {
+        Arc::new(real_ssh_client(
+            pool.clone(),
+            &configuration.ssh,
+            event_hooks.clone().into_inner(),
+        ))
     };
-
-    // TODO: maybe a better error message
-    let key = PrivateKeyWithHashAlg::new(Arc::new(key), hash)
-        .expect("Failed to convert key to Private key");
+    #[cfg(not(feature = "demo"))]
+    let ssh_client: Arc<dyn SshClient> = Arc::new(real_ssh_client(
+        pool.clone(),
+        &configuration.ssh,
+        event_hooks.clone().into_inner(),
+    ));
 
     let config = Data::new(configuration.clone());
-    let ssh_client = SshClient::new(pool.clone(), key, configuration.ssh.clone());
-
-    let caching_ssh_client = Data::new(CachingSshClient::new(pool.clone(), ssh_client.clone()));
+    let caching_ssh_client: Arc<dyn HostCache> = Arc::new(CachingSshClient::new(
+        pool.clone(),
+        ssh_client.clone(),
+        configuration.ssh.cache_ttl,
+        configuration.key_limits,
+    ));
 
     info!("Starting Secure SSH Manager");
     let secret_key = cookie::Key::derive_from(configuration.session_key.as_bytes());
 
-    let caching_client_jobs = Arc::clone(&caching_ssh_client);
-
-    let check_schedule = configuration.ssh.check_schedule;
-    let update_schedule = configuration.ssh.update_schedule;
-
-    if check_schedule.is_some() || update_schedule.is_some() {
-        let sched = JobScheduler::new()
-            .await
-            .expect("Failed to create job scheduler");
-
-        tokio::spawn(async move {
-            if let Some(check_schedule) = check_schedule {
-                let client = caching_client_jobs.clone();
-
-                let mut job = JobBuilder::new().with_cron_job_type();
-                job.schedule = Some(check_schedule.clone());
-                job = job.with_run_async(Box::new(move |_uuid, _sched| {
-                    let client = client.clone();
-                    Box::pin(async move {
-                        info!("Running check job");
-                        match client.get_current_state().await {
-                            Ok(_data) => {
-                                info!("Succeeded check job");
-                                // TODO: do something with data
-                            }
-                            Err(e) => {
-                                error!("Failed check job: {e}");
-                            }
-                        };
-                    })
-                }));
-
-                sched
-                    .add(job.build().expect("Failed to build check job"))
-                    .await
-                    .expect("Failed to create check job");
-                info!("Scheduled check job: '{}'", check_schedule.pattern);
-            }
-
-            if let Some(update_schedule) = update_schedule {
-                let mut job = JobBuilder::new().with_cron_job_type();
-                job.schedule = Some(update_schedule.clone());
-                job = job.with_run_async(Box::new(move |_uuid, _sched| {
-                    let client = caching_client_jobs.clone();
-                    Box::pin(async move {
-                        info!("Running update job");
-                        match client.get_current_state().await {
-                            Ok(_) => {
-                                info!("Succeeded update job");
-                            }
-                            Err(e) => {
-                                error!("Failed update job: {e}");
-                            }
-                        };
-                    })
-                }));
-
-                sched
-                    .add(job.build().expect("Failed to build update job"))
-                    .await
-                    .expect("Failed to create update job");
-                info!("Scheduled update job: '{}'", update_schedule.pattern);
-            }
-
-            info!("Starting scheduler");
-            sched.start().await
-        });
+    let security_headers = SecurityHeaders::new(&configuration.security_headers);
+    let admin_allowlist = AdminAllowlist::new(&configuration.admin_allowlist);
+    let restrict_networks = admin_allowlist.enabled();
+    let dr_bundle = Data::new(dr_bundle::DrBundle::new(
+        &configuration.dr_bundle,
+        &configuration.ssh.private_key_file,
+    ));
+
+    let scheduler = Data::new(Scheduler::new(
+        caching_ssh_client.clone(),
+        pool.clone(),
+        &configuration.ssh,
+        event_hooks.clone().into_inner(),
+        Arc::new(remediation::RemediationPolicies::new(
+            &configuration.auto_remediate,
+        )),
+        freezes.clone().into_inner(),
+        activity_log.clone().into_inner(),
+    ));
+    tokio::spawn(scheduler.clone().into_inner().start());
+
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_config) = configuration.grpc.clone() {
+        tokio::spawn(grpc::serve(
+            grpc_config,
+            pool.clone(),
+            ssh_client.clone(),
+            event_hooks.clone().into_inner(),
+            freezes.clone().into_inner(),
+            activity_log.clone().into_inner(),
+            scheduler.clone().into_inner(),
+            configuration.key_limits,
+        ));
     }
 
-    HttpServer::new(move || {
+    let compress_api = configuration.compress_api;
+    let debug_timing = configuration.debug_timing;
+    let result = HttpServer::new(move || {
         let generated = generate();
 
-        App::new()
+        let app = App::new()
             .wrap(middleware::AuthMiddleware)
             .wrap(
                 SessionMiddleware::builder(CookieSessionStore::default(), secret_key.clone())
@@ -356,15 +658,44 @@ async fn main() -> Result<(), std::io::Error> {
                     )))
                 }),
             )
-            .app_data(Data::new(ssh_client.clone()))
-            .app_data(caching_ssh_client.clone())
+            .wrap(Condition::new(restrict_networks, admin_allowlist.clone()))
+            .wrap(security_headers.clone())
+            .wrap(Condition::new(debug_timing, middleware::RequestTiming))
+            .wrap(middleware::Localization)
+            .app_data(Data::from(ssh_client.clone()))
+            .app_data(Data::from(caching_ssh_client.clone()))
+            .app_data(scheduler.clone())
+            .app_data(event_hooks.clone())
+            .app_data(access_control.clone())
+            .app_data(freezes.clone())
+            .app_data(activity_log.clone())
+            .app_data(update_check.clone())
+            .app_data(dr_bundle.clone())
             .app_data(config.clone())
             .app_data(web::Data::new(pool.clone()))
-            .service(ResourceFiles::new("/", generated).skip_handler_when_not_found())
+            .service(ResourceFiles::new("/", generated).skip_handler_when_not_found());
+
+        #[cfg(feature = "otel")]
+        let app = app.wrap(tracing_actix_web::TracingLogger::default());
+
+        // Unknown paths below /app get the index, so the frontend can do its own routing
+        #[cfg(feature = "frontend")]
+        let app = app.service(
+            ResourceFiles::new("/app", generate_frontend()).resolve_not_found_to_root(),
+        );
+
+        app
             .service(web::scope("/auth").configure(routes::auth::auth_config))
-            .configure(routes::route_config)
+            .configure(routes::route_config(compress_api))
     })
     .bind((configuration.listen, configuration.port))?
     .run()
-    .await
+    .await;
+
+    #[cfg(feature = "otel")]
+    if let Some(Err(e)) = tracer_provider.map(|provider| provider.shutdown()) {
+        error!("Failed to send remaining traces: {e}");
+    }
+
+    result
 }
@@ -1,14 +1,36 @@
 use actix_identity::Identity;
+use actix_session::SessionExt;
 use actix_web::{
     body::{BoxBody, MessageBody},
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    http::header,
-    Error, FromRequest, HttpResponse,
+    http::{
+        header::{self, HeaderName, HeaderValue},
+        Method, StatusCode,
+    },
+    web::{self, Data},
+    Error, FromRequest, HttpRequest, HttpResponse,
 };
 use futures_util::future::LocalBoxFuture;
-use log::warn;
+use ipnet::IpNet;
+use log::{error, warn};
+use serde::Deserialize;
 use std::future::{ready, Ready};
+use std::net::IpAddr;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::{
+    access::AccessControl,
+    activity::{ActivityLog, READ_ACTION},
+    forms::FormResponseBuilder,
+    freeze::Freezes,
+    htpasswd,
+    i18n::{self, Lang, Message},
+    routes::api::message_response,
+    timing::{self, Timings},
+    Configuration, ConnectionPool,
+};
 
 pub struct AuthMiddleware;
 
@@ -51,11 +73,13 @@ where
         let path = request.path().to_owned();
         let method = request.method().to_owned();
 
-        // Skip authentication for login page, static files, and assets
+        // Skip authentication for login page, static files, assets and the embedded frontend,
+        // which logs in by itself
         if request.path().starts_with("/auth/")
             || request.path().starts_with("/static/")
             || request.path().ends_with(".css")
             || request.path().ends_with(".js")
+            || (cfg!(feature = "frontend") && (path == "/app" || path.starts_with("/app/")))
         {
             warn!("[Web] {} {} (public path)", method, path);
             let fut = self.service.call(request);
@@ -70,24 +94,831 @@ where
         let service = self.service.clone();
 
         Box::pin(async move {
-            let Ok(id) = identity.await else {
-                warn!("[Web] {} {} (unauthorized)", method, path);
+            let login_required = |http_req| {
                 let response = HttpResponse::Found()
                     .append_header((header::LOCATION, "/auth/login"))
                     .insert_header(("HX-Redirect", "/auth/login"))
                     .body("<a href=\"/auth/login\">Login</a>");
-                return Ok(ServiceResponse::new(http_req, response).map_into_boxed_body());
+                Ok(ServiceResponse::new(http_req, response).map_into_boxed_body())
             };
+            let Ok(id) = identity.await else {
+                warn!("[Web] {} {} (unauthorized)", method, path);
+                return login_required(http_req);
+            };
+
+            let actor = id.id().unwrap_or_else(|_| "unknown".to_owned());
+            if !credential_unchanged(&http_req, &actor).await {
+                warn!("[Web] {} {} (password of {} changed)", method, path, actor);
+                id.logout();
+                return login_required(http_req);
+            }
+            warn!("[Web] {} {} (authenticated user: {})", method, path, actor);
+            let action = activity_action(&method, &path);
+            let denied = action.is_some_and(|action| {
+                http_req
+                    .app_data::<Data<AccessControl>>()
+                    .is_some_and(|access| !access.may_perform(&actor, action))
+            });
+            let read_only = (!allowed_when_read_only(&method, &path))
+                .then(|| http_req.app_data::<Data<Freezes>>()?.read_only())
+                .flatten();
+            let res = if denied {
+                warn!("[Web] {} {} denied for {}", method, path, actor);
+                let error = Message::new("access.operator_denied").arg(&actor);
+                let response = if path.starts_with("/api/") {
+                    message_response(StatusCode::FORBIDDEN, &error)
+                } else {
+                    FormResponseBuilder::forbidden(error).into_response()
+                };
+                ServiceResponse::new(http_req, response).map_into_boxed_body()
+            } else if let Some(read_only) = read_only {
+                warn!("[Web] {} {} rejected, SSM is read-only", method, path);
+                let error = Message::new("maintenance.read_only")
+                    .arg(read_only.since_str())
+                    .arg(&read_only.reason);
+                let response = if path.starts_with("/api/") {
+                    message_response(StatusCode::SERVICE_UNAVAILABLE, &error)
+                } else {
+                    FormResponseBuilder::unavailable(error).into_response()
+                };
+                ServiceResponse::new(http_req, response).map_into_boxed_body()
+            } else {
+                let req = ServiceRequest::from_parts(http_req, payload);
+                service.call(req).await?.map_into_boxed_body()
+            };
+
+            let pool = res.request().app_data::<Data<ConnectionPool>>().cloned();
+            let activity_log = res.request().app_data::<Data<ActivityLog>>().cloned();
+            // Read-only requests are only recorded if their category is turned on
+            let action = action.or_else(|| {
+                (method == Method::GET
+                    && activity_log.as_ref().is_some_and(|log| log.records_reads()))
+                .then_some(READ_ACTION)
+            });
+            if let (Some(action), Some(pool), Some(activity_log)) = (action, pool, activity_log) {
+                let status = res.status().as_u16();
+                let _ = web::block(move || {
+                    let recorded = pool.get().map_err(|e| e.to_string()).and_then(|mut conn| {
+                        activity_log.record(
+                            &mut conn,
+                            &actor,
+                            action,
+                            method.as_str(),
+                            &path,
+                            status,
+                        )
+                    });
+                    if let Err(e) = recorded {
+                        error!("Failed to record {action} by {actor}: {e}");
+                    }
+                })
+                .await;
+            }
+            Ok(res)
+        })
+    }
+}
+
+/// Whether the password of `actor` is still the one the session logged in with. Other sessions
+/// of a login end this way when its password is changed, through the API or in the htpasswd file.
+/// Sessions from before the fingerprint was kept get the current one.
+async fn credential_unchanged(http_req: &HttpRequest, actor: &str) -> bool {
+    let Some(config) = http_req.app_data::<Data<Configuration>>() else {
+        return true;
+    };
+    let (path, username) = (config.htpasswd_path.clone(), actor.to_owned());
+    let current = web::block(move || htpasswd::current_fingerprint(&path, &username))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|current| current);
+    let current = match current {
+        Ok(Some(current)) => current,
+        // Not a login anymore
+        Ok(None) => return false,
+        Err(e) => {
+            error!("Can't check whether the password of {actor} changed, keeping the session: {e}");
+            return true;
+        }
+    };
+    let session = http_req.get_session();
+    match session.get::<String>(CREDENTIAL_SESSION_KEY) {
+        Ok(Some(credential)) => credential == current,
+        _ => {
+            if let Err(e) = session.insert(CREDENTIAL_SESSION_KEY, current) {
+                error!("Failed to keep the password fingerprint of {actor} in the session: {e}");
+            }
+            true
+        }
+    }
+}
+
+/// Requests that work in read-only mode: reads, switching it off, changing the own password, and
+/// requests that only show dialogs, previews or test something
+fn allowed_when_read_only(method: &Method, path: &str) -> bool {
+    if method.is_safe() {
+        return true;
+    }
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    matches!(
+        (method.as_str(), segments.as_slice()),
+        (
+            "POST",
+            ["api", "settings", "read_only"]
+                | ["api", "auth", "change_password"]
+                | ["api", "key", "convert"]
+                | ["api", "host", _, "test_connection"]
+                | ["api", "simulate"]
+                | ["api", "graphql"]
+                | ["diff", "assign_key_dialog" | "authorize_user_dialog"]
+                | ["hosts", "gen_authorized_keys"]
+        )
+    )
+}
+
+/// Normalized name of the action a changing request performs, requests that only show dialogs
+/// or previews, like generating an authorized_keys file, aren't recorded. Downloading the
+/// recovery bundle is recorded like a change, and limited to admins like one.
+fn activity_action(method: &Method, path: &str) -> Option<&'static str> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let action = match (method.as_str(), segments.as_slice()) {
+        ("POST", ["hosts", _, "set_authorized_keys"]) => "deploy",
+        ("POST", ["hosts", "add"] | ["hosts", "add", "confirm"] | ["api", "host", "bulk"]) => {
+            "host.create"
+        }
+        ("POST", ["hosts", "user", "authorize"]) => "authorization.create",
+        ("POST", ["hosts", _, "edit"]) => "host.update",
+        ("POST", ["hosts", _, "delete"]) => "host.delete",
+        ("POST", ["hosts", _, "add_hostkey"] | ["api", "host", _, "hostkeys"]) => {
+            "host.hostkey.add"
+        }
+        ("DELETE", ["api", "host", _, "hostkeys"]) => "host.hostkey.remove",
+        ("POST", ["api", "host", "import", "known_hosts"]) => "host.hostkey.import",
+        ("PUT", ["api", "host", _, "sshd_config"]) => "host.sshd_config.update",
+        ("PUT", ["api", "authorization", _] | ["api", "authorization", _, "principals"]) => {
+            "authorization.update"
+        }
+        (
+            "POST",
+            ["hosts", "delete_authorization"] | ["api", "reports", "unused_access", _, "revoke"],
+        ) => "authorization.delete",
+        ("POST", ["users", "add"]) => "user.create",
+        ("POST", ["users", "edit"]) => "user.update",
+        ("POST", ["users", "delete"]) => "user.delete",
+        ("POST", ["api", "user", "merge"]) => "user.merge",
+        ("PUT", ["api", "user", _]) => "user.update",
+        ("POST", ["users", "assign_key"]) => "key.create",
+        ("POST", ["keys", "update_comment", _]) => "key.update",
+        ("POST", ["keys", "delete"]) => "key.delete",
+        ("POST", ["api", "key", _, "transfer"]) => "key.transfer",
+        ("POST", ["api", "key", "batch", "delete"]) => "key.batch.delete",
+        ("POST", ["api", "key", "batch", "reassign"]) => "key.batch.reassign",
+        ("POST", ["api", "cache", "invalidate"]) => "cache.invalidate",
+        ("POST", ["api", "cache", "warm"]) => "cache.warm",
+        ("POST", ["api", "settings", "schedules"]) => "schedule.create",
+        ("PUT", ["api", "settings", "schedules", _]) => "schedule.update",
+        ("DELETE", ["api", "settings", "schedules", _]) => "schedule.delete",
+        ("POST", ["api", "scheduler", _, "run_now"]) => "schedule.run",
+        ("DELETE", ["api", "scheduler", "deploy_halt"]) => "schedule.resume",
+        ("POST", ["api", "freeze"]) => "freeze.create",
+        ("DELETE", ["api", "freeze", _]) => "freeze.delete",
+        ("POST", ["api", "rule"]) => "rule.create",
+        ("DELETE", ["api", "rule", _]) => "rule.delete",
+        ("POST", ["api", "recertification"]) => "recertification.create",
+        ("POST", ["api", "recertification", _, "items", _]) => "recertification.review",
+        ("POST", ["api", "reports", "orphans", _, "cleanup"]) => "orphans.cleanup",
+        ("PUT", ["api", "settings", "activity", _]) => "settings.activity.update",
+        ("DELETE", ["api", "settings", "activity", _]) => "settings.activity.reset",
+        ("POST", ["api", "settings", "read_only"]) => "settings.read_only.update",
+        ("POST", ["api", "settings", "diff_ignore"]) => "settings.diff_ignore.create",
+        ("DELETE", ["api", "settings", "diff_ignore", _]) => "settings.diff_ignore.delete",
+        ("GET", ["api", "admin", "dr_bundle"]) => "admin.dr_bundle",
+        ("POST", ["api", "auth", "change_password"]) => "auth.change_password",
+        _ => return None,
+    };
+    Some(action)
+}
+
+/// Session key of the fingerprint of the password hash the session logged in with
+pub const CREDENTIAL_SESSION_KEY: &str = "credential";
+
+/// Session key of the CSRF token
+pub const CSRF_SESSION_KEY: &str = "csrf_token";
+/// Header that has to carry the CSRF token on state changing requests
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// Rejects state changing requests unless they carry the CSRF token of the session in the [`CSRF_HEADER`]
+pub struct CsrfProtection;
+
+impl<S, B> Transform<S, ServiceRequest> for CsrfProtection
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CsrfProtectionService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CsrfProtectionService {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct CsrfProtectionService<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for CsrfProtectionService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        if request.method().is_safe() {
+            let fut = self.service.call(request);
+            return Box::pin(async move {
+                let res = fut.await?;
+                Ok(res.map_into_boxed_body())
+            });
+        }
+
+        let expected = request
+            .get_session()
+            .get::<String>(CSRF_SESSION_KEY)
+            .ok()
+            .flatten();
+        let provided = request
+            .headers()
+            .get(CSRF_HEADER)
+            .and_then(|value| value.to_str().ok());
+
+        match (expected, provided) {
+            (Some(expected), Some(provided)) if expected.eq(provided) => {
+                let fut = self.service.call(request);
+                Box::pin(async move {
+                    let res = fut.await?;
+                    Ok(res.map_into_boxed_body())
+                })
+            }
+            _ => {
+                warn!(
+                    "[Web] {} {} (missing or invalid CSRF token)",
+                    request.method(),
+                    request.path()
+                );
+                let response = message_response(
+                    StatusCode::FORBIDDEN,
+                    &Message::new("csrf.invalid").arg(CSRF_HEADER),
+                );
+                Box::pin(async move { Ok(request.into_response(response)) })
+            }
+        }
+    }
+}
+
+fn default_content_security_policy() -> String {
+    // The templates use inline scripts and load htmx from unpkg
+    "default-src 'self'; script-src 'self' 'unsafe-inline' https://unpkg.com; style-src 'self' 'unsafe-inline'; img-src 'self' data:; frame-ancestors 'none'".to_owned()
+}
+
+fn default_referrer_policy() -> String {
+    "same-origin".to_owned()
+}
+
+const fn default_hsts_max_age() -> u64 {
+    // one year
+    31_536_000
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SecurityHeadersConfig {
+    /// Content-Security-Policy header, empty to not send it
+    #[serde(default = "default_content_security_policy")]
+    content_security_policy: String,
+    /// Referrer-Policy header, empty to not send it
+    #[serde(default = "default_referrer_policy")]
+    referrer_policy: String,
+    /// max-age of the Strict-Transport-Security header sent on HTTPS requests, 0 to not send it
+    #[serde(default = "default_hsts_max_age")]
+    hsts_max_age: u64,
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        Self {
+            content_security_policy: default_content_security_policy(),
+            referrer_policy: default_referrer_policy(),
+            hsts_max_age: default_hsts_max_age(),
+        }
+    }
+}
+
+/// Adds security related headers to every response, unless a handler already set them
+#[derive(Clone)]
+pub struct SecurityHeaders {
+    headers: Arc<Vec<(HeaderName, HeaderValue)>>,
+    /// Only sent when the request came in over HTTPS, possibly through a proxy
+    hsts: Option<HeaderValue>,
+}
+
+impl SecurityHeaders {
+    /// Checks the configured headers, exiting on invalid values
+    pub fn new(config: &SecurityHeadersConfig) -> Self {
+        let value = |name: &str, value: &str| {
+            HeaderValue::from_str(value).unwrap_or_else(|e| {
+                eprintln!("Invalid value for the {name} header: {e}");
+                std::process::exit(3);
+            })
+        };
+
+        let mut headers = vec![(
+            header::X_CONTENT_TYPE_OPTIONS,
+            HeaderValue::from_static("nosniff"),
+        )];
+        if !config.content_security_policy.is_empty() {
+            headers.push((
+                header::CONTENT_SECURITY_POLICY,
+                value("Content-Security-Policy", &config.content_security_policy),
+            ));
+        }
+        if !config.referrer_policy.is_empty() {
+            headers.push((
+                header::REFERRER_POLICY,
+                value("Referrer-Policy", &config.referrer_policy),
+            ));
+        }
+
+        Self {
+            headers: Arc::new(headers),
+            hsts: (config.hsts_max_age > 0).then(|| {
+                value(
+                    "Strict-Transport-Security",
+                    &format!("max-age={}", config.hsts_max_age),
+                )
+            }),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for SecurityHeaders
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = SecurityHeadersService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(SecurityHeadersService {
+            service: Rc::new(service),
+            config: self.clone(),
+        }))
+    }
+}
+
+pub struct SecurityHeadersService<S> {
+    service: Rc<S>,
+    config: SecurityHeaders,
+}
+
+impl<S, B> Service<ServiceRequest> for SecurityHeadersService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
 
-            warn!(
-                "[Web] {} {} (authenticated user: {})",
-                method,
-                path,
-                id.id().unwrap_or_else(|_| "unknown".to_owned())
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let https = request.connection_info().scheme().eq("https");
+        let config = self.config.clone();
+        let fut = self.service.call(request);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+            let headers = res.headers_mut();
+            for (name, value) in config.headers.iter() {
+                if !headers.contains_key(name) {
+                    headers.insert(name.clone(), value.clone());
+                }
+            }
+            if let Some(hsts) = config.hsts.filter(|_| https) {
+                if !headers.contains_key(header::STRICT_TRANSPORT_SECURITY) {
+                    headers.insert(header::STRICT_TRANSPORT_SECURITY, hsts);
+                }
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AllowlistScope {
+    /// Every request, the login page included
+    #[default]
+    All,
+    /// Only requests that change something, anything but GET, HEAD and OPTIONS. Logging in and out
+    /// is possible from everywhere
+    Changes,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct AdminAllowlistConfig {
+    /// Networks the Web UI and the API can be used from, like `10.0.0.0/8` or `2001:db8::/32`.
+    /// Empty allows every address (default)
+    #[serde(default)]
+    networks: Vec<String>,
+    /// Which requests are limited to the networks (default all)
+    #[serde(default)]
+    scope: AllowlistScope,
+    /// Reverse proxies whose X-Forwarded-For header tells the address of the client (default none)
+    #[serde(default)]
+    trusted_proxies: Vec<String>,
+}
+
+/// Rejects requests from addresses outside the configured networks, so leaked credentials are no
+/// use outside the ops network
+#[derive(Clone)]
+pub struct AdminAllowlist {
+    networks: Arc<Vec<IpNet>>,
+    trusted_proxies: Arc<Vec<IpNet>>,
+    scope: AllowlistScope,
+}
+
+impl AdminAllowlist {
+    /// Checks the configured networks, exiting on invalid ones
+    pub fn new(config: &AdminAllowlistConfig) -> Self {
+        let parse = |setting: &str, networks: &[String]| -> Arc<Vec<IpNet>> {
+            networks
+                .iter()
+                .map(|network| {
+                    // Single addresses are networks of their own
+                    network
+                        .parse::<IpNet>()
+                        .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+                        .unwrap_or_else(|e| {
+                            eprintln!(
+                                "Invalid network {network} in admin_allowlist.{setting}: {e}"
+                            );
+                            std::process::exit(3);
+                        })
+                })
+                .collect::<Vec<_>>()
+                .into()
+        };
+
+        Self {
+            networks: parse("networks", &config.networks),
+            trusted_proxies: parse("trusted_proxies", &config.trusted_proxies),
+            scope: config.scope,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.networks.is_empty()
+    }
+
+    /// The address of the client: the peer, or behind a trusted proxy the last address of
+    /// X-Forwarded-For that isn't another trusted proxy
+    fn client_address(&self, request: &ServiceRequest) -> Option<IpAddr> {
+        let peer = request.peer_addr()?.ip();
+        let trusted = |address: &IpAddr| {
+            self.trusted_proxies
+                .iter()
+                .any(|proxy| proxy.contains(address))
+        };
+        if !trusted(&peer) {
+            return Some(peer);
+        }
+        let forwarded: Vec<IpAddr> = request
+            .headers()
+            .get_all("X-Forwarded-For")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|address| address.trim().parse().ok())
+            .collect();
+        Some(
+            forwarded
+                .into_iter()
+                .rev()
+                .find(|address| !trusted(address))
+                .unwrap_or(peer),
+        )
+    }
+
+    fn allows(&self, request: &ServiceRequest) -> Result<(), Option<IpAddr>> {
+        if self.scope == AllowlistScope::Changes
+            && (request.method().is_safe() || request.path().starts_with("/auth/"))
+        {
+            return Ok(());
+        }
+        let address = self.client_address(request);
+        match address {
+            Some(ip) if self.networks.iter().any(|network| network.contains(&ip)) => Ok(()),
+            _ => Err(address),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AdminAllowlist
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AdminAllowlistService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AdminAllowlistService {
+            service: Rc::new(service),
+            allowlist: self.clone(),
+        }))
+    }
+}
+
+pub struct AdminAllowlistService<S> {
+    service: Rc<S>,
+    allowlist: AdminAllowlist,
+}
+
+impl<S, B> Service<ServiceRequest> for AdminAllowlistService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        match self.allowlist.allows(&request) {
+            Ok(()) => {
+                let fut = self.service.call(request);
+                Box::pin(async move {
+                    let res = fut.await?;
+                    Ok(res.map_into_boxed_body())
+                })
+            }
+            Err(address) => {
+                let address = address.map_or_else(|| "unknown".to_owned(), |a| a.to_string());
+                warn!(
+                    "[Web] {} {} (address {address} not allowed)",
+                    request.method(),
+                    request.path()
+                );
+                let error = Message::new("access.network_denied").arg(&address);
+                let response = if request.path().starts_with("/api/") {
+                    message_response(StatusCode::FORBIDDEN, &error)
+                } else {
+                    FormResponseBuilder::forbidden(error).into_response()
+                };
+                Box::pin(async move { Ok(request.into_response(response)) })
+            }
+        }
+    }
+}
+
+/// Adds an `X-SSM-Timing` header with the time a request took, split into database, SSH and the rest,
+/// to every response for diagnosing slow requests
+pub struct RequestTiming;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestTiming
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestTimingService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestTimingService {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequestTimingService<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestTimingService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let timings = Arc::new(Timings::default());
+        let fut = self.service.call(request);
+
+        Box::pin(async move {
+            let mut res = timing::scope(Some(Arc::clone(&timings)), fut).await?;
+            if let Ok(value) = HeaderValue::from_str(&timings.header(started.elapsed())) {
+                res.headers_mut()
+                    .insert(HeaderName::from_static("x-ssm-timing"), value);
+            }
+            Ok(res)
+        })
+    }
+}
+
+/// Shows messages in the language of the Accept-Language header and names it in Content-Language
+pub struct Localization;
+
+impl<S, B> Transform<S, ServiceRequest> for Localization
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = LocalizationService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(LocalizationService {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct LocalizationService<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for LocalizationService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let lang = request
+            .headers()
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok())
+            .map(Lang::from_accept_language)
+            .unwrap_or_default();
+        // Inner middleware can answer right away, before the future is polled
+        let fut = i18n::sync_scope(lang, || self.service.call(request));
+
+        Box::pin(async move {
+            let mut res = i18n::scope(lang, fut).await?;
+            res.headers_mut().insert(
+                header::CONTENT_LANGUAGE,
+                HeaderValue::from_static(lang.tag()),
             );
-            let req = ServiceRequest::from_parts(http_req, payload);
-            let res = service.call(req).await?;
-            Ok(res.map_into_boxed_body())
+            Ok(res)
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn allowlist(
+        networks: &[&str],
+        trusted_proxies: &[&str],
+        scope: AllowlistScope,
+    ) -> AdminAllowlist {
+        AdminAllowlist::new(&AdminAllowlistConfig {
+            networks: networks
+                .iter()
+                .map(|network| (*network).to_owned())
+                .collect(),
+            scope,
+            trusted_proxies: trusted_proxies
+                .iter()
+                .map(|proxy| (*proxy).to_owned())
+                .collect(),
+        })
+    }
+
+    fn request(peer: &str, forwarded_for: &[&str]) -> ServiceRequest {
+        forwarded_for
+            .iter()
+            .fold(
+                TestRequest::get()
+                    .uri("/hosts")
+                    .peer_addr(peer.parse().unwrap()),
+                |request, value| request.append_header(("X-Forwarded-For", *value)),
+            )
+            .to_srv_request()
+    }
+
+    #[test]
+    fn forwarded_for_needs_a_trusted_proxy() {
+        let allowlist = allowlist(&["10.0.0.0/8"], &["127.0.0.1"], AllowlistScope::All);
+
+        assert_eq!(allowlist.allows(&request("10.1.2.3:4000", &[])), Ok(()));
+        let spoofed = request("203.0.113.5:4000", &["10.1.2.3"]);
+        assert_eq!(
+            allowlist.allows(&spoofed),
+            Err(Some("203.0.113.5".parse().unwrap()))
+        );
+        assert_eq!(
+            allowlist.allows(&request("127.0.0.1:4000", &["10.1.2.3"])),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn forwarded_for_counts_from_the_last_untrusted_address() {
+        let allowlist = allowlist(
+            &["10.0.0.0/8", "2001:db8::/32"],
+            &["127.0.0.1", "192.168.0.0/16"],
+            AllowlistScope::All,
+        );
+
+        // Addresses in front of the one the trusted proxies saw can be made up by the client
+        let prepended = request("127.0.0.1:4000", &["10.1.2.3, 203.0.113.9"]);
+        assert_eq!(
+            allowlist.allows(&prepended),
+            Err(Some("203.0.113.9".parse().unwrap()))
+        );
+        let chained = request("127.0.0.1:4000", &["203.0.113.9, 10.1.2.3", "192.168.1.2"]);
+        assert_eq!(allowlist.allows(&chained), Ok(()));
+        let ipv6 = request("127.0.0.1:4000", &["not an address, 2001:db8::1"]);
+        assert_eq!(allowlist.allows(&ipv6), Ok(()));
+        // Without a usable address the proxy itself is the client
+        let unusable = request("192.168.1.2:4000", &["unknown"]);
+        assert_eq!(
+            allowlist.allows(&unusable),
+            Err(Some("192.168.1.2".parse().unwrap()))
+        );
+    }
+
+    #[test]
+    fn changes_scope_only_limits_changes() {
+        let allowlist = allowlist(&["10.1.2.3"], &[], AllowlistScope::Changes);
+        let outside = || TestRequest::default().peer_addr("203.0.113.5:4000".parse().unwrap());
+
+        assert_eq!(
+            allowlist.allows(&outside().uri("/hosts").to_srv_request()),
+            Ok(())
+        );
+        let login = outside()
+            .method(Method::POST)
+            .uri("/auth/login")
+            .to_srv_request();
+        assert_eq!(allowlist.allows(&login), Ok(()));
+        let change = outside()
+            .method(Method::POST)
+            .uri("/api/key")
+            .to_srv_request();
+        assert!(allowlist.allows(&change).is_err());
+        let inside = TestRequest::post()
+            .uri("/api/key")
+            .peer_addr("10.1.2.3:4000".parse().unwrap())
+            .to_srv_request();
+        assert_eq!(allowlist.allows(&inside), Ok(()));
+    }
+}
@@ -1,6 +1,5 @@
 use diesel::prelude::*;
-use serde::Deserialize;
-use crate::DbConnection;
+use serde::{Deserialize, Serialize};
 
 #[derive(Queryable, Selectable, Associations, Clone, Debug)]
 #[diesel(table_name = crate::schema::host)]
@@ -11,49 +10,27 @@ pub struct Host {
     pub name: String,
     pub username: String,
     pub address: String,
-    pub port: i32,
+    #[diesel(deserialize_as = i32)]
+    pub port: u16,
     pub key_fingerprint: Option<String>,
     pub jump_via: Option<i32>,
-}
-
-impl Host {
-    /// Updates the host's name, address, username, port, key_fingerprint, and jump_via. This is a stub implementation; in a real application, you should perform a database update.
-    pub fn update_host(
-        conn: &mut crate::DbConnection,
-        old_name: String,
-        new_name: String,
-        new_address: String,
-        new_username: String,
-        new_port: i32,
-        new_key_fingerprint: Option<String>,
-        new_jump_via: Option<i32>
-    ) -> Result<(), actix_web::Error> {
-        use crate::schema::host::dsl::*;
-        log::warn!(
-            "ssm::models::Host: Host update details for '{}':\n  Name -> {}\n  Address -> {}\n  Username -> {}\n  Port -> {}\n  Key Fingerprint -> {:?}\n  Jump Via -> {:?}",
-            old_name,
-            new_name,
-            new_address,
-            new_username,
-            new_port,
-            new_key_fingerprint,
-            new_jump_via
-        );
-
-        diesel::update(host.filter(name.eq(&old_name)))
-            .set((
-                name.eq(new_name),
-                address.eq(new_address),
-                username.eq(new_username),
-                port.eq(new_port),
-                key_fingerprint.eq(new_key_fingerprint),
-                jump_via.eq(new_jump_via),
-            ))
-            .execute(conn)
-            .map_err(actix_web::error::ErrorInternalServerError)?;
-
-        Ok(())
-    }
+    pub transport: String,
+    pub tags: String,
+    pub pre_deploy_hook: Option<String>,
+    pub post_deploy_hook: Option<String>,
+    pub address_family: Option<String>,
+    pub proxy: Option<String>,
+    pub fallback_addresses: String,
+    pub aliases: String,
+    pub additional_key_fingerprints: String,
+    pub key_mismatch: Option<String>,
+    pub protected: bool,
+    pub tolerated_keys: String,
+    pub principals_file: Option<String>,
+    pub key_algorithms: String,
+    pub connection_plugin: Option<String>,
+    pub script_path: Option<String>,
+    pub script_interpreter: Option<String>,
 }
 
 #[derive(Insertable, Clone)]
@@ -62,10 +39,62 @@ impl Host {
 pub struct NewHost {
     pub name: String,
     pub address: String,
-    pub port: i32,
+    #[diesel(serialize_as = i32)]
+    pub port: u16,
     pub username: String,
     pub key_fingerprint: String,
     pub jump_via: Option<i32>,
+    pub transport: String,
+    pub tags: String,
+    pub proxy: Option<String>,
+}
+
+/// The settings of a host that are edited together, `None` clears a field
+#[derive(AsChangeset, Clone, Debug)]
+#[diesel(table_name = crate::schema::host, treat_none_as_null = true)]
+pub struct HostChanges {
+    pub name: String,
+    pub address: String,
+    pub username: String,
+    #[diesel(serialize_as = i32)]
+    pub port: u16,
+    pub key_fingerprint: Option<String>,
+    pub jump_via: Option<i32>,
+    pub transport: String,
+    pub tags: String,
+    pub pre_deploy_hook: Option<String>,
+    pub post_deploy_hook: Option<String>,
+    pub address_family: Option<String>,
+    pub proxy: Option<String>,
+    pub fallback_addresses: String,
+    pub aliases: String,
+    pub protected: bool,
+    pub tolerated_keys: String,
+    pub principals_file: Option<String>,
+    pub key_algorithms: String,
+    pub connection_plugin: Option<String>,
+    pub script_path: Option<String>,
+    pub script_interpreter: Option<String>,
+}
+
+/// A discovered host waiting for its host key to be confirmed
+#[derive(Queryable, Selectable, Insertable, Clone, Debug, Serialize)]
+#[diesel(table_name = crate::schema::pending_host)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct PendingHost {
+    pub token: String,
+    pub name: String,
+    pub username: String,
+    pub address: String,
+    #[diesel(deserialize_as = i32, serialize_as = i32)]
+    pub port: u16,
+    pub key_fingerprint: String,
+    pub jump_via: Option<i32>,
+    pub transport: String,
+    pub tags: String,
+    pub proxy: Option<String>,
+    #[serde(with = "crate::db::utc_rfc3339")]
+    pub created_at: time::PrimitiveDateTime,
 }
 
 #[derive(Queryable, Selectable, Associations, Clone, Debug)]
@@ -92,6 +121,10 @@ pub struct NewPublicUserKey {
 }
 
 impl NewPublicUserKey {
+    pub fn key_base64(&self) -> &str {
+        &self.key_base64
+    }
+
     pub fn new(
         algorithm: ssh_key::Algorithm,
         base64: String,
@@ -107,13 +140,27 @@ impl NewPublicUserKey {
     }
 }
 
-#[derive(Queryable, Selectable, Clone)]
+#[derive(Queryable, Selectable, Clone, Serialize)]
 #[diesel(table_name = crate::schema::user)]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
 pub struct User {
     pub id: i32,
     pub username: String,
     pub enabled: bool,
+    pub email: Option<String>,
+    pub full_name: Option<String>,
+    pub department: Option<String>,
+    pub external_id: Option<String>,
+}
+
+/// Who a user is, empty fields are cleared
+#[derive(AsChangeset, Deserialize, Clone, Debug, Default)]
+#[diesel(table_name = crate::schema::user, treat_none_as_null = true)]
+pub struct UserDetails {
+    pub email: Option<String>,
+    pub full_name: Option<String>,
+    pub department: Option<String>,
+    pub external_id: Option<String>,
 }
 
 #[derive(Insertable, Deserialize, Clone)]
@@ -130,6 +177,12 @@ impl PublicUserKey {
         }
     }
 
+    /// Whether the key lives on a FIDO2 security key
+    pub fn is_security_key(&self) -> bool {
+        ssh_key::Algorithm::new(&self.key_type)
+            .is_ok_and(|algorithm| crate::ssh::is_security_key(&algorithm))
+    }
+
     pub fn key_preview(&self) -> String {
         let preview: String = self
             .key_base64
@@ -150,3 +203,328 @@ impl TryFrom<&PublicUserKey> for ssh_key::public::PublicKey {
         Self::from_openssh(&value.to_openssh()).map_err(|e| e.to_string())
     }
 }
+
+#[derive(Queryable, Selectable, Clone, Debug, Serialize)]
+#[diesel(table_name = crate::schema::schedule)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct Schedule {
+    pub id: i32,
+    pub name: String,
+    pub cron: String,
+    pub job: String,
+    pub tag: Option<String>,
+    /// How a deploy job rolls out, see [`crate::scheduler::DeployStrategy`]
+    pub strategy: Option<String>,
+}
+
+#[derive(Insertable, AsChangeset, Clone, Deserialize)]
+#[diesel(table_name = crate::schema::schedule)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[diesel(treat_none_as_null = true)]
+pub struct NewSchedule {
+    pub name: String,
+    pub cron: String,
+    pub job: String,
+    pub tag: Option<String>,
+    /// How a deploy job rolls out, see [`crate::scheduler::DeployStrategy`]
+    pub strategy: Option<String>,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug, Serialize)]
+#[diesel(table_name = crate::schema::authorization_history)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct AuthorizationHistory {
+    pub id: i32,
+    pub authorization_id: i32,
+    pub action: String,
+    pub host_id: i32,
+    pub host_name: String,
+    pub user_id: i32,
+    pub username: String,
+    pub login: String,
+    pub options: Option<String>,
+    pub actor: String,
+    #[serde(with = "crate::db::utc_rfc3339")]
+    pub changed_at: time::PrimitiveDateTime,
+    pub old_login: Option<String>,
+    pub old_options: Option<String>,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = crate::schema::authorization_history)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewAuthorizationHistory {
+    pub authorization_id: i32,
+    pub action: String,
+    pub host_id: i32,
+    pub host_name: String,
+    pub user_id: i32,
+    pub username: String,
+    pub login: String,
+    pub options: Option<String>,
+    pub actor: String,
+    pub changed_at: time::PrimitiveDateTime,
+    pub old_login: Option<String>,
+    pub old_options: Option<String>,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug, Serialize)]
+#[diesel(table_name = crate::schema::key_history)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct KeyHistory {
+    pub id: i32,
+    pub key_id: Option<i32>,
+    pub key_base64: String,
+    pub action: String,
+    pub username: Option<String>,
+    pub host_name: Option<String>,
+    pub login: Option<String>,
+    pub actor: String,
+    #[serde(with = "crate::db::utc_rfc3339")]
+    pub changed_at: time::PrimitiveDateTime,
+    pub previous_username: Option<String>,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = crate::schema::key_history)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewKeyHistory {
+    pub key_id: Option<i32>,
+    pub key_base64: String,
+    pub action: String,
+    pub username: Option<String>,
+    pub host_name: Option<String>,
+    pub login: Option<String>,
+    pub actor: String,
+    pub changed_at: time::PrimitiveDateTime,
+    pub previous_username: Option<String>,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug, Serialize)]
+#[diesel(table_name = crate::schema::security_event)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct SecurityEvent {
+    pub id: i32,
+    pub kind: String,
+    pub severity: String,
+    pub host_name: Option<String>,
+    pub message: String,
+    #[serde(with = "crate::db::utc_rfc3339")]
+    pub created_at: time::PrimitiveDateTime,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = crate::schema::security_event)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewSecurityEvent {
+    pub kind: String,
+    pub severity: String,
+    pub host_name: Option<String>,
+    pub message: String,
+    pub created_at: time::PrimitiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = crate::schema::event_outbox)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct OutboxEvent {
+    pub id: i32,
+    pub event: String,
+    pub payload: String,
+    pub created_at: time::PrimitiveDateTime,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = crate::schema::event_outbox)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewOutboxEvent {
+    pub event: String,
+    pub payload: String,
+    pub created_at: time::PrimitiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug, Serialize)]
+#[diesel(table_name = crate::schema::activity)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct Activity {
+    pub id: i32,
+    pub actor: String,
+    pub action: String,
+    pub method: String,
+    pub path: String,
+    pub status: i32,
+    #[serde(with = "crate::db::utc_rfc3339")]
+    pub created_at: time::PrimitiveDateTime,
+    pub category: String,
+    pub severity: String,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = crate::schema::activity)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewActivity {
+    pub actor: String,
+    pub action: String,
+    pub method: String,
+    pub path: String,
+    pub status: i32,
+    pub created_at: time::PrimitiveDateTime,
+    pub category: String,
+    pub severity: String,
+}
+
+#[derive(Queryable, Selectable, Insertable, Clone, Debug)]
+#[diesel(table_name = crate::schema::activity_category)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct ActivityCategorySetting {
+    pub category: String,
+    pub enabled: bool,
+    pub severity: String,
+}
+
+#[derive(Queryable, Selectable, Insertable, Clone, Debug)]
+#[diesel(table_name = crate::schema::host_confirmation)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct HostConfirmation {
+    pub token: String,
+    pub host_id: i32,
+    pub action: String,
+    pub login: Option<String>,
+    pub authorized_keys: Option<String>,
+    pub created_at: time::PrimitiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug, Serialize)]
+#[diesel(table_name = crate::schema::freeze_window)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct FreezeWindow {
+    pub id: i32,
+    #[serde(with = "crate::db::utc_rfc3339")]
+    pub starts_at: time::PrimitiveDateTime,
+    #[serde(with = "crate::db::utc_rfc3339")]
+    pub ends_at: time::PrimitiveDateTime,
+    pub reason: String,
+    pub created_by: String,
+    #[serde(with = "crate::db::utc_rfc3339")]
+    pub created_at: time::PrimitiveDateTime,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = crate::schema::freeze_window)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewFreezeWindow {
+    pub starts_at: time::PrimitiveDateTime,
+    pub ends_at: time::PrimitiveDateTime,
+    pub reason: String,
+    pub created_by: String,
+    pub created_at: time::PrimitiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug, Serialize)]
+#[diesel(table_name = crate::schema::authorization_rule)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct AuthorizationRule {
+    pub id: i32,
+    pub department: String,
+    pub tag: String,
+    pub login: String,
+    pub options: Option<String>,
+    pub created_by: String,
+    #[serde(with = "crate::db::utc_rfc3339")]
+    pub created_at: time::PrimitiveDateTime,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = crate::schema::authorization_rule)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewAuthorizationRule {
+    pub department: String,
+    pub tag: String,
+    pub login: String,
+    pub options: Option<String>,
+    pub created_by: String,
+    pub created_at: time::PrimitiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug, Serialize)]
+#[diesel(table_name = crate::schema::diff_ignore_rule)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct DiffIgnoreRule {
+    pub id: i32,
+    pub host_id: Option<i32>,
+    pub login: Option<String>,
+    pub pattern: String,
+    pub created_by: String,
+    #[serde(with = "crate::db::utc_rfc3339")]
+    pub created_at: time::PrimitiveDateTime,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = crate::schema::diff_ignore_rule)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewDiffIgnoreRule {
+    pub host_id: Option<i32>,
+    pub login: Option<String>,
+    pub pattern: String,
+    pub created_by: String,
+    pub created_at: time::PrimitiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug, Serialize)]
+#[diesel(table_name = crate::schema::recertification_campaign)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct RecertificationCampaign {
+    pub id: i32,
+    pub name: String,
+    pub tag: Option<String>,
+    #[serde(with = "crate::db::utc_rfc3339")]
+    pub deadline: time::PrimitiveDateTime,
+    pub auto_revoke: bool,
+    pub created_by: String,
+    #[serde(with = "crate::db::utc_rfc3339")]
+    pub created_at: time::PrimitiveDateTime,
+    #[serde(with = "crate::db::utc_rfc3339::option")]
+    pub closed_at: Option<time::PrimitiveDateTime>,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = crate::schema::recertification_campaign)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewRecertificationCampaign {
+    pub name: String,
+    pub tag: Option<String>,
+    pub deadline: time::PrimitiveDateTime,
+    pub auto_revoke: bool,
+    pub created_by: String,
+    pub created_at: time::PrimitiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug, Serialize)]
+#[diesel(table_name = crate::schema::recertification_item)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct RecertificationItem {
+    pub id: i32,
+    pub campaign_id: i32,
+    pub authorization_id: i32,
+    pub host: String,
+    pub username: String,
+    pub login: String,
+    pub options: Option<String>,
+    pub decision: Option<String>,
+    pub reviewer: Option<String>,
+    #[serde(with = "crate::db::utc_rfc3339::option")]
+    pub reviewed_at: Option<time::PrimitiveDateTime>,
+    pub comment: Option<String>,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = crate::schema::recertification_item)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewRecertificationItem {
+    pub campaign_id: i32,
+    pub authorization_id: i32,
+    pub host: String,
+    pub username: String,
+    pub login: String,
+    pub options: Option<String>,
+}
@@ -0,0 +1,115 @@
+//! Policies for fixing the drift the check job finds, instead of only reporting it
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::{models::Host, ssh::DiffItem};
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    /// Drift is only reported by the drift.detected hooks (default)
+    #[default]
+    Off,
+    /// Additionally logs what full_sync would deploy
+    ReportOnly,
+    /// Removes keys that aren't authorized, without adding missing ones or taking over unmanaged files
+    FixUnknownKeys,
+    /// Deploys the authorized_keys file generated from the database
+    FullSync,
+}
+
+impl Policy {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::ReportOnly => "report_only",
+            Self::FixUnknownKeys => "fix_unknown_keys",
+            Self::FullSync => "full_sync",
+        }
+    }
+
+    /// The keyfile this policy deploys to fix the drift of a login, `None` if it leaves the login alone
+    pub fn keyfile(self, generated: &str, diff: &[DiffItem]) -> Option<String> {
+        match self {
+            Self::Off | Self::ReportOnly => None,
+            Self::FullSync => Some(generated.to_owned()),
+            Self::FixUnknownKeys => {
+                let mut missing = Vec::new();
+                let mut unknown = false;
+                for item in diff {
+                    match item {
+                        DiffItem::PragmaMissing => return None,
+                        DiffItem::KeyMissing(key, _) => missing.push(key.base64.as_str()),
+                        DiffItem::UnknownKey(_)
+                        | DiffItem::UnauthorizedKey(_, _)
+                        | DiffItem::DuplicateKey(_)
+                        | DiffItem::FaultyKey(_, _) => unknown = true,
+                        // Principals are only deployed with a full sync
+                        DiffItem::PrincipalMissing(_, _) | DiffItem::UnknownPrincipal(_) => {}
+                        // Left out of the generated file anyway
+                        DiffItem::IncompatibleKey(_, _) => {}
+                        // Deploying checks the limits itself
+                        DiffItem::LimitReached(_) => {}
+                    }
+                }
+                unknown.then(|| {
+                    generated
+                        .lines()
+                        .filter(|line| !line.split_whitespace().any(|part| missing.contains(&part)))
+                        .map(|line| format!("{line}\n"))
+                        .collect()
+                })
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RemediationRule {
+    /// Name of a single host, takes precedence over tags
+    host: Option<String>,
+    /// Hosts with this tag
+    tag: Option<String>,
+    policy: Policy,
+}
+
+#[derive(Debug, Default)]
+pub struct RemediationPolicies {
+    hosts: HashMap<String, Policy>,
+    /// In configuration order, the first matching tag wins
+    tags: Vec<(String, Policy)>,
+}
+
+impl RemediationPolicies {
+    /// Checks the rules, exiting if one is invalid
+    pub fn new(rules: &[RemediationRule]) -> Self {
+        let mut policies = Self::default();
+        for rule in rules {
+            match (&rule.host, &rule.tag) {
+                (Some(host), None) => {
+                    policies.hosts.insert(host.clone(), rule.policy);
+                }
+                (None, Some(tag)) => policies.tags.push((tag.clone(), rule.policy)),
+                _ => {
+                    eprintln!(
+                        "The {} remediation rule needs either a host or a tag",
+                        rule.policy.as_str()
+                    );
+                    std::process::exit(3);
+                }
+            }
+        }
+        policies
+    }
+
+    pub fn policy_for(&self, host: &Host) -> Policy {
+        self.hosts.get(&host.name).copied().unwrap_or_else(|| {
+            self.tags
+                .iter()
+                .find(|(tag, _)| host.has_tag(tag))
+                .map(|(_, policy)| *policy)
+                .unwrap_or_default()
+        })
+    }
+}
@@ -0,0 +1,136 @@
+use actix_web::{
+    get,
+    http::StatusCode,
+    web::{self, Data, Query},
+    HttpResponse, Responder,
+};
+use serde::Deserialize;
+
+use crate::{
+    db::activity::ActivityFilter,
+    export::{csv_timestamp, CsvRecord},
+    models::Activity,
+    timing, ConnectionPool,
+};
+
+use super::{
+    audit::parse_timestamp,
+    error_response,
+    stream::{stream_rows, ListFormat},
+};
+
+pub fn activity_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list);
+}
+
+/// Rows returned as JSON unless a limit is given
+const DEFAULT_LIMIT: i64 = 100;
+/// Rows read at a time for NDJSON and CSV exports
+const EXPORT_PAGE: i64 = 1000;
+
+#[derive(Deserialize)]
+struct ActivityQuery {
+    actor: Option<String>,
+    action: Option<String>,
+    category: Option<String>,
+    /// Minimum severity
+    severity: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+    /// Defaults to 100 for JSON, NDJSON and CSV export everything
+    limit: Option<i64>,
+    #[serde(default)]
+    format: ListFormat,
+}
+
+impl CsvRecord for Activity {
+    const HEADER: &'static [&'static str] = &[
+        "id",
+        "created_at",
+        "actor",
+        "action",
+        "method",
+        "path",
+        "status",
+        "category",
+        "severity",
+    ];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.id.to_string(),
+            csv_timestamp(self.created_at),
+            self.actor.clone(),
+            self.action.clone(),
+            self.method.clone(),
+            self.path.clone(),
+            self.status.to_string(),
+            self.category.clone(),
+            self.severity.clone(),
+        ]
+    }
+}
+
+/// Changes made through the Web UI, API and gRPC with who made them, and reads if their category
+/// is turned on, newest first. As NDJSON or CSV the rows are streamed while they are read.
+#[get("")]
+async fn list(
+    conn: Data<ConnectionPool>,
+    params: Query<ActivityQuery>,
+) -> actix_web::Result<impl Responder> {
+    let params = params.into_inner();
+    let parse = |input: Option<String>| input.as_deref().map(parse_timestamp).transpose();
+    let (since, until) = match (parse(params.since), parse(params.until)) {
+        (Ok(since), Ok(until)) => (since, until),
+        (Err(error), _) | (_, Err(error)) => {
+            return Ok(error_response(StatusCode::BAD_REQUEST, error))
+        }
+    };
+    let severity = match params.severity.as_deref().map(str::parse).transpose() {
+        Ok(severity) => severity,
+        Err(error) => return Ok(error_response(StatusCode::BAD_REQUEST, error)),
+    };
+    let filter = ActivityFilter {
+        actor: params.actor,
+        action: params.action,
+        category: params.category,
+        severity,
+        since,
+        until,
+    };
+
+    if params.format != ListFormat::Json {
+        let limit = params.limit;
+        return Ok(stream_rows(params.format, "activity", move |emit| {
+            let mut conn = conn.get().unwrap();
+            let mut remaining = limit.unwrap_or(i64::MAX);
+            let mut before = None;
+            while remaining > 0 {
+                let page = Activity::search_before(
+                    &mut conn,
+                    &filter,
+                    before,
+                    remaining.min(EXPORT_PAGE),
+                )?;
+                let Some(last) = page.last() else { break };
+                before = Some((last.created_at, last.id));
+                remaining -= page.len() as i64;
+                for activity in page {
+                    if !emit(activity) {
+                        return Ok(());
+                    }
+                }
+            }
+            Ok(())
+        }));
+    }
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+
+    let res =
+        timing::block(move || Activity::search(&mut conn.get().unwrap(), &filter, limit)).await?;
+
+    Ok(match res {
+        Ok(activity) => HttpResponse::Ok().json(activity),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
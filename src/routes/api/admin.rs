@@ -0,0 +1,61 @@
+use actix_web::{
+    get,
+    http::StatusCode,
+    web::{self, Data},
+    HttpResponse, Responder,
+};
+use time::{macros::format_description, OffsetDateTime};
+
+use crate::{
+    db::backup::database_files, dr_bundle::DrBundle, i18n::Message, models::Host, ssh::SshClient,
+    timing, ConnectionPool,
+};
+
+use super::{error_response, message_response};
+
+pub fn admin_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(dr_bundle);
+}
+
+/// Everything needed to rebuild a lost SSM server, as a tar archive encrypted with age
+#[get("/dr_bundle")]
+async fn dr_bundle(
+    conn: Data<ConnectionPool>,
+    bundle: Data<DrBundle>,
+    ssh_client: Data<dyn SshClient>,
+) -> actix_web::Result<impl Responder> {
+    if !bundle.enabled() {
+        return Ok(message_response(
+            StatusCode::NOT_FOUND,
+            &Message::new("dr_bundle.disabled"),
+        ));
+    }
+    let public_key = ssh_client.get_own_key_openssh();
+
+    let res = timing::block(move || {
+        let mut conn = conn.get().unwrap();
+        let hosts = Host::get_all_hosts(&mut conn)?;
+        let mut files = database_files(&mut conn)?;
+        files.extend(bundle.files(&public_key, &hosts)?);
+        bundle.seal(&files)
+    })
+    .await?;
+
+    Ok(match res {
+        Ok(sealed) => {
+            let created = OffsetDateTime::now_utc()
+                .format(format_description!(
+                    "[year][month][day]T[hour][minute][second]Z"
+                ))
+                .unwrap_or_default();
+            HttpResponse::Ok()
+                .content_type("application/octet-stream")
+                .insert_header((
+                    "Content-Disposition",
+                    format!("attachment; filename=\"ssm-dr-{created}.tar.age\""),
+                ))
+                .body(sealed)
+        }
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
@@ -0,0 +1,258 @@
+use actix_web::{
+    get,
+    http::StatusCode,
+    web::{self, Data, Query},
+    HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+use time::{
+    format_description::well_known::Rfc3339, macros::format_description, Date, OffsetDateTime,
+    PrimitiveDateTime, Time, UtcOffset,
+};
+
+use crate::{
+    export::CsvRecord,
+    models::{AuthorizationHistory, Host, SecurityEvent},
+    timing, ConnectionPool, DbConnection,
+};
+
+use super::{
+    error_response,
+    stream::{stream_rows, ListFormat},
+};
+
+pub fn audit_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(access).service(matrix).service(security_events);
+}
+
+/// Accepts RFC 3339 with any offset, also without seconds (`2024-02-01T00:00Z`,
+/// `2024-02-01T01:00+01:00`), and plain dates as midnight UTC. Returns the time in UTC like it is
+/// stored. Times without an offset are rejected, they could be meant in any time zone.
+pub(super) fn parse_timestamp(input: &str) -> Result<PrimitiveDateTime, String> {
+    let at = OffsetDateTime::parse(input, &Rfc3339)
+        .or_else(|_| {
+            OffsetDateTime::parse(
+                input,
+                format_description!(
+                    "[year]-[month]-[day]T[hour]:[minute][offset_hour sign:mandatory]:[offset_minute]"
+                ),
+            )
+        })
+        .or_else(|_| {
+            PrimitiveDateTime::parse(
+                input,
+                format_description!("[year]-[month]-[day]T[hour]:[minute]Z"),
+            )
+            .map(PrimitiveDateTime::assume_utc)
+        });
+    if let Ok(at) = at {
+        let at = at.to_offset(UtcOffset::UTC);
+        return Ok(PrimitiveDateTime::new(at.date(), at.time()));
+    }
+    Date::parse(input, format_description!("[year]-[month]-[day]"))
+        .map(|date| PrimitiveDateTime::new(date, Time::MIDNIGHT))
+        .map_err(|_| {
+            format!(
+                "Couldn't parse timestamp '{input}', expected RFC 3339 with an offset like \
+                 2024-02-01T00:00:00Z or a date like 2024-02-01"
+            )
+        })
+}
+
+#[derive(Deserialize)]
+struct AccessQuery {
+    host: String,
+    /// Defaults to now
+    at: Option<String>,
+}
+
+#[derive(Serialize)]
+struct AccessResponse {
+    host: String,
+    #[serde(with = "crate::db::utc_rfc3339")]
+    at: PrimitiveDateTime,
+    /// Changes before this are not recorded
+    #[serde(with = "crate::db::utc_rfc3339::option")]
+    history_since: Option<PrimitiveDateTime>,
+    /// The change that granted each authorization that existed at this time
+    access: Vec<AuthorizationHistory>,
+}
+
+/// Who was authorized on a host at a given time, reconstructed from the authorization history
+#[get("/access")]
+async fn access(
+    conn: Data<ConnectionPool>,
+    params: Query<AccessQuery>,
+) -> actix_web::Result<impl Responder> {
+    let params = params.into_inner();
+    let at = match params.at.as_deref().map(parse_timestamp) {
+        Some(Ok(at)) => at,
+        Some(Err(error)) => return Ok(error_response(StatusCode::BAD_REQUEST, error)),
+        None => {
+            let now = OffsetDateTime::now_utc();
+            PrimitiveDateTime::new(now.date(), now.time())
+        }
+    };
+
+    let host = params.host;
+    let res = timing::block(move || {
+        let mut conn = conn.get().unwrap();
+        let history_since = AuthorizationHistory::first_record(&mut conn)?;
+        AuthorizationHistory::access_at(&mut conn, &host, at).map(|access| AccessResponse {
+            host,
+            at,
+            history_since,
+            access,
+        })
+    })
+    .await?;
+
+    Ok(match res {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
+
+#[derive(Deserialize)]
+struct MatrixQuery {
+    host: Option<String>,
+    user: Option<String>,
+    #[serde(default)]
+    format: ListFormat,
+}
+
+#[derive(Serialize)]
+struct MatrixEntry {
+    host: String,
+    login: String,
+    username: String,
+    options: Option<String>,
+    /// `authorization` or `rule`
+    source: &'static str,
+    /// Id of the authorization or rule granting the access
+    source_id: i32,
+}
+
+impl CsvRecord for MatrixEntry {
+    const HEADER: &'static [&'static str] = &[
+        "host",
+        "login",
+        "username",
+        "options",
+        "source",
+        "source_id",
+    ];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.host.clone(),
+            self.login.clone(),
+            self.username.clone(),
+            self.options.clone().unwrap_or_default(),
+            self.source.to_owned(),
+            self.source_id.to_string(),
+        ]
+    }
+}
+
+/// Hosts of the matrix, sorted by name
+fn matrix_hosts(conn: &mut DbConnection, host: Option<&String>) -> Result<Vec<Host>, String> {
+    let mut hosts = Host::get_all_hosts(conn)?;
+    hosts.retain(|current| host.is_none_or(|host| host.eq(&current.name)));
+    hosts.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(hosts)
+}
+
+/// Who may log in on one host, sorted by login and username
+fn host_matrix(
+    conn: &mut DbConnection,
+    host: &Host,
+    user: Option<&String>,
+) -> Result<Vec<MatrixEntry>, String> {
+    let explicit = host
+        .get_authorized_users(conn)?
+        .into_iter()
+        .map(|grant| ("authorization", grant));
+    let ruled = host
+        .get_rule_grants(conn)?
+        .into_iter()
+        .map(|grant| ("rule", grant));
+    let mut entries: Vec<MatrixEntry> = explicit
+        .chain(ruled)
+        .filter(|(_, (_, username, _, _))| user.is_none_or(|u| u.eq(username)))
+        .map(
+            |(source, (source_id, username, login, options))| MatrixEntry {
+                host: host.name.clone(),
+                login,
+                username,
+                options,
+                source,
+                source_id,
+            },
+        )
+        .collect();
+    entries.sort_by(|a, b| (&a.login, &a.username).cmp(&(&b.login, &b.username)));
+    Ok(entries)
+}
+
+/// Who may log in where right now, with the authorization or rule granting it.
+/// As NDJSON or CSV the rows are streamed host by host.
+#[get("/matrix")]
+async fn matrix(
+    conn: Data<ConnectionPool>,
+    params: Query<MatrixQuery>,
+) -> actix_web::Result<impl Responder> {
+    let MatrixQuery { host, user, format } = params.into_inner();
+    if format != ListFormat::Json {
+        return Ok(stream_rows(format, "matrix", move |emit| {
+            let mut conn = conn.get().unwrap();
+            for current in matrix_hosts(&mut conn, host.as_ref())? {
+                for entry in host_matrix(&mut conn, &current, user.as_ref())? {
+                    if !emit(entry) {
+                        return Ok(());
+                    }
+                }
+            }
+            Ok(())
+        }));
+    }
+    let res = timing::block(move || {
+        let mut conn = conn.get().unwrap();
+        let mut entries = Vec::new();
+        for current in matrix_hosts(&mut conn, host.as_ref())? {
+            entries.extend(host_matrix(&mut conn, &current, user.as_ref())?);
+        }
+        Ok::<_, String>(entries)
+    })
+    .await?;
+
+    Ok(match res {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
+
+const fn default_limit() -> i64 {
+    100
+}
+
+#[derive(Deserialize)]
+struct SecurityEventsQuery {
+    #[serde(default = "default_limit")]
+    limit: i64,
+}
+
+/// Recent security events like host key mismatches, newest first
+#[get("/security_events")]
+async fn security_events(
+    conn: Data<ConnectionPool>,
+    params: Query<SecurityEventsQuery>,
+) -> actix_web::Result<impl Responder> {
+    let limit = params.limit;
+    let res = timing::block(move || SecurityEvent::recent(&mut conn.get().unwrap(), limit)).await?;
+
+    Ok(match res {
+        Ok(events) => HttpResponse::Ok().json(events),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
@@ -0,0 +1,105 @@
+use actix_identity::Identity;
+use actix_session::Session;
+use actix_web::{
+    get,
+    http::StatusCode,
+    post,
+    web::{self, Data, Json},
+    HttpResponse, Responder,
+};
+use log::info;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    htpasswd,
+    i18n::Message,
+    middleware::{CREDENTIAL_SESSION_KEY, CSRF_HEADER, CSRF_SESSION_KEY},
+    routes::actor,
+    Configuration,
+};
+
+use super::{error_response, message_response};
+
+/// New passwords need at least this many characters
+const MIN_PASSWORD_LENGTH: usize = 12;
+
+pub fn auth_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(csrf_token).service(change_password);
+}
+
+#[derive(Serialize)]
+struct CsrfResponse {
+    /// Token to send with every state changing request of this session
+    token: String,
+    /// Header the token is expected in
+    header: &'static str,
+}
+
+/// The CSRF token of the current session, created on first use
+#[get("/csrf")]
+async fn csrf_token(session: Session) -> impl Responder {
+    let token = match session.get::<String>(CSRF_SESSION_KEY) {
+        Ok(Some(token)) => token,
+        Ok(None) => {
+            let token = Uuid::new_v4().simple().to_string();
+            if let Err(e) = session.insert(CSRF_SESSION_KEY, &token) {
+                return error_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+            }
+            token
+        }
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+
+    HttpResponse::Ok().json(CsrfResponse {
+        token,
+        header: CSRF_HEADER,
+    })
+}
+
+#[derive(Deserialize)]
+struct ChangePassword {
+    current_password: String,
+    new_password: String,
+}
+
+/// Changes the password of the login in the htpasswd file. The other sessions of the login end,
+/// this one continues with the new password.
+#[post("/change_password")]
+async fn change_password(
+    config: Data<Configuration>,
+    identity: Identity,
+    session: Session,
+    passwords: Json<ChangePassword>,
+) -> actix_web::Result<impl Responder> {
+    let ChangePassword {
+        current_password,
+        new_password,
+    } = passwords.into_inner();
+    if new_password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Ok(message_response(
+            StatusCode::BAD_REQUEST,
+            &Message::new("auth.password_too_short").arg(MIN_PASSWORD_LENGTH),
+        ));
+    }
+
+    let actor = actor(&identity);
+    let path = config.htpasswd_path.clone();
+    let username = actor.clone();
+    let res = web::block(move || {
+        htpasswd::change_password(&path, &username, &current_password, &new_password)
+    })
+    .await?;
+
+    Ok(match res {
+        Ok(Some(hash)) => {
+            info!("{actor} changed their password");
+            match session.insert(CREDENTIAL_SESSION_KEY, htpasswd::fingerprint(&hash)) {
+                Ok(()) => HttpResponse::NoContent().finish(),
+                Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+            }
+        }
+        Ok(None) => message_response(StatusCode::FORBIDDEN, &Message::new("auth.wrong_password")),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
@@ -0,0 +1,128 @@
+use actix_identity::Identity;
+use actix_web::{
+    get,
+    http::StatusCode,
+    put,
+    web::{self, Data, Json, Path},
+    HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    access::AccessControl,
+    i18n::Message,
+    models::{AuthorizationHistory, Host},
+    routes::actor,
+    timing, ConnectionPool,
+};
+
+use super::{error_response, message_response};
+
+pub fn authorization_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(authorization_history)
+        .service(update_authorization)
+        .service(set_principals);
+}
+
+/// Every recorded change of an authorization, oldest first. Still available after it was deleted.
+#[get("/{id}/history")]
+async fn authorization_history(
+    conn: Data<ConnectionPool>,
+    id: Path<i32>,
+) -> actix_web::Result<impl Responder> {
+    let id = id.into_inner();
+    let res = timing::block(move || {
+        AuthorizationHistory::for_authorization(&mut conn.get().unwrap(), id)
+    })
+    .await?;
+
+    Ok(match res {
+        Ok(history) if history.is_empty() => message_response(
+            StatusCode::NOT_FOUND,
+            &Message::new("authorization.no_history"),
+        ),
+        Ok(history) => HttpResponse::Ok().json(history),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
+
+#[derive(Deserialize)]
+struct UpdateAuthorization {
+    login: String,
+    options: Option<String>,
+}
+
+#[put("/{id}")]
+async fn update_authorization(
+    conn: Data<ConnectionPool>,
+    access: Data<AccessControl>,
+    identity: Identity,
+    id: Path<i32>,
+    update: Json<UpdateAuthorization>,
+) -> actix_web::Result<impl Responder> {
+    let id = id.into_inner();
+    let update = update.into_inner();
+    let actor = actor(&identity);
+
+    let res = timing::block(move || {
+        let mut conn = conn.get().unwrap();
+        if let Some(host) = Host::get_from_authorization(&mut conn, id)? {
+            if !access.may_change_host(&actor, &host) {
+                return Ok(Err(AccessControl::denied(&actor, &host.name)));
+            }
+        }
+        Host::update_authorization(&mut conn, id, update.login, update.options, &actor)?;
+        AuthorizationHistory::for_authorization(&mut conn, id).map(Ok)
+    })
+    .await?;
+
+    Ok(match res {
+        Ok(Ok(history)) => HttpResponse::Ok().json(history.last()),
+        Ok(Err(denied)) => message_response(StatusCode::FORBIDDEN, &denied),
+        Err(error) => error_response(StatusCode::BAD_REQUEST, error),
+    })
+}
+
+#[derive(Deserialize, Serialize)]
+struct Principals {
+    principals: Vec<String>,
+}
+
+/// Replaces the certificate principals of an authorization. They end up in the
+/// authorized_principals file of the login on hosts with a principals file.
+#[put("/{id}/principals")]
+async fn set_principals(
+    conn: Data<ConnectionPool>,
+    access: Data<AccessControl>,
+    identity: Identity,
+    id: Path<i32>,
+    principals: Json<Principals>,
+) -> actix_web::Result<impl Responder> {
+    let id = id.into_inner();
+    let Principals { principals } = principals.into_inner();
+    let actor = actor(&identity);
+
+    let res = timing::block(move || {
+        let mut conn = conn.get().unwrap();
+        let Some(host) = Host::get_from_authorization(&mut conn, id)? else {
+            return Ok(Err((
+                StatusCode::NOT_FOUND,
+                Message::new("authorization.not_found"),
+            )));
+        };
+        if !access.may_change_host(&actor, &host) {
+            return Ok(Err((
+                StatusCode::FORBIDDEN,
+                AccessControl::denied(&actor, &host.name),
+            )));
+        }
+        Host::set_authorization_principals(&mut conn, id, &principals, &actor).map(Ok)
+    })
+    .await?;
+
+    Ok(match res {
+        Ok(Ok(principals)) => HttpResponse::Ok().json(Principals { principals }),
+        Ok(Err((status, error))) => message_response(status, &error),
+        Err(error) => error_response(StatusCode::BAD_REQUEST, error),
+    })
+}
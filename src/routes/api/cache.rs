@@ -0,0 +1,49 @@
+use actix_web::{
+    post,
+    web::{self, Data},
+    HttpResponse, Responder,
+};
+use log::{error, info};
+use serde::Serialize;
+
+use crate::ssh::HostCache;
+
+pub fn cache_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(invalidate).service(warm);
+}
+
+#[derive(Serialize)]
+struct InvalidateResponse {
+    /// Number of hosts that had cached data
+    invalidated: usize,
+}
+
+/// Drop the cached data of all hosts. The next request for a host connects to it again.
+#[post("/invalidate")]
+async fn invalidate(caching_ssh_client: Data<dyn HostCache>) -> impl Responder {
+    let invalidated = caching_ssh_client.invalidate_all().await;
+    info!("Invalidated cached data of {invalidated} hosts");
+
+    HttpResponse::Ok().json(InvalidateResponse { invalidated })
+}
+
+#[derive(Serialize)]
+struct WarmResponse {
+    started: bool,
+}
+
+/// Refresh the cached data of all hosts in the background
+#[post("/warm")]
+async fn warm(caching_ssh_client: Data<dyn HostCache>) -> impl Responder {
+    let client = caching_ssh_client.into_inner();
+
+    tokio::spawn(async move {
+        info!("Running cache warm job");
+        match client.get_current_state(None, 1).await {
+            Ok(state) => info!("Succeeded cache warm job for {} hosts", state.len()),
+            Err(e) => error!("Failed cache warm job: {e}"),
+        }
+    });
+
+    HttpResponse::Accepted().json(WarmResponse { started: true })
+}
@@ -0,0 +1,401 @@
+use actix_web::{
+    get,
+    http::StatusCode,
+    web::{self, Data, Query},
+    HttpResponse, Responder,
+};
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    compliance::{self, ComplianceConfig, Context, HostState, Policy, PolicyPack},
+    db::history::now,
+    findings::{to_csv, to_sarif, Finding},
+    i18n::Message,
+    models::{Host, KeyHistory},
+    ssh::{HostCache, SshClient},
+    sshd::{host_report, SshdReport},
+    timing, Configuration, ConnectionPool,
+};
+
+use super::{
+    error_response,
+    fleet::{FleetResponse, HostResult},
+    message_response,
+};
+
+pub fn compliance_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(scores)
+        .service(packs)
+        .service(host_score)
+        .service(findings)
+        .service(sshd_config);
+}
+
+#[derive(Deserialize)]
+struct PackFilter {
+    /// Pack to check against (default `default`)
+    pack: Option<String>,
+    /// Only hosts with this tag
+    tag: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PolicyInfo {
+    policy: Policy,
+    description: &'static str,
+}
+
+#[derive(Serialize)]
+struct PackInfo {
+    name: String,
+    policies: Vec<PolicyInfo>,
+}
+
+impl From<&PolicyPack> for PackInfo {
+    fn from(pack: &PolicyPack) -> Self {
+        Self {
+            name: pack.name.clone(),
+            policies: pack
+                .policies
+                .iter()
+                .map(|policy| PolicyInfo {
+                    policy: *policy,
+                    description: policy.description(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ComplianceReport {
+    pack: PackInfo,
+    /// Average score of the checked hosts
+    score: Option<u8>,
+    hosts: Vec<compliance::HostCompliance>,
+    /// Hosts that were never fetched or couldn't be reached when they last were
+    unchecked: Vec<String>,
+}
+
+/// Checks the cached state of the hosts matching `filter` against a pack. Returns the
+/// evaluated hosts and the names of the ones without usable cached state.
+async fn evaluate(
+    conn: Data<ConnectionPool>,
+    caching_ssh_client: &dyn HostCache,
+    pack: &PolicyPack,
+    config: &ComplianceConfig,
+    filter: impl Fn(&Host) -> bool,
+) -> Result<(Vec<compliance::HostCompliance>, Vec<String>), String> {
+    let key_created = timing::block(move || KeyHistory::created_dates(&mut conn.get().unwrap()))
+        .await
+        .map_err(|error| error.to_string())??;
+    let own_key_base64 = caching_ssh_client.get_own_key_b64();
+    let context = Context {
+        own_key_base64: &own_key_base64,
+        key_created: &key_created,
+        now: now(),
+        max_key_age: time::Duration::days(config.max_key_age_days),
+        security_key_tags: &config.security_key_tags,
+    };
+
+    let mut hosts = Vec::new();
+    let mut unchecked = Vec::new();
+    for (host, cache, logins) in caching_ssh_client.get_cached_logins().await? {
+        if !filter(&host) {
+            continue;
+        }
+        match logins {
+            Ok(logins) => {
+                let logins = logins
+                    .into_iter()
+                    .map(|(login, has_pragma, entries, _)| (login, has_pragma, entries));
+                let tags = host.tag_list().map(str::to_owned).collect();
+                hosts.push(pack.evaluate(HostState::new(host.name, tags, cache, logins), &context));
+            }
+            Err(_) => unchecked.push(host.name),
+        }
+    }
+    Ok((hosts, unchecked))
+}
+
+fn find_pack(config: &Configuration, name: Option<&str>) -> Result<PolicyPack, HttpResponse> {
+    let name = name.unwrap_or(compliance::DEFAULT_PACK);
+    config.compliance.pack(name).ok_or_else(|| {
+        message_response(
+            StatusCode::NOT_FOUND,
+            &Message::new("compliance.pack_not_found").arg(name),
+        )
+    })
+}
+
+/// Scores the cached state of every host against a policy pack, without connecting to any host
+#[get("")]
+async fn scores(
+    conn: Data<ConnectionPool>,
+    caching_ssh_client: Data<dyn HostCache>,
+    config: Data<Configuration>,
+    filter: Query<PackFilter>,
+) -> actix_web::Result<impl Responder> {
+    let pack = match find_pack(&config, filter.pack.as_deref()) {
+        Ok(pack) => pack,
+        Err(response) => return Ok(response),
+    };
+    let all_hosts = {
+        let conn = conn.clone();
+        match timing::block(move || Host::get_all_hosts(&mut conn.get().unwrap())).await? {
+            Ok(hosts) => hosts,
+            Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+        }
+    };
+    let matches = |host: &Host| filter.tag.as_ref().is_none_or(|tag| host.has_tag(tag));
+    let (hosts, mut unchecked) = match evaluate(
+        conn,
+        caching_ssh_client.get_ref(),
+        &pack,
+        &config.compliance,
+        matches,
+    )
+    .await
+    {
+        Ok(result) => result,
+        Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+    };
+
+    // Hosts that were never fetched aren't in the cache at all
+    unchecked.extend(
+        all_hosts
+            .into_iter()
+            .filter(|host| matches(host))
+            .map(|host| host.name)
+            .filter(|name| {
+                !hosts.iter().any(|checked| checked.host.eq(name)) && !unchecked.contains(name)
+            })
+            .collect::<Vec<_>>(),
+    );
+    unchecked.sort();
+    let score = (!hosts.is_empty()).then(|| {
+        (hosts
+            .iter()
+            .map(|host| usize::from(host.score))
+            .sum::<usize>()
+            / hosts.len()) as u8
+    });
+    Ok(HttpResponse::Ok().json(ComplianceReport {
+        pack: PackInfo::from(&pack),
+        score,
+        hosts,
+        unchecked,
+    }))
+}
+
+/// Lists the policy packs, including the default one
+#[get("/packs")]
+async fn packs(config: Data<Configuration>) -> impl Responder {
+    let packs: Vec<PackInfo> = config
+        .compliance
+        .packs()
+        .iter()
+        .map(PackInfo::from)
+        .collect();
+    HttpResponse::Ok().json(packs)
+}
+
+#[derive(Deserialize)]
+struct PackQuery {
+    /// Pack to check against (default `default`)
+    pack: Option<String>,
+}
+
+/// Scores the cached state of one host against a policy pack
+#[get("/host/{name}")]
+async fn host_score(
+    conn: Data<ConnectionPool>,
+    caching_ssh_client: Data<dyn HostCache>,
+    config: Data<Configuration>,
+    name: web::Path<String>,
+    query: Query<PackQuery>,
+) -> actix_web::Result<impl Responder> {
+    let pack = match find_pack(&config, query.pack.as_deref()) {
+        Ok(pack) => pack,
+        Err(response) => return Ok(response),
+    };
+    let name = name.into_inner();
+    let (mut hosts, _) = match evaluate(
+        conn,
+        caching_ssh_client.get_ref(),
+        &pack,
+        &config.compliance,
+        |host| host.name.eq(&name),
+    )
+    .await
+    {
+        Ok(result) => result,
+        Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+    };
+    Ok(match hosts.pop() {
+        Some(host) => HttpResponse::Ok().json(host),
+        None => message_response(
+            StatusCode::NOT_FOUND,
+            &Message::new("compliance.no_cached_state").arg(name),
+        ),
+    })
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum ExportFormat {
+    #[default]
+    Sarif,
+    Csv,
+}
+
+#[derive(Deserialize)]
+struct FindingsQuery {
+    #[serde(default)]
+    format: ExportFormat,
+    /// Pack the policy violations are from (default `default`)
+    pack: Option<String>,
+    /// Only hosts with this tag
+    tag: Option<String>,
+}
+
+/// Exports the policy violations and the differences to the database of the cached hosts,
+/// with severity and a remediation hint, as SARIF or CSV
+#[get("/findings")]
+async fn findings(
+    conn: Data<ConnectionPool>,
+    caching_ssh_client: Data<dyn HostCache>,
+    config: Data<Configuration>,
+    query: Query<FindingsQuery>,
+) -> actix_web::Result<impl Responder> {
+    let pack = match find_pack(&config, query.pack.as_deref()) {
+        Ok(pack) => pack,
+        Err(response) => return Ok(response),
+    };
+    let matches = |host: &Host| query.tag.as_ref().is_none_or(|tag| host.has_tag(tag));
+    let (hosts, _) = match evaluate(
+        conn,
+        caching_ssh_client.get_ref(),
+        &pack,
+        &config.compliance,
+        matches,
+    )
+    .await
+    {
+        Ok(result) => result,
+        Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+    };
+    let diffs = match caching_ssh_client.get_cached_state().await {
+        Ok(state) => state,
+        Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+    };
+
+    let mut findings: Vec<Finding> = hosts
+        .iter()
+        .flat_map(|host| {
+            host.violations
+                .iter()
+                .map(|violation| Finding::from_violation(&host.host, violation))
+        })
+        .collect();
+    // The evaluated hosts are the cached ones matching the tag
+    for (host, (_, diff)) in diffs {
+        let Ok(logins) = diff else { continue };
+        if !hosts.iter().any(|checked| checked.host.eq(&host)) {
+            continue;
+        }
+        for (login, items) in logins {
+            findings.extend(
+                items
+                    .iter()
+                    .map(|item| Finding::from_diff(&host, &login, item)),
+            );
+        }
+    }
+    findings.sort_by(|a, b| (a.severity, &a.host).cmp(&(b.severity, &b.host)));
+
+    Ok(match query.format {
+        ExportFormat::Sarif => HttpResponse::Ok()
+            .content_type("application/sarif+json")
+            .insert_header((
+                "Content-Disposition",
+                "attachment; filename=\"ssm-findings.sarif\"",
+            ))
+            .body(to_sarif(&findings).to_string()),
+        ExportFormat::Csv => HttpResponse::Ok()
+            .content_type("text/csv; charset=utf-8")
+            .insert_header((
+                "Content-Disposition",
+                "attachment; filename=\"ssm-findings.csv\"",
+            ))
+            .body(to_csv(&findings)),
+    })
+}
+
+#[derive(Deserialize)]
+struct ComplianceFilter {
+    /// Only hosts with this tag
+    tag: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SshdComplianceReport {
+    compliant: usize,
+    non_compliant: usize,
+    #[serde(flatten)]
+    fleet: FleetResponse<SshdReport>,
+}
+
+/// Compares the sshd_config of every host with the expected directives, connecting to at most
+/// `max_concurrent_connections` hosts at the same time
+#[get("/sshd_config")]
+async fn sshd_config(
+    conn: Data<ConnectionPool>,
+    ssh_client: Data<dyn SshClient>,
+    config: Data<Configuration>,
+    filter: Query<ComplianceFilter>,
+) -> actix_web::Result<impl Responder> {
+    if !config.sshd_config.enabled {
+        return Ok(message_response(
+            StatusCode::NOT_FOUND,
+            &Message::new("sshd_config.read_disabled"),
+        ));
+    }
+    let mut hosts =
+        match timing::block(move || Host::get_all_hosts(&mut conn.get().unwrap())).await? {
+            Ok(hosts) => hosts,
+            Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+        };
+    if let Some(tag) = &filter.tag {
+        hosts.retain(|host| host.has_tag(tag));
+    }
+
+    let hosts: Vec<HostResult<SshdReport>> = stream::iter(hosts)
+        .map(|host| {
+            let (ssh_client, policy) = (ssh_client.get_ref(), &config.sshd_config);
+            async move {
+                let name = host.name.clone();
+                HostResult::new(name, host_report(ssh_client, policy, host).await, None)
+            }
+        })
+        .buffered(config.ssh.max_concurrent_connections.max(1))
+        .collect()
+        .await;
+
+    let count = |compliant: bool| {
+        hosts
+            .iter()
+            .filter(|host| {
+                host.data
+                    .as_ref()
+                    .is_some_and(|report| report.compliant == compliant)
+            })
+            .count()
+    };
+    Ok(HttpResponse::Ok().json(SshdComplianceReport {
+        compliant: count(true),
+        non_compliant: count(false),
+        fleet: FleetResponse::new(hosts),
+    }))
+}
@@ -0,0 +1,152 @@
+use actix_web::{
+    get,
+    http::StatusCode,
+    web::{self, Data},
+    HttpResponse, Responder,
+};
+use serde::Serialize;
+use time::{OffsetDateTime, PrimitiveDateTime};
+
+use crate::{
+    db::stats::Counts,
+    models::{AuthorizationHistory, KeyHistory, PendingHost, SecurityEvent},
+    ssh::HostCache,
+    timing,
+    update::{UpdateCheck, UpdateNotice},
+    ConnectionPool,
+};
+
+use super::error_response;
+
+/// How many entries of each history are merged into the recent activity
+const RECENT_ACTIVITY: i64 = 20;
+
+pub fn dashboard_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(dashboard);
+}
+
+#[derive(Serialize)]
+struct DriftedHost {
+    host: String,
+    /// Logins whose authorized_keys differ from the database
+    logins: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct UnreachableHost {
+    host: String,
+    error: String,
+    #[serde(with = "time::serde::rfc3339")]
+    checked_at: OffsetDateTime,
+}
+
+/// Built from cached host data only, no host is contacted
+#[derive(Serialize, Default)]
+struct DriftSummary {
+    in_sync: usize,
+    drifted: Vec<DriftedHost>,
+    unreachable: Vec<UnreachableHost>,
+    /// Hosts without cached data
+    unchecked: usize,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Activity {
+    Authorization(AuthorizationHistory),
+    Key(KeyHistory),
+    Security(SecurityEvent),
+}
+
+impl Activity {
+    const fn at(&self) -> PrimitiveDateTime {
+        match self {
+            Self::Authorization(change) => change.changed_at,
+            Self::Key(change) => change.changed_at,
+            Self::Security(event) => event.created_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DashboardResponse {
+    counts: Counts,
+    drift: DriftSummary,
+    /// Authorization and key changes and security events, newest first
+    recent_activity: Vec<Activity>,
+    /// Discovered hosts waiting for their host key to be confirmed
+    pending_approvals: Vec<PendingHost>,
+    /// Newer SSM release, if the update check found one
+    update: Option<UpdateNotice>,
+}
+
+/// Everything the start page shows in one request
+#[get("")]
+async fn dashboard(
+    conn: Data<ConnectionPool>,
+    caching_ssh_client: Data<dyn HostCache>,
+    update_check: Data<UpdateCheck>,
+) -> actix_web::Result<impl Responder> {
+    let res = timing::block(move || {
+        let mut conn = conn.get().unwrap();
+        let counts = Counts::get(&mut conn)?;
+
+        let mut recent_activity: Vec<Activity> =
+            AuthorizationHistory::recent(&mut conn, RECENT_ACTIVITY)?
+                .into_iter()
+                .map(Activity::Authorization)
+                .chain(
+                    KeyHistory::recent(&mut conn, RECENT_ACTIVITY)?
+                        .into_iter()
+                        .map(Activity::Key),
+                )
+                .chain(
+                    SecurityEvent::recent(&mut conn, RECENT_ACTIVITY)?
+                        .into_iter()
+                        .map(Activity::Security),
+                )
+                .collect();
+        recent_activity.sort_by_key(|activity| std::cmp::Reverse(activity.at()));
+        recent_activity.truncate(RECENT_ACTIVITY as usize);
+
+        Ok::<_, String>((counts, recent_activity, PendingHost::list(&mut conn)?))
+    })
+    .await?;
+    let (counts, recent_activity, pending_approvals) = match res {
+        Ok(res) => res,
+        Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+    };
+
+    let state = match caching_ssh_client.get_cached_state().await {
+        Ok(state) => state,
+        Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+    };
+    let mut drift = DriftSummary {
+        unchecked: usize::try_from(counts.hosts)
+            .unwrap_or_default()
+            .saturating_sub(state.len()),
+        ..Default::default()
+    };
+    for (host, (info, diff)) in state {
+        match diff {
+            Ok(logins) if logins.is_empty() => drift.in_sync += 1,
+            Ok(logins) => drift.drifted.push(DriftedHost {
+                host,
+                logins: logins.into_iter().map(|(login, _)| login).collect(),
+            }),
+            Err(e) => drift.unreachable.push(UnreachableHost {
+                host,
+                error: e.to_string(),
+                checked_at: info.cached_at,
+            }),
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(DashboardResponse {
+        counts,
+        drift,
+        recent_activity,
+        pending_approvals,
+        update: update_check.notice(),
+    }))
+}
@@ -0,0 +1,141 @@
+use actix_web::{
+    get,
+    http::StatusCode,
+    web::{self, Data, Path, Query},
+    HttpResponse, Responder,
+};
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::{
+    findings::Finding,
+    i18n::Message,
+    models::Host,
+    ssh::{HostCache, HostDiff},
+    timing, Configuration, ConnectionPool,
+};
+
+use super::{
+    error_response,
+    fleet::{FleetResponse, HostResult},
+    message_response,
+};
+
+pub fn diff_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(diffs).service(host_diff);
+}
+
+#[derive(Deserialize)]
+struct DiffQuery {
+    /// Only hosts with this tag
+    tag: Option<String>,
+    /// Fetch the hosts instead of using cached data
+    #[serde(default)]
+    force_update: bool,
+}
+
+#[derive(Serialize)]
+struct DiffEntry {
+    kind: &'static str,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct LoginDiff {
+    login: String,
+    differences: Vec<DiffEntry>,
+}
+
+/// The differences of a host per login, from `get_host_diff_until`
+fn host_result(host: String, diff: Option<HostDiff>, complete: bool) -> HostResult<Vec<LoginDiff>> {
+    let diff = diff.map(|(cache, diff)| {
+        let logins = diff.map(|logins| {
+            logins
+                .into_iter()
+                .map(|(login, items)| LoginDiff {
+                    differences: items
+                        .iter()
+                        .map(|item| {
+                            let finding = Finding::from_diff(&host, &login, item);
+                            DiffEntry {
+                                kind: finding.rule,
+                                message: finding.message,
+                            }
+                        })
+                        .collect(),
+                    login,
+                })
+                .collect()
+        });
+        (cache, logins)
+    });
+    match diff {
+        Some((cache, logins)) if complete => HostResult::new(host, logins, Some(cache)),
+        cached => HostResult::timeout(host, cached),
+    }
+}
+
+/// Differences between the database and the hosts. Hosts that don't answer within `ssh.diff_deadline`
+/// are answered from the cache with the timeout status, their fetch goes on in the background.
+#[get("")]
+async fn diffs(
+    conn: Data<ConnectionPool>,
+    caching_ssh_client: Data<dyn HostCache>,
+    config: Data<Configuration>,
+    query: Query<DiffQuery>,
+) -> actix_web::Result<impl Responder> {
+    let deadline = Instant::now() + config.ssh.diff_deadline;
+    let mut hosts =
+        match timing::block(move || Host::get_all_hosts(&mut conn.get().unwrap())).await? {
+            Ok(hosts) => hosts,
+            Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+        };
+    if let Some(tag) = &query.tag {
+        hosts.retain(|host| host.has_tag(tag));
+    }
+
+    let force_update = query.force_update;
+    let hosts: Vec<HostResult<Vec<LoginDiff>>> = stream::iter(hosts)
+        .map(|host| {
+            let caching_ssh_client = caching_ssh_client.get_ref();
+            async move {
+                let name = host.name.clone();
+                let (diff, complete) = caching_ssh_client
+                    .get_host_diff_until(host, force_update, deadline)
+                    .await;
+                host_result(name, diff, complete)
+            }
+        })
+        .buffered(config.ssh.max_concurrent_connections.max(1))
+        .collect()
+        .await;
+    Ok(HttpResponse::Ok().json(FleetResponse::new(hosts)))
+}
+
+/// Differences between the database and one host, with the same deadline
+#[get("/{name}")]
+async fn host_diff(
+    conn: Data<ConnectionPool>,
+    caching_ssh_client: Data<dyn HostCache>,
+    config: Data<Configuration>,
+    name: Path<String>,
+    query: Query<DiffQuery>,
+) -> actix_web::Result<impl Responder> {
+    let deadline = Instant::now() + config.ssh.diff_deadline;
+    let host = match Host::get_from_name(conn.get().unwrap(), name.into_inner()).await {
+        Ok(Some(host)) => host,
+        Ok(None) => {
+            return Ok(message_response(
+                StatusCode::NOT_FOUND,
+                &Message::new("host.not_found"),
+            ))
+        }
+        Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+    };
+    let name = host.name.clone();
+    let (diff, complete) = caching_ssh_client
+        .get_host_diff_until(host, query.force_update, deadline)
+        .await;
+    Ok(HttpResponse::Ok().json(host_result(name, diff, complete)))
+}
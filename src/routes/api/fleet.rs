@@ -0,0 +1,108 @@
+//! The response of every endpoint that works on many hosts at once. Each host gets its own status,
+//! so one host that is down never fails the whole request.
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::ssh::{CacheInfo, SshClientError};
+
+/// How a host fared
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HostStatus {
+    /// Current data
+    Ok,
+    /// Cached data older than the cache TTL, it is being refreshed in the background
+    Stale,
+    /// The host didn't answer in time, `data` is the cached state if there is one
+    Timeout,
+    /// The host couldn't be checked, see `error`
+    Error,
+}
+
+#[derive(Serialize)]
+pub struct HostResult<T> {
+    pub host: String,
+    pub status: HostStatus,
+    /// When the data was fetched from the host, missing if nothing is known about it
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub cached_at: Option<OffsetDateTime>,
+    pub data: Option<T>,
+    pub error: Option<String>,
+}
+
+impl<T> HostResult<T> {
+    /// The answer of a host, `cache` tells how old it is if it came from the cache
+    pub fn new(host: String, result: Result<T, SshClientError>, cache: Option<CacheInfo>) -> Self {
+        let (status, data, error) = match result {
+            Ok(data) if cache.is_some_and(|cache| cache.stale) => {
+                (HostStatus::Stale, Some(data), None)
+            }
+            Ok(data) => (HostStatus::Ok, Some(data), None),
+            Err(SshClientError::Timeout) => (
+                HostStatus::Timeout,
+                None,
+                Some(SshClientError::Timeout.to_string()),
+            ),
+            Err(error) => (HostStatus::Error, None, Some(error.to_string())),
+        };
+        Self {
+            host,
+            status,
+            cached_at: cache.map(|cache| cache.cached_at),
+            data,
+            error,
+        }
+    }
+
+    /// A host that didn't answer before the deadline, with its cached state if there is one
+    pub fn timeout(host: String, cached: Option<(CacheInfo, Result<T, SshClientError>)>) -> Self {
+        let Some((cache, result)) = cached else {
+            return Self {
+                host,
+                status: HostStatus::Timeout,
+                cached_at: None,
+                data: None,
+                error: None,
+            };
+        };
+        Self {
+            status: HostStatus::Timeout,
+            ..Self::new(host, result, Some(cache))
+        }
+    }
+}
+
+#[derive(Serialize, Default)]
+pub struct FleetSummary {
+    pub ok: usize,
+    pub stale: usize,
+    pub timeout: usize,
+    pub error: usize,
+}
+
+#[derive(Serialize)]
+pub struct FleetResponse<T> {
+    /// Some hosts didn't answer with current data
+    pub incomplete: bool,
+    pub summary: FleetSummary,
+    pub hosts: Vec<HostResult<T>>,
+}
+
+impl<T> FleetResponse<T> {
+    pub fn new(hosts: Vec<HostResult<T>>) -> Self {
+        let mut summary = FleetSummary::default();
+        for host in &hosts {
+            match host.status {
+                HostStatus::Ok => summary.ok += 1,
+                HostStatus::Stale => summary.stale += 1,
+                HostStatus::Timeout => summary.timeout += 1,
+                HostStatus::Error => summary.error += 1,
+            }
+        }
+        Self {
+            incomplete: summary.ok < hosts.len(),
+            summary,
+            hosts,
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use actix_identity::Identity;
+use actix_web::{
+    delete, get,
+    http::StatusCode,
+    post,
+    web::{self, Data, Json, Path},
+    HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::history::now,
+    freeze::{ActiveFreeze, Freezes, RecurringFreeze},
+    i18n::Message,
+    models::FreezeWindow,
+    routes::actor,
+    timing, ConnectionPool,
+};
+
+use super::{audit::parse_timestamp, error_response, message_response};
+
+pub fn freeze_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list).service(add).service(delete);
+}
+
+#[derive(Serialize)]
+struct FreezeOverview<'a> {
+    /// Deployments are rejected while this is set
+    active: Option<ActiveFreeze>,
+    /// From the configuration file
+    recurring: &'a [RecurringFreeze],
+    /// Ad-hoc freezes that didn't end yet
+    scheduled: Vec<FreezeWindow>,
+}
+
+/// The active change freeze and the configured and ad-hoc ones
+#[get("")]
+async fn list(
+    conn: Data<ConnectionPool>,
+    freezes: Data<Freezes>,
+) -> actix_web::Result<impl Responder> {
+    let res = {
+        let freezes = freezes.clone();
+        timing::block(move || {
+            let mut conn = conn.get().unwrap();
+            Ok::<_, String>((
+                freezes.active(&mut conn, now())?,
+                FreezeWindow::upcoming(&mut conn)?,
+            ))
+        })
+        .await?
+    };
+
+    Ok(match res {
+        Ok((active, scheduled)) => HttpResponse::Ok().json(FreezeOverview {
+            active,
+            recurring: freezes.recurring(),
+            scheduled,
+        }),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
+
+#[derive(Deserialize)]
+struct NewFreeze {
+    /// Defaults to now
+    from: Option<String>,
+    until: String,
+    reason: String,
+}
+
+/// Adds an ad-hoc freeze, e.g. during an incident
+#[post("")]
+async fn add(
+    conn: Data<ConnectionPool>,
+    identity: Identity,
+    freeze: Json<NewFreeze>,
+) -> actix_web::Result<impl Responder> {
+    let NewFreeze {
+        from,
+        until,
+        reason,
+    } = freeze.into_inner();
+    let starts_at = match from.as_deref().map(parse_timestamp).transpose() {
+        Ok(from) => from.unwrap_or_else(now),
+        Err(error) => return Ok(error_response(StatusCode::BAD_REQUEST, error)),
+    };
+    let ends_at = match parse_timestamp(&until) {
+        Ok(until) => until,
+        Err(error) => return Ok(error_response(StatusCode::BAD_REQUEST, error)),
+    };
+    if ends_at <= starts_at || ends_at <= now() {
+        return Ok(message_response(
+            StatusCode::BAD_REQUEST,
+            &Message::new("freeze.invalid_window"),
+        ));
+    }
+    if reason.trim().is_empty() {
+        return Ok(message_response(
+            StatusCode::BAD_REQUEST,
+            &Message::new("freeze.reason_required"),
+        ));
+    }
+
+    let actor = actor(&identity);
+    let res = timing::block(move || {
+        FreezeWindow::add(
+            &mut conn.get().unwrap(),
+            starts_at,
+            ends_at,
+            reason.trim().to_owned(),
+            &actor,
+        )
+    })
+    .await?;
+
+    Ok(match res {
+        Ok(window) => HttpResponse::Created().json(window),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
+
+/// Ends an ad-hoc freeze early, recurring ones can only be changed in the configuration file
+#[delete("/{id}")]
+async fn delete(conn: Data<ConnectionPool>, id: Path<i32>) -> actix_web::Result<impl Responder> {
+    let id = id.into_inner();
+    let res = timing::block(move || FreezeWindow::delete(&mut conn.get().unwrap(), id)).await?;
+
+    Ok(match res {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(error) => error_response(StatusCode::NOT_FOUND, error),
+    })
+}
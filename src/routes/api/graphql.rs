@@ -0,0 +1,398 @@
+//! Read-only GraphQL view of hosts, users, keys and authorizations, for questions that need
+//! several REST calls, like "users with keys older than a year who can reach prod hosts"
+use std::collections::HashSet;
+
+use actix_web::{
+    post,
+    web::{self, Data, Json},
+    HttpResponse, Responder,
+};
+use async_graphql::{Context, EmptyMutation, EmptySubscription, Object, Schema};
+use time::{format_description::well_known::Rfc3339, PrimitiveDateTime};
+
+use crate::{
+    db::user::UserFilter,
+    models::{Host, KeyHistory, PublicUserKey, User},
+    timing, ConnectionPool, DbConnection,
+};
+
+use super::audit::parse_timestamp;
+
+/// Nesting allowed in a query, enough for host -> authorizations -> user -> keys -> user
+const MAX_DEPTH: usize = 8;
+/// Bounds how many fields one query may resolve
+const MAX_COMPLEXITY: usize = 500;
+
+type SsmSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+pub fn graphql_config(cfg: &mut web::ServiceConfig) {
+    let schema = Schema::build(Query, EmptyMutation, EmptySubscription)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish();
+    cfg.app_data(Data::new(schema)).service(graphql);
+}
+
+/// Executes a GraphQL query. Errors are reported in the response body, as GraphQL clients expect.
+#[post("")]
+async fn graphql(
+    schema: Data<SsmSchema>,
+    conn: Data<ConnectionPool>,
+    request: Json<async_graphql::Request>,
+) -> impl Responder {
+    let request = request.into_inner().data(conn.get_ref().clone());
+    HttpResponse::Ok().json(schema.execute(request).await)
+}
+
+/// Runs a database query on the blocking thread pool
+async fn with_conn<T, F>(ctx: &Context<'_>, f: F) -> async_graphql::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut DbConnection) -> Result<T, String> + Send + 'static,
+{
+    let pool = ctx.data::<ConnectionPool>()?.clone();
+    let res = timing::block(move || {
+        let mut conn = pool.get().map_err(|e| e.to_string())?;
+        f(&mut conn)
+    })
+    .await
+    .map_err(|_| "Blocking error.".to_owned())?;
+    Ok(res?)
+}
+
+fn parse_before(input: Option<String>) -> async_graphql::Result<Option<PrimitiveDateTime>> {
+    Ok(input.as_deref().map(parse_timestamp).transpose()?)
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// All hosts, optionally only those with a tag
+    async fn hosts(
+        &self,
+        ctx: &Context<'_>,
+        tag: Option<String>,
+    ) -> async_graphql::Result<Vec<HostObject>> {
+        let hosts = with_conn(ctx, Host::get_all_hosts).await?;
+        Ok(hosts
+            .into_iter()
+            .filter(|host| tag.as_deref().is_none_or(|tag| host.has_tag(tag)))
+            .map(HostObject)
+            .collect())
+    }
+
+    /// A host by name or alias
+    async fn host(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+    ) -> async_graphql::Result<Option<HostObject>> {
+        let host = with_conn(ctx, move |conn| Host::get_from_name_sync(conn, name)).await?;
+        Ok(host.map(HostObject))
+    }
+
+    /// All users, optionally only those of a department or authorized on a host with a tag
+    async fn users(
+        &self,
+        ctx: &Context<'_>,
+        reaches_tag: Option<String>,
+        department: Option<String>,
+    ) -> async_graphql::Result<Vec<UserObject>> {
+        let users = with_conn(ctx, move |conn| {
+            let filter = UserFilter {
+                department,
+                ..UserFilter::default()
+            };
+            let users = User::search(conn, &filter)?;
+            let Some(tag) = reaches_tag else {
+                return Ok(users);
+            };
+            let tagged: HashSet<String> = Host::get_all_hosts(conn)?
+                .into_iter()
+                .filter(|host| host.has_tag(&tag))
+                .map(|host| host.name)
+                .collect();
+            let mut reaching = Vec::new();
+            for user in users {
+                let authorizations = user.get_authorizations(conn)?;
+                if authorizations
+                    .iter()
+                    .any(|(_, host, _, _)| tagged.contains(host))
+                {
+                    reaching.push(user);
+                }
+            }
+            Ok(reaching)
+        })
+        .await?;
+        Ok(users.into_iter().map(UserObject).collect())
+    }
+
+    async fn user(
+        &self,
+        ctx: &Context<'_>,
+        username: String,
+    ) -> async_graphql::Result<Option<UserObject>> {
+        let user = with_conn(ctx, move |conn| User::find_user(conn, &username)).await?;
+        Ok(user.map(UserObject))
+    }
+
+    /// All keys, optionally only those added before a timestamp
+    async fn keys(
+        &self,
+        ctx: &Context<'_>,
+        created_before: Option<String>,
+    ) -> async_graphql::Result<Vec<KeyObject>> {
+        let before = parse_before(created_before)?;
+        with_conn(ctx, move |conn| {
+            let keys = PublicUserKey::get_all_keys(conn)?;
+            load_keys(conn, keys, before)
+        })
+        .await
+    }
+}
+
+/// Looks up when each key was added. With `before`, keys without a recorded creation are left out.
+fn load_keys(
+    conn: &mut DbConnection,
+    keys: Vec<PublicUserKey>,
+    before: Option<PrimitiveDateTime>,
+) -> Result<Vec<KeyObject>, String> {
+    let mut loaded = Vec::new();
+    for key in keys {
+        let created_at = KeyHistory::created_at(conn, key.id)?;
+        if let Some(before) = before {
+            if created_at.is_none_or(|created_at| created_at >= before) {
+                continue;
+            }
+        }
+        loaded.push(KeyObject { key, created_at });
+    }
+    Ok(loaded)
+}
+
+pub struct HostObject(Host);
+
+#[Object(name = "Host")]
+impl HostObject {
+    async fn id(&self) -> i32 {
+        self.0.id
+    }
+
+    async fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    async fn address(&self) -> &str {
+        &self.0.address
+    }
+
+    async fn port(&self) -> u16 {
+        self.0.port
+    }
+
+    /// Login used by ssm to connect
+    async fn username(&self) -> &str {
+        &self.0.username
+    }
+
+    async fn transport(&self) -> &str {
+        &self.0.transport
+    }
+
+    async fn tags(&self) -> Vec<&str> {
+        self.0.tag_list().collect()
+    }
+
+    async fn aliases(&self) -> Vec<&str> {
+        self.0.alias_list().collect()
+    }
+
+    /// Accepted host key fingerprints, the primary one first
+    async fn key_fingerprints(&self) -> Vec<&str> {
+        self.0.key_fingerprint_list().collect()
+    }
+
+    /// Fingerprint offered by the host that didn't match, under the strict host key policy
+    async fn key_mismatch(&self) -> Option<&str> {
+        self.0.key_mismatch.as_deref()
+    }
+
+    /// Deleting and deploying need a confirmation from a preview
+    async fn protected(&self) -> bool {
+        self.0.protected
+    }
+
+    async fn jump_host(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<HostObject>> {
+        let Some(jump_via) = self.0.jump_via else {
+            return Ok(None);
+        };
+        let host = with_conn(ctx, move |conn| Host::get_from_id_sync(conn, jump_via)).await?;
+        Ok(host.map(HostObject))
+    }
+
+    async fn authorizations(
+        &self,
+        ctx: &Context<'_>,
+    ) -> async_graphql::Result<Vec<AuthorizationObject>> {
+        let host = self.0.clone();
+        let authorizations = with_conn(ctx, move |conn| host.get_authorized_users(conn)).await?;
+        Ok(authorizations
+            .into_iter()
+            .map(|(id, username, login, options)| AuthorizationObject {
+                id,
+                host_name: self.0.name.clone(),
+                username,
+                login,
+                options,
+            })
+            .collect())
+    }
+}
+
+pub struct UserObject(User);
+
+#[Object(name = "User")]
+impl UserObject {
+    async fn id(&self) -> i32 {
+        self.0.id
+    }
+
+    async fn username(&self) -> &str {
+        &self.0.username
+    }
+
+    async fn enabled(&self) -> bool {
+        self.0.enabled
+    }
+
+    async fn email(&self) -> Option<&str> {
+        self.0.email.as_deref()
+    }
+
+    async fn full_name(&self) -> Option<&str> {
+        self.0.full_name.as_deref()
+    }
+
+    async fn department(&self) -> Option<&str> {
+        self.0.department.as_deref()
+    }
+
+    /// Id of the user at the identity provider
+    async fn external_id(&self) -> Option<&str> {
+        self.0.external_id.as_deref()
+    }
+
+    /// The user's keys, optionally only those added before a timestamp
+    async fn keys(
+        &self,
+        ctx: &Context<'_>,
+        created_before: Option<String>,
+    ) -> async_graphql::Result<Vec<KeyObject>> {
+        let before = parse_before(created_before)?;
+        let user = self.0.clone();
+        with_conn(ctx, move |conn| {
+            let keys = user.get_keys(conn)?;
+            load_keys(conn, keys, before)
+        })
+        .await
+    }
+
+    async fn authorizations(
+        &self,
+        ctx: &Context<'_>,
+    ) -> async_graphql::Result<Vec<AuthorizationObject>> {
+        let user = self.0.clone();
+        let authorizations = with_conn(ctx, move |conn| user.get_authorizations(conn)).await?;
+        Ok(authorizations
+            .into_iter()
+            .map(|(id, host_name, login, options)| AuthorizationObject {
+                id,
+                host_name,
+                username: self.0.username.clone(),
+                login,
+                options,
+            })
+            .collect())
+    }
+}
+
+pub struct KeyObject {
+    key: PublicUserKey,
+    created_at: Option<PrimitiveDateTime>,
+}
+
+#[Object(name = "Key")]
+impl KeyObject {
+    async fn id(&self) -> i32 {
+        self.key.id
+    }
+
+    async fn key_type(&self) -> &str {
+        &self.key.key_type
+    }
+
+    /// The key lives on a FIDO2 security key
+    async fn security_key(&self) -> bool {
+        self.key.is_security_key()
+    }
+
+    async fn base64(&self) -> &str {
+        &self.key.key_base64
+    }
+
+    async fn comment(&self) -> Option<&str> {
+        self.key.comment.as_deref()
+    }
+
+    /// When the key was added to ssm as RFC 3339, unknown for keys added before history was recorded
+    async fn created_at(&self) -> async_graphql::Result<Option<String>> {
+        Ok(self
+            .created_at
+            .map(|created_at| created_at.assume_utc().format(&Rfc3339))
+            .transpose()?)
+    }
+
+    async fn user(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<UserObject>> {
+        let user_id = self.key.user_id;
+        let user = with_conn(ctx, move |conn| User::get_from_id(conn, user_id)).await?;
+        Ok(user.map(UserObject))
+    }
+}
+
+pub struct AuthorizationObject {
+    id: i32,
+    host_name: String,
+    username: String,
+    login: String,
+    options: Option<String>,
+}
+
+#[Object(name = "Authorization")]
+impl AuthorizationObject {
+    async fn id(&self) -> i32 {
+        self.id
+    }
+
+    /// Login on the host the user's keys are deployed to
+    async fn login(&self) -> &str {
+        &self.login
+    }
+
+    async fn options(&self) -> Option<&str> {
+        self.options.as_deref()
+    }
+
+    async fn host(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<HostObject>> {
+        let name = self.host_name.clone();
+        let host = with_conn(ctx, move |conn| Host::get_from_name_sync(conn, name)).await?;
+        Ok(host.map(HostObject))
+    }
+
+    async fn user(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<UserObject>> {
+        let username = self.username.clone();
+        let user = with_conn(ctx, move |conn| User::find_user(conn, &username)).await?;
+        Ok(user.map(UserObject))
+    }
+}
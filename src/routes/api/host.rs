@@ -0,0 +1,1145 @@
+use std::{collections::BTreeMap, str::FromStr};
+
+use actix_identity::Identity;
+use actix_web::{
+    delete, get,
+    http::StatusCode,
+    post, put,
+    web::{self, Data, Json, Path, Query},
+    HttpResponse, Responder,
+};
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    access::AccessControl,
+    db::host_confirmation::SSHD_CONFIG,
+    failed_logins::host_failed_logins,
+    hooks::EventHooks,
+    i18n::Message,
+    key_usage::host_usage,
+    models::{Host, HostConfirmation, NewHost, PendingHost, PublicUserKey},
+    routes::{actor, hosts::add_confirmed_host},
+    ssh::{
+        keyfile_from_entries, locks_out, locks_out_ssm, managed_keyfile, shell_quote,
+        CheckStatus, ConnectionCheck, ConnectionDetails, HostCache, KnownHosts, Proxy,
+        SshClient, TransportKind,
+    },
+    sshd::{self, host_report},
+    timing, Configuration, ConnectionPool,
+};
+
+use super::{error_response, message_response};
+
+pub fn host_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(bulk_create)
+        .service(import_known_hosts)
+        .service(operations)
+        .service(test_connection)
+        .service(bootstrap_command)
+        .service(host_keys)
+        .service(add_host_key)
+        .service(remove_host_key)
+        .service(key_sources)
+        .service(key_usage)
+        .service(preview)
+        .service(keyfile)
+        .service(failed_logins)
+        .service(sshd_config)
+        .service(change_sshd_config);
+}
+
+/// Recent connections and commands on a host, newest first. Kept in memory only.
+#[get("/{name}/operations")]
+async fn operations(
+    conn: Data<ConnectionPool>,
+    ssh_client: Data<dyn SshClient>,
+    name: Path<String>,
+) -> actix_web::Result<impl Responder> {
+    let host_name = name.into_inner();
+
+    Ok(
+        match Host::get_from_name(conn.get().unwrap(), host_name.clone()).await {
+            Ok(Some(host)) => HttpResponse::Ok().json(ssh_client.operations(&host.name)),
+            Ok(None) => message_response(StatusCode::NOT_FOUND, &Message::new("host.not_found")),
+            Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+        },
+    )
+}
+
+#[derive(Serialize)]
+struct ConnectionTestResponse {
+    host: String,
+    /// All steps passed
+    success: bool,
+    checks: Vec<ConnectionCheck>,
+}
+
+/// Connects to a host step by step: name resolution, TCP, host key, authentication and transport
+#[post("/{name}/test_connection")]
+async fn test_connection(
+    conn: Data<ConnectionPool>,
+    ssh_client: Data<dyn SshClient>,
+    name: Path<String>,
+) -> actix_web::Result<impl Responder> {
+    let host = match Host::get_from_name(conn.get().unwrap(), name.into_inner()).await {
+        Ok(Some(host)) => host,
+        Ok(None) => {
+            return Ok(message_response(
+                StatusCode::NOT_FOUND,
+                &Message::new("host.not_found"),
+            ))
+        }
+        Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+    };
+
+    let host_name = host.name.clone();
+    let checks = ssh_client.test_connection(host).await;
+
+    Ok(HttpResponse::Ok().json(ConnectionTestResponse {
+        host: host_name,
+        success: checks
+            .iter()
+            .all(|check| matches!(check.status, CheckStatus::Passed)),
+        checks,
+    }))
+}
+
+#[derive(Serialize)]
+struct BootstrapCommandResponse {
+    host: String,
+    /// Login SSM connects as
+    login: String,
+    public_key: String,
+    /// Shell command that authorizes the key, run on the host as root or as the login
+    command: String,
+}
+
+/// A command for hosts SSM can't log in to yet: it adds the key of SSM to the authorized_keys
+/// of the login, creating `~/.ssh` with the permissions sshd requires, and does nothing if the
+/// key is already there
+#[get("/{name}/bootstrap_command")]
+async fn bootstrap_command(
+    conn: Data<ConnectionPool>,
+    ssh_client: Data<dyn SshClient>,
+    name: Path<String>,
+) -> actix_web::Result<impl Responder> {
+    let host = match Host::get_from_name(conn.get().unwrap(), name.into_inner()).await {
+        Ok(Some(host)) => host,
+        Ok(None) => {
+            return Ok(message_response(
+                StatusCode::NOT_FOUND,
+                &Message::new("host.not_found"),
+            ))
+        }
+        Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+    };
+
+    let public_key = ssh_client.get_own_key_openssh();
+    let command = format!(
+        r#"u={login}; k={key}; h=$(getent passwd "$u" | cut -d: -f6) && [ -n "$h" ] && install -d -m 700 "$h/.ssh" && f="$h/.ssh/authorized_keys" && touch "$f" && chmod 600 "$f" && chown "$u:" "$h/.ssh" "$f" && {{ grep -qxF "$k" "$f" || printf '%s\n' "$k" >> "$f"; }}"#,
+        login = shell_quote(&host.username),
+        key = shell_quote(&public_key),
+    );
+
+    Ok(HttpResponse::Ok().json(BootstrapCommandResponse {
+        host: host.name,
+        login: host.username,
+        public_key,
+        command,
+    }))
+}
+
+#[derive(Serialize)]
+struct HostKeysResponse {
+    host: String,
+    /// Accepted host key fingerprints, the primary one first
+    fingerprints: Vec<String>,
+}
+
+impl From<&Host> for HostKeysResponse {
+    fn from(host: &Host) -> Self {
+        Self {
+            host: host.name.clone(),
+            fingerprints: host.key_fingerprint_list().map(str::to_owned).collect(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct HostKeyRequest {
+    fingerprint: String,
+}
+
+/// Host key fingerprints accepted when connecting to a host
+#[get("/{name}/hostkeys")]
+async fn host_keys(
+    conn: Data<ConnectionPool>,
+    name: Path<String>,
+) -> actix_web::Result<impl Responder> {
+    Ok(
+        match Host::get_from_name(conn.get().unwrap(), name.into_inner()).await {
+            Ok(Some(host)) => HttpResponse::Ok().json(HostKeysResponse::from(&host)),
+            Ok(None) => message_response(StatusCode::NOT_FOUND, &Message::new("host.not_found")),
+            Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+        },
+    )
+}
+
+/// Accepts another host key, e.g. one the host offers for a different algorithm
+#[post("/{name}/hostkeys")]
+async fn add_host_key(
+    conn: Data<ConnectionPool>,
+    access: Data<AccessControl>,
+    identity: Identity,
+    name: Path<String>,
+    request: Json<HostKeyRequest>,
+) -> actix_web::Result<impl Responder> {
+    let fingerprint = request.into_inner().fingerprint.trim().to_owned();
+    if ssh_key::Fingerprint::from_str(&fingerprint).is_err() {
+        return Ok(message_response(
+            StatusCode::BAD_REQUEST,
+            &Message::new("host.invalid_fingerprint").arg(fingerprint),
+        ));
+    }
+
+    update_host_keys(
+        conn,
+        &access,
+        &actor(&identity),
+        name.into_inner(),
+        StatusCode::CREATED,
+        move |conn, host| host.add_key_fingerprint(conn, &fingerprint),
+    )
+    .await
+}
+
+/// Stops accepting a host key. The last one can't be removed.
+#[delete("/{name}/hostkeys")]
+async fn remove_host_key(
+    conn: Data<ConnectionPool>,
+    access: Data<AccessControl>,
+    identity: Identity,
+    name: Path<String>,
+    request: Json<HostKeyRequest>,
+) -> actix_web::Result<impl Responder> {
+    let fingerprint = request.into_inner().fingerprint.trim().to_owned();
+
+    update_host_keys(
+        conn,
+        &access,
+        &actor(&identity),
+        name.into_inner(),
+        StatusCode::OK,
+        move |conn, host| host.remove_key_fingerprint(conn, &fingerprint),
+    )
+    .await
+}
+
+/// Applies a change to the host keys of a host and responds with the keys now accepted
+async fn update_host_keys<F>(
+    conn: Data<ConnectionPool>,
+    access: &AccessControl,
+    actor: &str,
+    host_name: String,
+    status: StatusCode,
+    update: F,
+) -> actix_web::Result<HttpResponse>
+where
+    F: FnOnce(&mut crate::DbConnection, &Host) -> Result<(), String> + Send + 'static,
+{
+    let host = match Host::get_from_name(conn.get().unwrap(), host_name).await {
+        Ok(Some(host)) => host,
+        Ok(None) => {
+            return Ok(message_response(
+                StatusCode::NOT_FOUND,
+                &Message::new("host.not_found"),
+            ))
+        }
+        Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+    };
+    if !access.may_change_host(actor, &host) {
+        return Ok(message_response(
+            StatusCode::FORBIDDEN,
+            &AccessControl::denied(actor, &host.name),
+        ));
+    }
+
+    let res = timing::block(move || {
+        let mut conn = conn.get().unwrap();
+        update(&mut conn, &host)?;
+        Host::get_from_id_sync(&mut conn, host.id)
+    })
+    .await?;
+
+    Ok(match res {
+        Ok(Some(host)) => HttpResponse::build(status).json(HostKeysResponse::from(&host)),
+        Ok(None) => message_response(StatusCode::NOT_FOUND, &Message::new("host.not_found")),
+        Err(error) => error_response(StatusCode::BAD_REQUEST, error),
+    })
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum KnownHostsStatus {
+    /// The host had no host key yet, the listed keys are now accepted
+    Imported,
+    /// All listed keys were already accepted
+    Verified,
+    /// One of the listed keys was already accepted, the others were added
+    Extended,
+    /// None of the listed keys is accepted, nothing was changed
+    Mismatch,
+}
+
+#[derive(Serialize)]
+struct KnownHostsResult {
+    host: String,
+    status: KnownHostsStatus,
+    /// Fingerprints of the keys listed for the host
+    fingerprints: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct KnownHostsResponse {
+    /// Hosts with keys in the file, other hosts are left out
+    results: Vec<KnownHostsResult>,
+    /// Lines that couldn't be parsed
+    invalid_lines: Vec<usize>,
+}
+
+/// Pre-seeds and verifies host keys from an OpenSSH known_hosts file sent as the request body.
+/// Hosts are matched by their address and fallback addresses with their port.
+#[post("/import/known_hosts")]
+async fn import_known_hosts(
+    conn: Data<ConnectionPool>,
+    body: String,
+) -> actix_web::Result<impl Responder> {
+    let known_hosts = KnownHosts::parse(&body);
+    let invalid_lines = known_hosts.invalid_lines.clone();
+
+    let res = timing::block(move || {
+        let mut conn = conn.get().unwrap();
+        let mut results = Vec::new();
+        for host in Host::get_all_hosts(&mut conn)? {
+            let mut fingerprints: Vec<String> = Vec::new();
+            for address in
+                std::iter::once(host.address.as_str()).chain(host.fallback_address_list())
+            {
+                for fingerprint in known_hosts.fingerprints(address, host.port) {
+                    if !fingerprints.contains(&fingerprint) {
+                        fingerprints.push(fingerprint);
+                    }
+                }
+            }
+            if fingerprints.is_empty() {
+                continue;
+            }
+
+            let status = if host.key_fingerprint.is_none() {
+                KnownHostsStatus::Imported
+            } else if fingerprints
+                .iter()
+                .all(|fingerprint| host.accepts_key_fingerprint(fingerprint))
+            {
+                KnownHostsStatus::Verified
+            } else if fingerprints
+                .iter()
+                .any(|fingerprint| host.accepts_key_fingerprint(fingerprint))
+            {
+                KnownHostsStatus::Extended
+            } else {
+                KnownHostsStatus::Mismatch
+            };
+            if matches!(
+                status,
+                KnownHostsStatus::Imported | KnownHostsStatus::Extended
+            ) {
+                host.extend_key_fingerprints(&mut conn, &fingerprints)?;
+            }
+
+            results.push(KnownHostsResult {
+                host: host.name,
+                status,
+                fingerprints,
+            });
+        }
+        Ok::<_, String>(results)
+    })
+    .await?;
+
+    Ok(match res {
+        Ok(results) => HttpResponse::Ok().json(KnownHostsResponse {
+            results,
+            invalid_lines,
+        }),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
+
+const fn default_port() -> u16 {
+    22
+}
+
+fn default_transport() -> String {
+    TransportKind::default().to_string()
+}
+
+/// A new host, or the confirmation of a host key returned for an earlier entry
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BulkEntry {
+    Confirm { confirmation: String },
+    Create(CreateHostRequest),
+}
+
+/// A host to create, with the same fields as the add host form
+#[derive(Deserialize)]
+struct CreateHostRequest {
+    name: String,
+    address: String,
+    #[serde(default = "default_port")]
+    port: u16,
+    username: String,
+    /// Without it the host isn't created, the offered host key is returned for confirmation instead
+    key_fingerprint: Option<String>,
+    /// Name of the jump host
+    jump_via: Option<String>,
+    #[serde(default = "default_transport")]
+    transport: String,
+    #[serde(default)]
+    tags: String,
+    proxy: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum BulkOutcome {
+    Created {
+        id: i32,
+        /// The host was added, but the script couldn't be installed
+        #[serde(skip_serializing_if = "Option::is_none")]
+        install_error: Option<String>,
+    },
+    /// Check the fingerprint and send `{"confirmation": "<token>"}` to add the host with this key
+    NeedsConfirmation {
+        key_fingerprint: String,
+        confirmation: String,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+#[derive(Serialize)]
+struct BulkResult {
+    name: String,
+    #[serde(flatten)]
+    outcome: BulkOutcome,
+}
+
+#[derive(Serialize)]
+struct BulkResponse {
+    created: usize,
+    needs_confirmation: usize,
+    failed: usize,
+    results: Vec<BulkResult>,
+}
+
+/// Creates many hosts at once, connecting to at most `max_concurrent_connections` of them at the same time.
+/// Every entry succeeds or fails on its own, results are in the order of the request.
+#[post("/bulk")]
+async fn bulk_create(
+    conn: Data<ConnectionPool>,
+    ssh_client: Data<dyn SshClient>,
+    event_hooks: Data<EventHooks>,
+    config: Data<Configuration>,
+    access: Data<AccessControl>,
+    identity: Identity,
+    entries: Json<Vec<BulkEntry>>,
+) -> actix_web::Result<impl Responder> {
+    let actor = actor(&identity);
+    let results: Vec<BulkResult> = stream::iter(entries.into_inner())
+        .map(|entry| {
+            let (conn, ssh_client, event_hooks) = (&conn, &ssh_client, &event_hooks);
+            let allowed = |name: &str, tags: &str| {
+                if access.may_use_tags(&actor, tags) {
+                    Ok(())
+                } else {
+                    Err(AccessControl::denied(&actor, name).to_string())
+                }
+            };
+            async move {
+                let (name, outcome) = match entry {
+                    BulkEntry::Create(entry) => (
+                        entry.name.clone(),
+                        match allowed(&entry.name, &entry.tags) {
+                            Ok(()) => {
+                                create_host(conn, ssh_client.get_ref(), event_hooks, entry).await
+                            }
+                            Err(error) => Err(error),
+                        },
+                    ),
+                    BulkEntry::Confirm { confirmation } => {
+                        confirm_host(
+                            conn,
+                            ssh_client.get_ref(),
+                            event_hooks,
+                            confirmation,
+                            allowed,
+                        )
+                        .await
+                    }
+                };
+                BulkResult {
+                    name,
+                    outcome: outcome.unwrap_or_else(|error| BulkOutcome::Failed { error }),
+                }
+            }
+        })
+        .buffered(config.ssh.max_concurrent_connections.max(1))
+        .collect()
+        .await;
+
+    let count = |matches: fn(&BulkOutcome) -> bool| {
+        results
+            .iter()
+            .filter(|result| matches(&result.outcome))
+            .count()
+    };
+    Ok(HttpResponse::Ok().json(BulkResponse {
+        created: count(|outcome| matches!(outcome, BulkOutcome::Created { .. })),
+        needs_confirmation: count(|outcome| {
+            matches!(outcome, BulkOutcome::NeedsConfirmation { .. })
+        }),
+        failed: count(|outcome| matches!(outcome, BulkOutcome::Failed { .. })),
+        results,
+    }))
+}
+
+/// Same steps as adding a host through the form: fetch the host key, or authenticate with the given one and add the host
+async fn create_host(
+    conn: &ConnectionPool,
+    ssh_client: &dyn SshClient,
+    event_hooks: &EventHooks,
+    entry: CreateHostRequest,
+) -> Result<BulkOutcome, String> {
+    TransportKind::from_str(&entry.transport)?;
+    let proxy = entry.proxy.filter(|proxy| !proxy.trim().is_empty());
+    let mut address = ConnectionDetails::new(entry.address.clone(), entry.port);
+    address.proxy = proxy.as_deref().map(Proxy::from_str).transpose()?;
+
+    if Host::get_from_name(conn.get().unwrap(), entry.name.clone())
+        .await?
+        .is_some()
+    {
+        return Err(String::from("A host with this name already exists"));
+    }
+    let jump_host = match entry.jump_via {
+        Some(via) => Some(
+            Host::get_from_name(conn.get().unwrap(), via.clone())
+                .await?
+                .ok_or_else(|| format!("Jump host {via} doesn't exist"))?,
+        ),
+        None => None,
+    };
+
+    let Some(key_fingerprint) = entry.key_fingerprint else {
+        let jump_via = jump_host.as_ref().map(|host| host.id);
+        let key_receiver = match jump_host {
+            Some(via) => ssh_client.get_hostkey_via(via, address).await,
+            None => ssh_client.get_hostkey(address).await,
+        }
+        .map_err(|e| e.to_string())?;
+        let key_fingerprint = timing::block(move || key_receiver.recv())
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|_| String::from("Connection timed out"))?;
+
+        let pending = NewHost {
+            name: entry.name,
+            address: entry.address,
+            port: entry.port,
+            username: entry.username,
+            key_fingerprint: key_fingerprint.clone(),
+            jump_via,
+            transport: entry.transport,
+            tags: Host::normalize_tags(&entry.tags),
+            proxy,
+        };
+        let pool = conn.clone();
+        let confirmation =
+            timing::block(move || PendingHost::create(&mut pool.get().unwrap(), pending))
+                .await
+                .map_err(|e| e.to_string())??;
+        return Ok(BulkOutcome::NeedsConfirmation {
+            key_fingerprint,
+            confirmation,
+        });
+    };
+
+    let new_host = NewHost {
+        name: entry.name,
+        address: entry.address,
+        port: entry.port,
+        username: entry.username,
+        key_fingerprint,
+        jump_via: jump_host.as_ref().map(|host| host.id),
+        transport: entry.transport,
+        tags: Host::normalize_tags(&entry.tags),
+        proxy,
+    };
+    let id =
+        add_confirmed_host(conn, ssh_client, event_hooks, jump_host, address, new_host).await?;
+    Ok(created(ssh_client, id).await)
+}
+
+/// Adds a pending host with the host key it offered, returns the name of the host and the outcome
+async fn confirm_host(
+    conn: &ConnectionPool,
+    ssh_client: &dyn SshClient,
+    event_hooks: &EventHooks,
+    token: String,
+    allowed: impl Fn(&str, &str) -> Result<(), String>,
+) -> (String, Result<BulkOutcome, String>) {
+    let pool = conn.clone();
+    let lookup = token.clone();
+    let pending =
+        match timing::block(move || PendingHost::get(&mut pool.get().unwrap(), &lookup)).await {
+            Ok(Ok(Some(pending))) => pending,
+            Ok(Ok(None)) => {
+                return (
+                    token,
+                    Err(String::from(
+                        "Unknown or expired confirmation, add the host again",
+                    )),
+                )
+            }
+            Ok(Err(e)) => return (token, Err(e)),
+            Err(e) => return (token, Err(e.to_string())),
+        };
+    let name = pending.name.clone();
+    if let Err(error) = allowed(&pending.name, &pending.tags) {
+        return (name, Err(error));
+    }
+
+    let outcome = async {
+        let (jump_host, address) = pending.connection(conn).await?;
+        let id = add_confirmed_host(
+            conn,
+            ssh_client,
+            event_hooks,
+            jump_host,
+            address,
+            pending.into(),
+        )
+        .await?;
+        let pool = conn.clone();
+        if let Ok(Err(e)) =
+            timing::block(move || PendingHost::delete(&mut pool.get().unwrap(), &token)).await
+        {
+            log::warn!("Failed to remove pending host: {e}");
+        }
+        Ok(created(ssh_client, id).await)
+    }
+    .await;
+    (name, outcome)
+}
+
+/// Installs the script on a newly created host
+async fn created(ssh_client: &dyn SshClient, id: i32) -> BulkOutcome {
+    let install_error = ssh_client
+        .install_script_on_host(id)
+        .await
+        .err()
+        .map(|e| e.to_string());
+    BulkOutcome::Created { id, install_error }
+}
+
+/// Where the sshd of a host looks for keys, with warnings if that makes managing its
+/// authorized_keys files ineffective, e.g. because of an AuthorizedKeysCommand
+#[get("/{name}/key_sources")]
+async fn key_sources(
+    conn: Data<ConnectionPool>,
+    ssh_client: Data<dyn SshClient>,
+    name: Path<String>,
+) -> actix_web::Result<impl Responder> {
+    let host = match Host::get_from_name(conn.get().unwrap(), name.into_inner()).await {
+        Ok(Some(host)) => host,
+        Ok(None) => {
+            return Ok(message_response(
+                StatusCode::NOT_FOUND,
+                &Message::new("host.not_found"),
+            ))
+        }
+        Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+    };
+
+    Ok(match sshd::key_sources(ssh_client.get_ref(), host).await {
+        Ok(sources) => HttpResponse::Ok().json(sources),
+        Err(error) => error_response(StatusCode::BAD_GATEWAY, error.to_string()),
+    })
+}
+
+/// When the logins and keys of a host were last used according to its sshd logs, with the users
+/// owning the keys
+#[get("/{name}/key_usage")]
+async fn key_usage(
+    conn: Data<ConnectionPool>,
+    ssh_client: Data<dyn SshClient>,
+    config: Data<Configuration>,
+    name: Path<String>,
+) -> actix_web::Result<impl Responder> {
+    if !config.key_usage.enabled {
+        return Ok(message_response(
+            StatusCode::NOT_FOUND,
+            &Message::new("key.usage_disabled"),
+        ));
+    }
+    let host = match Host::get_from_name(conn.get().unwrap(), name.into_inner()).await {
+        Ok(Some(host)) => host,
+        Ok(None) => {
+            return Ok(message_response(
+                StatusCode::NOT_FOUND,
+                &Message::new("host.not_found"),
+            ))
+        }
+        Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+    };
+    let keys = match timing::block(move || {
+        PublicUserKey::get_all_keys_with_username(&mut conn.get().unwrap())
+    })
+    .await?
+    {
+        Ok(keys) => keys,
+        Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+    };
+
+    Ok(match host_usage(ssh_client.get_ref(), host).await {
+        Ok(mut usage) => {
+            usage.assign_owners(&keys);
+            HttpResponse::Ok().json(usage)
+        }
+        Err(error) => error_response(StatusCode::BAD_GATEWAY, error.to_string()),
+    })
+}
+
+#[derive(Deserialize)]
+struct PreviewQuery {
+    login: String,
+}
+
+#[derive(Serialize)]
+struct DeployPreview {
+    login: String,
+    /// The authorized_keys file on the host
+    current: String,
+    /// The file a deployment would write
+    generated: String,
+    /// Unified diff from `current` to `generated`, empty if they are the same
+    diff: String,
+    /// The deployment leaves the login without a key besides the one of SSM
+    lockout: bool,
+    /// The deployment removes the key SSM connects to the host with
+    ssm_lockout: bool,
+}
+
+/// The authorized_keys file of a login before and after a deployment, without deploying
+#[get("/{name}/preview")]
+async fn preview(
+    conn: Data<ConnectionPool>,
+    ssh_client: Data<dyn SshClient>,
+    config: Data<Configuration>,
+    name: Path<String>,
+    params: Query<PreviewQuery>,
+) -> actix_web::Result<impl Responder> {
+    let login = params.into_inner().login;
+    let host = match Host::get_from_name(conn.get().unwrap(), name.into_inner()).await {
+        Ok(Some(host)) => host,
+        Ok(None) => {
+            return Ok(message_response(
+                StatusCode::NOT_FOUND,
+                &Message::new("host.not_found"),
+            ))
+        }
+        Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+    };
+
+    let current = match ssh_client
+        .get_authorized_keyfile(host.clone(), &login)
+        .await
+    {
+        Ok(current) => current,
+        Err(error) => return Ok(error_response(StatusCode::BAD_GATEWAY, error.to_string())),
+    };
+    let generated = {
+        let (host, login, ssh_client) = (host.clone(), login.clone(), ssh_client.clone());
+        timing::block(move || {
+            host.get_authorized_keys_file_for(
+                ssh_client.as_ref(),
+                &mut conn.get().unwrap(),
+                &login,
+                &config.key_limits,
+            )
+        })
+        .await?
+    };
+    let authorized_keys = match generated {
+        Ok(generated) => host.keep_tolerated_keys(generated, &current),
+        Err(error) => return Ok(error_response(StatusCode::UNPROCESSABLE_ENTITY, error)),
+    };
+
+    let own_key = ssh_client.get_own_key_b64();
+    let generated = managed_keyfile(&authorized_keys);
+    let diff = similar::TextDiff::from_lines(&current, &generated)
+        .unified_diff()
+        .header("current", "generated")
+        .to_string();
+    Ok(HttpResponse::Ok().json(DeployPreview {
+        lockout: locks_out(&current, &authorized_keys, &own_key),
+        ssm_lockout: locks_out_ssm(&host, &login, &authorized_keys, &own_key),
+        login,
+        current,
+        generated,
+        diff,
+    }))
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum KeyfileSource {
+    /// Read from the host now
+    #[default]
+    Remote,
+    /// Rebuilt from the cached entries the diff uses, without contacting the host
+    Cached,
+    /// The file a deployment would write
+    Generated,
+}
+
+#[derive(Deserialize)]
+struct KeyfileQuery {
+    login: String,
+    #[serde(default)]
+    source: KeyfileSource,
+}
+
+/// The raw authorized_keys file of a login, for tracking down differences between the host,
+/// the cache and the generated file
+#[get("/{name}/keyfile")]
+async fn keyfile(
+    conn: Data<ConnectionPool>,
+    ssh_client: Data<dyn SshClient>,
+    caching_ssh_client: Data<dyn HostCache>,
+    config: Data<Configuration>,
+    name: Path<String>,
+    params: Query<KeyfileQuery>,
+) -> actix_web::Result<impl Responder> {
+    let KeyfileQuery { login, source } = params.into_inner();
+    let host = match Host::get_from_name(conn.get().unwrap(), name.into_inner()).await {
+        Ok(Some(host)) => host,
+        Ok(None) => {
+            return Ok(message_response(
+                StatusCode::NOT_FOUND,
+                &Message::new("host.not_found"),
+            ))
+        }
+        Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+    };
+
+    let keyfile = match source {
+        KeyfileSource::Remote => {
+            match ssh_client
+                .get_authorized_keyfile(host.clone(), &login)
+                .await
+            {
+                Ok(keyfile) => keyfile,
+                Err(error) => {
+                    return Ok(error_response(StatusCode::BAD_GATEWAY, error.to_string()))
+                }
+            }
+        }
+        KeyfileSource::Cached => {
+            let logins = match caching_ssh_client.get_cached(&host.name).await {
+                Some((_, Ok(logins))) => logins,
+                Some((_, Err(error))) => {
+                    return Ok(error_response(StatusCode::BAD_GATEWAY, error.to_string()))
+                }
+                None => {
+                    return Ok(message_response(
+                        StatusCode::NOT_FOUND,
+                        &Message::new("keyfile.not_cached").arg(host.name),
+                    ))
+                }
+            };
+            match logins.iter().find(|(cached, _, _, _)| cached == &login) {
+                Some((_, has_pragma, entries, _)) => keyfile_from_entries(*has_pragma, entries),
+                None => {
+                    return Ok(message_response(
+                        StatusCode::NOT_FOUND,
+                        &Message::new("keyfile.login_not_found")
+                            .arg(host.name)
+                            .arg(login),
+                    ))
+                }
+            }
+        }
+        KeyfileSource::Generated => {
+            let generated = {
+                let (host, login, ssh_client) = (host.clone(), login.clone(), ssh_client.clone());
+                timing::block(move || {
+                    host.get_authorized_keys_file_for(
+                        ssh_client.as_ref(),
+                        &mut conn.get().unwrap(),
+                        &login,
+                        &config.key_limits,
+                    )
+                })
+                .await?
+            };
+            let generated = match generated {
+                Ok(generated) => generated,
+                Err(error) => return Ok(error_response(StatusCode::UNPROCESSABLE_ENTITY, error)),
+            };
+            // Tolerated keys are taken over from the current file
+            let authorized_keys = if host.tolerated_keys.is_empty() {
+                generated
+            } else {
+                match ssh_client
+                    .get_authorized_keyfile(host.clone(), &login)
+                    .await
+                {
+                    Ok(current) => host.keep_tolerated_keys(generated, &current),
+                    Err(error) => {
+                        return Ok(error_response(StatusCode::BAD_GATEWAY, error.to_string()))
+                    }
+                }
+            };
+            managed_keyfile(&authorized_keys)
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .body(keyfile))
+}
+
+#[derive(Deserialize)]
+struct FailedLoginsQuery {
+    /// Hours to look back (default `failed_logins.hours` of the configuration)
+    hours: Option<u32>,
+}
+
+/// Failed logins on a host according to its sshd logs, per login and source address
+#[get("/{name}/failed_logins")]
+async fn failed_logins(
+    conn: Data<ConnectionPool>,
+    ssh_client: Data<dyn SshClient>,
+    config: Data<Configuration>,
+    name: Path<String>,
+    params: Query<FailedLoginsQuery>,
+) -> actix_web::Result<impl Responder> {
+    if !config.failed_logins.enabled {
+        return Ok(message_response(
+            StatusCode::NOT_FOUND,
+            &Message::new("failed_logins.disabled"),
+        ));
+    }
+    let host = match Host::get_from_name(conn.get().unwrap(), name.into_inner()).await {
+        Ok(Some(host)) => host,
+        Ok(None) => {
+            return Ok(message_response(
+                StatusCode::NOT_FOUND,
+                &Message::new("host.not_found"),
+            ))
+        }
+        Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+    };
+    let hours = params.hours.unwrap_or(config.failed_logins.hours).max(1);
+
+    Ok(
+        match host_failed_logins(ssh_client.get_ref(), host, hours).await {
+            Ok(failed) => HttpResponse::Ok().json(failed),
+            Err(error) => error_response(StatusCode::BAD_GATEWAY, error.to_string()),
+        },
+    )
+}
+
+/// The managed sshd_config directives of a host, compared with the expected values
+#[get("/{name}/sshd_config")]
+async fn sshd_config(
+    conn: Data<ConnectionPool>,
+    ssh_client: Data<dyn SshClient>,
+    config: Data<Configuration>,
+    name: Path<String>,
+) -> actix_web::Result<impl Responder> {
+    if !config.sshd_config.enabled {
+        return Ok(message_response(
+            StatusCode::NOT_FOUND,
+            &Message::new("sshd_config.read_disabled"),
+        ));
+    }
+    let host = match Host::get_from_name(conn.get().unwrap(), name.into_inner()).await {
+        Ok(Some(host)) => host,
+        Ok(None) => {
+            return Ok(message_response(
+                StatusCode::NOT_FOUND,
+                &Message::new("host.not_found"),
+            ))
+        }
+        Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+    };
+
+    Ok(
+        match host_report(ssh_client.get_ref(), &config.sshd_config, host).await {
+            Ok(report) => HttpResponse::Ok().json(report),
+            Err(error) => error_response(StatusCode::BAD_GATEWAY, error.to_string()),
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct SshdConfigChange {
+    directives: BTreeMap<String, String>,
+    /// From the preview, applies the change
+    confirm_token: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SshdConfigPreview {
+    confirm_token: String,
+    /// Lines of sshd_config the change removes and adds
+    removed: Vec<String>,
+    added: Vec<String>,
+}
+
+/// Changes sshd_config directives of a host. Without `confirm_token` only the preview of the change
+/// is returned with a token, sending the same change with the token applies it.
+#[put("/{name}/sshd_config")]
+#[allow(clippy::too_many_arguments)]
+async fn change_sshd_config(
+    conn: Data<ConnectionPool>,
+    ssh_client: Data<dyn SshClient>,
+    config: Data<Configuration>,
+    access: Data<AccessControl>,
+    identity: Identity,
+    name: Path<String>,
+    change: Json<SshdConfigChange>,
+) -> actix_web::Result<impl Responder> {
+    let policy = &config.sshd_config;
+    if !policy.enabled || !policy.allow_changes {
+        return Ok(message_response(
+            StatusCode::FORBIDDEN,
+            &Message::new("sshd_config.change_disabled"),
+        ));
+    }
+    let SshdConfigChange {
+        directives,
+        confirm_token,
+    } = change.into_inner();
+    let mut changes = BTreeMap::new();
+    for (directive, value) in directives {
+        let value = value.trim().to_owned();
+        let checked = sshd::directive_name(&directive)
+            .ok_or_else(|| format!("{directive} isn't a managed sshd_config directive"))
+            .and_then(|name| sshd::check_value(name, &value).map(|()| name));
+        match checked {
+            Ok(name) => {
+                changes.insert(name, value);
+            }
+            Err(error) => return Ok(error_response(StatusCode::BAD_REQUEST, error)),
+        }
+    }
+    if changes.is_empty() {
+        return Ok(message_response(
+            StatusCode::BAD_REQUEST,
+            &Message::new("sshd_config.no_directives"),
+        ));
+    }
+
+    let actor = actor(&identity);
+    let host = match Host::get_from_name(conn.get().unwrap(), name.into_inner()).await {
+        Ok(Some(host)) if !access.may_change_host(&actor, &host) => {
+            return Ok(message_response(
+                StatusCode::FORBIDDEN,
+                &AccessControl::denied(&actor, &host.name),
+            ))
+        }
+        Ok(Some(host)) => host,
+        Ok(None) => {
+            return Ok(message_response(
+                StatusCode::NOT_FOUND,
+                &Message::new("host.not_found"),
+            ))
+        }
+        Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+    };
+
+    let current = match ssh_client.get_sshd_config(host.clone()).await {
+        Ok(current) => current,
+        Err(error) => return Ok(error_response(StatusCode::BAD_GATEWAY, error.to_string())),
+    };
+    let new = sshd::set_directives(&current, &changes);
+
+    let Some(token) = confirm_token else {
+        let diff = similar::TextDiff::from_lines(&current, &new);
+        let lines = |tag| {
+            diff.iter_all_changes()
+                .filter(|change| change.tag() == tag)
+                .map(|change| change.value().trim_end().to_owned())
+                .collect()
+        };
+        let (removed, added) = (
+            lines(similar::ChangeTag::Delete),
+            lines(similar::ChangeTag::Insert),
+        );
+        let host_id = host.id;
+        let token = timing::block(move || {
+            HostConfirmation::create(
+                &mut conn.get().unwrap(),
+                host_id,
+                SSHD_CONFIG,
+                None,
+                Some(new),
+            )
+        })
+        .await?;
+        return Ok(match token {
+            Ok(confirm_token) => HttpResponse::Accepted().json(SshdConfigPreview {
+                confirm_token,
+                removed,
+                added,
+            }),
+            Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+        });
+    };
+
+    // The token only confirms the file that was previewed, so it fails if sshd_config changed since
+    let (host_id, previewed) = (host.id, new.clone());
+    let confirmed = timing::block(move || {
+        HostConfirmation::redeem(
+            &mut conn.get().unwrap(),
+            &token,
+            host_id,
+            SSHD_CONFIG,
+            None,
+            Some(&previewed),
+        )
+    })
+    .await?;
+    match confirmed {
+        Ok(true) => {}
+        Ok(false) => {
+            return Ok(message_response(
+                StatusCode::CONFLICT,
+                &Message::new("confirmation.invalid"),
+            ))
+        }
+        Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+    }
+
+    Ok(match ssh_client.set_sshd_config(host, new.clone()).await {
+        Ok(()) => HttpResponse::Ok().json(policy.report(&new)),
+        Err(error) => error_response(StatusCode::BAD_GATEWAY, error.to_string()),
+    })
+}
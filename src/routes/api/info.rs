@@ -0,0 +1,36 @@
+use actix_web::{
+    get,
+    web::{self, Data},
+    HttpResponse, Responder,
+};
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::update::{UpdateCheck, UpdateNotice, CURRENT_VERSION};
+
+pub fn info_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(info);
+}
+
+#[derive(Serialize)]
+struct InfoResponse {
+    version: &'static str,
+    /// Whether newer releases are looked for
+    update_check: bool,
+    /// Last successful check for a newer release
+    #[serde(with = "time::serde::rfc3339::option")]
+    update_checked_at: Option<OffsetDateTime>,
+    /// Newer release, if the last check found one
+    update: Option<UpdateNotice>,
+}
+
+/// The running version and whether a newer one is available
+#[get("")]
+async fn info(update_check: Data<UpdateCheck>) -> impl Responder {
+    HttpResponse::Ok().json(InfoResponse {
+        version: CURRENT_VERSION,
+        update_check: update_check.enabled(),
+        update_checked_at: update_check.checked_at(),
+        update: update_check.notice(),
+    })
+}
@@ -0,0 +1,174 @@
+use actix_web::{
+    get,
+    http::StatusCode,
+    post,
+    web::{self, Data, Json, Path, Query},
+    HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    integration::{self, InventoryHost, Platform},
+    models::{Host, User},
+    timing, ConnectionPool,
+};
+
+use super::error_response;
+
+pub fn integration_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(export).service(import);
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    /// Only hosts with this tag
+    tag: Option<String>,
+    /// Roles of the exported Teleport users, comma separated (default access)
+    roles: Option<String>,
+    /// Catalog the Boundary hosts are created in, required for Boundary
+    host_catalog_id: Option<String>,
+}
+
+/// The hosts, and for Teleport the users with their logins, as resources of the platform
+#[get("/{platform}/export")]
+async fn export(
+    conn: Data<ConnectionPool>,
+    platform: Path<String>,
+    params: Query<ExportQuery>,
+) -> actix_web::Result<impl Responder> {
+    let platform = match platform.parse::<Platform>() {
+        Ok(platform) => platform,
+        Err(error) => return Ok(error_response(StatusCode::NOT_FOUND, error)),
+    };
+    let params = params.into_inner();
+    let host_catalog_id = match (platform, params.host_catalog_id) {
+        (Platform::Boundary, None) => {
+            return Ok(error_response(
+                StatusCode::BAD_REQUEST,
+                String::from("host_catalog_id is required for Boundary"),
+            ))
+        }
+        (_, host_catalog_id) => host_catalog_id.unwrap_or_default(),
+    };
+
+    let res = timing::block(move || {
+        let mut conn = conn.get().unwrap();
+        let mut hosts = Host::get_all_hosts(&mut conn)?;
+        if let Some(tag) = params.tag {
+            hosts.retain(|host| host.has_tag(&tag));
+        }
+        let logins = match platform {
+            Platform::Teleport => User::logins(&mut conn)?,
+            Platform::Boundary => Vec::new(),
+        };
+        Ok::<_, String>((hosts, logins))
+    })
+    .await?;
+
+    Ok(match res {
+        Ok((hosts, logins)) => match platform {
+            Platform::Teleport => {
+                let roles = params.roles.as_deref().unwrap_or("access");
+                let roles: Vec<&str> = roles
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|role| !role.is_empty())
+                    .collect();
+                HttpResponse::Ok()
+                    .content_type("application/yaml")
+                    .body(integration::teleport_resources(&hosts, &logins, &roles))
+            }
+            Platform::Boundary => {
+                HttpResponse::Ok().json(integration::boundary_hosts(&hosts, &host_catalog_id))
+            }
+        },
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
+
+#[derive(Deserialize)]
+struct ImportQuery {
+    /// Login SSM uses on the new hosts (default root)
+    login: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ImportedHost {
+    #[serde(flatten)]
+    host: InventoryHost,
+    /// The SSM host with this name, alias or address
+    ssm_host: Option<String>,
+}
+
+/// A host that isn't in SSM yet, as an entry for /api/host/bulk
+#[derive(Serialize)]
+struct BulkHost {
+    name: String,
+    address: String,
+    port: u16,
+    username: String,
+    tags: String,
+}
+
+#[derive(Serialize)]
+struct ImportResponse {
+    hosts: Vec<ImportedHost>,
+    bulk: Vec<BulkHost>,
+}
+
+/// Compares the inventory of a platform with the hosts of SSM. Nothing is changed, the hosts that
+/// are missing are returned ready to be created through /api/host/bulk.
+#[post("/{platform}/import")]
+async fn import(
+    conn: Data<ConnectionPool>,
+    platform: Path<String>,
+    params: Query<ImportQuery>,
+    inventory: Json<Value>,
+) -> actix_web::Result<impl Responder> {
+    let platform = match platform.parse::<Platform>() {
+        Ok(platform) => platform,
+        Err(error) => return Ok(error_response(StatusCode::NOT_FOUND, error)),
+    };
+    let inventory = match integration::parse_inventory(platform, &inventory) {
+        Ok(inventory) => inventory,
+        Err(error) => return Ok(error_response(StatusCode::BAD_REQUEST, error)),
+    };
+    let login = params
+        .into_inner()
+        .login
+        .unwrap_or_else(|| "root".to_owned());
+
+    let ssm_hosts =
+        match timing::block(move || Host::get_all_hosts(&mut conn.get().unwrap())).await? {
+            Ok(hosts) => hosts,
+            Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+        };
+
+    let mut bulk: Vec<BulkHost> = Vec::new();
+    let hosts = inventory
+        .into_iter()
+        .map(|host| {
+            let ssm_host = ssm_hosts
+                .iter()
+                .find(|ssm_host| {
+                    ssm_host.name == host.name
+                        || ssm_host.alias_list().any(|alias| alias == host.name)
+                        || (ssm_host.address == host.address && ssm_host.port == host.port)
+                })
+                .map(|ssm_host| ssm_host.name.clone());
+            if ssm_host.is_none() && !bulk.iter().any(|entry| entry.name == host.name) {
+                bulk.push(BulkHost {
+                    name: host.name.clone(),
+                    address: host.address.clone(),
+                    port: host.port,
+                    username: login.clone(),
+                    tags: host.tags.clone(),
+                });
+            }
+            ImportedHost { host, ssm_host }
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(ImportResponse { hosts, bulk }))
+}
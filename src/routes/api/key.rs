@@ -0,0 +1,428 @@
+use actix_identity::Identity;
+use actix_web::{
+    get,
+    http::StatusCode,
+    post,
+    web::{self, Data, Json, Path},
+    HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+use time::PrimitiveDateTime;
+
+use crate::{
+    db::{
+        history,
+        key_batch::{BatchKey, KeyBatch, KeyFilter},
+    },
+    i18n::Message,
+    models::{KeyHistory, NewPublicUserKey, PublicUserKey, User},
+    routes::actor,
+    scheduler::{KeyRemoval, RemovalStatus, Scheduler},
+    ssh::{parse_public_key, KeyFormats},
+    timing, ConnectionPool,
+};
+
+use super::{audit::parse_timestamp, error_response, message_response};
+
+pub fn key_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(add_key)
+        .service(batch_delete_keys)
+        .service(batch_reassign_keys)
+        .service(convert_key)
+        .service(key_history)
+        .service(key_removals)
+        .service(transfer_key);
+}
+
+#[derive(Deserialize)]
+struct KeyUpload {
+    /// Owner of the key
+    username: String,
+    /// An OpenSSH public key line or a key in the RFC4716 format
+    key: String,
+    /// Replaces the comment of the key
+    comment: Option<String>,
+}
+
+#[derive(Serialize)]
+struct KeyResponse {
+    id: i32,
+    username: String,
+    key_type: String,
+    key_base64: String,
+    comment: Option<String>,
+    fingerprint: Option<String>,
+    /// The key lives on a FIDO2 security key
+    security_key: bool,
+    /// Options the pasted line had, they were not stored with the key but can be
+    /// set on its authorizations
+    suggested_options: Option<String>,
+}
+
+/// Adds a pasted public key to a user. The algorithm, key and comment are taken from the key,
+/// options of an authorized_keys line are returned as `suggested_options` instead of being stored.
+#[post("")]
+async fn add_key(
+    conn: Data<ConnectionPool>,
+    identity: Identity,
+    upload: Json<KeyUpload>,
+) -> actix_web::Result<impl Responder> {
+    let KeyUpload {
+        username,
+        key,
+        comment,
+    } = upload.into_inner();
+    let mut key = match parse_public_key(&key) {
+        Ok(key) => key,
+        Err(error) => return Ok(error_response(StatusCode::BAD_REQUEST, error)),
+    };
+    if let Some(comment) = comment {
+        key.comment = Some(comment.trim().to_owned()).filter(|comment| !comment.is_empty());
+    }
+    let fingerprint = key.fingerprint();
+    let suggested_options =
+        Some(key.options.as_str().to_owned()).filter(|options| !options.is_empty());
+    let actor = actor(&identity);
+
+    let res = timing::block(move || {
+        let mut conn = conn.get().unwrap();
+        let Some(user) = User::find_user(&mut conn, &username)? else {
+            return Ok(Err((
+                StatusCode::NOT_FOUND,
+                Message::new("user.not_found").arg(username),
+            )));
+        };
+        if let Some((owner, _)) = PublicUserKey::find_by_base64(&mut conn, &key.base64)? {
+            return Ok(Err((
+                StatusCode::CONFLICT,
+                Message::new("key.already_owned").arg(owner),
+            )));
+        }
+        let new_key =
+            NewPublicUserKey::new(key.algorithm, key.base64.clone(), key.comment, user.id);
+        PublicUserKey::add_key(&mut conn, new_key, &actor)?;
+        PublicUserKey::find_by_base64(&mut conn, &key.base64).map(Ok)
+    })
+    .await?;
+
+    Ok(match res {
+        Ok(Ok(Some((username, key)))) => HttpResponse::Created().json(KeyResponse {
+            id: key.id,
+            username,
+            security_key: key.is_security_key(),
+            key_type: key.key_type,
+            key_base64: key.key_base64,
+            comment: key.comment,
+            fingerprint,
+            suggested_options,
+        }),
+        Ok(Ok(None)) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            String::from("Added key not found"),
+        ),
+        Ok(Err((status, error))) => message_response(status, &error),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
+
+#[derive(Deserialize)]
+struct KeyConversion {
+    /// An OpenSSH public key line, a key in the RFC4716 format or an RSA key as PEM
+    key: String,
+}
+
+/// A pasted public key in the OpenSSH, RFC4716 and PEM formats, with its size and fingerprints.
+/// Nothing is stored.
+#[post("/convert")]
+async fn convert_key(conversion: Json<KeyConversion>) -> impl Responder {
+    match parse_public_key(&conversion.key).and_then(|key| KeyFormats::new(&key)) {
+        Ok(formats) => HttpResponse::Ok().json(formats),
+        Err(error) => error_response(StatusCode::BAD_REQUEST, error),
+    }
+}
+
+#[derive(Serialize)]
+struct KeyHistoryResponse {
+    key_id: i32,
+    key_base64: String,
+    /// Last time the key was written to any host
+    #[serde(with = "crate::db::utc_rfc3339::option")]
+    last_deployed_at: Option<PrimitiveDateTime>,
+    history: Vec<KeyHistory>,
+}
+
+/// When a key was added and deleted, and on which hosts it was deployed or removed, oldest first.
+/// Still available after the key was deleted.
+#[get("/{id}/history")]
+async fn key_history(
+    conn: Data<ConnectionPool>,
+    id: Path<i32>,
+) -> actix_web::Result<impl Responder> {
+    let key_id = id.into_inner();
+    let res = timing::block(move || KeyHistory::for_key(&mut conn.get().unwrap(), key_id)).await?;
+
+    Ok(match res {
+        Ok(None) => message_response(StatusCode::NOT_FOUND, &Message::new("key.no_history")),
+        Ok(Some((key_base64, history))) => HttpResponse::Ok().json(KeyHistoryResponse {
+            key_id,
+            key_base64,
+            last_deployed_at: history
+                .iter()
+                .rev()
+                .find(|entry| entry.action.eq(history::DEPLOYED))
+                .map(|entry| entry.changed_at),
+            history,
+        }),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
+
+#[derive(Deserialize)]
+struct KeyTransfer {
+    /// Username of the new owner
+    to: String,
+}
+
+/// Moves a key to another user, e.g. when a contractor becomes an employee, keeping its history.
+/// The key follows the authorizations of its new owner from the next deployment on.
+#[post("/{id}/transfer")]
+async fn transfer_key(
+    conn: Data<ConnectionPool>,
+    identity: Identity,
+    id: Path<i32>,
+    transfer: Json<KeyTransfer>,
+) -> actix_web::Result<impl Responder> {
+    let key_id = id.into_inner();
+    let to = transfer.into_inner().to;
+    let actor = actor(&identity);
+
+    let res = timing::block(move || {
+        let mut conn = conn.get().unwrap();
+        let Some((owner, _)) = PublicUserKey::get_with_username(&mut conn, key_id)? else {
+            return Ok(Err((StatusCode::NOT_FOUND, Message::new("key.not_found"))));
+        };
+        let Some(user) = User::find_user(&mut conn, &to)? else {
+            return Ok(Err((
+                StatusCode::BAD_REQUEST,
+                Message::new("user.not_found").arg(to),
+            )));
+        };
+        if owner.eq(&user.username) {
+            return Ok(Err((
+                StatusCode::BAD_REQUEST,
+                Message::new("key.already_owned").arg(owner),
+            )));
+        }
+        PublicUserKey::transfer(&mut conn, key_id, user.id, &actor)?;
+        KeyHistory::for_key(&mut conn, key_id).map(|history| Ok(history.map(|(_, h)| h)))
+    })
+    .await?;
+
+    Ok(match res {
+        Ok(Ok(history)) => {
+            HttpResponse::Ok().json(history.and_then(|history| history.last().cloned()))
+        }
+        Ok(Err((status, error))) => message_response(status, &error),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
+
+#[derive(Deserialize)]
+struct BatchFilter {
+    /// Owner of the keys
+    username: Option<String>,
+    /// Pattern for the comment, `*` matches any text and `?` a single character
+    comment: Option<String>,
+    /// Keys added before this time, RFC 3339 or a plain date
+    created_before: Option<String>,
+}
+
+impl TryFrom<BatchFilter> for KeyFilter {
+    type Error = String;
+
+    fn try_from(filter: BatchFilter) -> Result<Self, Self::Error> {
+        Ok(Self {
+            username: filter.username,
+            comment: filter.comment,
+            created_before: filter
+                .created_before
+                .as_deref()
+                .map(parse_timestamp)
+                .transpose()?,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct BatchDelete {
+    #[serde(flatten)]
+    filter: BatchFilter,
+    /// Only report which keys would be deleted
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// A key removal and how many of its logins got how far
+#[derive(Serialize)]
+struct RemovalSummary {
+    #[serde(flatten)]
+    removal: KeyRemoval,
+    queued: usize,
+    removed: usize,
+    failed: usize,
+    skipped: usize,
+}
+
+impl From<KeyRemoval> for RemovalSummary {
+    fn from(removal: KeyRemoval) -> Self {
+        Self {
+            queued: removal.count(RemovalStatus::Queued),
+            removed: removal.count(RemovalStatus::Removed),
+            failed: removal.count(RemovalStatus::Failed),
+            skipped: removal.count(RemovalStatus::Skipped),
+            removal,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BatchDeleted {
+    #[serde(flatten)]
+    batch: KeyBatch,
+    /// The logins the keys are removed from, missing for dry runs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    removal: Option<RemovalSummary>,
+}
+
+/// Deletes all keys matching a filter in one transaction, e.g. every key with the comment
+/// `*@old-laptop` or every key added before 2020. At least one criterion is required.
+/// The logins the keys were deployed to are queued for the deploy job, which removes them.
+#[post("/batch/delete")]
+async fn batch_delete_keys(
+    conn: Data<ConnectionPool>,
+    scheduler: Data<Scheduler>,
+    identity: Identity,
+    request: Json<BatchDelete>,
+) -> actix_web::Result<impl Responder> {
+    let BatchDelete { filter, dry_run } = request.into_inner();
+    let filter = match KeyFilter::try_from(filter) {
+        Ok(filter) if filter.is_empty() => {
+            return Ok(message_response(
+                StatusCode::BAD_REQUEST,
+                &Message::new("key.batch_filter_required"),
+            ))
+        }
+        Ok(filter) => filter,
+        Err(error) => return Ok(error_response(StatusCode::BAD_REQUEST, error)),
+    };
+    let actor = actor(&identity);
+
+    let res = {
+        let actor = actor.clone();
+        timing::block(move || {
+            let mut conn = conn.get().unwrap();
+            let batch = KeyBatch::delete(&mut conn, &filter, &actor, dry_run)?;
+            let logins = if batch.applied {
+                PublicUserKey::deployed_to(&mut conn, &batch.usernames())?
+            } else {
+                Vec::new()
+            };
+            Ok::<_, String>((batch, logins))
+        })
+        .await?
+    };
+
+    Ok(match res {
+        Ok((batch, logins)) => {
+            let removal = batch.applied.then(|| {
+                let keys = batch
+                    .keys
+                    .iter()
+                    .filter_map(BatchKey::fingerprint)
+                    .collect();
+                scheduler
+                    .into_inner()
+                    .queue_key_removal(keys, logins, &actor)
+                    .into()
+            });
+            HttpResponse::Ok().json(BatchDeleted { batch, removal })
+        }
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
+
+/// The latest deleted keys and the logins the deploy job removed them from, newest first
+#[get("/removals")]
+async fn key_removals(scheduler: Data<Scheduler>) -> impl Responder {
+    let removals: Vec<RemovalSummary> = scheduler
+        .key_removals()
+        .into_iter()
+        .map(RemovalSummary::from)
+        .collect();
+    HttpResponse::Ok().json(removals)
+}
+
+#[derive(Deserialize)]
+struct BatchReassign {
+    /// Username of the current owner
+    from: String,
+    /// Username of the new owner
+    to: String,
+    /// Pattern for the comment, `*` matches any text and `?` a single character
+    comment: Option<String>,
+    /// Keys added before this time, RFC 3339 or a plain date
+    created_before: Option<String>,
+    /// Only report which keys would be moved
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Moves the keys of one user matching a filter to another user in one transaction,
+/// keeping their history like a single transfer.
+#[post("/batch/reassign")]
+async fn batch_reassign_keys(
+    conn: Data<ConnectionPool>,
+    identity: Identity,
+    request: Json<BatchReassign>,
+) -> actix_web::Result<impl Responder> {
+    let BatchReassign {
+        from,
+        to,
+        comment,
+        created_before,
+        dry_run,
+    } = request.into_inner();
+    if from.eq(&to) {
+        return Ok(message_response(
+            StatusCode::BAD_REQUEST,
+            &Message::new("key.batch_already_owned").arg(to),
+        ));
+    }
+    let filter = match KeyFilter::try_from(BatchFilter {
+        username: Some(from.clone()),
+        comment,
+        created_before,
+    }) {
+        Ok(filter) => filter,
+        Err(error) => return Ok(error_response(StatusCode::BAD_REQUEST, error)),
+    };
+    let actor = actor(&identity);
+
+    let res = timing::block(move || {
+        let mut conn = conn.get().unwrap();
+        let (Some(_), Some(to_user)) = (
+            User::find_user(&mut conn, &from)?,
+            User::find_user(&mut conn, &to)?,
+        ) else {
+            return Ok(Err(Message::new("user.pair_not_found").arg(from).arg(to)));
+        };
+        KeyBatch::reassign(&mut conn, &filter, &to_user, &actor, dry_run).map(Ok)
+    })
+    .await?;
+
+    Ok(match res {
+        Ok(Ok(batch)) => HttpResponse::Ok().json(batch),
+        Ok(Err(error)) => message_response(StatusCode::NOT_FOUND, &error),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
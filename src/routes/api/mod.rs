@@ -0,0 +1,96 @@
+//! JSON endpoints for automation and operators
+mod activity;
+mod admin;
+mod audit;
+mod auth;
+mod authorization;
+mod cache;
+mod compliance;
+mod dashboard;
+mod diff;
+mod fleet;
+mod freeze;
+#[cfg(feature = "graphql")]
+mod graphql;
+mod host;
+mod info;
+mod integration;
+mod key;
+mod recertification;
+mod reports;
+mod rule;
+mod scheduler;
+mod search;
+mod settings;
+mod simulate;
+mod stream;
+mod user;
+
+use actix_web::{
+    error::{InternalError, JsonPayloadError},
+    http::StatusCode,
+    web, HttpRequest, HttpResponse,
+};
+use serde::Serialize;
+
+use crate::i18n::Message;
+
+pub fn api_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::scope("/activity").configure(activity::activity_config))
+        .service(web::scope("/admin").configure(admin::admin_config))
+        .service(web::scope("/audit").configure(audit::audit_config))
+        .service(web::scope("/auth").configure(auth::auth_config))
+        .service(web::scope("/authorization").configure(authorization::authorization_config))
+        .service(web::scope("/cache").configure(cache::cache_config))
+        .service(web::scope("/compliance").configure(compliance::compliance_config))
+        .service(web::scope("/dashboard").configure(dashboard::dashboard_config))
+        .service(web::scope("/diff").configure(diff::diff_config))
+        .service(web::scope("/freeze").configure(freeze::freeze_config))
+        .service(web::scope("/host").configure(host::host_config))
+        .service(web::scope("/info").configure(info::info_config))
+        .service(web::scope("/integration").configure(integration::integration_config))
+        .service(web::scope("/key").configure(key::key_config))
+        .service(web::scope("/recertification").configure(recertification::recertification_config))
+        .service(web::scope("/reports").configure(reports::reports_config))
+        .service(web::scope("/rule").configure(rule::rule_config))
+        .service(web::scope("/scheduler").configure(scheduler::scheduler_config))
+        .service(web::scope("/search").configure(search::search_config))
+        .service(web::scope("/settings").configure(settings::settings_config))
+        .service(web::scope("/simulate").configure(simulate::simulate_config))
+        .service(web::scope("/user").configure(user::user_config));
+    #[cfg(feature = "graphql")]
+    cfg.service(web::scope("/graphql").configure(graphql::graphql_config));
+}
+
+#[derive(Serialize)]
+struct ApiError {
+    error: String,
+    /// Code of the message in the catalog, missing for messages that aren't in it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'static str>,
+}
+
+/// Valid JSON with values that don't fit the request, like a port above 65535, is answered with
+/// 422 and the reason
+pub(crate) fn json_error(error: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let response = match &error {
+        JsonPayloadError::Deserialize(json_error) if json_error.is_data() => {
+            error_response(StatusCode::UNPROCESSABLE_ENTITY, json_error.to_string())
+        }
+        _ => return error.into(),
+    };
+    InternalError::from_response(error, response).into()
+}
+
+/// JSON body with an error message
+pub(crate) fn error_response(status: StatusCode, error: String) -> HttpResponse {
+    HttpResponse::build(status).json(ApiError { error, code: None })
+}
+
+/// JSON body with a message of the catalog in the language of the request and its code
+pub(crate) fn message_response(status: StatusCode, message: &Message) -> HttpResponse {
+    HttpResponse::build(status).json(ApiError {
+        error: message.to_string(),
+        code: Some(message.code()),
+    })
+}
@@ -0,0 +1,212 @@
+use actix_identity::Identity;
+use actix_web::{
+    get,
+    http::StatusCode,
+    post,
+    web::{self, Data, Json, Path},
+    HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::{
+        history::now,
+        recertification::{RecertificationReport, OVERDUE},
+    },
+    hooks::{Event, EventHooks},
+    i18n::Message,
+    models::{RecertificationCampaign, RecertificationItem},
+    routes::actor,
+    timing, ConnectionPool,
+};
+
+use super::{audit::parse_timestamp, error_response, message_response};
+
+pub fn recertification_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list)
+        .service(create)
+        .service(show)
+        .service(review);
+}
+
+/// All campaigns with their progress, newest first
+#[get("")]
+async fn list(conn: Data<ConnectionPool>) -> actix_web::Result<impl Responder> {
+    let res = timing::block(move || {
+        let mut conn = conn.get().unwrap();
+        RecertificationCampaign::all(&mut conn)?
+            .into_iter()
+            .map(|campaign| campaign.report(&mut conn))
+            .collect::<Result<Vec<_>, String>>()
+    })
+    .await?;
+
+    Ok(match res {
+        Ok(reports) => HttpResponse::Ok().json(reports),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
+
+#[derive(Deserialize)]
+struct NewCampaign {
+    name: String,
+    /// Defaults to all hosts
+    tag: Option<String>,
+    deadline: String,
+    #[serde(default)]
+    auto_revoke: bool,
+}
+
+/// Starts a campaign reviewing every authorization on the hosts with the tag
+#[post("")]
+async fn create(
+    conn: Data<ConnectionPool>,
+    identity: Identity,
+    campaign: Json<NewCampaign>,
+) -> actix_web::Result<impl Responder> {
+    let NewCampaign {
+        name,
+        tag,
+        deadline,
+        auto_revoke,
+    } = campaign.into_inner();
+    let deadline = match parse_timestamp(&deadline) {
+        Ok(deadline) if deadline > now() => deadline,
+        Ok(_) => {
+            return Ok(message_response(
+                StatusCode::BAD_REQUEST,
+                &Message::new("recertification.deadline_past"),
+            ))
+        }
+        Err(error) => return Ok(error_response(StatusCode::BAD_REQUEST, error)),
+    };
+    if name.trim().is_empty() {
+        return Ok(message_response(
+            StatusCode::BAD_REQUEST,
+            &Message::new("recertification.name_required"),
+        ));
+    }
+    let tag = tag
+        .map(|tag| tag.trim().to_owned())
+        .filter(|tag| !tag.is_empty());
+
+    let actor = actor(&identity);
+    let res = timing::block(move || {
+        let mut conn = conn.get().unwrap();
+        RecertificationCampaign::create(
+            &mut conn,
+            name.trim().to_owned(),
+            tag,
+            deadline,
+            auto_revoke,
+            &actor,
+        )?
+        .map(|campaign| campaign.report(&mut conn))
+        .transpose()
+    })
+    .await?;
+
+    Ok(match res {
+        Ok(Some(report)) => HttpResponse::Created().json(report),
+        Ok(None) => message_response(
+            StatusCode::BAD_REQUEST,
+            &Message::new("recertification.nothing_to_review"),
+        ),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
+
+#[derive(Serialize)]
+struct CampaignDetails {
+    #[serde(flatten)]
+    report: RecertificationReport,
+    items: Vec<RecertificationItem>,
+}
+
+/// The report of a campaign with every reviewed authorization
+#[get("/{id}")]
+async fn show(conn: Data<ConnectionPool>, id: Path<i32>) -> actix_web::Result<impl Responder> {
+    let id = id.into_inner();
+    let res = timing::block(move || {
+        let mut conn = conn.get().unwrap();
+        let campaign = RecertificationCampaign::get(&mut conn, id)?;
+        let items = campaign.items(&mut conn)?;
+        Ok::<_, String>(CampaignDetails {
+            report: campaign.report(&mut conn)?,
+            items,
+        })
+    })
+    .await?;
+
+    Ok(match res {
+        Ok(details) => HttpResponse::Ok().json(details),
+        Err(error) => error_response(StatusCode::NOT_FOUND, error),
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Decision {
+    Approve,
+    Revoke,
+}
+
+#[derive(Deserialize)]
+struct Review {
+    decision: Decision,
+    comment: Option<String>,
+}
+
+/// Approves or revokes an authorization. Revoking deletes it, the key is removed from the host
+/// with its next deployment. Overdue authorizations can still be reviewed after the deadline.
+#[post("/{id}/items/{item}")]
+async fn review(
+    conn: Data<ConnectionPool>,
+    event_hooks: Data<EventHooks>,
+    identity: Identity,
+    path: Path<(i32, i32)>,
+    review: Json<Review>,
+) -> actix_web::Result<impl Responder> {
+    let (campaign_id, item_id) = path.into_inner();
+    let Review { decision, comment } = review.into_inner();
+    let comment = comment
+        .map(|comment| comment.trim().to_owned())
+        .filter(|comment| !comment.is_empty());
+    let actor = actor(&identity);
+
+    let res = timing::block(move || {
+        let mut conn = conn.get().unwrap();
+        let Some(item) = RecertificationItem::get(&mut conn, campaign_id, item_id)? else {
+            return Ok(Err((
+                StatusCode::NOT_FOUND,
+                Message::new("recertification.item_not_found"),
+            )));
+        };
+        if let Some(decided) = item.decision.as_deref().filter(|d| !d.eq(&OVERDUE)) {
+            return Ok(Err((
+                StatusCode::CONFLICT,
+                Message::new("recertification.already_decided").arg(decided),
+            )));
+        }
+        let item = item.review(
+            &mut conn,
+            matches!(decision, Decision::Approve),
+            comment,
+            &actor,
+        )?;
+        let completed = RecertificationCampaign::close_if_reviewed(&mut conn, campaign_id)?;
+        Ok::<_, String>(Ok((item, completed)))
+    })
+    .await?;
+
+    Ok(match res {
+        Ok(Ok((item, completed))) => {
+            if let Some(report) = completed {
+                event_hooks.emit(Event::recertification_completed(report));
+            }
+            HttpResponse::Ok().json(item)
+        }
+        Ok(Err((status, error))) => message_response(status, &error),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
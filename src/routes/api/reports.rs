@@ -0,0 +1,498 @@
+use std::{cmp::Reverse, collections::BTreeMap};
+
+use actix_identity::Identity;
+use actix_web::{
+    get,
+    http::StatusCode,
+    post,
+    web::{self, Data, Path, Query},
+    HttpResponse, Responder,
+};
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use time::{Duration, OffsetDateTime, PrimitiveDateTime};
+
+use crate::{
+    access::AccessControl,
+    db::{
+        orphans::{OrphanKind, Orphans},
+        unused_access::GrantedAuthorization,
+    },
+    failed_logins::{host_failed_logins, FailedLogins},
+    i18n::Message,
+    key_usage::{self, KeyUsage},
+    models::{Host, PublicUserKey},
+    routes::actor,
+    scheduler::Scheduler,
+    ssh::{HostCache, SshClient},
+    timing, Configuration, ConnectionPool,
+};
+
+use super::{error_response, message_response};
+
+pub fn reports_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(orphans)
+        .service(clean_up_orphans)
+        .service(unused_keys)
+        .service(unused_access)
+        .service(revoke_unused_access)
+        .service(failed_logins);
+}
+
+/// Keys whose owner isn't authorized anywhere, users without keys, hosts nobody is authorized on
+/// and authorizations of disabled users
+#[get("/orphans")]
+async fn orphans(conn: Data<ConnectionPool>) -> actix_web::Result<impl Responder> {
+    let res = timing::block(move || Orphans::find(&mut conn.get().unwrap())).await?;
+
+    Ok(match res {
+        Ok(orphans) => HttpResponse::Ok().json(orphans),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
+
+/// Deletes all current orphans of one kind: `keys`, `users`, `hosts` or `authorizations`
+#[post("/orphans/{kind}/cleanup")]
+async fn clean_up_orphans(
+    conn: Data<ConnectionPool>,
+    caching_ssh_client: Data<dyn HostCache>,
+    identity: Identity,
+    kind: Path<String>,
+) -> actix_web::Result<impl Responder> {
+    let kind = match kind.parse::<OrphanKind>() {
+        Ok(kind) => kind,
+        Err(error) => return Ok(error_response(StatusCode::NOT_FOUND, error)),
+    };
+    let actor = actor(&identity);
+
+    let res =
+        timing::block(move || Orphans::clean_up(&mut conn.get().unwrap(), kind, &actor)).await?;
+
+    Ok(match res {
+        Ok(removed) => {
+            for host in &removed.hosts {
+                caching_ssh_client.remove(&host.name).await;
+            }
+            HttpResponse::Ok().json(removed)
+        }
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
+
+/// Reads the key usage of the hosts, `max_concurrent` at the same time. Returns the usage of the
+/// hosts whose logs could be read by name, and the names of the others.
+async fn read_usages(
+    ssh_client: &dyn SshClient,
+    hosts: Vec<Host>,
+    max_concurrent: usize,
+) -> (Vec<(String, KeyUsage)>, Vec<String>) {
+    let usages: Vec<(String, Result<KeyUsage, _>)> = stream::iter(hosts)
+        .map(|host| async move {
+            let name = host.name.clone();
+            (name, key_usage::host_usage(ssh_client, host).await)
+        })
+        .buffered(max_concurrent.max(1))
+        .collect()
+        .await;
+    let mut checked = Vec::new();
+    let mut unchecked = Vec::new();
+    for (host, usage) in usages {
+        match usage {
+            Ok(usage) => checked.push((host, usage)),
+            Err(_) => unchecked.push(host),
+        }
+    }
+    (checked, unchecked)
+}
+
+#[derive(Deserialize)]
+struct UnusedKeysQuery {
+    /// Keys nobody logged in with for this many days (default 365)
+    days: Option<i64>,
+    /// Only read the logs of hosts with this tag
+    tag: Option<String>,
+}
+
+#[derive(Serialize)]
+struct UnusedKey {
+    id: i32,
+    username: String,
+    key_type: String,
+    comment: Option<String>,
+    fingerprint: Option<String>,
+    /// The last login with the key on any host, missing if none is in the logs
+    #[serde(with = "time::serde::rfc3339::option")]
+    last_used: Option<OffsetDateTime>,
+    /// Host of that login
+    last_host: Option<String>,
+}
+
+#[derive(Serialize)]
+struct UnusedKeysReport {
+    #[serde(with = "time::serde::rfc3339")]
+    since: OffsetDateTime,
+    keys: Vec<UnusedKey>,
+    /// Hosts whose logs couldn't be read, the keys may have been used there
+    unchecked: Vec<String>,
+}
+
+/// Keys nobody logged in with since `days` days ago, according to the sshd logs of every host.
+/// Reads the logs of at most `max_concurrent_connections` hosts at the same time.
+#[get("/unused_keys")]
+async fn unused_keys(
+    conn: Data<ConnectionPool>,
+    ssh_client: Data<dyn SshClient>,
+    config: Data<Configuration>,
+    params: Query<UnusedKeysQuery>,
+) -> actix_web::Result<impl Responder> {
+    if !config.key_usage.enabled {
+        return Ok(message_response(
+            StatusCode::NOT_FOUND,
+            &Message::new("key.usage_disabled"),
+        ));
+    }
+    let res = timing::block(move || {
+        let mut conn = conn.get().unwrap();
+        Ok::<_, String>((
+            Host::get_all_hosts(&mut conn)?,
+            PublicUserKey::get_all_keys_with_username(&mut conn)?,
+        ))
+    })
+    .await?;
+    let (mut hosts, keys) = match res {
+        Ok(res) => res,
+        Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+    };
+    if let Some(tag) = &params.tag {
+        hosts.retain(|host| host.has_tag(tag));
+    }
+
+    let (checked, unchecked) = read_usages(
+        ssh_client.get_ref(),
+        hosts,
+        config.ssh.max_concurrent_connections,
+    )
+    .await;
+
+    let since = OffsetDateTime::now_utc() - Duration::days(params.days.unwrap_or(365));
+    let keys = keys
+        .into_iter()
+        .filter_map(|(username, key)| {
+            let fingerprint = key_usage::fingerprint(&key);
+            let last = fingerprint.as_deref().and_then(|fingerprint| {
+                checked
+                    .iter()
+                    .filter_map(|(host, usage)| Some((usage.last_used(fingerprint)?, host)))
+                    .max()
+            });
+            if last.is_some_and(|(last_used, _)| last_used >= since) {
+                return None;
+            }
+            Some(UnusedKey {
+                id: key.id,
+                username,
+                key_type: key.key_type,
+                comment: key.comment,
+                fingerprint,
+                last_used: last.map(|(last_used, _)| last_used),
+                last_host: last.map(|(_, host)| host.clone()),
+            })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(UnusedKeysReport {
+        since,
+        keys,
+        unchecked,
+    }))
+}
+
+#[derive(Deserialize)]
+struct UnusedAccessQuery {
+    /// Authorizations nobody logged in with for this many days (default 90)
+    days: Option<i64>,
+    /// Only hosts with this tag
+    tag: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Action {
+    method: &'static str,
+    url: String,
+}
+
+#[derive(Serialize)]
+struct UnusedAuthorization {
+    id: i32,
+    host: String,
+    username: String,
+    login: String,
+    /// Missing for authorizations older than the authorization history
+    #[serde(with = "crate::db::utc_rfc3339::option")]
+    granted_at: Option<PrimitiveDateTime>,
+    /// The last login as `login` with a key of the user, missing if none is in the logs
+    #[serde(with = "time::serde::rfc3339::option")]
+    last_used: Option<OffsetDateTime>,
+    /// Revokes the authorization and queues the deployment of the login
+    revoke: Action,
+}
+
+#[derive(Serialize)]
+struct UnusedAccessReport {
+    #[serde(with = "time::serde::rfc3339")]
+    since: OffsetDateTime,
+    /// Authorizations granted before `since` whose keys didn't log in since, recommended to be revoked
+    authorizations: Vec<UnusedAuthorization>,
+    /// Hosts whose logs couldn't be read, their authorizations aren't checked
+    unchecked: Vec<String>,
+}
+
+/// Authorizations to revoke: granted at least `days` days ago, and nobody logged in with a key of
+/// the user as the login since, according to the sshd logs of the host
+#[get("/unused_access")]
+async fn unused_access(
+    conn: Data<ConnectionPool>,
+    ssh_client: Data<dyn SshClient>,
+    config: Data<Configuration>,
+    params: Query<UnusedAccessQuery>,
+) -> actix_web::Result<impl Responder> {
+    if !config.key_usage.enabled {
+        return Ok(message_response(
+            StatusCode::NOT_FOUND,
+            &Message::new("key.usage_disabled"),
+        ));
+    }
+    let mut authorizations =
+        match timing::block(move || GrantedAuthorization::all(&mut conn.get().unwrap())).await? {
+            Ok(authorizations) => authorizations,
+            Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+        };
+    if let Some(tag) = &params.tag {
+        authorizations.retain(|authorization| authorization.host.has_tag(tag));
+    }
+
+    let mut hosts: Vec<Host> = Vec::new();
+    for authorization in &authorizations {
+        if !hosts.iter().any(|host| host.id == authorization.host.id) {
+            hosts.push(authorization.host.clone());
+        }
+    }
+    let (checked, unchecked) = read_usages(
+        ssh_client.get_ref(),
+        hosts,
+        config.ssh.max_concurrent_connections,
+    )
+    .await;
+
+    let since = OffsetDateTime::now_utc() - Duration::days(params.days.unwrap_or(90));
+    let authorizations = authorizations
+        .into_iter()
+        .filter(|authorization| {
+            authorization
+                .granted_at
+                .is_none_or(|granted_at| granted_at.assume_utc() < since)
+        })
+        .filter_map(|authorization| {
+            let (_, usage) = checked
+                .iter()
+                .find(|(host, _)| host.eq(&authorization.host.name))?;
+            let fingerprints: Vec<String> = authorization
+                .keys
+                .iter()
+                .filter_map(key_usage::fingerprint)
+                .collect();
+            let last_used = usage.last_used_as(&authorization.login, &fingerprints);
+            if last_used.is_some_and(|last_used| last_used >= since) {
+                return None;
+            }
+            Some(UnusedAuthorization {
+                id: authorization.id,
+                host: authorization.host.name,
+                username: authorization.username,
+                login: authorization.login,
+                granted_at: authorization.granted_at,
+                last_used,
+                revoke: Action {
+                    method: "POST",
+                    url: format!("/api/reports/unused_access/{}/revoke", authorization.id),
+                },
+            })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(UnusedAccessReport {
+        since,
+        authorizations,
+        unchecked,
+    }))
+}
+
+#[derive(Serialize)]
+struct RevokedAccess {
+    id: i32,
+    host: String,
+    login: String,
+}
+
+/// Revokes an authorization and queues the deployment of its login, the deploy job then removes
+/// the key from the host
+#[post("/unused_access/{id}/revoke")]
+async fn revoke_unused_access(
+    conn: Data<ConnectionPool>,
+    access: Data<AccessControl>,
+    scheduler: Data<Scheduler>,
+    identity: Identity,
+    id: Path<i32>,
+) -> actix_web::Result<impl Responder> {
+    let id = id.into_inner();
+    let actor = actor(&identity);
+
+    let res = {
+        let actor = actor.clone();
+        timing::block(move || {
+            let mut conn = conn.get().unwrap();
+            let Some(host) = Host::get_from_authorization(&mut conn, id)? else {
+                return Ok(Err((
+                    StatusCode::NOT_FOUND,
+                    Message::new("authorization.not_found"),
+                )));
+            };
+            if !access.may_change_host(&actor, &host) {
+                return Ok(Err((
+                    StatusCode::FORBIDDEN,
+                    AccessControl::denied(&actor, &host.name),
+                )));
+            }
+            let login = host
+                .get_authorized_users(&mut conn)?
+                .into_iter()
+                .find(|(authorization, _, _, _)| *authorization == id)
+                .map(|(_, _, login, _)| login)
+                .unwrap_or_default();
+            Host::delete_authorization(&mut conn, id, &actor)?;
+            Ok::<_, String>(Ok((host.name, login)))
+        })
+        .await?
+    };
+
+    Ok(match res {
+        Ok(Ok((host, login))) => {
+            scheduler
+                .into_inner()
+                .queue_deploy(host.clone(), login.clone(), &actor);
+            HttpResponse::Accepted().json(RevokedAccess { id, host, login })
+        }
+        Ok(Err((status, message))) => message_response(status, &message),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
+
+#[derive(Deserialize)]
+struct FailedLoginsQuery {
+    /// Hours to look back (default `failed_logins.hours` of the configuration)
+    hours: Option<u32>,
+    /// Only read the logs of hosts with this tag
+    tag: Option<String>,
+}
+
+#[derive(Serialize)]
+struct HostFailedLogins {
+    host: String,
+    #[serde(flatten)]
+    failed: FailedLogins,
+}
+
+/// Failed logins from one address on any host
+#[derive(Serialize)]
+struct FleetSource {
+    address: String,
+    attempts: usize,
+    hosts: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct FailedLoginsReport {
+    #[serde(with = "time::serde::rfc3339")]
+    since: OffsetDateTime,
+    attempts: usize,
+    /// Hosts with failed logins, most attempts first
+    hosts: Vec<HostFailedLogins>,
+    /// Most attempts first
+    sources: Vec<FleetSource>,
+    /// Hosts whose logs couldn't be read
+    unchecked: Vec<String>,
+}
+
+/// Failed logins of the last `hours` hours on every host, per host and login and per source address
+/// across the fleet. Reads the logs of at most `max_concurrent_connections` hosts at the same time.
+#[get("/failed_logins")]
+async fn failed_logins(
+    conn: Data<ConnectionPool>,
+    ssh_client: Data<dyn SshClient>,
+    config: Data<Configuration>,
+    params: Query<FailedLoginsQuery>,
+) -> actix_web::Result<impl Responder> {
+    if !config.failed_logins.enabled {
+        return Ok(message_response(
+            StatusCode::NOT_FOUND,
+            &Message::new("failed_logins.disabled"),
+        ));
+    }
+    let res = timing::block(move || Host::get_all_hosts(&mut conn.get().unwrap())).await?;
+    let mut hosts = match res {
+        Ok(hosts) => hosts,
+        Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+    };
+    if let Some(tag) = &params.tag {
+        hosts.retain(|host| host.has_tag(tag));
+    }
+    let hours = params.hours.unwrap_or(config.failed_logins.hours).max(1);
+
+    let ssh_client = ssh_client.get_ref();
+    let results: Vec<(String, Result<FailedLogins, _>)> = stream::iter(hosts)
+        .map(|host| async move {
+            let name = host.name.clone();
+            (name, host_failed_logins(ssh_client, host, hours).await)
+        })
+        .buffered(config.ssh.max_concurrent_connections.max(1))
+        .collect()
+        .await;
+
+    let mut report_hosts = Vec::new();
+    let mut unchecked = Vec::new();
+    let mut sources: BTreeMap<String, (usize, Vec<String>)> = BTreeMap::new();
+    for (host, failed) in results {
+        let failed = match failed {
+            Ok(failed) if failed.attempts == 0 => continue,
+            Ok(failed) => failed,
+            Err(_) => {
+                unchecked.push(host);
+                continue;
+            }
+        };
+        for (address, attempts) in failed.sources() {
+            let (total, hosts) = sources.entry(address.to_owned()).or_default();
+            *total += attempts;
+            hosts.push(host.clone());
+        }
+        report_hosts.push(HostFailedLogins { host, failed });
+    }
+    report_hosts.sort_by_key(|host| Reverse(host.failed.attempts));
+    let mut sources: Vec<FleetSource> = sources
+        .into_iter()
+        .map(|(address, (attempts, hosts))| FleetSource {
+            address,
+            attempts,
+            hosts,
+        })
+        .collect();
+    sources.sort_by_key(|source| Reverse(source.attempts));
+
+    Ok(HttpResponse::Ok().json(FailedLoginsReport {
+        since: OffsetDateTime::now_utc() - Duration::hours(i64::from(hours)),
+        attempts: report_hosts.iter().map(|host| host.failed.attempts).sum(),
+        hosts: report_hosts,
+        sources,
+        unchecked,
+    }))
+}
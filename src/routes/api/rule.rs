@@ -0,0 +1,92 @@
+use actix_identity::Identity;
+use actix_web::{
+    delete, get,
+    http::StatusCode,
+    post,
+    web::{self, Data, Json, Path},
+    HttpResponse, Responder,
+};
+use serde::Deserialize;
+
+use crate::{models::AuthorizationRule, routes::actor, timing, ConnectionPool};
+
+use super::error_response;
+
+pub fn rule_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list).service(add).service(delete);
+}
+
+#[get("")]
+async fn list(conn: Data<ConnectionPool>) -> actix_web::Result<impl Responder> {
+    let res = timing::block(move || AuthorizationRule::all(&mut conn.get().unwrap())).await?;
+
+    Ok(match res {
+        Ok(rules) => HttpResponse::Ok().json(rules),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
+
+#[derive(Deserialize)]
+struct NewRule {
+    department: String,
+    tag: String,
+    login: String,
+    options: Option<String>,
+}
+
+/// Grants every user of a department a login on every host with a tag
+#[post("")]
+async fn add(
+    conn: Data<ConnectionPool>,
+    identity: Identity,
+    rule: Json<NewRule>,
+) -> actix_web::Result<impl Responder> {
+    let NewRule {
+        department,
+        tag,
+        login,
+        options,
+    } = rule.into_inner();
+    let (department, tag, login) = (
+        department.trim().to_owned(),
+        tag.trim().to_owned(),
+        login.trim().to_owned(),
+    );
+    if department.is_empty() || tag.is_empty() || login.is_empty() {
+        return Ok(error_response(
+            StatusCode::BAD_REQUEST,
+            "department, tag and login are required".to_owned(),
+        ));
+    }
+
+    let actor = actor(&identity);
+    let res = timing::block(move || {
+        AuthorizationRule::add(
+            &mut conn.get().unwrap(),
+            department,
+            tag,
+            login,
+            options,
+            &actor,
+        )
+    })
+    .await?;
+
+    Ok(match res {
+        Ok(rule) => HttpResponse::Created().json(rule),
+        Err(error) => error_response(StatusCode::BAD_REQUEST, error),
+    })
+}
+
+/// Removes a rule, the logins it granted are removed with the next deployment
+#[delete("/{id}")]
+async fn delete(conn: Data<ConnectionPool>, id: Path<i32>) -> actix_web::Result<impl Responder> {
+    let id = id.into_inner();
+    let res =
+        timing::block(move || AuthorizationRule::delete(&mut conn.get().unwrap(), id)).await?;
+
+    Ok(match res {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(error) => error_response(StatusCode::NOT_FOUND, error),
+    })
+}
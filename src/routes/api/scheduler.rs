@@ -0,0 +1,82 @@
+use actix_web::{
+    delete, get,
+    http::StatusCode,
+    post,
+    web::{self, Data, Path},
+    HttpResponse, Responder,
+};
+use serde::Serialize;
+
+use crate::{
+    scheduler::{RunError, Scheduler},
+    ssh::SshClient,
+};
+
+use super::error_response;
+
+pub fn scheduler_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_jobs)
+        .service(deploy_queue)
+        .service(writes)
+        .service(resume_deploys)
+        .service(run_now);
+}
+
+/// Schedules and last results of all background jobs
+#[get("")]
+async fn list_jobs(scheduler: Data<Scheduler>) -> impl Responder {
+    HttpResponse::Ok().json(scheduler.jobs())
+}
+
+/// Logins waiting for the deploy job, e.g. after their authorization was revoked
+#[get("/deploy_queue")]
+async fn deploy_queue(scheduler: Data<Scheduler>) -> impl Responder {
+    HttpResponse::Ok().json(scheduler.queued_deploys())
+}
+
+/// Lets the deploy jobs deploy again after canaries failed, without starting one. Answers with why
+/// they were halted, or 404 if they weren't
+#[delete("/deploy_halt")]
+async fn resume_deploys(scheduler: Data<Scheduler>) -> impl Responder {
+    match scheduler.resume_deploys() {
+        Some(halt) => HttpResponse::Ok().json(halt),
+        None => error_response(
+            StatusCode::NOT_FOUND,
+            "Deployments aren't halted".to_owned(),
+        ),
+    }
+}
+
+/// Writes to hosts that are running, and the ones waiting for an earlier write to the same login
+#[get("/writes")]
+async fn writes(ssh_client: Data<dyn SshClient>) -> impl Responder {
+    HttpResponse::Ok().json(ssh_client.queued_writes())
+}
+
+#[derive(Serialize)]
+struct RunNowResponse {
+    started: bool,
+    error: Option<String>,
+}
+
+/// Start a job immediately instead of waiting for its schedule, deploy jobs resume halted
+/// deployments
+#[post("/{job}/run_now")]
+async fn run_now(scheduler: Data<Scheduler>, job: Path<String>) -> impl Responder {
+    match scheduler.into_inner().run_requested(&job) {
+        Ok(()) => HttpResponse::Accepted().json(RunNowResponse {
+            started: true,
+            error: None,
+        }),
+        Err(e) => {
+            let mut res = match e {
+                RunError::NoSuchJob => HttpResponse::NotFound(),
+                RunError::AlreadyRunning => HttpResponse::Conflict(),
+            };
+            res.json(RunNowResponse {
+                started: false,
+                error: Some(e.to_string()),
+            })
+        }
+    }
+}
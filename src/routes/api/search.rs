@@ -0,0 +1,234 @@
+use actix_web::{
+    get,
+    http::StatusCode,
+    web::{self, Data, Query},
+    HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::{
+    db::search::{search as search_text, SearchResults},
+    i18n::Message,
+    key_usage::fingerprint,
+    models::{Activity, Host, User},
+    ssh::{AuthorizedKey, HostCache},
+    timing, ConnectionPool,
+};
+
+use super::{error_response, message_response};
+
+pub fn search_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(search).service(remote_keys);
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+    /// Results per kind (default 20, at most 100)
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct HostMatch {
+    name: String,
+    address: String,
+    aliases: Vec<String>,
+    tags: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct KeyMatch {
+    id: i32,
+    username: String,
+    key_type: String,
+    comment: Option<String>,
+    fingerprint: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SearchResponse {
+    hosts: Vec<HostMatch>,
+    users: Vec<User>,
+    keys: Vec<KeyMatch>,
+    activity: Vec<Activity>,
+}
+
+impl From<SearchResults> for SearchResponse {
+    fn from(results: SearchResults) -> Self {
+        Self {
+            hosts: results
+                .hosts
+                .into_iter()
+                .map(|host| HostMatch {
+                    aliases: host.alias_list().map(str::to_owned).collect(),
+                    tags: host.tag_list().map(str::to_owned).collect(),
+                    name: host.name,
+                    address: host.address,
+                })
+                .collect(),
+            users: results.users,
+            keys: results
+                .keys
+                .into_iter()
+                .map(|(username, key)| KeyMatch {
+                    fingerprint: fingerprint(&key),
+                    id: key.id,
+                    username,
+                    key_type: key.key_type,
+                    comment: key.comment,
+                })
+                .collect(),
+            activity: results.activity,
+        }
+    }
+}
+
+/// Hosts, users, keys and recorded requests containing `q` in a name, address, comment or path
+#[get("")]
+async fn search(
+    conn: Data<ConnectionPool>,
+    query: Query<SearchQuery>,
+) -> actix_web::Result<impl Responder> {
+    let SearchQuery { q, limit } = query.into_inner();
+    let text = q.trim().to_owned();
+    if text.is_empty() {
+        return Ok(message_response(
+            StatusCode::BAD_REQUEST,
+            &Message::new("search.query_required"),
+        ));
+    }
+    let limit = limit.unwrap_or(20).clamp(1, 100);
+
+    Ok(
+        match timing::block(move || search_text(&mut conn.get().unwrap(), &text, limit)).await? {
+            Ok(results) => HttpResponse::Ok().json(SearchResponse::from(results)),
+            Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct RemoteKeyQuery {
+    /// SHA256 fingerprint, with or without the `SHA256:` prefix
+    fingerprint: Option<String>,
+    /// Base64 of the key
+    base64: Option<String>,
+    /// Part of the comment
+    comment: Option<String>,
+}
+
+impl RemoteKeyQuery {
+    /// Whether the key matches every given criterion
+    fn matches(&self, key: &AuthorizedKey) -> bool {
+        self.fingerprint.as_deref().is_none_or(|fingerprint| {
+            key.fingerprint().is_some_and(|found| {
+                found.strip_prefix("SHA256:") == Some(fingerprint.trim_start_matches("SHA256:"))
+            })
+        }) && self
+            .base64
+            .as_deref()
+            .is_none_or(|base64| key.base64 == base64)
+            && self.comment.as_deref().is_none_or(|comment| {
+                key.comment
+                    .as_deref()
+                    .is_some_and(|found| found.contains(comment))
+            })
+    }
+}
+
+#[derive(Serialize)]
+struct RemoteKeyMatch {
+    host: String,
+    login: String,
+    fingerprint: Option<String>,
+    algorithm: String,
+    comment: Option<String>,
+    options: Option<String>,
+    /// The file is managed by SSM
+    managed: bool,
+    #[serde(with = "time::serde::rfc3339")]
+    cached_at: OffsetDateTime,
+    /// The cached file is older than `cache_ttl`
+    stale: bool,
+}
+
+#[derive(Serialize)]
+struct RemoteKeySearch {
+    found: bool,
+    matches: Vec<RemoteKeyMatch>,
+    /// Hosts that were never fetched or couldn't be reached when they last were
+    unchecked: Vec<String>,
+}
+
+/// Finds a key in the cached authorized_keys files of all hosts, without connecting to any host
+#[get("/remote_keys")]
+async fn remote_keys(
+    conn: Data<ConnectionPool>,
+    caching_ssh_client: Data<dyn HostCache>,
+    query: Query<RemoteKeyQuery>,
+) -> actix_web::Result<impl Responder> {
+    // An unencoded `+` of base64 arrives as a space, which neither contains
+    let unescaped = |value: &Option<String>| {
+        value
+            .as_deref()
+            .map(|value| value.trim().replace(' ', "+"))
+            .filter(|value| !value.is_empty())
+    };
+    let query = RemoteKeyQuery {
+        fingerprint: unescaped(&query.fingerprint),
+        base64: unescaped(&query.base64),
+        comment: query.comment.clone().filter(|value| !value.is_empty()),
+    };
+    if query.fingerprint.is_none() && query.base64.is_none() && query.comment.is_none() {
+        return Ok(message_response(
+            StatusCode::BAD_REQUEST,
+            &Message::new("search.no_criteria"),
+        ));
+    }
+
+    let cached = match caching_ssh_client.get_cached_logins().await {
+        Ok(cached) => cached,
+        Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+    };
+    let mut unchecked: Vec<String> =
+        match timing::block(move || Host::get_all_hosts(&mut conn.get().unwrap())).await? {
+            Ok(hosts) => hosts
+                .into_iter()
+                .map(|host| host.name)
+                .filter(|name| !cached.iter().any(|(host, _, _)| &host.name == name))
+                .collect(),
+            Err(error) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error)),
+        };
+
+    let mut matches = Vec::new();
+    for (host, cache, logins) in cached {
+        let Ok(logins) = logins else {
+            unchecked.push(host.name);
+            continue;
+        };
+        for (login, managed, entries, _) in logins {
+            for key in entries.iter().flatten().filter(|key| query.matches(key)) {
+                matches.push(RemoteKeyMatch {
+                    host: host.name.clone(),
+                    login: login.clone(),
+                    fingerprint: key.fingerprint(),
+                    algorithm: key.algorithm.to_string(),
+                    comment: key.comment.clone(),
+                    options: Some(key.options.as_str().to_owned())
+                        .filter(|options| !options.is_empty()),
+                    managed,
+                    cached_at: cache.cached_at,
+                    stale: cache.stale,
+                });
+            }
+        }
+    }
+    unchecked.sort_unstable();
+
+    Ok(HttpResponse::Ok().json(RemoteKeySearch {
+        found: !matches.is_empty(),
+        matches,
+        unchecked,
+    }))
+}
@@ -0,0 +1,365 @@
+use actix_identity::Identity;
+use actix_web::{
+    delete, get,
+    http::StatusCode,
+    post, put,
+    web::{self, Data, Json, Path},
+    HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    activity::{ActivityCategory, ActivityLog, CategorySetting},
+    db::history::now,
+    freeze::{Freezes, ReadOnly},
+    i18n::Message,
+    models::{DiffIgnoreRule, Host, NewSchedule, Schedule},
+    routes::actor,
+    scheduler::Scheduler,
+    ssh::SshClient,
+    timing, ConnectionPool,
+};
+
+use super::{error_response, message_response};
+
+pub fn settings_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_schedules)
+        .service(add_schedule)
+        .service(update_schedule)
+        .service(delete_schedule)
+        .service(list_activity_categories)
+        .service(update_activity_category)
+        .service(reset_activity_category)
+        .service(public_key)
+        .service(get_read_only)
+        .service(set_read_only)
+        .service(list_diff_ignore_rules)
+        .service(add_diff_ignore_rule)
+        .service(delete_diff_ignore_rule);
+}
+
+/// Cleans up user input before it is validated and stored
+fn normalize(mut schedule: NewSchedule) -> NewSchedule {
+    schedule.name = schedule.name.trim().to_owned();
+    schedule.tag = schedule
+        .tag
+        .map(|tag| tag.trim().to_owned())
+        .filter(|tag| !tag.is_empty());
+    schedule.strategy = schedule
+        .strategy
+        .map(|strategy| strategy.trim().to_owned())
+        .filter(|strategy| !strategy.is_empty());
+    schedule
+}
+
+/// Pick up the changed schedules and answer with the stored ones
+async fn reload_and_list(
+    conn: Data<ConnectionPool>,
+    scheduler: Data<Scheduler>,
+    status: StatusCode,
+) -> actix_web::Result<HttpResponse> {
+    if let Err(error) = scheduler.into_inner().reload().await {
+        return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, error));
+    }
+
+    let res = timing::block(move || Schedule::get_all_schedules(&mut conn.get().unwrap())).await?;
+    Ok(match res {
+        Ok(schedules) => HttpResponse::build(status).json(schedules),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
+
+/// Additional schedules, on top of the ones from the configuration file
+#[get("/schedules")]
+async fn list_schedules(conn: Data<ConnectionPool>) -> actix_web::Result<impl Responder> {
+    let res = timing::block(move || Schedule::get_all_schedules(&mut conn.get().unwrap())).await?;
+
+    Ok(match res {
+        Ok(schedules) => HttpResponse::Ok().json(schedules),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
+
+#[post("/schedules")]
+async fn add_schedule(
+    conn: Data<ConnectionPool>,
+    scheduler: Data<Scheduler>,
+    schedule: Json<NewSchedule>,
+) -> actix_web::Result<impl Responder> {
+    let schedule = normalize(schedule.into_inner());
+    if let Err(error) = Scheduler::validate(&schedule) {
+        return Ok(error_response(StatusCode::BAD_REQUEST, error));
+    }
+
+    let db = conn.clone();
+    let res =
+        timing::block(move || Schedule::add_schedule(&mut db.get().unwrap(), &schedule)).await?;
+    if let Err(error) = res {
+        return Ok(error_response(StatusCode::BAD_REQUEST, error));
+    }
+
+    reload_and_list(conn, scheduler, StatusCode::CREATED).await
+}
+
+#[put("/schedules/{name}")]
+async fn update_schedule(
+    conn: Data<ConnectionPool>,
+    scheduler: Data<Scheduler>,
+    name: Path<String>,
+    schedule: Json<NewSchedule>,
+) -> actix_web::Result<impl Responder> {
+    let schedule = normalize(schedule.into_inner());
+    if let Err(error) = Scheduler::validate(&schedule) {
+        return Ok(error_response(StatusCode::BAD_REQUEST, error));
+    }
+
+    let db = conn.clone();
+    let res =
+        timing::block(move || Schedule::update_schedule(&mut db.get().unwrap(), &name, &schedule))
+            .await?;
+    if let Err(error) = res {
+        return Ok(error_response(StatusCode::NOT_FOUND, error));
+    }
+
+    reload_and_list(conn, scheduler, StatusCode::OK).await
+}
+
+#[delete("/schedules/{name}")]
+async fn delete_schedule(
+    conn: Data<ConnectionPool>,
+    scheduler: Data<Scheduler>,
+    name: Path<String>,
+) -> actix_web::Result<impl Responder> {
+    let db = conn.clone();
+    let res =
+        timing::block(move || Schedule::delete_schedule(&mut db.get().unwrap(), &name)).await?;
+    if let Err(error) = res {
+        return Ok(error_response(StatusCode::NOT_FOUND, error));
+    }
+
+    reload_and_list(conn, scheduler, StatusCode::OK).await
+}
+
+/// Categories of the activity log with whether they are recorded and their severity
+#[get("/activity")]
+async fn list_activity_categories(activity_log: Data<ActivityLog>) -> impl Responder {
+    HttpResponse::Ok().json(activity_log.states())
+}
+
+#[put("/activity/{category}")]
+async fn update_activity_category(
+    conn: Data<ConnectionPool>,
+    activity_log: Data<ActivityLog>,
+    category: Path<String>,
+    setting: Json<CategorySetting>,
+) -> actix_web::Result<impl Responder> {
+    let Ok(category) = category.parse::<ActivityCategory>() else {
+        return Ok(message_response(
+            StatusCode::NOT_FOUND,
+            &Message::new("activity.category_not_found").arg(category),
+        ));
+    };
+    let setting = setting.into_inner();
+    // Otherwise turning categories off wouldn't leave a trace
+    if category == ActivityCategory::Settings && !setting.enabled {
+        return Ok(message_response(
+            StatusCode::BAD_REQUEST,
+            &Message::new("activity.settings_required"),
+        ));
+    }
+
+    let log = activity_log.clone();
+    let res = timing::block(move || log.update(&mut conn.get().unwrap(), category, Some(setting)))
+        .await?;
+    Ok(match res {
+        Ok(()) => HttpResponse::Ok().json(activity_log.states()),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
+
+/// Goes back to the default setting of the category
+#[delete("/activity/{category}")]
+async fn reset_activity_category(
+    conn: Data<ConnectionPool>,
+    activity_log: Data<ActivityLog>,
+    category: Path<String>,
+) -> actix_web::Result<impl Responder> {
+    let Ok(category) = category.parse::<ActivityCategory>() else {
+        return Ok(message_response(
+            StatusCode::NOT_FOUND,
+            &Message::new("activity.category_not_found").arg(category),
+        ));
+    };
+
+    let log = activity_log.clone();
+    let res = timing::block(move || log.update(&mut conn.get().unwrap(), category, None)).await?;
+    Ok(match res {
+        Ok(()) => HttpResponse::Ok().json(activity_log.states()),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
+
+#[derive(Serialize)]
+struct PublicKeyResponse {
+    /// OpenSSH format, as it is added to authorized_keys
+    public_key: String,
+    /// SHA256 fingerprint, as `ssh-keygen -l` prints it
+    fingerprint: Option<String>,
+}
+
+/// The key SSM logs in with, for adding it to new hosts
+#[get("/public_key")]
+async fn public_key(ssh_client: Data<dyn SshClient>) -> impl Responder {
+    let public_key = ssh_client.get_own_key_openssh();
+    let fingerprint = ssh_key::PublicKey::from_openssh(&public_key)
+        .ok()
+        .map(|key| key.fingerprint(ssh_key::HashAlg::Sha256).to_string());
+    HttpResponse::Ok().json(PublicKeyResponse {
+        public_key,
+        fingerprint,
+    })
+}
+
+#[derive(Serialize)]
+struct ReadOnlyState {
+    read_only: bool,
+    #[serde(flatten)]
+    state: Option<ReadOnly>,
+}
+
+impl ReadOnlyState {
+    fn of(freezes: &Freezes) -> Self {
+        let state = freezes.read_only();
+        Self {
+            read_only: state.is_some(),
+            state,
+        }
+    }
+}
+
+/// Whether SSM is read-only, why and since when
+#[get("/read_only")]
+async fn get_read_only(freezes: Data<Freezes>) -> impl Responder {
+    HttpResponse::Ok().json(ReadOnlyState::of(&freezes))
+}
+
+#[derive(Deserialize)]
+struct ReadOnlyChange {
+    enabled: bool,
+    /// Shown when a change is rejected (default "Maintenance")
+    reason: Option<String>,
+}
+
+/// Switches read-only mode on or off. While it is on, changes are rejected and jobs don't deploy.
+#[post("/read_only")]
+async fn set_read_only(
+    freezes: Data<Freezes>,
+    identity: Identity,
+    change: Json<ReadOnlyChange>,
+) -> impl Responder {
+    let ReadOnlyChange { enabled, reason } = change.into_inner();
+    let reason = reason
+        .map(|reason| reason.trim().to_owned())
+        .filter(|reason| !reason.is_empty())
+        .unwrap_or_else(|| String::from("Maintenance"));
+    freezes.set_read_only(enabled.then(|| ReadOnly {
+        reason,
+        by: actor(&identity),
+        since: now(),
+    }));
+    HttpResponse::Ok().json(ReadOnlyState::of(&freezes))
+}
+
+#[derive(Serialize)]
+struct DiffIgnoreRuleResponse {
+    #[serde(flatten)]
+    rule: DiffIgnoreRule,
+    /// Name of the host, `null` for rules of every host
+    host: Option<String>,
+}
+
+/// Rules for lines of authorized_keys files the diff doesn't report
+#[get("/diff_ignore")]
+async fn list_diff_ignore_rules(conn: Data<ConnectionPool>) -> actix_web::Result<impl Responder> {
+    let res = timing::block(move || DiffIgnoreRule::all(&mut conn.get().unwrap())).await?;
+
+    Ok(match res {
+        Ok(rules) => HttpResponse::Ok().json(
+            rules
+                .into_iter()
+                .map(|(rule, host)| DiffIgnoreRuleResponse { rule, host })
+                .collect::<Vec<_>>(),
+        ),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
+
+#[derive(Deserialize)]
+struct NewIgnoreRule {
+    /// Regular expression, searched for in each line
+    pattern: String,
+    /// Every host if not set
+    host: Option<String>,
+    /// Every login if not set
+    login: Option<String>,
+}
+
+/// Hides matching lines, like keys a cloud provider injects, from the diff
+#[post("/diff_ignore")]
+async fn add_diff_ignore_rule(
+    conn: Data<ConnectionPool>,
+    identity: Identity,
+    rule: Json<NewIgnoreRule>,
+) -> actix_web::Result<impl Responder> {
+    let NewIgnoreRule {
+        pattern,
+        host,
+        login,
+    } = rule.into_inner();
+    if pattern.is_empty() {
+        return Ok(error_response(
+            StatusCode::BAD_REQUEST,
+            "pattern is required".to_owned(),
+        ));
+    }
+    let host = host
+        .map(|host| host.trim().to_owned())
+        .filter(|host| !host.is_empty());
+    let login = login
+        .map(|login| login.trim().to_owned())
+        .filter(|login| !login.is_empty());
+
+    let actor = actor(&identity);
+    let res = timing::block(move || {
+        let mut conn = conn.get().unwrap();
+        let host_id = match host {
+            Some(name) => match Host::get_from_name_sync(&mut conn, name)? {
+                Some(host) => Some(host.id),
+                None => return Ok(None),
+            },
+            None => None,
+        };
+        DiffIgnoreRule::add(&mut conn, host_id, login, pattern, &actor).map(Some)
+    })
+    .await?;
+
+    Ok(match res {
+        Ok(Some(rule)) => HttpResponse::Created().json(rule),
+        Ok(None) => message_response(StatusCode::NOT_FOUND, &Message::new("host.not_found")),
+        Err(error) => error_response(StatusCode::BAD_REQUEST, error),
+    })
+}
+
+#[delete("/diff_ignore/{id}")]
+async fn delete_diff_ignore_rule(
+    conn: Data<ConnectionPool>,
+    id: Path<i32>,
+) -> actix_web::Result<impl Responder> {
+    let id = id.into_inner();
+    let res = timing::block(move || DiffIgnoreRule::delete(&mut conn.get().unwrap(), id)).await?;
+
+    Ok(match res {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(error) => error_response(StatusCode::NOT_FOUND, error),
+    })
+}
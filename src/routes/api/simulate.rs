@@ -0,0 +1,115 @@
+use actix_web::{
+    http::StatusCode,
+    post,
+    web::{self, Data, Json},
+    HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::simulation::{SimulatedChange, SimulatedFileChange},
+    models::{Host, User},
+    timing, ConnectionPool, DbConnection,
+};
+
+use super::error_response;
+
+pub fn simulate_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(simulate);
+}
+
+#[derive(Deserialize)]
+struct AuthorizationChange {
+    host: String,
+    username: String,
+    login: String,
+    options: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SimulationRequest {
+    #[serde(default)]
+    add_authorizations: Vec<AuthorizationChange>,
+    /// Options are ignored
+    #[serde(default)]
+    remove_authorizations: Vec<AuthorizationChange>,
+    #[serde(default)]
+    disable_users: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct SimulationResponse {
+    /// Only logins whose authorized_keys file would change
+    changes: Vec<SimulatedFileChange>,
+}
+
+/// Resolves the names of the request, `Err` for unknown hosts, users and authorizations
+fn resolve(
+    conn: &mut DbConnection,
+    request: SimulationRequest,
+) -> Result<Result<Vec<SimulatedChange>, String>, String> {
+    let mut changes = Vec::new();
+    for add in request.add_authorizations {
+        let Some(host) = Host::get_from_name_sync(conn, add.host.clone())? else {
+            return Ok(Err(format!("No such host '{}'", add.host)));
+        };
+        let Some(user) = User::find_user(conn, &add.username)? else {
+            return Ok(Err(format!("No such user '{}'", add.username)));
+        };
+        changes.push(SimulatedChange::Authorize {
+            host_id: host.id,
+            user_id: user.id,
+            login: add.login,
+            options: add.options.filter(|options| !options.is_empty()),
+        });
+    }
+    for remove in request.remove_authorizations {
+        let Some(host) = Host::get_from_name_sync(conn, remove.host.clone())? else {
+            return Ok(Err(format!("No such host '{}'", remove.host)));
+        };
+        let Some((id, _, _, _)) =
+            host.get_authorized_users(conn)?
+                .into_iter()
+                .find(|(_, username, login, _)| {
+                    username.eq(&remove.username) && login.eq(&remove.login)
+                })
+        else {
+            return Ok(Err(format!(
+                "'{}' isn't authorized as '{}' on '{}'",
+                remove.username, remove.login, remove.host
+            )));
+        };
+        changes.push(SimulatedChange::RemoveAuthorization(id));
+    }
+    for username in request.disable_users {
+        let Some(user) = User::find_user(conn, &username)? else {
+            return Ok(Err(format!("No such user '{username}'")));
+        };
+        changes.push(SimulatedChange::DisableUser(user.id));
+    }
+    Ok(Ok(changes))
+}
+
+/// Which authorized_keys files a set of changes would change and how, without changing anything.
+/// Authorization rules are taken into account.
+#[post("")]
+async fn simulate(
+    conn: Data<ConnectionPool>,
+    request: Json<SimulationRequest>,
+) -> actix_web::Result<impl Responder> {
+    let request = request.into_inner();
+    let res = timing::block(move || {
+        let mut conn = conn.get().unwrap();
+        match resolve(&mut conn, request)? {
+            Ok(changes) => SimulatedChange::simulate(&mut conn, &changes).map(Ok),
+            Err(error) => Ok(Err(error)),
+        }
+    })
+    .await?;
+
+    Ok(match res {
+        Ok(Ok(changes)) => HttpResponse::Ok().json(SimulationResponse { changes }),
+        Ok(Err(error)) => error_response(StatusCode::BAD_REQUEST, error),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
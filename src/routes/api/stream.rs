@@ -0,0 +1,103 @@
+//! Exports streamed in chunks while they are read from the database, so large fleets don't
+//! have to fit the whole document into memory
+use actix_web::{web::Bytes, HttpResponse};
+use futures::stream;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::export::{csv_line, CsvRecord};
+
+/// Rows are collected into chunks of about this many bytes before they are sent
+const CHUNK_SIZE: usize = 64 * 1024;
+/// How many chunks may wait for a slow client before reading the database pauses
+const CHUNKS_IN_FLIGHT: usize = 4;
+
+/// How a list endpoint answers
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ListFormat {
+    /// One JSON array, built in memory
+    #[default]
+    Json,
+    /// One JSON object per line, streamed
+    Ndjson,
+    /// A header line and one record per row, streamed
+    Csv,
+}
+
+/// Streams the rows `produce` hands to its callback as NDJSON or CSV. `produce` runs on a blocking
+/// thread and should read the rows in pages, the callback returns false once the client is gone.
+/// An error after the response started aborts it, so clients don't take a partial export for a complete one.
+pub fn stream_rows<T, F>(format: ListFormat, name: &str, produce: F) -> HttpResponse
+where
+    T: Serialize + CsvRecord,
+    F: FnOnce(&mut dyn FnMut(T) -> bool) -> Result<(), String> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel::<Result<Bytes, String>>(CHUNKS_IN_FLIGHT);
+    let export = name.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let mut chunk = String::new();
+        if format == ListFormat::Csv {
+            chunk.push_str(&csv_line(T::HEADER));
+        }
+        let send = |chunk: &mut String| {
+            sender
+                .blocking_send(Ok(Bytes::from(std::mem::take(chunk))))
+                .is_ok()
+        };
+        let mut open = true;
+        let res = produce(&mut |row: T| {
+            match format {
+                ListFormat::Csv => chunk.push_str(&csv_line(&row.fields())),
+                _ => match serde_json::to_string(&row) {
+                    Ok(line) => {
+                        chunk.push_str(&line);
+                        chunk.push('\n');
+                    }
+                    Err(e) => warn!("Failed to serialize a row of the {export} export: {e}"),
+                },
+            }
+            if chunk.len() >= CHUNK_SIZE {
+                open = send(&mut chunk);
+            }
+            open
+        });
+        match res {
+            Ok(()) if open && !chunk.is_empty() => {
+                send(&mut chunk);
+            }
+            Ok(()) => {}
+            Err(e) => {
+                error!("Failed to read the {export} export: {e}");
+                let _ = sender.blocking_send(Err(e));
+            }
+        }
+    });
+
+    let body = stream::unfold(receiver, |mut receiver| async move {
+        let chunk = receiver
+            .recv()
+            .await?
+            .map_err(actix_web::error::ErrorInternalServerError);
+        Some((chunk, receiver))
+    });
+    let content_type = match format {
+        ListFormat::Csv => "text/csv; charset=utf-8",
+        _ => "application/x-ndjson",
+    };
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((
+            "Content-Disposition",
+            format!(
+                "attachment; filename=\"ssm-{name}.{}\"",
+                if format == ListFormat::Csv {
+                    "csv"
+                } else {
+                    "ndjson"
+                }
+            ),
+        ))
+        .streaming(body)
+}
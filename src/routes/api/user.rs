@@ -0,0 +1,139 @@
+use actix_identity::Identity;
+use actix_web::{
+    get,
+    http::StatusCode,
+    post, put,
+    web::{self, Data, Json, Path, Query},
+    HttpResponse, Responder,
+};
+use serde::Deserialize;
+
+use crate::{
+    db::{user::UserFilter, user_merge::UserMerge},
+    i18n::Message,
+    models::{User, UserDetails},
+    routes::actor,
+    timing, ConnectionPool,
+};
+
+use super::{error_response, message_response};
+
+pub fn user_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(list)
+        .service(merge_users)
+        .service(update_details);
+}
+
+#[derive(Deserialize)]
+struct UserQuery {
+    department: Option<String>,
+    email: Option<String>,
+    external_id: Option<String>,
+    /// Part of the username or full name
+    name: Option<String>,
+}
+
+/// Users with their details, filtered by department, email, external id or name
+#[get("")]
+async fn list(
+    conn: Data<ConnectionPool>,
+    params: Query<UserQuery>,
+) -> actix_web::Result<impl Responder> {
+    let params = params.into_inner();
+    let filter = UserFilter {
+        department: params.department,
+        email: params.email,
+        external_id: params.external_id,
+        name: params.name,
+    };
+    let res = timing::block(move || User::search(&mut conn.get().unwrap(), &filter)).await?;
+
+    Ok(match res {
+        Ok(users) => HttpResponse::Ok().json(users),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
+
+/// Replaces email, full name, department and external id of a user, e.g. synced from an identity provider
+#[put("/{name}")]
+async fn update_details(
+    conn: Data<ConnectionPool>,
+    name: Path<String>,
+    details: Json<UserDetails>,
+) -> actix_web::Result<impl Responder> {
+    let details = match details.into_inner().normalized() {
+        Ok(details) => details,
+        Err(error) => return Ok(error_response(StatusCode::BAD_REQUEST, error)),
+    };
+    let username = name.into_inner();
+    let not_found = Message::new("user.not_found").arg(&username);
+
+    let res = timing::block(move || {
+        let mut conn = conn.get().unwrap();
+        if User::find_user(&mut conn, &username)?.is_none() {
+            return Ok(None);
+        }
+        if let Err(error) = User::set_details(&mut conn, &username, &details) {
+            return Ok(Some(Err(error)));
+        }
+        User::get_user(&mut conn, username).map(|user| Some(Ok(user)))
+    })
+    .await?;
+
+    Ok(match res {
+        Ok(Some(Ok(user))) => HttpResponse::Ok().json(user),
+        Ok(Some(Err(error))) => error_response(StatusCode::BAD_REQUEST, error),
+        Ok(None) => message_response(StatusCode::NOT_FOUND, &not_found),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
+
+#[derive(Deserialize)]
+struct MergeRequest {
+    /// User that is merged and deleted
+    from: String,
+    /// User that receives the keys and authorizations
+    into: String,
+    /// Only report what would change
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Merges two records of the same person, e.g. `jdoe` and `john.doe`, into one
+#[post("/merge")]
+async fn merge_users(
+    conn: Data<ConnectionPool>,
+    identity: Identity,
+    request: Json<MergeRequest>,
+) -> actix_web::Result<impl Responder> {
+    let MergeRequest {
+        from,
+        into,
+        dry_run,
+    } = request.into_inner();
+    if from.eq(&into) {
+        return Ok(message_response(
+            StatusCode::BAD_REQUEST,
+            &Message::new("user.merge_into_itself"),
+        ));
+    }
+    let actor = actor(&identity);
+
+    let res = timing::block(move || {
+        let mut conn = conn.get().unwrap();
+        let (Some(from_user), Some(into_user)) = (
+            User::find_user(&mut conn, &from)?,
+            User::find_user(&mut conn, &into)?,
+        ) else {
+            return Ok(Err(Message::new("user.pair_not_found").arg(from).arg(into)));
+        };
+        UserMerge::merge(&mut conn, &from_user, &into_user, &actor, dry_run).map(Ok)
+    })
+    .await?;
+
+    Ok(match res {
+        Ok(Ok(merge)) => HttpResponse::Ok().json(merge),
+        Ok(Err(error)) => message_response(StatusCode::NOT_FOUND, &error),
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error),
+    })
+}
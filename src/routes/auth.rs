@@ -1,16 +1,20 @@
 use actix_identity::Identity;
+use actix_session::SessionExt;
 use actix_web::{
     get, post,
     web::{self, Data, Form},
     HttpMessage, HttpRequest, HttpResponse, Responder,
 };
 use askama_actix::{Template, TemplateToResponse};
-use bcrypt::{verify, BcryptError};
-use log::error;
+use log::{error, info, warn};
 use serde::Deserialize;
 use std::fs;
 
-use crate::{Configuration, ConnectionPool};
+use crate::{
+    htpasswd::{self, HashScheme},
+    middleware::CREDENTIAL_SESSION_KEY,
+    Configuration, ConnectionPool,
+};
 
 use super::ErrorTemplate;
 
@@ -30,22 +34,6 @@ pub struct LoginForm {
     password: String,
 }
 
-fn verify_apache_password(password: &str, hash: &str) -> Result<bool, BcryptError> {
-    // Apache htpasswd bcrypt format starts with $2y$
-
-    match &hash[..4] {
-        "$2y$" => {
-            let converted_hash = format!("$2b${}", &hash[4..]);
-            verify(password, &converted_hash)
-        }
-        "$2b$" => verify(password, hash),
-        hash_type => {
-            error!("Unsupported hash type '{hash_type}' encountered.");
-            Ok(false)
-        }
-    }
-}
-
 #[get("/login")]
 async fn login_page() -> impl Responder {
     LoginTemplate {}.to_response()
@@ -81,24 +69,37 @@ async fn login(
         }
     };
 
-    let mut is_valid = false;
-    for line in password_file.lines() {
-        if let Some((username, hash)) = line.split_once(':') {
-            if username == form.username {
-                match verify_apache_password(&form.password, hash) {
-                    Ok(valid) => {
-                        is_valid = valid;
-                        break;
-                    }
-                    Err(_) => continue,
-                }
+    let hash = htpasswd::hash_of(&password_file, &form.username);
+    let is_valid = hash.is_some_and(|hash| htpasswd::verify(&form.password, hash));
+    let mut credential = hash.map(htpasswd::fingerprint).unwrap_or_default();
+
+    let legacy = hash
+        .and_then(HashScheme::of)
+        .filter(|scheme| scheme.is_legacy());
+    if let Some(scheme) = legacy.filter(|_| is_valid && config.upgrade_password_hashes) {
+        let path = htpasswd_path.to_owned();
+        let (username, password) = (form.username.clone(), form.password.clone());
+        let upgraded = web::block(move || htpasswd::set_password(&path, &username, &password))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|result| result);
+        let username = &form.username;
+        match upgraded {
+            Ok(hash) => {
+                info!("Upgraded the password hash of {username} from {scheme:?} to argon2id in the htpasswd file");
+                credential = htpasswd::fingerprint(&hash);
             }
+            Err(e) => warn!("Couldn't upgrade the {scheme:?} password hash of {username}: {e}"),
         }
     }
 
     if is_valid {
         Identity::login(&req.extensions(), form.username.clone())
             .map_err(actix_web::error::ErrorInternalServerError)?;
+        // Sessions end when the password changes, see the AuthMiddleware
+        req.get_session()
+            .insert(CREDENTIAL_SESSION_KEY, credential)
+            .map_err(actix_web::error::ErrorInternalServerError)?;
         Ok(HttpResponse::Found()
             .insert_header(("Location", "/"))
             .finish())
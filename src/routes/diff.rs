@@ -1,7 +1,8 @@
 use crate::{
     routes::{should_update, ForceUpdate},
-    ssh::{CachingSshClient, DiffItem, SshClient, SshClientError},
+    ssh::{CacheInfo, DiffItem, HostCache, SshClient, SshClientError},
     templates::AsHTML,
+    Configuration,
 };
 use actix_web::{
     get, post,
@@ -11,13 +12,14 @@ use actix_web::{
 use askama_actix::{Template, TemplateToResponse};
 use log::warn;
 use serde::Deserialize;
-use time::OffsetDateTime;
+use tokio::time::Instant;
 
 use crate::{
     forms::{FormResponseBuilder, Modal},
+    i18n::Message,
     routes::{ErrorTemplate, RenderErrorTemplate},
     ssh::SshPublicKey,
-    ConnectionPool,
+    timing, ConnectionPool,
 };
 
 use crate::models::{Host, User};
@@ -38,7 +40,7 @@ struct DiffPageTemplate {
 
 #[get("")]
 async fn diff_page(conn: Data<ConnectionPool>) -> actix_web::Result<impl Responder> {
-    let hosts = web::block(move || Host::get_all_hosts(&mut conn.get().unwrap())).await?;
+    let hosts = timing::block(move || Host::get_all_hosts(&mut conn.get().unwrap())).await?;
 
     Ok(match hosts {
         Ok(hosts) => DiffPageTemplate { hosts }.to_response(),
@@ -51,12 +53,21 @@ async fn diff_page(conn: Data<ConnectionPool>) -> actix_web::Result<impl Respond
 struct RenderDiffTemplate {
     host: Host,
     diff: Result<Vec<(String, Vec<DiffItem>)>, SshClientError>,
-    cached_from: OffsetDateTime,
+    cache: CacheInfo,
+    /// The host didn't answer before the deadline, this is the cached state
+    incomplete: bool,
+}
+
+/// Shown while a host without cached data is still being fetched, asks again in a few seconds
+#[derive(Template)]
+#[template(path = "diff/pending.htm")]
+struct PendingDiffTemplate {
+    host: Host,
 }
 
 async fn check_host_fingerprint(
     conn: &ConnectionPool,
-    ssh_client: &SshClient,
+    ssh_client: &dyn SshClient,
     host: &Host,
 ) -> Result<(), actix_web::Error> {
     let target = host.to_connection().unwrap();
@@ -76,7 +87,7 @@ async fn check_host_fingerprint(
 
     // Check fingerprint
     if let Ok(key_receiver) = connection_res {
-        if let Ok(current_fingerprint) = web::block(move || key_receiver.recv()).await? {
+        if let Ok(current_fingerprint) = timing::block(move || key_receiver.recv()).await? {
             match &host.key_fingerprint {
                 Some(stored_fingerprint) if current_fingerprint != *stored_fingerprint => {
                     warn!("Host {} key mismatch - Stored: {}, Current: {}", 
@@ -96,11 +107,13 @@ async fn check_host_fingerprint(
 #[get("/{host_name}.htm")]
 async fn render_diff(
     conn: Data<ConnectionPool>,
-    caching_ssh_client: Data<CachingSshClient>,
-    ssh_client: Data<SshClient>,
+    caching_ssh_client: Data<dyn HostCache>,
+    ssh_client: Data<dyn SshClient>,
+    config: Data<Configuration>,
     host_name: Path<String>,
     force_update: ForceUpdate,
 ) -> actix_web::Result<impl Responder> {
+    let deadline = Instant::now() + config.ssh.diff_deadline;
     let res = Host::get_from_name(conn.get().unwrap(), host_name.to_string()).await;
 
     let host = match res {
@@ -116,18 +129,30 @@ async fn render_diff(
         Err(error) => return Ok(RenderErrorTemplate { error }.to_response()),
     };
 
-    check_host_fingerprint(&conn, &ssh_client, &host).await?;
+    // Only logs a mismatch, so a host that doesn't answer in time is skipped
+    if let Ok(res) = tokio::time::timeout_at(
+        deadline,
+        check_host_fingerprint(&conn, ssh_client.as_ref(), &host),
+    )
+    .await
+    {
+        res?;
+    }
 
-    let (cached_from, diff) = caching_ssh_client
-        .get_host_diff(host.clone(), should_update(force_update))
+    let (diff, complete) = caching_ssh_client
+        .get_host_diff_until(host.clone(), should_update(force_update), deadline)
         .await;
 
-    Ok(RenderDiffTemplate {
-        host,
-        diff,
-        cached_from,
-    }
-    .to_response())
+    Ok(match diff {
+        Some((cache, diff)) => RenderDiffTemplate {
+            host,
+            diff,
+            cache,
+            incomplete: !complete,
+        }
+        .to_response(),
+        None => PendingDiffTemplate { host }.to_response(),
+    })
 }
 
 #[derive(Template)]
@@ -169,7 +194,7 @@ async fn assign_key_dialog(
     conn: Data<ConnectionPool>,
     key: web::Form<SshPublicKey>,
 ) -> actix_web::Result<impl Responder> {
-    let res = web::block(move || User::get_all_users(&mut conn.get().unwrap())).await?;
+    let res = timing::block(move || User::get_all_users(&mut conn.get().unwrap())).await?;
 
     Ok(match res {
         Ok(users) => FormResponseBuilder::dialog(Modal {
@@ -205,7 +230,7 @@ async fn authorize_user_dialog(
     form: web::Form<AuthorizeUserForm>,
 ) -> actix_web::Result<impl Responder> {
     let login = form.login.clone();
-    let (user, host) = web::block(move || {
+    let (user, host) = timing::block(move || {
         let mut connection = conn.get().unwrap();
 
         let user = User::get_user(&mut connection, form.username.clone());
@@ -225,7 +250,11 @@ async fn authorize_user_dialog(
     let host = match host {
         Ok(h) => match h {
             Some(h) => h,
-            None => return Ok(FormResponseBuilder::error(String::from("Host not found"))),
+            None => {
+                return Ok(FormResponseBuilder::error(
+                    Message::new("host.not_found").to_string(),
+                ))
+            }
         },
         Err(error) => return Ok(FormResponseBuilder::error(error)),
     };
@@ -1,27 +1,45 @@
+use actix_identity::Identity;
 use actix_web::{
     get, post,
     web::{self, Data, Path},
     Responder,
 };
 use askama_actix::{Template, TemplateToResponse};
-use log::{debug, info};
+use log::{debug, info, warn};
 use serde::Deserialize;
+use std::str::FromStr;
 
 use crate::{
-    db::UserAndOptions,
+    access::AccessControl,
+    db::{PrincipalGrant, RuleGrant, UserAndOptions},
     forms::{FormResponseBuilder, Modal},
-    routes::{should_update, ErrorTemplate, ForceUpdate, RenderErrorTemplate},
-    ssh::{CachingSshClient, ConnectionDetails, KeyDiffItem, SshClient, SshClientError},
-    ConnectionPool, DbConnection,
+    freeze::Freezes,
+    hooks::{Event, EventHooks},
+    i18n::Message,
+    routes::{actor, should_update, ErrorTemplate, ForceUpdate, RenderErrorTemplate},
+    ssh::{
+        deploy_principals, locks_out, locks_out_ssm, AddressFamily, CacheInfo, ConnectionDetails,
+        HostCache, KeyDiffItem, Proxy, SshClient, SshClientError, TransportKind,
+    },
+    sshd::{host_report, key_sources, KeySources, SshdReport},
+    timing, Configuration, ConnectionPool, DbConnection,
 };
 
-use crate::models::{Host, NewHost, User};
+use crate::db::history::now;
+use crate::db::host_confirmation::{DELETE, DEPLOY};
+use crate::db::security_event::{FREEZE_OVERRIDE, WARNING};
+use crate::models::{
+    Host, HostConfirmation, KeyHistory, NewHost, PendingHost, SecurityEvent, User,
+};
 
 pub fn hosts_config(cfg: &mut web::ServiceConfig) {
     cfg.service(hosts_page)
         .service(render_hosts)
         .service(show_host)
         .service(get_logins)
+        .service(get_sshd_config)
+        .service(get_key_sources)
+        .service(confirm_host)
         .service(add_host)
         .service(authorize_user)
         .service(gen_authorized_keys)
@@ -35,14 +53,25 @@ pub fn hosts_config(cfg: &mut web::ServiceConfig) {
 
 #[derive(Template)]
 #[template(path = "hosts/index.html")]
-struct HostsTemplate {}
+struct HostsTemplate {
+    transports: [TransportKind; 3],
+}
 
 #[get("")]
 async fn hosts_page() -> impl Responder {
-    HostsTemplate {}
+    HostsTemplate {
+        transports: TransportKind::ALL,
+    }
 }
 
-type HostData = (Host, Option<String>, Vec<UserAndOptions>, Vec<User>);
+type HostData = (
+    Host,
+    Option<String>,
+    Vec<UserAndOptions>,
+    Vec<RuleGrant>,
+    Vec<PrincipalGrant>,
+    Vec<User>,
+);
 
 enum HostDataError {
     HostNotFound,
@@ -67,27 +96,50 @@ fn get_all_host_data(conn: &mut DbConnection, host: String) -> Result<HostData,
     let authorized_users = host
         .get_authorized_users(conn)
         .map_err(HostDataError::DatabaseError)?;
+    let rule_grants = host
+        .get_rule_grants(conn)
+        .map_err(HostDataError::DatabaseError)?;
+    let principals = if host.principals_file.is_some() {
+        host.get_authorized_principals(conn)
+            .map_err(HostDataError::DatabaseError)?
+    } else {
+        vec![]
+    };
 
     // Skip getting users if we can't connect
     if host.key_fingerprint.is_none() {
-        return Ok((host, jumphost, authorized_users, vec![]));
+        return Ok((
+            host,
+            jumphost,
+            authorized_users,
+            rule_grants,
+            principals,
+            vec![],
+        ));
     }
 
     let user_list = User::get_all_users(conn).map_err(HostDataError::DatabaseError)?;
 
-    Ok((host, jumphost, authorized_users, user_list))
+    Ok((
+        host,
+        jumphost,
+        authorized_users,
+        rule_grants,
+        principals,
+        user_list,
+    ))
 }
 
 #[derive(Template)]
 #[template(path = "hosts/logins.htm")]
 struct LoginsTemplate {
-    logins: Result<Vec<String>, SshClientError>,
+    logins: Result<(CacheInfo, Vec<String>), SshClientError>,
 }
 
 #[get("/{name}/logins")]
 async fn get_logins(
     conn: Data<ConnectionPool>,
-    caching_ssh_client: Data<CachingSshClient>,
+    caching_ssh_client: Data<dyn HostCache>,
     host_name: Path<String>,
     update: ForceUpdate,
 ) -> actix_web::Result<impl Responder> {
@@ -108,24 +160,89 @@ async fn get_logins(
     }
 }
 
+#[derive(Template)]
+#[template(path = "hosts/sshd_config.htm")]
+struct SshdConfigTemplate {
+    report: Result<SshdReport, SshClientError>,
+}
+
+#[get("/{name}/sshd_config")]
+async fn get_sshd_config(
+    conn: Data<ConnectionPool>,
+    ssh_client: Data<dyn SshClient>,
+    config: Data<Configuration>,
+    host_name: Path<String>,
+) -> actix_web::Result<impl Responder> {
+    if !config.sshd_config.enabled {
+        return Ok(RenderErrorTemplate {
+            error: "Reading sshd_config is disabled".to_owned(),
+        }
+        .to_response());
+    }
+    match Host::get_from_name(conn.get().unwrap(), host_name.to_string()).await {
+        Err(error) => Ok(RenderErrorTemplate { error }.to_response()),
+        Ok(None) => Ok(RenderErrorTemplate {
+            error: "Host not found".to_owned(),
+        }
+        .to_response()),
+        Ok(Some(host)) => {
+            let report = host_report(ssh_client.get_ref(), &config.sshd_config, host).await;
+            Ok(SshdConfigTemplate { report }.to_response())
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "hosts/key_sources.htm")]
+struct KeySourcesTemplate {
+    sources: Result<KeySources, SshClientError>,
+}
+
+/// Warns when sshd doesn't only rely on the authorized_keys files SSM manages
+#[get("/{name}/key_sources")]
+async fn get_key_sources(
+    conn: Data<ConnectionPool>,
+    ssh_client: Data<dyn SshClient>,
+    host_name: Path<String>,
+) -> actix_web::Result<impl Responder> {
+    match Host::get_from_name(conn.get().unwrap(), host_name.to_string()).await {
+        Err(error) => Ok(RenderErrorTemplate { error }.to_response()),
+        Ok(None) => Ok(RenderErrorTemplate {
+            error: "Host not found".to_owned(),
+        }
+        .to_response()),
+        Ok(Some(host)) => {
+            let sources = key_sources(ssh_client.get_ref(), host).await;
+            Ok(KeySourcesTemplate { sources }.to_response())
+        }
+    }
+}
+
 #[derive(Template)]
 #[template(path = "hosts/show_host.html")]
 struct ShowHostTemplate {
     host: Host,
     jumphost: Option<String>,
     authorized_users: Vec<UserAndOptions>,
+    /// Logins granted by authorization rules
+    rule_grants: Vec<RuleGrant>,
+    /// Certificate principals, only for hosts with a principals file
+    principals: Vec<PrincipalGrant>,
     user_list: Vec<User>,
+    /// Load the sshd_config directives of the host
+    sshd_config: bool,
 }
 
 #[get("/{name}")]
 async fn show_host(
     conn: Data<ConnectionPool>,
+    config: Data<Configuration>,
     host: Path<String>,
 ) -> actix_web::Result<impl Responder> {
     let res =
-        web::block(move || get_all_host_data(&mut conn.get().unwrap(), host.to_string())).await?;
+        timing::block(move || get_all_host_data(&mut conn.get().unwrap(), host.to_string())).await?;
 
-    let (host, jumphost, authorized_users, user_list) = match res {
+    let (host, jumphost, authorized_users, rule_grants, principals, user_list) = match res {
         Ok(host_data) => host_data,
         Err(e) => {
             return Ok(match e {
@@ -142,7 +259,10 @@ async fn show_host(
         host,
         jumphost,
         authorized_users,
+        rule_grants,
+        principals,
         user_list,
+        sshd_config: config.sshd_config.enabled,
     }
     .to_response())
 }
@@ -155,7 +275,9 @@ struct AddHostkeyForm {
 #[post("/{id}/add_hostkey")]
 async fn add_host_key(
     conn: Data<ConnectionPool>,
-    ssh_client: Data<SshClient>,
+    ssh_client: Data<dyn SshClient>,
+    access: Data<AccessControl>,
+    identity: Identity,
     host_id: Path<i32>,
     new_hostkey: web::Form<AddHostkeyForm>,
 ) -> actix_web::Result<impl Responder> {
@@ -167,13 +289,18 @@ async fn add_host_key(
     };
 
     match host {
+        Some(host) if !access.may_change_host(&actor(&identity), &host) => Ok(
+            FormResponseBuilder::forbidden(AccessControl::denied(&actor(&identity), &host.name)),
+        ),
         Some(host) => {
             if let Some(ref new_hostkey) = new_hostkey.key_fingerprint {
                 let res =
                     host.update_fingerprint(&mut cloned_conn.get().unwrap(), new_hostkey.clone());
                 return Ok(match res {
-                    Ok(()) => FormResponseBuilder::created("Added hostkey".to_owned())
-                        .add_trigger("reloadDiff".to_owned()),
+                    Ok(()) => {
+                        FormResponseBuilder::created(Message::new("hostkey.added").to_string())
+                            .add_trigger("reloadDiff".to_owned())
+                    }
                     Err(e) => FormResponseBuilder::error(e),
                 });
             }
@@ -185,7 +312,9 @@ async fn add_host_key(
 
             let connection_res = match maybe_jumphost {
                 Some(Ok(None)) => {
-                    return Ok(FormResponseBuilder::error("Jump host not found".to_owned()));
+                    return Ok(FormResponseBuilder::error(
+                        Message::new("host.jump_host_not_found").to_string(),
+                    ));
                 }
                 Some(Err(e)) => {
                     return Ok(FormResponseBuilder::error(e));
@@ -199,7 +328,7 @@ async fn add_host_key(
                 Err(e) => return Ok(FormResponseBuilder::error(e.to_string())),
             };
 
-            let Ok(key_fingerprint) = web::block(move || key_receiver.recv()).await? else {
+            let Ok(key_fingerprint) = timing::block(move || key_receiver.recv()).await? else {
                 return Ok(FormResponseBuilder::error(String::from(
                     "Connection timed out",
                 )));
@@ -214,13 +343,17 @@ async fn add_host_key(
                     address: host.address,
                     port: host.port,
                     jumphost: host.jump_via,
+                    transport: host.transport,
+                    tags: host.tags,
+                    proxy: host.proxy.unwrap_or_default(),
                     key_fingerprint,
+                    confirmation: None,
                 }
                 .to_string(),
             }))
         }
         None => Ok(FormResponseBuilder::not_found(
-            "Couldn't find host".to_owned(),
+            Message::new("host.not_found").to_string(),
         )),
     }
 }
@@ -231,9 +364,18 @@ struct HostkeyDialog {
     name: String,
     username: String,
     address: String,
-    port: i32,
+    port: u16,
     key_fingerprint: String,
     jumphost: Option<i32>,
+    transport: String,
+    tags: String,
+    proxy: String,
+    /// Token of the pending host, when adding a new host
+    confirmation: Option<String>,
+}
+
+fn default_transport() -> String {
+    TransportKind::default().to_string()
 }
 
 #[derive(Deserialize)]
@@ -241,19 +383,43 @@ struct HostAddForm {
     name: String,
     username: String,
     address: String,
-    port: i32,
+    port: u16,
     jumphost: Option<i32>,
     key_fingerprint: Option<String>,
+    #[serde(default = "default_transport")]
+    transport: String,
+    #[serde(default)]
+    tags: String,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    proxy: Option<String>,
 }
 
 #[post("/add")]
 async fn add_host(
     conn: Data<ConnectionPool>,
-    ssh_client: Data<SshClient>,
+    ssh_client: Data<dyn SshClient>,
+    event_hooks: Data<EventHooks>,
+    access: Data<AccessControl>,
+    identity: Identity,
     form: web::Form<HostAddForm>,
 ) -> actix_web::Result<impl Responder> {
     let form = form.0;
 
+    let actor = actor(&identity);
+    if !access.may_use_tags(&actor, &form.tags) {
+        return Ok(FormResponseBuilder::forbidden(AccessControl::denied(
+            &actor, &form.name,
+        )));
+    }
+
+    if let Err(error) = TransportKind::from_str(&form.transport) {
+        return Ok(FormResponseBuilder::error(error));
+    }
+    let proxy = match form.proxy.as_deref().map(Proxy::from_str).transpose() {
+        Ok(proxy) => proxy,
+        Err(error) => return Ok(FormResponseBuilder::error(error)),
+    };
+
     // TODO: better error handling for jumphost (serde deserialize opt)
     let cloned_conn = conn.clone();
     let maybe_jumphost: Option<Host> = if let Some(via) = form.jumphost {
@@ -263,25 +429,23 @@ async fn add_host(
             match Host::get_from_id(cloned_conn.get().unwrap(), via).await {
                 Ok(j) => j,
                 Err(_) => {
-                    return Ok(FormResponseBuilder::not_found(String::from(
-                        "Couldn't find jump host",
-                    )));
+                    return Ok(FormResponseBuilder::not_found(
+                        Message::new("host.jump_host_not_found").to_string(),
+                    ));
                 }
             }
         }
     } else {
         None
     };
-    let Ok(address) = ConnectionDetails::new_from_signed(form.address.clone(), form.port) else {
-        return Ok(FormResponseBuilder::error(String::from(
-            "Invalid port number",
-        )));
-    };
+    let mut address = ConnectionDetails::new(form.address.clone(), form.port);
+    address.proxy = proxy;
     debug!(
         "Trying to connect to {} on port {} via jumphost: {:?}",
         &address.hostname, &address.port, maybe_jumphost
     );
     let Some(key_fingerprint) = form.key_fingerprint else {
+        let jump_via = maybe_jumphost.as_ref().map(|h| h.id);
         let connection_res = match maybe_jumphost {
             Some(via) => ssh_client.get_hostkey_via(via, address).await,
             None => ssh_client.get_hostkey(address).await,
@@ -292,67 +456,195 @@ async fn add_host(
             Err(e) => return Ok(FormResponseBuilder::error(e.to_string())),
         };
 
-        let Ok(key_fingerprint) = web::block(move || key_receiver.recv()).await? else {
+        let Ok(key_fingerprint) = timing::block(move || key_receiver.recv()).await? else {
             return Ok(FormResponseBuilder::error(String::from(
                 "Connection timed out",
             )));
         };
 
+        let pending = NewHost {
+            name: form.name.clone(),
+            address: form.address.clone(),
+            port: form.port,
+            username: form.username.clone(),
+            key_fingerprint: key_fingerprint.clone(),
+            jump_via,
+            transport: form.transport.clone(),
+            tags: Host::normalize_tags(&form.tags),
+            proxy: form.proxy.clone(),
+        };
+        let token = match timing::block(move || PendingHost::create(&mut conn.get().unwrap(), pending))
+            .await?
+        {
+            Ok(token) => token,
+            Err(e) => return Ok(FormResponseBuilder::error(e)),
+        };
+
         return Ok(FormResponseBuilder::dialog(Modal {
             title: String::from("Please check the hostkey"),
-            request_target: String::from("/hosts/add"),
+            request_target: String::from("/hosts/add/confirm"),
             template: HostkeyDialog {
                 name: form.name,
                 username: form.username,
                 address: form.address,
                 port: form.port,
                 jumphost: form.jumphost,
+                transport: form.transport,
+                tags: form.tags,
+                proxy: form.proxy.unwrap_or_default(),
                 key_fingerprint,
+                confirmation: Some(token),
             }
             .to_string(),
         }));
     };
 
-    if let Err(error) = {
-        match maybe_jumphost {
-            Some(ref via) => {
-                ssh_client
-                    .try_authenticate_via(
-                        via.clone(),
-                        address,
-                        key_fingerprint.clone(),
-                        form.username.clone(),
-                    )
-                    .await
-            }
-            None => {
-                ssh_client
-                    .try_authenticate(address, key_fingerprint.clone(), form.username.clone())
-                    .await
-            }
-        }
-    } {
-        return Ok(FormResponseBuilder::error(error.to_string()));
-    };
-
     let new_host = NewHost {
-        name: form.name.clone(),
+        name: form.name,
         address: form.address,
         port: form.port,
         username: form.username,
         key_fingerprint,
-        jump_via: maybe_jumphost.map(|h| h.id),
+        jump_via: maybe_jumphost.as_ref().map(|h| h.id),
+        transport: form.transport,
+        tags: Host::normalize_tags(&form.tags),
+        proxy: form.proxy,
     };
-    let res = web::block(move || Host::add_host(&mut conn.get().unwrap(), &new_host)).await?;
-
-    Ok(match res {
-        Ok(id) => match ssh_client.install_script_on_host(id).await {
-            Ok(()) => FormResponseBuilder::created(String::from("Added host"))
-                .add_trigger(String::from("reload-hosts")),
-            Err(error) => FormResponseBuilder::error(format!("Failed to install script: {error}")),
+    Ok(
+        match add_confirmed_host(
+            &conn,
+            ssh_client.get_ref(),
+            &event_hooks,
+            maybe_jumphost,
+            address,
+            new_host,
+        )
+        .await
+        {
+            Ok(id) => install_script_response(ssh_client.get_ref(), id).await,
+            Err(e) => FormResponseBuilder::error(e),
         },
-        Err(e) => FormResponseBuilder::error(e),
+    )
+}
+
+#[derive(Deserialize)]
+struct ConfirmHostForm {
+    confirmation: String,
+}
+
+/// Adds a host with the host key that was shown when it was discovered
+#[post("/add/confirm")]
+async fn confirm_host(
+    conn: Data<ConnectionPool>,
+    ssh_client: Data<dyn SshClient>,
+    event_hooks: Data<EventHooks>,
+    access: Data<AccessControl>,
+    identity: Identity,
+    form: web::Form<ConfirmHostForm>,
+) -> actix_web::Result<impl Responder> {
+    let pool = conn.clone();
+    let token = form.into_inner().confirmation;
+    let pending =
+        match timing::block(move || PendingHost::get(&mut pool.get().unwrap(), &token)).await? {
+            Ok(Some(pending)) => pending,
+            Ok(None) => {
+                return Ok(FormResponseBuilder::error(
+                    Message::new("confirmation.expired").to_string(),
+                ))
+            }
+            Err(e) => return Ok(FormResponseBuilder::error(e)),
+        };
+    let actor = actor(&identity);
+    if !access.may_use_tags(&actor, &pending.tags) {
+        return Ok(FormResponseBuilder::forbidden(AccessControl::denied(
+            &actor,
+            &pending.name,
+        )));
+    }
+    let token = pending.token.clone();
+
+    let (jumphost, address) = match pending.connection(&conn).await {
+        Ok(connection) => connection,
+        Err(e) => return Ok(FormResponseBuilder::error(e)),
+    };
+    let id = match add_confirmed_host(
+        &conn,
+        ssh_client.get_ref(),
+        &event_hooks,
+        jumphost,
+        address,
+        pending.into(),
+    )
+    .await
+    {
+        Ok(id) => id,
+        Err(e) => return Ok(FormResponseBuilder::error(e)),
+    };
+    if let Err(e) =
+        timing::block(move || PendingHost::delete(&mut conn.get().unwrap(), &token)).await?
+    {
+        warn!("Failed to remove pending host: {e}");
+    }
+
+    Ok(install_script_response(ssh_client.get_ref(), id).await)
+}
+
+/// Checks that SSM can log in with the confirmed host key, then adds the host and returns its id
+pub(crate) async fn add_confirmed_host(
+    conn: &ConnectionPool,
+    ssh_client: &dyn SshClient,
+    event_hooks: &EventHooks,
+    jumphost: Option<Host>,
+    address: ConnectionDetails,
+    new_host: NewHost,
+) -> Result<i32, String> {
+    match jumphost {
+        Some(via) => {
+            ssh_client
+                .try_authenticate_via(
+                    via,
+                    address,
+                    new_host.key_fingerprint.clone(),
+                    new_host.username.clone(),
+                )
+                .await
+        }
+        None => {
+            ssh_client
+                .try_authenticate(
+                    address,
+                    new_host.key_fingerprint.clone(),
+                    new_host.username.clone(),
+                )
+                .await
+        }
+    }
+    .map_err(|e| e.to_string())?;
+
+    let event = Event::HostCreated {
+        host: new_host.name.clone(),
+        address: new_host.address.clone(),
+    };
+    let pool = conn.clone();
+    let host = timing::block(move || {
+        let mut conn = pool.get().unwrap();
+        Host::add_host(&mut conn, &new_host)?;
+        Host::get_from_name_sync(&mut conn, new_host.name)?
+            .ok_or_else(|| String::from("Host disappeared after adding it"))
     })
+    .await
+    .map_err(|e| e.to_string())??;
+    event_hooks.emit(event);
+
+    Ok(host.id)
+}
+
+async fn install_script_response(ssh_client: &dyn SshClient, id: i32) -> FormResponseBuilder {
+    match ssh_client.install_script_on_host(id).await {
+        Ok(()) => FormResponseBuilder::created(Message::new("host.added").to_string())
+            .add_trigger(String::from("reload-hosts")),
+        Err(error) => FormResponseBuilder::error(format!("Failed to install script: {error}")),
+    }
 }
 
 // Added view model for host list rendering to convert optional fields
@@ -361,9 +653,11 @@ struct ListHostView {
     pub name: String,
     pub address: String,
     pub username: String,
-    pub port: i32,
+    pub port: u16,
     pub key_fingerprint: String,
     pub jump_via: String,
+    /// The host offered an unknown host key
+    pub key_mismatch: bool,
 }
 
 // Update RenderHostsTemplate to use ListHostView instead of Host
@@ -376,7 +670,7 @@ struct RenderHostsTemplate {
 // Modify the render_hosts function to map Host to ListHostView
 #[get("/list.htm")]
 async fn render_hosts(conn: Data<ConnectionPool>) -> actix_web::Result<impl Responder> {
-    let all_hosts = web::block(move || Host::get_all_hosts(&mut conn.get().unwrap())).await?;
+    let all_hosts = timing::block(move || Host::get_all_hosts(&mut conn.get().unwrap())).await?;
 
     Ok(match all_hosts {
         Ok(hosts) => {
@@ -388,6 +682,7 @@ async fn render_hosts(conn: Data<ConnectionPool>) -> actix_web::Result<impl Resp
                 port: host.port,
                 key_fingerprint: host.key_fingerprint.unwrap_or_default(),
                 jump_via: host.jump_via.map(|v| v.to_string()).unwrap_or_default(),
+                key_mismatch: host.key_mismatch.is_some(),
             }).collect();
             RenderHostsTemplate { hosts: view_hosts }.to_response()
         },
@@ -406,22 +701,47 @@ struct AuthorizeUserForm {
 #[post("/user/authorize")]
 async fn authorize_user(
     conn: Data<ConnectionPool>,
-
+    ssh_client: Data<dyn SshClient>,
+    access: Data<AccessControl>,
+    config: Data<Configuration>,
+    identity: Identity,
     form: web::Form<AuthorizeUserForm>,
 ) -> actix_web::Result<impl Responder> {
-    let res = web::block(move || {
+    let actor = actor(&identity);
+    let host = match Host::get_from_id(conn.get().unwrap(), form.host_id).await {
+        Ok(Some(host)) if !access.may_change_host(&actor, &host) => {
+            return Ok(FormResponseBuilder::forbidden(AccessControl::denied(
+                &actor, &host.name,
+            )));
+        }
+        Ok(host) => host,
+        Err(e) => return Ok(FormResponseBuilder::error(e)),
+    };
+    let res = timing::block(move || {
+        let mut conn = conn.get().unwrap();
+        if let Some(host) = host {
+            host.check_key_limits(
+                ssh_client.as_ref(),
+                &mut conn,
+                form.user_id,
+                &form.login,
+                form.options.as_deref(),
+                &config.key_limits,
+            )?;
+        }
         Host::authorize_user(
-            &mut conn.get().unwrap(),
+            &mut conn,
             form.host_id,
             form.user_id,
             form.login.clone(),
             form.options.clone(),
+            &actor,
         )
     })
     .await?;
 
     Ok(match res {
-        Ok(()) => FormResponseBuilder::success(String::from("Authorized user"))
+        Ok(()) => FormResponseBuilder::success(Message::new("authorization.added").to_string())
             .add_trigger("reloadDiff".to_owned()),
         Err(e) => FormResponseBuilder::error(e),
     })
@@ -439,39 +759,89 @@ struct AuthorizedKeyfileDialog {
     login: String,
     authorized_keys: String,
     diff: Vec<KeyDiffItem>,
+    /// Protected hosts only apply the previewed file with this token
+    confirm_token: Option<String>,
+    /// Message of the active change freeze
+    freeze: Option<String>,
+    /// Whether the login may deploy anyway
+    may_break_freeze: bool,
+    /// Whether the file leaves the login without a key besides the one of SSM
+    lockout: bool,
+    /// Whether the file leaves out the key SSM connects to the host with
+    ssm_lockout: bool,
 }
 
 #[post("/gen_authorized_keys")]
 async fn gen_authorized_keys(
     conn: Data<ConnectionPool>,
-    ssh_client: Data<SshClient>,
+    ssh_client: Data<dyn SshClient>,
+    freezes: Data<Freezes>,
+    access: Data<AccessControl>,
+    config: Data<Configuration>,
+    identity: Identity,
     form: web::Form<GenAuthorizedKeysForm>,
 ) -> actix_web::Result<impl Responder> {
     let host_name = &form.host_name;
     let login = &form.login;
 
-    let authorized_keys = match Host::get_from_name(conn.get().unwrap(), host_name.to_owned()).await
-    {
+    let host = match Host::get_from_name(conn.get().unwrap(), host_name.to_owned()).await {
         Err(error) => {
             return Ok(FormResponseBuilder::error(error));
         }
         Ok(None) => {
-            return Ok(FormResponseBuilder::error("No such host.".to_owned()));
-        }
-        Ok(Some(host)) => {
-            host.get_authorized_keys_file_for(&ssh_client, &mut conn.get().unwrap(), login.as_ref())
+            return Ok(FormResponseBuilder::error(
+                Message::new("host.not_found").to_string(),
+            ));
         }
+        Ok(Some(host)) => host,
     };
 
-    let authorized_keys = match authorized_keys {
+    let authorized_keys = match host.get_authorized_keys_file_for(
+        ssh_client.as_ref(),
+        &mut conn.get().unwrap(),
+        login.as_ref(),
+        &config.key_limits,
+    ) {
         Ok(keys) => keys,
         Err(error) => {
             return Ok(FormResponseBuilder::error(error));
         }
     };
+    let current = ssh_client.get_authorized_keyfile(host.clone(), login).await;
+    let authorized_keys = if host.tolerated_keys.is_empty() {
+        authorized_keys
+    } else {
+        match &current {
+            Ok(current) => host.keep_tolerated_keys(authorized_keys, current),
+            Err(error) => return Ok(FormResponseBuilder::error(error.to_string())),
+        }
+    };
+    let own_key = ssh_client.get_own_key_b64();
+    let lockout = current.is_ok_and(|current| locks_out(&current, &authorized_keys, &own_key));
+    let ssm_lockout = locks_out_ssm(&host, login, &authorized_keys, &own_key);
+
+    let confirm_token = if host.protected {
+        match HostConfirmation::create(
+            &mut conn.get().unwrap(),
+            host.id,
+            DEPLOY,
+            Some(login.clone()),
+            Some(authorized_keys.clone()),
+        ) {
+            Ok(token) => Some(token),
+            Err(error) => return Ok(FormResponseBuilder::error(error)),
+        }
+    } else {
+        None
+    };
+
+    let freeze = match freezes.active(&mut conn.get().unwrap(), now()) {
+        Ok(freeze) => freeze.map(|freeze| freeze.message()),
+        Err(error) => return Ok(FormResponseBuilder::error(error)),
+    };
 
     let Ok(key_diff) = ssh_client
-        .key_diff(authorized_keys.as_ref(), host_name.clone(), login.clone())
+        .key_diff(authorized_keys.as_ref(), host, login.clone())
         .await
     else {
         return Ok(FormResponseBuilder::error(
@@ -486,6 +856,11 @@ async fn gen_authorized_keys(
             login: login.to_owned(),
             diff: key_diff,
             authorized_keys,
+            confirm_token,
+            freeze,
+            may_break_freeze: access.may_break_freeze(&actor(&identity)),
+            lockout,
+            ssm_lockout,
         }
         .to_string(),
     }))
@@ -495,14 +870,110 @@ async fn gen_authorized_keys(
 struct SetAuthorizedKeysForm {
     login: String,
     authorized_keys: String,
+    /// From the preview, required for protected hosts
+    confirm_token: Option<String>,
+    /// Deploy during a change freeze, needs the break_freeze role
+    #[serde(default)]
+    break_freeze: bool,
+    /// Deploy even if it locks out the users of the login or SSM itself
+    #[serde(default)]
+    force: bool,
 }
 
 #[post("/{name}/set_authorized_keys")]
+#[allow(clippy::too_many_arguments)]
 async fn set_authorized_keys(
     form: web::Form<SetAuthorizedKeysForm>,
     host: Path<String>,
-    ssh_client: Data<SshClient>,
+    conn: Data<ConnectionPool>,
+    ssh_client: Data<dyn SshClient>,
+    event_hooks: Data<EventHooks>,
+    access: Data<AccessControl>,
+    freezes: Data<Freezes>,
+    identity: Identity,
 ) -> actix_web::Result<impl Responder> {
+    let db_host = match Host::get_from_name(conn.get().unwrap(), host.to_string()).await {
+        Ok(Some(db_host)) => db_host,
+        Ok(None) => {
+            return Ok(FormResponseBuilder::error(
+                Message::new("host.not_found").to_string(),
+            ))
+        }
+        Err(error) => return Ok(FormResponseBuilder::error(error)),
+    };
+    if !access.may_change_host(&actor(&identity), &db_host) {
+        return Ok(FormResponseBuilder::forbidden(AccessControl::denied(
+            &actor(&identity),
+            &db_host.name,
+        )));
+    }
+    if let Err(response) = check_freeze(
+        &mut conn.get().unwrap(),
+        &freezes,
+        &access,
+        &actor(&identity),
+        &db_host.name,
+        &form.login,
+        form.break_freeze,
+    ) {
+        return Ok(response);
+    }
+    if let Err(error) = confirm_protected(
+        &mut conn.get().unwrap(),
+        &db_host,
+        form.confirm_token.as_deref(),
+        DEPLOY,
+        Some(&form.login),
+        Some(&form.authorized_keys),
+    ) {
+        return Ok(FormResponseBuilder::error(error));
+    }
+
+    // The previous file tells which keys this deployment adds and removes
+    let previous_keyfile = ssh_client
+        .get_authorized_keyfile(db_host.clone(), &form.login)
+        .await
+        .unwrap_or_else(|e| {
+            debug!(
+                "Couldn't read previous authorized_keys of {}: {e}",
+                form.login
+            );
+            String::new()
+        });
+    let own_key = ssh_client.get_own_key_b64();
+    if locks_out_ssm(&db_host, &form.login, &form.authorized_keys, &own_key) {
+        if !form.force {
+            return Ok(FormResponseBuilder::error(
+                Message::new("deploy.own_key")
+                    .arg(&form.login)
+                    .arg(&db_host.name)
+                    .to_string(),
+            ));
+        }
+        warn!(
+            "{} removed the key of SSM for {} on {}",
+            actor(&identity),
+            form.login,
+            db_host.name
+        );
+    }
+    if locks_out(&previous_keyfile, &form.authorized_keys, &own_key) {
+        if !form.force {
+            return Ok(FormResponseBuilder::error(
+                Message::new("deploy.last_key")
+                    .arg(&form.login)
+                    .arg(&db_host.name)
+                    .to_string(),
+            ));
+        }
+        warn!(
+            "{} removed the last key of {} on {}",
+            actor(&identity),
+            form.login,
+            db_host.name
+        );
+    }
+
     let res = ssh_client
         .set_authorized_keys(
             host.to_string(),
@@ -512,8 +983,58 @@ async fn set_authorized_keys(
         .await;
 
     Ok(match res {
-        Ok(()) => FormResponseBuilder::success(String::from("Applied authorized_keys"))
-            .add_trigger("reloadDiff".to_owned()),
+        Ok(output) => {
+            let principals =
+                deploy_principals(ssh_client.as_ref(), &conn, &db_host, &form.login).await;
+            let actor = actor(&identity);
+            let (host_name, login, new_keyfile) = (
+                host.to_string(),
+                form.login.clone(),
+                form.authorized_keys.clone(),
+            );
+            let recorded = timing::block(move || {
+                KeyHistory::record_deploy(
+                    &mut conn.get().unwrap(),
+                    &host_name,
+                    &login,
+                    &previous_keyfile,
+                    &new_keyfile,
+                    &actor,
+                )
+            })
+            .await?;
+            if let Err(e) = recorded {
+                warn!("Failed to record key history for {host}: {e}");
+            }
+
+            event_hooks.emit(Event::KeysDeployed {
+                host: host.to_string(),
+                login: form.login.clone(),
+            });
+            let mut message = String::from("Applied authorized_keys");
+            if let Some(pre_hook) = output.pre_hook {
+                message.push_str(&format!("\nPre deploy hook: {pre_hook}"));
+            }
+            if let Some(post_hook) = output.post_hook {
+                message.push_str(&format!("\nPost deploy hook: {post_hook}"));
+            }
+            if let Some(hash) = output.verified_hash {
+                message.push_str(&format!("\nVerified, SHA256 {hash}"));
+            }
+            for finding in output.permissions {
+                message.push_str(&format!("\nPermissions: {finding}"));
+            }
+            match principals {
+                Ok(true) => message.push_str("\nApplied authorized_principals"),
+                Ok(false) => {}
+                Err(error) => {
+                    return Ok(FormResponseBuilder::error(format!(
+                        "{message}\nFailed to apply authorized_principals: {error}"
+                    )))
+                }
+            }
+            FormResponseBuilder::success(message).add_trigger("reloadDiff".to_owned())
+        }
         Err(error) => FormResponseBuilder::error(error.to_string()),
     })
 }
@@ -523,36 +1044,60 @@ async fn set_authorized_keys(
 struct DeleteHostTemplate {
     authorizations: Vec<UserAndOptions>,
     affected_hosts: Vec<String>,
+    /// Protected hosts are only deleted with this token
+    confirm_token: Option<String>,
 }
 
 #[derive(Deserialize)]
 struct HostDeleteForm {
     #[serde(default)]
     confirm: bool,
+    /// From the preview, required for protected hosts
+    confirm_token: Option<String>,
 }
 
 #[post("/{name}/delete")]
 async fn delete(
     conn: Data<ConnectionPool>,
-    caching_ssh_client: Data<CachingSshClient>,
+    caching_ssh_client: Data<dyn HostCache>,
+    access: Data<AccessControl>,
     form: web::Form<HostDeleteForm>,
     host_name: Path<String>,
+    identity: Identity,
 ) -> impl Responder {
     let host = match Host::get_from_name(conn.get().unwrap(), host_name.to_owned()).await {
         Ok(None) => {
-            return FormResponseBuilder::error("Host not found".to_owned());
+            return FormResponseBuilder::error(Message::new("host.not_found").to_string());
         }
         Err(error) => {
             return FormResponseBuilder::error(format!("Database error: {error}"));
         }
         Ok(Some(host)) => host,
     };
+    if !access.may_change_host(&actor(&identity), &host) {
+        return FormResponseBuilder::forbidden(AccessControl::denied(
+            &actor(&identity),
+            &host.name,
+        ));
+    }
 
     if form.confirm {
-        return match host.delete(&mut conn.get().unwrap()) {
+        if let Err(error) = confirm_protected(
+            &mut conn.get().unwrap(),
+            &host,
+            form.confirm_token.as_deref(),
+            DELETE,
+            None,
+            None,
+        ) {
+            return FormResponseBuilder::error(error);
+        }
+        return match host.delete(&mut conn.get().unwrap(), &actor(&identity)) {
             Ok(amt) => {
                 caching_ssh_client.remove(host_name.as_str()).await;
-                return FormResponseBuilder::success(format!("Deleted {amt} record(s)"));
+                return FormResponseBuilder::success(
+                    Message::new("host.deleted").arg(amt).to_string(),
+                );
             }
             Err(e) => FormResponseBuilder::error(format!("Failed to delete host: {e}")),
         };
@@ -565,16 +1110,24 @@ async fn delete(
         .and_then(|authorizations| {
             host.get_dependant_hosts(&mut connection)
                 .map(|hosts| (authorizations, hosts))
+        })
+        .and_then(|(authorizations, hosts)| {
+            let confirm_token = host
+                .protected
+                .then(|| HostConfirmation::create(&mut connection, host.id, DELETE, None, None))
+                .transpose()?;
+            Ok((authorizations, hosts, confirm_token))
         });
 
     // TODO: resolve authorizations of dependant hosts
     match res {
-        Ok((authorizations, affected_hosts)) => FormResponseBuilder::dialog(Modal {
+        Ok((authorizations, affected_hosts, confirm_token)) => FormResponseBuilder::dialog(Modal {
             title: format!("In addition to {host_name}, these entries will be affected"),
             request_target: format!("/hosts/{host_name}/delete"),
             template: DeleteHostTemplate {
                 authorizations,
                 affected_hosts,
+                confirm_token,
             }
             .to_string(),
         }),
@@ -582,6 +1135,68 @@ async fn delete(
     }
 }
 
+/// Deployments during a change freeze need the break_freeze role and an explicit override,
+/// which is recorded as a security event
+fn check_freeze(
+    conn: &mut DbConnection,
+    freezes: &Freezes,
+    access: &AccessControl,
+    actor: &str,
+    host_name: &str,
+    login: &str,
+    break_freeze: bool,
+) -> Result<(), FormResponseBuilder> {
+    let freeze = match freezes.active(conn, now()) {
+        Ok(Some(freeze)) => freeze,
+        Ok(None) => return Ok(()),
+        Err(error) => return Err(FormResponseBuilder::error(error)),
+    };
+    if !break_freeze {
+        return Err(FormResponseBuilder::locked(freeze.message()));
+    }
+    if !access.may_break_freeze(actor) {
+        return Err(FormResponseBuilder::forbidden(
+            Message::new("freeze.deploy_denied").arg(actor),
+        ));
+    }
+
+    let message = format!(
+        "{actor} deployed '{login}' on '{host_name}' during a change freeze ({})",
+        freeze.reason
+    );
+    warn!("{message}");
+    SecurityEvent::record(conn, FREEZE_OVERRIDE, WARNING, Some(host_name), message)
+        .map_err(FormResponseBuilder::error)
+}
+
+/// Destructive operations on protected hosts need the token handed out by their preview
+fn confirm_protected(
+    conn: &mut DbConnection,
+    host: &Host,
+    token: Option<&str>,
+    action: &str,
+    login: Option<&str>,
+    authorized_keys: Option<&str>,
+) -> Result<(), String> {
+    if !host.protected {
+        return Ok(());
+    }
+    let confirmed = match token {
+        Some(token) => {
+            HostConfirmation::redeem(conn, token, host.id, action, login, authorized_keys)?
+        }
+        None => false,
+    };
+    if confirmed {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} is protected, confirm the {action} from its preview",
+            host.name
+        ))
+    }
+}
+
 #[derive(Deserialize)]
 struct DeleteAuthorizationForm {
     authorization_id: i32,
@@ -591,17 +1206,26 @@ struct DeleteAuthorizationForm {
 async fn delete_authorization(
     form: web::Form<DeleteAuthorizationForm>,
     conn: Data<ConnectionPool>,
+    access: Data<AccessControl>,
+    identity: Identity,
 ) -> actix_web::Result<impl Responder> {
-    let res = web::block(move || {
+    let actor = actor(&identity);
+    let res = timing::block(move || {
         let mut connection = conn.get().unwrap();
 
-        Host::delete_authorization(&mut connection, form.authorization_id)
+        if let Some(host) = Host::get_from_authorization(&mut connection, form.authorization_id)? {
+            if !access.may_change_host(&actor, &host) {
+                return Ok(Some(AccessControl::denied(&actor, &host.name)));
+            }
+        }
+        Host::delete_authorization(&mut connection, form.authorization_id, &actor).map(|()| None)
     })
     .await?;
 
     Ok(match res {
-        Ok(()) => FormResponseBuilder::success("Deleted authorization.".to_owned())
+        Ok(None) => FormResponseBuilder::success(Message::new("authorization.deleted").to_string())
             .add_trigger("reload-authorizations".to_owned()),
+        Ok(Some(denied)) => FormResponseBuilder::forbidden(denied),
         Err(e) => FormResponseBuilder::error(e),
     })
 }
@@ -610,6 +1234,10 @@ async fn delete_authorization(
 #[template(path = "hosts/edit_host.html")]
 struct EditHostTemplate {
     host: EditHostView,
+    transports: [TransportKind; 3],
+    address_families: [AddressFamily; 3],
+    /// Names of the configured connection plugins
+    connection_plugins: Vec<String>,
 }
 
 // A view model for rendering the edit host form with types that implement Display
@@ -617,14 +1245,30 @@ struct EditHostView {
     name: String,
     address: String,
     username: String,
-    port: i32,
+    port: u16,
     key_fingerprint: String,
     jump_via: String,
+    transport: String,
+    tags: String,
+    pre_deploy_hook: String,
+    post_deploy_hook: String,
+    address_family: String,
+    proxy: String,
+    fallback_addresses: String,
+    aliases: String,
+    protected: bool,
+    tolerated_keys: String,
+    principals_file: String,
+    key_algorithms: String,
+    connection_plugin: String,
+    script_path: String,
+    script_interpreter: String,
 }
 
 #[get("/{name}/edit")]
 async fn edit_host_form(
     conn: actix_web::web::Data<crate::ConnectionPool>,
+    config: Data<Configuration>,
     host_name: actix_web::web::Path<String>,
 ) -> actix_web::Result<impl actix_web::Responder> {
     let host_result = crate::models::Host::get_from_name(conn.get().unwrap(), host_name.to_string())
@@ -640,8 +1284,29 @@ async fn edit_host_form(
             port: host.port,
             key_fingerprint: host.key_fingerprint.unwrap_or_default(),
             jump_via: host.jump_via.map(|v| v.to_string()).unwrap_or_default(),
+            transport: host.transport,
+            tags: host.tags,
+            pre_deploy_hook: host.pre_deploy_hook.unwrap_or_default(),
+            post_deploy_hook: host.post_deploy_hook.unwrap_or_default(),
+            address_family: host.address_family.unwrap_or_default(),
+            proxy: host.proxy.unwrap_or_default(),
+            fallback_addresses: host.fallback_addresses,
+            aliases: host.aliases,
+            protected: host.protected,
+            tolerated_keys: host.tolerated_keys,
+            principals_file: host.principals_file.unwrap_or_default(),
+            key_algorithms: host.key_algorithms,
+            connection_plugin: host.connection_plugin.unwrap_or_default(),
+            script_path: host.script_path.unwrap_or_default(),
+            script_interpreter: host.script_interpreter.unwrap_or_default(),
         };
-        Ok(EditHostTemplate { host: view }.to_response())
+        Ok(EditHostTemplate {
+            host: view,
+            transports: TransportKind::ALL,
+            address_families: AddressFamily::ALL,
+            connection_plugins: config.ssh.connection_plugins.keys().cloned().collect(),
+        }
+        .to_response())
     } else {
         Ok(crate::routes::ErrorTemplate { error: "Host not found".to_string() }.to_response())
     }
@@ -660,6 +1325,14 @@ where
     }
 }
 
+// Like empty_string_as_none, without the whitespace around the value
+fn trimmed_string_as_none<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    empty_string_as_none(deserializer).map(|s| s.map(|s| s.trim().to_owned()))
+}
+
 fn empty_string_as_none_int<'de, D>(deserializer: D) -> Result<Option<i32>, D::Error>
 where
     D: serde::Deserializer<'de>,
@@ -677,30 +1350,154 @@ struct EditHostForm {
     name: String,
     address: String,
     username: String,
-    port: i32,
+    port: u16,
     #[serde(deserialize_with = "empty_string_as_none")]
     key_fingerprint: Option<String>,
     #[serde(deserialize_with = "empty_string_as_none_int")]
     jump_via: Option<i32>,
+    #[serde(default = "default_transport")]
+    transport: String,
+    #[serde(default)]
+    tags: String,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pre_deploy_hook: Option<String>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    post_deploy_hook: Option<String>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    address_family: Option<String>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    proxy: Option<String>,
+    #[serde(default)]
+    fallback_addresses: String,
+    #[serde(default)]
+    aliases: String,
+    #[serde(default)]
+    protected: bool,
+    #[serde(default)]
+    tolerated_keys: String,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    principals_file: Option<String>,
+    #[serde(default)]
+    key_algorithms: String,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    connection_plugin: Option<String>,
+    #[serde(default, deserialize_with = "trimmed_string_as_none")]
+    script_path: Option<String>,
+    #[serde(default, deserialize_with = "trimmed_string_as_none")]
+    script_interpreter: Option<String>,
 }
 
 #[post("/{name}/edit")]
 async fn edit_host(
     conn: actix_web::web::Data<crate::ConnectionPool>,
+    config: Data<Configuration>,
+    access: Data<AccessControl>,
+    identity: Identity,
     host_name: actix_web::web::Path<String>,
     form: actix_web::web::Form<EditHostForm>,
 ) -> actix_web::Result<impl actix_web::Responder> {
+    if let Err(error) = TransportKind::from_str(&form.transport) {
+        return Ok(crate::routes::ErrorTemplate { error }.to_response());
+    }
+    if let Some(Err(error)) = form.address_family.as_deref().map(AddressFamily::from_str) {
+        return Ok(crate::routes::ErrorTemplate { error }.to_response());
+    }
+    if let Some(Err(error)) = form.proxy.as_deref().map(Proxy::from_str) {
+        return Ok(crate::routes::ErrorTemplate { error }.to_response());
+    }
+    if let Some(plugin) = &form.connection_plugin {
+        let error = if !config.ssh.connection_plugins.contains_key(plugin) {
+            Some(format!("There is no connection plugin named '{plugin}'"))
+        } else if form.jump_via.is_some() || form.proxy.is_some() {
+            Some("A host with a connection plugin can't also use a jump host or proxy".to_owned())
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            return Ok(crate::routes::ErrorTemplate { error }.to_response());
+        }
+    }
+
     let mut db_conn = conn.get().unwrap();
-    match crate::models::Host::update_host(
-        &mut db_conn,
-        host_name.to_string(),
-        form.name.clone(),
-        form.address.clone(),
-        form.username.clone(),
-        form.port,
-        form.key_fingerprint.clone(),
-        form.jump_via,
+    // Operators can't move a host out of their tags either
+    let actor = actor(&identity);
+    if let Ok(Some(host)) = Host::get_from_name_sync(&mut db_conn, host_name.to_string()) {
+        if !access.may_change_host(&actor, &host) || !access.may_use_tags(&actor, &form.tags) {
+            let mut response = ErrorTemplate {
+                error: AccessControl::denied(&actor, &host.name).to_string(),
+            }
+            .to_response();
+            *response.status_mut() = actix_web::http::StatusCode::FORBIDDEN;
+            return Ok(response);
+        }
+    }
+    let aliases = crate::models::Host::normalize_list(&form.aliases);
+    if aliases.split(',').any(|alias| alias.eq(&form.name)) {
+        let error = format!("'{}' is already the name of this host", form.name);
+        return Ok(crate::routes::ErrorTemplate { error }.to_response());
+    }
+    let tolerated_keys = crate::models::Host::normalize_list(&form.tolerated_keys);
+    if let Some(invalid) = tolerated_keys.split(',').find(|fingerprint| {
+        !fingerprint.is_empty() && ssh_key::Fingerprint::from_str(fingerprint).is_err()
+    }) {
+        let error = format!("'{invalid}' is not a key fingerprint like SHA256:...");
+        return Ok(crate::routes::ErrorTemplate { error }.to_response());
+    }
+    let principals_file = form.principals_file.as_deref().map(str::trim);
+    if let Some(Err(error)) = principals_file.map(crate::models::Host::check_principals_file) {
+        return Ok(crate::routes::ErrorTemplate { error }.to_response());
+    }
+    if let Err(error) = Host::check_script_location(
+        form.script_path.as_deref(),
+        form.script_interpreter.as_deref(),
     ) {
+        return Ok(crate::routes::ErrorTemplate { error }.to_response());
+    }
+    let key_algorithms = match crate::models::Host::normalize_key_algorithms(&form.key_algorithms) {
+        Ok(key_algorithms) => key_algorithms,
+        Err(error) => return Ok(crate::routes::ErrorTemplate { error }.to_response()),
+    };
+    let conflict = crate::models::Host::get_from_name_sync(&mut db_conn, host_name.to_string())
+        .and_then(|host| {
+            let host_id = host.map_or(-1, |host| host.id);
+            crate::models::Host::find_name_conflict(
+                &mut db_conn,
+                host_id,
+                std::iter::once(form.name.as_str()).chain(aliases.split(',')),
+            )
+        });
+    match conflict {
+        Ok(None) => {}
+        Ok(Some((name, other))) => {
+            let error = format!("'{name}' is already used by host {other}");
+            return Ok(crate::routes::ErrorTemplate { error }.to_response());
+        }
+        Err(error) => return Ok(crate::routes::ErrorTemplate { error }.to_response()),
+    }
+    let changes = crate::models::HostChanges {
+        name: form.name.clone(),
+        address: form.address.clone(),
+        username: form.username.clone(),
+        port: form.port,
+        key_fingerprint: form.key_fingerprint.clone(),
+        jump_via: form.jump_via,
+        transport: form.transport.clone(),
+        tags: crate::models::Host::normalize_tags(&form.tags),
+        pre_deploy_hook: form.pre_deploy_hook.clone(),
+        post_deploy_hook: form.post_deploy_hook.clone(),
+        address_family: form.address_family.clone(),
+        proxy: form.proxy.clone(),
+        fallback_addresses: crate::models::Host::normalize_list(&form.fallback_addresses),
+        aliases,
+        protected: form.protected,
+        tolerated_keys,
+        principals_file: principals_file.map(str::to_owned),
+        key_algorithms,
+        connection_plugin: form.connection_plugin.clone(),
+        script_path: form.script_path.clone(),
+        script_interpreter: form.script_interpreter.clone(),
+    };
+    match crate::models::Host::update(&mut db_conn, &host_name, changes) {
         Ok(()) => {
             info!("ssm::routes::hosts: Host {} updated successfully", host_name);
             Ok(actix_web::HttpResponse::Found().append_header(("Location", "/hosts")).finish())
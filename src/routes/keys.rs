@@ -1,3 +1,4 @@
+use actix_identity::Identity;
 use actix_web::{
     get, post,
     web::{self, Data},
@@ -7,7 +8,14 @@ use askama_actix::{Template, TemplateToResponse};
 use serde::Deserialize;
 
 use crate::{
-    db::UsernameAndKey, forms::FormResponseBuilder, routes::ErrorTemplate, ConnectionPool,
+    db::UsernameAndKey,
+    forms::FormResponseBuilder,
+    hooks::{Event, EventHooks},
+    i18n::Message,
+    key_usage::fingerprint,
+    routes::{actor, ErrorTemplate},
+    scheduler::Scheduler,
+    timing, ConnectionPool,
 };
 
 use crate::models::PublicUserKey;
@@ -21,7 +29,7 @@ struct KeysPageTemplate {
 #[get("")]
 pub async fn list_keys(conn: Data<ConnectionPool>) -> actix_web::Result<impl Responder> {
     let all_keys =
-        web::block(move || PublicUserKey::get_all_keys_with_username(&mut conn.get().unwrap()))
+        timing::block(move || PublicUserKey::get_all_keys_with_username(&mut conn.get().unwrap()))
             .await?;
 
     Ok(match all_keys {
@@ -35,18 +43,44 @@ struct DeleteKeyForm {
     id: i32,
 }
 
+/// Deletes a key and queues the deployment of every login it was deployed to, which removes it
+/// from the hosts
 #[post("delete")]
 pub async fn delete(
     conn: Data<ConnectionPool>,
     form: web::Form<DeleteKeyForm>,
+    event_hooks: Data<EventHooks>,
+    scheduler: Data<Scheduler>,
+    identity: Identity,
 ) -> actix_web::Result<impl Responder> {
-    let res =
-        web::block(move || PublicUserKey::delete_key(&mut conn.get().unwrap(), form.id)).await?;
+    let actor = actor(&identity);
+    let res = {
+        let actor = actor.clone();
+        timing::block(move || {
+            let mut conn = conn.get().unwrap();
+            let (username, key) = PublicUserKey::delete_key(&mut conn, form.id, &actor)?;
+            let logins = PublicUserKey::deployed_to(&mut conn, &[username.as_str()])?;
+            Ok::<_, String>((username, key, logins))
+        })
+        .await?
+    };
 
     Ok(match res {
-        Ok(()) => FormResponseBuilder::success("Deleted key".to_owned())
-            .add_trigger("reload-keys".to_owned())
-            .into_response(),
+        Ok((username, key, logins)) => {
+            let removal = scheduler.into_inner().queue_key_removal(
+                fingerprint(&key).into_iter().collect(),
+                logins,
+                &actor,
+            );
+            event_hooks.emit(Event::key_revoked(username, key));
+            let message = match removal.targets.len() {
+                0 => Message::new("key.deleted"),
+                files => Message::new("key.deleted_removing").arg(files),
+            };
+            FormResponseBuilder::success(message.to_string())
+                .add_trigger("reload-keys".to_owned())
+                .into_response()
+        }
         Err(e) => FormResponseBuilder::error(e).into_response(),
     })
 }
@@ -63,14 +97,14 @@ pub async fn update_key_comment(
     form: web::Form<UpdateKeyCommentForm>,
 ) -> actix_web::Result<impl Responder> {
     let key_id = key_id.into_inner();
-    let result = web::block(move || {
+    let result = timing::block(move || {
         let mut conn = conn.get().unwrap();
         PublicUserKey::update_comment(&mut conn, key_id, &form.comment)
     })
     .await?;
 
     Ok(match result {
-        Ok(()) => FormResponseBuilder::success("Comment updated successfully".to_owned())
+        Ok(()) => FormResponseBuilder::success(Message::new("key.comment_updated").to_string())
             .add_trigger("reload-keys".to_owned())
             .into_response(),
         Err(e) => FormResponseBuilder::error(e).into_response(),
@@ -1,25 +1,55 @@
+pub(crate) mod api;
 pub mod auth;
 mod diff;
 mod hosts;
 mod keys;
+mod search;
 mod users;
 
+use actix_identity::Identity;
 use actix_web::{
+    error::{InternalError, UrlencodedError},
     get,
     http::StatusCode,
+    middleware::{Compress, Condition},
     web::{self},
-    Responder,
+    HttpRequest, Responder,
 };
 use askama_actix::Template;
 use serde::Deserialize;
 
-pub fn route_config(cfg: &mut web::ServiceConfig) {
-    cfg.service(index)
-        .service(web::scope("/hosts").configure(hosts::hosts_config))
-        .service(web::scope("/users").configure(users::users_config))
-        .service(web::scope("/keys").configure(keys::keys_config))
-        .service(web::scope("/diff").configure(diff::diff_config))
-        .default_service(web::to(not_found));
+use crate::{forms::FormResponseBuilder, middleware};
+
+/// API responses are compressed if the client accepts it and `compress_api` is set
+pub fn route_config(compress_api: bool) -> impl FnOnce(&mut web::ServiceConfig) {
+    move |cfg| {
+        cfg.app_data(web::FormConfig::default().error_handler(form_error))
+            .service(index)
+            .service(web::scope("/hosts").configure(hosts::hosts_config))
+            .service(web::scope("/users").configure(users::users_config))
+            .service(web::scope("/keys").configure(keys::keys_config))
+            .service(web::scope("/diff").configure(diff::diff_config))
+            .service(web::scope("/search").configure(search::search_config))
+            .service(
+                web::scope("/api")
+                    .wrap(middleware::CsrfProtection)
+                    .wrap(Condition::new(compress_api, Compress::default()))
+                    .app_data(web::JsonConfig::default().error_handler(api::json_error))
+                    .configure(api::api_config),
+            )
+            .default_service(web::to(not_found));
+    }
+}
+
+/// Values that don't fit their field, like a port above 65535, are shown in the form
+fn form_error(error: UrlencodedError, req: &HttpRequest) -> actix_web::Error {
+    let response = match &error {
+        UrlencodedError::Parse(parse_error) => {
+            FormResponseBuilder::error(parse_error.to_string()).respond_to(req)
+        }
+        _ => return error.into(),
+    };
+    InternalError::from_response(error, response).into()
 }
 
 #[derive(Deserialize)]
@@ -33,6 +63,11 @@ fn should_update(force_update: ForceUpdate) -> bool {
     force_update.force_update.is_some_and(|update| update)
 }
 
+/// Name of the logged in user, recorded as the author of changes
+fn actor(identity: &Identity) -> String {
+    identity.id().unwrap_or_else(|_| String::from("unknown"))
+}
+
 #[derive(Template)]
 #[template(path = "error.html")]
 struct ErrorTemplate {
@@ -0,0 +1,60 @@
+use actix_web::{
+    get,
+    web::{self, Data, Query},
+    Responder,
+};
+use askama_actix::{Template, TemplateToResponse};
+use serde::Deserialize;
+
+use crate::{
+    db::search::{search, SearchResults},
+    routes::ErrorTemplate,
+    timing, ConnectionPool,
+};
+
+pub fn search_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(search_page);
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    #[serde(default)]
+    q: String,
+}
+
+#[derive(Template)]
+#[template(path = "search/index.html")]
+struct SearchPageTemplate {
+    text: String,
+    /// Nothing was searched for when the box was submitted empty
+    results: Option<SearchResults>,
+}
+
+#[get("")]
+async fn search_page(
+    conn: Data<ConnectionPool>,
+    query: Query<SearchQuery>,
+) -> actix_web::Result<impl Responder> {
+    let text = query.into_inner().q.trim().to_owned();
+    if text.is_empty() {
+        return Ok(SearchPageTemplate {
+            text,
+            results: None,
+        }
+        .to_response());
+    }
+
+    let results = {
+        let text = text.clone();
+        timing::block(move || search(&mut conn.get().unwrap(), &text, 50)).await?
+    };
+
+    Ok(match results {
+        Ok(results) => SearchPageTemplate {
+            text,
+            results: Some(results),
+        }
+        .to_response(),
+        Err(error) => ErrorTemplate { error }.to_response(),
+    })
+}
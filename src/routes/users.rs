@@ -1,3 +1,4 @@
+use actix_identity::Identity;
 use actix_web::{
     get, post,
     web::{self, Data, Path},
@@ -10,11 +11,13 @@ use ssh_key::PublicKey;
 use crate::{
     db::UserAndOptions,
     forms::FormResponseBuilder,
-    routes::{ErrorTemplate, RenderErrorTemplate},
-    ConnectionPool,
+    hooks::{Event, EventHooks},
+    i18n::Message,
+    routes::{actor, ErrorTemplate, RenderErrorTemplate},
+    timing, ConnectionPool,
 };
 
-use crate::models::{NewPublicUserKey, NewUser, PublicUserKey, User};
+use crate::models::{NewPublicUserKey, NewUser, PublicUserKey, User, UserDetails};
 
 pub fn users_config(cfg: &mut web::ServiceConfig) {
     cfg.service(users_page)
@@ -45,7 +48,7 @@ struct RenderUsersTemplate {
 
 #[get("/list.htm")]
 async fn render_users(conn: Data<ConnectionPool>) -> actix_web::Result<impl Responder> {
-    let all_users = web::block(move || User::get_all_users(&mut conn.get().unwrap())).await?;
+    let all_users = timing::block(move || User::get_all_users(&mut conn.get().unwrap())).await?;
 
     Ok(match all_users {
         Ok(users) => RenderUsersTemplate { users }.to_response(),
@@ -65,7 +68,7 @@ async fn show_user(
     user: Path<String>,
 ) -> actix_web::Result<impl Responder> {
     let mut conn = conn.clone().get().unwrap();
-    let maybe_user = web::block(move || User::get_user(&mut conn, user.to_string())).await?;
+    let maybe_user = timing::block(move || User::get_user(&mut conn, user.to_string())).await?;
 
     Ok(match maybe_user {
         Ok(user) => ShowUserTemplate { user }.to_response(),
@@ -80,9 +83,9 @@ async fn add_user(
 ) -> actix_web::Result<impl Responder> {
     let new_user = form.0;
 
-    let res = web::block(move || User::add_user(&mut conn.get().unwrap(), new_user)).await?;
+    let res = timing::block(move || User::add_user(&mut conn.get().unwrap(), new_user)).await?;
     Ok(match res {
-        Ok(_) => FormResponseBuilder::created(String::from("Added user"))
+        Ok(_) => FormResponseBuilder::created(Message::new("user.added").to_string())
             .add_trigger(String::from("reload-users")),
         Err(e) => FormResponseBuilder::error(e),
     })
@@ -97,13 +100,24 @@ struct DeleteUserForm {
 async fn delete_user(
     conn: Data<ConnectionPool>,
     form: web::Form<DeleteUserForm>,
+    event_hooks: Data<EventHooks>,
+    identity: Identity,
 ) -> actix_web::Result<impl Responder> {
     let username = form.0.username;
+    let actor = actor(&identity);
 
-    let res =
-        web::block(move || User::delete_user(&mut conn.get().unwrap(), username.as_str())).await?;
+    let deleted = username.clone();
+    let res = timing::block(move || {
+        User::delete_user(&mut conn.get().unwrap(), deleted.as_str(), &actor)
+    })
+    .await?;
     Ok(match res {
-        Ok(()) => FormResponseBuilder::success(String::from("Deleted user")),
+        Ok(keys) => {
+            for key in keys {
+                event_hooks.emit(Event::key_revoked(username.clone(), key));
+            }
+            FormResponseBuilder::success(Message::new("user.deleted").to_string())
+        }
         Err(e) => FormResponseBuilder::error(e),
     })
 }
@@ -119,7 +133,7 @@ async fn render_user_keys(
     conn: Data<ConnectionPool>,
     username: Path<String>,
 ) -> actix_web::Result<impl Responder> {
-    let maybe_user_keys = web::block(move || {
+    let maybe_user_keys = timing::block(move || {
         let mut connection = conn.get().unwrap();
         let user = User::get_user(&mut connection, username.to_string())?;
 
@@ -155,7 +169,7 @@ async fn list_user_authorizations(
     conn: Data<ConnectionPool>,
     username: Path<String>,
 ) -> actix_web::Result<impl Responder> {
-    let maybe_user_auth = web::block(move || {
+    let maybe_user_auth = timing::block(move || {
         let mut connection = conn.get().unwrap();
         let user = User::get_user(&mut connection, username.to_string())?;
 
@@ -181,10 +195,11 @@ struct AssignKeyDialogForm {
 async fn assign_key_to_user(
     conn: Data<ConnectionPool>,
     form: web::Form<AssignKeyDialogForm>,
+    identity: Identity,
 ) -> actix_web::Result<impl Responder> {
     let Ok(algo) = ssh_key::Algorithm::new(&form.key_type) else {
         return Ok(FormResponseBuilder::error(
-            "Invalid key algorithm".to_owned(),
+            Message::new("key.invalid_algorithm").to_string(),
         ));
     };
 
@@ -195,10 +210,12 @@ async fn assign_key_to_user(
         form.user_id,
     );
 
-    let res = web::block(move || PublicUserKey::add_key(&mut conn.get().unwrap(), new_key)).await?;
+    let actor = actor(&identity);
+    let res = timing::block(move || PublicUserKey::add_key(&mut conn.get().unwrap(), new_key, &actor))
+        .await?;
 
     Ok(match res {
-        Ok(()) => FormResponseBuilder::created(String::from("Added key"))
+        Ok(()) => FormResponseBuilder::created(Message::new("key.added").to_string())
             .add_trigger("reloadDiff".to_owned()),
         Err(e) => FormResponseBuilder::error(e),
     })
@@ -209,6 +226,14 @@ struct EditUserForm {
     old_username: String,
     new_username: String,
     enabled: bool,
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default)]
+    full_name: Option<String>,
+    #[serde(default)]
+    department: Option<String>,
+    #[serde(default)]
+    external_id: Option<String>,
 }
 
 #[post("/edit")]
@@ -216,13 +241,26 @@ async fn edit_user(
     conn: Data<ConnectionPool>,
     form: web::Form<EditUserForm>,
 ) -> actix_web::Result<impl Responder> {
+    let details = UserDetails {
+        email: form.email.clone(),
+        full_name: form.full_name.clone(),
+        department: form.department.clone(),
+        external_id: form.external_id.clone(),
+    }
+    .normalized();
+    let details = match details {
+        Ok(details) => details,
+        Err(error) => return Ok(FormResponseBuilder::error(error).into_response()),
+    };
     let mut conn = conn.get().unwrap();
     match User::update_user(
         &mut conn,
         &form.old_username,
         &form.new_username,
         form.enabled,
-    ) {
+    )
+    .and_then(|()| User::set_details(&mut conn, &form.new_username, &details))
+    {
         Ok(_) => {
             let response = actix_web::HttpResponse::Found()
                 .insert_header(("Location", format!("/users/{}", form.new_username)))
@@ -0,0 +1,1215 @@
+//! Cron jobs that periodically check the hosts, and what they did last
+use std::collections::HashSet;
+use std::hash::{BuildHasher, RandomState};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use croner::Cron;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio_cron_scheduler::{JobBuilder, JobScheduler};
+use uuid::Uuid;
+
+use crate::{
+    activity::ActivityLog,
+    db::history::now,
+    freeze::Freezes,
+    hooks::{Event, EventHooks},
+    models::{Host, KeyHistory, NewSchedule, RecertificationCampaign, Schedule},
+    remediation::{Policy, RemediationPolicies},
+    ssh::{deploy_principals, locks_out, locks_out_ssm, CheckStatus, DiffItem, HostCache},
+    ConnectionPool, SshConfig,
+};
+
+/// Actor of the changes made by remediation in the history and activity log
+const ACTOR: &str = "auto_remediate";
+/// Reviewer of the authorizations the recertify job revokes or flags
+const RECERTIFY_ACTOR: &str = "auto_recertify";
+
+/// A host with drift and the differences of each login
+type HostDrift = (String, Vec<(String, Vec<DiffItem>)>);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobKind {
+    /// Check all hosts for differences
+    Check,
+    /// Refresh the cache of all hosts
+    Update,
+    /// Close the recertification campaigns whose deadline passed
+    Recertify,
+    /// Deploy the logins queued by changes like revoked authorizations
+    Deploy,
+}
+
+impl JobKind {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Check => "check",
+            Self::Update => "update",
+            Self::Recertify => "recertify",
+            Self::Deploy => "deploy",
+        }
+    }
+}
+
+impl FromStr for JobKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "check" => Ok(Self::Check),
+            "update" => Ok(Self::Update),
+            "recertify" => Ok(Self::Recertify),
+            "deploy" => Ok(Self::Deploy),
+            other => Err(format!(
+                "Unknown job '{other}', expected check, update, recertify or deploy"
+            )),
+        }
+    }
+}
+
+/// How the deploy job rolls out the queued logins
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum DeployStrategy {
+    /// Every host right away
+    #[default]
+    All,
+    /// This many hosts first, the others only if those still accept connections afterwards
+    Canary(usize),
+    /// One host of every tag first
+    CanaryPerTag,
+}
+
+impl FromStr for DeployStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "all" => Ok(Self::All),
+            Some(("canary", "tag")) => Ok(Self::CanaryPerTag),
+            Some(("canary", count)) => match count.parse() {
+                Ok(count) if count > 0 => Ok(Self::Canary(count)),
+                _ => Err(format!("Invalid number of canary hosts '{count}'")),
+            },
+            _ => Err(format!(
+                "Unknown deploy strategy '{s}', expected all, canary:<hosts> or canary:tag"
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for DeployStrategy {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl std::fmt::Display for DeployStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::All => write!(f, "all"),
+            Self::Canary(count) => write!(f, "canary:{count}"),
+            Self::CanaryPerTag => write!(f, "canary:tag"),
+        }
+    }
+}
+
+/// What happened the last time a job ran
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct JobStatus {
+    /// The job is running right now
+    pub running: bool,
+    /// When the last run started
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_run: Option<OffsetDateTime>,
+    /// How long the last run took in milliseconds
+    pub last_duration_ms: Option<u64>,
+    /// Error of the last run, if it failed
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobInfo {
+    pub name: String,
+    pub job: &'static str,
+    /// Cron expression, if the job is scheduled
+    pub schedule: Option<String>,
+    /// Only hosts with this tag are targeted
+    pub tag: Option<String>,
+    /// How a deploy job rolls out, missing for other jobs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strategy: Option<String>,
+    /// Why the deploy jobs don't deploy anymore, missing while they do and for other jobs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub halted: Option<DeployHalt>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub next_run: Option<OffsetDateTime>,
+    #[serde(flatten)]
+    pub status: JobStatus,
+}
+
+/// A failed canary deployment, which stops the deploy job until it is resumed
+#[derive(Clone, Debug, Serialize)]
+pub struct DeployHalt {
+    pub reason: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub since: OffsetDateTime,
+}
+
+/// A login whose authorized_keys file the deploy job deploys
+#[derive(Clone, Debug, Serialize)]
+pub struct QueuedDeploy {
+    pub host: String,
+    pub login: String,
+    /// Who made the change, recorded as actor of the deployment
+    pub actor: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub queued_at: OffsetDateTime,
+}
+
+/// What became of a login a deleted key is removed from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RemovalStatus {
+    /// Waiting for the deploy job
+    Queued,
+    Removed,
+    /// The deploy job tries again on its next run
+    Failed,
+    /// The host is protected or was deleted
+    Skipped,
+}
+
+/// A login whose authorized_keys file had a deleted key
+#[derive(Clone, Debug, Serialize)]
+pub struct RemovalTarget {
+    pub host: String,
+    pub login: String,
+    pub status: RemovalStatus,
+}
+
+/// Deleted keys and the logins the deploy job removes them from
+#[derive(Clone, Debug, Serialize)]
+pub struct KeyRemoval {
+    pub id: u64,
+    /// Fingerprints of the deleted keys
+    pub keys: Vec<String>,
+    pub actor: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub deleted_at: OffsetDateTime,
+    pub targets: Vec<RemovalTarget>,
+}
+
+impl KeyRemoval {
+    /// How many logins have this status
+    pub fn count(&self, status: RemovalStatus) -> usize {
+        self.targets
+            .iter()
+            .filter(|target| target.status == status)
+            .count()
+    }
+}
+
+/// Key removals kept for the API, older ones are dropped
+const KEPT_REMOVALS: usize = 100;
+
+#[derive(Debug)]
+struct ScheduledJob {
+    name: String,
+    kind: JobKind,
+    schedule: Option<Cron>,
+    tag: Option<String>,
+    /// Only used by deploy jobs
+    strategy: DeployStrategy,
+    /// Comes from the schedule table and is replaced on reload
+    from_database: bool,
+    /// Id of this job in the cron scheduler
+    guid: Mutex<Option<Uuid>>,
+    status: Mutex<JobStatus>,
+}
+
+impl ScheduledJob {
+    fn new(
+        name: String,
+        kind: JobKind,
+        schedule: Option<Cron>,
+        tag: Option<String>,
+        strategy: DeployStrategy,
+        from_database: bool,
+    ) -> Self {
+        Self {
+            name,
+            kind,
+            schedule,
+            tag,
+            strategy,
+            from_database,
+            guid: Mutex::new(None),
+            status: Mutex::new(JobStatus::default()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RunError {
+    NoSuchJob,
+    AlreadyRunning,
+}
+
+impl std::fmt::Display for RunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoSuchJob => write!(f, "No such job"),
+            Self::AlreadyRunning => write!(f, "This job is already running"),
+        }
+    }
+}
+
+fn parse_cron(pattern: &str) -> Result<Cron, String> {
+    Cron::new(pattern)
+        .with_seconds_optional()
+        .parse()
+        .map_err(|e| format!("Failed to parse Cron syntax '{pattern}': {e}"))
+}
+
+pub struct Scheduler {
+    client: Arc<dyn HostCache>,
+    conn: ConnectionPool,
+    hooks: Arc<EventHooks>,
+    remediation: Arc<RemediationPolicies>,
+    freezes: Arc<Freezes>,
+    activity_log: Arc<ActivityLog>,
+    jobs: RwLock<Vec<Arc<ScheduledJob>>>,
+    /// Logins waiting for the deploy job, kept in memory only
+    deploy_queue: Mutex<Vec<QueuedDeploy>>,
+    /// Set when canaries fail, kept in memory only
+    deploy_halt: Mutex<Option<DeployHalt>>,
+    /// The latest key removals and how far they got, kept in memory only
+    key_removals: Mutex<Vec<KeyRemoval>>,
+    cron: tokio::sync::Mutex<Option<JobScheduler>>,
+    /// Maximum random delay before a scheduled run
+    jitter: Duration,
+    /// Hosts contacted at the same time by a run
+    max_concurrent: usize,
+}
+
+impl Scheduler {
+    pub fn new(
+        client: Arc<dyn HostCache>,
+        conn: ConnectionPool,
+        config: &SshConfig,
+        hooks: Arc<EventHooks>,
+        remediation: Arc<RemediationPolicies>,
+        freezes: Arc<Freezes>,
+        activity_log: Arc<ActivityLog>,
+    ) -> Self {
+        let jobs = [
+            (JobKind::Check, config.check_schedule.clone()),
+            (JobKind::Update, config.update_schedule.clone()),
+            (JobKind::Recertify, config.recertify_schedule.clone()),
+            (JobKind::Deploy, config.deploy_schedule.clone()),
+        ]
+        .into_iter()
+        .map(|(kind, schedule)| {
+            Arc::new(ScheduledJob::new(
+                kind.as_str().to_owned(),
+                kind,
+                schedule,
+                None,
+                config.deploy_strategy,
+                false,
+            ))
+        })
+        .collect();
+
+        Self {
+            client,
+            conn,
+            hooks,
+            remediation,
+            freezes,
+            activity_log,
+            jobs: RwLock::new(jobs),
+            deploy_queue: Mutex::new(Vec::new()),
+            deploy_halt: Mutex::new(None),
+            key_removals: Mutex::new(Vec::new()),
+            cron: tokio::sync::Mutex::new(None),
+            jitter: config.schedule_jitter,
+            max_concurrent: config.max_concurrent_connections,
+        }
+    }
+
+    /// Check a schedule before it is stored
+    pub fn validate(schedule: &NewSchedule) -> Result<(), String> {
+        if schedule.name.trim().is_empty() {
+            return Err(String::from("The schedule needs a name"));
+        }
+        if JobKind::from_str(&schedule.name).is_ok() {
+            return Err(format!(
+                "'{}' is reserved for the builtin job",
+                schedule.name
+            ));
+        }
+        let kind = JobKind::from_str(&schedule.job)?;
+        if let Some(ref strategy) = schedule.strategy {
+            if kind != JobKind::Deploy {
+                return Err(String::from("Only deploy jobs have a strategy"));
+            }
+            DeployStrategy::from_str(strategy)?;
+        }
+        parse_cron(&schedule.cron).map(|_| ())
+    }
+
+    /// Configuration and state of all jobs
+    pub fn jobs(&self) -> Vec<JobInfo> {
+        let halted = self.deploy_halt();
+        self.jobs
+            .read()
+            .expect("Job list lock poisoned")
+            .iter()
+            .map(|job| JobInfo {
+                name: job.name.clone(),
+                job: job.kind.as_str(),
+                schedule: job.schedule.as_ref().map(|cron| cron.pattern.to_string()),
+                tag: job.tag.clone(),
+                strategy: (job.kind == JobKind::Deploy).then(|| job.strategy.to_string()),
+                halted: halted.clone().filter(|_| job.kind == JobKind::Deploy),
+                next_run: job.schedule.as_ref().and_then(next_occurrence),
+                status: job.status.lock().expect("Job status lock poisoned").clone(),
+            })
+            .collect()
+    }
+
+    fn find(&self, name: &str) -> Option<Arc<ScheduledJob>> {
+        self.jobs
+            .read()
+            .expect("Job list lock poisoned")
+            .iter()
+            .find(|job| job.name.eq(name))
+            .cloned()
+    }
+
+    /// Mark a job as running, failing if it already is
+    fn begin(&self, name: &str) -> Result<Arc<ScheduledJob>, RunError> {
+        let job = self.find(name).ok_or(RunError::NoSuchJob)?;
+
+        {
+            let mut status = job.status.lock().expect("Job status lock poisoned");
+            if status.running {
+                return Err(RunError::AlreadyRunning);
+            }
+            status.running = true;
+            status.last_run = Some(OffsetDateTime::now_utc());
+        }
+
+        Ok(job)
+    }
+
+    async fn execute(&self, job: &ScheduledJob) {
+        let name = &job.name;
+        let tag = job.tag.as_deref();
+        let started = Instant::now();
+
+        info!("Running {name} job");
+        let state = self.client.get_current_state(tag, self.max_concurrent);
+        let result = match job.kind {
+            JobKind::Check => match state.await {
+                Ok(data) => {
+                    let mut drifted = Vec::new();
+                    for (host, (_, diff)) in data {
+                        let Ok(diff) = diff else {
+                            continue;
+                        };
+                        if !diff.is_empty() {
+                            self.hooks.emit(Event::DriftDetected {
+                                host: host.clone(),
+                                logins: diff.iter().map(|(login, _)| login.clone()).collect(),
+                            });
+                            drifted.push((host, diff));
+                        }
+                    }
+                    self.remediate(name, drifted).await;
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            },
+            JobKind::Update => state.await.map(|_| ()),
+            JobKind::Recertify => self.recertify(),
+            JobKind::Deploy => self.deploy_queued(name, job.strategy).await,
+        };
+
+        match result {
+            Ok(()) => info!("Succeeded {name} job"),
+            Err(ref e) => error!("Failed {name} job: {e}"),
+        }
+
+        let mut status = job.status.lock().expect("Job status lock poisoned");
+        status.running = false;
+        status.last_duration_ms =
+            Some(u64::try_from(started.elapsed().as_millis()).unwrap_or(u64::MAX));
+        status.last_error = result.err();
+    }
+
+    /// Closes the recertification campaigns whose deadline passed, the tag of the job is ignored
+    fn recertify(&self) -> Result<(), String> {
+        let reports =
+            RecertificationCampaign::close_overdue(&mut self.conn.get().unwrap(), RECERTIFY_ACTOR)?;
+        for report in reports {
+            info!(
+                "Closed recertification campaign {}: {} approved, {} revoked, {} auto-revoked, {} overdue",
+                report.campaign.name,
+                report.approved,
+                report.revoked,
+                report.auto_revoked,
+                report.overdue
+            );
+            self.hooks.emit(Event::recertification_completed(report));
+        }
+        Ok(())
+    }
+
+    /// Queues the deployment of a login and starts the deploy job
+    pub fn queue_deploy(self: Arc<Self>, host: String, login: String, actor: &str) {
+        self.enqueue_deploy(host, login, actor);
+        self.start_deploy();
+    }
+
+    fn enqueue_deploy(&self, host: String, login: String, actor: &str) {
+        let mut queue = self
+            .deploy_queue
+            .lock()
+            .expect("Deploy queue lock poisoned");
+        if !queue
+            .iter()
+            .any(|queued| queued.host == host && queued.login == login)
+        {
+            queue.push(QueuedDeploy {
+                host,
+                login,
+                actor: actor.to_owned(),
+                queued_at: OffsetDateTime::now_utc(),
+            });
+        }
+    }
+
+    fn start_deploy(self: Arc<Self>) {
+        match self.run_now(JobKind::Deploy.as_str()) {
+            // A running job takes it from the queue as well
+            Ok(()) | Err(RunError::AlreadyRunning) => {}
+            Err(e) => warn!("Failed to start deploy job: {e}"),
+        }
+    }
+
+    /// Queues the deployment of every login that had one of the deleted keys and starts the
+    /// deploy job, which regenerates their authorized_keys files without the keys
+    pub fn queue_key_removal(
+        self: Arc<Self>,
+        keys: Vec<String>,
+        logins: Vec<(String, String)>,
+        actor: &str,
+    ) -> KeyRemoval {
+        let removal = {
+            let mut removals = self
+                .key_removals
+                .lock()
+                .expect("Key removals lock poisoned");
+            let removal = KeyRemoval {
+                id: removals.last().map_or(1, |removal| removal.id + 1),
+                keys,
+                actor: actor.to_owned(),
+                deleted_at: OffsetDateTime::now_utc(),
+                targets: logins
+                    .iter()
+                    .map(|(host, login)| RemovalTarget {
+                        host: host.clone(),
+                        login: login.clone(),
+                        status: RemovalStatus::Queued,
+                    })
+                    .collect(),
+            };
+            if removals.len() >= KEPT_REMOVALS {
+                removals.remove(0);
+            }
+            removals.push(removal.clone());
+            removal
+        };
+        if !logins.is_empty() {
+            for (host, login) in logins {
+                self.enqueue_deploy(host, login, actor);
+            }
+            self.start_deploy();
+        }
+        removal
+    }
+
+    /// The latest key removals, newest first
+    pub fn key_removals(&self) -> Vec<KeyRemoval> {
+        let mut removals = self
+            .key_removals
+            .lock()
+            .expect("Key removals lock poisoned")
+            .clone();
+        removals.reverse();
+        removals
+    }
+
+    /// Records the outcome of a deployment in the removals still waiting for it
+    fn update_removals(&self, host: &str, login: &str, status: RemovalStatus) {
+        let mut removals = self
+            .key_removals
+            .lock()
+            .expect("Key removals lock poisoned");
+        for target in removals.iter_mut().flat_map(|removal| &mut removal.targets) {
+            if target.host == host
+                && target.login == login
+                && matches!(target.status, RemovalStatus::Queued | RemovalStatus::Failed)
+            {
+                target.status = status;
+            }
+        }
+    }
+
+    /// Logins waiting for the deploy job, oldest first
+    pub fn queued_deploys(&self) -> Vec<QueuedDeploy> {
+        self.deploy_queue
+            .lock()
+            .expect("Deploy queue lock poisoned")
+            .clone()
+    }
+
+    /// Why the deploy jobs are halted, if canaries failed
+    pub fn deploy_halt(&self) -> Option<DeployHalt> {
+        self.deploy_halt
+            .lock()
+            .expect("Deploy halt lock poisoned")
+            .clone()
+    }
+
+    /// Lets the deploy jobs deploy again after canaries failed, returning why they were halted
+    pub fn resume_deploys(&self) -> Option<DeployHalt> {
+        let halt = self
+            .deploy_halt
+            .lock()
+            .expect("Deploy halt lock poisoned")
+            .take();
+        if let Some(ref halt) = halt {
+            info!(
+                "Resuming deployments halted since {}",
+                halt.since.format(&Rfc3339).unwrap_or_default()
+            );
+        }
+        halt
+    }
+
+    /// Deploys the queued logins one after another, the tag of the job is ignored. Logins that fail
+    /// stay queued for the next run, and nothing is deployed during a change freeze or in read-only
+    /// mode. With a canary strategy the logins of some hosts are deployed first. If those fail or
+    /// the hosts don't accept connections afterwards, the others stay queued and nothing is deployed
+    /// until the deployments are resumed.
+    async fn deploy_queued(&self, job_name: &str, strategy: DeployStrategy) -> Result<(), String> {
+        if let Some(halt) = self.deploy_halt() {
+            return Err(format!(
+                "Not deploying {} queued logins, deployments are halted since {}: {}",
+                self.queued_deploys().len(),
+                halt.since.format(&Rfc3339).unwrap_or_default(),
+                halt.reason
+            ));
+        }
+        if let Some(read_only) = self.freezes.read_only() {
+            return Err(format!(
+                "Not deploying {} queued logins: {}",
+                self.queued_deploys().len(),
+                read_only.message()
+            ));
+        }
+        if let Some(freeze) = self.freezes.active(&mut self.conn.get().unwrap(), now())? {
+            return Err(format!(
+                "Not deploying {} queued logins: {}",
+                self.queued_deploys().len(),
+                freeze.message()
+            ));
+        }
+
+        let canaries = self.canaries(strategy).await;
+        if !canaries.is_empty() {
+            let names: Vec<&str> = canaries.iter().map(|host| host.name.as_str()).collect();
+            let names = names.join(", ");
+            let batch: Vec<QueuedDeploy> = {
+                let mut queue = self
+                    .deploy_queue
+                    .lock()
+                    .expect("Deploy queue lock poisoned");
+                let (batch, rest) = queue
+                    .drain(..)
+                    .partition(|queued| canaries.iter().any(|host| host.name == queued.host));
+                *queue = rest;
+                batch
+            };
+            info!("Deploying {} logins to the canaries {names}", batch.len());
+
+            let mut failed = Vec::new();
+            for queued in batch {
+                if let Err(queued) = self.deploy_one(job_name, queued).await {
+                    failed.push(queued);
+                }
+            }
+            let mut unreachable = false;
+            if failed.is_empty() {
+                for host in canaries {
+                    let checks = self.client.test_connection(host.clone()).await;
+                    if let Some(check) = checks
+                        .iter()
+                        .find(|check| matches!(check.status, CheckStatus::Failed))
+                    {
+                        error!(
+                            "Canary {} failed the connection test after the deployment: {} {}",
+                            host.name, check.step, check.detail
+                        );
+                        unreachable = true;
+                    }
+                }
+            }
+            if !failed.is_empty() || unreachable {
+                let held_back = self.queued_deploys().len();
+                let mut queue = self
+                    .deploy_queue
+                    .lock()
+                    .expect("Deploy queue lock poisoned");
+                queue.splice(0..0, failed);
+                let reason = format!(
+                    "Canary deployment to {names} failed, {held_back} queued logins were held back"
+                );
+                *self.deploy_halt.lock().expect("Deploy halt lock poisoned") = Some(DeployHalt {
+                    reason: reason.clone(),
+                    since: OffsetDateTime::now_utc(),
+                });
+                return Err(reason);
+            }
+            info!("Canary deployment to {names} succeeded, deploying the other hosts");
+        }
+
+        let mut failed = Vec::new();
+        loop {
+            let next = {
+                let mut queue = self
+                    .deploy_queue
+                    .lock()
+                    .expect("Deploy queue lock poisoned");
+                (!queue.is_empty()).then(|| queue.remove(0))
+            };
+            let Some(queued) = next else {
+                break;
+            };
+            if let Err(queued) = self.deploy_one(job_name, queued).await {
+                failed.push(queued);
+            }
+        }
+
+        if failed.is_empty() {
+            return Ok(());
+        }
+        let count = failed.len();
+        self.deploy_queue
+            .lock()
+            .expect("Deploy queue lock poisoned")
+            .extend(failed);
+        Err(format!("Failed to deploy {count} queued logins"))
+    }
+
+    /// Hosts of the queue the strategy deploys to first, none if all are deployed right away.
+    /// Protected hosts are never deployed by the job, so they aren't canaries either.
+    async fn canaries(&self, strategy: DeployStrategy) -> Vec<Host> {
+        if strategy == DeployStrategy::All {
+            return Vec::new();
+        }
+        let mut names: Vec<String> = Vec::new();
+        for queued in self.queued_deploys() {
+            if !names.contains(&queued.host) {
+                names.push(queued.host);
+            }
+        }
+
+        let mut hosts = Vec::new();
+        for name in names {
+            match Host::get_from_name(self.conn.get().unwrap(), name).await {
+                Ok(Some(host)) if !host.protected => hosts.push(host),
+                _ => {}
+            }
+        }
+        let picked = pick_canaries(strategy, hosts.iter().map(|host| host.tag_list().collect()));
+        hosts
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, host)| picked.contains(&index).then_some(host))
+            .collect()
+    }
+
+    /// Deploys one queued login, handing it back if it has to be tried again
+    async fn deploy_one(&self, job_name: &str, queued: QueuedDeploy) -> Result<(), QueuedDeploy> {
+        let host = match Host::get_from_name(self.conn.get().unwrap(), queued.host.clone()).await {
+            Ok(Some(host)) => host,
+            Ok(None) => {
+                self.update_removals(&queued.host, &queued.login, RemovalStatus::Skipped);
+                return Ok(());
+            }
+            Err(e) => {
+                error!("Failed to look up {} for deployment: {e}", queued.host);
+                self.update_removals(&queued.host, &queued.login, RemovalStatus::Failed);
+                return Err(queued);
+            }
+        };
+        if host.protected {
+            warn!(
+                "Not deploying {} on {}, it is protected",
+                queued.login, host.name
+            );
+            self.update_removals(&queued.host, &queued.login, RemovalStatus::Skipped);
+            return Ok(());
+        }
+        let deployed = self
+            .remediate_login(
+                job_name,
+                &queued.actor,
+                &host,
+                Policy::FullSync,
+                &queued.login,
+                &[],
+            )
+            .await;
+        if deployed {
+            self.update_removals(&queued.host, &queued.login, RemovalStatus::Removed);
+            // Refresh the cache, so the removed keys aren't shown anymore
+            let _ = self.client.get_host_diff(host, true).await;
+            Ok(())
+        } else {
+            self.update_removals(&queued.host, &queued.login, RemovalStatus::Failed);
+            Err(queued)
+        }
+    }
+
+    /// Fixes the drift of hosts according to their remediation policy, one host after another.
+    /// Protected hosts are left alone, and nothing is deployed during a change freeze or in
+    /// read-only mode.
+    async fn remediate(&self, job_name: &str, drifted: Vec<HostDrift>) {
+        let mut hosts = Vec::new();
+        for (host_name, logins) in drifted {
+            match Host::get_from_name(self.conn.get().unwrap(), host_name.clone()).await {
+                Ok(Some(host)) => match self.remediation.policy_for(&host) {
+                    Policy::Off => {}
+                    Policy::ReportOnly => {
+                        for (login, diff) in logins {
+                            info!(
+                                "Drift of {login} on {host_name} isn't remediated (report_only), full_sync would fix {} differences",
+                                diff.len()
+                            );
+                        }
+                    }
+                    policy if host.protected => warn!(
+                        "Not remediating drift on {host_name} ({}), it is protected",
+                        policy.as_str()
+                    ),
+                    policy => hosts.push((host, policy, logins)),
+                },
+                Ok(None) => {}
+                Err(e) => error!("Failed to look up {host_name} for remediation: {e}"),
+            }
+        }
+        if hosts.is_empty() {
+            return;
+        }
+
+        if let Some(read_only) = self.freezes.read_only() {
+            warn!(
+                "Not remediating drift on {} hosts: {}",
+                hosts.len(),
+                read_only.message()
+            );
+            return;
+        }
+        match self.freezes.active(&mut self.conn.get().unwrap(), now()) {
+            Ok(None) => {}
+            Ok(Some(freeze)) => {
+                warn!(
+                    "Not remediating drift on {} hosts: {}",
+                    hosts.len(),
+                    freeze.message()
+                );
+                return;
+            }
+            Err(e) => {
+                error!("Failed to check for change freezes, not remediating drift: {e}");
+                return;
+            }
+        }
+
+        for (host, policy, logins) in hosts {
+            let mut deployed = false;
+            for (login, diff) in logins {
+                deployed |= self
+                    .remediate_login(job_name, ACTOR, &host, policy, &login, &diff)
+                    .await;
+            }
+            // Refresh the cache, so the fixed drift isn't shown anymore
+            if deployed {
+                if let (_, Ok(diff)) = self.client.get_host_diff(host.clone(), true).await {
+                    if !diff.is_empty() {
+                        warn!("{} still has drift after remediation", host.name);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Deploys the keyfile the policy chooses for a login, returns whether something was deployed
+    async fn remediate_login(
+        &self,
+        job_name: &str,
+        actor: &str,
+        host: &Host,
+        policy: Policy,
+        login: &str,
+        diff: &[DiffItem],
+    ) -> bool {
+        let previous = self
+            .client
+            .get_authorized_keyfile(host.clone(), login)
+            .await
+            .unwrap_or_default();
+        let generated = host.get_authorized_keys_file_for(
+            self.client.as_ref(),
+            &mut self.conn.get().unwrap(),
+            login,
+            self.client.key_limits(),
+        );
+        let keyfile = match generated {
+            Ok(generated) => {
+                match policy.keyfile(&host.keep_tolerated_keys(generated, &previous), diff) {
+                    Some(keyfile) => keyfile,
+                    None => return false,
+                }
+            }
+            Err(e) => {
+                error!(
+                    "Failed to generate authorized_keys of {login} on {}: {e}",
+                    host.name
+                );
+                return false;
+            }
+        };
+        let own_key = self.client.get_own_key_b64();
+        if locks_out_ssm(host, login, &keyfile, &own_key) {
+            error!(
+                "Not deploying to {login} on {}, the key of SSM would be missing",
+                host.name
+            );
+            return false;
+        }
+        // Jobs never force this, a login without keys needs a deployment by hand
+        if locks_out(&previous, &keyfile, &own_key) {
+            warn!(
+                "Not deploying to {login} on {}, no key besides the one of SSM would be left",
+                host.name
+            );
+            return false;
+        }
+
+        let mut result = self
+            .client
+            .set_authorized_keys(host.name.clone(), login.to_owned(), keyfile.clone())
+            .await
+            .map(|output| {
+                for finding in output.permissions {
+                    if finding.starts_with("fixed") {
+                        info!("Permissions of {login} on {}: {finding}", host.name);
+                    } else {
+                        warn!("Permissions of {login} on {}: {finding}", host.name);
+                    }
+                }
+            });
+        // Principals are part of the generated state only a full sync restores
+        if result.is_ok() && matches!(policy, Policy::FullSync) {
+            result = deploy_principals(self.client.as_ref(), &self.conn, host, login)
+                .await
+                .map(|_| ());
+        }
+
+        let mut conn = self.conn.get().unwrap();
+        let status = match &result {
+            Ok(_) => {
+                info!(
+                    "Remediated drift of {login} on {} ({})",
+                    host.name,
+                    policy.as_str()
+                );
+                if let Err(e) = KeyHistory::record_deploy(
+                    &mut conn, &host.name, login, &previous, &keyfile, actor,
+                ) {
+                    warn!("Failed to record key history for {}: {e}", host.name);
+                }
+                self.hooks.emit(Event::KeysDeployed {
+                    host: host.name.clone(),
+                    login: login.to_owned(),
+                });
+                200
+            }
+            Err(e) => {
+                error!("Failed to remediate drift of {login} on {}: {e}", host.name);
+                500
+            }
+        };
+        let target = format!("{job_name} {} {login}", host.name);
+        if let Err(e) = self
+            .activity_log
+            .record(&mut conn, actor, "deploy", "JOB", &target, status)
+        {
+            error!("Failed to record deploy by {actor}: {e}");
+        }
+        result.is_ok()
+    }
+
+    /// Run a job and wait for it to finish
+    pub async fn run(&self, name: &str) -> Result<(), RunError> {
+        let job = self.begin(name)?;
+        self.execute(&job).await;
+        Ok(())
+    }
+
+    /// Start a job in the background on request, a deploy job also resumes halted deployments
+    pub fn run_requested(self: Arc<Self>, name: &str) -> Result<(), RunError> {
+        if self
+            .find(name)
+            .is_some_and(|job| job.kind == JobKind::Deploy)
+        {
+            self.resume_deploys();
+        }
+        self.run_now(name)
+    }
+
+    /// Start a job in the background, outside of its schedule
+    pub fn run_now(self: Arc<Self>, name: &str) -> Result<(), RunError> {
+        let job = self.begin(name)?;
+        tokio::spawn(async move { self.execute(&job).await });
+        Ok(())
+    }
+
+    /// Random delay between zero and the configured jitter, so that jobs firing at the same
+    /// time don't all connect at once
+    fn random_delay(&self) -> Duration {
+        let jitter_ms = u64::try_from(self.jitter.as_millis()).unwrap_or(u64::MAX);
+        if jitter_ms == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_millis(RandomState::new().hash_one(Instant::now()) % jitter_ms)
+    }
+
+    /// Add a job to the cron scheduler, if it has a schedule
+    async fn schedule(self: &Arc<Self>, sched: &JobScheduler, job: &ScheduledJob) {
+        let Some(ref schedule) = job.schedule else {
+            return;
+        };
+        let name = job.name.clone();
+        let scheduler = Arc::clone(self);
+
+        let mut builder = JobBuilder::new().with_cron_job_type();
+        builder.schedule = Some(schedule.clone());
+        builder = builder.with_run_async(Box::new(move |_uuid, _sched| {
+            let scheduler = Arc::clone(&scheduler);
+            let name = name.clone();
+            Box::pin(async move {
+                let delay = scheduler.random_delay();
+                if !delay.is_zero() {
+                    debug!("Delaying {name} job by {}ms", delay.as_millis());
+                    tokio::time::sleep(delay).await;
+                }
+                if let Err(e) = scheduler.run(&name).await {
+                    warn!("Skipped {name} job: {e}");
+                }
+            })
+        }));
+
+        let added = match builder.build() {
+            Ok(cron_job) => sched.add(cron_job).await,
+            Err(e) => Err(e),
+        };
+        match added {
+            Ok(guid) => {
+                *job.guid.lock().expect("Job id lock poisoned") = Some(guid);
+                info!("Scheduled {} job: '{}'", job.name, schedule.pattern);
+            }
+            Err(e) => error!("Failed to create {} job: {e}", job.name),
+        }
+    }
+
+    /// Replace the jobs from the schedule table with its current contents
+    pub async fn reload(self: &Arc<Self>) -> Result<(), String> {
+        let schedules = Schedule::get_all_schedules(&mut self.conn.get().unwrap())?;
+
+        let mut loaded = Vec::with_capacity(schedules.len());
+        for schedule in schedules {
+            let parsed = JobKind::from_str(&schedule.job).and_then(|kind| {
+                let strategy = schedule.strategy.as_deref().map(str::parse).transpose()?;
+                parse_cron(&schedule.cron).map(|cron| (kind, cron, strategy.unwrap_or_default()))
+            });
+            let (kind, cron, strategy) = match parsed {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    error!("Ignoring schedule {}: {e}", schedule.name);
+                    continue;
+                }
+            };
+            let job = ScheduledJob::new(
+                schedule.name,
+                kind,
+                Some(cron),
+                schedule.tag,
+                strategy,
+                true,
+            );
+            if let Some(previous) = self.find(&job.name) {
+                *job.status.lock().expect("Job status lock poisoned") = previous
+                    .status
+                    .lock()
+                    .expect("Job status lock poisoned")
+                    .clone();
+            }
+            loaded.push(Arc::new(job));
+        }
+
+        let cron = self.cron.lock().await;
+
+        let removed: Vec<Arc<ScheduledJob>> = {
+            let mut jobs = self.jobs.write().expect("Job list lock poisoned");
+            let (removed, kept) = jobs.drain(..).partition(|job| job.from_database);
+            *jobs = kept;
+            jobs.extend(loaded.iter().cloned());
+            removed
+        };
+
+        if let Some(ref sched) = *cron {
+            for job in removed {
+                let guid = job.guid.lock().expect("Job id lock poisoned").take();
+                if let Some(guid) = guid {
+                    if let Err(e) = sched.remove(&guid).await {
+                        error!("Failed to remove {} job: {e}", job.name);
+                    }
+                }
+            }
+            for job in &loaded {
+                self.schedule(sched, job).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Register all scheduled jobs with a cron scheduler and start it
+    pub async fn start(self: Arc<Self>) {
+        let sched = match JobScheduler::new().await {
+            Ok(sched) => sched,
+            Err(e) => {
+                error!("Failed to create job scheduler: {e}");
+                return;
+            }
+        };
+
+        let builtin: Vec<Arc<ScheduledJob>> =
+            self.jobs.read().expect("Job list lock poisoned").clone();
+        for job in &builtin {
+            self.schedule(&sched, job).await;
+        }
+        *self.cron.lock().await = Some(sched.clone());
+
+        if let Err(e) = self.reload().await {
+            error!("Failed to load schedules: {e}");
+        }
+
+        info!("Starting scheduler");
+        if let Err(e) = sched.start().await {
+            error!("Failed to start scheduler: {e}");
+        }
+    }
+}
+
+/// Positions of the hosts the strategy deploys to first, given the tags of the queued hosts in
+/// queue order. Hosts without tags are a group of their own for `canary:tag`.
+fn pick_canaries<'a>(
+    strategy: DeployStrategy,
+    hosts: impl IntoIterator<Item = Vec<&'a str>>,
+) -> Vec<usize> {
+    let mut picked = Vec::new();
+    let mut groups = HashSet::new();
+    for (index, mut tags) in hosts.into_iter().enumerate() {
+        match strategy {
+            DeployStrategy::All => break,
+            DeployStrategy::Canary(count) if picked.len() >= count => break,
+            DeployStrategy::Canary(_) => picked.push(index),
+            DeployStrategy::CanaryPerTag => {
+                if tags.is_empty() {
+                    tags.push("");
+                }
+                // A host with several new tags stands in for all of them
+                let new_groups = tags.into_iter().filter(|tag| groups.insert(*tag)).count();
+                if new_groups > 0 {
+                    picked.push(index);
+                }
+            }
+        }
+    }
+    picked
+}
+
+fn next_occurrence(cron: &Cron) -> Option<OffsetDateTime> {
+    let next = cron.find_next_occurrence(&chrono::Utc::now(), false).ok()?;
+    OffsetDateTime::from_unix_timestamp(next.timestamp()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canary_count() {
+        let hosts = || vec![vec!["web"], vec!["web"], vec![], vec!["db"]];
+        assert_eq!(
+            pick_canaries(DeployStrategy::All, hosts()),
+            Vec::<usize>::new()
+        );
+        assert_eq!(
+            pick_canaries(DeployStrategy::Canary(2), hosts()),
+            vec![0, 1]
+        );
+        assert_eq!(
+            pick_canaries(DeployStrategy::Canary(9), hosts()),
+            vec![0, 1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn canary_per_tag() {
+        let hosts = vec![vec!["web"], vec!["web", "eu"], vec!["db", "eu"], vec!["db"]];
+        assert_eq!(
+            pick_canaries(DeployStrategy::CanaryPerTag, hosts),
+            vec![0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn untagged_hosts_are_a_group_of_their_own() {
+        let untagged = vec![vec![], vec![], vec![]];
+        assert_eq!(
+            pick_canaries(DeployStrategy::CanaryPerTag, untagged),
+            vec![0]
+        );
+
+        let mixed = vec![vec!["web"], vec![], vec!["web"], vec![]];
+        assert_eq!(
+            pick_canaries(DeployStrategy::CanaryPerTag, mixed),
+            vec![0, 1]
+        );
+    }
+}
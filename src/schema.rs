@@ -15,6 +15,58 @@ diesel::table! {
         key_fingerprint -> Nullable<Text>,
         /// jumphost for ssh connections
         jump_via -> Nullable<Integer>,
+        /// how authorized_keys files are read and written (script, exec or sftp)
+        transport -> Text,
+        /// comma separated tags for grouping hosts
+        tags -> Text,
+        /// command run on the host before writing an authorized_keys file
+        pre_deploy_hook -> Nullable<Text>,
+        /// command run on the host after writing an authorized_keys file
+        post_deploy_hook -> Nullable<Text>,
+        /// which addresses are tried first (any, ipv4 or ipv6), NULL for the configured default
+        address_family -> Nullable<Text>,
+        /// socks5:// or http:// proxy the connection is opened through
+        proxy -> Nullable<Text>,
+        /// comma separated addresses tried in order if address can't be reached
+        fallback_addresses -> Text,
+        /// comma separated alternative names
+        aliases -> Text,
+        /// comma separated host key fingerprints accepted besides key_fingerprint
+        additional_key_fingerprints -> Text,
+        /// host key fingerprint offered instead of an accepted one, until the host keys are changed
+        key_mismatch -> Nullable<Text>,
+        /// deleting the host and deploying to it need a confirmation token from a preview
+        protected -> Bool,
+        /// comma separated fingerprints of unmanaged keys that are kept and not reported as drift
+        tolerated_keys -> Text,
+        /// authorized_principals file managed for every login, relative to the home directory or with %h and %u
+        principals_file -> Nullable<Text>,
+        /// comma separated key algorithms the sshd of the host accepts, empty for all
+        key_algorithms -> Text,
+        /// connection plugin of the configuration that carries the SSH connection instead of TCP
+        connection_plugin -> Nullable<Text>,
+        /// where the script transport installs its script, relative to the home directory or absolute
+        script_path -> Nullable<Text>,
+        /// command the script is run with instead of its shebang line
+        script_interpreter -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    /// Additional cron schedules for the background jobs
+    schedule (id) {
+        /// unique id
+        id -> Integer,
+        /// unique name of this schedule
+        name -> Text,
+        /// cron expression
+        cron -> Text,
+        /// which job to run (check or update)
+        job -> Text,
+        /// only run on hosts with this tag
+        tag -> Nullable<Text>,
+        /// how a deploy job rolls out, all hosts at once without
+        strategy -> Nullable<Text>,
     }
 }
 
@@ -27,6 +79,14 @@ diesel::table! {
         username -> Text,
         /// whether this user is active
         enabled -> Bool,
+        /// contact address
+        email -> Nullable<Text>,
+        /// real name
+        full_name -> Nullable<Text>,
+        /// department or team
+        department -> Nullable<Text>,
+        /// id of the user at the identity provider
+        external_id -> Nullable<Text>,
     }
 }
 
@@ -45,6 +105,8 @@ diesel::table! {
         login -> Text,
         /// ssh key options
         options -> Nullable<Text>,
+        /// comma separated certificate principals
+        principals -> Nullable<Text>,
     }
 }
 
@@ -65,4 +127,307 @@ diesel::table! {
     }
 }
 
-diesel::allow_tables_to_appear_in_same_query!(host, user, authorization, user_key,);
+diesel::table! {
+    /// Every change to an authorization, kept after the authorization is gone
+    authorization_history (id) {
+        /// unique id
+        id -> Integer,
+        /// the authorization that changed
+        authorization_id -> Integer,
+        /// created, updated or deleted
+        action -> Text,
+        /// host at the time of the change
+        host_id -> Integer,
+        /// name of the host at the time of the change
+        host_name -> Text,
+        /// user at the time of the change
+        user_id -> Integer,
+        /// name of the user at the time of the change
+        username -> Text,
+        /// username on the host
+        login -> Text,
+        /// ssh key options
+        options -> Nullable<Text>,
+        /// who made the change
+        actor -> Text,
+        /// when the change happened (UTC)
+        changed_at -> Timestamp,
+        /// login before an update
+        old_login -> Nullable<Text>,
+        /// ssh key options before an update
+        old_options -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    /// What happened to a public key: added, deleted, deployed to or removed from a host
+    key_history (id) {
+        /// unique id
+        id -> Integer,
+        /// the key, if it is known to ssm
+        key_id -> Nullable<Integer>,
+        /// base64 encoded public key
+        key_base64 -> Text,
+        /// created, deleted, transferred, deployed or removed
+        action -> Text,
+        /// owner of the key at the time
+        username -> Nullable<Text>,
+        /// host the key was deployed to or removed from
+        host_name -> Nullable<Text>,
+        /// login on the host
+        login -> Nullable<Text>,
+        /// who made the change
+        actor -> Text,
+        /// when the change happened (UTC)
+        changed_at -> Timestamp,
+        /// owner before a transfer
+        previous_username -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    /// Discovered hosts waiting for their host key to be confirmed
+    pending_host (token) {
+        /// random token the confirmation refers to
+        token -> Text,
+        /// display name
+        name -> Text,
+        /// username for ssh connections
+        username -> Text,
+        /// hostname or ip address for ssh connections
+        address -> Text,
+        /// port for ssh connections
+        port -> Integer,
+        /// fingerprint of the host key offered on discovery
+        key_fingerprint -> Text,
+        /// jumphost for ssh connections
+        jump_via -> Nullable<Integer>,
+        /// how authorized_keys files are read and written (script, exec or sftp)
+        transport -> Text,
+        /// comma separated tags for grouping hosts
+        tags -> Text,
+        /// socks5:// or http:// proxy the connection is opened through
+        proxy -> Nullable<Text>,
+        /// when the host key was discovered (UTC)
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    /// Security relevant events, like a host presenting an unknown host key
+    security_event (id) {
+        /// unique id
+        id -> Integer,
+        /// what happened, e.g. hostkey.mismatch
+        kind -> Text,
+        /// how serious the event is, e.g. critical
+        severity -> Text,
+        /// host the event is about
+        host_name -> Nullable<Text>,
+        /// human readable description
+        message -> Text,
+        /// when it happened (UTC)
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    /// Events waiting to be published to the message bus
+    event_outbox (id) {
+        /// unique id, publishing follows its order
+        id -> Integer,
+        /// event name, e.g. host.created
+        event -> Text,
+        /// the event as JSON, like it is sent to hooks
+        payload -> Text,
+        /// when the event happened (UTC)
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    /// Requests and who made them, reads only if their category is enabled
+    activity (id) {
+        /// unique id
+        id -> Integer,
+        /// logged in user, or grpc
+        actor -> Text,
+        /// normalized action name, e.g. deploy or host.create
+        action -> Text,
+        /// HTTP method, or GRPC
+        method -> Text,
+        /// requested path including names and ids, or the gRPC call and its target
+        path -> Text,
+        /// HTTP status of the response
+        status -> Integer,
+        /// when the request was made (UTC)
+        created_at -> Timestamp,
+        /// category of the action, e.g. hosts for host.create
+        category -> Text,
+        /// severity of the category when the request was recorded (debug, info, notice or warning)
+        severity -> Text,
+    }
+}
+
+diesel::table! {
+    /// Activity categories changed through the settings API, the others use their defaults
+    activity_category (category) {
+        /// name of the category
+        category -> Text,
+        /// whether requests of the category are recorded
+        enabled -> Bool,
+        /// severity the requests are recorded with
+        severity -> Text,
+    }
+}
+
+diesel::table! {
+    /// Confirmation tokens handed out by previews of destructive operations on protected hosts
+    host_confirmation (token) {
+        /// random token
+        token -> Text,
+        /// the protected host
+        host_id -> Integer,
+        /// what is confirmed, delete or deploy
+        action -> Text,
+        /// login whose authorized_keys are deployed
+        login -> Nullable<Text>,
+        /// the previewed authorized_keys file
+        authorized_keys -> Nullable<Text>,
+        /// when the preview was shown (UTC)
+        created_at -> Timestamp,
+    }
+}
+
+diesel::joinable!(host_confirmation -> host (host_id));
+
+diesel::table! {
+    /// Ad-hoc change freezes, deployments are rejected while one is active
+    freeze_window (id) {
+        /// unique id
+        id -> Integer,
+        /// when the freeze begins (UTC)
+        starts_at -> Timestamp,
+        /// when the freeze ends (UTC)
+        ends_at -> Timestamp,
+        /// shown when a deployment is rejected
+        reason -> Text,
+        /// who added the freeze
+        created_by -> Text,
+        /// when the freeze was added (UTC)
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    /// Authorizations granted by attributes: users of a department get a login on hosts with a tag
+    authorization_rule (id) {
+        /// unique id
+        id -> Integer,
+        /// users with this department
+        department -> Text,
+        /// hosts with this tag
+        tag -> Text,
+        /// username on the host
+        login -> Text,
+        /// ssh key options
+        options -> Nullable<Text>,
+        /// who added the rule
+        created_by -> Text,
+        /// when the rule was added (UTC)
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    /// Periodic reviews of who may log in where
+    recertification_campaign (id) {
+        /// unique id
+        id -> Integer,
+        /// shown to the reviewers
+        name -> Text,
+        /// authorizations on hosts with this tag are reviewed, all hosts if null
+        tag -> Nullable<Text>,
+        /// when unreviewed authorizations are flagged or revoked (UTC)
+        deadline -> Timestamp,
+        /// revoke unreviewed authorizations at the deadline instead of flagging them
+        auto_revoke -> Bool,
+        /// who started the campaign
+        created_by -> Text,
+        /// when the campaign was started (UTC)
+        created_at -> Timestamp,
+        /// when every authorization was reviewed or the deadline passed (UTC)
+        closed_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    /// One authorization to review in a campaign
+    recertification_item (id) {
+        /// unique id
+        id -> Integer,
+        /// the campaign
+        campaign_id -> Integer,
+        /// the reviewed authorization, which may have been deleted since
+        authorization_id -> Integer,
+        /// name of the host
+        host -> Text,
+        /// the authorized user
+        username -> Text,
+        /// username on the host
+        login -> Text,
+        /// ssh key options
+        options -> Nullable<Text>,
+        /// approved, revoked, auto_revoked or overdue, null while pending
+        decision -> Nullable<Text>,
+        /// who decided
+        reviewer -> Nullable<Text>,
+        /// when it was decided (UTC)
+        reviewed_at -> Nullable<Timestamp>,
+        /// why it was decided
+        comment -> Nullable<Text>,
+    }
+}
+
+diesel::joinable!(recertification_item -> recertification_campaign (campaign_id));
+
+diesel::table! {
+    /// Lines of authorized_keys files the diff doesn't report, e.g. keys a cloud provider injects
+    diff_ignore_rule (id) {
+        /// unique id
+        id -> Integer,
+        /// the host, every host if null
+        host_id -> Nullable<Integer>,
+        /// username on the host, every login if null
+        login -> Nullable<Text>,
+        /// regular expression, searched for in the line
+        pattern -> Text,
+        /// who added the rule
+        created_by -> Text,
+        /// when the rule was added (UTC)
+        created_at -> Timestamp,
+    }
+}
+
+diesel::joinable!(diff_ignore_rule -> host (host_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    host,
+    user,
+    authorization,
+    user_key,
+    schedule,
+    authorization_history,
+    key_history,
+    pending_host,
+    security_event,
+    event_outbox,
+    activity,
+    host_confirmation,
+    freeze_window,
+    authorization_rule,
+    recertification_campaign,
+    recertification_item,
+    activity_category,
+    diff_ignore_rule,
+);
@@ -1,80 +1,152 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
 
+use async_trait::async_trait;
 use diesel::r2d2::{ConnectionManager, PooledConnection};
+use futures::{stream, StreamExt};
+use log::{debug, warn};
 use time::OffsetDateTime;
 use tokio::sync::RwLock;
+use tokio::time::Instant;
 
 use crate::{
+    db::diff_ignore_rule::DiffIgnoreRules,
+    limits::{KeyLimits, KeyfileUsage},
     models::{Host, PublicUserKey},
-    ConnectionPool, DbConnection,
+    timing, ConnectionPool, DbConnection,
 };
 
 use super::{
-    sshclient::SshClientError, AuthorizedKeyEntry, AuthorizedKeys, Cache, CacheValue, DiffItem,
-    HostDiff, HostName, Login, SshClient,
+    sshclient::SshClientError, AuthorizedKeys, Cache, CacheInfo, CacheValue, ConnectionCheck,
+    ConnectionDetails, DeployOutput, DiffItem, HostCache, HostDiff, HostName, Login, LoginState,
+    Operation, QueuedWrite, SshClient,
 };
 
 #[derive(Debug)]
 pub struct CachingSshClient {
     conn: ConnectionPool,
-    ssh_client: SshClient,
-    cache: RwLock<Cache>,
+    ssh_client: Arc<dyn SshClient>,
+    cache: Arc<RwLock<Cache>>,
+    /// Entries older than this are served stale and refreshed in the background
+    ttl: Option<Duration>,
+    /// Limits the diff warns about
+    key_limits: KeyLimits,
+    /// Hosts with a background refresh in flight
+    refreshing: Arc<Mutex<HashSet<HostName>>>,
+}
+
+/// Fetch the authorized keys of a host, bypassing the cache
+#[tracing::instrument(name = "host.fetch", skip(conn, ssh_client))]
+async fn fetch_host_data(
+    conn: &ConnectionPool,
+    ssh_client: &dyn SshClient,
+    host_name: &str,
+) -> Result<AuthorizedKeys, SshClientError> {
+    let conn = conn.get().unwrap();
+
+    match Host::get_from_name(conn, host_name.to_owned()).await? {
+        Some(host) => Ok(ssh_client.get_authorized_keys(host).await),
+        None => Err(SshClientError::NoSuchHost),
+    }
 }
 
 impl CachingSshClient {
-    pub fn new(conn: ConnectionPool, ssh_client: SshClient) -> Self {
+    pub fn new(
+        conn: ConnectionPool,
+        ssh_client: Arc<dyn SshClient>,
+        ttl: Option<Duration>,
+        key_limits: KeyLimits,
+    ) -> Self {
         Self {
             conn,
             ssh_client,
-            cache: RwLock::new(HashMap::new()),
+            cache: Arc::new(RwLock::new(HashMap::new())),
+            ttl,
+            key_limits,
+            refreshing: Arc::new(Mutex::new(HashSet::new())),
         }
     }
 
-    /// Removes a cache entry entirely. This should only be used when the underlying host no longer exists.
-    pub async fn remove(&self, host_name: &str) {
-        let mut lock = self.cache.write().await;
-        let _ = lock.remove(host_name);
+    fn is_stale(&self, cached_at: OffsetDateTime) -> bool {
+        self.ttl
+            .is_some_and(|ttl| OffsetDateTime::now_utc() - cached_at > ttl)
     }
 
-    async fn get_current_host_data(
-        &self,
-        host_name: &str,
-    ) -> Result<AuthorizedKeys, SshClientError> {
-        let conn = self.conn.get().unwrap();
-
-        match Host::get_from_name(conn, host_name.to_owned()).await? {
-            Some(host) => Ok(self.ssh_client.clone().get_authorized_keys(host).await),
-            None => Err(SshClientError::NoSuchHost),
+    /// Refresh an entry in the background, unless a refresh for this host is already running
+    fn revalidate(&self, host_name: &str) {
+        if !self
+            .refreshing
+            .lock()
+            .expect("Refresh lock poisoned")
+            .insert(host_name.to_owned())
+        {
+            return;
         }
+        debug!("Refreshing stale cache entry for {host_name}");
+
+        let conn = self.conn.clone();
+        let ssh_client = Arc::clone(&self.ssh_client);
+        let cache = Arc::clone(&self.cache);
+        let refreshing = Arc::clone(&self.refreshing);
+        let host_name = host_name.to_owned();
+
+        tokio::spawn(async move {
+            match fetch_host_data(&conn, ssh_client.as_ref(), &host_name).await {
+                Ok(data) => {
+                    cache
+                        .write()
+                        .await
+                        .insert(host_name.clone(), (OffsetDateTime::now_utc(), data));
+                }
+                Err(e) => warn!("Failed to refresh cache entry for {host_name}: {e}"),
+            }
+            refreshing
+                .lock()
+                .expect("Refresh lock poisoned")
+                .remove(&host_name);
+        });
     }
 
     async fn get_entry(
         &self,
         host_name: &String,
         force_update: bool,
-    ) -> Result<CacheValue, SshClientError> {
+    ) -> Result<(CacheInfo, AuthorizedKeys), SshClientError> {
         if !force_update {
-            let cache = self.cache.read().await;
-            if let Some(cached) = cache.get(host_name) {
-                return Ok(cached.clone());
+            let cached: Option<CacheValue> = self.cache.read().await.get(host_name).cloned();
+            if let Some((cached_at, data)) = cached {
+                let stale = self.is_stale(cached_at);
+                if stale {
+                    self.revalidate(host_name);
+                }
+                return Ok((CacheInfo { cached_at, stale }, data));
             }
         }
 
-        let data = self.get_current_host_data(host_name).await?;
-        let time = OffsetDateTime::now_utc();
+        let data = fetch_host_data(&self.conn, self.ssh_client.as_ref(), host_name).await?;
+        let cached_at = OffsetDateTime::now_utc();
 
         let mut lock = self.cache.write().await;
-        lock.insert(host_name.clone(), (time, data));
-        Ok(lock.get(host_name).expect("We just inserted this").clone())
+        lock.insert(host_name.clone(), (cached_at, data.clone()));
+        Ok((
+            CacheInfo {
+                cached_at,
+                stale: false,
+            },
+            data,
+        ))
     }
 
     fn calculate_diff(
         &self,
         mut conn: PooledConnection<ConnectionManager<DbConnection>>,
-        host_entries: Vec<(Login, bool, Vec<AuthorizedKeyEntry>)>,
+        host_entries: Vec<LoginState>,
         host: &Host,
     ) -> Result<Vec<(Login, Vec<DiffItem>)>, SshClientError> {
         let db_authorized_entries = host.get_authorized_keys(&mut conn)?;
+        let db_principals = host.get_authorized_principals(&mut conn)?;
+        let ignore_rules = DiffIgnoreRules::for_host(&mut conn, host)?;
 
         let mut conn = self.conn.get().unwrap();
         let all_user_keys = PublicUserKey::get_all_keys_with_username(&mut conn)?;
@@ -84,7 +156,7 @@ impl CachingSshClient {
         let mut diff_items = Vec::new();
         let mut used_indecies = Vec::new();
 
-        for (login, has_pragma, host_entries) in host_entries {
+        for (login, has_pragma, host_entries, host_principals) in host_entries {
             let mut this_user_diff = Vec::new();
             if !has_pragma {
                 this_user_diff.push(DiffItem::PragmaMissing);
@@ -94,7 +166,9 @@ impl CachingSshClient {
                 let host_entry = match host_entry {
                     Ok(k) => k,
                     Err((error, line)) => {
-                        this_user_diff.push(DiffItem::FaultyKey(error, line));
+                        if !ignore_rules.ignores(&login, &line) {
+                            this_user_diff.push(DiffItem::FaultyKey(error, line));
+                        }
                         continue 'entries;
                     }
                 };
@@ -111,11 +185,25 @@ impl CachingSshClient {
                             this_user_diff.push(DiffItem::DuplicateKey(host_entry));
                         } else {
                             used_indecies.push(i);
+                            if !host.accepts_key_type(&db_entry.key.key_type) {
+                                this_user_diff.push(DiffItem::IncompatibleKey(
+                                    host_entry,
+                                    db_entry.username.clone(),
+                                ));
+                            }
                         }
                         continue 'entries;
                     }
                 }
 
+                // Unmanaged keys the host keeps on purpose
+                if host.tolerates(&host_entry) {
+                    continue 'entries;
+                }
+                if ignore_rules.ignores(&login, &host_entry.to_line()) {
+                    continue 'entries;
+                }
+
                 for (username, key) in &all_user_keys {
                     if host_entry.base64.eq(&key.key_base64) {
                         this_user_diff
@@ -129,25 +217,86 @@ impl CachingSshClient {
 
             for (i, unused_entry) in db_authorized_entries.iter().enumerate() {
                 if !used_indecies.contains(&i) && unused_entry.login.eq(&login) {
-                    this_user_diff.push(DiffItem::KeyMissing(
-                        unused_entry.clone().into(),
-                        unused_entry.username.clone(),
-                    ));
+                    let key = unused_entry.clone().into();
+                    let username = unused_entry.username.clone();
+                    this_user_diff.push(if host.accepts_key_type(&unused_entry.key.key_type) {
+                        DiffItem::KeyMissing(key, username)
+                    } else {
+                        DiffItem::IncompatibleKey(key, username)
+                    });
+                }
+            }
+
+            // Only hosts with a principals file report them
+            if let Some(host_principals) = host_principals {
+                let login_principals: Vec<_> = db_principals
+                    .iter()
+                    .filter(|(principal_login, _, _)| principal_login.eq(&login))
+                    .collect();
+                for principal in &host_principals {
+                    if !login_principals.iter().any(|(_, p, _)| p.eq(principal)) {
+                        this_user_diff.push(DiffItem::UnknownPrincipal(principal.clone()));
+                    }
                 }
+                for (_, principal, username) in login_principals {
+                    if !host_principals.contains(principal) {
+                        this_user_diff.push(DiffItem::PrincipalMissing(
+                            principal.clone(),
+                            username.clone(),
+                        ));
+                    }
+                }
+            }
+            if self.key_limits.enabled() {
+                let keyfile = host.generate_authorized_keys_file(
+                    self.ssh_client.as_ref(),
+                    &mut conn,
+                    &login,
+                )?;
+                this_user_diff.extend(
+                    self.key_limits
+                        .warnings(KeyfileUsage::of(&keyfile))
+                        .into_iter()
+                        .map(DiffItem::LimitReached),
+                );
             }
             diff_items.push((login, this_user_diff));
         }
         diff_items.retain(|(_, user_diff)| !user_diff.is_empty());
         Ok(diff_items)
     }
+}
 
-    /// Get the difference between the supposed and actual state of the authorized keys
-    pub async fn get_host_diff(&self, host: Host, force_update: bool) -> HostDiff {
+#[async_trait]
+impl HostCache for CachingSshClient {
+    fn key_limits(&self) -> &KeyLimits {
+        &self.key_limits
+    }
+
+    async fn remove(&self, host_name: &str) {
+        let mut lock = self.cache.write().await;
+        let _ = lock.remove(host_name);
+    }
+
+    async fn invalidate_all(&self) -> usize {
+        let mut lock = self.cache.write().await;
+        let count = lock.len();
+        lock.clear();
+        count
+    }
+
+    async fn get_host_diff(&self, host: Host, force_update: bool) -> HostDiff {
         let (inserted, cached_authorized_keys) =
             match self.get_entry(&host.name, force_update).await {
                 Ok(t) => t,
                 Err(e) => {
-                    return (OffsetDateTime::now_utc(), Err(e));
+                    return (
+                        CacheInfo {
+                            cached_at: OffsetDateTime::now_utc(),
+                            stale: false,
+                        },
+                        Err(e),
+                    );
                 }
             };
 
@@ -166,28 +315,275 @@ impl CachingSshClient {
         )
     }
 
-    /// Gets the current state of all known hosts, forcing an update
-    pub async fn get_current_state(&self) -> Result<Vec<(HostName, HostDiff)>, String> {
-        let hosts = Host::get_all_hosts(&mut self.conn.get().unwrap())?;
+    async fn get_host_diff_until(
+        &self,
+        host: Host,
+        force_update: bool,
+        deadline: Instant,
+    ) -> (Option<HostDiff>, bool) {
+        let cached: Option<CacheValue> = self.cache.read().await.get(&host.name).cloned();
+        if !force_update && cached.is_some() {
+            return (Some(self.get_host_diff(host, false).await), true);
+        }
 
-        let mut state = Vec::with_capacity(hosts.len());
+        let conn = self.conn.clone();
+        let ssh_client = Arc::clone(&self.ssh_client);
+        let cache = Arc::clone(&self.cache);
+        let host_name = host.name.clone();
+        let fetch = tokio::spawn(timing::scope(timing::current(), async move {
+            let data = fetch_host_data(&conn, ssh_client.as_ref(), &host_name).await?;
+            let cached_at = OffsetDateTime::now_utc();
+            cache
+                .write()
+                .await
+                .insert(host_name, (cached_at, data.clone()));
+            Ok::<_, SshClientError>((cached_at, data))
+        }));
 
-        for host in hosts.into_iter() {
-            let hostname = host.name.to_owned();
-            let res = self.get_host_diff(host, true).await;
-            state.push((hostname, res));
+        let now = || CacheInfo {
+            cached_at: OffsetDateTime::now_utc(),
+            stale: false,
+        };
+        let (info, data, complete) = match tokio::time::timeout_at(deadline, fetch).await {
+            Ok(Ok(Ok((cached_at, data)))) => (
+                CacheInfo {
+                    cached_at,
+                    stale: false,
+                },
+                data,
+                true,
+            ),
+            Ok(Ok(Err(e))) => return (Some((now(), Err(e))), true),
+            Ok(Err(e)) => {
+                return (
+                    Some((now(), Err(SshClientError::ExecutionError(e.to_string())))),
+                    true,
+                )
+            }
+            Err(_) => {
+                debug!(
+                    "{} didn't answer in time, using the cached state",
+                    host.name
+                );
+                let Some((cached_at, data)) = cached else {
+                    return (None, false);
+                };
+                let info = CacheInfo {
+                    cached_at,
+                    stale: self.is_stale(cached_at),
+                };
+                (info, data, false)
+            }
+        };
+        let diff =
+            data.and_then(|entries| self.calculate_diff(self.conn.get().unwrap(), entries, &host));
+        (Some((info, diff)), complete)
+    }
+
+    #[tracing::instrument(name = "fleet.refresh", skip(self))]
+    async fn get_current_state(
+        &self,
+        tag: Option<&str>,
+        max_concurrent: usize,
+    ) -> Result<Vec<(HostName, HostDiff)>, String> {
+        let mut hosts = Host::get_all_hosts(&mut self.conn.get().unwrap())?;
+        if let Some(tag) = tag {
+            hosts.retain(|host| host.has_tag(tag));
         }
 
-        Ok(state)
+        Ok(stream::iter(hosts)
+            .map(|host| async move {
+                let hostname = host.name.to_owned();
+                let res = self.get_host_diff(host, true).await;
+                (hostname, res)
+            })
+            .buffered(max_concurrent.max(1))
+            .collect()
+            .await)
+    }
+
+    async fn get_cached_state(&self) -> Result<Vec<(HostName, HostDiff)>, String> {
+        Ok(self
+            .get_cached_logins()
+            .await?
+            .into_iter()
+            .map(|(host, info, data)| {
+                let diff = data.and_then(|entries| {
+                    self.calculate_diff(self.conn.get().unwrap(), entries, &host)
+                });
+                (host.name, (info, diff))
+            })
+            .collect())
     }
 
-    pub async fn get_logins(
+    async fn get_cached(&self, host_name: &str) -> Option<(CacheInfo, AuthorizedKeys)> {
+        let (cached_at, data) = self.cache.read().await.get(host_name).cloned()?;
+        Some((
+            CacheInfo {
+                cached_at,
+                stale: self.is_stale(cached_at),
+            },
+            data,
+        ))
+    }
+
+    async fn get_cached_logins(&self) -> Result<Vec<(Host, CacheInfo, AuthorizedKeys)>, String> {
+        let hosts = Host::get_all_hosts(&mut self.conn.get().unwrap())?;
+        let cached: Vec<(Host, CacheValue)> = {
+            let cache = self.cache.read().await;
+            hosts
+                .into_iter()
+                .filter_map(|host| {
+                    let entry = cache.get(&host.name).cloned()?;
+                    Some((host, entry))
+                })
+                .collect()
+        };
+
+        Ok(cached
+            .into_iter()
+            .map(|(host, (cached_at, data))| {
+                let info = CacheInfo {
+                    cached_at,
+                    stale: self.is_stale(cached_at),
+                };
+                (host, info, data)
+            })
+            .collect())
+    }
+
+    async fn get_logins(
         &self,
         host: Host,
         force_update: bool,
-    ) -> Result<Vec<Login>, SshClientError> {
-        let logins = self.get_entry(&host.name, force_update).await?.1;
+    ) -> Result<(CacheInfo, Vec<Login>), SshClientError> {
+        let (info, logins) = self.get_entry(&host.name, force_update).await?;
+
+        logins.map(|logins| {
+            (
+                info,
+                logins.into_iter().map(|(login, _, _, _)| login).collect(),
+            )
+        })
+    }
+}
+
+/// Answers reads of all logins from the cache, everything else goes to the wrapped client
+#[async_trait]
+impl SshClient for CachingSshClient {
+    fn get_own_key_openssh(&self) -> String {
+        self.ssh_client.get_own_key_openssh()
+    }
+
+    fn get_own_key_b64(&self) -> String {
+        self.ssh_client.get_own_key_b64()
+    }
+
+    async fn get_hostkey(
+        &self,
+        target: ConnectionDetails,
+    ) -> Result<mpsc::Receiver<String>, SshClientError> {
+        self.ssh_client.get_hostkey(target).await
+    }
+
+    async fn get_hostkey_via(
+        &self,
+        host: Host,
+        target: ConnectionDetails,
+    ) -> Result<mpsc::Receiver<String>, SshClientError> {
+        self.ssh_client.get_hostkey_via(host, target).await
+    }
+
+    async fn try_authenticate(
+        &self,
+        address: ConnectionDetails,
+        hostkey: String,
+        user: String,
+    ) -> Result<(), SshClientError> {
+        self.ssh_client
+            .try_authenticate(address, hostkey, user)
+            .await
+    }
+
+    async fn try_authenticate_via(
+        &self,
+        host: Host,
+        address: ConnectionDetails,
+        hostkey: String,
+        user: String,
+    ) -> Result<(), SshClientError> {
+        self.ssh_client
+            .try_authenticate_via(host, address, hostkey, user)
+            .await
+    }
+
+    async fn get_authorized_keys(&self, host: Host) -> AuthorizedKeys {
+        self.get_entry(&host.name, false).await?.1
+    }
+
+    async fn get_authorized_keyfile(
+        &self,
+        host: Host,
+        login: &str,
+    ) -> Result<String, SshClientError> {
+        self.ssh_client.get_authorized_keyfile(host, login).await
+    }
+
+    async fn set_authorized_keys(
+        &self,
+        host_name: String,
+        login: String,
+        authorized_keys: String,
+    ) -> Result<DeployOutput, SshClientError> {
+        self.ssh_client
+            .set_authorized_keys(host_name, login, authorized_keys)
+            .await
+    }
+
+    async fn set_authorized_principals(
+        &self,
+        host: Host,
+        login: String,
+        principals: String,
+    ) -> Result<(), SshClientError> {
+        self.ssh_client
+            .set_authorized_principals(host, login, principals)
+            .await
+    }
+
+    async fn get_sshd_config(&self, host: Host) -> Result<String, SshClientError> {
+        self.ssh_client.get_sshd_config(host).await
+    }
+
+    async fn get_key_sources(&self, host: Host) -> Result<String, SshClientError> {
+        self.ssh_client.get_key_sources(host).await
+    }
+
+    async fn get_key_usage(&self, host: Host) -> Result<String, SshClientError> {
+        self.ssh_client.get_key_usage(host).await
+    }
+
+    async fn get_failed_logins(&self, host: Host, hours: u32) -> Result<String, SshClientError> {
+        self.ssh_client.get_failed_logins(host, hours).await
+    }
+
+    async fn set_sshd_config(&self, host: Host, content: String) -> Result<(), SshClientError> {
+        self.ssh_client.set_sshd_config(host, content).await
+    }
+
+    async fn test_connection(&self, host: Host) -> Vec<ConnectionCheck> {
+        self.ssh_client.test_connection(host).await
+    }
+
+    fn operations(&self, host_name: &str) -> Vec<Operation> {
+        self.ssh_client.operations(host_name)
+    }
+
+    fn queued_writes(&self) -> Vec<QueuedWrite> {
+        self.ssh_client.queued_writes()
+    }
 
-        logins.map(|logins| logins.into_iter().map(|(login, _, _)| login).collect())
+    async fn install_script_on_host(&self, host: i32) -> Result<(), SshClientError> {
+        self.ssh_client.install_script_on_host(host).await
     }
 }
@@ -0,0 +1,564 @@
+//! In-memory fake fleet used instead of real SSH connections when running in demo mode
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{mpsc, RwLock};
+
+use async_trait::async_trait;
+use log::info;
+use ssh_key::{private::Ed25519Keypair, rand_core::OsRng, Algorithm, HashAlg, PrivateKey};
+
+use crate::{
+    models::{Host, NewHost, NewPublicUserKey, NewUser, PublicUserKey, User},
+    DbConnection,
+};
+
+use super::{
+    sshclient::{keyfile_hash, parse_authorized_keyfile, parse_principals_file, PRAGMA},
+    AuthorizedKeys, ConnectionCheck, ConnectionDetails, ConnectionTest, DeployOutput, HostName, Login, SshClient, SshClientError, TransportKind,
+};
+
+/// SSH client answering from an in-memory fleet instead of connecting to real hosts
+#[derive(Debug)]
+pub struct DemoSshClient {
+    /// Base64 of the ssm key
+    own_key_b64: String,
+    /// The ssm key in OpenSSH format, as deployed on the fake hosts
+    own_key: String,
+    /// Contents of the authorized_keys files of each host
+    keyfiles: RwLock<HashMap<HostName, BTreeMap<Login, String>>>,
+    /// Contents of the authorized_principals files of each host
+    principals: RwLock<HashMap<HostName, BTreeMap<Login, String>>>,
+    /// sshd_config of the hosts that don't use the stock one
+    sshd_configs: RwLock<HashMap<HostName, String>>,
+}
+
+/// sshd_config as shipped by Debian
+const STOCK_SSHD_CONFIG: &str = "Include /etc/ssh/sshd_config.d/*.conf
+
+#PermitRootLogin prohibit-password
+#AuthorizedKeysFile	.ssh/authorized_keys .ssh/authorized_keys2
+KbdInteractiveAuthentication no
+UsePAM yes
+X11Forwarding yes
+PrintMotd no
+AcceptEnv LANG LC_*
+Subsystem	sftp	/usr/lib/openssh/sftp-server
+";
+
+/// Generates a new ed25519 key and returns its base64 and a full OpenSSH line with the comment
+fn random_public_key(comment: &str) -> (String, String) {
+    let key =
+        PrivateKey::random(&mut OsRng, Algorithm::Ed25519).expect("Failed to generate demo key");
+    let base64 = key
+        .public_key()
+        .to_openssh()
+        .expect("Failed to encode demo key")
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or_default()
+        .to_owned();
+    let line = format!("ssh-ed25519 {base64} {comment}");
+
+    (base64, line)
+}
+
+impl DemoSshClient {
+    pub fn new(key: &PrivateKey) -> Self {
+        let public_key = key.public_key();
+        let own_key_b64 = public_key
+            .to_openssh()
+            .expect("Failed to encode demo key")
+            .split_whitespace()
+            .nth(1)
+            .unwrap_or_default()
+            .to_owned();
+
+        Self {
+            own_key: format!("{} {own_key_b64} ssm", public_key.algorithm()),
+            own_key_b64,
+            keyfiles: RwLock::new(HashMap::new()),
+            principals: RwLock::new(HashMap::new()),
+            sshd_configs: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Fingerprint of the hostkey a fake host presents. The key is derived from the address,
+    /// so it stays the same across restarts
+    fn fingerprint(&self, address: &str) -> String {
+        let mut seed = [0u8; 32];
+        seed.copy_from_slice(&HashAlg::Sha256.digest(address.as_bytes()));
+
+        PrivateKey::from(Ed25519Keypair::from_seed(&seed))
+            .fingerprint(HashAlg::default())
+            .to_string()
+    }
+
+    fn authenticate(
+        &self,
+        target: &ConnectionDetails,
+        hostkey: &str,
+    ) -> Result<(), SshClientError> {
+        if self.fingerprint(&target.hostname).eq(hostkey) {
+            Ok(())
+        } else {
+            Err(SshClientError::UnknownKey)
+        }
+    }
+
+    /// Like [`Self::authenticate`], against every host key accepted for the host
+    fn authenticate_host(&self, host: &Host) -> Result<(), SshClientError> {
+        if host.key_fingerprint.is_none() {
+            return Err(SshClientError::NoHostkey);
+        }
+        if host.accepts_key_fingerprint(&self.fingerprint(&host.address)) {
+            Ok(())
+        } else {
+            Err(SshClientError::UnknownKey)
+        }
+    }
+
+    /// A keyfile only containing the ssm key, like after a fresh setup
+    fn initial_keyfile(&self) -> String {
+        format!("{PRAGMA}\n{}\n", self.own_key)
+    }
+
+    /// Fill an empty database with demo data and give the fake hosts some drift to show.
+    /// Hosts that already exist in the database get keyfiles matching their authorizations.
+    pub fn seed(&self, conn: &mut DbConnection) -> Result<(), String> {
+        let existing_hosts = Host::get_all_hosts(conn)?;
+
+        if !existing_hosts.is_empty() {
+            for host in existing_hosts {
+                let mut logins: Vec<String> = host
+                    .get_authorized_users(conn)?
+                    .into_iter()
+                    .map(|(_, _, login, _)| login)
+                    .collect();
+                logins.push(host.username.clone());
+                logins.sort();
+                logins.dedup();
+
+                let mut keyfiles = self.keyfiles.write().expect("Demo fleet lock poisoned");
+                let host_files = keyfiles.entry(host.name.clone()).or_default();
+                for login in logins {
+                    let content = host.render_demo_keyfile(conn, &self.own_key, &login)?;
+                    host_files.insert(login, content);
+                }
+            }
+            return Ok(());
+        }
+
+        info!("Seeding database with demo data");
+
+        let mut user_keys = HashMap::new();
+        for (username, comment) in [
+            ("alice", "alice@workstation"),
+            ("bob", "bob@laptop"),
+            ("carol", "carol@build-server"),
+        ] {
+            User::add_user(
+                conn,
+                NewUser {
+                    username: username.to_owned(),
+                },
+            )?;
+            let user = User::get_user(conn, username.to_owned())?;
+            let (base64, key_line) = random_public_key(comment);
+
+            PublicUserKey::add_key(
+                conn,
+                NewPublicUserKey::new(
+                    Algorithm::Ed25519,
+                    base64.clone(),
+                    Some(comment.to_owned()),
+                    user.id,
+                ),
+                "demo",
+            )?;
+            user_keys.insert(username, (user.id, key_line));
+        }
+
+        for (name, address, jump_via, tags) in [
+            ("web-01", "web-01.demo.internal", None, "prod,web"),
+            ("web-02", "web-02.demo.internal", None, "lab,web"),
+            ("db-01", "db-01.demo.internal", Some("web-01"), "db,prod"),
+        ] {
+            let jump_via = match jump_via {
+                Some(jump) => Host::get_from_name_sync(conn, jump.to_owned())?.map(|h| h.id),
+                None => None,
+            };
+            Host::add_host(
+                conn,
+                &NewHost {
+                    name: name.to_owned(),
+                    address: address.to_owned(),
+                    port: 22,
+                    username: String::from("root"),
+                    key_fingerprint: self.fingerprint(address),
+                    jump_via,
+                    transport: TransportKind::default().to_string(),
+                    tags: tags.to_owned(),
+                    proxy: None,
+                },
+            )?;
+        }
+
+        for (host_name, username, login) in [
+            ("web-01", "alice", "root"),
+            ("web-01", "bob", "deploy"),
+            ("web-02", "alice", "root"),
+            ("db-01", "alice", "root"),
+            ("db-01", "carol", "postgres"),
+        ] {
+            let host = Host::get_from_name_sync(conn, host_name.to_owned())?
+                .ok_or_else(|| format!("Demo host {host_name} is missing"))?;
+            Host::authorize_user(
+                conn,
+                host.id,
+                user_keys[username].0,
+                login.to_owned(),
+                None,
+                "demo",
+            )?;
+        }
+
+        let alice = &user_keys["alice"].1;
+        let bob = &user_keys["bob"].1;
+        let carol = &user_keys["carol"].1;
+        let (_, stranger) = random_public_key("contractor@unknown");
+        let own_key = &self.own_key;
+
+        let mut keyfiles = self.keyfiles.write().expect("Demo fleet lock poisoned");
+        for (host_name, login, content) in [
+            // In sync
+            ("web-01", "root", format!("{PRAGMA}\n{alice}\n{own_key}\n")),
+            // Not yet managed and an unknown key
+            (
+                "web-01",
+                "deploy",
+                format!("{bob}\n{stranger}\n"),
+            ),
+            // alice is missing and carol is not authorized
+            ("web-02", "root", format!("{PRAGMA}\n{carol}\n{own_key}\n")),
+            ("db-01", "root", format!("{PRAGMA}\n{alice}\n{own_key}\n")),
+            // Duplicate and broken entries
+            (
+                "db-01",
+                "postgres",
+                format!("{PRAGMA}\n{carol}\n{carol}\nssh-rsa AAAAthisisbroken old-key\n"),
+            ),
+        ] {
+            keyfiles
+                .entry(host_name.to_owned())
+                .or_default()
+                .insert(login.to_owned(), content);
+        }
+
+        // A lab host nobody hardened
+        let mut sshd_configs = self.sshd_configs.write().expect("Demo fleet lock poisoned");
+        sshd_configs.insert(
+            "web-02".to_owned(),
+            format!("PermitRootLogin yes\nPasswordAuthentication yes\n{STOCK_SSHD_CONFIG}"),
+        );
+        // Joined to a directory that hands out keys as well
+        sshd_configs.insert(
+            "db-01".to_owned(),
+            format!(
+                "AuthorizedKeysCommand /usr/bin/sss_ssh_authorizedkeys\n\
+                 AuthorizedKeysCommandUser nobody\n{STOCK_SSHD_CONFIG}"
+            ),
+        );
+
+        Ok(())
+    }
+}
+
+
+#[async_trait]
+impl SshClient for DemoSshClient {
+    fn get_own_key_openssh(&self) -> String {
+        self.own_key.clone()
+    }
+
+    fn get_own_key_b64(&self) -> String {
+        self.own_key_b64.clone()
+    }
+
+    async fn get_hostkey(
+        &self,
+        target: ConnectionDetails,
+    ) -> Result<mpsc::Receiver<String>, SshClientError> {
+        let (tx, rx) = mpsc::channel();
+        tx.send(self.fingerprint(&target.hostname)).map_err(|_| {
+            SshClientError::ExecutionError(String::from("Failed to send data over mpsc"))
+        })?;
+        Ok(rx)
+    }
+
+    async fn get_hostkey_via(
+        &self,
+        _host: Host,
+        target: ConnectionDetails,
+    ) -> Result<mpsc::Receiver<String>, SshClientError> {
+        self.get_hostkey(target).await
+    }
+
+    async fn try_authenticate(
+        &self,
+        address: ConnectionDetails,
+        hostkey: String,
+        _user: String,
+    ) -> Result<(), SshClientError> {
+        self.authenticate(&address, &hostkey)
+    }
+
+    async fn try_authenticate_via(
+        &self,
+        _host: Host,
+        address: ConnectionDetails,
+        hostkey: String,
+        _user: String,
+    ) -> Result<(), SshClientError> {
+        self.authenticate(&address, &hostkey)
+    }
+
+    async fn get_authorized_keys(&self, host: Host) -> AuthorizedKeys {
+        self.authenticate_host(&host)?;
+
+        let mut keyfiles = self.keyfiles.write().expect("Demo fleet lock poisoned");
+        let host_files = keyfiles.entry(host.name.clone()).or_default();
+        if host_files.is_empty() {
+            host_files.insert(host.username.clone(), self.initial_keyfile());
+        }
+
+        let principals = self.principals.read().expect("Demo fleet lock poisoned");
+        let host_principals = principals.get(&host.name);
+        Ok(host_files
+            .iter()
+            .map(|(login, keyfile)| {
+                let (has_pragma, keys) = parse_authorized_keyfile(keyfile);
+                let login_principals = host.principals_file.as_ref().map(|_| {
+                    host_principals
+                        .and_then(|files| files.get(login))
+                        .map(|file| parse_principals_file(file))
+                        .unwrap_or_default()
+                });
+                (login.clone(), has_pragma, keys, login_principals)
+            })
+            .collect())
+    }
+
+    async fn get_authorized_keyfile(
+        &self,
+        host: Host,
+        login: &str,
+    ) -> Result<String, SshClientError> {
+        self.keyfiles
+            .read()
+            .expect("Demo fleet lock poisoned")
+            .get(&host.name)
+            .and_then(|files| files.get(login))
+            .cloned()
+            .ok_or_else(|| {
+                SshClientError::ExecutionError(String::from(
+                    "Couldn't find authorized_keys for this user.",
+                ))
+            })
+    }
+
+    async fn set_authorized_keys(
+        &self,
+        host_name: String,
+        login: String,
+        authorized_keys: String,
+    ) -> Result<DeployOutput, SshClientError> {
+        self.keyfiles
+            .write()
+            .expect("Demo fleet lock poisoned")
+            .entry(host_name)
+            .or_default()
+            .insert(login, format!("{PRAGMA}\n{authorized_keys}"));
+        // There is nothing to run deploy hooks on
+        Ok(DeployOutput {
+            verified_hash: Some(keyfile_hash(&authorized_keys)),
+            ..DeployOutput::default()
+        })
+    }
+
+    async fn set_authorized_principals(
+        &self,
+        host: Host,
+        login: String,
+        principals: String,
+    ) -> Result<(), SshClientError> {
+        self.authenticate_host(&host)?;
+        if host.principals_file.is_none() {
+            return Err(SshClientError::ExecutionError(String::from(
+                "The host has no principals file",
+            )));
+        }
+        self.principals
+            .write()
+            .expect("Demo fleet lock poisoned")
+            .entry(host.name)
+            .or_default()
+            .insert(login, format!("{PRAGMA}\n{principals}"));
+        Ok(())
+    }
+
+    async fn get_sshd_config(&self, host: Host) -> Result<String, SshClientError> {
+        self.authenticate_host(&host)?;
+        Ok(self
+            .sshd_configs
+            .read()
+            .expect("Demo fleet lock poisoned")
+            .get(&host.name)
+            .cloned()
+            .unwrap_or_else(|| STOCK_SSHD_CONFIG.to_owned()))
+    }
+
+    async fn get_key_sources(&self, host: Host) -> Result<String, SshClientError> {
+        // There is no sshd to ask, like on hosts where SSM isn't root
+        self.get_sshd_config(host).await
+    }
+
+    async fn get_key_usage(&self, host: Host) -> Result<String, SshClientError> {
+        self.authenticate_host(&host)?;
+        let now = time::OffsetDateTime::now_utc().unix_timestamp();
+        let keyfiles = self.keyfiles.read().expect("Demo fleet lock poisoned");
+        let mut lines = Vec::new();
+        for (login, keyfile) in keyfiles.get(&host.name).into_iter().flatten() {
+            for key in parse_authorized_keyfile(keyfile).1.into_iter().flatten() {
+                let Some(fingerprint) = key.fingerprint() else {
+                    continue;
+                };
+                // SSM logs in all the time, everyone else some time in the last two years
+                let days_ago = if key.base64 == self.own_key_b64 {
+                    0
+                } else {
+                    let seed = format!("{}{fingerprint}", host.name);
+                    let digest = HashAlg::Sha256.digest(seed.as_bytes());
+                    i64::from(u16::from_be_bytes([digest[0], digest[1]]) % 730)
+                };
+                lines.push(format!(
+                    "{}.000000 {} sshd[{}]: Accepted publickey for {login} from 192.0.2.10 \
+                     port 50022 ssh2: {} {fingerprint}",
+                    now - days_ago * 86400,
+                    host.name,
+                    1000 + days_ago,
+                    key.algorithm.as_str(),
+                ));
+            }
+        }
+        Ok(lines.join("\n"))
+    }
+
+    async fn get_failed_logins(&self, host: Host, hours: u32) -> Result<String, SshClientError> {
+        self.authenticate_host(&host)?;
+        let now = time::OffsetDateTime::now_utc().unix_timestamp();
+        // Web servers face the internet and its scanners, the others see a mistyped login now and then
+        let attempts: &[(u32, &str, &str)] = if host.has_tag("web") {
+            &[
+                (412, "Failed password for root", "203.0.113.45"),
+                (37, "Invalid user admin", "203.0.113.45"),
+                (95, "Failed password for root", "198.51.100.23"),
+                (12, "Invalid user oracle", "198.51.100.23"),
+                (8, "Invalid user ubuntu", "192.0.2.200"),
+            ]
+        } else {
+            &[(2, "Failed publickey for deploy", "10.0.0.12")]
+        };
+        let lines: Vec<String> = attempts
+            .iter()
+            .enumerate()
+            .map(|(i, (count, attempt, source))| {
+                // Spread over the hours asked for
+                let minutes_ago =
+                    i64::from(hours) * 60 * (i as i64 + 1) / (attempts.len() as i64 + 1);
+                format!(
+                    "{count} {}.000000 {} sshd[{}]: {attempt} from {source} port 41022 ssh2",
+                    now - minutes_ago * 60,
+                    host.name,
+                    2000 + i,
+                )
+            })
+            .collect();
+        Ok(lines.join("\n"))
+    }
+
+    async fn set_sshd_config(&self, host: Host, content: String) -> Result<(), SshClientError> {
+        self.authenticate_host(&host)?;
+        // There is no sshd to check the file and reload
+        self.sshd_configs
+            .write()
+            .expect("Demo fleet lock poisoned")
+            .insert(host.name, content);
+        Ok(())
+    }
+
+    async fn test_connection(&self, host: Host) -> Vec<ConnectionCheck> {
+        let mut test = ConnectionTest::default();
+        // Every address is reachable in the demo, only the host key can be wrong
+        let _ = async {
+            test.check("dns", async { Ok(((), "Resolved to a demo host".to_owned())) })
+                .await?;
+            test.check("tcp", async { Ok(((), "Connected".to_owned())) })
+                .await?;
+            test.check("hostkey", async {
+                let offered = self.fingerprint(&host.address);
+                self.authenticate_host(&host)
+                    .map(|()| ((), format!("Host key matches {offered}")))
+                    .map_err(|_| {
+                        format!(
+                            "Host offered {offered}, but only '{}' is accepted",
+                            host.key_fingerprint_list().collect::<Vec<_>>().join(", ")
+                        )
+                    })
+            })
+            .await?;
+            test.check("authentication", async {
+                Ok(((), format!("Authenticated as {}", host.username)))
+            })
+            .await?;
+            test.check("transport", async {
+                Ok(((), format!("Demo host, using {} transport", host.transport)))
+            })
+            .await
+        }
+        .await;
+
+        test.finish(&["dns", "tcp", "hostkey", "authentication", "transport"])
+    }
+
+    async fn install_script_on_host(&self, _host: i32) -> Result<(), SshClientError> {
+        Ok(())
+    }
+}
+
+impl Host {
+    /// Generated keyfile as it would be deployed, including the pragma
+    fn render_demo_keyfile(
+        &self,
+        conn: &mut DbConnection,
+        own_key: &str,
+        login: &str,
+    ) -> Result<String, String> {
+        let keys: String = self
+            .get_authorized_keys(conn)?
+            .into_iter()
+            .filter(|entry| entry.login.eq(login))
+            .map(|entry| {
+                entry
+                    .options
+                    .map_or_else(String::new, |o| o + " ")
+                    + entry.key.to_openssh().as_str()
+                    + "\n"
+            })
+            .collect();
+
+        Ok(if self.username.eq(login) {
+            format!("{PRAGMA}\n{keys}{own_key}\n")
+        } else {
+            format!("{PRAGMA}\n{keys}")
+        })
+    }
+}
@@ -0,0 +1,377 @@
+//! Public keys in the formats users paste: OpenSSH lines, RFC4716 as exported by
+//! PuTTY and `ssh-keygen -e`, PuTTY .ppk files and PEM as exported by OpenSSL
+use std::str::FromStr;
+
+use rsa::pkcs1::{DecodeRsaPublicKey, EncodeRsaPublicKey};
+use rsa::pkcs8::{DecodePublicKey, EncodePublicKey, LineEnding};
+use serde::Serialize;
+use ssh_encoding::base64::{Base64, Encoding};
+use ssh_key::authorized_keys::Entry;
+use ssh_key::public::{EcdsaPublicKey, KeyData};
+use ssh_key::{Algorithm, EcdsaCurve, HashAlg, PublicKey};
+
+use super::sshclient::authorized_key;
+use super::AuthorizedKey;
+
+const RFC4716_BEGIN: &str = "---- BEGIN SSH2 PUBLIC KEY ----";
+const RFC4716_END: &str = "---- END SSH2 PUBLIC KEY ----";
+/// Longest line of the RFC4716 format, without the line break
+const RFC4716_LINE: usize = 72;
+/// First line of a .ppk file of PuTTY, followed by the format version
+const PPK_BEGIN: &str = "PuTTY-User-Key-File-";
+/// PKCS#1, only RSA keys
+const PEM_RSA_BEGIN: &str = "-----BEGIN RSA PUBLIC KEY-----";
+/// X.509 SubjectPublicKeyInfo as in PKCS#8
+const PEM_BEGIN: &str = "-----BEGIN PUBLIC KEY-----";
+
+/// Parses a pasted public key, either an OpenSSH line like in authorized_keys, the RFC4716
+/// format, a PuTTY .ppk file or an RSA key as PEM. Options of an authorized_keys line are kept
+/// in `options`, they belong to authorizations and aren't stored with the key.
+pub fn parse_public_key(input: &str) -> Result<AuthorizedKey, String> {
+    let input = input.trim();
+    let line = if input.starts_with(RFC4716_BEGIN) {
+        rfc4716_to_openssh(input)?
+    } else if input.starts_with(PPK_BEGIN) {
+        ppk_to_openssh(input)?
+    } else if input.starts_with(PEM_RSA_BEGIN) || input.starts_with(PEM_BEGIN) {
+        pem_to_openssh(input)?
+    } else if input.lines().count() > 1 {
+        return Err("Expected a single public key".to_owned());
+    } else {
+        input.to_owned()
+    };
+    let key = Entry::from_str(&line)
+        .map(|key| authorized_key(&key))
+        .map_err(|e| format!("Invalid public key: {e}"))?;
+
+    match key.algorithm {
+        Algorithm::Dsa | Algorithm::Other(_) => {
+            Err(format!("Keys of type {} are not supported", key.algorithm))
+        }
+        _ => Ok(key),
+    }
+}
+
+/// The OpenSSH line of a key in the RFC4716 format, with the Comment header as comment
+fn rfc4716_to_openssh(input: &str) -> Result<String, String> {
+    // Continuation lines keep their leading whitespace, it is part of the header
+    let mut lines = input.lines().map(str::trim_end);
+    lines.next();
+    let mut headers = Vec::new();
+    let mut body = String::new();
+    let mut ended = false;
+    while let Some(line) = lines.next() {
+        let line = line.trim_start();
+        if line.eq(RFC4716_END) {
+            ended = true;
+            break;
+        }
+        if line.contains(':') {
+            // A backslash at the end continues the header on the next line
+            let mut header = line.to_owned();
+            while header.ends_with('\\') {
+                header.pop();
+                header.push_str(lines.next().unwrap_or_default());
+            }
+            headers.push(header);
+        } else {
+            body.push_str(line);
+        }
+    }
+    if !ended || lines.any(|line| !line.trim_start().is_empty()) {
+        return Err(format!("Expected a single key ending with '{RFC4716_END}'"));
+    }
+
+    let bytes = Base64::decode_vec(&body).map_err(|e| format!("Invalid public key: {e}"))?;
+    let key = PublicKey::from_bytes(&bytes).map_err(|e| format!("Invalid public key: {e}"))?;
+    let comment = headers.iter().find_map(|header| {
+        let (tag, value) = header.split_once(':')?;
+        tag.trim()
+            .eq_ignore_ascii_case("Comment")
+            .then(|| value.trim().trim_matches('"').to_owned())
+    });
+
+    Ok(format!(
+        "{} {body} {}",
+        key.algorithm(),
+        comment.unwrap_or_default()
+    ))
+}
+
+/// The OpenSSH line of the public key in a .ppk file, with its comment. The private key
+/// that follows it is never read.
+fn ppk_to_openssh(input: &str) -> Result<String, String> {
+    let mut lines = input.lines().map(str::trim);
+    let mut comment = None;
+    while let Some(line) = lines.next() {
+        let Some((header, value)) = line.split_once(':') else {
+            break;
+        };
+        match header {
+            "Comment" => comment = Some(value.trim()),
+            "Public-Lines" => {
+                let count = value
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| "Invalid PuTTY key file: Public-Lines is not a number")?;
+                let body = lines.by_ref().take(count).collect::<String>();
+                let bytes =
+                    Base64::decode_vec(&body).map_err(|e| format!("Invalid public key: {e}"))?;
+                let key = PublicKey::from_bytes(&bytes)
+                    .map_err(|e| format!("Invalid public key: {e}"))?;
+                return Ok(format!(
+                    "{} {body} {}",
+                    key.algorithm(),
+                    comment.unwrap_or_default()
+                ));
+            }
+            _ => {}
+        }
+    }
+    Err("Invalid PuTTY key file: no public key found".to_owned())
+}
+
+/// The OpenSSH line of an RSA key in PKCS#1 or SubjectPublicKeyInfo PEM
+fn pem_to_openssh(input: &str) -> Result<String, String> {
+    let rsa = if input.starts_with(PEM_RSA_BEGIN) {
+        rsa::RsaPublicKey::from_pkcs1_pem(input).map_err(|e| e.to_string())
+    } else {
+        rsa::RsaPublicKey::from_public_key_pem(input).map_err(|e| e.to_string())
+    }
+    .map_err(|e| format!("Invalid public key, only RSA keys are read from PEM: {e}"))?;
+    let key_data = ssh_key::public::RsaPublicKey::try_from(&rsa)
+        .map(KeyData::Rsa)
+        .map_err(|e| format!("Invalid public key: {e}"))?;
+    PublicKey::new(key_data, "")
+        .to_openssh()
+        .map_err(|e| format!("Invalid public key: {e}"))
+}
+
+/// A public key in all formats it can be written in
+#[derive(Serialize)]
+pub struct KeyFormats {
+    pub algorithm: String,
+    /// Size of the modulus or curve, missing for unknown algorithms
+    pub bits: Option<usize>,
+    /// The key lives on a FIDO2 security key
+    pub security_key: bool,
+    pub comment: Option<String>,
+    pub fingerprint_sha256: String,
+    pub fingerprint_sha512: String,
+    pub openssh: String,
+    pub rfc4716: String,
+    /// PKCS#1 like `ssh-keygen -e -m PEM`, only for RSA keys
+    pub pem: Option<String>,
+    /// SubjectPublicKeyInfo like `ssh-keygen -e -m PKCS8`, only for RSA keys
+    pub pkcs8: Option<String>,
+}
+
+impl KeyFormats {
+    pub fn new(key: &AuthorizedKey) -> Result<Self, String> {
+        let line = format!("{} {}", key.algorithm, key.base64);
+        let mut public_key =
+            PublicKey::from_openssh(&line).map_err(|e| format!("Invalid public key: {e}"))?;
+        if let Some(comment) = &key.comment {
+            public_key.set_comment(comment);
+        }
+        let openssh = public_key
+            .to_openssh()
+            .map_err(|e| format!("Invalid public key: {e}"))?;
+        let rsa = match public_key.key_data() {
+            KeyData::Rsa(key) => rsa::RsaPublicKey::try_from(key).ok(),
+            _ => None,
+        };
+
+        Ok(Self {
+            algorithm: key.algorithm.to_string(),
+            bits: bits(public_key.key_data()),
+            security_key: key.is_security_key(),
+            comment: key.comment.clone(),
+            fingerprint_sha256: public_key.fingerprint(HashAlg::Sha256).to_string(),
+            fingerprint_sha512: public_key.fingerprint(HashAlg::Sha512).to_string(),
+            openssh,
+            rfc4716: to_rfc4716(&key.base64, key.comment.as_deref()),
+            pem: rsa
+                .as_ref()
+                .and_then(|rsa| rsa.to_pkcs1_pem(LineEnding::LF).ok()),
+            pkcs8: rsa
+                .as_ref()
+                .and_then(|rsa| rsa.to_public_key_pem(LineEnding::LF).ok()),
+        })
+    }
+}
+
+/// Key size as `ssh-keygen -l` prints it
+fn bits(key_data: &KeyData) -> Option<usize> {
+    let mpint_bits = |mpint: &ssh_key::Mpint| {
+        mpint.as_positive_bytes().map(|bytes| match bytes.first() {
+            Some(first) => bytes.len() * 8 - first.leading_zeros() as usize,
+            None => 0,
+        })
+    };
+    let curve_bits = |key: &EcdsaPublicKey| match key.curve() {
+        EcdsaCurve::NistP256 => 256,
+        EcdsaCurve::NistP384 => 384,
+        EcdsaCurve::NistP521 => 521,
+    };
+    match key_data {
+        KeyData::Rsa(key) => mpint_bits(&key.n),
+        KeyData::Dsa(key) => mpint_bits(&key.p),
+        KeyData::Ecdsa(key) => Some(curve_bits(key)),
+        KeyData::Ed25519(_) | KeyData::SkEd25519(_) | KeyData::SkEcdsaSha2NistP256(_) => Some(256),
+        _ => None,
+    }
+}
+
+/// The key in the RFC4716 format, with the comment as quoted Comment header
+fn to_rfc4716(base64: &str, comment: Option<&str>) -> String {
+    let mut out = String::from(RFC4716_BEGIN);
+    out.push('\n');
+    if let Some(comment) = comment {
+        let header = format!("Comment: \"{comment}\"");
+        let chars: Vec<char> = header.chars().collect();
+        let mut lines = chars.chunks(RFC4716_LINE - 1).peekable();
+        while let Some(line) = lines.next() {
+            out.extend(line);
+            if lines.peek().is_some() {
+                out.push('\\');
+            }
+            out.push('\n');
+        }
+    }
+    // The body is ASCII
+    for line in base64.as_bytes().chunks(70) {
+        out.push_str(std::str::from_utf8(line).unwrap_or_default());
+        out.push('\n');
+    }
+    out.push_str(RFC4716_END);
+    out.push('\n');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ED25519: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIBo7QHOYAqVpIylrSckWHh1+/bSZeHe+0/qiPVyG+N4+";
+    const RSA: &str = "AAAAB3NzaC1yc2EAAAADAQABAAABAQC1mKmVHEZ5dcQnfLYie5wKNgfV+SoIPb3eVY99GsiBLMIji9RCdcQa4rZLt05+5Q2p6oq9UhuNvrIkw1O/BhKdUUcMMsIMXYr+FOdp6k59zGaBBg3/dnVAkQ6gBKX75K9RKTAVSYyBuDHot6QDMTdNDG5ERwZyqTAEFzJ5TVKUKIDVe1LcZCZn7PQOHs077t5aXE/dqyU6jTqsN/S6pjnMpHt8+smvwfDtcKu/iVIqM/B2q3Az6pd+w6mnwFPQT2Xo1YMq+Bbtg+McKYS86u+Nm5H1L2XPgxRW1LeRrramWTfzonJMWbrD68MZUtyqVW/aHoQNndZfvYq2Q434v9iD";
+
+    #[test]
+    fn rfc4716_with_continued_comment() {
+        let input = format!(
+            "---- BEGIN SSH2 PUBLIC KEY ----\n\
+             Subject: alice\n\
+             Comment: \"256-bit ED25519, converted by \\\n\
+             alice@laptop from OpenSSH\"\n\
+             {}\n\
+             {}\n\
+             ---- END SSH2 PUBLIC KEY ----\n",
+            &ED25519[..40],
+            &ED25519[40..]
+        );
+
+        let key = parse_public_key(&input).unwrap();
+        assert_eq!(key.algorithm, Algorithm::Ed25519);
+        assert_eq!(key.base64, ED25519);
+        assert_eq!(
+            key.comment.as_deref(),
+            Some("256-bit ED25519, converted by alice@laptop from OpenSSH")
+        );
+    }
+
+    #[test]
+    fn rfc4716_needs_a_single_key() {
+        let key = format!("{RFC4716_BEGIN}\n{ED25519}\n{RFC4716_END}");
+        assert!(parse_public_key(&format!("{RFC4716_BEGIN}\n{ED25519}\n")).is_err());
+        assert!(parse_public_key(&format!("{key}\n{key}")).is_err());
+    }
+
+    #[test]
+    fn rfc4716_round_trip() {
+        let comment =
+            "a comment long enough to be continued on the next line of the Comment header";
+        let exported = to_rfc4716(RSA, Some(comment));
+        assert!(exported.lines().all(|line| line.len() <= RFC4716_LINE));
+
+        let key = parse_public_key(&exported).unwrap();
+        assert_eq!(key.base64, RSA);
+        assert_eq!(key.comment.as_deref(), Some(comment));
+    }
+
+    #[test]
+    fn pem_pkcs1_and_spki() {
+        let pkcs1 = "-----BEGIN RSA PUBLIC KEY-----
+MIIBCgKCAQEAtZiplRxGeXXEJ3y2InucCjYH1fkqCD293lWPfRrIgSzCI4vUQnXE
+GuK2S7dOfuUNqeqKvVIbjb6yJMNTvwYSnVFHDDLCDF2K/hTnaepOfcxmgQYN/3Z1
+QJEOoASl++SvUSkwFUmMgbgx6LekAzE3TQxuREcGcqkwBBcyeU1SlCiA1XtS3GQm
+Z+z0Dh7NO+7eWlxP3aslOo06rDf0uqY5zKR7fPrJr8Hw7XCrv4lSKjPwdqtwM+qX
+fsOpp8BT0E9l6NWDKvgW7YPjHCmEvOrvjZuR9S9lz4MUVtS3ka62plk386JyTFm6
+w+vDGVLcqlVv2h6EDZ3WX72KtkON+L/YgwIDAQAB
+-----END RSA PUBLIC KEY-----
+";
+        let spki = "-----BEGIN PUBLIC KEY-----
+MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAtZiplRxGeXXEJ3y2Inuc
+CjYH1fkqCD293lWPfRrIgSzCI4vUQnXEGuK2S7dOfuUNqeqKvVIbjb6yJMNTvwYS
+nVFHDDLCDF2K/hTnaepOfcxmgQYN/3Z1QJEOoASl++SvUSkwFUmMgbgx6LekAzE3
+TQxuREcGcqkwBBcyeU1SlCiA1XtS3GQmZ+z0Dh7NO+7eWlxP3aslOo06rDf0uqY5
+zKR7fPrJr8Hw7XCrv4lSKjPwdqtwM+qXfsOpp8BT0E9l6NWDKvgW7YPjHCmEvOrv
+jZuR9S9lz4MUVtS3ka62plk386JyTFm6w+vDGVLcqlVv2h6EDZ3WX72KtkON+L/Y
+gwIDAQAB
+-----END PUBLIC KEY-----
+";
+
+        for pem in [pkcs1, spki] {
+            let key = parse_public_key(pem).unwrap();
+            assert_eq!(key.algorithm, Algorithm::Rsa { hash: None });
+            assert_eq!(key.base64, RSA);
+        }
+        let formats = KeyFormats::new(&parse_public_key(pkcs1).unwrap()).unwrap();
+        assert_eq!(formats.bits, Some(2048));
+        assert_eq!(formats.pem.as_deref(), Some(pkcs1));
+        assert_eq!(formats.pkcs8.as_deref(), Some(spki));
+    }
+
+    #[test]
+    fn pem_only_reads_rsa() {
+        let pem = "-----BEGIN PUBLIC KEY-----
+MCowBQYDK2VwAyEAGjtAc5gCpWkjKWtJyRYeHX79tJl4d77T+qI9XIb43j4=
+-----END PUBLIC KEY-----";
+        let error = parse_public_key(pem).unwrap_err();
+        assert!(error.contains("only RSA keys"), "{error}");
+    }
+
+    #[test]
+    fn ppk_public_key_and_comment() {
+        let ppk = "PuTTY-User-Key-File-3: ssh-rsa
+Encryption: aes256-cbc
+Comment: rsa-key-20261018
+Public-Lines: 6
+AAAAB3NzaC1yc2EAAAADAQABAAABAQC1mKmVHEZ5dcQnfLYie5wKNgfV+SoIPb3e
+VY99GsiBLMIji9RCdcQa4rZLt05+5Q2p6oq9UhuNvrIkw1O/BhKdUUcMMsIMXYr+
+FOdp6k59zGaBBg3/dnVAkQ6gBKX75K9RKTAVSYyBuDHot6QDMTdNDG5ERwZyqTAE
+FzJ5TVKUKIDVe1LcZCZn7PQOHs077t5aXE/dqyU6jTqsN/S6pjnMpHt8+smvwfDt
+cKu/iVIqM/B2q3Az6pd+w6mnwFPQT2Xo1YMq+Bbtg+McKYS86u+Nm5H1L2XPgxRW
+1LeRrramWTfzonJMWbrD68MZUtyqVW/aHoQNndZfvYq2Q434v9iD
+Private-Lines: 1
+not base64, never read
+Private-MAC: 0123456789abcdef
+";
+
+        let key = parse_public_key(ppk).unwrap();
+        assert_eq!(key.algorithm, Algorithm::Rsa { hash: None });
+        assert_eq!(key.base64, RSA);
+        assert_eq!(key.comment.as_deref(), Some("rsa-key-20261018"));
+    }
+
+    #[test]
+    fn ppk_without_public_key() {
+        let missing = "PuTTY-User-Key-File-2: ssh-ed25519\nEncryption: none\nComment: test\n";
+        let invalid = "PuTTY-User-Key-File-2: ssh-ed25519\nPublic-Lines: two\n";
+        assert!(parse_public_key(missing)
+            .unwrap_err()
+            .contains("no public key"));
+        assert!(parse_public_key(invalid)
+            .unwrap_err()
+            .contains("not a number"));
+    }
+}
@@ -0,0 +1,70 @@
+//! Reading host keys from OpenSSH known_hosts files
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use ssh_key::known_hosts::{Entry, HostPatterns};
+use ssh_key::HashAlg;
+
+/// Host keys from a known_hosts file. Only plain and hashed hostnames are matched,
+/// wildcard patterns, negations, revoked keys and certificate authorities are ignored.
+#[derive(Debug, Default)]
+pub struct KnownHosts {
+    entries: Vec<(HostPatterns, String)>,
+    /// Line numbers that couldn't be parsed
+    pub invalid_lines: Vec<usize>,
+}
+
+impl KnownHosts {
+    pub fn parse(input: &str) -> Self {
+        let mut known_hosts = Self::default();
+        for (index, line) in input.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.parse::<Entry>() {
+                Ok(entry) if entry.marker().is_none() => known_hosts.entries.push((
+                    entry.host_patterns().clone(),
+                    entry
+                        .public_key()
+                        .fingerprint(HashAlg::default())
+                        .to_string(),
+                )),
+                Ok(_) => {}
+                Err(_) => known_hosts.invalid_lines.push(index + 1),
+            }
+        }
+        known_hosts
+    }
+
+    /// Fingerprints of the keys listed for an address, in the order of the file
+    pub fn fingerprints(&self, address: &str, port: u16) -> Vec<String> {
+        let name = if port == 22 {
+            address.to_lowercase()
+        } else {
+            format!("[{}]:{port}", address.to_lowercase())
+        };
+
+        let mut fingerprints: Vec<String> = Vec::new();
+        for (patterns, fingerprint) in &self.entries {
+            if matches(patterns, &name) && !fingerprints.contains(fingerprint) {
+                fingerprints.push(fingerprint.clone());
+            }
+        }
+        fingerprints
+    }
+}
+
+fn matches(patterns: &HostPatterns, name: &str) -> bool {
+    match patterns {
+        HostPatterns::Patterns(patterns) => patterns
+            .iter()
+            .any(|pattern| pattern.to_lowercase().eq(name)),
+        HostPatterns::HashedName { salt, hash } => {
+            let Ok(mut mac) = Hmac::<Sha1>::new_from_slice(salt) else {
+                return false;
+            };
+            mac.update(name.as_bytes());
+            mac.verify_slice(hash).is_ok()
+        }
+    }
+}
@@ -1,12 +1,282 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 use ssh_key::{authorized_keys::ConfigOpts, Algorithm};
 use std::collections::HashMap;
-use time::OffsetDateTime;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::time::Instant;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime, UtcOffset};
+
+use crate::{
+    limits::{KeyLimits, LimitWarning},
+    models::Host,
+    ConnectionPool,
+};
 
 mod caching_client;
+#[cfg(feature = "demo")]
+pub mod demo;
+mod key_format;
+mod known_hosts;
+mod operation_log;
+mod plugin;
+mod proxy;
 mod sshclient;
+mod transport;
+mod write_lock;
 
 pub use caching_client::CachingSshClient;
-pub use sshclient::{SshClient, SshClientError};
+pub use key_format::{parse_public_key, KeyFormats};
+pub use known_hosts::KnownHosts;
+pub use operation_log::Operation;
+pub use plugin::ConnectionPlugins;
+pub use proxy::Proxy;
+pub use sshclient::{parse_authorized_keyfile, RealSshClient, SshClientError};
+pub use transport::TransportKind;
+pub use write_lock::QueuedWrite;
+
+use sshclient::PRAGMA;
+
+/// Operations SSM performs on remote hosts
+#[async_trait]
+pub trait SshClient: Send + Sync + std::fmt::Debug {
+    /// The public key SSM authenticates with, in OpenSSH format
+    fn get_own_key_openssh(&self) -> String;
+
+    /// Base64 of the public key SSM authenticates with
+    fn get_own_key_b64(&self) -> String;
+
+    /// Tries to connect to a host and returns hostkeys to validate
+    async fn get_hostkey(
+        &self,
+        target: ConnectionDetails,
+    ) -> Result<mpsc::Receiver<String>, SshClientError>;
+
+    /// Tries to connect to a host via a jumphost and returns hostkeys to validate
+    async fn get_hostkey_via(
+        &self,
+        host: Host,
+        target: ConnectionDetails,
+    ) -> Result<mpsc::Receiver<String>, SshClientError>;
+
+    /// Checks if we can authenticate on a host with the given hostkey
+    async fn try_authenticate(
+        &self,
+        address: ConnectionDetails,
+        hostkey: String,
+        user: String,
+    ) -> Result<(), SshClientError>;
+
+    /// Checks if we can authenticate on a host via a jumphost with the given hostkey
+    async fn try_authenticate_via(
+        &self,
+        host: Host,
+        address: ConnectionDetails,
+        hostkey: String,
+        user: String,
+    ) -> Result<(), SshClientError>;
+
+    /// Reads the authorized_keys files of all logins on a host
+    async fn get_authorized_keys(&self, host: Host) -> AuthorizedKeys;
+
+    /// Reads the raw authorized_keys file of a single login
+    async fn get_authorized_keyfile(
+        &self,
+        host: Host,
+        login: &str,
+    ) -> Result<String, SshClientError>;
+
+    /// Replaces the authorized_keys file of a login, running the deploy hooks of the host around it
+    async fn set_authorized_keys(
+        &self,
+        host_name: String,
+        login: String,
+        authorized_keys: String,
+    ) -> Result<DeployOutput, SshClientError>;
+
+    /// Replaces the authorized_principals file of a login on a host with a principals file
+    async fn set_authorized_principals(
+        &self,
+        host: Host,
+        login: String,
+        principals: String,
+    ) -> Result<(), SshClientError>;
+
+    /// Reads the sshd_config of a host
+    async fn get_sshd_config(&self, host: Host) -> Result<String, SshClientError>;
+
+    /// Reads the AuthorizedKeysCommand and AuthorizedKeysFile sshd uses on a host, as `sshd -T`
+    /// prints them or from sshd_config if sshd can't tell
+    async fn get_key_sources(&self, host: Host) -> Result<String, SshClientError>;
+
+    /// Reads the logins sshd accepted from the journal, the auth logs and lastlog of a host
+    async fn get_key_usage(&self, host: Host) -> Result<String, SshClientError>;
+
+    /// Reads the logins sshd refused in the last hours, counted per login, source and day
+    async fn get_failed_logins(&self, host: Host, hours: u32) -> Result<String, SshClientError>;
+
+    /// Replaces the sshd_config of a host once sshd accepts it, and reloads sshd
+    async fn set_sshd_config(&self, host: Host, content: String) -> Result<(), SshClientError>;
+
+    /// Connects to a host step by step, reporting which step failed and why
+    async fn test_connection(&self, host: Host) -> Vec<ConnectionCheck>;
+
+    /// Recent connections and commands on a host, newest first
+    fn operations(&self, _host_name: &str) -> Vec<Operation> {
+        Vec::new()
+    }
+
+    /// Writes to hosts that are running or wait for an earlier write to the same login
+    fn queued_writes(&self) -> Vec<QueuedWrite> {
+        Vec::new()
+    }
+
+    /// Prepares a freshly added host for management
+    async fn install_script_on_host(&self, host: i32) -> Result<(), SshClientError>;
+
+    /// Line based difference between the current keyfile of a login and a new one
+    async fn key_diff(
+        &self,
+        new: &str,
+        host: Host,
+        login: String,
+    ) -> Result<Vec<KeyDiffItem>, SshClientError> {
+        let curr_keys = self.get_authorized_keyfile(host, &login).await?;
+
+        let new_keys = new.to_owned();
+
+        let diff = similar::TextDiff::from_lines(&curr_keys, &new_keys);
+
+        Ok(diff
+            .iter_all_changes()
+            .filter_map(|e| match e.tag() {
+                similar::ChangeTag::Delete => Some(KeyDiffItem::Removed(e.value().to_owned())),
+                similar::ChangeTag::Insert => Some(KeyDiffItem::Added(e.value().to_owned())),
+                similar::ChangeTag::Equal => None,
+            })
+            .collect())
+    }
+}
+
+/// State of the hosts kept on top of an [`SshClient`], injected into the routes as a trait object
+/// like the client itself
+#[async_trait]
+pub trait HostCache: SshClient {
+    /// Limits the diff warns about
+    fn key_limits(&self) -> &KeyLimits;
+
+    /// Removes a cache entry entirely. This should only be used when the underlying host no longer exists.
+    async fn remove(&self, host_name: &str);
+
+    /// Removes the cache entries of all hosts and returns how many there were
+    async fn invalidate_all(&self) -> usize;
+
+    /// Get the difference between the supposed and actual state of the authorized keys
+    async fn get_host_diff(&self, host: Host, force_update: bool) -> HostDiff;
+
+    /// Like `get_host_diff`, but waits for the host only until `deadline`. A fetch that takes longer
+    /// goes on in the background and fills the cache, meanwhile the cached state is returned, `None`
+    /// without one. The flag tells whether the answer is complete.
+    async fn get_host_diff_until(
+        &self,
+        host: Host,
+        force_update: bool,
+        deadline: tokio::time::Instant,
+    ) -> (Option<HostDiff>, bool);
+
+    /// Gets the current state of all known hosts, or only those with the given tag, forcing an update.
+    /// At most `max_concurrent` hosts are contacted at the same time.
+    async fn get_current_state(
+        &self,
+        tag: Option<&str>,
+        max_concurrent: usize,
+    ) -> Result<Vec<(HostName, HostDiff)>, String>;
+
+    /// Gets the state of all hosts with cached data, without contacting any host.
+    /// Hosts that were never fetched are left out.
+    async fn get_cached_state(&self) -> Result<Vec<(HostName, HostDiff)>, String>;
+
+    /// Gets the cached logins of one host without contacting it, `None` if it was never fetched
+    async fn get_cached(&self, host_name: &str) -> Option<(CacheInfo, AuthorizedKeys)>;
+
+    /// Gets the cached logins of all hosts, without contacting any host.
+    /// Hosts that were never fetched are left out.
+    async fn get_cached_logins(&self) -> Result<Vec<(Host, CacheInfo, AuthorizedKeys)>, String>;
+
+    /// The logins of a host with their keys, from the cache unless `force_update` is set
+    async fn get_logins(
+        &self,
+        host: Host,
+        force_update: bool,
+    ) -> Result<(CacheInfo, Vec<Login>), SshClientError>;
+}
+
+/// Deploys the generated authorized_principals file of a login, if the host has a principals file.
+/// Returns whether a file was deployed.
+pub async fn deploy_principals(
+    ssh_client: &dyn SshClient,
+    pool: &ConnectionPool,
+    host: &Host,
+    login: &str,
+) -> Result<bool, SshClientError> {
+    if host.principals_file.is_none() {
+        return Ok(false);
+    }
+    let principals = host.get_authorized_principals_file_for(&mut pool.get().unwrap(), login)?;
+    ssh_client
+        .set_authorized_principals(host.clone(), login.to_owned(), principals)
+        .await
+        .map(|()| true)
+}
+
+/// Whether writing `next` takes the last key besides the one of SSM from a login that has one,
+/// which locks out whoever uses the login, like a service account
+pub fn locks_out(previous: &str, next: &str, own_key_b64: &str) -> bool {
+    let other_keys = |keyfile: &str| {
+        parse_authorized_keyfile(keyfile)
+            .1
+            .into_iter()
+            .flatten()
+            .filter(|key| key.base64 != own_key_b64)
+            .count()
+    };
+    other_keys(next) == 0 && other_keys(previous) > 0
+}
+
+/// The authorized_keys file as it is written to a host, with the pragma marking it as managed
+pub fn managed_keyfile(authorized_keys: &str) -> String {
+    format!("{PRAGMA}\n{authorized_keys}")
+}
+
+/// An authorized_keys file rebuilt from its parsed entries, other comments and blank lines aren't kept
+pub fn keyfile_from_entries(has_pragma: bool, entries: &[AuthorizedKeyEntry]) -> String {
+    let mut keyfile = String::new();
+    if has_pragma {
+        keyfile.push_str(PRAGMA);
+        keyfile.push('\n');
+    }
+    for entry in entries {
+        match entry {
+            Ok(key) => keyfile.push_str(&key.to_line()),
+            Err((_, line)) => keyfile.push_str(line),
+        }
+        keyfile.push('\n');
+    }
+    keyfile
+}
+
+/// Whether `next` leaves out the key of SSM for the login SSM connects to the host with, so SSM
+/// can't manage the host anymore
+pub fn locks_out_ssm(host: &Host, login: &str, next: &str, own_key_b64: &str) -> bool {
+    host.username == login
+        && !parse_authorized_keyfile(next)
+            .1
+            .into_iter()
+            .flatten()
+            .any(|key| key.base64 == own_key_b64)
+}
 
 #[derive(Debug, Clone, serde::Deserialize)]
 pub struct SshPublicKey {
@@ -28,6 +298,50 @@ pub struct AuthorizedKey {
     pub comment: Option<String>,
 }
 
+/// Quotes a value for a POSIX shell
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Whether keys of this algorithm live on a FIDO2 security key, `sk-ssh-ed25519@openssh.com`
+/// and `sk-ecdsa-sha2-nistp256@openssh.com`
+pub const fn is_security_key(algorithm: &Algorithm) -> bool {
+    matches!(
+        algorithm,
+        Algorithm::SkEd25519 | Algorithm::SkEcdsaSha2NistP256
+    )
+}
+
+impl AuthorizedKey {
+    pub const fn is_security_key(&self) -> bool {
+        is_security_key(&self.algorithm)
+    }
+
+    /// SHA256 fingerprint of the key, as `ssh-keygen -l` prints it
+    pub fn fingerprint(&self) -> Option<String> {
+        ssh_key::PublicKey::from_openssh(&format!("{} {}", self.algorithm.as_str(), self.base64))
+            .ok()
+            .map(|key| key.fingerprint(ssh_key::HashAlg::Sha256).to_string())
+    }
+
+    /// The entry as a line of an authorized_keys file
+    pub fn to_line(&self) -> String {
+        let mut line = String::new();
+        if !self.options.as_str().is_empty() {
+            line.push_str(self.options.as_str());
+            line.push(' ');
+        }
+        line.push_str(self.algorithm.as_str());
+        line.push(' ');
+        line.push_str(&self.base64);
+        if let Some(comment) = &self.comment {
+            line.push(' ');
+            line.push_str(comment);
+        }
+        line
+    }
+}
+
 impl std::fmt::Display for SshPublicKey {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self.comment.clone() {
@@ -44,35 +358,203 @@ impl std::fmt::Display for SshPublicKey {
 #[derive(Debug, Clone)]
 pub struct ConnectionDetails {
     pub hostname: String,
-    pub port: u32,
+    pub port: u16,
+    /// Overrides the configured address family
+    pub address_family: Option<AddressFamily>,
+    /// Open the connection through this proxy, which then also resolves the hostname
+    pub proxy: Option<Proxy>,
+    /// Tried in order if `hostname` can't be reached
+    pub fallback_addresses: Vec<String>,
 }
 
 impl ConnectionDetails {
-    pub const fn new(hostname: String, port: u32) -> Self {
-        Self { hostname, port }
-    }
-    pub fn new_from_signed(hostname: String, port: i32) -> Result<Self, SshClientError> {
-        Ok(Self {
+    pub const fn new(hostname: String, port: u16) -> Self {
+        Self {
             hostname,
-            port: port
-                .try_into()
-                .map_err(|_| SshClientError::PortCastFailed)?,
-        })
+            port,
+            address_family: None,
+            proxy: None,
+            fallback_addresses: Vec::new(),
+        }
+    }
+    /// Looks the hostname up again, so changed records are picked up on every connection attempt.
+    /// Addresses of the preferred family come first, `default` applies unless this connection overrides it.
+    pub async fn resolve(&self, default: AddressFamily) -> Result<Vec<SocketAddr>, SshClientError> {
+        let mut addresses: Vec<SocketAddr> =
+            tokio::net::lookup_host((self.hostname.as_str(), self.port))
+                .await
+                .map_err(|e| SshClientError::ResolveFailed(format!("{}: {e}", self.hostname)))?
+                .collect();
+        self.address_family.unwrap_or(default).sort(&mut addresses);
+        if addresses.is_empty() {
+            return Err(SshClientError::ResolveFailed(format!(
+                "{}: no addresses found",
+                self.hostname
+            )));
+        }
+        Ok(addresses)
+    }
+
+    /// This address followed by the fallback addresses, in the order they are tried
+    pub fn candidates(&self) -> Vec<Self> {
+        std::iter::once(&self.hostname)
+            .chain(&self.fallback_addresses)
+            .map(|hostname| Self {
+                hostname: hostname.clone(),
+                port: self.port,
+                address_family: self.address_family,
+                proxy: self.proxy.clone(),
+                fallback_addresses: Vec::new(),
+            })
+            .collect()
+    }
+}
+
+/// Which addresses are tried first when a hostname resolves to both IPv4 and IPv6
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressFamily {
+    /// Keep the order of the resolver (default)
+    #[default]
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    pub const ALL: [Self; 3] = [Self::Any, Self::Ipv4, Self::Ipv6];
+
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Any => "any",
+            Self::Ipv4 => "ipv4",
+            Self::Ipv6 => "ipv6",
+        }
+    }
+
+    /// Moves the preferred addresses to the front, otherwise keeping the order.
+    /// The others are still tried if none of the preferred addresses can be reached.
+    fn sort(self, addresses: &mut [SocketAddr]) {
+        match self {
+            Self::Any => {}
+            Self::Ipv4 => addresses.sort_by_key(|address| !address.is_ipv4()),
+            Self::Ipv6 => addresses.sort_by_key(|address| !address.is_ipv6()),
+        }
+    }
+}
+
+impl std::fmt::Display for AddressFamily {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
     }
-    pub fn into_addr(self) -> String {
-        format!("{}:{}", self.hostname, self.port)
+}
+
+impl FromStr for AddressFamily {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|family| family.as_str().eq(s))
+            .ok_or_else(|| format!("Unknown address family '{s}'"))
     }
 }
 
+/// What happens when a host offers a host key that isn't accepted for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HostKeyPolicy {
+    /// The connection fails with a log message (default)
+    #[default]
+    Log,
+    /// Additionally a security event is recorded, the `hostkey.mismatch` hooks run
+    /// and the host is marked until its host keys are changed
+    Strict,
+}
+
 #[derive(Debug, Clone)]
 pub enum KeyDiffItem {
     Added(String),
     Removed(String),
 }
 
+/// Output of the deploy hooks that ran while writing an authorized_keys file
+#[derive(Clone, Debug, Default)]
+pub struct DeployOutput {
+    pub pre_hook: Option<String>,
+    pub post_hook: Option<String>,
+    /// SHA256 of the file as read back after writing it
+    pub verified_hash: Option<String>,
+    /// Wrong owners, modes and SELinux contexts of the keyfile and `.ssh`, `fixed` ones were repaired
+    pub permissions: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Passed,
+    Failed,
+    /// Not attempted because an earlier step failed
+    Skipped,
+}
+
+/// Outcome of one step of a connection test
+#[derive(Clone, Debug, Serialize)]
+pub struct ConnectionCheck {
+    pub step: &'static str,
+    pub status: CheckStatus,
+    /// What was found, or why the step failed
+    pub detail: String,
+    pub duration_ms: u128,
+}
+
+/// Runs the steps of a connection test in order, until one fails
+#[derive(Default)]
+pub struct ConnectionTest {
+    checks: Vec<ConnectionCheck>,
+}
+
+impl ConnectionTest {
+    /// Runs a step and records its outcome. Returns `None` if it failed.
+    pub async fn check<T>(
+        &mut self,
+        step: &'static str,
+        run: impl Future<Output = Result<(T, String), String>>,
+    ) -> Option<T> {
+        let started = Instant::now();
+        let result = run.await;
+        let duration_ms = started.elapsed().as_millis();
+
+        let (status, detail, value) = match result {
+            Ok((value, detail)) => (CheckStatus::Passed, detail, Some(value)),
+            Err(error) => (CheckStatus::Failed, error, None),
+        };
+        self.checks.push(ConnectionCheck {
+            step,
+            status,
+            detail,
+            duration_ms,
+        });
+        value
+    }
+
+    /// Returns all checks, with the steps that didn't run marked as skipped
+    pub fn finish(mut self, steps: &[&'static str]) -> Vec<ConnectionCheck> {
+        for step in steps.iter().skip(self.checks.len()) {
+            self.checks.push(ConnectionCheck {
+                step,
+                status: CheckStatus::Skipped,
+                detail: String::new(),
+                duration_ms: 0,
+            });
+        }
+        self.checks
+    }
+}
+
 type Login = String;
 pub type HostDiff = (
-    OffsetDateTime,
+    CacheInfo,
     Result<Vec<(Login, Vec<DiffItem>)>, SshClientError>,
 );
 
@@ -90,8 +572,39 @@ pub enum DiffItem {
     FaultyKey(ErrorMsg, Line),
     /// The Pragma is missing, meaning this file is not yet managed
     PragmaMissing,
+    /// An authorized certificate principal is missing with the Username
+    PrincipalMissing(String, String),
+    /// A certificate principal that is not authorized is present
+    UnknownPrincipal(String),
+    /// An authorized key with the Username whose algorithm the host doesn't accept, it isn't deployed
+    IncompatibleKey(AuthorizedKey, String),
+    /// The generated file is close to or over a limit
+    LimitReached(LimitWarning),
 }
 type HostName = String;
-type AuthorizedKeys = Result<Vec<(Login, bool, Vec<AuthorizedKeyEntry>)>, SshClientError>;
+/// Principals in the authorized_principals file of a login, `None` if the host has no principals file
+type Principals = Option<Vec<String>>;
+/// A login with whether its keyfile has the pragma, its keys and principals
+type LoginState = (Login, bool, Vec<AuthorizedKeyEntry>, Principals);
+type AuthorizedKeys = Result<Vec<LoginState>, SshClientError>;
 type CacheValue = (OffsetDateTime, AuthorizedKeys);
+
+/// Age of a cached answer
+#[derive(Clone, Copy, Debug)]
+pub struct CacheInfo {
+    /// When the data was fetched from the host
+    pub cached_at: OffsetDateTime,
+    /// The data is older than the configured TTL and is being refreshed in the background
+    pub stale: bool,
+}
+
+impl CacheInfo {
+    /// `cached_at` as RFC 3339 in UTC, for the pages that show it
+    pub fn cached_at_rfc3339(&self) -> String {
+        self.cached_at
+            .to_offset(UtcOffset::UTC)
+            .format(&Rfc3339)
+            .unwrap_or_default()
+    }
+}
 type Cache = HashMap<HostName, CacheValue>;
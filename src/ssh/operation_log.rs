@@ -0,0 +1,132 @@
+//! Recent SSH operations per host, to see why something failed on a host without digging through the server logs
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use super::SshClientError;
+use crate::timing::{self, Phase};
+
+/// Longer command output is cut off
+const MAX_OUTPUT: usize = 4096;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Operation {
+    #[serde(with = "time::serde::rfc3339")]
+    pub started_at: OffsetDateTime,
+    /// Command that was run, or `connect` for connection attempts
+    pub command: String,
+    /// Not set if the command couldn't be run at all
+    pub exit_code: Option<u32>,
+    pub success: bool,
+    pub duration_ms: u128,
+    /// Output of the command or why it failed, truncated
+    pub output: String,
+}
+
+#[derive(Debug)]
+pub struct OperationLog {
+    /// How many operations are kept per host, 0 disables the log
+    capacity: usize,
+    hosts: Mutex<HashMap<String, VecDeque<Operation>>>,
+}
+
+impl OperationLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records the result of running a command
+    pub fn record(
+        &self,
+        host: &str,
+        command: &str,
+        started_at: OffsetDateTime,
+        duration: Duration,
+        result: Result<(u32, &str), &SshClientError>,
+    ) {
+        timing::record(Phase::Ssh, duration);
+        let (exit_code, success, output) = match result {
+            Ok((exit_code, output)) => (Some(exit_code), exit_code == 0, truncate(output)),
+            Err(error) => (None, false, truncate(&error.to_string())),
+        };
+        self.push(
+            host,
+            Operation {
+                started_at,
+                command: command.to_owned(),
+                exit_code,
+                success,
+                duration_ms: duration.as_millis(),
+                output,
+            },
+        );
+    }
+
+    /// Records a connection attempt and the address it connected to, unknown for hosts behind a jumphost
+    pub fn record_connect(
+        &self,
+        host: &str,
+        started_at: OffsetDateTime,
+        duration: Duration,
+        result: Result<Option<SocketAddr>, &SshClientError>,
+    ) {
+        timing::record(Phase::Ssh, duration);
+        self.push(
+            host,
+            Operation {
+                started_at,
+                command: "connect".to_owned(),
+                exit_code: None,
+                success: result.is_ok(),
+                duration_ms: duration.as_millis(),
+                output: match result {
+                    Ok(Some(peer)) => format!("Connected to {peer}"),
+                    Ok(None) => String::new(),
+                    Err(error) => truncate(&error.to_string()),
+                },
+            },
+        );
+    }
+
+    /// Adds an operation, dropping the oldest one of this host if the log is full
+    fn push(&self, host: &str, operation: Operation) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut hosts = self.hosts.lock().expect("operation log lock poisoned");
+        let operations = hosts.entry(host.to_owned()).or_default();
+        if operations.len() >= self.capacity {
+            operations.pop_front();
+        }
+        operations.push_back(operation);
+    }
+
+    /// Recorded operations of a host, newest first
+    pub fn get(&self, host: &str) -> Vec<Operation> {
+        self.hosts
+            .lock()
+            .expect("operation log lock poisoned")
+            .get(host)
+            .map(|operations| operations.iter().rev().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+fn truncate(output: &str) -> String {
+    if output.len() <= MAX_OUTPUT {
+        return output.to_owned();
+    }
+    let mut end = MAX_OUTPUT;
+    while !output.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}... (truncated)", &output[..end])
+}
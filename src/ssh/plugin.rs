@@ -0,0 +1,122 @@
+//! Connection plugins, commands like `tsh proxy ssh` or `aws ssm start-session` whose stdin and stdout
+//! carry the SSH connection, like a ProxyCommand of OpenSSH. They are defined in the configuration,
+//! hosts only refer to them by name, so editing a host can't run arbitrary commands.
+use std::collections::BTreeMap;
+use std::io;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{Context, Poll};
+
+use log::warn;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader, ReadBuf};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+
+use super::{shell_quote, SshClientError};
+use crate::models::Host;
+
+/// Commands of the connection plugins by name
+pub type ConnectionPlugins = BTreeMap<String, String>;
+
+/// Replaces `%h` with the address, `%p` with the port, `%r` with the login and `%n` with the name
+/// of the host, quoted for the shell. `%%` is a literal `%`.
+fn expand_command(command: &str, host: &Host) -> String {
+    let mut expanded = String::with_capacity(command.len());
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('h') => expanded.push_str(&shell_quote(&host.address)),
+            Some('p') => expanded.push_str(&host.port.to_string()),
+            Some('r') => expanded.push_str(&shell_quote(&host.username)),
+            Some('n') => expanded.push_str(&shell_quote(&host.name)),
+            Some('%') => expanded.push('%'),
+            Some(other) => {
+                expanded.push('%');
+                expanded.push(other);
+            }
+            None => expanded.push('%'),
+        }
+    }
+    expanded
+}
+
+/// The stdin and stdout of a running plugin, the plugin is killed when the stream is dropped
+pub(super) struct PluginStream {
+    stdout: ChildStdout,
+    stdin: ChildStdin,
+    _child: Child,
+}
+
+impl PluginStream {
+    /// Starts the plugin of the host, what it writes to stderr is logged
+    pub(super) fn spawn(
+        plugins: &ConnectionPlugins,
+        name: &str,
+        host: &Host,
+    ) -> Result<Self, SshClientError> {
+        let command = plugins
+            .get(name)
+            .ok_or_else(|| SshClientError::PluginFailed(format!("{name} isn't configured")))?;
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(expand_command(command, host))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| SshClientError::PluginFailed(format!("{name}: {e}")))?;
+
+        if let Some(stderr) = child.stderr.take() {
+            let (name, host_name) = (name.to_owned(), host.name.clone());
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    warn!("Connection plugin {name} for {host_name}: {line}");
+                }
+            });
+        }
+
+        match (child.stdout.take(), child.stdin.take()) {
+            (Some(stdout), Some(stdin)) => Ok(Self {
+                stdout,
+                stdin,
+                _child: child,
+            }),
+            _ => Err(SshClientError::PluginFailed(format!(
+                "{name}: stdin or stdout isn't available"
+            ))),
+        }
+    }
+}
+
+impl AsyncRead for PluginStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for PluginStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stdin).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdin).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdin).poll_shutdown(cx)
+    }
+}
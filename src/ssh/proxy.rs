@@ -0,0 +1,229 @@
+//! Opening connections through a SOCKS5 or HTTP proxy, for hosts that are reachable through a proxy but not via an SSH jumphost
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::{ConnectionDetails, SshClientError};
+
+/// Longest response header accepted from an HTTP proxy
+const MAX_HTTP_RESPONSE: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProxyKind {
+    Socks5,
+    /// HTTP proxy supporting the CONNECT method
+    Http,
+}
+
+/// A proxy written as `socks5://[user:password@]host:port` or `http://host:port`.
+/// The proxy resolves the hostname of the target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proxy {
+    kind: ProxyKind,
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+}
+
+impl Proxy {
+    /// Where the proxy itself is reached
+    pub fn connection(&self) -> ConnectionDetails {
+        ConnectionDetails::new(self.host.clone(), self.port)
+    }
+
+    /// Asks the proxy to connect to the target over an established connection to the proxy
+    pub async fn open(
+        &self,
+        stream: &mut TcpStream,
+        host: &str,
+        port: u16,
+    ) -> Result<(), SshClientError> {
+        match self.kind {
+            ProxyKind::Socks5 => self.socks5_connect(stream, host, port).await,
+            ProxyKind::Http => http_connect(stream, host, port).await,
+        }
+        .map_err(|e| SshClientError::ProxyFailed(format!("{self}: {e}")))
+    }
+
+    async fn socks5_connect(
+        &self,
+        stream: &mut TcpStream,
+        host: &str,
+        port: u16,
+    ) -> io::Result<()> {
+        let method = if self.credentials.is_some() { 2 } else { 0 };
+        stream.write_all(&[5, 1, method]).await?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != 5 {
+            return Err(io::Error::other("not a SOCKS5 proxy"));
+        }
+        if reply[1] != method {
+            return Err(io::Error::other("authentication method not accepted"));
+        }
+
+        if let Some((username, password)) = &self.credentials {
+            // Lengths are checked when parsing
+            let mut request = vec![1, username.len() as u8];
+            request.extend_from_slice(username.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            stream.write_all(&request).await?;
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0 {
+                return Err(io::Error::other("authentication failed"));
+            }
+        }
+
+        let mut request = vec![5, 1, 0];
+        match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(1);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                let length = u8::try_from(host.len())
+                    .map_err(|_| io::Error::other("hostname is too long"))?;
+                request.extend_from_slice(&[3, length]);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut head = [0u8; 4];
+        stream.read_exact(&mut head).await?;
+        if head[1] != 0 {
+            return Err(io::Error::other(socks5_error(head[1])));
+        }
+        // Skip the bound address and port
+        let length = match head[3] {
+            1 => 4,
+            4 => 16,
+            3 => {
+                let mut length = [0u8; 1];
+                stream.read_exact(&mut length).await?;
+                length[0].into()
+            }
+            _ => return Err(io::Error::other("invalid reply")),
+        };
+        let mut bound = vec![0u8; length + 2];
+        stream.read_exact(&mut bound).await?;
+
+        Ok(())
+    }
+}
+
+fn socks5_error(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+async fn http_connect(stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+    let authority = if host.contains(':') && !host.starts_with('[') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    };
+    let request =
+        format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\nUser-Agent: ssm\r\n\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read byte by byte, anything after the header already belongs to the SSH connection
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_HTTP_RESPONSE {
+            return Err(io::Error::other("response header too long"));
+        }
+        response.push(stream.read_u8().await?);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "CONNECT failed with '{status_line}'"
+        ))),
+    }
+}
+
+/// Shown without the password
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = match self.kind {
+            ProxyKind::Socks5 => "socks5",
+            ProxyKind::Http => "http",
+        };
+        match &self.credentials {
+            Some((username, _)) => write!(f, "{scheme}://{username}@{}:{}", self.host, self.port),
+            None => write!(f, "{scheme}://{}:{}", self.host, self.port),
+        }
+    }
+}
+
+impl FromStr for Proxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, rest) = if let Some(rest) = s.strip_prefix("socks5://") {
+            (ProxyKind::Socks5, rest)
+        } else if let Some(rest) = s.strip_prefix("http://") {
+            (ProxyKind::Http, rest)
+        } else {
+            return Err(format!(
+                "Unsupported proxy '{s}', use socks5://host:port or http://host:port"
+            ));
+        };
+
+        let (credentials, authority) = match rest.rsplit_once('@') {
+            Some((credentials, authority)) => {
+                if kind == ProxyKind::Http {
+                    return Err("Credentials are only supported for SOCKS5 proxies".to_owned());
+                }
+                let (username, password) = credentials.split_once(':').unwrap_or((credentials, ""));
+                if username.is_empty() || username.len() > 255 || password.len() > 255 {
+                    return Err("Proxy username and password must be 1 to 255 bytes".to_owned());
+                }
+                (Some((username.to_owned(), password.to_owned())), authority)
+            }
+            None => (None, rest),
+        };
+
+        let authority = authority.trim_end_matches('/');
+        let (host, port) = authority
+            .rsplit_once(':')
+            .ok_or_else(|| format!("Proxy '{authority}' is missing a port"))?;
+        let port = port
+            .parse()
+            .map_err(|_| format!("Invalid proxy port '{port}'"))?;
+        let host = host.trim_matches(['[', ']']);
+        if host.is_empty() {
+            return Err("Proxy host is missing".to_owned());
+        }
+
+        Ok(Self {
+            kind,
+            host: host.to_owned(),
+            port,
+            credentials,
+        })
+    }
+}
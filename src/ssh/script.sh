@@ -8,11 +8,16 @@ set -eu
 authorized_keys_location=".ssh/authorized_keys"
 externaly_managed_keyfile="${HOME}/.ssh/external_managed_keys"
 readonly_keyfile="${HOME}/.ssh/readonly_keys"
-version="Secure SSH Manager script v0.3-alpha"
+version="Secure SSH Manager script v0.7-alpha"
 keyfile_head="# Auto-generated by Secure SSH Manager. DO NOT EDIT!"
 
+keyfile_tmp=""
+
 cleanup() {
     rm -f "${TMP}/homedirs.$$"
+    if [ -n "${keyfile_tmp}" ]; then
+        rm -f "${keyfile_tmp}"
+    fi
 }
 trap cleanup EXIT INT TERM
 
@@ -26,8 +31,20 @@ Usage: $(basename "$0") COMMAND [OPTIONS]
 
 Commands:
   get_authorized_keyfile USER    Display authorized keys for specified user
-  set_authorized_keyfile USER    Set authorized keys for specified user (read from stdin)
+  set_authorized_keyfile USER [SIZE [SHA256]]
+                                 Set authorized keys for specified user (read from stdin),
+                                 only replacing the keyfile if it is SIZE bytes with SHA256
+  get_authorized_principals USER FILE
+                                 Display certificate principals for specified user
+  set_authorized_principals USER FILE
+                                 Set certificate principals for specified user (read from stdin)
   get_ssh_users                  List all users with SSH access
+  get_key_sources                Display AuthorizedKeysCommand and AuthorizedKeysFile of sshd
+  get_key_usage                  Display the last login of every user and key from the sshd logs
+  get_failed_logins HOURS        Display failed logins of the last hours from the sshd logs
+  check_keyfile_permissions USER [repair]
+                                 Display wrong owners, modes and SELinux contexts of ~/.ssh and
+                                 authorized_keys of a user, with repair fix them
   update                         Update this script (read from stdin)
   version                        Display version information
 EOF
@@ -63,6 +80,20 @@ get_authorized_keys_location() {
   echo "${home}/${authorized_keys_location}"
 }
 
+# Get the location of the authorized_principals file given a username and a
+# location like AuthorizedPrincipalsFile, relative to the home directory or with %h and %u
+get_authorized_principals_location() {
+  user="$1"
+  location="$2"
+  home=$(do_getent_passwd "${user}" | cut -d: -f6)
+  location=$(printf "%s" "${location}" | sed -e "s|%h|${home}|g" -e "s|%u|${user}|g")
+
+  case "${location}" in
+    /*) echo "${location}" ;;
+    *) echo "${home}/${location}" ;;
+  esac
+}
+
 # Check if the system has any conditions that make the keyfile externally managed or readonly
 check_keyfile_conditions() {
     conditions=""
@@ -80,6 +111,21 @@ check_keyfile_conditions() {
     echo "${conditions}"
 }
 
+# SHA256 of a file, fails if there is no tool for it
+sha256_of() {
+    if command -v sha256sum >/dev/null 2>&1; then
+        sha256sum < "$1" | cut -d' ' -f1
+    elif command -v shasum >/dev/null 2>&1; then
+        shasum -a 256 < "$1" | cut -d' ' -f1
+    elif command -v sha256 >/dev/null 2>&1; then
+        sha256 -q < "$1"
+    elif command -v openssl >/dev/null 2>&1; then
+        openssl dgst -sha256 -r < "$1" | cut -d' ' -f1
+    else
+        return 1
+    fi
+}
+
 # Output comments for get_authorized_keyfile command
 print_keyfile_comments() {
     conditions=$(check_keyfile_conditions)
@@ -117,6 +163,8 @@ handle_get_authorized_keyfile() {
 
 handle_set_authorized_keyfile() {
     user="$1"
+    size="${2:-}"
+    sha256="${3:-}"
     keyfile_location=$(get_authorized_keys_location "${user}")
 
     if is_keyfile_readonly; then
@@ -124,16 +172,79 @@ handle_set_authorized_keyfile() {
         exit 1
     fi
 
+    # Written next to the keyfile and renamed over it once complete, so a full disk
+    # leaves the previous keyfile in place instead of a truncated one
+    keyfile_tmp="${keyfile_location}.ssm.$$"
     if [ -e "${keyfile_location}" ]; then
         file_head=$(head -n1 < "${keyfile_location}")
 
         if [ "${file_head}" != "${keyfile_head}" ]; then
-            mv "${keyfile_location}" "${keyfile_location}.backup"
+            cp -p "${keyfile_location}" "${keyfile_location}.backup"
+        fi
+        # Keeps owner and mode of the keyfile
+        cp -p "${keyfile_location}" "${keyfile_tmp}"
+    fi
+
+    printf "%s\n" "${keyfile_head}" > "${keyfile_tmp}"
+    cat - >> "${keyfile_tmp}"
+    sync "${keyfile_tmp}" 2> /dev/null || sync
+
+    written=$(wc -c < "${keyfile_tmp}" | tr -d ' ')
+    if [ -n "${size}" ] && [ "${written}" != "${size}" ]; then
+        echo "Wrote ${written} of ${size} bytes, keeping the previous keyfile."
+        exit 1
+    fi
+    hash=""
+    if hash=$(sha256_of "${keyfile_tmp}"); then
+        if [ -n "${sha256}" ] && [ "${hash}" != "${sha256}" ]; then
+            echo "Wrote a keyfile with SHA256 ${hash} instead of ${sha256}, keeping the previous keyfile."
+            exit 1
         fi
     fi
 
-    printf "%s\n" "${keyfile_head}" > "${keyfile_location}"
-    cat - >> "${keyfile_location}"
+    mv -f "${keyfile_tmp}" "${keyfile_location}"
+    keyfile_tmp=""
+    if [ -n "${hash}" ]; then
+        echo "sha256 ${hash}"
+    fi
+    exit 0
+}
+
+handle_get_authorized_principals() {
+    principals_location=$(get_authorized_principals_location "$1" "$2")
+
+    # A missing file accepts no principals
+    if [ -e "${principals_location}" ]; then
+        cat "${principals_location}"
+    fi
+    exit 0
+}
+
+handle_set_authorized_principals() {
+    principals_location=$(get_authorized_principals_location "$1" "$2")
+
+    if is_keyfile_readonly; then
+        echo "Keyfile is readonly, aborting."
+        exit 1
+    fi
+
+    # Renamed over the file once complete, like the keyfile
+    keyfile_tmp="${principals_location}.ssm.$$"
+    if [ -e "${principals_location}" ]; then
+        file_head=$(head -n1 < "${principals_location}")
+
+        if [ "${file_head}" != "${keyfile_head}" ]; then
+            cp -p "${principals_location}" "${principals_location}.backup"
+        fi
+        cp -p "${principals_location}" "${keyfile_tmp}"
+    fi
+
+    printf "%s\n" "${keyfile_head}" > "${keyfile_tmp}"
+    cat - >> "${keyfile_tmp}"
+    sync "${keyfile_tmp}" 2> /dev/null || sync
+
+    mv -f "${keyfile_tmp}" "${principals_location}"
+    keyfile_tmp=""
     exit 0
 }
 
@@ -150,6 +261,135 @@ handle_get_ssh_users() {
     exit 0
 }
 
+# sshd -T prints the settings sshd uses, but only works as root. Otherwise read the
+# configuration files, included ones first as Include is usually at the top.
+handle_get_key_sources() {
+    pattern='^[[:space:]]*(authorizedkeyscommand|authorizedkeysfile|match)([[:space:]]|=)'
+    sshd=$(command -v sshd || echo /usr/sbin/sshd)
+    "${sshd}" -T 2>/dev/null | grep -iE "${pattern}" \
+        || cat /etc/ssh/sshd_config.d/*.conf /etc/ssh/sshd_config 2>/dev/null | grep -iE "${pattern}" \
+        || true
+    exit 0
+}
+
+# Keeps the last line per user and key fingerprint of its sorted input
+last_per_key='{
+    user = ""; key = ""
+    for (i = 1; i <= NF; i++) {
+        if (user == "" && $i == "for") user = $(i + 1)
+        if (key == "" && $i ~ /^(SHA256|MD5):/) key = $i
+    }
+    k = user " " key
+    if (!(k in last)) order[n++] = k
+    last[k] = $0
+}
+END { for (i = 0; i < n; i++) print last[order[i]] }'
+
+# The logins sshd accepted, from the journal and from the auth logs, oldest file first.
+# lastlog adds logins whose log lines were rotated away. Reading the logs needs root or
+# the adm or systemd-journal group.
+handle_get_key_usage() {
+    pattern='sshd(-session)?\[[0-9]+\]: Accepted [^ ]+ for '
+    if command -v journalctl >/dev/null 2>&1; then
+        journalctl -q --no-pager -o short-unix -t sshd -t sshd-session 2>/dev/null \
+            | grep -E "${pattern}" | awk "${last_per_key}" || true
+    fi
+    # shellcheck disable=SC2012
+    for log in $(ls -tr /var/log/auth.log* /var/log/secure* 2>/dev/null); do
+        case "${log}" in
+            *.gz) gzip -dc "${log}" 2>/dev/null ;;
+            *)    cat "${log}" 2>/dev/null ;;
+        esac
+    done | grep -E "${pattern}" | awk "${last_per_key}" || true
+    if command -v lastlog >/dev/null 2>&1; then
+        lastlog 2>/dev/null | tail -n +2 | grep -v 'Never logged in' | sed 's/^/lastlog /' || true
+    fi
+    exit 0
+}
+
+# Counts its input per user, source and day, printing the count before the last line of each
+count_per_source='/Failed [^ ]+ for invalid user / { next }
+{
+    found = 0; user = ""; from = ""
+    for (i = 2; i < NF; i++) {
+        if (!found && ($i == "for" || ($i == "user" && $(i - 1) == "Invalid"))) {
+            found = 1
+            if ($(i + 1) != "from") user = $(i + 1)
+        }
+        if (found && $i == "from") { from = $(i + 1); break }
+    }
+    if ($1 ~ /^[0-9]+(\.[0-9]+)?$/) day = int($1 / 86400)
+    else if ($1 ~ /T/) day = substr($1, 1, 10)
+    else day = $1 " " $2
+    k = user " " from " " day
+    if (!(k in count)) order[n++] = k
+    count[k]++
+    last[k] = $0
+}
+END { for (i = 0; i < n; i++) print count[order[i]], last[order[i]] }'
+
+# Failed password and key logins and logins as unknown users of the last HOURS hours of the
+# journal. Without any there, auth.log is read as far as the current file reaches, syslog daemons
+# often write the same lines to both.
+handle_get_failed_logins() {
+    hours="${1:-24}"
+    case "${hours}" in
+        ''|*[!0-9]*) printf "Invalid number of hours: %s\n" "${hours}" >&2; exit 1 ;;
+    esac
+    pattern='sshd(-session)?\[[0-9]+\]: (Failed [^ ]+ for|Invalid user) '
+    failed=""
+    if command -v journalctl >/dev/null 2>&1; then
+        failed=$(journalctl -q --no-pager -o short-unix --since "-${hours}h" -t sshd -t sshd-session 2>/dev/null \
+            | grep -E "${pattern}" | awk "${count_per_source}")
+    fi
+    if [ -n "${failed}" ]; then
+        printf "%s\n" "${failed}"
+        exit 0
+    fi
+    for log in /var/log/auth.log /var/log/secure; do
+        if [ -r "${log}" ]; then
+            grep -E "${pattern}" "${log}" | awk "${count_per_source}" || true
+        fi
+    done
+    exit 0
+}
+
+# sshd ignores keyfiles that others than their owner may change (StrictModes), and SELinux keeps
+# it from reading files with the wrong context. Prints one line per problem, "fixed" if repaired.
+handle_check_keyfile_permissions() {
+    user="$1"
+    repair="${2:-}"
+    home=$(do_getent_passwd "${user}" | cut -d: -f6)
+    for path in "${home}/.ssh" "$(get_authorized_keys_location "${user}")"; do
+        [ -e "${path}" ] || continue
+        owner=$(stat -c %U "${path}" 2>/dev/null || stat -f %Su "${path}")
+        if [ "${owner}" != "${user}" ]; then
+            if [ -n "${repair}" ] && chown "${user}" "${path}" 2>/dev/null; then
+                echo "fixed ${path}: owner was ${owner}, now ${user}"
+            else
+                echo "found ${path}: owner is ${owner}, not ${user}"
+            fi
+        fi
+        mode=$(stat -c %a "${path}" 2>/dev/null || stat -f %Lp "${path}")
+        if [ $((0${mode} & 022)) -ne 0 ]; then
+            if [ -n "${repair}" ] && chmod go-w "${path}" 2>/dev/null; then
+                echo "fixed ${path}: mode was ${mode}, group and others can't write anymore"
+            else
+                echo "found ${path}: mode ${mode} lets group or others write"
+            fi
+        fi
+    done
+    if command -v selinuxenabled >/dev/null 2>&1 && selinuxenabled \
+        && command -v restorecon >/dev/null 2>&1 && [ -e "${home}/.ssh" ]; then
+        if [ -n "${repair}" ]; then
+            restorecon -R -v "${home}/.ssh" 2>&1 | sed 's/^/fixed SELinux context: /' || true
+        else
+            restorecon -R -n -v "${home}/.ssh" 2>&1 | sed 's/^/found SELinux context: /' || true
+        fi
+    fi
+    exit 0
+}
+
 handle_update() {
     newfile="${0}.new"
     cat - > "${newfile}"
@@ -181,7 +421,13 @@ shift
 case "${command}" in
     get_authorized_keyfile)  handle_get_authorized_keyfile "$@" ;;
     set_authorized_keyfile)  handle_set_authorized_keyfile "$@" ;;
+    get_authorized_principals) handle_get_authorized_principals "$@" ;;
+    set_authorized_principals) handle_set_authorized_principals "$@" ;;
     get_ssh_users)           handle_get_ssh_users ;;
+    get_key_sources)         handle_get_key_sources ;;
+    get_key_usage)           handle_get_key_usage ;;
+    get_failed_logins)       handle_get_failed_logins "$@" ;;
+    check_keyfile_permissions) handle_check_keyfile_permissions "$@" ;;
     update)                  handle_update ;;
     version)                 handle_version ;;
     *)
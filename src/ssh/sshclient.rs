@@ -2,50 +2,83 @@ use actix_web::error::BlockingError;
 use async_trait::async_trait;
 use core::fmt;
 use futures::future::BoxFuture;
-use futures::AsyncWriteExt;
 use futures::FutureExt;
 use log::debug;
+use log::error;
 use log::info;
 use log::warn;
 use russh::keys::key::PrivateKeyWithHashAlg;
 use russh::keys::PublicKeyBase64;
-use ssh_encoding::Base64Writer;
-use ssh_encoding::Encode;
+use ssh_encoding::base64::{Base64, Encoding};
 use ssh_key::authorized_keys::Entry;
 use ssh_key::PublicKey;
-use std::io::Cursor;
+use std::net::SocketAddr;
 use std::ops::Deref;
 use std::str::FromStr;
 use std::sync::mpsc;
 use std::sync::Arc;
-use tokio::io::AsyncRead;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+use tokio::net::TcpStream;
+use tracing::Instrument;
 
-const PRAGMA: &str = "# Auto-generated by Secure SSH Manager. DO NOT EDIT!";
+pub(super) const PRAGMA: &str = "# Auto-generated by Secure SSH Manager. DO NOT EDIT!";
 
+use crate::hooks::{Event, EventHooks};
+use crate::models::SecurityEvent;
 use crate::SshConfig;
-use crate::{models::Host, ConnectionPool};
+use crate::{db::security_event, models::Host, ConnectionPool};
 
+use super::operation_log::{Operation, OperationLog};
+use super::plugin::PluginStream;
+use super::transport::{run_hook, sha256_hex, transport_for, RemoteHostTransport};
+use super::write_lock::{QueuedWrite, WriteLocks};
 use super::AuthorizedKey;
 use super::AuthorizedKeyEntry;
 use super::AuthorizedKeys;
+use super::ConnectionCheck;
 use super::ConnectionDetails;
-use super::KeyDiffItem;
+use super::ConnectionTest;
+use super::DeployOutput;
+use super::HostKeyPolicy;
+use super::SshClient;
+
+/// SHA256 of a keyfile in hex. The pragma, the read-only markers of the script and trailing
+/// whitespace are left out, as they differ between the file sent and the file read back.
+pub(super) fn keyfile_hash(keyfile: &str) -> String {
+    let content: String = keyfile
+        .lines()
+        .filter(|line| !line.eq(&PRAGMA) && !line.starts_with("# !"))
+        .map(|line| format!("{}\n", line.trim_end()))
+        .collect();
+    sha256_hex(content.trim_end().as_bytes())
+}
 
+/// SSH client connecting to real hosts with russh
 #[derive(Debug, Clone)]
-pub struct SshClient {
+pub struct RealSshClient {
     conn: ConnectionPool,
     key: Arc<PrivateKeyWithHashAlg>,
     config: Arc<SshConfig>,
     connection_config: Arc<russh::client::Config>,
+    operation_log: Arc<OperationLog>,
+    write_locks: Arc<WriteLocks>,
+    event_hooks: Arc<EventHooks>,
 }
 
 #[derive(Debug, Clone)]
 pub enum SshClientError {
     ExecutionError(String),
     NoSuchHost,
-    PortCastFailed,
     NoHostkey,
     Timeout,
+    /// The hostname couldn't be resolved
+    ResolveFailed(String),
+    /// The proxy didn't open a connection to the host
+    ProxyFailed(String),
+    /// The connection plugin of the host couldn't be started
+    PluginFailed(String),
 
     // Because russh::Error doesn't impl Clone we copy all Errors we care about
     // from russh, the rest gets converted to Strings
@@ -53,20 +86,53 @@ pub enum SshClientError {
     NotAuthenticated,
 
     SshError(String),
+
+    /// The pre deploy hook failed, the authorized_keys file was not touched
+    PreDeployHookFailed(String),
+    /// The authorized_keys file was written, but the post deploy hook failed
+    PostDeployHookFailed(String),
+    /// The authorized_keys file was written, but reading it back or logging in afterwards failed
+    VerificationFailed {
+        reason: String,
+        rolled_back: bool,
+    },
 }
 
 impl fmt::Display for SshClientError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::NoSuchHost => write!(f, "The host doesn't exist in the database."),
-            Self::PortCastFailed => write!(f, "Couldn't convert an i32 to u32."),
             Self::NoHostkey => write!(f, "No hostkey available for this host."),
             Self::Timeout => write!(f, "Connection to this host timed out."),
+            Self::ResolveFailed(t) => write!(f, "Couldn't resolve {t}"),
+            Self::ProxyFailed(t) => write!(f, "Proxy {t}"),
+            Self::PluginFailed(t) => write!(f, "Connection plugin {t}"),
             Self::UnknownKey => write!(f, "Host responded with an unknown hostkey."),
             Self::NotAuthenticated => write!(f, "Couldn't authenticate on the host."),
             Self::ExecutionError(t) | Self::SshError(t) => {
                 write!(f, "{t}")
             }
+            Self::PreDeployHookFailed(output) => write!(
+                f,
+                "Pre deploy hook failed, authorized_keys was not changed: {output}"
+            ),
+            Self::PostDeployHookFailed(output) => write!(
+                f,
+                "authorized_keys was applied, but the post deploy hook failed: {output}"
+            ),
+            Self::VerificationFailed {
+                reason,
+                rolled_back,
+            } => {
+                write!(
+                    f,
+                    "authorized_keys was applied, but verifying it failed: {reason}"
+                )?;
+                if *rolled_back {
+                    write!(f, ". The previous file was restored")?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -87,9 +153,47 @@ impl From<BlockingError> for SshClientError {
     }
 }
 
+/// An authenticated connection to a host, recording the commands run over it
+pub(super) struct SshHandle {
+    handle: russh::client::Handle<SshHandler>,
+    host: String,
+    log: Arc<OperationLog>,
+    /// Most bytes read from a command or file before giving up
+    max_output_size: usize,
+}
+
+impl SshHandle {
+    pub(super) const fn max_output_size(&self) -> usize {
+        self.max_output_size
+    }
+
+    /// Adds a command run on this host to the operation log
+    pub(super) fn record(
+        &self,
+        command: &str,
+        started_at: OffsetDateTime,
+        duration: Duration,
+        result: Result<(u32, &str), &SshClientError>,
+    ) {
+        self.log
+            .record(&self.host, command, started_at, duration, result);
+    }
+}
+
+impl Deref for SshHandle {
+    type Target = russh::client::Handle<SshHandler>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.handle
+    }
+}
+
 #[derive(Debug)]
-struct SshHandler {
-    hostkey_fingerprint: String,
+pub(super) struct SshHandler {
+    /// Fingerprints of all host keys accepted for the host
+    accepted_fingerprints: Vec<String>,
+    /// Fingerprint of the key the host offered, to explain a mismatch
+    offered_fingerprint: Arc<Mutex<Option<String>>>,
 }
 
 #[async_trait]
@@ -100,9 +204,15 @@ impl russh::client::Handler for SshHandler {
         &mut self,
         server_public_key: &PublicKey,
     ) -> Result<bool, Self::Error> {
-        let fingerprint = server_public_key.fingerprint(ssh_key::HashAlg::default());
+        let fingerprint = server_public_key
+            .fingerprint(ssh_key::HashAlg::default())
+            .to_string();
+        let matches = self.accepted_fingerprints.contains(&fingerprint);
+        if let Ok(mut offered) = self.offered_fingerprint.lock() {
+            *offered = Some(fingerprint);
+        }
 
-        Ok(fingerprint.to_string().eq(&self.hostkey_fingerprint))
+        Ok(matches)
     }
 }
 
@@ -141,30 +251,529 @@ impl russh::client::Handler for SshFirstConnectionHandler {
         })
     }
 }
-impl SshClient {
-    pub fn new(conn: ConnectionPool, key: PrivateKeyWithHashAlg, config: SshConfig) -> Self {
+impl RealSshClient {
+    pub fn new(
+        conn: ConnectionPool,
+        key: PrivateKeyWithHashAlg,
+        config: SshConfig,
+        event_hooks: Arc<EventHooks>,
+    ) -> Self {
         Self {
             conn,
             key: key.into(),
-            config: config.into(),
             connection_config: russh::client::Config::default().into(),
+            operation_log: OperationLog::new(config.operation_log_size).into(),
+            write_locks: Arc::default(),
+            config: config.into(),
+            event_hooks,
+        }
+    }
+
+    /// Handles a host offering a host key that isn't accepted for it, according to the host key policy
+    fn hostkey_mismatch(&self, host: &Host, offered: &str) {
+        if self.config.hostkey_policy == HostKeyPolicy::Log {
+            warn!("{} offered the unknown host key {offered}", host.name);
+            return;
+        }
+        error!(
+            "{} offered the unknown host key {offered}, this may be a man-in-the-middle attack",
+            host.name
+        );
+
+        let accepted: Vec<String> = host.key_fingerprint_list().map(str::to_owned).collect();
+        let result = self
+            .conn
+            .get()
+            .map_err(|e| e.to_string())
+            .and_then(|mut conn| {
+                // Scheduled jobs run into the same mismatch again, only alert once per offered key
+                let current = Host::get_from_id_sync(&mut conn, host.id)?;
+                if current.is_some_and(|current| current.key_mismatch.as_deref() == Some(offered)) {
+                    return Ok(false);
+                }
+                SecurityEvent::record(
+                    &mut conn,
+                    security_event::HOSTKEY_MISMATCH,
+                    security_event::CRITICAL,
+                    Some(&host.name),
+                    format!(
+                        "{} ({}) offered the host key {offered}, but only {} is accepted",
+                        host.name,
+                        host.address,
+                        accepted.join(", ")
+                    ),
+                )?;
+                host.mark_key_mismatch(&mut conn, offered)?;
+                Ok(true)
+            });
+
+        match result {
+            Ok(true) => self.event_hooks.emit(Event::HostKeyMismatch {
+                host: host.name.clone(),
+                address: host.address.clone(),
+                offered: offered.to_owned(),
+                accepted,
+            }),
+            Ok(false) => {}
+            Err(e) => error!(
+                "Failed to record the host key mismatch of {}: {e}",
+                host.name
+            ),
         }
     }
 
     fn get_key(&self) -> PrivateKeyWithHashAlg {
         Arc::clone(&self.key).deref().to_owned()
     }
-    pub fn get_own_key_openssh(&self) -> String {
+
+    fn connect(self, host: Host) -> BoxFuture<'static, Result<SshHandle, SshClientError>> {
+        let accepted_fingerprints: Vec<String> =
+            host.key_fingerprint_list().map(str::to_owned).collect();
+        if accepted_fingerprints.is_empty() {
+            return Box::pin(async { Err(SshClientError::NoHostkey) });
+        }
+        let offered_fingerprint: Arc<Mutex<Option<String>>> = Arc::default();
+        let handler = SshHandler {
+            accepted_fingerprints,
+            offered_fingerprint: Arc::clone(&offered_fingerprint),
+        };
+        let span = tracing::info_span!(
+            "ssh.connect",
+            host = %host.name,
+            address = %host.address,
+            peer = tracing::field::Empty
+        );
+        let host_name = host.name.clone();
+        let operation_log = Arc::clone(&self.operation_log);
+        let max_output_size = self.config.max_output_size;
+        let started_at = OffsetDateTime::now_utc();
+        let started = Instant::now();
+
+        async move {
+            let handshake = async {
+                Ok(match (host.connection_plugin.as_deref(), host.jump_via) {
+                    (Some(plugin), _) => {
+                        let stream =
+                            PluginStream::spawn(&self.config.connection_plugins, plugin, &host)?;
+
+                        let handle = tokio::time::timeout(
+                            self.config.timeout,
+                            russh::client::connect_stream(
+                                self.connection_config.clone(),
+                                stream,
+                                handler,
+                            ),
+                        )
+                        .await
+                        .map_err(|_| SshClientError::Timeout)??;
+                        (handle, None)
+                    }
+                    (None, Some(via)) => {
+                        let jump_host = Host::get_from_id(self.conn.get().unwrap(), via)
+                            .await?
+                            .ok_or(SshClientError::NoSuchHost)?;
+                        let stream = self.connect_via(jump_host, host.to_connection()?).await?;
+
+                        let handle = russh::client::connect_stream(
+                            self.connection_config.clone(),
+                            stream,
+                            handler,
+                        )
+                        .await?;
+                        (handle, None)
+                    }
+                    (None, None) => {
+                        let (stream, peer) = self.open_tcp(&host.to_connection()?).await?;
+                        tracing::Span::current().record("peer", tracing::field::display(peer));
+
+                        let handle = tokio::time::timeout(
+                            self.config.timeout,
+                            russh::client::connect_stream(
+                                self.connection_config.clone(),
+                                stream,
+                                handler,
+                            ),
+                        )
+                        .await
+                        .map_err(|_| SshClientError::Timeout)??;
+                        (handle, Some(peer))
+                    }
+                })
+            }
+            .await;
+            if let (Err(SshClientError::UnknownKey), Some(offered)) = (
+                &handshake,
+                offered_fingerprint.lock().ok().and_then(|o| o.clone()),
+            ) {
+                self.hostkey_mismatch(&host, &offered);
+            }
+            let (mut handle, peer) = handshake?;
+
+            if !handle
+                .authenticate_publickey(host.username.clone(), self.get_key())
+                .await?
+            {
+                return Err(SshClientError::NotAuthenticated);
+            };
+
+            Ok((handle, peer))
+        }
+        .map(move |result| {
+            operation_log.record_connect(
+                &host_name,
+                started_at,
+                started.elapsed(),
+                result.as_ref().map(|(_, peer)| *peer),
+            );
+            result.map(|(handle, _)| SshHandle {
+                handle,
+                host: host_name,
+                log: operation_log,
+                max_output_size,
+            })
+        })
+        .instrument(span)
+        .boxed()
+    }
+
+    /// Opens a TCP connection to the target, falling back to its other addresses in order
+    async fn open_tcp(
+        &self,
+        target: &ConnectionDetails,
+    ) -> Result<(TcpStream, SocketAddr), SshClientError> {
+        let mut errors = Vec::new();
+        for candidate in target.candidates() {
+            match self.open_tcp_to(&candidate).await {
+                Ok(connected) => return Ok(connected),
+                Err(e) if target.fallback_addresses.is_empty() => return Err(e),
+                Err(e) => {
+                    debug!("Couldn't connect to {}: {e}", candidate.hostname);
+                    errors.push(format!("{}: {e}", candidate.hostname));
+                }
+            }
+        }
+        Err(SshClientError::SshError(format!(
+            "None of the addresses could be reached: {}",
+            errors.join("; ")
+        )))
+    }
+
+    /// Resolves the address again and opens a TCP connection to the first of its IPs that accepts it.
+    /// With a proxy, the connection goes to the proxy and the returned address is the one of the proxy.
+    async fn open_tcp_to(
+        &self,
+        target: &ConnectionDetails,
+    ) -> Result<(TcpStream, SocketAddr), SshClientError> {
+        let Some(ref proxy) = target.proxy else {
+            let addresses = target.resolve(self.config.address_family).await?;
+            return connect_any(&addresses, self.config.timeout).await;
+        };
+
+        let mut proxy_target = proxy.connection();
+        proxy_target.address_family = target.address_family;
+        let addresses = proxy_target.resolve(self.config.address_family).await?;
+        let (mut stream, peer) = connect_any(&addresses, self.config.timeout).await?;
+
+        tokio::time::timeout(
+            self.config.timeout,
+            proxy.open(&mut stream, &target.hostname, target.port),
+        )
+        .await
+        .map_err(|_| SshClientError::Timeout)??;
+        debug!(
+            "Connected to {}:{} via {proxy}",
+            target.hostname, target.port
+        );
+        Ok((stream, peer))
+    }
+
+    #[tracing::instrument(name = "ssh.connect_via", skip_all, fields(via = %via.name, to = %to.hostname))]
+    async fn connect_via(
+        &self,
+        via: Host,
+        to: ConnectionDetails,
+    ) -> Result<russh::ChannelStream<russh::client::Msg>, SshClientError> {
+        let jump_handle = self.clone().connect(via).await?;
+
+        debug!("Got handle for jump host targeting {}", to.hostname);
+
+        let mut errors = Vec::new();
+        for candidate in to.candidates() {
+            let result = tokio::time::timeout(
+                self.config.timeout,
+                jump_handle.channel_open_direct_tcpip(
+                    candidate.hostname.clone(),
+                    candidate.port.into(),
+                    "127.0.0.1",
+                    0,
+                ),
+            )
+            .await
+            .map_err(|_| SshClientError::Timeout)
+            .and_then(|channel| channel.map_err(SshClientError::from));
+
+            match result {
+                Ok(channel) => return Ok(channel.into_stream()),
+                Err(e) if to.fallback_addresses.is_empty() => return Err(e),
+                Err(e) => errors.push(format!("{}: {e}", candidate.hostname)),
+            }
+        }
+        Err(SshClientError::SshError(format!(
+            "None of the addresses could be reached via the jumphost: {}",
+            errors.join("; ")
+        )))
+    }
+}
+
+/// Tries the addresses in order, so a host with several A/AAAA records stays reachable while one of them is down
+async fn connect_any(
+    addresses: &[SocketAddr],
+    timeout: Duration,
+) -> Result<(TcpStream, SocketAddr), SshClientError> {
+    let mut errors = Vec::with_capacity(addresses.len());
+    for &address in addresses {
+        match tokio::time::timeout(timeout, TcpStream::connect(address)).await {
+            Ok(Ok(stream)) => {
+                debug!("Connected to {address}");
+                return Ok((stream, address));
+            }
+            Ok(Err(e)) => errors.push(format!("{address}: {e}")),
+            Err(_) => errors.push(format!("{address}: timed out")),
+        }
+    }
+    Err(SshClientError::SshError(format!(
+        "Couldn't connect to any address: {}",
+        errors.join(", ")
+    )))
+}
+
+/// Steps of a connection test, hosts behind a jumphost or proxy are resolved and connected to by the jumphost or proxy
+const DIRECT_STEPS: [&str; 5] = ["dns", "tcp", "hostkey", "authentication", "transport"];
+const JUMPHOST_STEPS: [&str; 4] = ["jumphost", "hostkey", "authentication", "transport"];
+const PROXY_STEPS: [&str; 4] = ["proxy", "hostkey", "authentication", "transport"];
+const PLUGIN_STEPS: [&str; 4] = ["plugin", "hostkey", "authentication", "transport"];
+
+impl RealSshClient {
+    /// Reads a deployed file back and logs in on a new connection, so a file that locks SSM out
+    /// is noticed right away. Returns the hash of the written file.
+    async fn verify_deploy(
+        &self,
+        handle: &SshHandle,
+        transport: &dyn RemoteHostTransport,
+        host: Host,
+        login: &str,
+        authorized_keys: &str,
+    ) -> Result<String, String> {
+        let written = transport
+            .get_authorized_keyfile(handle, login)
+            .await
+            .map_err(|e| format!("Couldn't read the file back: {e}"))?;
+        let (expected, actual) = (keyfile_hash(authorized_keys), keyfile_hash(&written));
+        if expected != actual {
+            return Err(format!(
+                "The file read back has SHA256 {actual}, but {expected} was sent"
+            ));
+        }
+
+        self.clone()
+            .connect(host)
+            .await
+            .map_err(|e| format!("SSM can't log in anymore: {e}"))?;
+        Ok(actual)
+    }
+
+    /// Runs the connection test until a step fails
+    async fn run_connection_test(&self, host: &Host, test: &mut ConnectionTest) -> Option<()> {
+        let handle = match (host.connection_plugin.as_deref(), host.jump_via) {
+            (Some(plugin), _) => {
+                let stream = test
+                    .check("plugin", async {
+                        let stream =
+                            PluginStream::spawn(&self.config.connection_plugins, plugin, host)
+                                .map_err(|e| e.to_string())?;
+                        Ok((stream, format!("Started {plugin}")))
+                    })
+                    .await?;
+                self.test_handshake(host, stream, test).await?
+            }
+            (None, Some(via)) => {
+                let stream = test
+                    .check("jumphost", async {
+                        let jump_host = Host::get_from_id(self.conn.get().unwrap(), via)
+                            .await?
+                            .ok_or_else(|| "Jumphost doesn't exist".to_owned())?;
+                        let jump_host_name = jump_host.name.clone();
+                        let target = host.to_connection().map_err(|e| e.to_string())?;
+                        let stream = self
+                            .connect_via(jump_host, target)
+                            .await
+                            .map_err(|e| format!("Via {jump_host_name}: {e}"))?;
+                        Ok((stream, format!("Opened a tunnel via {jump_host_name}")))
+                    })
+                    .await?;
+                self.test_handshake(host, stream, test).await?
+            }
+            (None, None) if host.proxy.is_some() => {
+                let stream = test
+                    .check("proxy", async {
+                        let target = host.to_connection().map_err(|e| e.to_string())?;
+                        let (stream, _) =
+                            self.open_tcp(&target).await.map_err(|e| e.to_string())?;
+                        let detail = match target.proxy {
+                            Some(proxy) => format!("Connected via {proxy}"),
+                            None => "Connected".to_owned(),
+                        };
+                        Ok((stream, detail))
+                    })
+                    .await?;
+                self.test_handshake(host, stream, test).await?
+            }
+            (None, None) => {
+                let resolved = test
+                    .check("dns", async {
+                        let target = host.to_connection().map_err(|e| e.to_string())?;
+                        let mut resolved = Vec::new();
+                        let mut details = Vec::new();
+                        for candidate in target.candidates() {
+                            match candidate.resolve(self.config.address_family).await {
+                                Ok(addresses) => {
+                                    details.push(format!(
+                                        "{} resolved to {}",
+                                        candidate.hostname,
+                                        addresses
+                                            .iter()
+                                            .map(|address| address.ip().to_string())
+                                            .collect::<Vec<_>>()
+                                            .join(", ")
+                                    ));
+                                    resolved.push(addresses);
+                                }
+                                Err(e) => details.push(e.to_string()),
+                            }
+                        }
+                        if resolved.is_empty() {
+                            Err(details.join("; "))
+                        } else {
+                            Ok((resolved, details.join("; ")))
+                        }
+                    })
+                    .await?;
+
+                let stream = test
+                    .check("tcp", async {
+                        let mut errors = Vec::new();
+                        for addresses in &resolved {
+                            match connect_any(addresses, self.config.timeout).await {
+                                Ok((stream, peer)) => {
+                                    return Ok((stream, format!("Connected to {peer}")))
+                                }
+                                Err(e) => errors.push(e.to_string()),
+                            }
+                        }
+                        Err(errors.join("; "))
+                    })
+                    .await?;
+                self.test_handshake(host, stream, test).await?
+            }
+        };
+
+        test.check("transport", async {
+            let transport = transport_for(host).map_err(|e| e.to_string())?;
+            transport
+                .check(&handle)
+                .await
+                .map(|detail| ((), detail))
+                .map_err(|e| e.to_string())
+        })
+        .await
+    }
+
+    /// Verifies the host key and authenticates over an open stream
+    async fn test_handshake<S>(
+        &self,
+        host: &Host,
+        stream: S,
+        test: &mut ConnectionTest,
+    ) -> Option<SshHandle>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut handle = test
+            .check("hostkey", async {
+                let expected: Vec<String> =
+                    host.key_fingerprint_list().map(str::to_owned).collect();
+                if expected.is_empty() {
+                    return Err("No host key is stored for this host".to_owned());
+                }
+                let offered_fingerprint = Arc::default();
+                let handler = SshHandler {
+                    accepted_fingerprints: expected.clone(),
+                    offered_fingerprint: Arc::clone(&offered_fingerprint),
+                };
+
+                let result = tokio::time::timeout(
+                    self.config.timeout,
+                    russh::client::connect_stream(self.connection_config.clone(), stream, handler),
+                )
+                .await
+                .map_err(|_| SshClientError::Timeout.to_string())?;
+                let offered = offered_fingerprint.lock().ok().and_then(|o| o.clone());
+
+                match (result, offered) {
+                    (Ok(handle), offered) => Ok((
+                        handle,
+                        format!("Host key matches {}", offered.unwrap_or_default()),
+                    )),
+                    (Err(_), Some(offered)) if !expected.contains(&offered) => {
+                        self.hostkey_mismatch(host, &offered);
+                        Err(format!(
+                            "Host offered {offered}, but only {} is accepted",
+                            expected.join(", ")
+                        ))
+                    }
+                    (Err(e), _) => Err(format!("SSH handshake failed: {e}")),
+                }
+            })
+            .await?;
+
+        test.check("authentication", async {
+            match handle
+                .authenticate_publickey(host.username.clone(), self.get_key())
+                .await
+            {
+                Ok(true) => Ok(((), format!("Authenticated as {}", host.username))),
+                Ok(false) => Err(format!(
+                    "The key of SSM is not accepted for {}",
+                    host.username
+                )),
+                Err(e) => Err(e.to_string()),
+            }
+        })
+        .await?;
+
+        Some(SshHandle {
+            handle,
+            host: host.name.clone(),
+            log: Arc::clone(&self.operation_log),
+            max_output_size: self.config.max_output_size,
+        })
+    }
+}
+
+#[async_trait]
+impl SshClient for RealSshClient {
+    fn get_own_key_openssh(&self) -> String {
         let b64 = self.key.public_key_base64();
         let algo = self.key.algorithm();
         format!("{algo} {b64} ssm")
     }
-    pub fn get_own_key_b64(&self) -> String {
+
+    fn get_own_key_b64(&self) -> String {
         self.key.public_key_base64()
     }
 
     /// Tries to connect to a host and returns hostkeys to validate
-    pub async fn get_hostkey(
+    async fn get_hostkey(
         &self,
         target: ConnectionDetails,
     ) -> Result<mpsc::Receiver<String>, SshClientError> {
@@ -173,9 +782,10 @@ impl SshClient {
         let handler = SshFirstConnectionHandler {
             state: FirstConnectionState::KeySender(tx),
         };
-        match russh::client::connect(
+        let (stream, _) = self.open_tcp(&target).await?;
+        match russh::client::connect_stream(
             Arc::new(russh::client::Config::default()),
-            target.into_addr(),
+            stream,
             handler,
         )
         .await
@@ -186,7 +796,7 @@ impl SshClient {
     }
 
     /// Tries to connect to a host via a jumphost and returns hostkeys to validate
-    pub async fn get_hostkey_via(
+    async fn get_hostkey_via(
         &self,
         host: Host,
         target: ConnectionDetails,
@@ -210,7 +820,7 @@ impl SshClient {
         }
     }
 
-    pub async fn try_authenticate(
+    async fn try_authenticate(
         &self,
         address: ConnectionDetails,
         hostkey: String,
@@ -220,9 +830,9 @@ impl SshClient {
             state: FirstConnectionState::Hostkey(hostkey),
         };
 
+        let (stream, _) = self.open_tcp(&address).await?;
         let mut handle =
-            russh::client::connect(self.connection_config.clone(), address.into_addr(), handler)
-                .await?;
+            russh::client::connect_stream(self.connection_config.clone(), stream, handler).await?;
 
         if handle.authenticate_publickey(user, self.get_key()).await? {
             Ok(())
@@ -231,7 +841,7 @@ impl SshClient {
         }
     }
 
-    pub async fn try_authenticate_via(
+    async fn try_authenticate_via(
         &self,
         host: Host,
         address: ConnectionDetails,
@@ -254,387 +864,285 @@ impl SshClient {
         }
     }
 
-    fn connect(
-        self,
-        host: Host,
-    ) -> BoxFuture<'static, Result<russh::client::Handle<SshHandler>, SshClientError>> {
-        let Some(ref key_fingerprint) = host.key_fingerprint else {
-            return Box::pin(async { Err(SshClientError::NoHostkey) });
-        };
-        let handler = SshHandler {
-            hostkey_fingerprint: key_fingerprint.clone(),
-        };
-
-        async move {
-            let mut handle = match host.jump_via {
-                Some(via) => {
-                    let jump_host = Host::get_from_id(self.conn.get().unwrap(), via)
-                        .await?
-                        .ok_or(SshClientError::NoSuchHost)?;
-                    let stream = self.connect_via(jump_host, host.to_connection()?).await?;
-
-                    russh::client::connect_stream(self.connection_config.clone(), stream, handler)
-                        .await
-                }
-                None => tokio::time::timeout(
-                    self.config.timeout,
-                    russh::client::connect(
-                        self.connection_config.clone(),
-                        host.to_connection()?.into_addr(),
-                        handler,
-                    ),
-                )
-                .await
-                .map_err(|_| SshClientError::Timeout)?,
-            }?;
-
-            if !handle
-                .authenticate_publickey(host.username.clone(), self.get_key())
-                .await?
-            {
-                return Err(SshClientError::NotAuthenticated);
-            };
-
-            Ok(handle)
-        }
-        .boxed()
-    }
-
-    async fn connect_via(
-        &self,
-        via: Host,
-        to: ConnectionDetails,
-    ) -> Result<russh::ChannelStream<russh::client::Msg>, SshClientError> {
-        let jump_handle = self.clone().connect(via).await?;
-
-        debug!("Got handle for jump host targeting {}", to.hostname);
-
-        tokio::time::timeout(
-            self.config.timeout,
-            jump_handle.channel_open_direct_tcpip(to.hostname, to.port, "127.0.0.1", 0),
-        )
-        .await
-        .map_err(|_| SshClientError::Timeout)?
-        .map(|e| e.into_stream())
-        .map_err(SshClientError::from)
-    }
-
-    pub async fn get_authorized_keys(self, host: Host) -> AuthorizedKeys {
+    #[tracing::instrument(name = "ssh.get_authorized_keys", skip_all, fields(host = %host.name))]
+    async fn get_authorized_keys(&self, host: Host) -> AuthorizedKeys {
+        let transport = transport_for(&host)?;
         let handle = self.clone().connect(host.clone()).await?;
-        let users = self.get_ssh_users(&handle).await?;
+        let users = transport.get_ssh_users(&handle).await?;
 
         let mut user_vec = Vec::with_capacity(users.len());
 
         for user in users {
             info!("Loading authorized keys for user: {user}");
-            let (has_pragma, keys) = self.get_authorized_keys_for(&handle, user.clone()).await?;
-            user_vec.push((user, has_pragma, keys));
+            let keyfile = transport.get_authorized_keyfile(&handle, &user).await?;
+            let (has_pragma, keys) = parse_authorized_keyfile(&keyfile);
+            let principals = match host.principals_file.as_deref() {
+                Some(location) => Some(parse_principals_file(
+                    &transport
+                        .get_authorized_principals(&handle, &user, location)
+                        .await?,
+                )),
+                None => None,
+            };
+            user_vec.push((user, has_pragma, keys, principals));
         }
 
         Ok(user_vec)
     }
 
-    /// Returns if the pragma is set and a list of authorized key entries
-    async fn get_authorized_keys_for(
-        &self,
-        handle: &russh::client::Handle<SshHandler>,
-        user: String,
-    ) -> Result<(bool, Vec<AuthorizedKeyEntry>), SshClientError> {
-        let res = self
-            .execute_bash(handle, BashCommand::GetAuthorizedKeyfile(user))
-            .await??;
-
-        let mut iter = res.trim().lines().peekable();
-        let has_pragma = iter.peek().is_some_and(|first| PRAGMA.to_owned().eq(first));
-        Ok((
-            has_pragma,
-            iter.filter(|line| !line.trim_start().starts_with('#'))
-                .map(|line| {
-                    Entry::from_str(line)
-                        .map_err(|e| (e.to_string(), line.to_owned()))
-                        .map(|key| {
-                            //TODO: algorithm to estimate size
-                            let mut buf = vec![0u8; 1024];
-                            let mut writer = Base64Writer::new(&mut buf).expect("buf is non-zero");
-
-                            let pkey = key.public_key();
-                            let comment = pkey.comment();
-
-                            pkey.key_data().encode(&mut writer).expect("Buffer overrun");
-                            let b64 = writer.finish().expect("Buffer overrun");
-
-                            AuthorizedKey {
-                                options: key.config_opts().clone(),
-                                algorithm: pkey.algorithm(),
-                                base64: b64.to_owned(),
-                                comment: if comment.is_empty() {
-                                    None
-                                } else {
-                                    Some(comment.to_owned())
-                                },
-                            }
-                        })
-                })
-                .collect(),
-        ))
-    }
-
-    pub async fn set_authorized_keys(
+    #[tracing::instrument(name = "ssh.set_authorized_keys", skip(self, authorized_keys))]
+    async fn set_authorized_keys(
         &self,
         host_name: String,
         login: String,
         authorized_keys: String,
-    ) -> Result<(), SshClientError> {
+    ) -> Result<DeployOutput, SshClientError> {
         let host = Host::get_from_name(self.conn.get().unwrap(), host_name)
             .await?
             .ok_or(SshClientError::NoSuchHost)?;
+        // By the name of the host, callers may use an alias
+        let _write = self
+            .write_locks
+            .lock(&host.name, Some(&login), "set_authorized_keys")
+            .await;
+        let transport = transport_for(&host)?;
+        let pre_deploy_hook = host.pre_deploy_hook.clone();
+        let post_deploy_hook = host.post_deploy_hook.clone();
         let handle = self.clone().connect(host.clone()).await?;
-        self.execute_bash(
-            &handle,
-            BashCommand::SetAuthorizedKeyfile(login, authorized_keys),
-        )
-        .await??;
 
-        Ok(())
-    }
+        let mut output = DeployOutput::default();
 
-    async fn get_ssh_users(
-        &self,
-        handle: &russh::client::Handle<SshHandler>,
-    ) -> Result<Vec<String>, SshClientError> {
-        let res = self
-            .execute_bash(handle, BashCommand::GetSshUsers)
-            .await??;
-
-        Ok(res.lines().map(std::borrow::ToOwned::to_owned).collect())
-    }
-
-    pub async fn install_script_on_host(&self, host: i32) -> Result<(), SshClientError> {
-        let host = Host::get_from_id(self.conn.get().unwrap(), host)
-            .await?
-            .ok_or(SshClientError::NoSuchHost)?;
-        let handle = self.clone().connect(host).await?;
+        // Kept to restore it if the new file can't be verified
+        let previous = if self.config.rollback_failed_deploys {
+            transport.get_authorized_keyfile(&handle, &login).await.ok()
+        } else {
+            None
+        };
 
-        self.install_script(&handle).await
-    }
+        if let Some(hook) = pre_deploy_hook {
+            output.pre_hook = Some(
+                run_hook(&handle, &hook)
+                    .await
+                    .map_err(SshClientError::PreDeployHookFailed)?,
+            );
+        }
 
-    async fn install_script(
-        &self,
-        handle: &russh::client::Handle<SshHandler>,
-    ) -> Result<(), SshClientError> {
-        let script = include_bytes!("./script.sh");
+        transport
+            .set_authorized_keyfile(&handle, &login, &authorized_keys)
+            .await?;
 
-        match self
-            .execute_with_data(
-                handle,
-                &script[..],
-                "cat - > .ssh/ssm.sh; chmod +x .ssh/ssm.sh",
-            )
+        match transport
+            .check_keyfile_permissions(&handle, &login, self.config.repair_permissions)
             .await
         {
-            Ok((code, _)) => {
-                if code != 0 {
-                    Err(SshClientError::ExecutionError(String::from(
-                        "Failed to install script.",
-                    )))
-                } else {
-                    Ok(())
-                }
+            Ok(report) => {
+                output.permissions = report
+                    .lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .map(str::to_owned)
+                    .collect();
             }
-            Err(error) => Err(error),
+            Err(e) => warn!("Failed to check the permissions of the keyfile of {login}: {e}"),
         }
-    }
 
-    async fn execute_bash(
-        &self,
-        handle: &russh::client::Handle<SshHandler>,
-        command: BashCommand,
-    ) -> Result<BashResult, SshClientError> {
-        let (exit_code, result) = self
-            .execute(handle, BashCommand::Version.to_string().as_str())
-            .await?;
-        // TODO: checksums
-        if exit_code != 0 || !result.contains("Secure SSH Manager") {
-            warn!("Script on host seems to be invalid. Trying to install");
-            match self.install_script(handle).await {
-                Ok(()) => {
-                    debug!("Succesfully installed script");
-                }
-                Err(error) => {
-                    warn!("Failed to install script on host: {}", error);
-                    return Err(SshClientError::ExecutionError(String::from(
-                        "Script not valid",
-                    )));
-                }
-            };
+        if let Some(hook) = post_deploy_hook {
+            output.post_hook = Some(
+                run_hook(&handle, &hook)
+                    .await
+                    .map_err(SshClientError::PostDeployHookFailed)?,
+            );
         }
 
-        let command_str = command.to_string();
-        debug!("Executing bash command {}", &command_str);
-
-        let stdin: Option<String> = match command {
-            BashCommand::SetAuthorizedKeyfile(_, new_keyfile) => Some(new_keyfile),
-            BashCommand::Update(new_script) => Some(new_script),
-
-            BashCommand::GetAuthorizedKeyfile(_)
-            | BashCommand::GetSshUsers
-            | BashCommand::Version => None,
-        };
-
-        let (exit_code, result) = match stdin {
-            Some(stdin) => {
-                self.execute_with_data(
-                    handle,
-                    Cursor::new(stdin.into_bytes()),
-                    command_str.as_str(),
-                )
-                .await
+        match self
+            .verify_deploy(&handle, transport.as_ref(), host, &login, &authorized_keys)
+            .await
+        {
+            Ok(hash) => output.verified_hash = Some(hash),
+            Err(reason) => {
+                warn!("Failed to verify authorized_keys of {login}: {reason}");
+                let rolled_back = match previous {
+                    Some(previous) => {
+                        let previous = previous
+                            .strip_prefix(PRAGMA)
+                            .map_or(previous.as_str(), |rest| rest.trim_start_matches('\n'));
+                        match transport
+                            .set_authorized_keyfile(&handle, &login, previous)
+                            .await
+                        {
+                            Ok(()) => true,
+                            Err(e) => {
+                                error!("Failed to restore authorized_keys of {login}: {e}");
+                                false
+                            }
+                        }
+                    }
+                    None => false,
+                };
+                return Err(SshClientError::VerificationFailed {
+                    reason,
+                    rolled_back,
+                });
             }
-            None => self.execute(handle, command_str.as_str()).await,
-        }?;
+        }
 
-        Ok(match exit_code {
-            0 => BashResult::Ok(result),
-            _ => BashResult::Err(result),
-        })
+        Ok(output)
     }
 
-    async fn execute(
+    #[tracing::instrument(name = "ssh.set_authorized_principals", skip_all, fields(host = %host.name, login = %login))]
+    async fn set_authorized_principals(
         &self,
-        handle: &russh::client::Handle<SshHandler>,
-        command: &str,
-    ) -> Result<(u32, String), SshClientError> {
-        self.execute_with_data(handle, tokio::io::empty(), command)
+        host: Host,
+        login: String,
+        principals: String,
+    ) -> Result<(), SshClientError> {
+        let location = host.principals_file.clone().ok_or_else(|| {
+            SshClientError::ExecutionError(String::from("The host has no principals file"))
+        })?;
+        let transport = transport_for(&host)?;
+        let _write = self
+            .write_locks
+            .lock(&host.name, Some(&login), "set_authorized_principals")
+            .await;
+        let handle = self.clone().connect(host).await?;
+
+        transport
+            .set_authorized_principals(&handle, &login, &location, &principals)
             .await
     }
 
-    /// Runs a command and returns exit code and std{out/err} merged as a touple
-    async fn execute_with_data<R>(
-        &self,
-        handle: &russh::client::Handle<SshHandler>,
-        data: R,
-        command: &str,
-    ) -> Result<(u32, String), SshClientError>
-    where
-        R: AsyncRead + Unpin,
-    {
-        let mut channel = handle.channel_open_session().await?;
-
-        channel.exec(true, command).await?;
+    #[tracing::instrument(name = "ssh.get_sshd_config", skip_all, fields(host = %host.name))]
+    async fn get_sshd_config(&self, host: Host) -> Result<String, SshClientError> {
+        let transport = transport_for(&host)?;
+        let handle = self.clone().connect(host).await?;
 
-        channel.data(data).await?;
-        channel.eof().await?;
+        transport.get_sshd_config(&handle).await
+    }
 
-        let mut exit_code: Option<u32> = None;
-        let mut out_buf = Vec::new();
+    #[tracing::instrument(name = "ssh.get_key_sources", skip_all, fields(host = %host.name))]
+    async fn get_key_sources(&self, host: Host) -> Result<String, SshClientError> {
+        let transport = transport_for(&host)?;
+        let handle = self.clone().connect(host).await?;
 
-        loop {
-            let Some(msg) = channel.wait().await else {
-                break;
-            };
-            match msg {
-                russh::ChannelMsg::Data { ref data } => {
-                    out_buf
-                        .write_all(data)
-                        .await
-                        .expect("couldnt write to out_buf");
-                }
-                russh::ChannelMsg::ExitStatus { exit_status } => {
-                    exit_code = Some(exit_status);
-                }
-                _ => {
-                    debug!("Received extra message: {:?}", msg);
-                }
-            }
-        }
+        transport.get_key_sources(&handle).await
+    }
 
-        match exit_code {
-            Some(code) => {
-                let output = String::from_utf8(out_buf).map_err(|_e| {
-                    SshClientError::ExecutionError(String::from(
-                        "Couldn't convert command output to utf-8",
-                    ))
-                })?;
+    #[tracing::instrument(name = "ssh.get_key_usage", skip_all, fields(host = %host.name))]
+    async fn get_key_usage(&self, host: Host) -> Result<String, SshClientError> {
+        let transport = transport_for(&host)?;
+        let handle = self.clone().connect(host).await?;
 
-                Ok((code, output))
-            }
-            None => Err(SshClientError::ExecutionError(String::from(
-                "Program didn't exit cleanly",
-            ))),
-        }
+        transport.get_key_usage(&handle).await
     }
 
-    // TODO: impl this on something else
-    pub async fn key_diff(
-        &self,
-        new: &str,
-        host_name: String,
-        login: String,
-    ) -> Result<Vec<KeyDiffItem>, SshClientError> {
-        let Some(host) = Host::get_from_name(self.conn.get().unwrap(), host_name).await? else {
-            return Err(SshClientError::NoSuchHost);
-        };
+    #[tracing::instrument(name = "ssh.get_failed_logins", skip_all, fields(host = %host.name))]
+    async fn get_failed_logins(&self, host: Host, hours: u32) -> Result<String, SshClientError> {
+        let transport = transport_for(&host)?;
+        let handle = self.clone().connect(host).await?;
 
-        let conn = self.clone().connect(host).await?;
+        transport.get_failed_logins(&handle, hours).await
+    }
 
-        let curr_keys = self
-            .execute_bash(&conn, BashCommand::GetAuthorizedKeyfile(login))
-            .await??;
+    #[tracing::instrument(name = "ssh.set_sshd_config", skip_all, fields(host = %host.name))]
+    async fn set_sshd_config(&self, host: Host, content: String) -> Result<(), SshClientError> {
+        let transport = transport_for(&host)?;
+        let _write = self
+            .write_locks
+            .lock(&host.name, None, "set_sshd_config")
+            .await;
+        let handle = self.clone().connect(host).await?;
 
-        let new_keys = new.to_owned();
+        transport.set_sshd_config(&handle, &content).await
+    }
 
-        let diff = similar::TextDiff::from_lines(&curr_keys, &new_keys);
+    #[tracing::instrument(name = "ssh.test_connection", skip_all, fields(host = %host.name))]
+    async fn test_connection(&self, host: Host) -> Vec<ConnectionCheck> {
+        let mut test = ConnectionTest::default();
+        self.run_connection_test(&host, &mut test).await;
+
+        test.finish(if host.connection_plugin.is_some() {
+            &PLUGIN_STEPS
+        } else if host.jump_via.is_some() {
+            &JUMPHOST_STEPS
+        } else if host.proxy.is_some() {
+            &PROXY_STEPS
+        } else {
+            &DIRECT_STEPS
+        })
+    }
 
-        Ok(diff
-            .iter_all_changes()
-            .filter_map(|e| match e.tag() {
-                similar::ChangeTag::Delete => Some(KeyDiffItem::Removed(e.value().to_owned())),
-                similar::ChangeTag::Insert => Some(KeyDiffItem::Added(e.value().to_owned())),
-                similar::ChangeTag::Equal => None,
-            })
-            .collect())
+    fn operations(&self, host_name: &str) -> Vec<Operation> {
+        self.operation_log.get(host_name)
     }
-}
 
-type User = String;
-pub enum BashCommand {
-    /// Read the authorized keys for a user
-    GetAuthorizedKeyfile(User),
+    fn queued_writes(&self) -> Vec<QueuedWrite> {
+        self.write_locks.queued()
+    }
 
-    /// Set authorized keys for a user
-    SetAuthorizedKeyfile(User, String),
+    async fn install_script_on_host(&self, host: i32) -> Result<(), SshClientError> {
+        let host = Host::get_from_id(self.conn.get().unwrap(), host)
+            .await?
+            .ok_or(SshClientError::NoSuchHost)?;
+        let transport = transport_for(&host)?;
+        let _write = self.write_locks.lock(&host.name, None, "install").await;
+        let handle = self.clone().connect(host).await?;
 
-    /// Get all users that are allowed to login via SSH
-    GetSshUsers,
+        transport.install(&handle).await
+    }
 
-    /// Update the bash script on the server
-    Update(String),
+    #[tracing::instrument(name = "ssh.get_authorized_keyfile", skip_all, fields(host = %host.name, login = %login))]
+    async fn get_authorized_keyfile(
+        &self,
+        host: Host,
+        login: &str,
+    ) -> Result<String, SshClientError> {
+        let transport = transport_for(&host)?;
+        let handle = self.clone().connect(host).await?;
 
-    /// Check the script version
-    Version,
+        transport.get_authorized_keyfile(&handle, login).await
+    }
 }
 
-impl std::fmt::Display for BashCommand {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, ".ssh/ssm.sh ")?;
-        match self {
-            Self::GetAuthorizedKeyfile(user) => write!(f, "get_authorized_keyfile {user}"),
-            Self::SetAuthorizedKeyfile(user, _new_keyfile) => {
-                write!(f, "set_authorized_keyfile {user}")
-            }
-            Self::GetSshUsers => write!(f, "get_ssh_users"),
-            Self::Update(_script) => write!(f, "update_script"),
-            Self::Version => write!(f, "version"),
-        }
-    }
+/// Returns if the pragma is set and a list of authorized key entries
+pub fn parse_authorized_keyfile(keyfile: &str) -> (bool, Vec<AuthorizedKeyEntry>) {
+    let mut iter = keyfile.trim().lines().peekable();
+    let has_pragma = iter.peek().is_some_and(|first| PRAGMA.to_owned().eq(first));
+    (
+        has_pragma,
+        iter.filter(|line| !line.trim_start().starts_with('#'))
+            .map(|line| {
+                Entry::from_str(line)
+                    .map_err(|e| (e.to_string(), line.to_owned()))
+                    .map(|key| authorized_key(&key))
+            })
+            .collect(),
+    )
 }
 
-impl From<BashExecError> for SshClientError {
-    fn from(value: BashExecError) -> Self {
-        Self::ExecutionError(value)
+/// The key of an authorized_keys entry with its options
+pub(super) fn authorized_key(key: &Entry) -> AuthorizedKey {
+    let pkey = key.public_key();
+    let comment = pkey.comment();
+
+    let bytes = pkey.to_bytes().expect("Key data is encodable");
+
+    AuthorizedKey {
+        options: key.config_opts().clone(),
+        algorithm: pkey.algorithm(),
+        base64: Base64::encode_string(&bytes),
+        comment: if comment.is_empty() {
+            None
+        } else {
+            Some(comment.to_owned())
+        },
     }
 }
 
-type BashExecError = String;
-type BashExecResponse = String;
-pub type BashResult = Result<BashExecResponse, BashExecError>;
+/// Principals of an authorized_principals file, without comments and empty lines
+pub fn parse_principals_file(principals: &str) -> Vec<String> {
+    principals
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect()
+}
@@ -0,0 +1,1300 @@
+use async_trait::async_trait;
+use futures::AsyncWriteExt;
+use log::{debug, warn};
+use russh_sftp::{client::SftpSession, protocol::FileAttributes};
+use std::fmt;
+use std::io::Cursor;
+use std::str::FromStr;
+use std::time::Instant;
+use time::OffsetDateTime;
+use tokio::io::AsyncRead;
+
+use crate::models::Host;
+
+use super::sshclient::{SshClientError, SshHandle, PRAGMA};
+use super::{shell_quote, Login};
+
+/// How SSM reads and writes the authorized_keys files once connected to a host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TransportKind {
+    /// Install and run the `ssm.sh` helper script (default)
+    #[default]
+    Script,
+    /// Run plain POSIX commands without installing anything on the host
+    Exec,
+    /// Use the SFTP subsystem, for hosts where `exec` is restricted
+    Sftp,
+}
+
+impl TransportKind {
+    pub const ALL: [Self; 3] = [Self::Script, Self::Exec, Self::Sftp];
+
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Script => "script",
+            Self::Exec => "exec",
+            Self::Sftp => "sftp",
+        }
+    }
+}
+
+impl fmt::Display for TransportKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for TransportKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.as_str().eq(s))
+            .ok_or_else(|| format!("Unknown transport '{s}'"))
+    }
+}
+
+/// Remote operations needed to manage the authorized_keys files on a host
+#[async_trait]
+pub trait RemoteHostTransport: Send + Sync {
+    /// Prepare the host for this transport, e.g. by installing a script
+    async fn install(&self, _handle: &SshHandle) -> Result<(), SshClientError> {
+        Ok(())
+    }
+
+    /// Checks that this transport works on the host, describing what was found
+    async fn check(&self, handle: &SshHandle) -> Result<String, SshClientError>;
+
+    /// Get all logins that have an authorized_keys file
+    async fn get_ssh_users(&self, handle: &SshHandle) -> Result<Vec<Login>, SshClientError>;
+
+    /// Read the raw authorized_keys file of a login
+    async fn get_authorized_keyfile(
+        &self,
+        handle: &SshHandle,
+        login: &str,
+    ) -> Result<String, SshClientError>;
+
+    /// Replace the authorized_keys file of a login. The pragma is prepended by the transport
+    async fn set_authorized_keyfile(
+        &self,
+        handle: &SshHandle,
+        login: &str,
+        authorized_keys: &str,
+    ) -> Result<(), SshClientError>;
+
+    /// Read the authorized_principals file of a login, empty if there is none.
+    /// `location` is the principals file of the host, see [`Host::check_principals_file`]
+    async fn get_authorized_principals(
+        &self,
+        handle: &SshHandle,
+        login: &str,
+        location: &str,
+    ) -> Result<String, SshClientError>;
+
+    /// Replace the authorized_principals file of a login. The pragma is prepended by the transport
+    async fn set_authorized_principals(
+        &self,
+        handle: &SshHandle,
+        login: &str,
+        location: &str,
+        principals: &str,
+    ) -> Result<(), SshClientError>;
+
+    /// Read the sshd_config of the host
+    async fn get_sshd_config(&self, handle: &SshHandle) -> Result<String, SshClientError> {
+        execute_checked(handle, tokio::io::empty(), &format!("cat {SSHD_CONFIG}")).await
+    }
+
+    /// Read where sshd looks for keys, see [`KEY_SOURCES`]
+    async fn get_key_sources(&self, handle: &SshHandle) -> Result<String, SshClientError> {
+        execute_checked(handle, tokio::io::empty(), KEY_SOURCES).await
+    }
+
+    /// Read the logins sshd accepted, see [`KEY_USAGE`]
+    async fn get_key_usage(&self, handle: &SshHandle) -> Result<String, SshClientError> {
+        execute_checked(handle, tokio::io::empty(), KEY_USAGE).await
+    }
+
+    /// Read the logins sshd refused in the last hours, see [`FAILED_LOGINS`]
+    async fn get_failed_logins(
+        &self,
+        handle: &SshHandle,
+        hours: u32,
+    ) -> Result<String, SshClientError> {
+        execute_checked(
+            handle,
+            tokio::io::empty(),
+            &format!("h={hours}; {FAILED_LOGINS}"),
+        )
+        .await
+    }
+
+    /// Looks for owners, modes and SELinux contexts of the `.ssh` directory and authorized_keys file
+    /// of a login that keep sshd from using them and fixes them with `repair`, see
+    /// [`KEYFILE_PERMISSIONS`]. One line per problem, starting with `fixed` if it was repaired.
+    async fn check_keyfile_permissions(
+        &self,
+        handle: &SshHandle,
+        login: &str,
+        repair: bool,
+    ) -> Result<String, SshClientError> {
+        let login = checked_login(login)?;
+        let repair = if repair { "repair" } else { "" };
+        execute_checked(
+            handle,
+            tokio::io::empty(),
+            &format!("u={login}; h=~{login}; r={repair}; {KEYFILE_PERMISSIONS}"),
+        )
+        .await
+    }
+
+    /// Replace the sshd_config once `sshd -t` accepts it and reload sshd.
+    /// The previous file is kept as a backup.
+    async fn set_sshd_config(
+        &self,
+        handle: &SshHandle,
+        content: &str,
+    ) -> Result<(), SshClientError> {
+        let command = format!(
+            r#"f={SSHD_CONFIG}; t="$f.ssm"; s=$(command -v sshd || echo /usr/sbin/sshd); cp -p "$f" "$t" && cat - > "$t" || exit 1; if ! "$s" -t -f "$t"; then rm -f "$t"; exit 1; fi; cp -p "$f" "$f.backup" && mv "$t" "$f" && {{ systemctl reload sshd 2>/dev/null || systemctl reload ssh 2>/dev/null || kill -HUP "$(cat /var/run/sshd.pid)"; }}"#
+        );
+        execute_checked(handle, Cursor::new(content.as_bytes().to_vec()), &command)
+            .await
+            .map(|_| ())
+    }
+}
+
+/// Location of the sshd configuration on the hosts
+const SSHD_CONFIG: &str = "/etc/ssh/sshd_config";
+
+/// Prints AuthorizedKeysCommand and AuthorizedKeysFile as sshd uses them. `sshd -T` needs root,
+/// otherwise they are taken from the configuration files, included ones first like Include at the top does.
+const KEY_SOURCES: &str = r#"p='^[[:space:]]*(authorizedkeyscommand|authorizedkeysfile|match)([[:space:]]|=)'; s=$(command -v sshd || echo /usr/sbin/sshd); "$s" -T 2>/dev/null | grep -iE "$p" || cat /etc/ssh/sshd_config.d/*.conf /etc/ssh/sshd_config 2>/dev/null | grep -iE "$p"; true"#;
+
+/// Prints the logins sshd accepted, the last line per user and key of the journal and of the auth logs,
+/// followed by lastlog. The same as `get_key_usage` of the script.
+const KEY_USAGE: &str = r#"p='sshd(-session)?\[[0-9]+\]: Accepted [^ ]+ for '; a='{ u = ""; k = ""; for (i = 1; i <= NF; i++) { if (u == "" && $i == "for") u = $(i + 1); if (k == "" && $i ~ /^(SHA256|MD5):/) k = $i }; k = u " " k; if (!(k in l)) o[n++] = k; l[k] = $0 } END { for (i = 0; i < n; i++) print l[o[i]] }'; journalctl -q --no-pager -o short-unix -t sshd -t sshd-session 2>/dev/null | grep -E "$p" | awk "$a"; for f in $(ls -tr /var/log/auth.log* /var/log/secure* 2>/dev/null); do case "$f" in *.gz) gzip -dc "$f" 2>/dev/null;; *) cat "$f" 2>/dev/null;; esac; done | grep -E "$p" | awk "$a"; lastlog 2>/dev/null | tail -n +2 | grep -v 'Never logged in' | sed 's/^/lastlog /'; true"#;
+
+/// Prints the failed logins of the last `$h` hours of the journal, or of the current auth log if there
+/// are none, counted per user, source and day before the last line of each. The same as
+/// `get_failed_logins` of the script.
+const FAILED_LOGINS: &str = r#"p='sshd(-session)?\[[0-9]+\]: (Failed [^ ]+ for|Invalid user) '; a='/Failed [^ ]+ for invalid user / { next } { f = 0; u = ""; s = ""; for (i = 2; i < NF; i++) { if (!f && ($i == "for" || ($i == "user" && $(i - 1) == "Invalid"))) { f = 1; if ($(i + 1) != "from") u = $(i + 1) }; if (f && $i == "from") { s = $(i + 1); break } }; if ($1 ~ /^[0-9]+(\.[0-9]+)?$/) d = int($1 / 86400); else if ($1 ~ /T/) d = substr($1, 1, 10); else d = $1 " " $2; k = u " " s " " d; if (!(k in c)) o[n++] = k; c[k]++; l[k] = $0 } END { for (i = 0; i < n; i++) print c[o[i]], l[o[i]] }'; r=$(journalctl -q --no-pager -o short-unix --since "-${h}h" -t sshd -t sshd-session 2>/dev/null | grep -E "$p" | awk "$a"); if [ -n "$r" ]; then printf '%s\n' "$r"; else for f in /var/log/auth.log /var/log/secure; do [ -r "$f" ] && grep -E "$p" "$f" | awk "$a"; done; fi; true"#;
+
+/// Checks owner, mode and SELinux context of `$h/.ssh` and its authorized_keys file for the login `$u`,
+/// repairing them if `$r` isn't empty. The same as `check_keyfile_permissions` of the script.
+const KEYFILE_PERMISSIONS: &str = r#"for p in "$h/.ssh" "$h/.ssh/authorized_keys"; do [ -e "$p" ] || continue; o=$(stat -c %U "$p" 2>/dev/null || stat -f %Su "$p"); if [ "$o" != "$u" ]; then if [ -n "$r" ] && chown "$u" "$p" 2>/dev/null; then echo "fixed $p: owner was $o, now $u"; else echo "found $p: owner is $o, not $u"; fi; fi; m=$(stat -c %a "$p" 2>/dev/null || stat -f %Lp "$p"); if [ $((0$m & 022)) -ne 0 ]; then if [ -n "$r" ] && chmod go-w "$p" 2>/dev/null; then echo "fixed $p: mode was $m, group and others can't write anymore"; else echo "found $p: mode $m lets group or others write"; fi; fi; done; if command -v selinuxenabled >/dev/null 2>&1 && selinuxenabled && command -v restorecon >/dev/null 2>&1 && [ -e "$h/.ssh" ]; then if [ -n "$r" ]; then restorecon -R -v "$h/.ssh" 2>&1 | sed 's/^/fixed SELinux context: /'; else restorecon -R -n -v "$h/.ssh" 2>&1 | sed 's/^/found SELinux context: /'; fi; fi; true"#;
+
+/// Put in front of every command, so neither the PATH nor the locale of the login decide what runs
+/// and what its output looks like
+const ENVIRONMENT: &str =
+    "export PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin LC_ALL=C; ";
+
+/// SHA256 of the script at `$f`, nothing if the host has no tool for it, exits with 2 if it is missing
+const SCRIPT_HASH: &str = r#"[ -r "$f" ] || exit 2; { sha256sum || shasum -a 256 || sha256 -q || openssl dgst -sha256 -r; } < "$f" 2>/dev/null | cut -d' ' -f1"#;
+
+/// Logs sshd writes to on hosts without a journal, Debian and Red Hat style
+const AUTH_LOGS: &[&str] = &["/var/log/auth.log", "/var/log/secure"];
+
+/// Get the transport configured for this host
+pub fn transport_for(host: &Host) -> Result<Box<dyn RemoteHostTransport>, SshClientError> {
+    let kind = TransportKind::from_str(&host.transport).map_err(SshClientError::ExecutionError)?;
+
+    Ok(match kind {
+        TransportKind::Script => Box::new(ScriptTransport::for_host(host)),
+        TransportKind::Exec => Box::new(ExecTransport),
+        TransportKind::Sftp => Box::new(SftpTransport),
+    })
+}
+
+/// Runs a command and returns exit code and std{out/err} merged as a touple
+#[tracing::instrument(name = "ssh.exec", skip(handle, data))]
+pub(super) async fn execute_with_data<R>(
+    handle: &SshHandle,
+    data: R,
+    command: &str,
+) -> Result<(u32, String), SshClientError>
+where
+    R: AsyncRead + Unpin,
+{
+    let started_at = OffsetDateTime::now_utc();
+    let started = Instant::now();
+    let result = run_on_channel(handle, data, command).await;
+    handle.record(
+        command,
+        started_at,
+        started.elapsed(),
+        result
+            .as_ref()
+            .map(|(exit_code, output)| (*exit_code, output.as_str())),
+    );
+    result
+}
+
+async fn run_on_channel<R>(
+    handle: &SshHandle,
+    data: R,
+    command: &str,
+) -> Result<(u32, String), SshClientError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut channel = handle.channel_open_session().await?;
+
+    // Without a pseudo terminal, so nothing from the login shell can prompt or mangle the output
+    channel
+        .exec(true, format!("{ENVIRONMENT}{command}"))
+        .await?;
+
+    channel.data(data).await?;
+    channel.eof().await?;
+
+    let mut exit_code: Option<u32> = None;
+    let mut out_buf = Vec::new();
+    let limit = handle.max_output_size();
+
+    loop {
+        let Some(msg) = channel.wait().await else {
+            break;
+        };
+        match msg {
+            russh::ChannelMsg::Data { ref data } => {
+                // Stops a hostile or broken host from filling the memory of SSM
+                if out_buf.len() + data.len() > limit {
+                    let _ = channel.close().await;
+                    return Err(SshClientError::ExecutionError(format!(
+                        "Output exceeded {limit} bytes, the command was aborted"
+                    )));
+                }
+                out_buf
+                    .write_all(data)
+                    .await
+                    .expect("couldnt write to out_buf");
+            }
+            russh::ChannelMsg::ExitStatus { exit_status } => {
+                exit_code = Some(exit_status);
+            }
+            _ => {
+                debug!("Received extra message: {:?}", msg);
+            }
+        }
+    }
+
+    match exit_code {
+        Some(code) => {
+            let output = String::from_utf8(out_buf).map_err(|e| {
+                SshClientError::ExecutionError(format!(
+                    "Couldn't convert command output to utf-8, invalid byte at offset {}",
+                    e.utf8_error().valid_up_to()
+                ))
+            })?;
+
+            Ok((code, output))
+        }
+        None => Err(SshClientError::ExecutionError(String::from(
+            "Program didn't exit cleanly",
+        ))),
+    }
+}
+
+pub(super) async fn execute(
+    handle: &SshHandle,
+    command: &str,
+) -> Result<(u32, String), SshClientError> {
+    execute_with_data(handle, tokio::io::empty(), command).await
+}
+
+/// Runs a command and returns its output, or an error if it exited with a non-zero code
+async fn execute_checked<R>(
+    handle: &SshHandle,
+    data: R,
+    command: &str,
+) -> Result<String, SshClientError>
+where
+    R: AsyncRead + Unpin,
+{
+    match execute_with_data(handle, data, command).await? {
+        (0, output) => Ok(output),
+        (_, output) => Err(SshClientError::ExecutionError(output)),
+    }
+}
+
+/// Runs a user supplied deploy hook, returning its output or why it failed
+pub(super) async fn run_hook(handle: &SshHandle, command: &str) -> Result<String, String> {
+    match execute(handle, command).await {
+        Ok((0, output)) => Ok(output),
+        Ok((exit_code, output)) => Err(format!("exited with {exit_code}: {output}")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// The file `set_authorized_keyfile` writes, as bytes
+fn managed_keyfile_bytes(authorized_keys: &str) -> Vec<u8> {
+    format!("{PRAGMA}\n{authorized_keys}").into_bytes()
+}
+
+/// SHA256 of the exact bytes of a file, as `sha256sum` prints it
+pub(super) fn sha256_hex(data: &[u8]) -> String {
+    ssh_key::HashAlg::Sha256
+        .digest(data)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Logins are interpolated into shell commands, so only allow portable usernames. A `$` is only
+/// allowed at the end, like in the machine accounts of Samba, anywhere else the shell expands it.
+fn checked_login(login: &str) -> Result<&str, SshClientError> {
+    let name = login.strip_suffix('$').unwrap_or(login);
+    if !name.is_empty()
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+    {
+        Ok(login)
+    } else {
+        Err(SshClientError::ExecutionError(format!(
+            "Refusing to handle invalid login '{login}'"
+        )))
+    }
+}
+
+/// Principals file locations are interpolated into shell commands as well
+fn checked_principals_file(location: &str) -> Result<&str, SshClientError> {
+    Host::check_principals_file(location)
+        .map(|()| location)
+        .map_err(SshClientError::ExecutionError)
+}
+
+/// Where the script is installed on hosts that don't set another location
+const SCRIPT_PATH: &str = ".ssh/ssm.sh";
+
+/// Uses the `ssm.sh` script, installing it when it is missing or outdated
+pub struct ScriptTransport {
+    /// Relative to the home directory or absolute, quoted for the shell
+    path: String,
+    /// Command the script is run with, without one its shebang line decides
+    interpreter: Option<String>,
+}
+
+impl ScriptTransport {
+    pub fn for_host(host: &Host) -> Self {
+        let path = host.script_path.as_deref().unwrap_or(SCRIPT_PATH);
+        Self {
+            // Without a slash the shell would look the script up in the PATH
+            path: if path.contains('/') {
+                shell_quote(path)
+            } else {
+                shell_quote(&format!("./{path}"))
+            },
+            interpreter: host.script_interpreter.clone(),
+        }
+    }
+
+    /// The command line running `command` with the script of the host
+    fn command_line(&self, command: &BashCommand) -> String {
+        match &self.interpreter {
+            // A program and its arguments, like `busybox sh`
+            Some(interpreter) => {
+                let words: Vec<String> = interpreter.split_whitespace().map(shell_quote).collect();
+                format!("{} {} {command}", words.join(" "), self.path)
+            }
+            None => format!("{} {command}", self.path),
+        }
+    }
+
+    /// SHA256 of the installed script, `None` if there is none. Hosts without a tool for it send the
+    /// script instead, which is hashed here
+    async fn installed_hash(&self, handle: &SshHandle) -> Result<Option<String>, SshClientError> {
+        let command = format!("f={}; {SCRIPT_HASH}", self.path);
+        match execute(handle, &command).await? {
+            (0, output) if output.trim().is_empty() => {
+                match execute(handle, &format!("cat {}", self.path)).await? {
+                    (0, script) => Ok(Some(sha256_hex(script.as_bytes()))),
+                    _ => Ok(None),
+                }
+            }
+            (0, output) => Ok(Some(output.trim().to_owned())),
+            _ => Ok(None),
+        }
+    }
+
+    async fn execute_bash(
+        &self,
+        handle: &SshHandle,
+        command: BashCommand,
+    ) -> Result<BashResult, SshClientError> {
+        // Checked before every command, so a script changed on the host never runs
+        let bundled = bundled_script_hash();
+        let installed = self.installed_hash(handle).await?;
+        if installed.as_deref() != Some(bundled.as_str()) {
+            warn!(
+                "Script on host is missing, outdated or was changed (SHA256 {}). Trying to install",
+                installed.as_deref().unwrap_or("none")
+            );
+            match self.install(handle).await {
+                Ok(()) => {
+                    debug!("Succesfully installed script");
+                }
+                Err(error) => {
+                    warn!("Failed to install script on host: {}", error);
+                    return Err(SshClientError::ExecutionError(String::from(
+                        "Script not valid",
+                    )));
+                }
+            };
+            if self.installed_hash(handle).await?.as_deref() != Some(bundled.as_str()) {
+                return Err(SshClientError::ExecutionError(String::from(
+                    "The installed script doesn't match the bundled one",
+                )));
+            }
+        }
+
+        let command_str = self.command_line(&command);
+        debug!("Executing bash command {}", &command_str);
+
+        let stdin: Option<String> = match command {
+            BashCommand::SetAuthorizedKeyfile(_, new_keyfile) => Some(new_keyfile),
+            BashCommand::SetAuthorizedPrincipals(_, _, principals) => Some(principals),
+
+            BashCommand::GetAuthorizedKeyfile(_)
+            | BashCommand::GetAuthorizedPrincipals(_, _)
+            | BashCommand::GetSshUsers
+            | BashCommand::GetKeySources
+            | BashCommand::GetKeyUsage
+            | BashCommand::GetFailedLogins(_)
+            | BashCommand::CheckKeyfilePermissions(_, _)
+            | BashCommand::Version => None,
+        };
+
+        let (exit_code, result) = match stdin {
+            Some(stdin) => {
+                execute_with_data(
+                    handle,
+                    Cursor::new(stdin.into_bytes()),
+                    command_str.as_str(),
+                )
+                .await
+            }
+            None => execute(handle, command_str.as_str()).await,
+        }?;
+
+        Ok(match exit_code {
+            0 => BashResult::Ok(result),
+            _ => BashResult::Err(result),
+        })
+    }
+}
+
+/// SHA256 of the bundled script
+fn bundled_script_hash() -> String {
+    sha256_hex(include_bytes!("./script.sh"))
+}
+
+/// Version string of the bundled script
+fn bundled_script_version() -> &'static str {
+    include_str!("./script.sh")
+        .lines()
+        .find_map(|line| line.strip_prefix("version=\""))
+        .map_or("", |version| version.trim_end_matches('"'))
+}
+
+#[async_trait]
+impl RemoteHostTransport for ScriptTransport {
+    async fn check(&self, handle: &SshHandle) -> Result<String, SshClientError> {
+        let bundled = bundled_script_version();
+        // Only runs the script once it is known to be the bundled one
+        match self.installed_hash(handle).await? {
+            None => {
+                return Err(SshClientError::ExecutionError(String::from(
+                    "Script is missing, it is installed on the next change",
+                )))
+            }
+            Some(installed) if installed != bundled_script_hash() => {
+                return Err(SshClientError::ExecutionError(format!(
+                    "Script differs from the bundled {bundled}, it is outdated or was changed on the host. It is replaced on the next change"
+                )))
+            }
+            Some(_) => {}
+        }
+
+        let (exit_code, output) =
+            execute(handle, &self.command_line(&BashCommand::Version)).await?;
+        let installed = output.trim();
+        if exit_code != 0 || installed != bundled {
+            Err(SshClientError::ExecutionError(format!(
+                "Script is installed but doesn't run, check its interpreter: {installed}"
+            )))
+        } else {
+            Ok(format!("Found {installed}"))
+        }
+    }
+
+    async fn install(&self, handle: &SshHandle) -> Result<(), SshClientError> {
+        let script = include_bytes!("./script.sh");
+
+        let path = &self.path;
+        match execute_with_data(
+            handle,
+            &script[..],
+            &format!(r#"mkdir -p "$(dirname {path})"; cat - > {path}; chmod +x {path}"#),
+        )
+        .await
+        {
+            Ok((code, _)) => {
+                if code != 0 {
+                    Err(SshClientError::ExecutionError(String::from(
+                        "Failed to install script.",
+                    )))
+                } else {
+                    Ok(())
+                }
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    async fn get_ssh_users(&self, handle: &SshHandle) -> Result<Vec<Login>, SshClientError> {
+        let res = self
+            .execute_bash(handle, BashCommand::GetSshUsers)
+            .await??;
+
+        Ok(res.lines().map(std::borrow::ToOwned::to_owned).collect())
+    }
+
+    async fn get_authorized_keyfile(
+        &self,
+        handle: &SshHandle,
+        login: &str,
+    ) -> Result<String, SshClientError> {
+        Ok(self
+            .execute_bash(
+                handle,
+                BashCommand::GetAuthorizedKeyfile(checked_login(login)?.to_owned()),
+            )
+            .await??)
+    }
+
+    async fn set_authorized_keyfile(
+        &self,
+        handle: &SshHandle,
+        login: &str,
+        authorized_keys: &str,
+    ) -> Result<(), SshClientError> {
+        let output = self
+            .execute_bash(
+                handle,
+                BashCommand::SetAuthorizedKeyfile(
+                    checked_login(login)?.to_owned(),
+                    authorized_keys.to_owned(),
+                ),
+            )
+            .await??;
+
+        // Hosts without a tool for it print none, the script checked the size of the keyfile then
+        if let Some(written) = output.lines().find_map(|line| line.strip_prefix("sha256 ")) {
+            let sent = sha256_hex(&managed_keyfile_bytes(authorized_keys));
+            if written.trim() != sent {
+                return Err(SshClientError::ExecutionError(format!(
+                    "The host wrote a keyfile with SHA256 {}, but {sent} was sent",
+                    written.trim()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn get_authorized_principals(
+        &self,
+        handle: &SshHandle,
+        login: &str,
+        location: &str,
+    ) -> Result<String, SshClientError> {
+        Ok(self
+            .execute_bash(
+                handle,
+                BashCommand::GetAuthorizedPrincipals(
+                    checked_login(login)?.to_owned(),
+                    checked_principals_file(location)?.to_owned(),
+                ),
+            )
+            .await??)
+    }
+
+    async fn set_authorized_principals(
+        &self,
+        handle: &SshHandle,
+        login: &str,
+        location: &str,
+        principals: &str,
+    ) -> Result<(), SshClientError> {
+        self.execute_bash(
+            handle,
+            BashCommand::SetAuthorizedPrincipals(
+                checked_login(login)?.to_owned(),
+                checked_principals_file(location)?.to_owned(),
+                principals.to_owned(),
+            ),
+        )
+        .await??;
+
+        Ok(())
+    }
+
+    async fn get_key_sources(&self, handle: &SshHandle) -> Result<String, SshClientError> {
+        Ok(self
+            .execute_bash(handle, BashCommand::GetKeySources)
+            .await??)
+    }
+
+    async fn get_key_usage(&self, handle: &SshHandle) -> Result<String, SshClientError> {
+        Ok(self
+            .execute_bash(handle, BashCommand::GetKeyUsage)
+            .await??)
+    }
+
+    async fn get_failed_logins(
+        &self,
+        handle: &SshHandle,
+        hours: u32,
+    ) -> Result<String, SshClientError> {
+        Ok(self
+            .execute_bash(handle, BashCommand::GetFailedLogins(hours))
+            .await??)
+    }
+
+    async fn check_keyfile_permissions(
+        &self,
+        handle: &SshHandle,
+        login: &str,
+        repair: bool,
+    ) -> Result<String, SshClientError> {
+        let command =
+            BashCommand::CheckKeyfilePermissions(checked_login(login)?.to_owned(), repair);
+        Ok(self.execute_bash(handle, command).await??)
+    }
+}
+
+/// Uses plain POSIX shell commands, for hosts where the script can't be installed
+pub struct ExecTransport;
+
+const EXEC_GET_SSH_USERS: &str = r#"(getent passwd 2>/dev/null || cat /etc/passwd) | while IFS=: read -r name _p _u _g _c home _s; do [ -e "${home}/.ssh/authorized_keys" ] && echo "${name}:${home}"; done; true"#;
+
+#[async_trait]
+impl RemoteHostTransport for ExecTransport {
+    async fn check(&self, handle: &SshHandle) -> Result<String, SshClientError> {
+        let login = execute_checked(handle, tokio::io::empty(), "id -un").await?;
+        Ok(format!("Commands run as {}", login.trim()))
+    }
+
+    async fn get_ssh_users(&self, handle: &SshHandle) -> Result<Vec<Login>, SshClientError> {
+        let res = execute_checked(handle, tokio::io::empty(), EXEC_GET_SSH_USERS).await?;
+
+        Ok(unique_logins(
+            res.lines().filter_map(|line| line.split_once(':')),
+        ))
+    }
+
+    async fn get_authorized_keyfile(
+        &self,
+        handle: &SshHandle,
+        login: &str,
+    ) -> Result<String, SshClientError> {
+        let login = checked_login(login)?;
+        execute_checked(
+            handle,
+            tokio::io::empty(),
+            format!("cat ~{login}/.ssh/authorized_keys").as_str(),
+        )
+        .await
+    }
+
+    async fn set_authorized_keyfile(
+        &self,
+        handle: &SshHandle,
+        login: &str,
+        authorized_keys: &str,
+    ) -> Result<(), SshClientError> {
+        let login = checked_login(login)?;
+        let data = managed_keyfile_bytes(authorized_keys);
+        // Like the script: written next to the keyfile and only renamed over it once it has the
+        // size and, on hosts with a tool for it, the SHA256 that was sent
+        let command = format!(
+            r#"f=~{login}/.ssh/authorized_keys; t="$f.ssm.$$"; if [ -e "$f" ]; then if [ "$(head -n1 < "$f")" != "{PRAGMA}" ]; then cp -p "$f" "$f.backup" || exit 1; fi; cp -p "$f" "$t" || exit 1; fi; if cat - > "$t" && [ "$(wc -c < "$t" | tr -d ' ')" = "{}" ]; then sync "$t" 2>/dev/null || sync; s=$({{ sha256sum || shasum -a 256 || sha256 -q || openssl dgst -sha256 -r; }} < "$t" 2>/dev/null | cut -d' ' -f1); if [ -n "$s" ] && [ "$s" != "{}" ]; then rm -f "$t"; echo "Wrote a keyfile with SHA256 $s, keeping the previous one."; exit 1; fi; mv -f "$t" "$f"; else rm -f "$t"; echo "Couldn't write the keyfile, keeping the previous one."; exit 1; fi"#,
+            data.len(),
+            sha256_hex(&data)
+        );
+
+        execute_checked(handle, Cursor::new(data), command.as_str())
+            .await
+            .map(|_| ())
+    }
+
+    async fn get_authorized_principals(
+        &self,
+        handle: &SshHandle,
+        login: &str,
+        location: &str,
+    ) -> Result<String, SshClientError> {
+        let login = checked_login(login)?;
+        let location = Host::principals_location(checked_principals_file(location)?, login, "${h}");
+        execute_checked(
+            handle,
+            tokio::io::empty(),
+            format!(r#"h=~{login}; f="{location}"; if [ -e "$f" ]; then cat "$f"; fi"#).as_str(),
+        )
+        .await
+    }
+
+    async fn set_authorized_principals(
+        &self,
+        handle: &SshHandle,
+        login: &str,
+        location: &str,
+        principals: &str,
+    ) -> Result<(), SshClientError> {
+        let login = checked_login(login)?;
+        let location = Host::principals_location(checked_principals_file(location)?, login, "${h}");
+        // Like the keyfile: written next to the file and renamed over it once complete
+        let command = format!(
+            r#"h=~{login}; f="{location}"; t="$f.ssm.$$"; if [ -e "$f" ]; then if [ "$(head -n1 < "$f")" != "{PRAGMA}" ]; then cp -p "$f" "$f.backup" || exit 1; fi; cp -p "$f" "$t" || exit 1; fi; if cat - > "$t"; then sync "$t" 2>/dev/null || sync; mv -f "$t" "$f"; else rm -f "$t"; echo "Couldn't write the principals file, keeping the previous one."; exit 1; fi"#
+        );
+        let data = format!("{PRAGMA}\n{principals}");
+
+        execute_checked(handle, Cursor::new(data.into_bytes()), command.as_str())
+            .await
+            .map(|_| ())
+    }
+}
+
+/// Uses the SFTP subsystem, for hosts where command execution is restricted
+pub struct SftpTransport;
+
+impl SftpTransport {
+    #[tracing::instrument(name = "ssh.sftp_session", skip_all)]
+    async fn session(handle: &SshHandle) -> Result<SftpSession, SshClientError> {
+        let channel = handle.channel_open_session().await?;
+        channel.request_subsystem(true, "sftp").await?;
+
+        SftpSession::new(channel.into_stream())
+            .await
+            .map_err(sftp_error)
+    }
+
+    /// Reads a whole file, refusing files larger than `limit` bytes
+    async fn read_limited(
+        sftp: &SftpSession,
+        path: &str,
+        limit: usize,
+    ) -> Result<Vec<u8>, SshClientError> {
+        use tokio::io::AsyncReadExt;
+
+        let file = sftp.open(path).await.map_err(sftp_error)?;
+        let mut content = Vec::new();
+        file.take(limit as u64 + 1)
+            .read_to_end(&mut content)
+            .await
+            .map_err(|e| SshClientError::ExecutionError(e.to_string()))?;
+        if content.len() > limit {
+            return Err(SshClientError::ExecutionError(format!(
+                "{path} is larger than {limit} bytes"
+            )));
+        }
+        Ok(content)
+    }
+
+    /// Reads the last `limit` bytes of a file, starting at a whole line
+    async fn read_tail(
+        sftp: &SftpSession,
+        path: &str,
+        limit: usize,
+    ) -> Result<Vec<u8>, SshClientError> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        let mut file = sftp.open(path).await.map_err(sftp_error)?;
+        let size = file.metadata().await.map_err(sftp_error)?.size.unwrap_or(0);
+        let skipped = size.saturating_sub(limit as u64);
+        if skipped > 0 {
+            file.seek(std::io::SeekFrom::Start(skipped))
+                .await
+                .map_err(|e| SshClientError::ExecutionError(e.to_string()))?;
+        }
+        let mut content = Vec::new();
+        file.take(limit as u64)
+            .read_to_end(&mut content)
+            .await
+            .map_err(|e| SshClientError::ExecutionError(e.to_string()))?;
+        if skipped > 0 {
+            // The first line was cut off
+            let start = content
+                .iter()
+                .position(|byte| *byte == b'\n')
+                .map_or(content.len(), |newline| newline + 1);
+            content.drain(..start);
+        }
+        Ok(content)
+    }
+
+    /// Read /etc/passwd and return (login, home directory) pairs
+    async fn passwd(sftp: &SftpSession) -> Result<Vec<(String, String)>, SshClientError> {
+        let passwd = sftp.read("/etc/passwd").await.map_err(sftp_error)?;
+
+        Ok(String::from_utf8_lossy(&passwd)
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split(':');
+                let name = fields.next()?;
+                let home = fields.nth(4)?;
+                Some((name.to_owned(), home.to_owned()))
+            })
+            .collect())
+    }
+
+    async fn home(sftp: &SftpSession, login: &str) -> Result<String, SshClientError> {
+        Self::passwd(sftp)
+            .await?
+            .into_iter()
+            .find(|(name, _)| name.eq(login))
+            .map(|(_, home)| home)
+            .ok_or_else(|| SshClientError::ExecutionError(format!("No such login '{login}'")))
+    }
+
+    async fn keyfile_location(sftp: &SftpSession, login: &str) -> Result<String, SshClientError> {
+        Self::home(sftp, login)
+            .await
+            .map(|home| format!("{home}/.ssh/authorized_keys"))
+    }
+
+    async fn principals_location(
+        sftp: &SftpSession,
+        login: &str,
+        location: &str,
+    ) -> Result<String, SshClientError> {
+        let location = checked_principals_file(location)?;
+        Self::home(sftp, login)
+            .await
+            .map(|home| Host::principals_location(location, login, &home))
+    }
+
+    /// Writes a file with the pragma, keeping a backup if it wasn't managed yet. Like the other transports
+    /// the content goes to a file next to it first, which only replaces it once complete and synced
+    async fn replace_managed_file(
+        sftp: &SftpSession,
+        location: String,
+        content: &str,
+    ) -> Result<(), SshClientError> {
+        use tokio::io::AsyncReadExt;
+
+        let existing = if sftp.try_exists(location.as_str()).await.unwrap_or(false) {
+            // Only the first line is needed, however large the file is
+            let mut head = Vec::new();
+            sftp.open(location.as_str())
+                .await
+                .map_err(sftp_error)?
+                .take(PRAGMA.len() as u64 + 1)
+                .read_to_end(&mut head)
+                .await
+                .map_err(|e| SshClientError::ExecutionError(e.to_string()))?;
+            let has_pragma = String::from_utf8_lossy(&head)
+                .lines()
+                .next()
+                .is_some_and(|first| first.eq(PRAGMA));
+            let metadata = sftp.metadata(location.as_str()).await.map_err(sftp_error)?;
+            Some((has_pragma, metadata.permissions))
+        } else {
+            None
+        };
+
+        let temporary = format!("{location}.ssm.tmp");
+        let data = format!("{PRAGMA}\n{content}");
+        if let Err(error) = Self::write_synced(
+            sftp,
+            &temporary,
+            data.as_bytes(),
+            existing.and_then(|(_, mode)| mode),
+        )
+        .await
+        {
+            let _ = sftp.remove_file(temporary.as_str()).await;
+            return Err(error);
+        }
+
+        // SFTP doesn't rename over existing files, so the previous one is moved aside first
+        let aside = match existing {
+            Some((has_pragma, _)) => {
+                let aside = if has_pragma {
+                    format!("{location}.ssm.old")
+                } else {
+                    format!("{location}.backup")
+                };
+                let _ = sftp.remove_file(aside.as_str()).await;
+                sftp.rename(location.as_str(), aside.as_str())
+                    .await
+                    .map_err(sftp_error)?;
+                Some((has_pragma, aside))
+            }
+            None => None,
+        };
+        if let Err(error) = sftp.rename(temporary.as_str(), location.as_str()).await {
+            if let Some((_, aside)) = &aside {
+                let _ = sftp.rename(aside.as_str(), location.as_str()).await;
+            }
+            let _ = sftp.remove_file(temporary.as_str()).await;
+            return Err(sftp_error(error));
+        }
+        if let Some((true, aside)) = aside {
+            let _ = sftp.remove_file(aside).await;
+        }
+
+        Ok(())
+    }
+
+    /// Creates `path` with `data` and the mode `permissions`, flushed to disk if the server supports it
+    async fn write_synced(
+        sftp: &SftpSession,
+        path: &str,
+        data: &[u8],
+        permissions: Option<u32>,
+    ) -> Result<(), SshClientError> {
+        use tokio::io::AsyncWriteExt;
+
+        let mut file = sftp.create(path).await.map_err(sftp_error)?;
+        file.write_all(data)
+            .await
+            .map_err(|e| SshClientError::ExecutionError(e.to_string()))?;
+        file.sync_all().await.map_err(sftp_error)?;
+        file.shutdown()
+            .await
+            .map_err(|e| SshClientError::ExecutionError(e.to_string()))?;
+
+        let metadata = sftp.metadata(path).await.map_err(sftp_error)?;
+        if metadata.size != Some(data.len() as u64) {
+            return Err(SshClientError::ExecutionError(format!(
+                "Wrote {} of {} bytes, keeping the previous file",
+                metadata.size.unwrap_or(0),
+                data.len()
+            )));
+        }
+        if let Some(permissions) = permissions {
+            let mut mode = FileAttributes::empty();
+            mode.permissions = Some(permissions);
+            sftp.set_metadata(path, mode).await.map_err(sftp_error)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn sftp_error(error: russh_sftp::client::error::Error) -> SshClientError {
+    SshClientError::ExecutionError(format!("SFTP error: {error}"))
+}
+
+#[async_trait]
+impl RemoteHostTransport for SftpTransport {
+    async fn check(&self, handle: &SshHandle) -> Result<String, SshClientError> {
+        Self::session(handle).await?;
+        Ok("SFTP subsystem is available".to_owned())
+    }
+
+    async fn get_ssh_users(&self, handle: &SshHandle) -> Result<Vec<Login>, SshClientError> {
+        let sftp = Self::session(handle).await?;
+
+        let mut with_keyfile = Vec::new();
+        for (name, home) in Self::passwd(&sftp).await? {
+            if sftp
+                .try_exists(format!("{home}/.ssh/authorized_keys"))
+                .await
+                .unwrap_or(false)
+            {
+                with_keyfile.push((name, home));
+            }
+        }
+
+        Ok(unique_logins(
+            with_keyfile
+                .iter()
+                .map(|(name, home)| (name.as_str(), home.as_str())),
+        ))
+    }
+
+    async fn get_authorized_keyfile(
+        &self,
+        handle: &SshHandle,
+        login: &str,
+    ) -> Result<String, SshClientError> {
+        let sftp = Self::session(handle).await?;
+        let location = Self::keyfile_location(&sftp, login).await?;
+
+        let content = Self::read_limited(&sftp, &location, handle.max_output_size()).await?;
+        String::from_utf8(content).map_err(|_| {
+            SshClientError::ExecutionError(String::from("Couldn't convert keyfile to utf-8"))
+        })
+    }
+
+    async fn set_authorized_keyfile(
+        &self,
+        handle: &SshHandle,
+        login: &str,
+        authorized_keys: &str,
+    ) -> Result<(), SshClientError> {
+        let sftp = Self::session(handle).await?;
+        let location = Self::keyfile_location(&sftp, login).await?;
+
+        Self::replace_managed_file(&sftp, location, authorized_keys).await
+    }
+
+    async fn get_authorized_principals(
+        &self,
+        handle: &SshHandle,
+        login: &str,
+        location: &str,
+    ) -> Result<String, SshClientError> {
+        let sftp = Self::session(handle).await?;
+        let location = Self::principals_location(&sftp, login, location).await?;
+
+        if !sftp.try_exists(location.as_str()).await.unwrap_or(false) {
+            return Ok(String::new());
+        }
+        let content = Self::read_limited(&sftp, &location, handle.max_output_size()).await?;
+        String::from_utf8(content).map_err(|_| {
+            SshClientError::ExecutionError(String::from("Couldn't convert principals to utf-8"))
+        })
+    }
+
+    async fn set_authorized_principals(
+        &self,
+        handle: &SshHandle,
+        login: &str,
+        location: &str,
+        principals: &str,
+    ) -> Result<(), SshClientError> {
+        let sftp = Self::session(handle).await?;
+        let location = Self::principals_location(&sftp, login, location).await?;
+
+        Self::replace_managed_file(&sftp, location, principals).await
+    }
+
+    async fn get_sshd_config(&self, handle: &SshHandle) -> Result<String, SshClientError> {
+        let sftp = Self::session(handle).await?;
+        let content = Self::read_limited(&sftp, SSHD_CONFIG, handle.max_output_size()).await?;
+        String::from_utf8(content).map_err(|_| {
+            SshClientError::ExecutionError(String::from("Couldn't convert sshd_config to utf-8"))
+        })
+    }
+
+    async fn get_key_sources(&self, handle: &SshHandle) -> Result<String, SshClientError> {
+        // sshd can't be asked without running commands
+        self.get_sshd_config(handle).await
+    }
+
+    async fn get_key_usage(&self, handle: &SshHandle) -> Result<String, SshClientError> {
+        // Only the current auth log, rotated ones are compressed and the journal needs journalctl.
+        // Of large logs only the newest lines are read.
+        let sftp = Self::session(handle).await?;
+        let mut usage = String::new();
+        for log in AUTH_LOGS {
+            if let Ok(content) = Self::read_tail(&sftp, log, handle.max_output_size()).await {
+                usage.push_str(&String::from_utf8_lossy(&content));
+            }
+        }
+        Ok(usage)
+    }
+
+    async fn get_failed_logins(
+        &self,
+        handle: &SshHandle,
+        _hours: u32,
+    ) -> Result<String, SshClientError> {
+        // The whole current auth log, the lines are filtered by their date afterwards
+        self.get_key_usage(handle).await
+    }
+
+    async fn check_keyfile_permissions(
+        &self,
+        handle: &SshHandle,
+        login: &str,
+        repair: bool,
+    ) -> Result<String, SshClientError> {
+        // Only the modes: owners by name and SELinux contexts can't be told without running commands
+        let sftp = Self::session(handle).await?;
+        let home = Self::home(&sftp, login).await?;
+        let mut report = String::new();
+        for path in [
+            format!("{home}/.ssh"),
+            format!("{home}/.ssh/authorized_keys"),
+        ] {
+            let Some(permissions) = sftp
+                .metadata(path.as_str())
+                .await
+                .ok()
+                .and_then(|metadata| metadata.permissions)
+            else {
+                continue;
+            };
+            let mode = permissions & 0o7777;
+            if mode & 0o022 == 0 {
+                continue;
+            }
+            let repaired = FileAttributes {
+                permissions: Some(mode & !0o022),
+                ..FileAttributes::empty()
+            };
+            if repair && sftp.set_metadata(path.as_str(), repaired).await.is_ok() {
+                report.push_str(&format!(
+                    "fixed {path}: mode was {mode:o}, group and others can't write anymore\n"
+                ));
+            } else {
+                report.push_str(&format!(
+                    "found {path}: mode {mode:o} lets group or others write\n"
+                ));
+            }
+        }
+        Ok(report)
+    }
+
+    async fn set_sshd_config(
+        &self,
+        _handle: &SshHandle,
+        _content: &str,
+    ) -> Result<(), SshClientError> {
+        Err(SshClientError::ExecutionError(String::from(
+            "The sftp transport can't check and reload sshd_config",
+        )))
+    }
+}
+
+/// Deduplicate logins sharing the same home directory, keeping the first one
+fn unique_logins<'a>(entries: impl Iterator<Item = (&'a str, &'a str)>) -> Vec<Login> {
+    let mut homes = Vec::new();
+    let mut logins = Vec::new();
+
+    for (name, home) in entries {
+        if !homes.contains(&home) {
+            homes.push(home);
+            logins.push(name.to_owned());
+        }
+    }
+    logins
+}
+
+type User = String;
+pub enum BashCommand {
+    /// Read the authorized keys for a user
+    GetAuthorizedKeyfile(User),
+
+    /// Set authorized keys for a user
+    SetAuthorizedKeyfile(User, String),
+
+    /// Read the certificate principals for a user from a principals file
+    GetAuthorizedPrincipals(User, String),
+
+    /// Set the certificate principals for a user in a principals file
+    SetAuthorizedPrincipals(User, String, String),
+
+    /// Get all users that are allowed to login via SSH
+    GetSshUsers,
+
+    /// Get AuthorizedKeysCommand and AuthorizedKeysFile of sshd
+    GetKeySources,
+
+    /// Get the logins sshd accepted
+    GetKeyUsage,
+
+    /// Get the logins sshd refused in the last hours
+    GetFailedLogins(u32),
+
+    /// Check owner, mode and SELinux context of the keyfile of a user, repairing them if set
+    CheckKeyfilePermissions(User, bool),
+
+    /// Check the script version
+    Version,
+}
+
+impl std::fmt::Display for BashCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::GetAuthorizedKeyfile(user) => write!(f, "get_authorized_keyfile {user}"),
+            Self::SetAuthorizedKeyfile(user, new_keyfile) => {
+                let keyfile = managed_keyfile_bytes(new_keyfile);
+                write!(
+                    f,
+                    "set_authorized_keyfile {user} {} {}",
+                    keyfile.len(),
+                    sha256_hex(&keyfile)
+                )
+            }
+            Self::GetAuthorizedPrincipals(user, location) => {
+                write!(f, "get_authorized_principals {user} {location}")
+            }
+            Self::SetAuthorizedPrincipals(user, location, _principals) => {
+                write!(f, "set_authorized_principals {user} {location}")
+            }
+            Self::GetSshUsers => write!(f, "get_ssh_users"),
+            Self::GetKeySources => write!(f, "get_key_sources"),
+            Self::GetKeyUsage => write!(f, "get_key_usage"),
+            Self::GetFailedLogins(hours) => write!(f, "get_failed_logins {hours}"),
+            Self::CheckKeyfilePermissions(user, repair) => {
+                write!(f, "check_keyfile_permissions {user}")?;
+                if *repair {
+                    write!(f, " repair")?;
+                }
+                Ok(())
+            }
+            Self::Version => write!(f, "version"),
+        }
+    }
+}
+
+impl From<BashExecError> for SshClientError {
+    fn from(value: BashExecError) -> Self {
+        Self::ExecutionError(value)
+    }
+}
+
+type BashExecError = String;
+type BashExecResponse = String;
+pub type BashResult = Result<BashExecResponse, BashExecError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checked_login_accepts_portable_names() {
+        for login in [
+            "root",
+            "deploy-user",
+            "svc.backup",
+            "build_01",
+            "WORKSTATION$",
+        ] {
+            assert_eq!(checked_login(login).ok(), Some(login));
+        }
+    }
+
+    #[test]
+    fn checked_login_rejects_shell_syntax() {
+        for login in [
+            "",
+            "-oProxyCommand=sh",
+            "root; rm -rf ~",
+            "$(id)",
+            "`id`",
+            "a b",
+            "root\n",
+            "../root",
+            "~root",
+            "'root'",
+            "jürgen",
+            "a$HOME",
+            "x$IFS",
+            "$x",
+            "$",
+        ] {
+            assert!(checked_login(login).is_err(), "{login:?} was accepted");
+        }
+    }
+}
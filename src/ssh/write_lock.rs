@@ -0,0 +1,110 @@
+//! Writes to the same login of a host wait for each other, so two deploys can't interleave between
+//! reading the previous file, writing the new one and verifying it
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use time::OffsetDateTime;
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+#[derive(Clone, Debug, Serialize)]
+pub struct QueuedWrite {
+    #[serde(skip)]
+    id: u64,
+    pub host: String,
+    /// Login whose files are written, missing for files of the host like sshd_config
+    pub login: Option<String>,
+    pub operation: &'static str,
+    #[serde(with = "time::serde::rfc3339")]
+    pub queued_at: OffsetDateTime,
+    /// Missing while the write waits for an earlier one
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub started_at: Option<OffsetDateTime>,
+}
+
+/// A host and one of its logins, or the host itself
+type Target = (String, Option<String>);
+
+#[derive(Debug, Default)]
+pub struct WriteLocks {
+    locks: Mutex<HashMap<Target, Arc<AsyncMutex<()>>>>,
+    writes: Mutex<Vec<QueuedWrite>>,
+    next_id: AtomicU64,
+}
+
+/// Held while writing, the next write to the same target starts once it is dropped
+pub struct WriteGuard<'a> {
+    locks: &'a WriteLocks,
+    id: u64,
+    _lock: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        self.locks
+            .writes
+            .lock()
+            .expect("Write queue lock poisoned")
+            .retain(|write| write.id != self.id);
+    }
+}
+
+impl WriteLocks {
+    /// Waits until earlier writes to the login of the host, or with no login to the host itself, are done
+    pub async fn lock(
+        &self,
+        host: &str,
+        login: Option<&str>,
+        operation: &'static str,
+    ) -> WriteGuard<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let lock = {
+            let mut locks = self.locks.lock().expect("Write lock map poisoned");
+            // Targets nobody holds or waits for anymore
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            Arc::clone(
+                locks
+                    .entry((host.to_owned(), login.map(str::to_owned)))
+                    .or_default(),
+            )
+        };
+        self.writes
+            .lock()
+            .expect("Write queue lock poisoned")
+            .push(QueuedWrite {
+                id,
+                host: host.to_owned(),
+                login: login.map(str::to_owned),
+                operation,
+                queued_at: OffsetDateTime::now_utc(),
+                started_at: None,
+            });
+
+        // Removes the write from the queue even if the caller gives up waiting
+        let mut guard = WriteGuard {
+            locks: self,
+            id,
+            _lock: None,
+        };
+        guard._lock = Some(lock.lock_owned().await);
+        if let Some(write) = self
+            .writes
+            .lock()
+            .expect("Write queue lock poisoned")
+            .iter_mut()
+            .find(|write| write.id == id)
+        {
+            write.started_at = Some(OffsetDateTime::now_utc());
+        }
+        guard
+    }
+
+    /// Running writes and the ones waiting for them, oldest first
+    pub fn queued(&self) -> Vec<QueuedWrite> {
+        self.writes
+            .lock()
+            .expect("Write queue lock poisoned")
+            .clone()
+    }
+}
@@ -0,0 +1,285 @@
+//! The sshd_config directives that decide how keys are used, read from and written to the hosts
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    models::Host,
+    ssh::{SshClient, SshClientError},
+};
+
+/// Managed directives with the value sshd uses when they aren't set
+pub const DIRECTIVES: [(&str, &str); 3] = [
+    ("PermitRootLogin", "prohibit-password"),
+    ("PasswordAuthentication", "yes"),
+    (
+        "AuthorizedKeysFile",
+        ".ssh/authorized_keys .ssh/authorized_keys2",
+    ),
+];
+
+/// Whether sshd_config is read at all, changed, and what the directives should be set to
+#[derive(Debug, Default, Deserialize, Clone)]
+pub struct SshdConfigPolicy {
+    /// Read the directives of hosts (default false)
+    #[serde(default)]
+    pub enabled: bool,
+    /// Allow changing them through the API, each change is confirmed from its preview (default false)
+    #[serde(default)]
+    pub allow_changes: bool,
+    /// Values the compliance report expects, by directive
+    #[serde(default)]
+    pub expected: BTreeMap<String, String>,
+}
+
+/// State of a directive on a host
+#[derive(Debug, Serialize)]
+pub struct DirectiveState {
+    pub directive: &'static str,
+    pub value: String,
+    /// Not set in sshd_config, the value is the default of sshd
+    pub default: bool,
+    pub expected: Option<String>,
+    pub compliant: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SshdReport {
+    pub compliant: bool,
+    pub directives: Vec<DirectiveState>,
+}
+
+/// The managed directive a keyword stands for, keywords are case insensitive
+pub fn directive_name(keyword: &str) -> Option<&'static str> {
+    DIRECTIVES
+        .iter()
+        .map(|(name, _)| *name)
+        .find(|name| name.eq_ignore_ascii_case(keyword))
+}
+
+/// Checks a value before it is written to sshd_config
+pub fn check_value(directive: &str, value: &str) -> Result<(), String> {
+    let valid = match directive {
+        "PermitRootLogin" => matches!(
+            value,
+            "yes" | "no" | "prohibit-password" | "without-password" | "forced-commands-only"
+        ),
+        "PasswordAuthentication" => matches!(value, "yes" | "no"),
+        "AuthorizedKeysFile" => {
+            !value.trim().is_empty()
+                && value.chars().all(|c| {
+                    c.is_ascii_alphanumeric() || matches!(c, '/' | '.' | '_' | '-' | '%' | ' ')
+                })
+        }
+        _ => return Err(format!("{directive} isn't a managed sshd_config directive")),
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("'{value}' isn't a valid value for {directive}"))
+    }
+}
+
+/// Keyword and value of a configuration line, `None` for comments and empty lines
+fn split_line(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let (keyword, value) = line
+        .split_once(|c: char| c.is_whitespace() || c == '=')
+        .unwrap_or((line, ""));
+    Some((
+        keyword,
+        value.trim_start_matches(|c: char| c.is_whitespace() || c == '='),
+    ))
+}
+
+/// Values of the managed directives set before the first `Match` block. Like sshd, the first value counts.
+/// Files pulled in with `Include` aren't read.
+pub fn read_directives(content: &str) -> BTreeMap<&'static str, String> {
+    let mut values = BTreeMap::new();
+    for (keyword, value) in content.lines().filter_map(split_line) {
+        if keyword.eq_ignore_ascii_case("Match") {
+            break;
+        }
+        if let Some(name) = directive_name(keyword) {
+            values
+                .entry(name)
+                .or_insert_with(|| value.split_whitespace().collect::<Vec<_>>().join(" "));
+        }
+    }
+    values
+}
+
+/// Sets directives in the global section. Earlier values are commented out and the new ones
+/// put at the top, before any `Include` could set them first.
+pub fn set_directives(content: &str, changes: &BTreeMap<&'static str, String>) -> String {
+    let mut global = true;
+    let mut lines: Vec<String> = changes
+        .iter()
+        .map(|(directive, value)| format!("{directive} {value}"))
+        .collect();
+    for line in content.lines() {
+        match split_line(line) {
+            Some((keyword, _)) if keyword.eq_ignore_ascii_case("Match") => {
+                global = false;
+                lines.push(line.to_owned());
+            }
+            Some((keyword, _))
+                if global && directive_name(keyword).is_some_and(|d| changes.contains_key(d)) =>
+            {
+                lines.push(format!("# Replaced by ssm: {line}"));
+            }
+            _ => lines.push(line.to_owned()),
+        }
+    }
+    lines.into_iter().map(|line| line + "\n").collect()
+}
+
+impl SshdConfigPolicy {
+    /// Checks the expected values, called at startup
+    pub fn check(&self) -> Result<(), String> {
+        for (directive, value) in &self.expected {
+            let name = directive_name(directive)
+                .ok_or_else(|| format!("{directive} isn't a managed sshd_config directive"))?;
+            check_value(name, value)?;
+        }
+        Ok(())
+    }
+
+    fn expected(&self, directive: &str) -> Option<&String> {
+        self.expected
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(directive))
+            .map(|(_, value)| value)
+    }
+
+    /// Compares the directives of a sshd_config with the expected values
+    pub fn report(&self, content: &str) -> SshdReport {
+        let values = read_directives(content);
+        let directives: Vec<DirectiveState> = DIRECTIVES
+            .iter()
+            .map(|(directive, default)| {
+                let value = values.get(directive);
+                let current = value.map_or(*default, String::as_str);
+                let expected = self.expected(directive).cloned();
+                let compliant = expected.as_deref().is_none_or(|expected| {
+                    // Paths are case sensitive, keywords like yes and no aren't
+                    if directive.eq(&"AuthorizedKeysFile") {
+                        current.eq(expected)
+                    } else {
+                        current.eq_ignore_ascii_case(expected)
+                    }
+                });
+                DirectiveState {
+                    directive,
+                    value: current.to_owned(),
+                    default: value.is_none(),
+                    expected,
+                    compliant,
+                }
+            })
+            .collect();
+        SshdReport {
+            compliant: directives.iter().all(|state| state.compliant),
+            directives,
+        }
+    }
+}
+
+/// The authorized_keys file SSM manages, relative to the home directory of the login
+const MANAGED_KEYS_FILE: &str = ".ssh/authorized_keys";
+/// Also read by sshd unless AuthorizedKeysFile is set, but long deprecated
+const LEGACY_KEYS_FILE: &str = ".ssh/authorized_keys2";
+
+/// Where sshd looks for the keys of a login
+#[derive(Debug, Serialize)]
+pub struct KeySources {
+    /// Program sshd asks for more keys
+    pub authorized_keys_command: Option<String>,
+    pub authorized_keys_file: String,
+    /// Why the authorized_keys files SSM manages may not decide who can log in, empty if they do
+    pub warnings: Vec<String>,
+}
+
+impl KeySources {
+    /// Reads the output of `sshd -T` or sshd_config itself, the first value before any `Match` counts
+    pub fn read(content: &str) -> Self {
+        let mut command = None;
+        let mut files = None;
+        for (keyword, value) in content.lines().filter_map(split_line) {
+            if keyword.eq_ignore_ascii_case("Match") {
+                break;
+            }
+            let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+            if keyword.eq_ignore_ascii_case("AuthorizedKeysCommand") {
+                command.get_or_insert(value);
+            } else if keyword.eq_ignore_ascii_case("AuthorizedKeysFile") {
+                files.get_or_insert(value);
+            }
+        }
+        let authorized_keys_command =
+            command.filter(|command| !command.eq_ignore_ascii_case("none"));
+        let authorized_keys_file = files.unwrap_or_else(|| {
+            DIRECTIVES
+                .iter()
+                .find(|(name, _)| name.eq(&"AuthorizedKeysFile"))
+                .map_or_else(String::new, |(_, default)| (*default).to_owned())
+        });
+
+        let mut warnings = Vec::new();
+        if let Some(command) = &authorized_keys_command {
+            warnings.push(format!(
+                "sshd asks AuthorizedKeysCommand {command} for keys, \
+                 the keys it returns can log in without being deployed by SSM"
+            ));
+        }
+        let files: Vec<&str> = authorized_keys_file
+            .split_whitespace()
+            .filter(|file| !file.eq_ignore_ascii_case("none"))
+            .map(|file| file.strip_prefix("%h/").unwrap_or(file))
+            .collect();
+        if !files.contains(&MANAGED_KEYS_FILE) {
+            warnings.push(format!(
+                "sshd doesn't read {MANAGED_KEYS_FILE} (AuthorizedKeysFile {authorized_keys_file}), \
+                 the keys deployed by SSM are ignored"
+            ));
+        }
+        let others: Vec<&str> = files
+            .into_iter()
+            .filter(|file| ![MANAGED_KEYS_FILE, LEGACY_KEYS_FILE].contains(file))
+            .collect();
+        if !others.is_empty() {
+            warnings.push(format!(
+                "sshd also reads {}, keys in them can log in without being managed by SSM",
+                others.join(" ")
+            ));
+        }
+
+        Self {
+            authorized_keys_command,
+            authorized_keys_file,
+            warnings,
+        }
+    }
+}
+
+/// Reads the sshd_config of a host and compares it with the expected values
+pub async fn host_report(
+    ssh_client: &dyn SshClient,
+    policy: &SshdConfigPolicy,
+    host: Host,
+) -> Result<SshdReport, SshClientError> {
+    let content = ssh_client.get_sshd_config(host).await?;
+    Ok(policy.report(&content))
+}
+
+/// Where the sshd of a host looks for keys
+pub async fn key_sources(
+    ssh_client: &dyn SshClient,
+    host: Host,
+) -> Result<KeySources, SshClientError> {
+    let content = ssh_client.get_key_sources(host).await?;
+    Ok(KeySources::read(&content))
+}
@@ -0,0 +1,100 @@
+//! Exports tracing spans of requests, database queries and SSH operations via OTLP
+use diesel::connection::{Instrumentation, InstrumentationEvent};
+use log::info;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use serde::Deserialize;
+use tracing::{level_filters::LevelFilter, Span};
+use tracing_subscriber::{filter::Targets, layer::SubscriberExt};
+
+fn default_endpoint() -> String {
+    "http://localhost:4318/v1/traces".to_owned()
+}
+
+fn default_service_name() -> String {
+    "ssm".to_owned()
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OtelConfig {
+    /// OTLP/HTTP endpoint the spans are sent to
+    #[serde(default = "default_endpoint")]
+    endpoint: String,
+    /// Name this instance reports as
+    #[serde(default = "default_service_name")]
+    service_name: String,
+}
+
+/// Installs the OTLP exporter as global tracing subscriber, exiting if that fails.
+/// Shut the returned provider down before exiting to send the remaining spans.
+pub fn init(config: &OtelConfig) -> SdkTracerProvider {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&config.endpoint)
+        .build()
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to create OTLP exporter: {e}");
+            std::process::exit(3);
+        });
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(config.service_name.clone())
+                .build(),
+        )
+        .build();
+
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("ssm")))
+        .with(
+            Targets::new()
+                .with_target("ssm", LevelFilter::INFO)
+                .with_target("tracing_actix_web", LevelFilter::INFO),
+        );
+    // Logging still goes through `log`, so don't let the subscriber install a logger
+    if let Err(e) = tracing::subscriber::set_global_default(subscriber) {
+        eprintln!("Failed to install tracing subscriber: {e}");
+        std::process::exit(3);
+    }
+
+    if let Err(e) =
+        diesel::connection::set_default_instrumentation(|| Some(Box::new(QuerySpans::default())))
+    {
+        eprintln!("Failed to instrument database connections: {e}");
+        std::process::exit(3);
+    }
+
+    info!("Exporting traces to {}", config.endpoint);
+    provider
+}
+
+/// Opens a span for every database query, ending when the query finished
+#[derive(Default)]
+struct QuerySpans {
+    open: Vec<Span>,
+}
+
+impl Instrumentation for QuerySpans {
+    fn on_connection_event(&mut self, event: InstrumentationEvent<'_>) {
+        match event {
+            InstrumentationEvent::StartQuery { query, .. } => {
+                self.open.push(tracing::info_span!(
+                    "db.query",
+                    db.statement = %query,
+                    error = tracing::field::Empty
+                ));
+            }
+            InstrumentationEvent::FinishQuery { error, .. } => {
+                if let Some(span) = self.open.pop() {
+                    if let Some(error) = error {
+                        span.record("error", tracing::field::display(error));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
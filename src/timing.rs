@@ -0,0 +1,101 @@
+//! Where the time of a request went, sent as `X-SSM-Timing` header when `debug_timing` is set
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::{error::BlockingError, web};
+use diesel::r2d2::{event::CheckinEvent, event::CheckoutEvent, HandleEvent};
+
+use crate::i18n;
+
+/// Time spent per phase while handling a request, in microseconds
+#[derive(Debug, Default)]
+pub struct Timings {
+    db: AtomicU64,
+    ssh: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Phase {
+    /// Waiting for and using database connections
+    Db,
+    /// Connecting to hosts and running commands on them
+    Ssh,
+}
+
+tokio::task_local! {
+    static TIMINGS: Arc<Timings>;
+}
+
+impl Timings {
+    const fn counter(&self, phase: Phase) -> &AtomicU64 {
+        match phase {
+            Phase::Db => &self.db,
+            Phase::Ssh => &self.ssh,
+        }
+    }
+
+    /// `X-SSM-Timing` value, the rest of `total` was spent in handlers and serializing the response
+    pub fn header(&self, total: Duration) -> String {
+        let micros = |phase: Phase| self.counter(phase).load(Ordering::Relaxed);
+        let (db, ssh) = (micros(Phase::Db), micros(Phase::Ssh));
+        let total = u64::try_from(total.as_micros()).unwrap_or(u64::MAX);
+        let ms = |micros: u64| micros as f64 / 1000.0;
+        format!(
+            "total={:.1}ms, db={:.1}ms, ssh={:.1}ms, other={:.1}ms",
+            ms(total),
+            ms(db),
+            ms(ssh),
+            ms(total.saturating_sub(db + ssh))
+        )
+    }
+}
+
+/// Adds time spent in a phase to the request that is handled, if its timings are collected
+pub fn record(phase: Phase, elapsed: Duration) {
+    let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+    let _ = TIMINGS.try_with(|timings| timings.counter(phase).fetch_add(micros, Ordering::Relaxed));
+}
+
+/// The timings of the request that is handled
+pub fn current() -> Option<Arc<Timings>> {
+    TIMINGS.try_with(Arc::clone).ok()
+}
+
+/// Collects the timings of everything `f` does into `timings`
+pub async fn scope<F: std::future::Future>(timings: Option<Arc<Timings>>, f: F) -> F::Output {
+    match timings {
+        Some(timings) => TIMINGS.scope(timings, f).await,
+        None => f.await,
+    }
+}
+
+/// Runs database work on the blocking thread pool like [`web::block`], counting it as database time.
+/// The blocking threads don't know which request they work for, so the time is taken here and
+/// the language of the request is passed on.
+pub async fn block<F, R>(f: F) -> Result<R, BlockingError>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let started = Instant::now();
+    let lang = i18n::current();
+    let res = web::block(move || i18n::sync_scope(lang, f)).await;
+    record(Phase::Db, started.elapsed());
+    res
+}
+
+/// Counts database connections used by async code as database time, from checking them out
+/// until they are returned to the pool
+#[derive(Debug)]
+pub struct PoolTimings;
+
+impl HandleEvent for PoolTimings {
+    fn handle_checkout(&self, event: CheckoutEvent) {
+        record(Phase::Db, event.duration());
+    }
+
+    fn handle_checkin(&self, event: CheckinEvent) {
+        record(Phase::Db, event.duration());
+    }
+}
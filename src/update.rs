@@ -0,0 +1,182 @@
+//! Looks for a newer release of SSM in the background, so the API can tell about it
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::i18n::Message;
+
+/// The version that is running
+pub const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+/// How long fetching the latest release may take
+const CHECK_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn default_url() -> String {
+    "https://api.github.com/repos/styliteag/ssm/releases/latest".to_owned()
+}
+
+const fn default_interval_hours() -> u64 {
+    24
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct UpdateCheckConfig {
+    /// Look for newer releases (default false)
+    #[serde(default)]
+    pub enabled: bool,
+    /// Hours between two checks (default 24)
+    #[serde(default = "default_interval_hours")]
+    interval_hours: u64,
+    /// Latest release in the format of the GitHub API (default the one of SSM on GitHub)
+    #[serde(default = "default_url")]
+    url: String,
+}
+
+impl Default for UpdateCheckConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_hours: default_interval_hours(),
+            url: default_url(),
+        }
+    }
+}
+
+/// The part of a GitHub release that is used
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+    html_url: String,
+}
+
+/// A release that is newer than the running version
+#[derive(Debug, Clone, Serialize)]
+pub struct AvailableUpdate {
+    pub version: String,
+    /// Release page with the changes of the version
+    pub changelog_url: String,
+}
+
+/// An available update as shown to the client, with a message in the language of the request
+#[derive(Debug, Serialize)]
+pub struct UpdateNotice {
+    pub message: String,
+    #[serde(flatten)]
+    pub update: AvailableUpdate,
+}
+
+#[derive(Debug, Default)]
+struct LastCheck {
+    checked_at: Option<OffsetDateTime>,
+    update: Option<AvailableUpdate>,
+}
+
+#[derive(Debug)]
+pub struct UpdateCheck {
+    config: UpdateCheckConfig,
+    last: RwLock<LastCheck>,
+}
+
+impl UpdateCheck {
+    pub fn new(config: &UpdateCheckConfig) -> Self {
+        Self {
+            config: config.clone(),
+            last: RwLock::default(),
+        }
+    }
+
+    pub const fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// When the latest release was last fetched successfully
+    pub fn checked_at(&self) -> Option<OffsetDateTime> {
+        self.last.read().ok().and_then(|last| last.checked_at)
+    }
+
+    /// The newer release found by the last successful check
+    pub fn notice(&self) -> Option<UpdateNotice> {
+        let update = self.last.read().ok()?.update.clone()?;
+        Some(UpdateNotice {
+            message: Message::new("update.available")
+                .arg(&update.version)
+                .to_string(),
+            update,
+        })
+    }
+
+    /// Checks now and then every interval, a failed check keeps the result of the previous one
+    pub async fn run(self: Arc<Self>) {
+        let interval = Duration::from_secs(self.config.interval_hours.max(1) * 60 * 60);
+        loop {
+            let url = self.config.url.clone();
+            match tokio::task::spawn_blocking(move || fetch_release(&url)).await {
+                Ok(Ok(release)) => self.store(release),
+                Ok(Err(e)) => warn!("Failed to check for a newer version: {e}"),
+                Err(e) => warn!("Failed to check for a newer version: {e}"),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    fn store(&self, release: Release) {
+        let version = release.tag_name.trim_start_matches('v').to_owned();
+        let update = is_newer(&version, CURRENT_VERSION).then_some(AvailableUpdate {
+            version,
+            changelog_url: release.html_url,
+        });
+        let Ok(mut last) = self.last.write() else {
+            return;
+        };
+        if let Some(update) = update.as_ref().filter(|update| {
+            last.update
+                .as_ref()
+                .is_none_or(|previous| previous.version != update.version)
+        }) {
+            info!(
+                "SSM {} is available, see {}",
+                update.version, update.changelog_url
+            );
+        }
+        *last = LastCheck {
+            checked_at: Some(OffsetDateTime::now_utc()),
+            update,
+        };
+    }
+}
+
+fn fetch_release(url: &str) -> Result<Release, String> {
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_global(Some(CHECK_TIMEOUT))
+        .user_agent(format!("ssm/{CURRENT_VERSION}"))
+        .build()
+        .into();
+    let body = agent
+        .get(url)
+        .header("Accept", "application/vnd.github+json")
+        .call()
+        .and_then(|mut response| response.body_mut().read_to_string())
+        .map_err(|e| e.to_string())?;
+    serde_json::from_str(&body).map_err(|e| format!("Unexpected release from {url}: {e}"))
+}
+
+/// Whether `version` is newer than `current`. The numbers are compared one by one and a
+/// pre-release like `1.0.0-beta` comes before its release.
+fn is_newer(version: &str, current: &str) -> bool {
+    version_key(version) > version_key(current)
+}
+
+fn version_key(version: &str) -> (Vec<u64>, bool, &str) {
+    let version = version.split('+').next().unwrap_or_default();
+    let (numbers, pre_release) = version.split_once('-').unwrap_or((version, ""));
+    let mut numbers: Vec<u64> = numbers
+        .split('.')
+        .map(|number| number.parse().unwrap_or_default())
+        .collect();
+    while numbers.last() == Some(&0) {
+        numbers.pop();
+    }
+    (numbers, pre_release.is_empty(), pre_release)
+}
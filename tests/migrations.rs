@@ -0,0 +1,167 @@
+//! Runs the migrations of every supported backend and checks that the schema they create is the one
+//! in `src/schema.rs`: all columns exist, ids are generated, booleans have their defaults and
+//! timestamps come back unchanged. Each backend is migrated up, down and up again.
+//!
+//! SQLite runs in memory. PostgreSQL needs the `postgres` feature and an empty database in
+//! `SSM_TEST_POSTGRES_URL`, everything is rolled back afterwards:
+//!
+//! ``` sh
+//! SSM_TEST_POSTGRES_URL=postgres://postgres@localhost/ssm_test cargo test --features postgres
+//! ```
+use diesel::prelude::*;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use time::macros::datetime;
+
+#[allow(dead_code)]
+#[path = "../src/schema.rs"]
+mod schema;
+
+use schema::*;
+
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+#[cfg(feature = "postgres")]
+const POSTGRES_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations_postgres");
+
+#[derive(diesel::MultiConnection)]
+enum TestConnection {
+    #[cfg(feature = "postgres")]
+    Postgresql(diesel::PgConnection),
+    Sqlite(diesel::SqliteConnection),
+}
+
+/// Migrates up, down and up again, checking the schema after each way up
+fn check_migrations(conn: &mut TestConnection, migrations: impl Fn() -> EmbeddedMigrations) {
+    conn.run_pending_migrations(migrations())
+        .expect("migrations should run");
+    check_columns(conn);
+    check_types(conn);
+
+    conn.revert_all_migrations(migrations())
+        .expect("migrations should be reverted");
+    assert!(
+        conn.applied_migrations()
+            .expect("applied migrations should be listed")
+            .is_empty(),
+        "every migration should be reverted"
+    );
+
+    conn.run_pending_migrations(migrations())
+        .expect("migrations should run again after reverting them");
+    check_columns(conn);
+}
+
+/// Selects every column of every table, which fails if one is missing or misnamed
+fn check_columns(conn: &mut TestConnection) {
+    macro_rules! check_tables {
+        ($($table:ident),* $(,)?) => {$(
+            $table::table
+                .select($table::all_columns)
+                .limit(1)
+                .execute(conn)
+                .unwrap_or_else(|e| panic!("{} doesn't match src/schema.rs: {e}", stringify!($table)));
+        )*};
+    }
+    check_tables!(
+        host,
+        schedule,
+        user,
+        authorization,
+        user_key,
+        authorization_history,
+        key_history,
+        pending_host,
+        security_event,
+        event_outbox,
+        activity,
+        host_confirmation,
+        freeze_window,
+        authorization_rule,
+        recertification_campaign,
+        recertification_item,
+        activity_category,
+        diff_ignore_rule,
+    );
+}
+
+/// Ids are generated, booleans default like in the models and timestamps keep their precision
+fn check_types(conn: &mut TestConnection) {
+    for username in ["alice", "bob"] {
+        diesel::insert_into(user::table)
+            .values(user::username.eq(username))
+            .execute(conn)
+            .expect("user should be inserted without an id");
+    }
+    let users = user::table
+        .select((user::id, user::enabled))
+        .order(user::id)
+        .load::<(i32, bool)>(conn)
+        .expect("users should be loaded");
+    assert_eq!(users.len(), 2);
+    assert_ne!(users[0].0, users[1].0, "ids should be generated");
+    assert!(users.iter().all(|(_, enabled)| *enabled));
+
+    diesel::update(user::table.filter(user::username.eq("bob")))
+        .set(user::enabled.eq(false))
+        .execute(conn)
+        .expect("user should be disabled");
+    let disabled = user::table
+        .filter(user::enabled.eq(false))
+        .select(user::username)
+        .load::<String>(conn)
+        .expect("disabled users should be loaded");
+    assert_eq!(disabled, ["bob"]);
+
+    let at = datetime!(2025-03-01 12:30:15.123456);
+    diesel::insert_into(activity::table)
+        .values((
+            activity::actor.eq("alice"),
+            activity::action.eq("deploy"),
+            activity::method.eq("POST"),
+            activity::path.eq("/api/deploy"),
+            activity::status.eq(200),
+            activity::created_at.eq(at),
+        ))
+        .execute(conn)
+        .expect("activity should be inserted");
+    let created_at = activity::table
+        .filter(activity::created_at.ge(datetime!(2025-03-01 12:30)))
+        .filter(activity::created_at.lt(datetime!(2025-03-01 12:31)))
+        .select(activity::created_at)
+        .first::<time::PrimitiveDateTime>(conn)
+        .expect("activity should be found by its time");
+    assert_eq!(created_at, at);
+
+    diesel::insert_into(recertification_campaign::table)
+        .values((
+            recertification_campaign::name.eq("q1"),
+            recertification_campaign::deadline.eq(at),
+            recertification_campaign::created_by.eq("alice"),
+            recertification_campaign::created_at.eq(at),
+        ))
+        .execute(conn)
+        .expect("campaign should be inserted");
+    let auto_revoke = recertification_campaign::table
+        .select(recertification_campaign::auto_revoke)
+        .first::<bool>(conn)
+        .expect("campaign should be loaded");
+    assert!(!auto_revoke);
+}
+
+#[test]
+fn sqlite_migrations() {
+    let mut conn = TestConnection::establish(":memory:").expect("in-memory database should open");
+    check_migrations(&mut conn, || MIGRATIONS);
+}
+
+#[cfg(feature = "postgres")]
+#[test]
+fn postgres_migrations() {
+    let Ok(url) = std::env::var("SSM_TEST_POSTGRES_URL") else {
+        eprintln!("SSM_TEST_POSTGRES_URL isn't set, skipping the PostgreSQL migrations");
+        return;
+    };
+    let mut conn = TestConnection::establish(&url).expect("PostgreSQL should be reachable");
+    conn.begin_test_transaction()
+        .expect("test transaction should begin");
+    check_migrations(&mut conn, || POSTGRES_MIGRATIONS);
+}